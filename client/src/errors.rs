@@ -2,6 +2,8 @@ use std::fmt::{Display, Formatter};
 use std::string::FromUtf8Error;
 use tokio_tungstenite::tungstenite::Error as WsError;
 use protocol::errors::{X3DHError, RatchetError};
+use crate::SessionRejection;
+use common::{CommonError, ResponseCode, UsernameError};
 
 
 #[derive(Debug)]
@@ -35,11 +37,47 @@ pub enum ClientError{
     ConnectionError(WsError),
     ProtocolError(ProtocolError),
     ServerResponseError,
+    /// The server refused to establish the session, answering with `code` and the given reason.
+    HandshakeRejected { code: ResponseCode, reason: String },
+    UndecryptableFrame(CommonError),
     UserAlreadyExistsError,
+    /// The server takes no more registrations, for the given reason.
+    RegistrationRefused(String),
     UserNotFoundError,
+    InvalidUsername(UsernameError),
     SerializationError,
     GenericError(String),
     SendError,
+    ReflectedMessageError,
+    /// The message came from a user blocked with [`crate::Client::block_user`], and was dropped.
+    BlockedUser(String),
+    TimeoutError,
+    SessionRejected(SessionRejection),
+    /// The given number of messages from a friend were missed and cannot be decrypted anymore.
+    MessagesLost(u64),
+    /// A file could not be sent or received, for the given reason.
+    FileError(String),
+    /// A file of the given size is larger than [`crate::files::MAX_FILE_SIZE`].
+    FileTooLarge(u64),
+    StateError(String),
+    IncompatibleStateVersion(u8),
+    /// No group with the given id, or the sender is not a member of it.
+    UnknownGroup(String),
+}
+
+impl ClientError {
+    /// Returns `true` for transient failures after which an idempotent request can be sent again.
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            ClientError::SendError
+                | ClientError::TimeoutError
+                | ClientError::HandshakeRejected {
+                    code: ResponseCode::ServiceUnavailable | ResponseCode::InternalServerError,
+                    ..
+                }
+        )
+    }
 }
 
 impl Display for ClientError {
@@ -48,10 +86,26 @@ impl Display for ClientError {
             ClientError::ConnectionError(e) => write!(f, "Connection error: {}", e),
             ClientError::ProtocolError(e) => write!(f, "Protocol error: {}", e),
             ClientError::ServerResponseError => write!(f, "Server response error"),
+            ClientError::HandshakeRejected { code, reason } => {
+                write!(f, "The server refused the connection ({}): {}", code, reason)
+            }
+            ClientError::UndecryptableFrame(e) => write!(f, "Undecryptable server frame: {}", e),
             ClientError::UserAlreadyExistsError => write!(f, "User already exists"),
+            ClientError::RegistrationRefused(reason) => write!(f, "Registration refused: {}", reason),
             ClientError::UserNotFoundError => write!(f, "User not found"),
+            ClientError::InvalidUsername(e) => write!(f, "Invalid username: {}", e),
             ClientError::SerializationError => write!(f, "Serialization error"),
             ClientError::SendError => write!(f, "Failed to send message"),
+            ClientError::ReflectedMessageError => write!(f, "Reflected message"),
+            ClientError::BlockedUser(user) => write!(f, "Message from blocked user {}", user),
+            ClientError::TimeoutError => write!(f, "Request timed out"),
+            ClientError::SessionRejected(reason) => write!(f, "Session rejected: {}", reason),
+            ClientError::MessagesLost(n) => write!(f, "{} messages could not be recovered", n),
+            ClientError::FileError(e) => write!(f, "File transfer error: {}", e),
+            ClientError::FileTooLarge(size) => write!(f, "File too large: {} bytes", size),
+            ClientError::StateError(e) => write!(f, "State file error: {}", e),
+            ClientError::IncompatibleStateVersion(v) => write!(f, "Unsupported state file version {}", v),
+            ClientError::UnknownGroup(id) => write!(f, "Unknown group {}", id),
             ClientError::GenericError(e) => write!(f, "Error: {}", e),
 
         }
@@ -75,6 +129,18 @@ impl From<RatchetError> for ClientError {
     }
 }
 
+impl From<UsernameError> for ClientError {
+    fn from(value: UsernameError) -> Self {
+        ClientError::InvalidUsername(value)
+    }
+}
+
+impl From<CommonError> for ClientError {
+    fn from(value: CommonError) -> Self {
+        ClientError::UndecryptableFrame(value)
+    }
+}
+
 impl From<()> for ClientError {
     fn from(_: ()) -> Self {
         ClientError::ServerResponseError
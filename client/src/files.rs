@@ -0,0 +1,222 @@
+//! Small files sent to a friend in encrypted chunks, one `file` message per chunk.
+
+use std::fs;
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use base64::Engine;
+use base64::engine::general_purpose;
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use crate::errors::ClientError;
+use crate::{ChatMessage, Client, DeliveryStatus};
+
+/// Largest number of bytes of the file carried by one chunk.
+pub const FILE_CHUNK_SIZE: usize = 16 * 1024;
+
+/// Largest file sent or received, in bytes.
+pub const MAX_FILE_SIZE: usize = 1024 * 1024;
+
+/// Largest number of files a friend can be sending us at once; a new file beyond it drops their oldest one.
+pub const MAX_PARTIAL_FILES_PER_SENDER: usize = 4;
+
+/// How long a file being received waits for its next chunk before it is dropped.
+pub const PARTIAL_FILE_TIMEOUT: Duration = Duration::minutes(10);
+
+/// A piece of a file, sent encrypted in the text of a `file` message.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct FileChunk {
+    pub filename: String,
+    /// Position of the chunk in the file, from 0.
+    pub index: usize,
+    /// Number of chunks in the file, at least one.
+    pub total: usize,
+    /// Hex-encoded SHA-256 hash of the whole file.
+    pub sha256: String,
+    /// The bytes of the chunk, base64-encoded.
+    pub data: String,
+}
+
+/// The chunks of a file received so far, in order.
+pub(crate) struct PartialFile {
+    total: usize,
+    sha256: String,
+    data: Vec<u8>,
+    received: usize,
+    /// When the last chunk arrived.
+    pub(crate) updated: DateTime<Utc>,
+}
+
+impl PartialFile {
+    /// Whether `chunk` is the next one of this file.
+    fn expects(&self, chunk: &FileChunk) -> bool {
+        self.total == chunk.total && self.sha256 == chunk.sha256 && self.received == chunk.index
+    }
+}
+
+fn sha256_hex(data: &[u8]) -> String {
+    Sha256::digest(data).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Returns `filename` if it names a file, without any directory a sender could make us write into.
+fn plain_file_name(filename: &str) -> Option<&str> {
+    Path::new(filename)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .filter(|name| *name == filename)
+}
+
+/// Creates a new file named after `filename` in `dir`, numbering the name rather than replacing an existing file.
+fn write_new_file(dir: &Path, filename: &str, data: &[u8]) -> Result<PathBuf, ClientError> {
+    fs::create_dir_all(dir).map_err(|e| ClientError::FileError(e.to_string()))?;
+    for n in 0.. {
+        let path = match n {
+            0 => dir.join(filename),
+            n => dir.join(format!("{}-{}", n, filename)),
+        };
+        match fs::OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(mut file) => {
+                file.write_all(data).map_err(|e| ClientError::FileError(e.to_string()))?;
+                return Ok(path);
+            }
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(ClientError::FileError(e.to_string())),
+        }
+    }
+    unreachable!()
+}
+
+impl Client {
+    /// Sends the file at `path` to `friend`, in chunks of at most [`FILE_CHUNK_SIZE`] bytes encrypted with the ratchet,
+    /// and notes it in the history once every chunk was sent.
+    ///
+    /// Stops at the first chunk the server refuses, returning [`DeliveryStatus::Refused`], and otherwise
+    /// returns the status of the last chunk. Files larger than [`MAX_FILE_SIZE`] are not sent.
+    pub async fn send_file(&mut self, friend: &str, path: &Path) -> Result<DeliveryStatus, ClientError> {
+        if !self.friends.contains_key(friend) {
+            return Err(ClientError::UserNotFoundError);
+        }
+        let filename = path.file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| ClientError::FileError("Invalid file name".to_string()))?
+            .to_string();
+        let size = fs::metadata(path).map_err(|e| ClientError::FileError(e.to_string()))?.len();
+        if size > MAX_FILE_SIZE as u64 {
+            return Err(ClientError::FileTooLarge(size));
+        }
+        let data = fs::read(path).map_err(|e| ClientError::FileError(e.to_string()))?;
+        if data.len() > MAX_FILE_SIZE {
+            return Err(ClientError::FileTooLarge(data.len() as u64));
+        }
+
+        let sha256 = sha256_hex(&data);
+        // An empty file still takes one chunk, so the friend hears of it
+        let chunks = data.chunks(FILE_CHUNK_SIZE).collect::<Vec<_>>();
+        let chunks = if chunks.is_empty() { vec![&data[..]] } else { chunks };
+        let mut status = DeliveryStatus::Delivered;
+        for (index, chunk) in chunks.iter().enumerate() {
+            let chunk = FileChunk {
+                filename: filename.clone(),
+                index,
+                total: chunks.len(),
+                sha256: sha256.clone(),
+                data: general_purpose::STANDARD.encode(chunk),
+            };
+            let text = serde_json::to_string(&chunk).map_err(|_| ClientError::SerializationError)?;
+            status = self.send_chat_message(ChatMessage::new(
+                "file".to_string(),
+                friend.to_string(),
+                self.username.clone(),
+                text,
+                Utc::now()
+            )).await?;
+            if status == DeliveryStatus::Refused {
+                return Ok(status);
+            }
+        }
+        let sent = ChatMessage::new("file".to_string(), friend.to_string(), self.username.clone(), filename, Utc::now());
+        self.add_chat_message(sent, friend);
+        Ok(status)
+    }
+
+    /// Decrypts a chunk of a file sent with [`Client::send_file`]. Once the last chunk arrived and the hash of
+    /// the file checks out, the file is written in `dir`, noted in the history, and its path returned.
+    ///
+    /// Chunks arrive in the order they were sent. A chunk out of order means one went missing: the file is
+    /// dropped and an error returned, as for a file that does not match its hash, so no corrupt file is written.
+    /// A file that gets no chunk for [`PARTIAL_FILE_TIMEOUT`] is dropped too, and a friend can be sending at
+    /// most [`MAX_PARTIAL_FILES_PER_SENDER`] files at once.
+    pub fn receive_file_chunk(&mut self, message: ChatMessage, dir: &Path) -> Result<Option<PathBuf>, ClientError> {
+        if message.from == self.username {
+            return Err(ClientError::ReflectedMessageError);
+        }
+        let friend = self.friends.get_mut(&message.from).ok_or(ClientError::UserNotFoundError)?;
+        let plaintext = friend.decrypt_inbound(message.text)?;
+        let chunk: FileChunk = serde_json::from_slice(&plaintext).map_err(|_| ClientError::SerializationError)?;
+        let filename = plain_file_name(&chunk.filename)
+            .ok_or_else(|| ClientError::FileError("Invalid file name".to_string()))?
+            .to_string();
+        if chunk.total == 0 || chunk.total > MAX_FILE_SIZE.div_ceil(FILE_CHUNK_SIZE) || chunk.index >= chunk.total {
+            return Err(ClientError::FileError(format!("Invalid chunk {} of {}", chunk.index, filename)));
+        }
+        let data = general_purpose::STANDARD.decode(&chunk.data)?;
+        if data.len() > FILE_CHUNK_SIZE {
+            return Err(ClientError::FileError(format!("Chunk {} of {} is too large", chunk.index, filename)));
+        }
+
+        let now = Utc::now();
+        self.partial_files.retain(|_, partial| now - partial.updated < PARTIAL_FILE_TIMEOUT);
+        let key = (message.from.clone(), filename.clone());
+
+        // A first chunk starts the file over, even if an earlier transfer of it was cut short
+        if chunk.index == 0 {
+            self.partial_files.remove(&key);
+            let sending: Vec<_> = self.partial_files.iter()
+                .filter(|((from, _), _)| *from == message.from)
+                .map(|(key, partial)| (partial.updated, key.clone()))
+                .collect();
+            if sending.len() >= MAX_PARTIAL_FILES_PER_SENDER {
+                let (_, oldest) = sending.into_iter().min().unwrap();
+                self.partial_files.remove(&oldest);
+            }
+            self.partial_files.insert(key.clone(), PartialFile {
+                total: chunk.total,
+                sha256: chunk.sha256.clone(),
+                data: Vec::new(),
+                received: 0,
+                updated: now,
+            });
+        }
+        let missing = match self.partial_files.get(&key) {
+            Some(partial) if partial.expects(&chunk) => None,
+            Some(partial) if partial.sha256 == chunk.sha256 => Some(partial.received),
+            _ => Some(0),
+        };
+        if let Some(missing) = missing {
+            // The file can no longer be completed
+            self.partial_files.remove(&key);
+            return Err(ClientError::FileError(format!("Chunk {} of {} is missing", missing, filename)));
+        }
+        let partial = self.partial_files.get_mut(&key).unwrap();
+        partial.data.extend_from_slice(&data);
+        partial.received += 1;
+        partial.updated = now;
+        if partial.data.len() > MAX_FILE_SIZE {
+            let size = partial.data.len() as u64;
+            self.partial_files.remove(&key);
+            return Err(ClientError::FileTooLarge(size));
+        }
+        if partial.received < partial.total {
+            return Ok(None);
+        }
+
+        let file = self.partial_files.remove(&key).unwrap();
+        if sha256_hex(&file.data) != file.sha256 {
+            return Err(ClientError::FileError(format!("{} does not match its hash", filename)));
+        }
+        let path = write_new_file(dir, &filename, &file.data)?;
+        let received = ChatMessage::new("file".to_string(), self.username.clone(), message.from.clone(), filename, Utc::now());
+        self.add_chat_message(received, &message.from);
+        Ok(Some(path))
+    }
+}
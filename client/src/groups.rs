@@ -0,0 +1,166 @@
+//! Group chats fanned out over the pairwise sessions: a message to a group is encrypted once for each member
+//! with the ratchet of their chat, and the copies carry the id of the group so that the members collate them.
+//!
+//! The id travels encrypted with the text, in `group_chat` messages, and with the name and members of the group
+//! in `group_invite` messages. The server does not see which messages belong to the same group, nor the name of
+//! the group or its members beyond the recipients of the copies.
+//!
+//! The members are fixed when the group is created. Adding and removing members is left for later: it needs
+//! the other members to agree on the new list, which a plain invite does not give.
+
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+use common::normalize_username;
+use crate::errors::ClientError;
+use crate::{ChatMessage, Client, DeliveryStatus};
+
+/// A group chat, whose messages are kept here rather than in the history of each member.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct GroupChat {
+    /// The id carried by every message of the group, random.
+    pub id: String,
+    pub name: String,
+    /// The other members of the group, without us.
+    pub members: Vec<String>,
+    pub chat: Vec<ChatMessage>,
+    pub unread: usize,
+}
+
+/// The id, name and members of a group, sent encrypted in the text of a `group_invite` message.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct GroupInvite {
+    pub id: String,
+    pub name: String,
+    /// Every member of the group, the sender included.
+    pub members: Vec<String>,
+}
+
+/// The text of a message to a group and the id of the group, sent encrypted in the text of a `group_chat` message.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct GroupPayload {
+    pub group_id: String,
+    pub text: String,
+}
+
+impl Client {
+    /// Creates a group of `members` and invites them, starting a session with those we have no chat with,
+    /// see [`Client::get_user_prekey_bundle`].
+    ///
+    /// Returns the id of the group. Members are given by username, we are left out if named.
+    pub async fn create_group(&mut self, name: String, members: Vec<String>) -> Result<String, ClientError> {
+        let mut others = Vec::new();
+        for member in members {
+            let member = normalize_username(&member)?;
+            if member != self.username && !others.contains(&member) {
+                others.push(member);
+            }
+        }
+        for member in &others {
+            if !self.friends.contains_key(member) {
+                self.get_user_prekey_bundle(member.clone()).await?;
+            }
+        }
+
+        let id = Uuid::new_v4().to_string();
+        let mut everyone = others.clone();
+        everyone.push(self.username.clone());
+        let invite = serde_json::to_string(&GroupInvite { id: id.clone(), name: name.clone(), members: everyone })
+            .map_err(|_| ClientError::SerializationError)?;
+        for member in &others {
+            self.send_encrypted(ChatMessage::new(
+                "group_invite".to_string(),
+                member.clone(),
+                self.username.clone(),
+                invite.clone(),
+                Utc::now()
+            )).await?;
+        }
+        self.groups.insert(id.clone(), GroupChat { id: id.clone(), name, members: others, chat: Vec::new(), unread: 0 });
+        Ok(id)
+    }
+
+    /// Joins the group a `group_invite` message invites us to, returning its id.
+    ///
+    /// An invite to a group we are already in is ignored, so that no member can change who else is in it.
+    /// Invites from blocked users are dropped before decryption, like their messages.
+    pub fn apply_group_invite(&mut self, message: ChatMessage) -> Result<String, ClientError> {
+        if message.from == self.username {
+            return Err(ClientError::ReflectedMessageError);
+        }
+        if self.blocked.contains(&message.from) {
+            return Err(ClientError::BlockedUser(message.from));
+        }
+        let friend = self.friends.get_mut(&message.from).ok_or(ClientError::UserNotFoundError)?;
+        let text = friend.decrypt_inbound(message.text)?;
+        let invite: GroupInvite = serde_json::from_slice(&text).map_err(|_| ClientError::SerializationError)?;
+        let id = invite.id;
+        if !self.groups.contains_key(&id) {
+            let members = invite.members.into_iter().filter(|m| *m != self.username).collect();
+            self.groups.insert(id.clone(), GroupChat { id: id.clone(), name: invite.name, members, chat: Vec::new(), unread: 0 });
+        }
+        Ok(id)
+    }
+
+    /// Sends `text` to every member of the group `group_id`, one copy per member, and appends it to the
+    /// history of the group once. Sessions with members we have no chat with are started first.
+    ///
+    /// Returns what the server did with each copy, by member.
+    pub async fn send_group_message(&mut self, group_id: &str, text: String) -> Result<Vec<(String, DeliveryStatus)>, ClientError> {
+        let group = self.groups.get(group_id).ok_or_else(|| ClientError::UnknownGroup(group_id.to_string()))?;
+        let members = group.members.clone();
+        let mut message = ChatMessage::new("chat".to_string(), String::new(), self.username.clone(), text, Utc::now());
+        message.group_id = Some(group_id.to_string());
+
+        let mut statuses = Vec::new();
+        for member in members {
+            if !self.friends.contains_key(&member) {
+                self.get_user_prekey_bundle(member.clone()).await?;
+            }
+            let mut copy = message.clone();
+            copy.to = member.clone();
+            statuses.push((member, self.send_chat_message(copy).await?));
+        }
+        if let Some(group) = self.groups.get_mut(group_id) {
+            group.chat.push(message);
+        }
+        Ok(statuses)
+    }
+
+    /// Appends a decrypted message of a member to the history of its group.
+    pub(crate) fn add_group_message(&mut self, message: ChatMessage, group_id: &str) -> Result<(), ClientError> {
+        let group = self.groups
+            .get_mut(group_id)
+            .filter(|g| g.members.contains(&message.from))
+            .ok_or_else(|| ClientError::UnknownGroup(group_id.to_string()))?;
+        group.unread += 1;
+        group.chat.push(message);
+        Ok(())
+    }
+
+    /// Returns the ids of the groups we are in, ordered by group name.
+    pub fn get_group_chats(&self) -> Vec<String> {
+        let mut groups: Vec<&GroupChat> = self.groups.values().collect();
+        groups.sort_by(|a, b| (&a.name, &a.id).cmp(&(&b.name, &b.id)));
+        groups.into_iter().map(|g| g.id.clone()).collect()
+    }
+
+    pub fn get_group(&self, group_id: &str) -> Option<&GroupChat> {
+        self.groups.get(group_id)
+    }
+
+    pub fn get_group_history(&self, group_id: &str) -> Option<Vec<ChatMessage>> {
+        self.groups.get(group_id).map(|g| g.chat.clone())
+    }
+
+    pub fn mark_group_read(&mut self, group_id: &str) {
+        if let Some(group) = self.groups.get_mut(group_id) {
+            group.unread = 0;
+        }
+    }
+
+    /// Leaves the group `group_id` on this side only: the other members are not told.
+    pub fn leave_group(&mut self, group_id: &str) -> bool {
+        self.groups.remove(group_id).is_some()
+    }
+}
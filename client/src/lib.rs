@@ -1,113 +1,357 @@
 #![allow(warnings)]
 pub mod errors;
-
-use std::collections::HashMap;
+#[cfg(feature = "file-transfer")]
+pub mod files;
+pub mod groups;
+#[cfg(feature = "state-file")]
+mod state;
+#[cfg(test)]
+mod tests;
+
+use std::collections::{HashMap, HashSet};
 use std::fmt::Display;
 use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
 use arrayref::array_ref;
 use base64::Engine;
 use base64::engine::general_purpose;
-use chrono::{DateTime, Utc};
-use common::{ResponseCode, ServerResponse, ResponseWrapper, RequestWrapper, CONFIG};
+use chrono::{DateTime, Duration, Utc};
+use common::{normalize_username, validate_username, CommonError, ReplenishPreKeysMessage, ResponseCode, ServerInfo, ServerResponse, ResponseWrapper, RequestWrapper, WireBundle, CONFIG};
 use futures_util::{
     stream::{SplitSink, SplitStream},
     SinkExt, StreamExt,
 };
 use log::{debug, error, info};
-use protocol::x3dh::{generate_prekey_bundle_with_otpk, process_initial_message, process_server_initial_message};
+use protocol::x3dh::{generate_prekey_bundle_with_otpk, process_initial_message_with_suite, process_server_initial_message, Role};
 use protocol::{
     utils::{
         AssociatedData, DecryptionKey, InitialMessage, PreKeyBundle, PrivateKey,
         SessionKeys,
     },
     x3dh::process_prekey_bundle,
-    ratchet::{Ratchet, RatchetKeyPair},
+    ratchet::{MessageMeta, Ratchet, RatchetKeyPair},
 
 };
 use serde_json::{json, Value};
 
 use tokio::net::TcpStream;
-use tokio::sync::{mpsc, oneshot, Mutex};
+use tokio::sync::{mpsc, oneshot, watch, Mutex};
 use tokio_tungstenite::{
     tungstenite::{Message, Utf8Bytes},
     MaybeTlsStream, WebSocketStream,
 };
 use uuid::Uuid;
-use protocol::utils::{PublicKey, Sha256Hash, SharedSecret};
+use protocol::utils::{OneTimePreKey, PublicKey, SharedSecret, Signature};
 use serde::{Deserialize, Serialize};
-use protocol::constants::AES256_NONCE_LENGTH;
-use crate::errors::ClientError;
+use protocol::constants::{AES256_NONCE_LENGTH, MAX_ONE_TIME_PREKEYS};
+use protocol::fingerprint::compute_safety_number;
+use crate::errors::{ClientError, ProtocolError};
+use protocol::errors::{RatchetError, X3DHError};
+use zeroize::{Zeroize, Zeroizing};
 
 type Sender = SplitSink<WebSocketStream<MaybeTlsStream<TcpStream>>, Message>;
 type Receiver = SplitStream<WebSocketStream<MaybeTlsStream<TcpStream>>>;
 
+/// A connection to the server opened by [`Client::dial`], for [`Client::resume`] to take over.
+pub struct Connection {
+    write: Sender,
+    read: Receiver,
+}
+
+impl Connection {
+    /// Opens a connection to the server at `url`, for [`Client::from_connection`].
+    pub async fn open(url: &str) -> Result<Self, ClientError> {
+        let (write, read) = Client::connect_to(url).await?;
+        Ok(Self { write, read })
+    }
+}
+
+/// The stages of starting a [`Client`], reported by [`Client::new_with_progress`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConnectionState {
+    /// Opening the connection to the server.
+    Connecting,
+    /// Decrypting the chats saved on disk.
+    LoadingState,
+    /// Establishing the encrypted session with the server.
+    Handshaking,
+    /// The client is connected and ready to register.
+    Ready,
+    /// Starting the client failed for the given reason.
+    Failed(String),
+}
+
+/// How long a replaced signed pre-key accepts initial messages by default.
+const SIGNED_PREKEY_GRACE: Duration = Duration::days(7);
+
 pub struct Client {
     pub(crate) friends: HashMap<String, Friend>,
-    session: SessionKeys,
+    session: Arc<Mutex<SessionKeys>>,
     write: Sender,
     read: Option<Receiver>,
     pub username: String,
+    /// Sent at registration, so that the server tells this client apart from the other devices of the user.
+    device_id: String,
     bundle: PreKeyBundle,
     identity_key: PrivateKey,
     signed_prekey: PrivateKey,
-    one_time_prekeys: HashMap<Sha256Hash, PrivateKey>,
+    /// Signed pre-keys replaced by [`Client::rotate_signed_prekey`], with the time they were replaced.
+    retired_signed_prekeys: Vec<(PrivateKey, DateTime<Utc>)>,
+    /// How long a replaced signed pre-key still accepts initial messages.
+    signed_prekey_grace: Duration,
+    /// How old the signed pre-key gets before [`Client::rotate_signed_prekey_if_due`] replaces it.
+    signed_prekey_rotation: Option<Duration>,
+    /// The private halves of the one-time pre-keys, by the id they have in the bundle.
+    one_time_prekeys: HashMap<u32, PrivateKey>,
+    /// The id given to the next one-time pre-key uploaded.
+    next_otpk_id: u32,
     pending: Arc<Mutex<HashMap<String, oneshot::Sender<Value>>>>,
     listener: Option<tokio::task::JoinHandle<()>>,
     chat_tx: mpsc::Sender<ChatMessage>,
+    retention: Option<RetentionPolicy>,
+    archived_chats: HashMap<String, Vec<ChatMessage>>,
+    rotation: Option<Duration>,
+    last_rotation: DateTime<Utc>,
+    rekey_request: Arc<Mutex<Option<String>>>,
+    retry: RetryPolicy,
+    /// Users the server has been asked not to relay messages from.
+    relay_blocked: HashSet<String>,
+    /// Users whose messages are dropped on arrival, see [`Client::block_user`].
+    blocked: HashSet<String>,
+    /// Cleared by the read loop when the connection to the server is lost.
+    connected: Arc<AtomicBool>,
+    reconnect: ReconnectPolicy,
+    /// The server to reconnect to, the configured one if `None`.
+    server_url: Option<String>,
+    /// The identity key the server answers the handshake with, the configured one if `None`.
+    server_key: Option<PublicKey>,
+    /// Files being received with [`Client::receive_file_chunk`], by sender and name.
+    #[cfg(feature = "file-transfer")]
+    partial_files: HashMap<(String, String), files::PartialFile>,
+    /// The group chats we are in, by id.
+    groups: HashMap<String, groups::GroupChat>,
 }
 
 impl Client {
 
     pub async fn new(chat_tx: mpsc::Sender<ChatMessage>) -> Result<Self, ClientError> {
-        let (write, read) = Self::connect().await?;
+        Self::new_with_progress(chat_tx, &watch::channel(ConnectionState::Connecting).0).await
+    }
+
+    /// Like [`Client::new`], reporting each stage of the startup on `progress`.
+    pub async fn new_with_progress(
+        chat_tx: mpsc::Sender<ChatMessage>,
+        progress: &watch::Sender<ConnectionState>,
+    ) -> Result<Self, ClientError> {
+        progress.send_replace(ConnectionState::Connecting);
+        let client = async {
+            let (write, read) = Self::connect().await?;
+            Self::with_connection(write, read, chat_tx).start(progress).await
+        };
+        report_startup(progress, client.await)
+    }
+
+    /// Establishes the session with the server and starts reading from it.
+    pub(crate) async fn start(mut self, progress: &watch::Sender<ConnectionState>) -> Result<Self, ClientError> {
+        progress.send_replace(ConnectionState::Handshaking);
+        self.establish_connection().await?;
+        self.listener = Some(self.start_read_loop());
+        Ok(self)
+    }
+
+    /// Creates a client on `connection` without exchanging anything with the server yet: the session is
+    /// established with [`Client::establish_connection`], which [`Client::new`] does on its own.
+    pub fn from_connection(connection: Connection, chat_tx: mpsc::Sender<ChatMessage>) -> Self {
+        Self::with_connection(connection.write, connection.read, chat_tx)
+    }
+
+    fn with_connection(write: Sender, read: Receiver, chat_tx: mpsc::Sender<ChatMessage>) -> Self {
         let (bundle, ik, spk, otpk) = generate_prekey_bundle_with_otpk(31);
-        let session = SessionKeys::new();
+        let session = Arc::new(Mutex::new(SessionKeys::new()));
         let username = "".to_string();
-        let public_otpk = bundle.otpk.clone();
-        let hash_otpk = public_otpk.iter().map(|v| v.hash()).collect::<Vec<Sha256Hash>>();
-        let otpk = hash_otpk
+        let next_otpk_id = otpk.len() as u32;
+        let otpk = bundle.otpk_ids
             .iter()
-            .zip(otpk.iter())
-            .map(|(k, v)| (k.to_owned(), v.to_owned()))
+            .copied()
+            .zip(otpk)
             .collect();
 
-        let mut client = Self {
+        Self {
             friends: HashMap::new(),
             session,
             write,
             read: Some(read),
             username,
+            device_id: Uuid::new_v4().to_string(),
             bundle,
             identity_key: ik,
             signed_prekey: spk,
+            retired_signed_prekeys: Vec::new(),
+            signed_prekey_grace: SIGNED_PREKEY_GRACE,
+            signed_prekey_rotation: None,
             one_time_prekeys: otpk,
+            next_otpk_id,
             pending: Arc::new(Mutex::new(HashMap::new())),
             listener: None,
             chat_tx,
-        };
-
-        client.establish_connection().await?;
-        client.listener = Some(client.start_read_loop());
-        Ok(client)
+            retention: None,
+            archived_chats: HashMap::new(),
+            rotation: None,
+            last_rotation: Utc::now(),
+            rekey_request: Arc::new(Mutex::new(None)),
+            retry: RetryPolicy::default(),
+            relay_blocked: HashSet::new(),
+            blocked: HashSet::new(),
+            connected: Arc::new(AtomicBool::new(false)),
+            reconnect: ReconnectPolicy::default(),
+            server_url: None,
+            server_key: None,
+            #[cfg(feature = "file-transfer")]
+            partial_files: HashMap::new(),
+            groups: HashMap::new(),
+        }
     }
 
     async fn connect() -> Result<(Sender, Receiver), ClientError> {
-        let (ws_stream, _) = tokio_tungstenite::connect_async(CONFIG.get_server_url()).await?;
+        Self::connect_to(&CONFIG.get_server_url()).await
+    }
+
+    async fn connect_to(url: &str) -> Result<(Sender, Receiver), ClientError> {
+        let (ws_stream, _) = tokio_tungstenite::connect_async(url).await?;
         let (write, read) = ws_stream.split();
         Ok((write, read))
     }
 
+    /// Returns `false` once the connection to the server is lost, see [`Client::reconnect`].
+    pub fn is_connected(&self) -> bool {
+        self.connected.load(Ordering::SeqCst)
+    }
+
+    pub fn set_reconnect_policy(&mut self, reconnect: ReconnectPolicy) {
+        self.reconnect = reconnect;
+    }
+
+    /// Opens a new connection to the server after the previous one was lost, retrying with
+    /// exponential backoff as set by the [`ReconnectPolicy`].
+    ///
+    /// The session with the server is established again and the username registered again,
+    /// the chats and their ratchets are kept as they are.
+    pub async fn reconnect(&mut self) -> Result<(), ClientError> {
+        if let Some(listener) = self.listener.take() {
+            listener.abort();
+        }
+        self.connected.store(false, Ordering::SeqCst);
+        let mut delay = self.reconnect.base_delay;
+        let mut attempt = 1;
+        loop {
+            match self.try_reconnect().await {
+                Ok(()) => return Ok(()),
+                Err(e) if attempt >= self.reconnect.max_attempts => return Err(e),
+                Err(e) => debug!("Reconnection attempt {} failed: {}", attempt, e),
+            }
+            tokio::time::sleep(delay).await;
+            delay = (delay * 2).min(self.reconnect.max_delay);
+            attempt += 1;
+        }
+    }
+
+    async fn try_reconnect(&mut self) -> Result<(), ClientError> {
+        let url = self.server_url.clone().unwrap_or_else(|| CONFIG.get_server_url());
+        let (write, read) = Self::connect_to(&url).await?;
+        self.resume(Connection { write, read }).await
+    }
+
+    /// Opens a new connection to the server, retrying with exponential backoff as set by the [`ReconnectPolicy`].
+    ///
+    /// The client is not borrowed while the connection is opened, so this can run in the background,
+    /// for example while a user interface keeps drawing. The connection is then handed to [`Client::resume`].
+    pub fn dial(&self) -> impl std::future::Future<Output = Result<Connection, ClientError>> + Send + 'static {
+        let url = self.server_url.clone().unwrap_or_else(|| CONFIG.get_server_url());
+        let policy = self.reconnect.clone();
+        async move {
+            let mut delay = policy.base_delay;
+            let mut attempt = 1;
+            loop {
+                match Self::connect_to(&url).await {
+                    Ok((write, read)) => return Ok(Connection { write, read }),
+                    Err(e) if attempt >= policy.max_attempts => return Err(e),
+                    Err(e) => debug!("Connection attempt {} failed: {}", attempt, e),
+                }
+                tokio::time::sleep(delay).await;
+                delay = (delay * 2).min(policy.max_delay);
+                attempt += 1;
+            }
+        }
+    }
+
+    /// Takes over `connection` after the previous one was lost, see [`Client::reconnect`].
+    ///
+    /// The session with the server is established again and the username registered again, with the
+    /// one-time pre-keys we still hold, the chats and their ratchets are kept as they are.
+    pub async fn resume(&mut self, connection: Connection) -> Result<(), ClientError> {
+        if let Some(listener) = self.listener.take() {
+            listener.abort();
+        }
+        self.write = connection.write;
+        self.read = Some(connection.read);
+        // Requests sent on the lost connection will never be answered
+        self.pending.lock().await.clear();
+        self.rekey_request.lock().await.take();
+        // Keys handed out since the bundle was last sent may have been used already
+        self.restore_one_time_prekeys();
+        self.establish_connection().await?;
+        self.listener = Some(self.start_read_loop());
+        if self.is_registered() {
+            self.register_user().await?;
+            // The server forgot the filters along with the connection
+            for from in self.relay_blocked.clone() {
+                self.set_relay_filter(&from, true).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Puts the one-time pre-keys whose private halves we still hold back in the bundle, in the order of their ids.
+    fn restore_one_time_prekeys(&mut self) {
+        while self.bundle.pop_otpk().is_some() {}
+        let mut ids = self.one_time_prekeys.keys().copied().collect::<Vec<u32>>();
+        ids.sort_unstable();
+        for id in ids {
+            let key = PublicKey::from(&self.one_time_prekeys[&id]);
+            let sig = PreKeyBundle::sign_otpk(&self.identity_key, &key);
+            self.bundle.push_otpk(OneTimePreKey { key, id: Some(id), sig: Some(sig) });
+        }
+    }
+
+    /// Establishes the encrypted session with the server, which answers our bundle with an initial message.
+    ///
+    /// A handshake the server refuses fails with [`ClientError::HandshakeRejected`]. Refusals the server may
+    /// get over, see [`ClientError::is_retryable`], are retried on the same connection as set by the [`RetryPolicy`].
     pub async fn establish_connection(&mut self) -> Result<(), ClientError> {
+        let mut attempt = 0;
+        loop {
+            match self.try_establish_connection().await {
+                Err(e) if e.is_retryable() && attempt + 1 < self.retry.max_attempts => {
+                    debug!("Handshake failed ({}), retrying", e);
+                    tokio::time::sleep(self.retry.base_delay * 2u32.pow(attempt)).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    async fn try_establish_connection(&mut self) -> Result<(), ClientError> {
 
         let msg = json!({
         "request_type": "establish_connection",
-        "bundle": self.bundle.clone().to_base64()
+        "bundle": self.bundle.to_base64(),
+        "typed": true
         });
 
         self.write
             .send(Message::Text(Utf8Bytes::from(msg.to_string())))
-            .await
-            .expect("Failed to send message");
+            .await?;
 
 
         if let Some(read) = &mut self.read {
@@ -117,27 +361,35 @@ impl Client {
 
                 let resp = ServerResponse::from_json(initial_msg.to_string())
                     .ok_or(ClientError::ServerResponseError)?;
+                if resp.code != ResponseCode::Ok {
+                    return Err(ClientError::HandshakeRejected { code: resp.code, reason: resp.text });
+                }
 
-                let mut im = resp.text;
-                debug!("im: {}", &im);
-                im.retain(|c| !c.eq(&("\"".parse::<char>().unwrap())));
-                let initial_message = InitialMessage::try_from(im)?;
-                let otpk_used = self.one_time_prekeys.get(
-                    &initial_message.one_time_key_hash
-                        .clone()
-                        .unwrap()
-                );
+                // Servers that predate the versioned form answer with the legacy string
+                debug!("im: {}", &resp.text);
+                let initial_message = InitialMessage::try_from(resp.text)?;
+                let otpk_used = self.one_time_prekey(&initial_message)
+                    .map_err(|_| ClientError::ServerResponseError)?;
+                let server_key = match &self.server_key {
+                    Some(key) => key.clone(),
+                    None => PublicKey::from_base64(CONFIG.get_public_key_server()).unwrap(),
+                };
                 let (ek, dk) = process_server_initial_message(
                     self.identity_key.clone(),
                     self.signed_prekey.clone(),
-                    otpk_used.cloned(),
-                    &PublicKey::from_base64(CONFIG.get_public_key_server()).unwrap(),
+                    otpk_used.clone().map(|(_, key)| key),
+                    &server_key,
                     initial_message.clone(),
                 )?;
 
-                self.session.set_encryption_key(ek);
-                self.session.set_decryption_key(dk);
-                self.session.set_associated_data(initial_message.associated_data);
+                if let Some((id, _)) = otpk_used {
+                    self.one_time_prekeys.remove(&id);
+                }
+                let mut session = self.session.lock().await;
+                session.set_encryption_key(ek);
+                session.set_decryption_key(dk);
+                session.set_associated_data(initial_message.associated_data);
+                self.last_rotation = Utc::now();
                 Ok(())
             } else {
                 Err(ClientError::ServerResponseError)
@@ -150,25 +402,69 @@ impl Client {
     fn start_read_loop(&mut self) -> tokio::task::JoinHandle<()> {
         let mut read = self.read.take().expect("Reader already taken");
         let pending_map = Arc::clone(&self.pending);
-        let decryption_key = self.session.get_decryption_key().unwrap();
+        let session = Arc::clone(&self.session);
+        let rekey_request = Arc::clone(&self.rekey_request);
         let chat_tx = self.chat_tx.clone();
+        let connected = Arc::clone(&self.connected);
+        connected.store(true, Ordering::SeqCst);
         tokio::task::spawn( async move {
             while let Some(msg_result) = StreamExt::next(&mut read).await {
                 match msg_result {
                     Ok(Message::Text(msg)) => {
-                        if let Ok(decrypted) = decrypt_server_request(msg.to_string(), &decryption_key) {
+                        let decrypted = decrypt_server_request(msg.to_string(), &*session.lock().await);
+                        if let Ok(decrypted) = decrypted {
                             if let Ok(response) = serde_json::from_str::<ResponseWrapper>(&decrypted.to_string()) {
 
+                                // The server switches keys right after acknowledging a rekey,
+                                // so rotate before reading the next frame.
+                                let mut rekey = rekey_request.lock().await;
+                                if rekey.as_ref() == Some(&response.request_id) {
+                                    rekey.take();
+                                    let accepted = ServerResponse::from_json(response.body.to_string())
+                                        .is_some_and(|r| matches!(r.code, ResponseCode::Ok));
+                                    if accepted {
+                                        if let Err(e) = session.lock().await.rotate() {
+                                            error!("Failed to rotate session keys: {}", e);
+                                        }
+                                    }
+                                }
+                                drop(rekey);
+
                                 // Look up the request_id in the pending map
+                                let refused = ServerResponse::from_json(response.body.to_string())
+                                    .filter(|r| matches!(r.code, ResponseCode::Forbidden));
                                 let mut lock = pending_map.lock().await;
                                 if let Some(tx) = lock.remove(&response.request_id) {
                                     // Send the "body" to whoever is waiting
                                     let _ = tx.send(response.body);
                                 }
+                                drop(lock);
+                                if let Some(refused) = refused {
+                                    // A chat message was refused because the recipient, named
+                                    // in the text, closed the chat: close it on our side too.
+                                    let close = ChatMessage::new(
+                                        "close_chat".to_string(),
+                                        "".to_string(),
+                                        refused.text,
+                                        "".to_string(),
+                                        Utc::now()
+                                    );
+                                    let _ = chat_tx.send(close).await;
+                                }
 
                             } else if let Ok(chat_msg) = serde_json::from_str::<ChatMessage>(&decrypted.to_string()) {
                                 // Forward to the chat channel
                                 let _ = chat_tx.send(chat_msg).await;
+                            } else if let Ok(replenish) = serde_json::from_str::<ReplenishPreKeysMessage>(&decrypted.to_string()) {
+                                // The upload needs the client, so let the chat consumer handle it
+                                let replenish = ChatMessage::new(
+                                    replenish.msg_type,
+                                    "".to_string(),
+                                    "".to_string(),
+                                    replenish.count.to_string(),
+                                    Utc::now()
+                                );
+                                let _ = chat_tx.send(replenish).await;
                             }
                             // 4) Otherwise, ignore or log unknown format
                             else {
@@ -187,17 +483,25 @@ impl Client {
                     _ => {}
                 }
             }
+            connected.store(false, Ordering::SeqCst);
+            // Waiting requests fail right away instead of timing out
+            pending_map.lock().await.clear();
         })
     }
 
     pub async fn register_user(&mut self) -> Result<(), ClientError> {
-        self.bundle.otpk.pop();
+        // Refused here rather than by the server, see `validate_username`
+        validate_username(&self.username)?;
+        self.username = normalize_username(&self.username)?;
+        self.bundle.pop_otpk();
         let req = json!({
             "username" : self.username.clone(),
-            "bundle": self.bundle.clone().to_base64()
+            "bundle": WireBundle::Typed(Box::new(self.bundle.clone())),
+            "idempotency_key": Uuid::new_v4().to_string(),
+            "device_id": self.device_id.clone(),
         });
 
-        let response_json = self.send_encrypted_message(req).await?;
+        let response_json = self.send_with_retry(req).await?;
         let response = ServerResponse::from_json(response_json.to_string())
             .ok_or(ClientError::ServerResponseError)?;
         match response.code {
@@ -207,6 +511,9 @@ impl Client {
             ResponseCode::Conflict => {
                 Err(ClientError::UserAlreadyExistsError)
             }
+            ResponseCode::ServiceUnavailable => {
+                Err(ClientError::RegistrationRefused(response.text))
+            }
             _ => {
                 Err(ClientError::ServerResponseError)
             }
@@ -217,32 +524,66 @@ impl Client {
         &mut self,
         username: String,
     ) -> Result<(), ClientError> {
+        self.open_chat(username, false).await
+    }
+
+    /// Starts a session with `username`. An `ephemeral` chat is never saved to disk,
+    /// and the friend is told to do the same with a `chat_settings` message.
+    pub async fn open_chat(&mut self, username: String, ephemeral: bool) -> Result<(), ClientError> {
+        let username = normalize_username(&username)?;
+        if self.relay_blocked.contains(&username) {
+            self.set_relay_filter(&username, false).await?;
+        }
         let req = json!({
-            "who": username.clone(),
+            "username": username.clone(),
+            "typed": true,
+            "idempotency_key": Uuid::new_v4().to_string(),
         });
 
-        let response_json = self.send_encrypted_message(req).await?;
+        let response_json = self.send_with_retry(req).await?;
         let response = ServerResponse::from_json(response_json.to_string())
             .ok_or(ClientError::ServerResponseError)?;
         match response.code {
             ResponseCode::Ok => {
-                let pb = PreKeyBundle::try_from(response.text)?;
+                // Servers that predate the typed form answer with the legacy string
+                let pb = match serde_json::from_str::<PreKeyBundle>(&response.text) {
+                    Ok(pb) => pb,
+                    Err(_) => PreKeyBundle::try_from(response.text)?,
+                };
+                pb.verify()?;
+                pb.validate()?;
                 let (im, ek, dk) = process_prekey_bundle(
                     self.identity_key.clone(),
                     pb.clone()
                 )?;
-                let sk = SharedSecret::from((ek, dk));
-                let ratchet = Ratchet::init_alice(sk, pb.spk.clone());
-
-                self.friends.insert(username.clone(), Friend::new(ratchet, Some(pb.clone()), im.associated_data.clone()));
+                let sk = SharedSecret::from_session_keys(Role::Initiator, &ek, &dk);
+                let ratchet = Ratchet::init_alice(sk, pb.spk.clone()).with_aead_suite(im.aead_suite);
+
+                let mut friend = Friend::new(ratchet, Role::Initiator, pb.ik.clone(), im.associated_data.clone());
+                friend.ephemeral = ephemeral;
+                friend.one_time_prekey = im.one_time_key_hash.is_some() || im.one_time_key_id.is_some();
+                self.friends.insert(username.clone(), friend);
+                // Peers that do not advertise the JSON form only parse the legacy one
+                let initial_message = if pb.json_initial_message { im.to_json() } else { im.to_base64() };
                 let chat_message = ChatMessage::new(
                     "initial_message".to_string(),
                     username.clone(),
                     self.username.clone(),
-                    im.to_base64(),
+                    initial_message,
                     Utc::now()
                 );
-                self.send_chat_message(chat_message).await?;
+                self.send_encrypted(chat_message).await?;
+                if ephemeral {
+                    let settings = serde_json::to_string(&ChatSettings { ephemeral })
+                        .map_err(|_| ClientError::SerializationError)?;
+                    self.send_encrypted(ChatMessage::new(
+                        "chat_settings".to_string(),
+                        username.clone(),
+                        self.username.clone(),
+                        settings,
+                        Utc::now()
+                    )).await?;
+                }
                 Ok(())
             },
             ResponseCode::NotFound => {
@@ -254,31 +595,155 @@ impl Client {
         }
     }
 
+    /// Generates `n` one-time pre-keys and appends their public halves to the bundle stored by the server.
+    ///
+    /// No more keys are generated than bring the ones we hold to [`MAX_ONE_TIME_PREKEYS`], whatever the server asks for.
+    pub async fn upload_one_time_prekeys(&mut self, n: usize) -> Result<(), ClientError> {
+        let n = n.min(MAX_ONE_TIME_PREKEYS.saturating_sub(self.one_time_prekeys.len()));
+        if n == 0 {
+            return Ok(());
+        }
+        let otpks = (0..n)
+            .map(|_| {
+                let private = PrivateKey::new();
+                let key = PublicKey::from(&private);
+                let id = self.next_otpk_id;
+                self.next_otpk_id += 1;
+                // Keep the private halves even if the response is lost, the server may have stored them
+                self.one_time_prekeys.insert(id, private);
+                let sig = PreKeyBundle::sign_otpk(&self.identity_key, &key);
+                OneTimePreKey { key, id: Some(id), sig: Some(sig) }
+            })
+            .collect::<Vec<OneTimePreKey>>();
+        let req = json!({
+            "request_type": "upload_prekeys",
+            "otpk": otpks.iter().map(|otpk| otpk.key.to_base64()).collect::<Vec<String>>(),
+            "otpk_ids": otpks.iter().filter_map(|otpk| otpk.id).collect::<Vec<u32>>(),
+            "otpk_sigs": otpks.iter().filter_map(|otpk| otpk.sig.as_ref().map(Signature::to_base64)).collect::<Vec<String>>(),
+        });
+
+        let response_json = self.send_encrypted_message(req).await?;
+        let response = ServerResponse::from_json(response_json.to_string())
+            .ok_or(ClientError::ServerResponseError)?;
+        match response.code {
+            ResponseCode::Ok => {
+                for otpk in otpks {
+                    self.bundle.push_otpk(otpk);
+                }
+                Ok(())
+            }
+            _ => Err(ClientError::ServerResponseError),
+        }
+    }
+
+    /// Replaces the signed pre-key by a new one, signed with the identity key, in the bundle stored by the server.
+    ///
+    /// The previous key keeps accepting initial messages for the grace period set with
+    /// [`Client::set_signed_prekey_grace`], so sessions started from a bundle fetched before the rotation still work.
+    pub async fn rotate_signed_prekey(&mut self) -> Result<(), ClientError> {
+        let private = PrivateKey::new();
+        let mut bundle = self.bundle.clone();
+        bundle.replace_spk(&self.identity_key, PublicKey::from(&private), Utc::now().timestamp() as u64);
+        let req = json!({
+            "request_type": "upload_signed_prekey",
+            "spk": bundle.spk.to_base64(),
+            "sig": bundle.sig.to_base64(),
+            "created_at": bundle.spk_created_at,
+        });
+
+        let response_json = match self.send_encrypted_message(req).await {
+            Ok(response_json) => response_json,
+            Err(e) => {
+                // The server may have stored the new key, keep accepting it for a while
+                self.retired_signed_prekeys.push((private, Utc::now()));
+                return Err(e);
+            }
+        };
+        let response = ServerResponse::from_json(response_json.to_string())
+            .ok_or(ClientError::ServerResponseError)?;
+        match response.code {
+            ResponseCode::Ok => {
+                let now = Utc::now();
+                let grace = self.signed_prekey_grace;
+                self.retired_signed_prekeys.retain(|(_, retired_at)| now - *retired_at < grace);
+                let previous = std::mem::replace(&mut self.signed_prekey, private);
+                self.retired_signed_prekeys.push((previous, now));
+                self.bundle = bundle;
+                Ok(())
+            }
+            _ => Err(ClientError::ServerResponseError),
+        }
+    }
+
+    /// Sets how long a signed pre-key replaced by [`Client::rotate_signed_prekey`] still accepts initial messages.
+    pub fn set_signed_prekey_grace(&mut self, grace: Duration) {
+        self.signed_prekey_grace = grace;
+    }
+
+    /// Sets how often [`Client::rotate_signed_prekey_if_due`] replaces the signed pre-key.
+    /// `None` disables periodic rotation.
+    pub fn set_signed_prekey_rotation_interval(&mut self, interval: Option<Duration>) {
+        self.signed_prekey_rotation = interval;
+    }
+
+    /// Replaces the signed pre-key if it was signed longer ago than the rotation interval, a bundle without
+    /// a signing time counting as due. Returns whether the key was replaced.
+    pub async fn rotate_signed_prekey_if_due(&mut self) -> Result<bool, ClientError> {
+        let Some(interval) = self.signed_prekey_rotation else {
+            return Ok(false);
+        };
+        let age = self.bundle.spk_created_at
+            .and_then(|created_at| DateTime::from_timestamp(created_at as i64, 0))
+            .map(|created_at| Utc::now() - created_at);
+        match age {
+            Some(age) if age < interval => Ok(false),
+            _ => {
+                self.rotate_signed_prekey().await?;
+                Ok(true)
+            }
+        }
+    }
+
     async fn send_encrypted_message(&mut self, req: Value) -> Result<Value, ClientError> {
-        let request_id = Uuid::new_v4().to_string();
-        let wrapper = RequestWrapper{ request_id: request_id.clone(), body: req };
-        let serialized = serde_json::to_string(&wrapper)
-            .map_err(|_| ClientError::SerializationError)?;
+        self.send_request(Uuid::new_v4().to_string(), req).await
+    }
 
+    /// Sends an idempotent request, retrying with exponential backoff on transient failures.
+    /// The request body must carry an `idempotency_key` so the server does not repeat the operation.
+    async fn send_with_retry(&mut self, req: Value) -> Result<Value, ClientError> {
+        let mut attempt = 0;
+        loop {
+            match self.send_encrypted_message(req.clone()).await {
+                Err(e) if e.is_retryable() && attempt + 1 < self.retry.max_attempts => {
+                    debug!("Request failed ({}), retrying", e);
+                    tokio::time::sleep(self.retry.base_delay * 2u32.pow(attempt)).await;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
 
-        let enc = self.session
-            .get_encryption_key()
-            .unwrap()
-            .encrypt(
-                serialized.as_bytes(),
-                &self.session
-                    .get_associated_data()
-                    .unwrap()
-                    .to_bytes(),
-            )?;
+    pub fn set_retry_policy(&mut self, retry: RetryPolicy) {
+        self.retry = retry;
+    }
 
+    async fn send_request(&mut self, request_id: String, req: Value) -> Result<Value, ClientError> {
+        let wrapper = RequestWrapper{ request_id: request_id.clone(), body: req };
+        let serialized = to_zeroizing_json(&wrapper)?;
 
+        let enc = self.encrypt_for_server(serialized.as_bytes()).await?;
+        self.exchange(request_id, enc).await
+    }
+
+    /// Sends an encrypted frame and waits for the read loop to hand over the response to `request_id`.
+    async fn exchange(&mut self, request_id: String, enc: String) -> Result<Value, ClientError> {
         let (tx, rx) = oneshot::channel();
 
         {
             // Insert the sender into the HashMap so the read loop can find it
             let mut lock = self.pending.lock().await;
-            lock.insert(request_id, tx);
+            lock.insert(request_id.clone(), tx);
         }
 
 
@@ -288,7 +753,99 @@ impl Client {
             .map_err(|_| ClientError::SendError)?;
 
         // 7. Wait for the response from the read loop
-        rx.await.map_err(|_| ClientError::ServerResponseError)
+        match tokio::time::timeout(self.retry.timeout, rx).await {
+            Ok(response) => response.map_err(|_| ClientError::ServerResponseError),
+            Err(_) => {
+                self.pending.lock().await.remove(&request_id);
+                Err(ClientError::TimeoutError)
+            }
+        }
+    }
+
+    async fn encrypt_for_server(&self, data: &[u8]) -> Result<String, ClientError> {
+        let session = self.session.lock().await;
+        let ek = session.get_encryption_key().ok_or(ClientError::ServerResponseError)?;
+        let aad = session.get_associated_data().ok_or(ClientError::ServerResponseError)?;
+        Ok(ek.encrypt(data, &aad.to_bytes())?)
+    }
+
+    /// Asks the server to rotate the session keys of this connection.
+    /// Both sides derive the new keys from the current ones, see [`SessionKeys::rotate`].
+    pub async fn rotate_session_keys(&mut self) -> Result<(), ClientError> {
+        let request_id = Uuid::new_v4().to_string();
+        *self.rekey_request.lock().await = Some(request_id.clone());
+        let req = json!({
+            "request_type": "rekey",
+        });
+
+        let response_json = self.send_request(request_id, req).await?;
+        let response = ServerResponse::from_json(response_json.to_string())
+            .ok_or(ClientError::ServerResponseError)?;
+        match response.code {
+            ResponseCode::Ok => {
+                self.last_rotation = Utc::now();
+                Ok(())
+            }
+            _ => {
+                Err(ClientError::ServerResponseError)
+            }
+        }
+    }
+
+    pub async fn server_info(&mut self) -> Result<ServerInfo, ClientError> {
+        let req = json!({
+            "request_type": "server_info",
+        });
+
+        let response_json = self.send_encrypted_message(req).await?;
+        let response = ServerResponse::from_json(response_json.to_string())
+            .ok_or(ClientError::ServerResponseError)?;
+        match response.code {
+            ResponseCode::Ok => {
+                serde_json::from_str(&response.text).map_err(|_| ClientError::SerializationError)
+            }
+            _ => {
+                Err(ClientError::ServerResponseError)
+            }
+        }
+    }
+
+    /// Asks the server which of `users` are online, answering for each of them as written.
+    pub async fn get_presence(&mut self, users: Vec<String>) -> Result<HashMap<String, bool>, ClientError> {
+        let req = json!({
+            "request_type": "presence",
+            "usernames": users,
+        });
+
+        let response_json = self.send_encrypted_message(req).await?;
+        let response = ServerResponse::from_json(response_json.to_string())
+            .ok_or(ClientError::ServerResponseError)?;
+        match response.code {
+            ResponseCode::Ok => {
+                serde_json::from_str(&response.text).map_err(|_| ClientError::SerializationError)
+            }
+            _ => {
+                Err(ClientError::ServerResponseError)
+            }
+        }
+    }
+
+    /// Sets how often [`Client::rotate_session_keys_if_due`] rotates the session keys.
+    /// `None` disables periodic rotation.
+    pub fn set_session_rotation_interval(&mut self, interval: Option<Duration>) {
+        self.rotation = interval;
+    }
+
+    /// Rotates the session keys if the rotation interval has elapsed since the last rotation.
+    /// Returns whether the keys were rotated.
+    pub async fn rotate_session_keys_if_due(&mut self) -> Result<bool, ClientError> {
+        match self.rotation {
+            Some(interval) if Utc::now() - self.last_rotation >= interval => {
+                self.rotate_session_keys().await?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
     }
 
     pub fn set_username(&mut self, username: String) {
@@ -299,6 +856,7 @@ impl Client {
         if let Some(listener) = self.listener.take() {
             listener.abort();
         }
+        self.connected.store(false, Ordering::SeqCst);
         self.write.close().await.expect("Failed to close connection");
     }
 
@@ -306,89 +864,409 @@ impl Client {
         self.username != "".to_string()
     }
 
-    pub async fn send_chat_message(&mut self, mut message: ChatMessage) -> Result<(), ClientError> {
-        if message.msg_type != "initial_message".to_string() {
-            let mut friend = self.friends.get_mut(&message.to);
-            if let Some(friend) = friend {
-               let aad = friend.get_friend_aad();
-                message.text = friend.ratchet.encrypt(
-                    message.text.as_bytes(),
-                    &aad.to_bytes(),
-                )?;
-            } else {
-                return Err(ClientError::UserNotFoundError);
-            }
-        }
-        let mut req = serde_json::to_value(message)
-            .map_err(|_| ClientError::SerializationError)?;
+    /// Encrypts and sends `message`, and waits for the server to tell what became of it. The plaintext text and
+    /// the serialized request are wiped once encrypted, callers keeping the message in the history
+    /// (see [`Client::add_chat_message`]) hold the only copy left.
+    ///
+    /// If no answer comes in time the message may still have been relayed, and the ratchet moved on regardless.
+    pub async fn send_chat_message(&mut self, message: ChatMessage) -> Result<DeliveryStatus, ClientError> {
+        self.send_acknowledged(message).await.map(|(status, _)| status)
+    }
 
-        let enc = self.session
-                .get_encryption_key()
-                .unwrap()
-                .encrypt(
-                    req.to_string().as_bytes(),
-                    &self.session
-                        .get_associated_data()
-                        .unwrap()
-                        .to_bytes(),
-                )?;
+    /// Sends a chat message like [`Client::send_chat_message`] and appends it to the history
+    /// along with how it was encrypted.
+    pub async fn send_and_store_chat_message(&mut self, message: ChatMessage) -> Result<DeliveryStatus, ClientError> {
+        let mut stored = message.clone();
+        let (status, meta) = self.send_acknowledged(message).await?;
+        stored.meta = meta;
+        let to = stored.to.clone();
+        self.add_chat_message(stored, &to);
+        Ok(status)
+    }
+
+    /// Encrypts and sends `message` without waiting for the server, returning how it was encrypted
+    /// unless it is a handshake message.
+    async fn send_encrypted(&mut self, mut message: ChatMessage) -> Result<Option<EncryptionMeta>, ClientError> {
+        let meta = self.encrypt_chat_message(&mut message)?;
+        let req = to_zeroizing_json(&message)?;
+
+        let enc = self.encrypt_for_server(req.as_bytes()).await?;
 
         self.write
                 .send(Message::Text(Utf8Bytes::from(enc)))
                 .await
                 .map_err(|_| ClientError::SendError)?;
 
-        Ok(())
+        Ok(meta)
+    }
+
+    /// Encrypts and sends `message` with a request id, and waits for the server to acknowledge it.
+    async fn send_acknowledged(&mut self, mut message: ChatMessage) -> Result<(DeliveryStatus, Option<EncryptionMeta>), ClientError> {
+        let meta = self.encrypt_chat_message(&mut message)?;
+        let request_id = Uuid::new_v4().to_string();
+        let req = to_zeroizing_json(&AcknowledgedMessage { message: &message, request_id: &request_id })?;
+
+        let enc = self.encrypt_for_server(req.as_bytes()).await?;
+        let response_json = self.exchange(request_id, enc).await?;
+        let response = ServerResponse::from_json(response_json.to_string())
+            .ok_or(ClientError::ServerResponseError)?;
+        let status = match response.code {
+            ResponseCode::Ok if response.text == "Queued" => DeliveryStatus::Queued,
+            ResponseCode::Ok => DeliveryStatus::Delivered,
+            ResponseCode::Forbidden => DeliveryStatus::Refused,
+            _ => return Err(ClientError::ServerResponseError),
+        };
+        Ok((status, meta))
+    }
+
+    /// Encrypts the text of `message` with the ratchet of its recipient, returning how it was encrypted.
+    /// Handshake messages are left as they are.
+    fn encrypt_chat_message(&mut self, message: &mut ChatMessage) -> Result<Option<EncryptionMeta>, ClientError> {
+        // The metadata is only kept in the history, the header already carries it
+        message.meta = None;
+        // Handshake messages travel before (or instead of) a ratchet
+        if message.msg_type == "initial_message" || message.msg_type == "session_rejected" {
+            return Ok(None);
+        }
+        let friend = self.friends.get_mut(&message.to).ok_or(ClientError::UserNotFoundError)?;
+        let aad = friend.get_friend_aad();
+        let chat = message.msg_type == "chat";
+        let plaintext = match message.group_id.take() {
+            // The id of the group is only sent encrypted, see `groups`
+            Some(group_id) if chat => {
+                message.msg_type = "group_chat".to_string();
+                to_zeroizing_json(&groups::GroupPayload { group_id, text: std::mem::take(&mut message.text) })?
+            }
+            _ => Zeroizing::new(std::mem::take(&mut message.text)),
+        };
+        let (ciphertext, ratchet) = friend.ratchet.encrypt_with_meta(
+            plaintext.as_bytes(),
+            &aad.to_bytes(),
+        )?;
+        message.text = ciphertext;
+        if chat {
+            friend.messages_sent += 1;
+        }
+        Ok(Some(EncryptionMeta { one_time_prekey: friend.one_time_prekey, ratchet }))
     }
 
 
     pub fn add_friend(&mut self, message: ChatMessage) -> Result<(), ClientError> {
+        if self.blocked.contains(&message.from) {
+            return Err(ClientError::BlockedUser(message.from));
+        }
 
         let im = InitialMessage::try_from(message.text.clone())?;
-        let otpk_used = self.one_time_prekeys.get(
-            &im.one_time_key_hash
-                .clone()
-                .unwrap()
-        );
-        let (ek, dk) = process_initial_message(
+        let (spk, spk_public) = self.signed_prekey_used(&im)?;
+        let otpk_used = self.one_time_prekey(&im)?;
+        // The friend must have adopted the suite of our bundle
+        let (ek, dk) = process_initial_message_with_suite(
             self.identity_key.clone(),
-            self.signed_prekey.clone(),
-            otpk_used.cloned(),
+            spk.clone(),
+            otpk_used.clone().map(|(_, key)| key),
+            self.bundle.aead_suite,
             im.clone()
         )?;
 
-        let sk = SharedSecret::from((dk, ek));
-        let keypair = RatchetKeyPair::new_from(
-            self.signed_prekey.clone(),
-            self.bundle.spk.clone(),
-        );
-        let ratchet = Ratchet::init_bob(sk, keypair);
+        let sk = SharedSecret::from_session_keys(Role::Responder, &ek, &dk);
+        let keypair = RatchetKeyPair::new_from(spk, spk_public);
+        let ratchet = Ratchet::init_bob(sk, keypair).with_aead_suite(im.aead_suite);
 
-        let friend = Friend::new(ratchet, None, im.associated_data.clone());
+        let mut friend = Friend::new(ratchet, Role::Responder, im.identity_key.clone(), im.associated_data.reversed());
+        friend.one_time_prekey = otpk_used.is_some();
+        // A one-time pre-key is used once
+        if let Some((id, _)) = otpk_used {
+            self.one_time_prekeys.remove(&id);
+        }
+        // A friend who lost track of the session starts a new one, see `Client::reset_session`
+        if let Some(previous) = self.friends.remove(&message.from) {
+            friend.inherit(previous);
+        }
         self.friends.insert(message.from, friend);
         Ok(())
     }
 
+    /// Returns the signed pre-key used by `im`: the current one, or one replaced less than the grace period ago.
+    fn signed_prekey_used(&self, im: &InitialMessage) -> Result<(PrivateKey, PublicKey), ClientError> {
+        if im.prekey_hash == self.bundle.spk.hash() {
+            return Ok((self.signed_prekey.clone(), self.bundle.spk.clone()));
+        }
+        let now = Utc::now();
+        self.retired_signed_prekeys
+            .iter()
+            .filter(|(_, retired_at)| now - *retired_at < self.signed_prekey_grace)
+            .map(|(key, _)| (key.clone(), PublicKey::from(key)))
+            .find(|(_, public)| public.hash() == im.prekey_hash)
+            .ok_or(ClientError::SessionRejected(SessionRejection::UnknownPreKey))
+    }
+
+    /// Returns the one-time pre-key used by `im` with its id, or `None` if it used none.
+    ///
+    /// The key is looked up by id, or by hash for initial messages built from a bundle without ids.
+    fn one_time_prekey(&self, im: &InitialMessage) -> Result<Option<(u32, PrivateKey)>, ClientError> {
+        let found = match (im.one_time_key_id, &im.one_time_key_hash) {
+            (Some(id), _) => self.one_time_prekeys.get_key_value(&id),
+            (None, Some(hash)) => self.one_time_prekeys.iter().find(|(_, k)| &PublicKey::from(*k).hash() == hash),
+            (None, None) => return Ok(None),
+        };
+        found
+            .map(|(id, key)| Some((*id, key.clone())))
+            .ok_or(ClientError::SessionRejected(SessionRejection::UnknownPreKey))
+    }
+
+    /// Establishes the session requested by an `initial_message`, or tells the sender why it was refused.
+    ///
+    /// Sessions requested by a blocked user are refused without an answer.
+    pub async fn accept_initial_message(&mut self, message: ChatMessage) -> Result<(), ClientError> {
+        let from = message.from.clone();
+        if let Err(e) = self.add_friend(message) {
+            if matches!(e, ClientError::BlockedUser(_)) {
+                return Err(e);
+            }
+            let reason = SessionRejection::from(&e);
+            debug!("Rejecting session with {}: {}", from, reason);
+            self.send_encrypted(ChatMessage::new(
+                "session_rejected".to_string(),
+                from,
+                self.username.clone(),
+                reason.code().to_string(),
+                Utc::now()
+            )).await?;
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Drops the session we initiated with the sender of a `session_rejected` message.
+    ///
+    /// Returns the reason if a session was dropped. Sessions where the friend already
+    /// answered are established on both sides and are left untouched.
+    pub fn session_rejected(&mut self, message: ChatMessage) -> Option<SessionRejection> {
+        let pending = self.friends.get(&message.from)
+            .is_some_and(|f| f.role == Role::Initiator && f.messages_received == 0);
+        if !pending {
+            return None;
+        }
+        self.friends.remove(&message.from);
+        Some(SessionRejection::from_code(&message.text))
+    }
+
+    /// Applies the settings the friend chose for the chat. An ephemeral chat stays ephemeral.
+    pub fn apply_chat_settings(&mut self, message: ChatMessage) -> Result<(), ClientError> {
+        if message.from == self.username {
+            return Err(ClientError::ReflectedMessageError);
+        }
+        let friend = self.friends.get_mut(&message.from).ok_or(ClientError::UserNotFoundError)?;
+        let text = friend.decrypt_inbound(message.text)?;
+        let settings: ChatSettings = serde_json::from_slice(&text)
+            .map_err(|_| ClientError::SerializationError)?;
+        friend.ephemeral |= settings.ephemeral;
+        Ok(())
+    }
+
+    /// Returns `true` if the history of the chat with `friend` is kept in memory only.
+    pub fn is_ephemeral(&self, friend: &str) -> bool {
+        self.friends.get(friend).is_some_and(|f| f.ephemeral)
+    }
+
     pub fn add_chat_message(&mut self, message: ChatMessage, friend: &str) {
         if let Some(friend) = self.friends.get_mut(friend) {
             friend.add_message(message);
         }
     }
 
-    pub fn decrypt_chat_message(&mut self, mut message: ChatMessage) -> Result<(), ClientError> {
+    /// Removes the message sent at `timestamp` from the history of the chat with `friend`, zeroizing its text.
+    /// Only the first message with that timestamp is removed, even if others share it.
+    /// Returns `false` if there is no such message.
+    pub fn delete_message(&mut self, friend: &str, timestamp: &str) -> bool {
+        let Some(friend) = self.friends.get_mut(friend) else { return false };
+        match friend.chat.iter().position(|m| m.timestamp == timestamp) {
+            Some(i) => {
+                friend.chat.remove(i).text.zeroize();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Decrypts a message from a friend and appends it to the history, which keeps the plaintext on purpose.
+    ///
+    /// Messages from a blocked user are dropped before decryption, so the ratchet does not advance:
+    /// once unblocked, the keys of the dropped messages are skipped like those of lost ones.
+    ///
+    /// Returns the chat the message was added to: the id of its group for a `group_chat` message,
+    /// the username of the sender otherwise.
+    pub fn decrypt_chat_message(&mut self, mut message: ChatMessage) -> Result<String, ClientError> {
+        if message.from == self.username {
+            return Err(ClientError::ReflectedMessageError);
+        }
+        if self.blocked.contains(&message.from) {
+            return Err(ClientError::BlockedUser(message.from));
+        }
         let mut friend = self.friends.get_mut(&message.from);
 
         if let Some(friend) = friend {
-            let text = friend.ratchet.decrypt(message.text)?;
-            message.text = String::from_utf8(text)?;
+            let (text, ratchet) = match friend.decrypt_inbound_with_meta(message.text) {
+                Ok(decrypted) => decrypted,
+                Err(ClientError::ProtocolError(ProtocolError::Ratchet(RatchetError::MessagesLost(gap)))) => {
+                    // The message authenticated, so the missed messages are gone for good: say so in the chat.
+                    // A forged header is rejected with `MaxSkipsExceeded` instead and leaves no trace
+                    let lost = ClientError::MessagesLost(gap);
+                    friend.add_message(ChatMessage::system_event(message.from, lost.to_string()));
+                    return Err(lost);
+                }
+                Err(e) => return Err(e),
+            };
+            message.meta = Some(EncryptionMeta { one_time_prekey: friend.one_time_prekey, ratchet });
+            // The copy kept in the history is the only one left once `text` is dropped
+            let text = std::str::from_utf8(&text)
+                .map_err(|_| ClientError::GenericError("Failed to decode utf8".to_string()))?;
+            // A group id sent in the clear is not trusted, it is only read from the encrypted text
+            message.group_id = None;
+            if message.msg_type == "group_chat" {
+                let payload: groups::GroupPayload = serde_json::from_str(text).map_err(|_| ClientError::SerializationError)?;
+                message.msg_type = "chat".to_string();
+                message.group_id = Some(payload.group_id);
+                message.text = payload.text;
+            } else {
+                message.text = text.to_string();
+            }
+            friend.messages_received += 1;
 
-            self.add_chat_message(message.clone(), &message.from);
-            Ok(())
+            if let Some(group_id) = message.group_id.clone() {
+                match self.add_group_message(message.clone(), &group_id) {
+                    // The ratchet has moved on, so the message is kept in the chat with its sender rather than lost
+                    Err(ClientError::UnknownGroup(_)) => message.group_id = None,
+                    result => return result.map(|_| group_id),
+                }
+            }
+            let from = message.from.clone();
+            if let Some(friend) = self.friends.get_mut(&from) {
+                friend.unread += 1;
+            }
+            self.add_chat_message(message, &from);
+            Ok(from)
         } else {
             Err(ClientError::UserNotFoundError)
         }
     }
 
+    /// Replaces the session with `username` by a new one, keeping the history and settings of the chat.
+    /// The friend accepts the `initial_message` like any other, see [`Client::add_friend`].
+    ///
+    /// The current session is kept if the new one cannot be started. It is never reset on its own:
+    /// once messages are lost, see [`ClientError::MessagesLost`], the user decides when to start over.
+    pub async fn reset_session(&mut self, username: &str) -> Result<(), ClientError> {
+        let previous = self.friends.remove(username).ok_or(ClientError::UserNotFoundError)?;
+        if let Err(e) = self.open_chat(username.to_string(), previous.ephemeral).await {
+            self.friends.insert(username.to_string(), previous);
+            return Err(e);
+        }
+        if let Some(friend) = self.friends.get_mut(username) {
+            friend.inherit(previous);
+        }
+        Ok(())
+    }
+
+    /// Tells `friend` that we read the message they sent at `message_ts`.
+    pub async fn send_read_receipt(&mut self, friend: &str, message_ts: String) -> Result<(), ClientError> {
+        self.send_encrypted(ChatMessage::new(
+            "read_receipt".to_string(),
+            friend.to_string(),
+            self.username.clone(),
+            message_ts,
+            Utc::now()
+        )).await.map(|_| ())
+    }
+
+    /// Marks the chat with `friend` as read, sending a receipt for every message not acknowledged yet.
+    pub async fn send_read_receipts(&mut self, friend: &str) -> Result<(), ClientError> {
+        self.mark_read(friend);
+        let pending = match self.friends.get_mut(friend) {
+            Some(f) => f.chat
+                .iter_mut()
+                .filter(|m| m.from == friend && !m.read)
+                .map(|m| {
+                    m.read = true;
+                    m.timestamp.clone()
+                })
+                .collect::<Vec<_>>(),
+            None => return Err(ClientError::UserNotFoundError),
+        };
+        for timestamp in pending {
+            self.send_read_receipt(friend, timestamp).await?;
+        }
+        Ok(())
+    }
+
+    /// Marks the message acknowledged by a `read_receipt` as read. The receipt itself is not kept in the history.
+    ///
+    /// Returns `false` if no message we sent has the acknowledged timestamp.
+    pub fn apply_read_receipt(&mut self, message: ChatMessage) -> Result<bool, ClientError> {
+        if message.from == self.username {
+            return Err(ClientError::ReflectedMessageError);
+        }
+        let username = self.username.clone();
+        let friend = self.friends.get_mut(&message.from).ok_or(ClientError::UserNotFoundError)?;
+        let timestamp = friend.decrypt_inbound(message.text)?;
+        match friend.chat.iter_mut().find(|m| m.from == username && m.timestamp.as_bytes() == timestamp.as_slice()) {
+            Some(acknowledged) => {
+                acknowledged.read = true;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Tells `friend` that we are typing. The notification is not kept in the history.
+    pub async fn send_typing(&mut self, friend: &str) -> Result<(), ClientError> {
+        self.send_encrypted(ChatMessage::new(
+            "typing".to_string(),
+            friend.to_string(),
+            self.username.clone(),
+            String::new(),
+            Utc::now()
+        )).await.map(|_| ())
+    }
+
+    /// Consumes a `typing` notification and returns who is typing.
+    ///
+    /// Returns `None` if we have no chat with the sender, e.g. because we closed it.
+    pub fn apply_typing(&mut self, message: ChatMessage) -> Result<Option<String>, ClientError> {
+        if message.from == self.username {
+            return Err(ClientError::ReflectedMessageError);
+        }
+        let Some(friend) = self.friends.get_mut(&message.from) else { return Ok(None) };
+        // Decrypted only to keep the ratchet in step with the friend
+        friend.decrypt_inbound(message.text)?;
+        Ok(Some(message.from))
+    }
+
+    /// Rotates our ratchet key in the chat with `friend` and tells them at once with an empty `rekey` message,
+    /// which carries the new key, for instance after a suspected compromise of this device. See [`Ratchet::force_rekey`].
+    pub async fn rekey_session(&mut self, friend: &str) -> Result<(), ClientError> {
+        self.friends.get_mut(friend).ok_or(ClientError::UserNotFoundError)?.ratchet.force_rekey();
+        self.send_encrypted(ChatMessage::new(
+            "rekey".to_string(),
+            friend.to_string(),
+            self.username.clone(),
+            String::new(),
+            Utc::now()
+        )).await.map(|_| ())
+    }
+
+    /// Consumes a `rekey` message, following the ratchet key rotation of its sender. It is not kept in the history.
+    ///
+    /// Returns `false` if we have no chat with the sender.
+    pub fn apply_rekey(&mut self, message: ChatMessage) -> Result<bool, ClientError> {
+        if message.from == self.username {
+            return Err(ClientError::ReflectedMessageError);
+        }
+        let Some(friend) = self.friends.get_mut(&message.from) else { return Ok(false) };
+        friend.decrypt_inbound(message.text)?;
+        Ok(true)
+    }
+
     pub fn get_chat_history(&self, username: &str) -> Option<Vec<ChatMessage>> {
         self.friends.get(username).map(|f| &f.chat).cloned()
     }
@@ -399,16 +1277,55 @@ impl Client {
 
     pub async fn close_chat(&mut self, f: String) -> Result<(), ClientError> {
 
-        self.send_chat_message(ChatMessage::new(
+        self.send_encrypted(ChatMessage::new(
             "close_chat".to_string(),
             f.clone(),
             self.username.clone(),
             "".to_string(),
             Utc::now()
         )).await?;
+        self.set_relay_filter(&f, true).await?;
         Ok(())
     }
 
+    /// Asks the server to stop (or resume) relaying messages from `from` to us.
+    pub async fn set_relay_filter(&mut self, from: &str, blocked: bool) -> Result<(), ClientError> {
+        let req = json!({
+            "request_type": "relay_filter",
+            "from": from,
+            "blocked": blocked,
+        });
+        let response_json = self.send_with_retry(req).await?;
+        let response = ServerResponse::from_json(response_json.to_string())
+            .ok_or(ClientError::ServerResponseError)?;
+        match response.code {
+            ResponseCode::Ok => {
+                if blocked {
+                    self.relay_blocked.insert(from.to_string());
+                } else {
+                    self.relay_blocked.remove(from);
+                }
+                Ok(())
+            }
+            _ => Err(ClientError::ServerResponseError),
+        }
+    }
+
+    /// Drops every message and session request from `username` from now on, without telling them.
+    /// The chat with them, if any, is kept.
+    pub fn block_user(&mut self, username: String) {
+        self.blocked.insert(username);
+    }
+
+    /// Accepts messages from `username` again.
+    pub fn unblock_user(&mut self, username: &str) {
+        self.blocked.remove(username);
+    }
+
+    pub fn is_blocked(&self, username: &str) -> bool {
+        self.blocked.contains(username)
+    }
+
     pub fn remove_friend(&mut self, f: String) {
         self.friends.remove(&f);
     }
@@ -416,6 +1333,250 @@ impl Client {
     pub fn get_friends_count(&self) -> usize {
         return self.friends.len();
     }
+
+    pub fn set_retention_policy(&mut self, policy: Option<RetentionPolicy>) {
+        self.retention = policy;
+    }
+
+    /// Opts a single chat in or out of the retention policy. Chats are never
+    /// auto-closed unless they have been explicitly opted in.
+    pub fn set_auto_close(&mut self, friend: &str, enabled: bool) -> Result<(), ClientError> {
+        let friend = self.friends.get_mut(friend).ok_or(ClientError::UserNotFoundError)?;
+        friend.auto_close = enabled;
+        Ok(())
+    }
+
+    pub fn is_auto_close(&self, friend: &str) -> bool {
+        self.friends.get(friend).map(|f| f.auto_close).unwrap_or(false)
+    }
+
+    /// Returns the opted-in chats that have been idle for longer than the retention policy allows.
+    pub fn idle_chats(&self, now: DateTime<Utc>) -> Vec<String> {
+        match &self.retention {
+            Some(policy) => self.friends
+                .iter()
+                .filter(|(_, f)| f.auto_close && now - f.last_activity > policy.max_idle)
+                .map(|(name, _)| name.clone())
+                .collect(),
+            None => vec![],
+        }
+    }
+
+    /// Sends a `close_chat` for every idle chat and tears down its ratchet, returning the chats closed.
+    /// The chat history is moved to the archive, see [`Client::get_archived_history`].
+    ///
+    /// A chat that cannot be closed stays open, to be tried again by the next call, and does not keep
+    /// the others open. The error of the first is returned if no idle chat could be closed.
+    pub async fn close_idle_chats(&mut self) -> Result<Vec<String>, ClientError> {
+        let mut closed = vec![];
+        let mut failure = None;
+        for f in self.idle_chats(Utc::now()) {
+            match self.close_chat(f.clone()).await {
+                Ok(()) => {
+                    self.archive_friend(&f);
+                    closed.push(f);
+                }
+                Err(e) => {
+                    error!("Failed to close the idle chat with {}: {}", f, e);
+                    failure.get_or_insert(e);
+                }
+            }
+        }
+        match failure {
+            Some(e) if closed.is_empty() => Err(e),
+            _ => Ok(closed),
+        }
+    }
+
+    fn archive_friend(&mut self, f: &str) {
+        // Ephemeral history is wiped rather than archived
+        if let Some(mut friend) = self.friends.remove(f).filter(|friend| !friend.ephemeral) {
+            self.archived_chats
+                .entry(f.to_string())
+                .or_default()
+                .extend(std::mem::take(&mut friend.chat));
+        }
+    }
+
+    pub fn get_archived_history(&self, username: &str) -> Option<Vec<ChatMessage>> {
+        self.archived_chats.get(username).cloned()
+    }
+
+    /// Returns the number of messages received from `friend` since the chat was last read.
+    pub fn unread_count(&self, friend: &str) -> usize {
+        self.friends.get(friend).map(|f| f.unread).unwrap_or(0)
+    }
+
+    pub fn mark_read(&mut self, friend: &str) {
+        if let Some(friend) = self.friends.get_mut(friend) {
+            friend.unread = 0;
+        }
+    }
+
+    /// Returns the unread messages across all chats, muted chats excluded, and groups.
+    pub fn total_unread(&self) -> usize {
+        let friends: usize = self.friends
+            .values()
+            .filter(|f| !f.muted)
+            .map(|f| f.unread)
+            .sum();
+        friends + self.groups.values().map(|g| g.unread).sum::<usize>()
+    }
+
+    pub fn set_muted(&mut self, friend: &str, muted: bool) -> Result<(), ClientError> {
+        let friend = self.friends.get_mut(friend).ok_or(ClientError::UserNotFoundError)?;
+        friend.muted = muted;
+        Ok(())
+    }
+
+    pub fn is_muted(&self, friend: &str) -> bool {
+        self.friends.get(friend).map(|f| f.muted).unwrap_or(false)
+    }
+
+    /// Returns a read-only view of the chat with `username`, without any key material.
+    pub fn friend_info(&self, username: &str) -> Option<FriendInfo> {
+        self.friends.get(username).map(|f| FriendInfo {
+            username: username.to_string(),
+            established_at: f.established_at,
+            role: f.role,
+            fingerprint: f.fingerprint(),
+            messages_sent: f.messages_sent,
+            messages_received: f.messages_received,
+            verified: f.verified,
+            skipped_keys: f.ratchet.skipped_key_count(),
+            ephemeral: f.ephemeral,
+        })
+    }
+
+    /// Returns the counters and ratchet keys of the session with `username`, to diagnose out-of-order delivery.
+    pub fn session_info(&self, username: &str) -> Option<SessionInfo> {
+        self.friends.get(username).map(|f| SessionInfo {
+            messages_sent: f.ratchet.messages_sent(),
+            messages_received: f.ratchet.messages_received(),
+            previous_chain_length: f.ratchet.previous_chain_length(),
+            skipped_keys: f.ratchet.skipped_key_count(),
+            sending_key: f.ratchet.state_snapshot().sending_key,
+            remote_key: f.ratchet.current_remote_key().cloned(),
+        })
+    }
+
+    /// Records whether the identity key of `friend` has been verified out of band.
+    pub fn set_verified(&mut self, friend: &str, verified: bool) -> Result<(), ClientError> {
+        let friend = self.friends.get_mut(friend).ok_or(ClientError::UserNotFoundError)?;
+        friend.verified = verified;
+        Ok(())
+    }
+
+    /// Returns the safety number of the chat with `friend`, which both users compare out of band before
+    /// marking the chat verified, see [`Client::verification_code`].
+    pub fn get_safety_number(&self, friend: &str) -> Result<String, ClientError> {
+        self.verification_code(friend).ok_or(ClientError::UserNotFoundError)
+    }
+
+    /// Returns the code both users of the chat with `friend` compare to verify it, or `None` without a chat.
+    ///
+    /// The code is derived from both identity keys with their usernames, taken in the order of the bytes of
+    /// the keys, so that it is the same whichever side computes it.
+    pub fn verification_code(&self, friend: &str) -> Option<String> {
+        let aad = &self.friends.get(friend)?.aad;
+        // Our identity key comes first in the associated data, whichever role we had
+        let mut parties = [(aad.initiator_identity_key(), self.username.as_str()), (aad.responder_identity_key(), friend)];
+        parties.sort_by(|(a, _), (b, _)| a.as_ref().cmp(b.as_ref()));
+        let [(first_ik, first_name), (second_ik, second_name)] = parties;
+        Some(compute_safety_number(first_ik, first_name, second_ik, second_name))
+    }
+}
+
+/// A read-only view of an open chat, returned by [`Client::friend_info`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct FriendInfo {
+    pub username: String,
+    /// When the X3DH handshake with the friend completed.
+    pub established_at: DateTime<Utc>,
+    /// Our role in the handshake.
+    pub role: Role,
+    /// Hex-encoded SHA-256 hash of the friend's identity key.
+    pub fingerprint: String,
+    pub messages_sent: usize,
+    pub messages_received: usize,
+    /// Whether the fingerprint has been verified out of band.
+    pub verified: bool,
+    /// Message keys kept for messages that have not arrived yet.
+    pub skipped_keys: usize,
+    /// Whether the chat is kept in memory only, see [`Client::open_chat`].
+    pub ephemeral: bool,
+}
+
+/// The state of the ratchet of an open chat, returned by [`Client::session_info`]. None of it is secret.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SessionInfo {
+    /// Messages sent in the current sending chain.
+    pub messages_sent: u64,
+    /// Messages received in the current receiving chain.
+    pub messages_received: u64,
+    /// Messages sent in the previous sending chain.
+    pub previous_chain_length: u64,
+    /// Message keys kept for messages that have not arrived yet.
+    pub skipped_keys: usize,
+    /// Our current ratchet public key.
+    pub sending_key: PublicKey,
+    /// The friend's current ratchet public key, which changes at every DH ratchet step.
+    /// `None` until the friend's first message arrives when we responded to the handshake.
+    pub remote_key: Option<PublicKey>,
+}
+
+/// How requests that are safe to repeat are retried after a transient failure.
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    /// Total number of attempts, including the first one.
+    pub max_attempts: u32,
+    /// Delay before the first retry, doubled at each further attempt.
+    pub base_delay: std::time::Duration,
+    /// How long to wait for a response before the attempt is considered failed.
+    pub timeout: std::time::Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            base_delay: std::time::Duration::from_millis(200),
+            timeout: std::time::Duration::from_secs(10),
+        }
+    }
+}
+
+/// How [`Client::reconnect`] retries after the connection to the server is lost.
+#[derive(Clone, Debug)]
+pub struct ReconnectPolicy {
+    /// Total number of attempts, including the first one.
+    pub max_attempts: u32,
+    /// Delay before the second attempt, doubled at each further attempt.
+    pub base_delay: std::time::Duration,
+    /// The longest delay between two attempts.
+    pub max_delay: std::time::Duration,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 6,
+            base_delay: std::time::Duration::from_millis(500),
+            max_delay: std::time::Duration::from_secs(30),
+        }
+    }
+}
+
+/// Closes chats that have been idle for longer than `max_idle`, freeing their ratchet state.
+#[derive(Clone, Debug)]
+pub struct RetentionPolicy {
+    pub max_idle: Duration,
+}
+
+impl RetentionPolicy {
+    pub fn new(max_idle: Duration) -> Self {
+        Self { max_idle }
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -424,7 +1585,45 @@ pub struct ChatMessage {
     pub from: String,
     pub to: String,
     pub text: String,
-    pub timestamp: String
+    pub timestamp: String,
+    /// For messages we sent, the friend acknowledged them with a `read_receipt`.
+    /// For messages we received, we sent one.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub read: bool,
+    /// How the message was encrypted, kept in the history only.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub meta: Option<EncryptionMeta>,
+    /// The group the message was sent to, see [`groups`]. Kept in the history only, it is sent encrypted.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group_id: Option<String>,
+}
+
+/// How a message in the history was encrypted, see [`MessageMeta`].
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct EncryptionMeta {
+    /// Whether the session was established with a one-time prekey.
+    pub one_time_prekey: bool,
+    #[serde(flatten)]
+    pub ratchet: MessageMeta,
+}
+
+/// A [`ChatMessage`] sent with a request id, which the server answers with a [`DeliveryStatus`].
+#[derive(Serialize)]
+struct AcknowledgedMessage<'a> {
+    #[serde(flatten)]
+    message: &'a ChatMessage,
+    request_id: &'a str,
+}
+
+/// What the server did with a message sent by [`Client::send_chat_message`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DeliveryStatus {
+    /// The message was handed to the connection of the recipient.
+    Delivered,
+    /// The recipient is offline, the message is kept until they connect.
+    Queued,
+    /// The recipient closed the chat and does not take messages from us.
+    Refused,
 }
 
 impl ChatMessage {
@@ -434,7 +1633,79 @@ impl ChatMessage {
             to,
             from,
             text,
-            timestamp: timestamp.to_rfc3339()
+            timestamp: timestamp.to_rfc3339(),
+            read: false,
+            meta: None,
+            group_id: None,
+        }
+    }
+
+    /// A `system_event` written by the client into the history of the chat with `to`, never sent.
+    pub fn system_event(to: String, text: String) -> Self {
+        Self::new("system_event".to_string(), to, String::new(), text, Utc::now())
+    }
+}
+
+/// Settings of a chat, sent encrypted in the text of a `chat_settings` message.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ChatSettings {
+    /// Neither side writes the chat to disk.
+    pub ephemeral: bool,
+}
+
+/// Why a responder refused an `initial_message`, carried in the text of a `session_rejected` message.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SessionRejection {
+    /// The initial message could not be parsed.
+    MalformedMessage,
+    /// The one-time pre-key it refers to is unknown, or was already used.
+    UnknownPreKey,
+    /// The key agreement failed, so the challenge did not decrypt.
+    InvalidChallenge,
+    /// A reason this client does not know about.
+    Unknown,
+}
+
+impl SessionRejection {
+    pub fn code(&self) -> &'static str {
+        match self {
+            SessionRejection::MalformedMessage => "malformed_message",
+            SessionRejection::UnknownPreKey => "unknown_prekey",
+            SessionRejection::InvalidChallenge => "invalid_challenge",
+            SessionRejection::Unknown => "unknown",
+        }
+    }
+
+    pub fn from_code(code: &str) -> Self {
+        match code {
+            "malformed_message" => SessionRejection::MalformedMessage,
+            "unknown_prekey" => SessionRejection::UnknownPreKey,
+            "invalid_challenge" => SessionRejection::InvalidChallenge,
+            _ => SessionRejection::Unknown,
+        }
+    }
+}
+
+impl From<&ClientError> for SessionRejection {
+    fn from(value: &ClientError) -> Self {
+        match value {
+            ClientError::SessionRejected(reason) => *reason,
+            ClientError::ProtocolError(ProtocolError::X3DH(
+                X3DHError::InvalidInitialMessage | X3DHError::Base64DecodeError(_)
+            )) => SessionRejection::MalformedMessage,
+            ClientError::ProtocolError(ProtocolError::X3DH(_)) => SessionRejection::InvalidChallenge,
+            _ => SessionRejection::MalformedMessage,
+        }
+    }
+}
+
+impl Display for SessionRejection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SessionRejection::MalformedMessage => write!(f, "malformed initial message"),
+            SessionRejection::UnknownPreKey => write!(f, "unknown one-time pre-key"),
+            SessionRejection::InvalidChallenge => write!(f, "invalid challenge"),
+            SessionRejection::Unknown => write!(f, "unknown reason"),
         }
     }
 }
@@ -447,38 +1718,137 @@ impl Display for ChatMessage {
 
 struct Friend {
     pub ratchet: Ratchet,
-    pb: Option<PreKeyBundle>,
+    role: Role,
+    /// The friend's identity key.
+    identity_key: PublicKey,
+    established_at: DateTime<Utc>,
     chat: Vec<ChatMessage>,
+    /// Associated data of the messages we send, with our identity key first.
     aad: AssociatedData,
+    last_activity: DateTime<Utc>,
+    auto_close: bool,
+    unread: usize,
+    muted: bool,
+    messages_sent: usize,
+    messages_received: usize,
+    verified: bool,
+    /// The history is never saved, and is wiped when the chat is dropped.
+    ephemeral: bool,
+    /// Whether the session was established with a one-time prekey.
+    one_time_prekey: bool,
 }
 
 impl Friend {
-    fn new(ratchet: Ratchet, pb: Option<PreKeyBundle>, aad: AssociatedData) -> Self {
+    fn new(ratchet: Ratchet, role: Role, identity_key: PublicKey, aad: AssociatedData) -> Self {
         Self {
             ratchet,
-            pb,
+            role,
+            identity_key,
+            established_at: Utc::now(),
             chat: Vec::new(),
             aad,
+            last_activity: Utc::now(),
+            auto_close: false,
+            unread: 0,
+            muted: false,
+            messages_sent: 0,
+            messages_received: 0,
+            verified: false,
+            ephemeral: false,
+            one_time_prekey: false,
         }
     }
 
-    fn get_friend_bundle(&self) -> Option<PreKeyBundle> {
-        self.pb.clone()
+    fn fingerprint(&self) -> String {
+        self.identity_key.hash().0.iter().map(|b| format!("{:02x}", b)).collect()
     }
 
     fn get_friend_aad(&self) -> AssociatedData {
         self.aad.clone()
     }
 
+    fn get_inbound_aad(&self) -> AssociatedData {
+        self.aad.reversed()
+    }
+
+    /// Decrypts a message from the friend. The plaintext is wiped when dropped, callers copy out what they keep.
+    fn decrypt_inbound(&mut self, ciphertext: String) -> Result<Zeroizing<Vec<u8>>, ClientError> {
+        self.decrypt_inbound_with_meta(ciphertext).map(|(plaintext, _)| plaintext)
+    }
+
+    /// Decrypts a message from the friend like [`Friend::decrypt_inbound`], also returning the header values it was bound to.
+    fn decrypt_inbound_with_meta(&mut self, ciphertext: String) -> Result<(Zeroizing<Vec<u8>>, MessageMeta), ClientError> {
+        let aad = self.get_inbound_aad();
+        let (plaintext, meta) = self.ratchet.decrypt_with_meta(ciphertext, &aad).inspect_err(|e| {
+            // Where the ratchet stood helps tell a lost message from a session out of step
+            let snapshot = self.ratchet.state_snapshot();
+            debug!(
+                "Decryption failed ({}) with {} sent, {} received and {} in the previous chain",
+                e, snapshot.messages_sent, snapshot.messages_received, snapshot.previous_chain_length
+            );
+        })?;
+        Ok((Zeroizing::new(plaintext), meta))
+    }
+
     fn add_message(&mut self, message: ChatMessage) {
+        self.last_activity = Utc::now();
         self.chat.push(message);
     }
+
+    /// Takes over the history and settings of the chat held by `previous`, whose session this one replaces.
+    /// The fingerprint stays verified only if the identity key did not change.
+    fn inherit(&mut self, mut previous: Friend) {
+        self.chat = std::mem::take(&mut previous.chat);
+        self.auto_close = previous.auto_close;
+        self.unread = previous.unread;
+        self.muted = previous.muted;
+        self.messages_sent = previous.messages_sent;
+        self.messages_received = previous.messages_received;
+        self.verified = previous.verified && previous.identity_key == self.identity_key;
+        self.ephemeral |= previous.ephemeral;
+    }
+}
+
+impl Drop for Friend {
+    fn drop(&mut self) {
+        if self.ephemeral {
+            for message in &mut self.chat {
+                message.text.zeroize();
+            }
+        }
+    }
+}
+
+/// Reports the outcome of a startup on `progress`.
+pub(crate) fn report_startup(
+    progress: &watch::Sender<ConnectionState>,
+    client: Result<Client, ClientError>,
+) -> Result<Client, ClientError> {
+    progress.send_replace(match &client {
+        Ok(_) => ConnectionState::Ready,
+        Err(e) => ConnectionState::Failed(e.to_string()),
+    });
+    client
+}
+
+/// Serializes a request into a buffer that is wiped once it has been encrypted for the server.
+fn to_zeroizing_json<T: Serialize>(value: &T) -> Result<Zeroizing<String>, ClientError> {
+    serde_json::to_string(value)
+        .map(Zeroizing::new)
+        .map_err(|_| ClientError::SerializationError)
 }
 
-fn decrypt_server_request(req: String, dk: &DecryptionKey) -> Result<Value, ()> {
-    match common::decrypt_request(&req, dk) {
-        Ok((dec, _)) => Ok(dec),
-        Err(_) => Err(())
+fn decrypt_server_request(req: String, session: &SessionKeys) -> Result<Value, ClientError> {
+    let dk = session.get_decryption_key().ok_or(ClientError::ServerResponseError)?;
+    let aad = session.get_associated_data().ok_or(ClientError::ServerResponseError)?;
+    match common::decrypt_request(&req, &dk, &aad) {
+        Ok(dec) => Ok(dec),
+        // Frames encrypted before the last rotation may still be in flight
+        Err(e @ CommonError::Aead(_)) => match session.get_previous_decryption_key() {
+            Some(previous) => Ok(common::decrypt_request(&req, &previous, &aad)?),
+            None => Err(e.into()),
+        },
+        Err(e) => Err(e.into()),
     }
 }
 
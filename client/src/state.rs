@@ -0,0 +1,198 @@
+//! Encrypted on-disk storage of the open chats, so conversations survive a relaunch.
+
+use std::fs;
+use std::path::Path;
+use aes_gcm::aead::OsRng;
+use aes_gcm::aead::rand_core::RngCore;
+use base64::Engine;
+use base64::engine::general_purpose;
+use chrono::{DateTime, Utc};
+use protocol::constants::AES256_NONCE_LENGTH;
+use protocol::ratchet::Ratchet;
+use protocol::utils::{AssociatedData, DecryptionKey, EncryptionKey, PublicKey, SharedSecret};
+use protocol::x3dh::Role;
+use serde::{Deserialize, Serialize};
+use argon2::Argon2;
+use crate::errors::ClientError;
+use crate::groups::GroupChat;
+use tokio::sync::watch;
+use zeroize::Zeroizing;
+use crate::{report_startup, ChatMessage, Client, ConnectionState, Friend};
+
+/// Version of the state file layout, written in the clear before the ciphertext. Files of version 1,
+/// whose key was derived by iterated hashing, are no longer read.
+const STATE_VERSION: u8 = 2;
+
+/// Byte size of the random salt mixed into the passphrase.
+const SALT_LENGTH: usize = 16;
+
+#[derive(Serialize, Deserialize)]
+struct SavedState {
+    friends: Vec<SavedFriend>,
+    #[serde(default)]
+    blocked: Vec<String>,
+    #[serde(default)]
+    groups: Vec<GroupChat>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct SavedFriend {
+    username: String,
+    ratchet: Ratchet,
+    initiator: bool,
+    identity_key: String,
+    aad: String,
+    established_at: String,
+    chat: Vec<ChatMessage>,
+    auto_close: bool,
+    muted: bool,
+    unread: usize,
+    messages_sent: usize,
+    messages_received: usize,
+    verified: bool,
+    #[serde(default)]
+    one_time_prekey: bool,
+}
+
+impl SavedFriend {
+    fn new(username: &str, friend: &Friend) -> Self {
+        Self {
+            username: username.to_string(),
+            ratchet: friend.ratchet.clone(),
+            initiator: friend.role == Role::Initiator,
+            identity_key: friend.identity_key.to_base64(),
+            aad: general_purpose::STANDARD.encode(friend.aad.to_bytes()),
+            established_at: friend.established_at.to_rfc3339(),
+            chat: friend.chat.clone(),
+            auto_close: friend.auto_close,
+            muted: friend.muted,
+            unread: friend.unread,
+            messages_sent: friend.messages_sent,
+            messages_received: friend.messages_received,
+            verified: friend.verified,
+            one_time_prekey: friend.one_time_prekey,
+        }
+    }
+
+    fn into_friend(self) -> Result<(String, Friend), ClientError> {
+        let corrupted = || ClientError::StateError("Corrupted state file".to_string());
+        let role = if self.initiator { Role::Initiator } else { Role::Responder };
+        let identity_key = PublicKey::from_base64(self.identity_key).map_err(|_| corrupted())?;
+        let aad = general_purpose::STANDARD.decode(self.aad).map_err(|_| corrupted())?;
+        let aad = <&[u8; AssociatedData::SIZE]>::try_from(aad.as_slice()).map_err(|_| corrupted())?;
+        let aad = AssociatedData::try_from(aad).map_err(|_| corrupted())?;
+        let established_at = DateTime::parse_from_rfc3339(&self.established_at)
+            .map_err(|_| corrupted())?
+            .with_timezone(&Utc);
+
+        let mut friend = Friend::new(self.ratchet, role, identity_key, aad);
+        friend.established_at = established_at;
+        friend.last_activity = Utc::now();
+        friend.chat = self.chat;
+        friend.auto_close = self.auto_close;
+        friend.muted = self.muted;
+        friend.unread = self.unread;
+        friend.messages_sent = self.messages_sent;
+        friend.messages_received = self.messages_received;
+        friend.verified = self.verified;
+        friend.one_time_prekey = self.one_time_prekey;
+        Ok((self.username, friend))
+    }
+}
+
+/// Derives the key of the state file from the passphrase with Argon2id, under the default parameters of the
+/// Argon2 crate.
+fn derive_state_key(passphrase: &str, salt: &[u8; SALT_LENGTH]) -> Result<SharedSecret, ClientError> {
+    let mut key = Zeroizing::new([0u8; 32]);
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, key.as_mut())
+        .map_err(|e| ClientError::StateError(e.to_string()))?;
+    Ok(SharedSecret::from(*key))
+}
+
+impl Client {
+    /// Writes the ratchet, associated data and history of every open chat to `path`, the group chats and the
+    /// blocked users, encrypted with a key derived from `passphrase`. Ephemeral chats are left out.
+    pub fn save_state(&self, path: &Path, passphrase: &str) -> Result<(), ClientError> {
+        let state = SavedState {
+            friends: self.friends
+                .iter()
+                .filter(|(_, friend)| !friend.ephemeral)
+                .map(|(username, friend)| SavedFriend::new(username, friend))
+                .collect(),
+            blocked: self.blocked.iter().cloned().collect(),
+            groups: self.groups.values().cloned().collect(),
+        };
+        let json = serde_json::to_vec(&state).map_err(|_| ClientError::SerializationError)?;
+
+        let mut salt = [0u8; SALT_LENGTH];
+        OsRng.fill_bytes(&mut salt);
+        let mut header = vec![STATE_VERSION];
+        header.extend_from_slice(&salt);
+
+        let key = EncryptionKey::from(derive_state_key(passphrase, &salt)?);
+        let ciphertext = key.encrypt(&json, &header)?;
+        let mut file = header;
+        file.extend_from_slice(ciphertext.as_bytes());
+        fs::write(path, file).map_err(|e| ClientError::StateError(e.to_string()))
+    }
+
+    /// Restores the chats, groups and blocked users saved with [`Client::save_state`], replacing the current ones.
+    pub fn load_state(&mut self, path: &Path, passphrase: &str) -> Result<(), ClientError> {
+        let corrupted = || ClientError::StateError("Corrupted state file".to_string());
+        let file = fs::read(path).map_err(|e| ClientError::StateError(e.to_string()))?;
+        let version = *file.first().ok_or_else(corrupted)?;
+        if version != STATE_VERSION {
+            return Err(ClientError::IncompatibleStateVersion(version));
+        }
+        let header = file.get(..1 + SALT_LENGTH).ok_or_else(corrupted)?;
+        let salt: &[u8; SALT_LENGTH] = header[1..].try_into().unwrap();
+
+        let ciphertext = general_purpose::STANDARD.decode(&file[header.len()..]).map_err(|_| corrupted())?;
+        if ciphertext.len() < AES256_NONCE_LENGTH {
+            return Err(corrupted());
+        }
+        let key = DecryptionKey::from(derive_state_key(passphrase, salt)?);
+        // Files saved before the header was left out of the ciphertext are still read
+        let json = key.decrypt_frame(&ciphertext, header)
+            .map_err(|_| ClientError::StateError("Wrong passphrase or corrupted state file".to_string()))?;
+
+        let state: SavedState = serde_json::from_slice(&json).map_err(|_| corrupted())?;
+        let friends = state.friends
+            .into_iter()
+            .map(SavedFriend::into_friend)
+            .collect::<Result<_, _>>()?;
+        self.friends = friends;
+        self.blocked = state.blocked.into_iter().collect();
+        self.groups = state.groups.into_iter().map(|g| (g.id.clone(), g)).collect();
+        Ok(())
+    }
+
+    /// Like [`Client::new`], restoring the chats saved in `path` before the session with the server is established.
+    pub async fn with_state(
+        chat_tx: tokio::sync::mpsc::Sender<ChatMessage>,
+        path: &Path,
+        passphrase: &str,
+    ) -> Result<Self, ClientError> {
+        let progress = watch::channel(ConnectionState::Connecting).0;
+        Self::with_state_and_progress(chat_tx, path, passphrase, &progress).await
+    }
+
+    /// Like [`Client::with_state`], reporting each stage of the startup on `progress`.
+    pub async fn with_state_and_progress(
+        chat_tx: tokio::sync::mpsc::Sender<ChatMessage>,
+        path: &Path,
+        passphrase: &str,
+        progress: &watch::Sender<ConnectionState>,
+    ) -> Result<Self, ClientError> {
+        progress.send_replace(ConnectionState::Connecting);
+        let client = async {
+            let (write, read) = Self::connect().await?;
+            let mut client = Self::with_connection(write, read, chat_tx);
+            progress.send_replace(ConnectionState::LoadingState);
+            client.load_state(path, passphrase)?;
+            client.start(progress).await
+        };
+        report_startup(progress, client.await)
+    }
+}
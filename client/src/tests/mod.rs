@@ -0,0 +1,3 @@
+pub(crate) mod support;
+mod soak_tests;
+mod unit_tests;
@@ -0,0 +1,253 @@
+//! Soak runs of two clients chatting through their [`MockServer`]s.
+//!
+//! Alice and Bob exchange messages of random sizes, each relayed from the server end of the sender to the
+//! receiver over a link that drops and reorders them, the way a flaky connection and the offline queue of
+//! the server would. Every so often both clients reconnect with [`Client::resume`], and one of them resets the
+//! session with [`Client::reset_session`]. At the end the history of each client must hold what it sent and
+//! exactly what the other one sent and the link did not drop, and the skipped keys and state of every ratchet
+//! must have stayed within their bounds all along.
+//!
+//! The long run is ignored by default and reproducible from its seed:
+//!
+//! ```text
+//! SOAK_MESSAGES=10000 SOAK_SEED=42 cargo test -p client test_long_soak -- --ignored --nocapture
+//! ```
+
+use super::support::{connected_client, reconnected, MockServer};
+use super::super::*;
+use std::collections::BTreeMap;
+
+/// The probability that the link drops a message.
+const DROP_RATE: f64 = 0.02;
+
+/// The probability that the link holds a message back behind later ones.
+const REORDER_RATE: f64 = 0.1;
+
+/// The largest plaintext sent, in bytes, besides the sequence number it starts with.
+const MAX_SIZE: usize = 512;
+
+/// The serialized state a ratchet may reach at most is this fixed part plus [`STATE_BYTES_PER_SKIPPED_KEY`].
+const STATE_FIXED_BYTES: usize = 4096;
+
+/// What each skipped key a ratchet keeps may add to its serialized state.
+const STATE_BYTES_PER_SKIPPED_KEY: usize = 128;
+
+/// What a run went through.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+struct SoakReport {
+    sent: usize,
+    delivered: usize,
+    dropped: usize,
+    reordered: usize,
+    /// Messages delivered after the key of their position was evicted, lost like dropped ones.
+    evicted: usize,
+    reconnects: usize,
+    resets: usize,
+    max_skipped_keys: usize,
+    max_state_bytes: usize,
+}
+
+/// A splitmix64 generator, so that a seed gives the same run on every platform.
+struct Rng(u64);
+
+impl Rng {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// A number in `0..n`.
+    fn below(&mut self, n: usize) -> usize {
+        (self.next_u64() % n as u64) as usize
+    }
+
+    fn chance(&mut self, p: f64) -> bool {
+        ((self.next_u64() >> 11) as f64 / (1u64 << 53) as f64) < p
+    }
+}
+
+/// One client of the conversation with the server end of its connection.
+struct Side {
+    client: Client,
+    server: MockServer,
+    _chat_rx: mpsc::Receiver<ChatMessage>,
+    name: &'static str,
+    peer: &'static str,
+    /// The texts sent, by sequence number.
+    sent: BTreeMap<u64, String>,
+    /// The messages relayed to the peer and not delivered yet, with their sequence numbers.
+    link: Vec<(u64, ChatMessage)>,
+    /// The sequence numbers of the messages the link dropped.
+    dropped: Vec<u64>,
+}
+
+impl Side {
+    async fn new(name: &'static str, peer: &'static str) -> Self {
+        let (mut client, server, chat_rx) = connected_client(name).await;
+        client.listener = Some(client.start_read_loop());
+        Self { client, server, _chat_rx: chat_rx, name, peer, sent: BTreeMap::new(), link: Vec::new(), dropped: Vec::new() }
+    }
+}
+
+/// Opens the chat of `initiator` with `responder`, or resets it if it is open, as their servers would relay it.
+async fn open_chat(initiator: &mut Side, responder: &mut Side) {
+    let bundle = responder.client.bundle.to_base64();
+    let server = &mut initiator.server;
+    let server_side = async {
+        let fetch = server.next_request().await;
+        assert_eq!(fetch["body"]["username"], responder.name);
+        server.respond(&fetch, "200", &bundle).await;
+        server.next_request().await
+    };
+    let client = &mut initiator.client;
+    let opening = async {
+        if client.friends.contains_key(responder.name) {
+            client.reset_session(responder.name).await
+        } else {
+            client.open_chat(responder.name.to_string(), false).await
+        }
+    };
+    let (opened, initial) = tokio::join!(opening, server_side);
+    opened.unwrap();
+    assert_eq!(initial["msg_type"], "initial_message");
+    responder.client.accept_initial_message(serde_json::from_value(initial).unwrap()).await.unwrap();
+}
+
+/// Hands a message relayed from `from` to `to`, and checks the ratchet it went through.
+fn deliver(seq: u64, message: ChatMessage, to: &mut Side, report: &mut SoakReport) {
+    match to.client.decrypt_chat_message(message) {
+        Ok(_) => report.delivered += 1,
+        Err(ClientError::ProtocolError(ProtocolError::Ratchet(RatchetError::SkippedKeyEvicted))) => report.evicted += 1,
+        Err(e) => panic!("{} could not decrypt message {}: {}", to.name, seq, e),
+    }
+    let ratchet = &to.client.friends[to.peer].ratchet;
+    assert!(ratchet.skipped_key_count() <= ratchet.max_skipped_keys(), "{} skipped keys kept", ratchet.skipped_key_count());
+    let state_bytes = ratchet.to_bytes().len();
+    let state_limit = STATE_FIXED_BYTES + ratchet.skipped_key_count() * STATE_BYTES_PER_SKIPPED_KEY;
+    assert!(state_bytes <= state_limit, "A ratchet state reached {} bytes", state_bytes);
+    report.max_skipped_keys = report.max_skipped_keys.max(ratchet.skipped_key_count());
+    report.max_state_bytes = report.max_state_bytes.max(state_bytes);
+}
+
+/// Hands everything in flight to its receiver, in its current order.
+fn drain(sides: &mut [Side; 2], report: &mut SoakReport) {
+    for from in 0..2 {
+        for (seq, message) in std::mem::take(&mut sides[from].link) {
+            deliver(seq, message, &mut sides[1 - from], report);
+        }
+    }
+}
+
+/// Runs `messages` messages between two clients, with every random choice made from `seed`.
+async fn soak(messages: usize, seed: u64, reconnect_every: usize, reset_every: usize) -> SoakReport {
+    let mut rng = Rng(seed);
+    let mut report = SoakReport::default();
+    let mut sides = [Side::new("alice", "bob").await, Side::new("bob", "alice").await];
+    let [alice, bob] = &mut sides;
+    open_chat(alice, bob).await;
+
+    for seq in 0..messages as u64 {
+        let step = seq as usize;
+        if step > 0 && step.is_multiple_of(reset_every) {
+            // What is in flight arrives before the old session is forgotten
+            drain(&mut sides, &mut report);
+            let [alice, bob] = &mut sides;
+            if report.resets % 2 == 0 {
+                open_chat(bob, alice).await;
+            } else {
+                open_chat(alice, bob).await;
+            }
+            report.resets += 1;
+        } else if step > 0 && step.is_multiple_of(reconnect_every) {
+            // The messages in flight wait in the queue of the server meanwhile
+            for side in sides.iter_mut() {
+                side.server = reconnected(&mut side.client).await;
+            }
+            report.reconnects += 1;
+        }
+
+        let from = rng.below(2);
+        let size = 1 + rng.below(MAX_SIZE);
+        let text = format!("{} {}", seq, (0..size).map(|_| (b'a' + rng.below(26) as u8) as char).collect::<String>());
+        let side = &mut sides[from];
+        let message = ChatMessage::new("chat".to_string(), side.peer.to_string(), side.name.to_string(), text.clone(), Utc::now());
+        let (sent, relayed) = tokio::join!(side.client.send_and_store_chat_message(message), side.server.deliver());
+        assert_eq!(sent.unwrap(), DeliveryStatus::Delivered);
+        let relayed: ChatMessage = serde_json::from_value(relayed).unwrap();
+        side.sent.insert(seq, text);
+        report.sent += 1;
+
+        if rng.chance(DROP_RATE) {
+            side.dropped.push(seq);
+            report.dropped += 1;
+        } else if !side.link.is_empty() && rng.chance(REORDER_RATE) {
+            let at = rng.below(side.link.len());
+            side.link.insert(at, (seq, relayed));
+            report.reordered += 1;
+        } else {
+            side.link.push((seq, relayed));
+        }
+
+        // The link delivers a random number of the oldest messages in flight
+        for from in 0..2 {
+            let n = rng.below(sides[from].link.len() + 1);
+            for (seq, message) in sides[from].link.drain(..n).collect::<Vec<_>>() {
+                deliver(seq, message, &mut sides[1 - from], &mut report);
+            }
+        }
+    }
+    drain(&mut sides, &mut report);
+
+    let mut lost = 0;
+    for (from, to) in [(0, 1), (1, 0)] {
+        let history = sides[to].client.get_chat_history(sides[to].peer).unwrap();
+        assert!(history.iter().all(|m| m.msg_type == "chat"), "{} noted a loss", sides[to].name);
+        // What the receiver sent is kept in the order it was sent
+        let own = history.iter().filter(|m| m.from == sides[to].name).map(|m| m.text.clone()).collect::<Vec<_>>();
+        assert_eq!(own, sides[to].sent.values().cloned().collect::<Vec<_>>());
+
+        let mut expected = sides[from].sent.clone();
+        for seq in &sides[from].dropped {
+            expected.remove(seq);
+        }
+        let mut received = BTreeMap::new();
+        for message in history.iter().filter(|m| m.from == sides[from].name) {
+            let seq = message.text.split(' ').next().unwrap().parse::<u64>().unwrap();
+            assert!(received.insert(seq, message.text.clone()).is_none(), "Message {} delivered twice", seq);
+        }
+        for (seq, text) in &received {
+            assert_eq!(expected.get(seq), Some(text), "Message {} was never sent, was dropped or was altered", seq);
+        }
+        lost += expected.len() - received.len();
+    }
+    // Every message the link did not drop was either received or had its key evicted
+    assert_eq!(lost, report.evicted);
+    report
+}
+
+#[tokio::test]
+async fn test_short_soak() {
+    let report = soak(300, 42, 50, 120).await;
+    assert_eq!(report.sent, 300);
+    assert_eq!(report.delivered + report.dropped + report.evicted, report.sent);
+    assert!(report.dropped > 0 && report.reordered > 0);
+    assert_eq!((report.reconnects, report.resets), (5, 2));
+}
+
+#[tokio::test]
+async fn test_soak_is_reproducible_from_its_seed() {
+    assert_eq!(soak(100, 7, 30, 60).await, soak(100, 7, 30, 60).await);
+    assert_ne!(soak(100, 7, 30, 60).await, soak(100, 8, 30, 60).await);
+}
+
+#[tokio::test]
+#[ignore = "long run, sized with SOAK_MESSAGES and SOAK_SEED"]
+async fn test_long_soak() {
+    let from_env = |name: &str, default: u64| std::env::var(name).map_or(default, |v| v.parse().expect(name));
+    let (messages, seed) = (from_env("SOAK_MESSAGES", 10_000) as usize, from_env("SOAK_SEED", 0));
+    let report = soak(messages, seed, 500, 2500).await;
+    println!("Soak passed with seed {}: {:?}", seed, report);
+}
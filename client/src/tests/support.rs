@@ -0,0 +1,179 @@
+//! Helpers to run a [`Client`] against an in-process WebSocket peer without a real server.
+
+use super::super::*;
+use protocol::utils::EncryptionKey;
+use protocol::x3dh::process_initial_message;
+use tokio::net::TcpListener;
+use tokio_tungstenite::{accept_async, WebSocketStream};
+
+/// The server end of a loopback connection, holding the session keys agreed with the client.
+pub(crate) struct MockServer {
+    pub(crate) ws: WebSocketStream<TcpStream>,
+    pub(crate) ek: EncryptionKey,
+    pub(crate) dk: DecryptionKey,
+    pub(crate) aad: AssociatedData,
+}
+
+impl MockServer {
+    /// Reads the next text frame sent by the client and decrypts it with the session key.
+    pub(crate) async fn next_request(&mut self) -> Value {
+        loop {
+            match StreamExt::next(&mut self.ws).await {
+                Some(Ok(Message::Text(msg))) => {
+                    return common::decrypt_request(&msg, &self.dk, &self.aad)
+                        .expect("Failed to decrypt client request");
+                }
+                Some(Ok(_)) => continue,
+                other => panic!("Connection closed before a request arrived: {:?}", other.is_some()),
+            }
+        }
+    }
+
+    /// Reads the next chat message sent by the client and acknowledges it as delivered.
+    pub(crate) async fn deliver(&mut self) -> Value {
+        let message = self.next_request().await;
+        self.respond(&message, "200", "Delivered").await;
+        message
+    }
+
+    /// Encrypts `value` with the session key and sends it to the client.
+    pub(crate) async fn send(&mut self, value: Value) {
+        let ek = self.ek.clone();
+        self.send_with(&ek, value).await;
+    }
+
+    /// Answers the wrapped `request` with a server response.
+    pub(crate) async fn respond(&mut self, request: &Value, code: &str, message: &str) {
+        self.send(json!({
+            "request_id": request["request_id"],
+            "body": { "code": code, "message": message },
+        })).await;
+    }
+
+    /// Encrypts `value` with `ek` instead of the current session key and sends it to the client.
+    pub(crate) async fn send_with(&mut self, ek: &EncryptionKey, value: Value) {
+        let enc = ek.encrypt(value.to_string().as_bytes(), &self.aad.to_bytes()).unwrap();
+        self.ws.send(Message::Text(Utf8Bytes::from(enc))).await.unwrap();
+    }
+
+    /// Sends `text` to the client as is, without encrypting it.
+    pub(crate) async fn send_raw(&mut self, text: &str) {
+        self.ws.send(Message::Text(Utf8Bytes::from(text))).await.unwrap();
+    }
+
+    /// Rotates the session keys the same way the server does after acknowledging a rekey.
+    pub(crate) fn rotate(&mut self) {
+        let mut session = SessionKeys::new_with_keys(self.ek.clone(), self.dk.clone(), Some(self.aad.clone()));
+        session.rotate().unwrap();
+        self.ek = session.get_encryption_key().unwrap();
+        self.dk = session.get_decryption_key().unwrap();
+    }
+}
+
+/// Opens a WebSocket to an in-process listener, returning the client end and the server end.
+async fn loopback() -> (Connection, WebSocketStream<TcpStream>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let accept = tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        accept_async(stream).await.unwrap()
+    });
+    let (ws, _) = tokio_tungstenite::connect_async(format!("ws://{}", addr)).await.unwrap();
+    let server_ws = accept.await.unwrap();
+    let (write, read) = ws.split();
+    (Connection { write, read }, server_ws)
+}
+
+/// Creates a [`Client`] connected to the returned server end of a WebSocket, before any handshake.
+pub(crate) async fn unconnected_client() -> (Client, WebSocketStream<TcpStream>, mpsc::Receiver<ChatMessage>) {
+    let (connection, server_ws) = loopback().await;
+    let (chat_tx, chat_rx) = mpsc::channel(100);
+    (Client::from_connection(connection, chat_tx), server_ws, chat_rx)
+}
+
+/// Creates a registered [`Client`] connected to a [`MockServer`] with an established session.
+/// The read loop is not started, so frames sent by the mock server stay in the client socket.
+pub(crate) async fn connected_client(username: &str) -> (Client, MockServer, mpsc::Receiver<ChatMessage>) {
+    let (mut client, server_ws, chat_rx) = unconnected_client().await;
+    client.set_username(username.to_string());
+
+    let (im, server_ek, server_dk) = process_prekey_bundle(PrivateKey::new(), client.bundle.clone()).unwrap();
+    let otpk = client.one_time_prekey(&im).unwrap().map(|(_, key)| key);
+    let (ek, dk) = process_initial_message(
+        client.identity_key.clone(),
+        client.signed_prekey.clone(),
+        otpk,
+        im.clone(),
+    ).unwrap();
+    {
+        let mut session = client.session.lock().await;
+        session.set_encryption_key(ek);
+        session.set_decryption_key(dk);
+        session.set_associated_data(im.associated_data.clone());
+    }
+
+    let server = MockServer {
+        ws: server_ws,
+        ek: server_ek,
+        dk: server_dk,
+        aad: im.associated_data,
+    };
+    (client, server, chat_rx)
+}
+
+/// Hands `client` a new connection with [`Client::resume`], playing the server side of the handshake
+/// and of the registration that follows it. The read loop runs again once this returns.
+pub(crate) async fn reconnected(client: &mut Client) -> MockServer {
+    let (connection, mut ws) = loopback().await;
+    let client_name = client.username.clone();
+    let server_key = PrivateKey::new();
+    client.server_key = Some(PublicKey::from(&server_key));
+    let server_side = async move {
+        let hello: Value = match StreamExt::next(&mut ws).await {
+            Some(Ok(Message::Text(msg))) => serde_json::from_str(&msg).unwrap(),
+            _ => panic!("Connection closed before the handshake"),
+        };
+        let bundle = PreKeyBundle::try_from(hello["bundle"].as_str().unwrap().to_string()).unwrap();
+        let (im, ek, dk) = process_prekey_bundle(server_key, bundle).unwrap();
+        let response = ServerResponse::new(ResponseCode::Ok, im.to_json());
+        ws.send(Message::Text(Utf8Bytes::from(response.to_string()))).await.unwrap();
+
+        let mut server = MockServer { ws, ek, dk, aad: im.associated_data };
+        let registration = server.next_request().await;
+        assert_eq!(registration["body"]["username"], client_name);
+        server.respond(&registration, "200", "User registered").await;
+        server
+    };
+    let (resumed, server) = tokio::join!(client.resume(connection), server_side);
+    resumed.unwrap();
+    server
+}
+
+/// Creates a [`Friend`] backed by a throwaway ratchet, for tests that do not exchange messages.
+pub(crate) fn dummy_friend() -> Friend {
+    let pk = PublicKey::from(&PrivateKey::new());
+    let ratchet = Ratchet::init_alice(SharedSecret::from([1u8; 32]), pk.clone());
+    Friend::new(ratchet, Role::Initiator, pk.clone(), AssociatedData::new(pk.clone(), pk))
+}
+
+/// Creates the two ends of a chat: the [`Friend`] held by the initiator and the one held by the responder.
+pub(crate) fn friend_pair() -> (Friend, Friend) {
+    let spk = PrivateKey::new();
+    let spk_public = PublicKey::from(&spk);
+    let (initiator_ik, responder_ik) = (PrivateKey::new(), PrivateKey::new());
+    let aad = AssociatedData::new(PublicKey::from(&initiator_ik), PublicKey::from(&responder_ik));
+    let sk = SharedSecret::from([2u8; 32]);
+    let initiator = Friend::new(
+        Ratchet::init_alice(sk.clone(), spk_public.clone()),
+        Role::Initiator,
+        PublicKey::from(&responder_ik),
+        aad.clone(),
+    );
+    let responder = Friend::new(
+        Ratchet::init_bob(sk, RatchetKeyPair::new_from(spk, spk_public)),
+        Role::Responder,
+        PublicKey::from(&initiator_ik),
+        aad.reversed(),
+    );
+    (initiator, responder)
+}
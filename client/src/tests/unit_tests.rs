@@ -0,0 +1,1550 @@
+use super::support::{connected_client, dummy_friend, friend_pair, unconnected_client};
+use super::super::*;
+use common::UsernameError;
+use tokio_tungstenite::WebSocketStream;
+
+#[tokio::test]
+async fn test_idle_chat_is_closed_and_history_archived() {
+    let (mut client, mut server, _chat_rx) = connected_client("alice").await;
+    client.listener = Some(client.start_read_loop());
+    client.friends.insert("bob".to_string(), dummy_friend());
+    client.friends.insert("carol".to_string(), dummy_friend());
+    client.add_chat_message(
+        ChatMessage::new("chat".to_string(), "bob".to_string(), "alice".to_string(), "hi".to_string(), Utc::now()),
+        "bob",
+    );
+    client.set_retention_policy(Some(RetentionPolicy::new(Duration::minutes(10))));
+    client.set_auto_close("bob", true).unwrap();
+
+    // Both chats are idle, but only bob opted in.
+    for friend in client.friends.values_mut() {
+        friend.last_activity = Utc::now() - Duration::minutes(11);
+    }
+
+    let server_side = async {
+        let request = server.next_request().await;
+        assert_eq!(request["msg_type"], "close_chat");
+        assert_eq!(request["to"], "bob");
+        let filter = server.next_request().await;
+        server.respond(&filter, "200", "Relay filter updated").await;
+    };
+    let (closed, _) = tokio::join!(client.close_idle_chats(), server_side);
+    assert_eq!(closed.unwrap(), vec!["bob".to_string()]);
+    assert!(!client.friends.contains_key("bob"));
+    assert!(client.friends.contains_key("carol"));
+
+    let history = client.get_archived_history("bob").unwrap();
+    assert_eq!(history.len(), 1);
+    assert_eq!(history[0].text, "hi");
+}
+
+#[tokio::test]
+async fn test_failed_close_leaves_other_idle_chats_closing() {
+    let (mut client, mut server, _chat_rx) = connected_client("alice").await;
+    client.listener = Some(client.start_read_loop());
+    client.set_retention_policy(Some(RetentionPolicy::new(Duration::minutes(10))));
+    for name in ["bob", "carol"] {
+        client.friends.insert(name.to_string(), dummy_friend());
+        client.set_auto_close(name, true).unwrap();
+        client.friends.get_mut(name).unwrap().last_activity = Utc::now() - Duration::minutes(11);
+    }
+
+    // The server refuses to filter bob, whichever chat is closed first
+    let server_side = async {
+        for _ in 0..2 {
+            let request = server.next_request().await;
+            let filter = server.next_request().await;
+            let code = if request["to"] == "bob" { "400" } else { "200" };
+            server.respond(&filter, code, "").await;
+        }
+    };
+    let (closed, _) = tokio::join!(client.close_idle_chats(), server_side);
+    assert_eq!(closed.unwrap(), vec!["carol".to_string()]);
+    assert!(client.friends.contains_key("bob"));
+    assert!(!client.friends.contains_key("carol"));
+}
+
+#[tokio::test]
+async fn test_active_chat_is_not_closed() {
+    let (mut client, _server, _chat_rx) = connected_client("alice").await;
+    client.friends.insert("bob".to_string(), dummy_friend());
+    client.set_retention_policy(Some(RetentionPolicy::new(Duration::minutes(10))));
+    client.set_auto_close("bob", true).unwrap();
+
+    assert!(client.close_idle_chats().await.unwrap().is_empty());
+    assert!(client.friends.contains_key("bob"));
+}
+
+#[tokio::test]
+async fn test_rotate_session_keys() {
+    let (mut client, mut server, mut chat_rx) = connected_client("alice").await;
+    client.listener = Some(client.start_read_loop());
+    let chat = |text: &str| json!({
+        "msg_type": "chat",
+        "from": "bob",
+        "to": "alice",
+        "text": text,
+        "timestamp": Utc::now().to_rfc3339(),
+    });
+
+    let server_side = async {
+        let request = server.next_request().await;
+        assert_eq!(request["body"]["request_type"], "rekey");
+        let old_ek = server.ek.clone();
+        server.send(json!({
+            "request_id": request["request_id"],
+            "body": { "code": "200", "message": "Session keys rotated" },
+        })).await;
+        server.rotate();
+
+        // A frame encrypted before the switch, still in flight, then one under the new key
+        server.send_with(&old_ek, chat("in flight")).await;
+        server.send(chat("rotated")).await;
+    };
+    let (result, _) = tokio::join!(client.rotate_session_keys(), server_side);
+    result.unwrap();
+
+    assert_eq!(chat_rx.recv().await.unwrap().text, "in flight");
+    assert_eq!(chat_rx.recv().await.unwrap().text, "rotated");
+
+    // Requests from the client are now encrypted under the new key
+    client.friends.insert("bob".to_string(), dummy_friend());
+    let server_side = async {
+        let request = server.next_request().await;
+        assert_eq!(request["msg_type"], "close_chat");
+        let filter = server.next_request().await;
+        server.respond(&filter, "200", "Relay filter updated").await;
+    };
+    let (result, _) = tokio::join!(client.close_chat("bob".to_string()), server_side);
+    result.unwrap();
+}
+
+#[tokio::test]
+async fn test_reflected_message_is_dropped() {
+    let (mut client, mut server, _chat_rx) = connected_client("alice").await;
+    client.listener = Some(client.start_read_loop());
+    let (bob, mut alice) = friend_pair();
+    client.friends.insert("bob".to_string(), bob);
+
+    let message = ChatMessage::new("chat".to_string(), "bob".to_string(), "alice".to_string(), "hi".to_string(), Utc::now());
+    let (result, sent) = tokio::join!(client.send_chat_message(message), server.deliver());
+    result.unwrap();
+    let text = sent["text"].as_str().unwrap().to_string();
+
+    // The relay bounces the frame back, as ours and as if it came from bob
+    let reflected = |from: &str| ChatMessage::new("chat".to_string(), "alice".to_string(), from.to_string(), text.clone(), Utc::now());
+    assert!(matches!(client.decrypt_chat_message(reflected("alice")), Err(ClientError::ReflectedMessageError)));
+    assert!(client.decrypt_chat_message(reflected("bob")).is_err());
+    assert!(client.get_chat_history("bob").unwrap().is_empty());
+
+    // The ratchet state is untouched, so the conversation continues normally
+    let inbound = alice.get_inbound_aad();
+    assert_eq!(alice.ratchet.decrypt_with_aad(text, &inbound).unwrap(), b"hi");
+    let reply = alice.ratchet.encrypt(b"hello", &alice.get_friend_aad().to_bytes()).unwrap();
+    let reply = ChatMessage::new("chat".to_string(), "alice".to_string(), "bob".to_string(), reply, Utc::now());
+    client.decrypt_chat_message(reply).unwrap();
+    assert_eq!(client.get_chat_history("bob").unwrap()[0].text, "hello");
+}
+
+#[tokio::test]
+async fn test_truncated_frames_do_not_stop_the_read_loop() {
+    let (mut client, mut server, mut chat_rx) = connected_client("alice").await;
+    client.listener = Some(client.start_read_loop());
+    let (bob, mut alice) = friend_pair();
+    client.friends.insert("bob".to_string(), bob);
+    let chat = |text: String| ChatMessage::new("chat".to_string(), "alice".to_string(), "bob".to_string(), text, Utc::now());
+
+    let frame = server.ek.encrypt(b"{}", &server.aad.to_bytes()).unwrap();
+    let frame = general_purpose::STANDARD.decode(frame).unwrap();
+    for length in [0, 10, AES256_NONCE_LENGTH, AES256_NONCE_LENGTH + 1, frame.len() - 1] {
+        server.send_raw(&general_purpose::STANDARD.encode(&frame[..length])).await;
+    }
+    server.send(json!({ "msg_type": "chat", "from": "bob", "to": "alice", "text": "still here", "timestamp": Utc::now().to_rfc3339() })).await;
+    assert_eq!(chat_rx.recv().await.unwrap().text, "still here");
+
+    // Truncated chat messages are refused without touching the session
+    let message = alice.ratchet.encrypt(b"hello", &alice.get_friend_aad().to_bytes()).unwrap();
+    let bytes = general_purpose::STANDARD.decode(&message).unwrap();
+    for length in [0, 10, AES256_NONCE_LENGTH, bytes.len() - 1] {
+        let truncated = general_purpose::STANDARD.encode(&bytes[..length]);
+        assert!(client.decrypt_chat_message(chat(truncated)).is_err());
+    }
+    client.decrypt_chat_message(chat(message)).unwrap();
+    assert_eq!(client.get_chat_history("bob").unwrap()[0].text, "hello");
+}
+
+#[tokio::test]
+async fn test_blocked_sender_never_reaches_the_chat() {
+    let (mut client, _server, _chat_rx) = connected_client("alice").await;
+    let (bob, mut alice) = friend_pair();
+    client.friends.insert("bob".to_string(), bob);
+    let mut from_bob = |text: &str| {
+        let text = alice.ratchet.encrypt(text.as_bytes(), &alice.get_friend_aad().to_bytes()).unwrap();
+        ChatMessage::new("chat".to_string(), "alice".to_string(), "bob".to_string(), text, Utc::now())
+    };
+    let (first, second) = (from_bob("first"), from_bob("second"));
+
+    client.block_user("bob".to_string());
+    assert!(client.is_blocked("bob"));
+    assert!(matches!(client.decrypt_chat_message(first), Err(ClientError::BlockedUser(user)) if user == "bob"));
+    assert!(client.friends["bob"].chat.is_empty());
+    assert_eq!(client.unread_count("bob"), 0);
+    // Nor can a blocked user start a session over
+    let initial = ChatMessage::new("initial_message".to_string(), "alice".to_string(), "bob".to_string(), String::new(), Utc::now());
+    assert!(matches!(client.add_friend(initial), Err(ClientError::BlockedUser(_))));
+
+    client.unblock_user("bob");
+    client.decrypt_chat_message(second).unwrap();
+    let history = client.get_chat_history("bob").unwrap();
+    assert_eq!(history.len(), 1);
+    assert_eq!(history[0].text, "second");
+}
+
+#[tokio::test]
+async fn test_total_unread_excludes_muted_chats() {
+    let (mut client, _server, _chat_rx) = connected_client("alice").await;
+    let mut senders = vec![];
+    for name in ["bob", "carol", "dave"] {
+        let (ours, theirs) = friend_pair();
+        client.friends.insert(name.to_string(), ours);
+        senders.push((name, theirs));
+    }
+    client.set_muted("dave", true).unwrap();
+
+    for (name, friend) in senders.iter_mut() {
+        for _ in 0..2 {
+            let text = friend.ratchet.encrypt(b"hi", &friend.get_friend_aad().to_bytes()).unwrap();
+            let message = ChatMessage::new("chat".to_string(), "alice".to_string(), name.to_string(), text, Utc::now());
+            client.decrypt_chat_message(message).unwrap();
+        }
+    }
+
+    assert_eq!(client.unread_count("dave"), 2);
+    assert_eq!(client.total_unread(), 4);
+
+    client.mark_read("bob");
+    assert_eq!(client.total_unread(), 2);
+}
+
+#[tokio::test]
+async fn test_delete_message_removes_first_match() {
+    let (mut client, _server, _chat_rx) = connected_client("alice").await;
+    client.friends.insert("bob".to_string(), dummy_friend());
+    let sent_at = Utc::now();
+    for text in ["first", "second", "third"] {
+        let at = if text == "third" { sent_at + Duration::seconds(1) } else { sent_at };
+        let message = ChatMessage::new("chat".to_string(), "bob".to_string(), "alice".to_string(), text.to_string(), at);
+        client.add_chat_message(message, "bob");
+    }
+
+    // "first" and "second" share a timestamp, only the first of them goes
+    assert!(client.delete_message("bob", &sent_at.to_rfc3339()));
+    let texts = client.get_chat_history("bob").unwrap().into_iter().map(|m| m.text).collect::<Vec<_>>();
+    assert_eq!(texts, vec!["second", "third"]);
+
+    assert!(!client.delete_message("bob", "2000-01-01T00:00:00+00:00"));
+    assert!(!client.delete_message("carol", &sent_at.to_rfc3339()));
+    assert_eq!(client.get_chat_history("bob").unwrap().len(), 2);
+}
+
+#[tokio::test]
+async fn test_server_info() {
+    let (mut client, mut server, _chat_rx) = connected_client("alice").await;
+    client.listener = Some(client.start_read_loop());
+
+    let server_side = async {
+        let request = server.next_request().await;
+        assert_eq!(request["body"]["request_type"], "server_info");
+        let info = json!({
+            "version": "0.1.0",
+            "uptime_secs": 5,
+            "registered_users": 10,
+            "offline_storage": false,
+            "max_message_size": null,
+            "rate_limit_per_minute": null,
+        });
+        server.send(json!({
+            "request_id": request["request_id"],
+            "body": { "code": "200", "message": info.to_string() },
+        })).await;
+    };
+    let (info, _) = tokio::join!(client.server_info(), server_side);
+    let info = info.unwrap();
+    assert_eq!(info.version, "0.1.0");
+    assert_eq!(info.registered_users, 10);
+}
+
+#[tokio::test]
+async fn test_get_presence() {
+    let (mut client, mut server, _chat_rx) = connected_client("alice").await;
+    client.listener = Some(client.start_read_loop());
+
+    let server_side = async {
+        let request = server.next_request().await;
+        assert_eq!(request["body"]["request_type"], "presence");
+        assert_eq!(request["body"]["usernames"], json!(["bob", "carol"]));
+        server.respond(&request, "200", &json!({ "bob": true, "carol": false }).to_string()).await;
+    };
+    let users = vec!["bob".to_string(), "carol".to_string()];
+    let (presence, _) = tokio::join!(client.get_presence(users), server_side);
+    let presence = presence.unwrap();
+    assert_eq!(presence.get("bob"), Some(&true));
+    assert_eq!(presence.get("carol"), Some(&false));
+}
+
+#[tokio::test]
+async fn test_registration_is_retried_with_the_same_idempotency_key() {
+    let (mut client, mut server, _chat_rx) = connected_client("alice").await;
+    client.listener = Some(client.start_read_loop());
+    client.set_retry_policy(RetryPolicy {
+        max_attempts: 3,
+        base_delay: std::time::Duration::from_millis(10),
+        timeout: std::time::Duration::from_millis(200),
+    });
+
+    let server_side = async {
+        // The response to the first attempt is lost
+        let first = server.next_request().await;
+        let second = server.next_request().await;
+        assert_eq!(first["body"]["idempotency_key"], second["body"]["idempotency_key"]);
+        assert_ne!(first["request_id"], second["request_id"]);
+        // The device keeps its id across retries, and the server tells it apart from the other devices of the user
+        assert!(uuid::Uuid::parse_str(first["body"]["device_id"].as_str().unwrap()).is_ok());
+        assert_eq!(first["body"]["device_id"], second["body"]["device_id"]);
+        server.send(json!({
+            "request_id": second["request_id"],
+            "body": { "code": "200", "message": "User registered successfully!" },
+        })).await;
+    };
+    let (result, _) = tokio::join!(client.register_user(), server_side);
+    result.unwrap();
+}
+
+#[tokio::test]
+async fn test_invalid_username_is_not_sent() {
+    let (mut client, _server, _chat_rx) = connected_client("alice").await;
+    client.listener = Some(client.start_read_loop());
+    for (username, error) in [
+        ("a".repeat(64), UsernameError::InvalidLength(64)),
+        ("al".to_string(), UsernameError::InvalidLength(2)),
+        ("bo\u{301}b".to_string(), UsernameError::InvalidCharacter('\u{301}')),
+    ] {
+        client.set_username(username);
+        // Refused before any request, so no response is awaited
+        assert!(matches!(client.register_user().await, Err(ClientError::InvalidUsername(e)) if e == error));
+    }
+}
+
+#[tokio::test]
+async fn test_retries_are_bounded() {
+    let (mut client, mut server, _chat_rx) = connected_client("alice").await;
+    client.listener = Some(client.start_read_loop());
+    client.set_retry_policy(RetryPolicy {
+        max_attempts: 2,
+        base_delay: std::time::Duration::from_millis(10),
+        timeout: std::time::Duration::from_millis(100),
+    });
+
+    let server_side = async {
+        server.next_request().await;
+        server.next_request().await;
+    };
+    let (result, _) = tokio::join!(client.get_user_prekey_bundle("bob".to_string()), server_side);
+    assert!(matches!(result, Err(ClientError::TimeoutError)));
+}
+
+/// Reads the `establish_connection` request of a client from the server end of its connection.
+async fn next_handshake(ws: &mut WebSocketStream<TcpStream>) -> Value {
+    match StreamExt::next(ws).await {
+        Some(Ok(Message::Text(msg))) => serde_json::from_str(&msg).unwrap(),
+        _ => panic!("Connection closed before the handshake"),
+    }
+}
+
+async fn answer_handshake(ws: &mut WebSocketStream<TcpStream>, response: ServerResponse) {
+    ws.send(Message::Text(Utf8Bytes::from(response.to_string()))).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_rejected_handshake_names_the_reason() {
+    let codes = [
+        (ResponseCode::BadRequest, 1),
+        (ResponseCode::NotFound, 1),
+        (ResponseCode::Conflict, 1),
+        (ResponseCode::Forbidden, 1),
+        (ResponseCode::InternalServerError, 2),
+        (ResponseCode::ServiceUnavailable, 2),
+    ];
+    for (code, attempts) in codes {
+        let (mut client, mut ws, _chat_rx) = unconnected_client().await;
+        client.set_retry_policy(RetryPolicy {
+            max_attempts: 2,
+            base_delay: std::time::Duration::from_millis(1),
+            ..RetryPolicy::default()
+        });
+        let server_side = async {
+            // Only the refusals the server may get over are retried
+            for _ in 0..attempts {
+                assert_eq!(next_handshake(&mut ws).await["request_type"], "establish_connection");
+                answer_handshake(&mut ws, ServerResponse::new(code, "Failed to parse prekey bundle".to_string())).await;
+            }
+        };
+        let (result, _) = tokio::join!(client.establish_connection(), server_side);
+        match result {
+            Err(ClientError::HandshakeRejected { code: refused, reason }) => {
+                assert_eq!(refused, code);
+                assert_eq!(reason, "Failed to parse prekey bundle");
+            }
+            other => panic!("Expected a rejected handshake for {}, got {:?}", code, other.err()),
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_handshake_is_retried_while_the_server_is_unavailable() {
+    let (mut client, mut ws, _chat_rx) = unconnected_client().await;
+    client.set_retry_policy(RetryPolicy { base_delay: std::time::Duration::from_millis(1), ..RetryPolicy::default() });
+    let server_side = async {
+        for _ in 0..2 {
+            next_handshake(&mut ws).await;
+            answer_handshake(&mut ws, ServerResponse::new(ResponseCode::ServiceUnavailable, "Busy".to_string())).await;
+        }
+        // The bundle is sent again on every attempt
+        let request = next_handshake(&mut ws).await;
+        assert!(PreKeyBundle::try_from(request["bundle"].as_str().unwrap().to_string()).is_ok());
+        answer_handshake(&mut ws, ServerResponse::new(ResponseCode::BadRequest, "Rate limited".to_string())).await;
+    };
+    let (result, _) = tokio::join!(client.establish_connection(), server_side);
+    assert!(matches!(
+        result,
+        Err(ClientError::HandshakeRejected { code: ResponseCode::BadRequest, reason }) if reason == "Rate limited"
+    ));
+}
+
+#[tokio::test]
+async fn test_close_chat_blocks_relay_until_chat_is_reopened() {
+    let (mut client, mut server, _chat_rx) = connected_client("alice").await;
+    client.listener = Some(client.start_read_loop());
+    client.friends.insert("bob".to_string(), dummy_friend());
+
+    let server_side = async {
+        server.next_request().await;
+        let filter = server.next_request().await;
+        assert_eq!(filter["body"]["request_type"], "relay_filter");
+        assert_eq!(filter["body"]["from"], "bob");
+        assert_eq!(filter["body"]["blocked"], true);
+        server.respond(&filter, "200", "Relay filter updated").await;
+    };
+    let (result, _) = tokio::join!(client.close_chat("bob".to_string()), server_side);
+    result.unwrap();
+    client.remove_friend("bob".to_string());
+
+    // Starting a new chat lifts the filter before fetching the bundle
+    let server_side = async {
+        let filter = server.next_request().await;
+        assert_eq!(filter["body"]["request_type"], "relay_filter");
+        assert_eq!(filter["body"]["blocked"], false);
+        server.respond(&filter, "200", "Relay filter updated").await;
+        let fetch = server.next_request().await;
+        assert_eq!(fetch["body"]["username"], "bob");
+        server.respond(&fetch, "404", "User not found").await;
+    };
+    let (result, _) = tokio::join!(client.get_user_prekey_bundle("bob".to_string()), server_side);
+    assert!(matches!(result, Err(ClientError::UserNotFoundError)));
+    assert!(client.relay_blocked.is_empty());
+}
+
+#[tokio::test]
+async fn test_refused_relay_closes_chat() {
+    let (mut client, mut server, mut chat_rx) = connected_client("alice").await;
+    client.listener = Some(client.start_read_loop());
+
+    // Chat messages carry no request id, so the refusal is not matched to a pending request
+    server.send(json!({
+        "request_id": "",
+        "body": { "code": "403", "message": "bob" },
+    })).await;
+
+    let close = chat_rx.recv().await.unwrap();
+    assert_eq!(close.msg_type, "close_chat");
+    assert_eq!(close.from, "bob");
+}
+
+#[tokio::test]
+async fn test_send_chat_message_reports_delivery() {
+    let (mut client, mut server, _chat_rx) = connected_client("alice").await;
+    client.listener = Some(client.start_read_loop());
+    client.friends.insert("bob".to_string(), friend_pair().0);
+
+    for (code, text, expected) in [("200", "Delivered", DeliveryStatus::Delivered), ("200", "Queued", DeliveryStatus::Queued)] {
+        let message = ChatMessage::new("chat".to_string(), "bob".to_string(), "alice".to_string(), "hi".to_string(), Utc::now());
+        let server_side = async {
+            let sent = server.next_request().await;
+            assert!(sent["request_id"].is_string());
+            server.respond(&sent, code, text).await;
+        };
+        let (status, _) = tokio::join!(client.send_chat_message(message), server_side);
+        assert_eq!(status.unwrap(), expected);
+    }
+}
+
+#[tokio::test]
+async fn test_friend_info_after_handshake() {
+    let (mut alice, mut alice_server, _alice_rx) = connected_client("alice").await;
+    let (mut bob, mut bob_server, _bob_rx) = connected_client("bob").await;
+    bob.listener = Some(bob.start_read_loop());
+    alice.listener = Some(alice.start_read_loop());
+    let bob_bundle = bob.bundle.to_base64();
+
+    let server_side = async {
+        let fetch = alice_server.next_request().await;
+        alice_server.respond(&fetch, "200", &bob_bundle).await;
+        alice_server.next_request().await
+    };
+    let (result, initial) = tokio::join!(alice.get_user_prekey_bundle("bob".to_string()), server_side);
+    result.unwrap();
+    bob.add_friend(serde_json::from_value(initial).unwrap()).unwrap();
+
+    for text in ["hi", "how are you?"] {
+        let message = ChatMessage::new("chat".to_string(), "bob".to_string(), "alice".to_string(), text.to_string(), Utc::now());
+        let (sent, relayed) = tokio::join!(alice.send_chat_message(message), alice_server.deliver());
+        sent.unwrap();
+        bob.decrypt_chat_message(serde_json::from_value(relayed).unwrap()).unwrap();
+    }
+    let reply = ChatMessage::new("chat".to_string(), "alice".to_string(), "bob".to_string(), "fine".to_string(), Utc::now());
+    let (sent, relayed) = tokio::join!(bob.send_chat_message(reply), bob_server.deliver());
+    sent.unwrap();
+    alice.decrypt_chat_message(serde_json::from_value(relayed).unwrap()).unwrap();
+
+    let fingerprint = |key: &PublicKey| key.hash().0.iter().map(|b| format!("{:02x}", b)).collect::<String>();
+    let on_alice = alice.friend_info("bob").unwrap();
+    assert_eq!(on_alice.username, "bob");
+    assert_eq!(on_alice.role, Role::Initiator);
+    assert_eq!(on_alice.fingerprint, fingerprint(&PublicKey::from(&bob.identity_key)));
+    assert_eq!((on_alice.messages_sent, on_alice.messages_received), (2, 1));
+
+    let on_bob = bob.friend_info("alice").unwrap();
+    assert_eq!(on_bob.role, Role::Responder);
+    assert_eq!(on_bob.fingerprint, fingerprint(&PublicKey::from(&alice.identity_key)));
+    assert_eq!((on_bob.messages_sent, on_bob.messages_received), (1, 2));
+    assert_eq!(on_bob.skipped_keys, 0);
+    assert!(on_bob.established_at <= Utc::now());
+
+    assert!(!on_bob.verified);
+    bob.set_verified("alice", true).unwrap();
+    assert!(bob.friend_info("alice").unwrap().verified);
+    assert!(bob.friend_info("carol").is_none());
+}
+
+#[tokio::test]
+async fn test_safety_number_is_the_same_on_both_sides() {
+    let (mut alice, _alice_ws, _alice_rx) = unconnected_client().await;
+    let (mut bob, _bob_ws, _bob_rx) = unconnected_client().await;
+    alice.username = "alice".to_string();
+    bob.username = "bob".to_string();
+    let (on_alice, on_bob) = friend_pair();
+    alice.friends.insert("bob".to_string(), on_alice);
+    bob.friends.insert("alice".to_string(), on_bob);
+
+    let number = alice.get_safety_number("bob").unwrap();
+    assert_eq!(number, bob.get_safety_number("alice").unwrap());
+    assert_eq!(number.len(), protocol::fingerprint::SAFETY_NUMBER_DIGITS);
+    assert!(matches!(alice.get_safety_number("carol"), Err(ClientError::UserNotFoundError)));
+
+    // A key handed out by someone else gives another number
+    bob.friends.insert("alice".to_string(), dummy_friend());
+    assert_ne!(number, bob.get_safety_number("alice").unwrap());
+}
+
+#[tokio::test]
+async fn test_verification_code_is_deterministic_and_independent_of_key_order() {
+    let (mut alice, _alice_ws, _alice_rx) = unconnected_client().await;
+    let (mut bob, _bob_ws, _bob_rx) = unconnected_client().await;
+    alice.username = "alice".to_string();
+    bob.username = "bob".to_string();
+    let (on_alice, on_bob) = friend_pair();
+    let (alice_ik, bob_ik) = (on_alice.aad.initiator_identity_key().clone(), on_alice.aad.responder_identity_key().clone());
+    alice.friends.insert("bob".to_string(), on_alice);
+    bob.friends.insert("alice".to_string(), on_bob);
+
+    // Each side holds the keys in its own order, ours first
+    let code = alice.verification_code("bob").unwrap();
+    assert_eq!(code, alice.verification_code("bob").unwrap());
+    assert_eq!(code, bob.verification_code("alice").unwrap());
+    let mut parties = [(&alice_ik, "alice"), (&bob_ik, "bob")];
+    parties.sort_by(|(a, _), (b, _)| a.as_ref().cmp(b.as_ref()));
+    assert_eq!(code, protocol::fingerprint::compute_safety_number(parties[0].0, parties[0].1, parties[1].0, parties[1].1));
+    assert_eq!(code, alice.get_safety_number("bob").unwrap());
+    assert!(alice.verification_code("carol").is_none());
+}
+
+#[tokio::test]
+async fn test_session_info_follows_the_exchange() {
+    let (mut client, _ws, _chat_rx) = unconnected_client().await;
+    let (bob, mut alice) = friend_pair();
+    client.friends.insert("bob".to_string(), bob);
+    let info = client.session_info("bob").unwrap();
+    assert_eq!((info.messages_sent, info.messages_received, info.skipped_keys), (0, 0, 0));
+    assert!(info.remote_key.is_some());
+    assert!(client.session_info("carol").is_none());
+
+    let mut from_bob = |text: &str| {
+        let text = alice.ratchet.encrypt(text.as_bytes(), &alice.get_friend_aad().to_bytes()).unwrap();
+        ChatMessage::new("chat".to_string(), "alice".to_string(), "bob".to_string(), text, Utc::now())
+    };
+    let (first, second, third) = (from_bob("first"), from_bob("second"), from_bob("third"));
+    client.decrypt_chat_message(third).unwrap();
+    let info = client.session_info("bob").unwrap();
+    assert_eq!((info.messages_received, info.skipped_keys), (3, 2));
+    assert_eq!(info.remote_key, alice.ratchet.state_snapshot().sending_key.into());
+
+    client.decrypt_chat_message(first).unwrap();
+    client.decrypt_chat_message(second).unwrap();
+    assert_eq!(client.session_info("bob").unwrap().skipped_keys, 0);
+}
+
+#[tokio::test]
+async fn test_lost_messages_are_noted_and_the_session_reset_on_request() {
+    let (mut alice, mut alice_server, _alice_rx) = connected_client("alice").await;
+    let (mut bob, mut bob_server, _bob_rx) = connected_client("bob").await;
+    alice.listener = Some(alice.start_read_loop());
+    bob.listener = Some(bob.start_read_loop());
+    let (alice_bundle, bob_bundle) = (alice.bundle.to_base64(), bob.bundle.to_base64());
+
+    let server_side = async {
+        let fetch = alice_server.next_request().await;
+        alice_server.respond(&fetch, "200", &bob_bundle).await;
+        alice_server.next_request().await
+    };
+    let (result, initial) = tokio::join!(alice.get_user_prekey_bundle("bob".to_string()), server_side);
+    result.unwrap();
+    bob.add_friend(serde_json::from_value(initial).unwrap()).unwrap();
+    let message = ChatMessage::new("chat".to_string(), "bob".to_string(), "alice".to_string(), "hi".to_string(), Utc::now());
+    let (sent, relayed) = tokio::join!(alice.send_and_store_chat_message(message), alice_server.deliver());
+    sent.unwrap();
+    bob.decrypt_chat_message(serde_json::from_value(relayed).unwrap()).unwrap();
+
+    // More messages than the ratchet may skip never reach bob
+    let missed = bob.friends["alice"].ratchet.max_skips() + 1;
+    let friend = alice.friends.get_mut("bob").unwrap();
+    for _ in 0..missed {
+        friend.ratchet.encrypt(b"lost", &friend.get_friend_aad().to_bytes()).unwrap();
+    }
+    let message = ChatMessage::new("chat".to_string(), "bob".to_string(), "alice".to_string(), "still there?".to_string(), Utc::now());
+    let (sent, relayed) = tokio::join!(alice.send_chat_message(message), alice_server.deliver());
+    sent.unwrap();
+
+    // A tampered copy claiming the same gap does not authenticate, and leaves no trace
+    let mut forged: ChatMessage = serde_json::from_value(relayed.clone()).unwrap();
+    let mut bytes = general_purpose::STANDARD.decode(&forged.text).unwrap();
+    *bytes.last_mut().unwrap() ^= 1;
+    forged.text = general_purpose::STANDARD.encode(bytes);
+    assert!(matches!(
+        bob.decrypt_chat_message(forged),
+        Err(ClientError::ProtocolError(ProtocolError::Ratchet(RatchetError::MaxSkipsExceeded(n)))) if n == missed
+    ));
+    assert_eq!(bob.get_chat_history("alice").unwrap().len(), 1);
+
+    // The genuine message is noted as a loss, and the session is kept until the user resets it
+    let before = bob.friends["alice"].ratchet.state_snapshot();
+    let result = bob.decrypt_chat_message(serde_json::from_value(relayed).unwrap());
+    assert!(matches!(result, Err(ClientError::MessagesLost(n)) if n == missed));
+    assert_eq!(bob.friends["alice"].ratchet.state_snapshot(), before);
+    let history = bob.get_chat_history("alice").unwrap();
+    assert_eq!(history.len(), 2);
+    assert_eq!(history[1].msg_type, "system_event");
+    assert_eq!(history[1].text, format!("{} messages could not be recovered", missed));
+
+    let server_side = async {
+        let fetch = bob_server.next_request().await;
+        assert_eq!(fetch["body"]["username"], "alice");
+        bob_server.respond(&fetch, "200", &alice_bundle).await;
+        bob_server.next_request().await
+    };
+    let (result, initial) = tokio::join!(bob.reset_session("alice"), server_side);
+    result.unwrap();
+    assert_eq!(initial["msg_type"], "initial_message");
+
+    // Alice takes the new session, and the conversation goes on in the same chat
+    alice.accept_initial_message(serde_json::from_value(initial).unwrap()).await.unwrap();
+    let message = ChatMessage::new("chat".to_string(), "alice".to_string(), "bob".to_string(), "back".to_string(), Utc::now());
+    let (sent, relayed) = tokio::join!(bob.send_chat_message(message), bob_server.deliver());
+    sent.unwrap();
+    alice.decrypt_chat_message(serde_json::from_value(relayed).unwrap()).unwrap();
+    let history = alice.get_chat_history("bob").unwrap();
+    assert_eq!(history.iter().map(|m| m.text.as_str()).collect::<Vec<_>>(), vec!["hi", "back"]);
+    assert_eq!(alice.friend_info("bob").unwrap().role, Role::Responder);
+
+    let reply = ChatMessage::new("chat".to_string(), "bob".to_string(), "alice".to_string(), "welcome back".to_string(), Utc::now());
+    let (sent, relayed) = tokio::join!(alice.send_chat_message(reply), alice_server.deliver());
+    sent.unwrap();
+    bob.decrypt_chat_message(serde_json::from_value(relayed).unwrap()).unwrap();
+    assert_eq!(bob.get_chat_history("alice").unwrap()[2].text, "welcome back");
+}
+
+#[tokio::test]
+async fn test_read_receipt_marks_message_read() {
+    let (mut alice, mut alice_server, _alice_rx) = connected_client("alice").await;
+    let (mut bob, mut bob_server, _bob_rx) = connected_client("bob").await;
+    alice.listener = Some(alice.start_read_loop());
+    bob.listener = Some(bob.start_read_loop());
+    let bob_bundle = bob.bundle.to_base64();
+
+    let server_side = async {
+        let fetch = alice_server.next_request().await;
+        alice_server.respond(&fetch, "200", &bob_bundle).await;
+        alice_server.next_request().await
+    };
+    let (result, initial) = tokio::join!(alice.get_user_prekey_bundle("bob".to_string()), server_side);
+    result.unwrap();
+    bob.add_friend(serde_json::from_value(initial).unwrap()).unwrap();
+
+    let message = ChatMessage::new("chat".to_string(), "bob".to_string(), "alice".to_string(), "hi".to_string(), Utc::now());
+    let (sent, relayed) = tokio::join!(alice.send_chat_message(message.clone()), alice_server.deliver());
+    sent.unwrap();
+    alice.add_chat_message(message.clone(), "bob");
+    // Payloads of older clients have no read flag
+    assert!(relayed.get("read").is_none());
+    bob.decrypt_chat_message(serde_json::from_value(relayed).unwrap()).unwrap();
+
+    let (sent, receipt) = tokio::join!(bob.send_read_receipts("alice"), bob_server.next_request());
+    sent.unwrap();
+    assert_eq!(receipt["msg_type"], "read_receipt");
+    assert_ne!(receipt["text"], message.timestamp.as_str());
+    assert_eq!(bob.unread_count("alice"), 0);
+    assert!(bob.get_chat_history("alice").unwrap()[0].read);
+
+    assert!(alice.apply_read_receipt(serde_json::from_value(receipt).unwrap()).unwrap());
+    let history = alice.get_chat_history("bob").unwrap();
+    assert_eq!(history.len(), 1);
+    assert!(history[0].read);
+    assert_eq!(alice.friend_info("bob").unwrap().messages_received, 0);
+
+    // Messages already acknowledged are not acknowledged again: the next thing Bob sends is his reply
+    bob.send_read_receipts("alice").await.unwrap();
+    let reply = ChatMessage::new("chat".to_string(), "alice".to_string(), "bob".to_string(), "still here".to_string(), Utc::now());
+    let (sent, next) = tokio::join!(bob.send_chat_message(reply), bob_server.deliver());
+    sent.unwrap();
+    assert_eq!(next["msg_type"], "chat");
+}
+
+#[tokio::test]
+async fn test_typing_is_not_kept_in_history() {
+    let (mut alice, mut alice_server, _alice_rx) = connected_client("alice").await;
+    let (mut bob, _bob_server, _bob_rx) = connected_client("bob").await;
+    alice.listener = Some(alice.start_read_loop());
+    let bob_bundle = bob.bundle.to_base64();
+
+    let server_side = async {
+        let fetch = alice_server.next_request().await;
+        alice_server.respond(&fetch, "200", &bob_bundle).await;
+        alice_server.next_request().await
+    };
+    let (result, initial) = tokio::join!(alice.get_user_prekey_bundle("bob".to_string()), server_side);
+    result.unwrap();
+    bob.add_friend(serde_json::from_value(initial).unwrap()).unwrap();
+
+    alice.send_typing("bob").await.unwrap();
+    let typing = alice_server.next_request().await;
+    assert_eq!(typing["msg_type"], "typing");
+    assert_eq!(bob.apply_typing(serde_json::from_value(typing).unwrap()).unwrap(), Some("alice".to_string()));
+    assert!(bob.get_chat_history("alice").unwrap().is_empty());
+    assert_eq!(bob.unread_count("alice"), 0);
+    assert_eq!(alice.friend_info("bob").unwrap().messages_sent, 0);
+
+    // The ratchet stays in step, so the next message still decrypts
+    let message = ChatMessage::new("chat".to_string(), "bob".to_string(), "alice".to_string(), "hi".to_string(), Utc::now());
+    let (sent, relayed) = tokio::join!(alice.send_chat_message(message), alice_server.deliver());
+    sent.unwrap();
+    bob.decrypt_chat_message(serde_json::from_value(relayed).unwrap()).unwrap();
+
+    // Once the chat is closed, late notifications are dropped
+    alice.send_typing("bob").await.unwrap();
+    let late = alice_server.next_request().await;
+    bob.remove_friend("alice".to_string());
+    assert_eq!(bob.apply_typing(serde_json::from_value(late).unwrap()).unwrap(), None);
+}
+
+#[tokio::test]
+async fn test_history_keeps_encryption_meta() {
+    let (mut alice, mut alice_server, _alice_rx) = connected_client("alice").await;
+    let (mut bob, mut bob_server, _bob_rx) = connected_client("bob").await;
+    bob.listener = Some(bob.start_read_loop());
+    alice.listener = Some(alice.start_read_loop());
+    let bob_bundle = serde_json::to_string(&bob.bundle).unwrap();
+
+    let server_side = async {
+        let fetch = alice_server.next_request().await;
+        alice_server.respond(&fetch, "200", &bob_bundle).await;
+        alice_server.next_request().await
+    };
+    let (result, initial) = tokio::join!(alice.get_user_prekey_bundle("bob".to_string()), server_side);
+    result.unwrap();
+    bob.add_friend(serde_json::from_value(initial).unwrap()).unwrap();
+
+    // Two messages from Alice, a reply from Bob, then Alice again after a DH ratchet step
+    for (from_alice, text) in [(true, "one"), (true, "two"), (false, "three"), (true, "four")] {
+        let (sender, sender_server, receiver, from, to) = if from_alice {
+            (&mut alice, &mut alice_server, &mut bob, "alice", "bob")
+        } else {
+            (&mut bob, &mut bob_server, &mut alice, "bob", "alice")
+        };
+        let message = ChatMessage::new("chat".to_string(), to.to_string(), from.to_string(), text.to_string(), Utc::now());
+        let (sent, relayed) = tokio::join!(sender.send_and_store_chat_message(message), sender_server.deliver());
+        sent.unwrap();
+        assert!(relayed.get("meta").is_none());
+        receiver.decrypt_chat_message(serde_json::from_value(relayed).unwrap()).unwrap();
+    }
+
+    let alice_history = alice.get_chat_history("bob").unwrap();
+    let bob_history = bob.get_chat_history("alice").unwrap();
+    let metas = alice_history.iter().map(|m| m.meta.clone().unwrap()).collect::<Vec<_>>();
+    assert_eq!(metas, bob_history.iter().map(|m| m.meta.clone().unwrap()).collect::<Vec<_>>());
+    assert!(metas.iter().all(|m| m.one_time_prekey && !m.ratchet.header_encrypted));
+    let counters = metas.iter().map(|m| (m.ratchet.previous_chain_length, m.ratchet.message_number)).collect::<Vec<_>>();
+    assert_eq!(counters, vec![(0, 0), (0, 1), (0, 0), (2, 0)]);
+    assert_eq!(metas[0].ratchet.ratchet_key, metas[1].ratchet.ratchet_key);
+    assert_ne!(metas[1].ratchet.ratchet_key, metas[3].ratchet.ratchet_key);
+}
+
+#[tokio::test]
+async fn test_lost_connection_is_reported() {
+    let (mut client, mut server, _chat_rx) = connected_client("alice").await;
+    client.listener = Some(client.start_read_loop());
+    assert!(client.is_connected());
+
+    server.ws.close(None).await.unwrap();
+    tokio::time::timeout(std::time::Duration::from_secs(5), async {
+        while client.is_connected() {
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+    }).await.expect("The lost connection was not reported");
+}
+
+#[tokio::test]
+async fn test_failed_reconnection_keeps_chats() {
+    let (mut client, _server, _chat_rx) = connected_client("alice").await;
+    client.friends.insert("bob".to_string(), dummy_friend());
+    // Nothing listens on the port once the listener is dropped
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    client.server_url = Some(format!("ws://{}", listener.local_addr().unwrap()));
+    drop(listener);
+    client.set_reconnect_policy(ReconnectPolicy {
+        max_attempts: 3,
+        base_delay: std::time::Duration::from_millis(10),
+        max_delay: std::time::Duration::from_millis(20),
+    });
+
+    let started = std::time::Instant::now();
+    assert!(client.reconnect().await.is_err());
+    // Two delays between three attempts: 10ms then 20ms
+    assert!(started.elapsed() >= std::time::Duration::from_millis(30));
+    assert!(!client.is_connected());
+    assert!(client.friends.contains_key("bob"));
+}
+
+#[tokio::test]
+async fn test_replenish_request_uploads_one_time_prekeys() {
+    let (mut client, mut server, mut chat_rx) = connected_client("alice").await;
+    client.listener = Some(client.start_read_loop());
+    let known = client.one_time_prekeys.len();
+
+    server.send(json!({ "msg_type": "replenish_prekeys", "count": 3 })).await;
+    let replenish = chat_rx.recv().await.unwrap();
+    assert_eq!(replenish.msg_type, "replenish_prekeys");
+    let count: usize = replenish.text.parse().unwrap();
+
+    let server_side = async {
+        let upload = server.next_request().await;
+        assert_eq!(upload["body"]["request_type"], "upload_prekeys");
+        let otpk = upload["body"]["otpk"].as_array().unwrap().clone();
+        let ids = upload["body"]["otpk_ids"].as_array().unwrap().clone();
+        let sigs = upload["body"]["otpk_sigs"].as_array().unwrap().clone();
+        server.respond(&upload, "200", "3").await;
+        (otpk, ids, sigs)
+    };
+    let (result, (otpk, ids, sigs)) = tokio::join!(client.upload_one_time_prekeys(count), server_side);
+    result.unwrap();
+
+    assert_eq!(otpk.len(), 3);
+    assert_eq!(sigs.len(), 3);
+    assert_eq!(client.one_time_prekeys.len(), known + 3);
+    for (key, id) in otpk.into_iter().zip(ids) {
+        let key = PublicKey::from_base64(key.as_str().unwrap().to_string()).unwrap();
+        let id = id.as_u64().unwrap() as u32;
+        // Uploaded keys continue the ids of the generated ones
+        assert!(id as usize >= known);
+        assert!(PublicKey::from(&client.one_time_prekeys[&id]) == key);
+        assert!(client.bundle.otpk.contains(&key));
+    }
+    assert_eq!(client.bundle.otpk_ids.len(), client.bundle.otpk.len());
+    // The uploaded keys are signed like the generated ones
+    assert!(client.bundle.validate().unwrap().is_clean());
+
+    // A request for more keys than the bundle can hold is cut down to the keys missing
+    let server_side = async {
+        let upload = server.next_request().await;
+        let otpk = upload["body"]["otpk"].as_array().unwrap().len();
+        server.respond(&upload, "200", &otpk.to_string()).await;
+        otpk
+    };
+    let (result, uploaded) = tokio::join!(client.upload_one_time_prekeys(1000), server_side);
+    result.unwrap();
+    assert_eq!(uploaded, protocol::constants::MAX_ONE_TIME_PREKEYS - known - 3);
+    assert_eq!(client.one_time_prekeys.len(), protocol::constants::MAX_ONE_TIME_PREKEYS);
+    client.upload_one_time_prekeys(5).await.unwrap();
+    assert_eq!(client.one_time_prekeys.len(), protocol::constants::MAX_ONE_TIME_PREKEYS);
+}
+
+#[tokio::test]
+async fn test_initial_message_in_either_format_is_accepted() {
+    let (mut alice, mut alice_server, _alice_rx) = connected_client("alice").await;
+    let (mut bob, _bob_server, _bob_rx) = connected_client("bob").await;
+    alice.listener = Some(alice.start_read_loop());
+    let bob_bundle = bob.bundle.to_base64();
+
+    let server_side = async {
+        let fetch = alice_server.next_request().await;
+        alice_server.respond(&fetch, "200", &bob_bundle).await;
+        alice_server.next_request().await
+    };
+    let (result, initial) = tokio::join!(alice.get_user_prekey_bundle("bob".to_string()), server_side);
+    result.unwrap();
+    let initial: ChatMessage = serde_json::from_value(initial).unwrap();
+    let im = InitialMessage::try_from(initial.text.clone()).unwrap();
+
+    // A bundle in the legacy packing does not advertise the versioned form, so the legacy string is sent
+    assert!(!initial.text.starts_with('{'));
+    bob.add_friend(initial.clone()).unwrap();
+    assert!(bob.friends.contains_key("alice"));
+
+    bob.remove_friend("alice".to_string());
+    let mut versioned = initial;
+    versioned.text = im.to_json();
+    bob.add_friend(versioned).unwrap();
+    assert!(bob.friends.contains_key("alice"));
+}
+
+#[tokio::test]
+async fn test_initial_message_is_versioned_for_peers_advertising_it() {
+    let (mut alice, mut alice_server, _alice_rx) = connected_client("alice").await;
+    let (bob, _bob_server, _bob_rx) = connected_client("bob").await;
+    alice.listener = Some(alice.start_read_loop());
+    assert!(bob.bundle.json_initial_message);
+    let bob_bundle = serde_json::to_string(&bob.bundle).unwrap();
+
+    let server_side = async {
+        let fetch = alice_server.next_request().await;
+        alice_server.respond(&fetch, "200", &bob_bundle).await;
+        alice_server.next_request().await
+    };
+    let (result, initial) = tokio::join!(alice.get_user_prekey_bundle("bob".to_string()), server_side);
+    result.unwrap();
+    let initial: ChatMessage = serde_json::from_value(initial).unwrap();
+    assert!(initial.text.starts_with('{'));
+}
+
+#[tokio::test]
+async fn test_one_time_prekey_is_named_by_id() {
+    let (mut alice, mut alice_server, _alice_rx) = connected_client("alice").await;
+    let (mut bob, _bob_server, _bob_rx) = connected_client("bob").await;
+    alice.listener = Some(alice.start_read_loop());
+    let bob_bundle = serde_json::to_string(&bob.bundle).unwrap();
+
+    let server_side = async {
+        let fetch = alice_server.next_request().await;
+        alice_server.respond(&fetch, "200", &bob_bundle).await;
+        alice_server.next_request().await
+    };
+    let (result, initial) = tokio::join!(alice.get_user_prekey_bundle("bob".to_string()), server_side);
+    result.unwrap();
+    let initial: ChatMessage = serde_json::from_value(initial).unwrap();
+    let im = InitialMessage::try_from(initial.text.clone()).unwrap();
+    assert_eq!(im.one_time_key_id, bob.bundle.otpk_ids.last().copied());
+    assert!(im.one_time_key_hash.is_none());
+
+    bob.add_friend(initial).unwrap();
+    assert!(bob.friend_info("alice").is_some());
+
+    // The conversation only works if both sides used the same one-time pre-key
+    let message = ChatMessage::new("chat".to_string(), "bob".to_string(), "alice".to_string(), "hi".to_string(), Utc::now());
+    let (sent, relayed) = tokio::join!(alice.send_chat_message(message), alice_server.deliver());
+    sent.unwrap();
+    bob.decrypt_chat_message(serde_json::from_value(relayed).unwrap()).unwrap();
+}
+
+#[tokio::test]
+async fn test_used_one_time_prekey_is_left_out_of_the_next_bundle() {
+    let (mut alice, mut alice_server, _alice_rx) = connected_client("alice").await;
+    let (mut bob, _bob_server, _bob_rx) = connected_client("bob").await;
+    alice.listener = Some(alice.start_read_loop());
+    let bob_bundle = serde_json::to_string(&bob.bundle).unwrap();
+
+    let server_side = async {
+        let fetch = alice_server.next_request().await;
+        alice_server.respond(&fetch, "200", &bob_bundle).await;
+        alice_server.next_request().await
+    };
+    let (result, initial) = tokio::join!(alice.get_user_prekey_bundle("bob".to_string()), server_side);
+    result.unwrap();
+    let initial: ChatMessage = serde_json::from_value(initial).unwrap();
+    let used = InitialMessage::try_from(initial.text.clone()).unwrap().one_time_key_id.unwrap();
+    bob.add_friend(initial.clone()).unwrap();
+    assert!(!bob.one_time_prekeys.contains_key(&used));
+
+    // A replayed initial message finds the key gone
+    bob.remove_friend("alice".to_string());
+    let result = bob.add_friend(initial);
+    assert!(matches!(result, Err(ClientError::SessionRejected(SessionRejection::UnknownPreKey))));
+
+    // The bundle sent on reconnection holds the keys left, signed
+    bob.restore_one_time_prekeys();
+    let mut held = bob.one_time_prekeys.keys().copied().collect::<Vec<u32>>();
+    held.sort_unstable();
+    assert_eq!(bob.bundle.otpk_ids, held);
+    assert_eq!(bob.bundle.otpk_sigs.len(), held.len());
+    assert!(bob.bundle.validate().is_ok());
+}
+
+#[tokio::test]
+async fn test_initial_message_to_rotated_signed_prekey_is_accepted() {
+    let (mut alice, mut alice_server, _alice_rx) = connected_client("alice").await;
+    let (mut bob, mut bob_server, _bob_rx) = connected_client("bob").await;
+    alice.listener = Some(alice.start_read_loop());
+    bob.listener = Some(bob.start_read_loop());
+    let old_bundle = bob.bundle.to_base64();
+
+    let server_side = async {
+        let fetch = alice_server.next_request().await;
+        alice_server.respond(&fetch, "200", &old_bundle).await;
+        alice_server.next_request().await
+    };
+    let (result, initial) = tokio::join!(alice.get_user_prekey_bundle("bob".to_string()), server_side);
+    result.unwrap();
+    let initial: ChatMessage = serde_json::from_value(initial).unwrap();
+
+    // Bob rotates while the initial message built against the old key is in flight
+    let old_spk = bob.bundle.spk.clone();
+    let server_side = async {
+        let upload = bob_server.next_request().await;
+        assert_eq!(upload["body"]["request_type"], "upload_signed_prekey");
+        assert_ne!(upload["body"]["spk"], old_spk.to_base64());
+        bob_server.respond(&upload, "200", "Signed pre-key replaced").await;
+    };
+    let (result, _) = tokio::join!(bob.rotate_signed_prekey(), server_side);
+    result.unwrap();
+    assert_ne!(bob.bundle.spk, old_spk);
+    assert!(bob.bundle.spk_created_at.is_some());
+    bob.bundle.validate().unwrap();
+
+    bob.add_friend(initial.clone()).unwrap();
+    let message = ChatMessage::new("chat".to_string(), "bob".to_string(), "alice".to_string(), "hi".to_string(), Utc::now());
+    let (sent, relayed) = tokio::join!(alice.send_chat_message(message), alice_server.deliver());
+    sent.unwrap();
+    bob.decrypt_chat_message(serde_json::from_value(relayed).unwrap()).unwrap();
+
+    // Once the grace period is over the old key is forgotten
+    bob.friends.remove("alice");
+    bob.set_signed_prekey_grace(Duration::zero());
+    assert!(matches!(
+        bob.add_friend(initial),
+        Err(ClientError::SessionRejected(SessionRejection::UnknownPreKey))
+    ));
+}
+
+#[tokio::test]
+async fn test_signed_prekey_is_rotated_when_due() {
+    let (mut bob, mut bob_server, _bob_rx) = connected_client("bob").await;
+    bob.listener = Some(bob.start_read_loop());
+    let old_spk = bob.bundle.spk.clone();
+
+    // Periodic rotation is off by default, and a fresh key is not due
+    assert!(!bob.rotate_signed_prekey_if_due().await.unwrap());
+    bob.set_signed_prekey_rotation_interval(Some(Duration::days(7)));
+    assert!(!bob.rotate_signed_prekey_if_due().await.unwrap());
+    assert_eq!(bob.bundle.spk, old_spk);
+
+    bob.set_signed_prekey_rotation_interval(Some(Duration::zero()));
+    let server_side = async {
+        let upload = bob_server.next_request().await;
+        assert_eq!(upload["body"]["request_type"], "upload_signed_prekey");
+        bob_server.respond(&upload, "200", "Signed pre-key replaced").await;
+    };
+    let (result, _) = tokio::join!(bob.rotate_signed_prekey_if_due(), server_side);
+    assert!(result.unwrap());
+    assert_ne!(bob.bundle.spk, old_spk);
+    bob.bundle.validate().unwrap();
+}
+
+#[tokio::test]
+async fn test_rejected_initial_message_drops_pending_session() {
+    let (mut alice, mut alice_server, mut alice_rx) = connected_client("alice").await;
+    let (mut bob, mut bob_server, _bob_rx) = connected_client("bob").await;
+    alice.listener = Some(alice.start_read_loop());
+    let bob_bundle = serde_json::to_string(&bob.bundle).unwrap();
+
+    let server_side = async {
+        let fetch = alice_server.next_request().await;
+        alice_server.respond(&fetch, "200", &bob_bundle).await;
+        alice_server.next_request().await
+    };
+    let (result, initial) = tokio::join!(alice.get_user_prekey_bundle("bob".to_string()), server_side);
+    result.unwrap();
+    assert!(alice.friends.contains_key("bob"));
+
+    // Bob no longer has the one-time pre-key Alice used
+    bob.one_time_prekeys.clear();
+    let result = bob.accept_initial_message(serde_json::from_value(initial).unwrap()).await;
+    assert!(matches!(result, Err(ClientError::SessionRejected(SessionRejection::UnknownPreKey))));
+    assert!(!bob.friends.contains_key("alice"));
+
+    // The rejection is sent in the clear through the relay
+    let rejection = bob_server.next_request().await;
+    assert_eq!(rejection["msg_type"], "session_rejected");
+    assert_eq!(rejection["to"], "alice");
+    assert_eq!(rejection["text"], "unknown_prekey");
+
+    alice_server.send(rejection).await;
+    let rejection = alice_rx.recv().await.unwrap();
+    assert_eq!(alice.session_rejected(rejection), Some(SessionRejection::UnknownPreKey));
+    assert!(!alice.friends.contains_key("bob"));
+}
+
+#[test]
+fn test_transient_plaintext_is_zeroizing() {
+    let (mut alice_side, mut bob_side) = friend_pair();
+    let aad = alice_side.get_friend_aad();
+    let ciphertext = alice_side.ratchet.encrypt(b"secret", &aad.to_bytes()).unwrap();
+
+    // Both buffers are wiped on drop, only the copies moved into the history are kept
+    let plaintext: Zeroizing<Vec<u8>> = bob_side.decrypt_inbound(ciphertext).unwrap();
+    assert_eq!(plaintext.as_slice(), b"secret");
+    let message = ChatMessage::new("chat".to_string(), "bob".to_string(), "alice".to_string(), "secret".to_string(), Utc::now());
+    let serialized: Zeroizing<String> = to_zeroizing_json(&message).unwrap();
+    assert_eq!(serde_json::from_str::<Value>(&serialized).unwrap()["text"], "secret");
+}
+
+#[tokio::test]
+async fn test_rejection_does_not_drop_established_session() {
+    let (mut alice, mut alice_server, _alice_rx) = connected_client("alice").await;
+    let (mut bob, mut bob_server, _bob_rx) = connected_client("bob").await;
+    bob.listener = Some(bob.start_read_loop());
+    let (alice_side, bob_side) = friend_pair();
+    alice.friends.insert("bob".to_string(), alice_side);
+    bob.friends.insert("alice".to_string(), bob_side);
+
+    // A malformed initial message is rejected without touching the existing chat
+    let garbage = ChatMessage::new("initial_message".to_string(), "bob".to_string(), "alice".to_string(), "garbage".to_string(), Utc::now());
+    assert!(bob.accept_initial_message(garbage).await.is_err());
+    let rejection = bob_server.next_request().await;
+    assert_eq!(rejection["text"], "malformed_message");
+    assert!(bob.friends.contains_key("alice"));
+
+    let reply = ChatMessage::new("chat".to_string(), "alice".to_string(), "bob".to_string(), "hi".to_string(), Utc::now());
+    let (sent, relayed) = tokio::join!(bob.send_chat_message(reply), bob_server.deliver());
+    sent.unwrap();
+    alice.decrypt_chat_message(serde_json::from_value(relayed).unwrap()).unwrap();
+
+    // Alice already heard from Bob, so the session is not a pending one
+    assert_eq!(alice.session_rejected(serde_json::from_value(rejection).unwrap()), None);
+    assert!(alice.friends.contains_key("bob"));
+}
+
+#[cfg(feature = "state-file")]
+#[tokio::test]
+async fn test_state_survives_relaunch() {
+    let (mut alice, mut alice_server, _alice_rx) = connected_client("alice").await;
+    let (mut bob, mut bob_server, _bob_rx) = connected_client("bob").await;
+    bob.listener = Some(bob.start_read_loop());
+    let (alice_side, bob_side) = friend_pair();
+    alice.friends.insert("bob".to_string(), alice_side);
+    bob.friends.insert("alice".to_string(), bob_side);
+
+    let message = ChatMessage::new("chat".to_string(), "alice".to_string(), "bob".to_string(), "before".to_string(), Utc::now());
+    let (sent, relayed) = tokio::join!(bob.send_chat_message(message), bob_server.deliver());
+    sent.unwrap();
+    alice.decrypt_chat_message(serde_json::from_value(relayed).unwrap()).unwrap();
+    alice.set_verified("bob", true).unwrap();
+    alice.block_user("mallory".to_string());
+
+    let path = std::env::temp_dir().join(format!("state-{}", Uuid::new_v4()));
+    alice.save_state(&path, "correct horse").unwrap();
+
+    let (mut relaunched, _server, _rx) = connected_client("alice").await;
+    relaunched.load_state(&path, "correct horse").unwrap();
+    let history = relaunched.get_chat_history("bob").unwrap();
+    assert_eq!(history.len(), 1);
+    assert_eq!(history[0].text, "before");
+    assert!(relaunched.friend_info("bob").unwrap().verified);
+    assert!(relaunched.is_blocked("mallory"));
+
+    // The restored ratchet picks up where it left off
+    let message = ChatMessage::new("chat".to_string(), "alice".to_string(), "bob".to_string(), "after".to_string(), Utc::now());
+    let (sent, relayed) = tokio::join!(bob.send_chat_message(message), bob_server.deliver());
+    sent.unwrap();
+    relaunched.decrypt_chat_message(serde_json::from_value(relayed).unwrap()).unwrap();
+    assert_eq!(relaunched.get_chat_history("bob").unwrap()[1].text, "after");
+
+    assert!(matches!(relaunched.load_state(&path, "wrong"), Err(ClientError::StateError(_))));
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[cfg(feature = "state-file")]
+#[tokio::test]
+async fn test_incompatible_state_version_is_rejected() {
+    let (mut client, _server, _rx) = connected_client("alice").await;
+    client.friends.insert("bob".to_string(), dummy_friend());
+    let path = std::env::temp_dir().join(format!("state-{}", Uuid::new_v4()));
+    client.save_state(&path, "passphrase").unwrap();
+
+    let mut file = std::fs::read(&path).unwrap();
+    // Files of the first layout had their key derived otherwise
+    file[0] = 1;
+    std::fs::write(&path, &file).unwrap();
+    assert!(matches!(client.load_state(&path, "passphrase"), Err(ClientError::IncompatibleStateVersion(1))));
+
+    std::fs::write(&path, b"").unwrap();
+    assert!(matches!(client.load_state(&path, "passphrase"), Err(ClientError::StateError(_))));
+    // A failed load leaves the open chats alone
+    assert!(client.friends.contains_key("bob"));
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[cfg(feature = "state-file")]
+#[tokio::test]
+async fn test_ephemeral_chat_is_never_saved() {
+    let (mut alice, mut alice_server, _alice_rx) = connected_client("alice").await;
+    let (mut bob, mut bob_server, _bob_rx) = connected_client("bob").await;
+    bob.listener = Some(bob.start_read_loop());
+    alice.listener = Some(alice.start_read_loop());
+    alice.friends.insert("carol".to_string(), dummy_friend());
+    let bob_bundle = bob.bundle.to_base64();
+
+    let server_side = async {
+        let fetch = alice_server.next_request().await;
+        alice_server.respond(&fetch, "200", &bob_bundle).await;
+        (alice_server.next_request().await, alice_server.next_request().await)
+    };
+    let (result, (initial, settings)) = tokio::join!(alice.open_chat("bob".to_string(), true), server_side);
+    result.unwrap();
+    assert_eq!(settings["msg_type"], "chat_settings");
+    assert!(alice.is_ephemeral("bob"));
+
+    // Bob honors the setting chosen by Alice
+    bob.accept_initial_message(serde_json::from_value(initial).unwrap()).await.unwrap();
+    assert!(!bob.is_ephemeral("alice"));
+    bob.apply_chat_settings(serde_json::from_value(settings).unwrap()).unwrap();
+    assert!(bob.friend_info("alice").unwrap().ephemeral);
+
+    let message = ChatMessage::new("chat".to_string(), "alice".to_string(), "bob".to_string(), "off the record".to_string(), Utc::now());
+    let (sent, relayed) = tokio::join!(bob.send_chat_message(message), bob_server.deliver());
+    sent.unwrap();
+    alice.decrypt_chat_message(serde_json::from_value(relayed).unwrap()).unwrap();
+    let message = ChatMessage::new("chat".to_string(), "bob".to_string(), "alice".to_string(), "agreed".to_string(), Utc::now());
+    let (sent, relayed) = tokio::join!(alice.send_chat_message(message.clone()), alice_server.deliver());
+    sent.unwrap();
+    alice.add_chat_message(message, "bob");
+    bob.decrypt_chat_message(serde_json::from_value(relayed).unwrap()).unwrap();
+    assert_eq!(alice.get_chat_history("bob").unwrap().len(), 2);
+
+    let alice_path = std::env::temp_dir().join(format!("state-{}", Uuid::new_v4()));
+    let bob_path = std::env::temp_dir().join(format!("state-{}", Uuid::new_v4()));
+    alice.save_state(&alice_path, "passphrase").unwrap();
+    bob.save_state(&bob_path, "passphrase").unwrap();
+
+    let (mut relaunched, _server, _rx) = connected_client("alice").await;
+    relaunched.load_state(&alice_path, "passphrase").unwrap();
+    assert_eq!(relaunched.get_open_chats(), vec!["carol".to_string()]);
+    relaunched.load_state(&bob_path, "passphrase").unwrap();
+    assert!(relaunched.get_open_chats().is_empty());
+
+    // Closing an ephemeral chat does not archive its history either
+    alice.archive_friend("bob");
+    assert!(alice.get_archived_history("bob").is_none());
+    std::fs::remove_file(&alice_path).unwrap();
+    std::fs::remove_file(&bob_path).unwrap();
+}
+
+#[tokio::test]
+async fn test_chat_goes_on_across_a_rekey() {
+    let (mut alice, mut alice_server, _alice_rx) = connected_client("alice").await;
+    alice.listener = Some(alice.start_read_loop());
+    let (mut bob, mut bob_server, _bob_rx) = connected_client("bob").await;
+    bob.listener = Some(bob.start_read_loop());
+    let (alice_side, bob_side) = friend_pair();
+    alice.friends.insert("bob".to_string(), alice_side);
+    bob.friends.insert("alice".to_string(), bob_side);
+
+    for text in ["before", "after"] {
+        if text == "after" {
+            let (sent, rekey) = tokio::join!(alice.rekey_session("bob"), alice_server.next_request());
+            sent.unwrap();
+            assert!(bob.apply_rekey(serde_json::from_value(rekey).unwrap()).unwrap());
+        }
+        let message = ChatMessage::new("chat".to_string(), "bob".to_string(), "alice".to_string(), text.to_string(), Utc::now());
+        let (sent, relayed) = tokio::join!(alice.send_chat_message(message), alice_server.deliver());
+        sent.unwrap();
+        bob.decrypt_chat_message(serde_json::from_value(relayed).unwrap()).unwrap();
+    }
+    let history = bob.get_chat_history("alice").unwrap();
+    let ratchet_key = |i: usize| history[i].meta.as_ref().unwrap().ratchet.ratchet_key.clone();
+    assert_eq!(history[1].text, "after");
+    assert_ne!(ratchet_key(0), ratchet_key(1));
+
+    // Bob follows the rotation and the chat goes on
+    let reply = ChatMessage::new("chat".to_string(), "alice".to_string(), "bob".to_string(), "reply".to_string(), Utc::now());
+    let (sent, relayed) = tokio::join!(bob.send_chat_message(reply), bob_server.deliver());
+    sent.unwrap();
+    alice.decrypt_chat_message(serde_json::from_value(relayed).unwrap()).unwrap();
+    assert_eq!(alice.get_chat_history("bob").unwrap()[0].text, "reply");
+}
+
+#[tokio::test]
+async fn test_rekey_session_sends_the_new_key_at_once() {
+    let (mut alice, mut alice_server, _alice_rx) = connected_client("alice").await;
+    alice.listener = Some(alice.start_read_loop());
+    let (mut bob, _bob_server, _bob_rx) = unconnected_client().await;
+    bob.username = "bob".to_string();
+    let (alice_side, bob_side) = friend_pair();
+    alice.friends.insert("bob".to_string(), alice_side);
+    bob.friends.insert("alice".to_string(), bob_side);
+    assert!(matches!(alice.rekey_session("carol").await, Err(ClientError::UserNotFoundError)));
+
+    let before = alice.session_info("bob").unwrap().sending_key;
+    let (sent, rekey) = tokio::join!(alice.rekey_session("bob"), alice_server.next_request());
+    sent.unwrap();
+    let after = alice.session_info("bob").unwrap().sending_key;
+    assert_ne!(before, after);
+    assert_eq!(rekey["msg_type"], "rekey");
+
+    assert!(bob.apply_rekey(serde_json::from_value(rekey).unwrap()).unwrap());
+    assert!(bob.get_chat_history("alice").unwrap().is_empty());
+    assert_eq!(bob.session_info("alice").unwrap().remote_key, Some(after));
+}
+
+#[tokio::test]
+async fn test_group_messages_are_collated_by_group() {
+    let (mut alice, mut alice_server, _alice_rx) = connected_client("alice").await;
+    alice.listener = Some(alice.start_read_loop());
+    let mut members = Vec::new();
+    for name in ["bob", "carol"] {
+        let (mut member, _server, _rx) = unconnected_client().await;
+        member.username = name.to_string();
+        let (alice_side, member_side) = friend_pair();
+        alice.friends.insert(name.to_string(), alice_side);
+        member.friends.insert("alice".to_string(), member_side);
+        members.push(member);
+    }
+
+    let names = vec!["Bob".to_string(), "carol".to_string(), "alice".to_string(), "bob".to_string()];
+    let (created, invites) = tokio::join!(
+        alice.create_group("weekend".to_string(), names),
+        async { (alice_server.next_request().await, alice_server.next_request().await) }
+    );
+    let id = created.unwrap();
+    assert_eq!(alice.get_group(&id).unwrap().members, ["bob", "carol"]);
+    for (member, invite) in members.iter_mut().zip([invites.0, invites.1]) {
+        assert_eq!(invite["msg_type"], "group_invite");
+        assert!(invite.get("group_id").is_none());
+        assert_eq!(member.apply_group_invite(serde_json::from_value(invite).unwrap()).unwrap(), id);
+    }
+    let bob_view = members[0].get_group(&id).unwrap();
+    assert_eq!(bob_view.name, "weekend");
+    assert_eq!(bob_view.members, ["carol", "alice"]);
+
+    // One copy per member, each encrypted with the session of that member
+    let (sent, copies) = tokio::join!(
+        alice.send_group_message(&id, "hi all".to_string()),
+        async { (alice_server.deliver().await, alice_server.deliver().await) }
+    );
+    let statuses = sent.unwrap();
+    assert_eq!(statuses.iter().map(|(m, _)| m.as_str()).collect::<Vec<_>>(), ["bob", "carol"]);
+    assert_ne!(copies.0["text"], copies.1["text"]);
+    // The server cannot tell the copies belong to the same group
+    assert!(copies.0.get("group_id").is_none() && copies.1.get("group_id").is_none());
+    assert_eq!(copies.0["msg_type"], "group_chat");
+    assert_eq!(alice.get_group_history(&id).unwrap().len(), 1);
+
+    for (member, copy) in members.iter_mut().zip([copies.0, copies.1]) {
+        assert_eq!(member.decrypt_chat_message(serde_json::from_value(copy).unwrap()).unwrap(), id);
+        let history = member.get_group_history(&id).unwrap();
+        assert_eq!(history[0].text, "hi all");
+        assert_eq!(history[0].from, "alice");
+        // Kept out of the pairwise chat
+        assert!(member.get_chat_history("alice").unwrap().is_empty());
+        assert_eq!(member.total_unread(), 1);
+        member.mark_group_read(&id);
+        assert_eq!(member.total_unread(), 0);
+    }
+    assert!(matches!(alice.send_group_message("nope", "hi".to_string()).await, Err(ClientError::UnknownGroup(_))));
+
+    // A member who left the group still gets the message, in the chat with its sender
+    assert!(members[0].leave_group(&id));
+    let (sent, copies) = tokio::join!(
+        alice.send_group_message(&id, "still there?".to_string()),
+        async { (alice_server.deliver().await, alice_server.deliver().await) }
+    );
+    sent.unwrap();
+    assert_eq!(members[0].decrypt_chat_message(serde_json::from_value(copies.0).unwrap()).unwrap(), "alice");
+    let history = members[0].get_chat_history("alice").unwrap();
+    assert_eq!((history[0].text.as_str(), history[0].group_id.as_ref()), ("still there?", None));
+
+    // And an invite from a blocked user is dropped before decryption
+    members[1].block_user("alice".to_string());
+    let invite = ChatMessage::new("group_invite".to_string(), "carol".to_string(), "alice".to_string(), String::new(), Utc::now());
+    assert!(matches!(members[1].apply_group_invite(invite), Err(ClientError::BlockedUser(_))));
+}
+
+/// Connects alice and bob, who share a chat, and writes a file of `size` bytes for alice to send.
+#[cfg(feature = "file-transfer")]
+async fn file_transfer_setup(size: usize) -> (Client, super::support::MockServer, Client, std::path::PathBuf) {
+    let (mut alice, alice_server, _alice_rx) = connected_client("alice").await;
+    let (mut bob, _bob_server, _bob_rx) = connected_client("bob").await;
+    alice.listener = Some(alice.start_read_loop());
+    let (alice_side, bob_side) = friend_pair();
+    alice.friends.insert("bob".to_string(), alice_side);
+    bob.friends.insert("alice".to_string(), bob_side);
+
+    let dir = std::env::temp_dir().join(format!("files-{}", Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("notes.bin");
+    std::fs::write(&path, (0..size).map(|i| (i % 251) as u8).collect::<Vec<_>>()).unwrap();
+    (alice, alice_server, bob, path)
+}
+
+#[cfg(feature = "file-transfer")]
+#[tokio::test]
+async fn test_file_is_sent_in_chunks_and_reassembled() {
+    use crate::files::FILE_CHUNK_SIZE;
+    let (mut alice, mut alice_server, mut bob, path) = file_transfer_setup(2 * FILE_CHUNK_SIZE + 100).await;
+
+    let server_side = async {
+        let mut chunks = vec![];
+        for _ in 0..3 {
+            chunks.push(alice_server.deliver().await);
+        }
+        chunks
+    };
+    let (status, chunks) = tokio::join!(alice.send_file("bob", &path), server_side);
+    assert_eq!(status.unwrap(), DeliveryStatus::Delivered);
+    assert!(chunks.iter().all(|chunk| chunk["msg_type"] == "file"));
+    // The chunks travel encrypted, file name included
+    assert!(!chunks[0]["text"].as_str().unwrap().contains("notes.bin"));
+
+    let downloads = path.parent().unwrap().join("downloads");
+    let mut written = vec![];
+    for chunk in chunks {
+        written.push(bob.receive_file_chunk(serde_json::from_value(chunk).unwrap(), &downloads).unwrap());
+    }
+    assert_eq!(written[..2], [None, None]);
+    let written = written[2].clone().unwrap();
+    assert_eq!(written, downloads.join("notes.bin"));
+    assert_eq!(std::fs::read(&written).unwrap(), std::fs::read(&path).unwrap());
+
+    for (client, friend, from) in [(&alice, "bob", "alice"), (&bob, "alice", "alice")] {
+        let history = client.get_chat_history(friend).unwrap();
+        assert_eq!(history.len(), 1);
+        assert_eq!((history[0].msg_type.as_str(), history[0].text.as_str(), history[0].from.as_str()), ("file", "notes.bin", from));
+    }
+    std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+}
+
+#[cfg(feature = "file-transfer")]
+#[tokio::test]
+async fn test_missing_file_chunk_is_an_error() {
+    use crate::files::FILE_CHUNK_SIZE;
+    let (mut alice, mut alice_server, mut bob, path) = file_transfer_setup(2 * FILE_CHUNK_SIZE + 100).await;
+
+    let server_side = async {
+        let mut chunks = vec![];
+        for _ in 0..3 {
+            chunks.push(alice_server.deliver().await);
+        }
+        chunks
+    };
+    let (status, mut chunks) = tokio::join!(alice.send_file("bob", &path), server_side);
+    status.unwrap();
+
+    // The second chunk is lost on the way
+    chunks.remove(1);
+    let downloads = path.parent().unwrap().join("downloads");
+    assert_eq!(bob.receive_file_chunk(serde_json::from_value(chunks.remove(0)).unwrap(), &downloads).unwrap(), None);
+    let result = bob.receive_file_chunk(serde_json::from_value(chunks.remove(0)).unwrap(), &downloads);
+    assert!(matches!(result, Err(ClientError::FileError(e)) if e == "Chunk 1 of notes.bin is missing"));
+    assert!(!downloads.exists());
+    assert!(bob.partial_files.is_empty());
+    assert!(bob.get_chat_history("alice").unwrap().is_empty());
+
+    // A name that would escape the download directory is refused
+    let chunk = crate::files::FileChunk {
+        filename: "../notes.bin".to_string(),
+        index: 0,
+        total: 1,
+        sha256: String::new(),
+        data: String::new(),
+    };
+    let friend = alice.friends.get_mut("bob").unwrap();
+    let text = friend.ratchet.encrypt(serde_json::to_string(&chunk).unwrap().as_bytes(), &friend.get_friend_aad().to_bytes()).unwrap();
+    let message = ChatMessage::new("file".to_string(), "bob".to_string(), "alice".to_string(), text, Utc::now());
+    assert!(matches!(bob.receive_file_chunk(message, &downloads), Err(ClientError::FileError(_))));
+    assert!(!downloads.exists());
+    std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+}
+
+#[cfg(feature = "file-transfer")]
+#[tokio::test]
+async fn test_partial_files_are_kept_per_sender_capped_and_expired() {
+    use crate::files::{FileChunk, MAX_PARTIAL_FILES_PER_SENDER, PARTIAL_FILE_TIMEOUT};
+    let (mut bob, _bob_server, _bob_rx) = connected_client("bob").await;
+    let (mut alice_side, bob_side) = friend_pair();
+    let (mut carol_side, bob_carol_side) = friend_pair();
+    bob.friends.insert("alice".to_string(), bob_side);
+    bob.friends.insert("carol".to_string(), bob_carol_side);
+    let downloads = std::env::temp_dir().join(format!("files-{}", Uuid::new_v4()));
+
+    // The first of two chunks of a file
+    let first_chunk = |sender: &mut Friend, from: &str, filename: &str| {
+        let chunk = FileChunk {
+            filename: filename.to_string(),
+            index: 0,
+            total: 2,
+            sha256: String::new(),
+            data: String::new(),
+        };
+        let text = sender.ratchet.encrypt(serde_json::to_string(&chunk).unwrap().as_bytes(), &sender.get_friend_aad().to_bytes()).unwrap();
+        ChatMessage::new("file".to_string(), "bob".to_string(), from.to_string(), text, Utc::now())
+    };
+
+    // The same name from two friends names two files
+    for (sender, from) in [(&mut alice_side, "alice"), (&mut carol_side, "carol")] {
+        assert_eq!(bob.receive_file_chunk(first_chunk(sender, from, "notes.bin"), &downloads).unwrap(), None);
+    }
+    assert_eq!(bob.partial_files.len(), 2);
+
+    // A friend starting too many files loses the oldest one, not the files of others
+    for n in 0..MAX_PARTIAL_FILES_PER_SENDER {
+        bob.receive_file_chunk(first_chunk(&mut alice_side, "alice", &format!("{}.bin", n)), &downloads).unwrap();
+    }
+    assert_eq!(bob.partial_files.keys().filter(|(from, _)| from == "alice").count(), MAX_PARTIAL_FILES_PER_SENDER);
+    assert!(!bob.partial_files.contains_key(&("alice".to_string(), "notes.bin".to_string())));
+    assert!(bob.partial_files.contains_key(&("carol".to_string(), "notes.bin".to_string())));
+
+    // A file that stopped arriving is dropped with the next chunk
+    let stale = bob.partial_files.get_mut(&("carol".to_string(), "notes.bin".to_string())).unwrap();
+    stale.updated = stale.updated - PARTIAL_FILE_TIMEOUT;
+    bob.receive_file_chunk(first_chunk(&mut alice_side, "alice", "more.bin"), &downloads).unwrap();
+    assert!(bob.partial_files.keys().all(|(from, _)| from == "alice"));
+    assert!(!downloads.exists());
+}
+
+#[cfg(feature = "file-transfer")]
+#[tokio::test]
+async fn test_file_larger_than_the_cap_is_not_sent() {
+    use crate::files::MAX_FILE_SIZE;
+    let (mut alice, _alice_server, _bob, path) = file_transfer_setup(MAX_FILE_SIZE + 1).await;
+
+    let result = alice.send_file("bob", &path).await;
+    assert!(matches!(result, Err(ClientError::FileTooLarge(size)) if size == MAX_FILE_SIZE as u64 + 1));
+    assert!(alice.get_chat_history("bob").unwrap().is_empty());
+    std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+}
@@ -0,0 +1,34 @@
+//! Feature hygiene of the client crate: it must build as a headless library, without the terminal
+//! interface dependencies, so that bots can embed it.
+
+use std::process::Command;
+
+fn cargo() -> Command {
+    let mut command = Command::new(env!("CARGO"));
+    command.current_dir(env!("CARGO_MANIFEST_DIR"));
+    command
+}
+
+#[test]
+fn test_builds_without_default_features() {
+    // A separate target directory, since the build directory is locked while the tests run
+    let status = cargo()
+        .args(["check", "--lib", "--no-default-features"])
+        .env("CARGO_TARGET_DIR", concat!(env!("CARGO_TARGET_TMPDIR"), "/no-default-features"))
+        .status()
+        .unwrap();
+    assert!(status.success());
+}
+
+#[test]
+fn test_no_terminal_dependencies() {
+    let output = cargo()
+        .args(["tree", "--no-default-features", "--edges", "normal", "--prefix", "none"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    let tree = String::from_utf8(output.stdout).unwrap();
+    for dependency in ["ratatui ", "crossterm ", "env_logger "] {
+        assert!(!tree.lines().any(|line| line.starts_with(dependency)), "client pulls in {}", dependency.trim());
+    }
+}
@@ -1,57 +1,64 @@
-use arrayref::array_ref;
 use base64::{engine::general_purpose, Engine as _};
-use log::{debug, error};
+use log::debug;
 use protocol::{
     constants::AES256_NONCE_LENGTH,
-    utils::{AssociatedData, DecryptionKey},
+    errors::X3DHError,
+    utils::{AssociatedData, DecryptionKey, PreKeyBundle},
 };
-use serde_json::{json, Value};
+use serde_json::Value;
 use std::fmt::Display;
 use serde::{Serialize, Deserialize};
 use std::fs;
+use std::ops::RangeInclusive;
+use std::string::FromUtf8Error;
 use std::sync::LazyLock;
 
-pub fn decrypt_request(req: &str, dk: &DecryptionKey) -> Result<(Value, AssociatedData), ()> {
-    let enc_req = match general_purpose::STANDARD.decode(req.to_string()) {
-        Ok(s) => s,
-        Err(_e) => {
-            error!("Failed to decode request");
-            return Err(());
-        }
-    };
-    let nonce = *array_ref!(enc_req, 0, AES256_NONCE_LENGTH);
-    let aad = match AssociatedData::try_from(array_ref!(
-        enc_req,
-        AES256_NONCE_LENGTH,
-        AssociatedData::SIZE
-    )) {
-        Ok(aad) => aad,
-        Err(_) => return Err(()),
-    };
-    let offset = AES256_NONCE_LENGTH + AssociatedData::SIZE;
-    let end = enc_req.len();
-    let cipher_text = &enc_req[offset..end];
-    let text = match dk.decrypt(cipher_text, &nonce, &aad.clone().to_bytes()) {
-        Ok(dec) => dec,
-        Err(_) => return Err(()),
-    };
-
-    debug!(
-        "Decrypted request: {}",
-        String::from_utf8(text.clone()).unwrap()
-    );
-    match String::from_utf8(text) {
-        Ok(s) => Ok((
-            serde_json::from_str::<Value>(&s).unwrap_or(Value::Null),
-            aad,
-        )),
-        Err(e) => {
-            error!("Failed to parse request: {}", e);
-            Err(())
+/// Why [`decrypt_request`] could not read a frame.
+#[derive(Debug)]
+pub enum CommonError {
+    /// The frame is not valid base64.
+    Base64(base64::DecodeError),
+    /// The decoded frame, of the given length, cannot hold the nonce.
+    TooShort(usize),
+    /// The frame was not encrypted with the key, or for other associated data.
+    Aead(X3DHError),
+    /// The plaintext is not UTF-8.
+    Utf8(FromUtf8Error),
+    /// The plaintext is not JSON.
+    Json(serde_json::Error),
+}
+
+impl Display for CommonError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CommonError::Base64(e) => write!(f, "Invalid base64: {}", e),
+            CommonError::TooShort(len) => write!(f, "Frame too short: {} bytes", len),
+            CommonError::Aead(e) => write!(f, "Decryption failed: {}", e),
+            CommonError::Utf8(e) => write!(f, "Invalid UTF-8: {}", e),
+            CommonError::Json(e) => write!(f, "Invalid JSON: {}", e),
         }
     }
 }
 
+impl std::error::Error for CommonError {}
+
+/// Decrypts a base64 `[nonce | ciphertext]` frame with `dk` and parses the plaintext as JSON.
+///
+/// The associated data of the session, `aad`, is not part of the frame and is authenticated
+/// as known by both ends. Frames of older peers, which still carry it after the nonce, are
+/// accepted as well.
+pub fn decrypt_request(req: &str, dk: &DecryptionKey, aad: &AssociatedData) -> Result<Value, CommonError> {
+    let enc_req = general_purpose::STANDARD.decode(req).map_err(CommonError::Base64)?;
+    if enc_req.len() < AES256_NONCE_LENGTH {
+        return Err(CommonError::TooShort(enc_req.len()));
+    }
+    let text = dk.decrypt_frame(&enc_req, &aad.to_bytes()).map_err(CommonError::Aead)?;
+
+    let text = String::from_utf8(text).map_err(CommonError::Utf8)?;
+    debug!("Decrypted request: {}", text);
+    serde_json::from_str::<Value>(&text).map_err(CommonError::Json)
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct RequestWrapper {
     pub request_id: String,
@@ -66,13 +73,26 @@ pub struct ResponseWrapper {
     pub body: Value,
 }
 
-#[derive(Serialize, Deserialize)]
+/// The status of a [`ServerResponse`], written as its HTTP-like number, such as `"200"`.
+/// The variant names, which the derived serde form used to write, are still read.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ResponseCode {
+    #[serde(rename = "200", alias = "Ok")]
     Ok,
+    #[serde(rename = "400", alias = "BadRequest")]
     BadRequest,
+    #[serde(rename = "404", alias = "NotFound")]
     NotFound,
+    #[serde(rename = "500", alias = "InternalServerError")]
     InternalServerError,
+    #[serde(rename = "409", alias = "Conflict")]
     Conflict,
+    /// The recipient closed the chat and asked the server to stop relaying from the sender.
+    #[serde(rename = "403", alias = "Forbidden")]
+    Forbidden,
+    /// The server does not take more registrations, the text says why.
+    #[serde(rename = "503", alias = "ServiceUnavailable")]
+    ServiceUnavailable,
 }
 
 impl Display for ResponseCode {
@@ -83,6 +103,8 @@ impl Display for ResponseCode {
             ResponseCode::NotFound => write!(f, "404"),
             ResponseCode::InternalServerError => write!(f, "500"),
             ResponseCode::Conflict => write!(f, "409"),
+            ResponseCode::Forbidden => write!(f, "403"),
+            ResponseCode::ServiceUnavailable => write!(f, "503"),
         }
     }
 }
@@ -98,13 +120,17 @@ impl TryFrom<&str> for ResponseCode {
             "404" => Ok(Self::NotFound),
             "500" => Ok(Self::InternalServerError),
             "409" => Ok(Self::Conflict),
+            "403" => Ok(Self::Forbidden),
+            "503" => Ok(Self::ServiceUnavailable),
             _ => Err(()),
         }
     }
 }
+/// The answer to a request, written as `{"code": "200", "message": "..."}` by both serde and [`Display`].
 #[derive(Serialize, Deserialize)]
 pub struct ServerResponse {
     pub code: ResponseCode,
+    #[serde(rename = "message", alias = "text")]
     pub text: String,
 }
 
@@ -114,45 +140,293 @@ impl ServerResponse {
     }
 
     pub fn from_json(value: String) -> Option<Self>{
-        let value = serde_json::from_str::<Value>(&value).ok()?;
-        let code = value.get("code")?.as_str()?;
-        let text = value.get("message")?.as_str()?;
-        let code = ResponseCode::try_from(code).ok()?;
-        Some(Self::new(code, text.to_string()))
+        serde_json::from_str(&value).ok()
     }
 }
 
 
 impl Display for ServerResponse {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let res = json!({
-            "code": self.code.to_string(),
-            "message": self.text
-        })
-        .to_string();
-
+        let res = serde_json::to_string(self).map_err(|_| std::fmt::Error)?;
         write!(f, "{}", res)
     }
 }
 
+/// The number of characters of a username, checked at registration by [`validate_username`].
+pub const USERNAME_LENGTH: RangeInclusive<usize> = 3..=32;
+
+/// Why a username was refused by [`normalize_username`] or [`validate_username`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UsernameError {
+    /// Nothing is left once the surrounding whitespace is trimmed.
+    Empty,
+    /// The name contains a character other than an ASCII letter or digit.
+    InvalidCharacter(char),
+    /// The name has this many characters, outside of [`USERNAME_LENGTH`].
+    InvalidLength(usize),
+}
+
+impl Display for UsernameError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UsernameError::Empty => write!(f, "the username is empty"),
+            UsernameError::InvalidCharacter(c) => {
+                write!(f, "the username contains {:?}, only ASCII letters and digits are allowed", c)
+            }
+            UsernameError::InvalidLength(n) => write!(
+                f,
+                "the username has {} characters, it must have from {} to {}",
+                n,
+                USERNAME_LENGTH.start(),
+                USERNAME_LENGTH.end()
+            ),
+        }
+    }
+}
+
+/// Returns the canonical form of a username, under which it is registered and looked up.
+///
+/// Surrounding whitespace is trimmed and letters are folded to lowercase, so " Bob" and "bob" name
+/// the same user. Anything but ASCII letters and digits is refused rather than rewritten, which also
+/// means every accepted name is already in Unicode NFC form.
+pub fn normalize_username(raw: &str) -> Result<String, UsernameError> {
+    let trimmed = raw.trim();
+    if trimmed.is_empty() {
+        return Err(UsernameError::Empty);
+    }
+    if let Some(c) = trimmed.chars().find(|c| !c.is_ascii_alphanumeric()) {
+        return Err(UsernameError::InvalidCharacter(c));
+    }
+    Ok(trimmed.to_ascii_lowercase())
+}
+
+/// Checks that `raw` may be registered as a username, by the client before sending the registration
+/// and by the server before accepting it.
+///
+/// On top of the rules of [`normalize_username`], the name must have a length in [`USERNAME_LENGTH`].
+/// Being ASCII only, a name cannot pass for another one through Unicode confusables, and two names
+/// that only differ in case register the same user: the second one is refused as taken. Names are
+/// looked up with [`normalize_username`] alone, so users registered before the length was checked
+/// can still be reached.
+pub fn validate_username(raw: &str) -> Result<(), UsernameError> {
+    let username = normalize_username(raw)?;
+    if !USERNAME_LENGTH.contains(&username.len()) {
+        return Err(UsernameError::InvalidLength(username.len()));
+    }
+    Ok(())
+}
+
+/// A pre-key bundle in a request: the serde form of [`PreKeyBundle`], or the base64 string
+/// of [`PreKeyBundle::to_base64`] sent by older clients.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(untagged)]
+pub enum WireBundle {
+    Typed(Box<PreKeyBundle>),
+    Legacy(String),
+}
+
+impl TryFrom<WireBundle> for PreKeyBundle {
+    type Error = X3DHError;
+
+    fn try_from(bundle: WireBundle) -> Result<Self, Self::Error> {
+        match bundle {
+            WireBundle::Typed(bundle) => Ok(*bundle),
+            WireBundle::Legacy(encoded) => PreKeyBundle::try_from(encoded),
+        }
+    }
+}
+
+/// A request whose fields may arrive under legacy names, read through serde aliases.
+///
+/// The canonical names are snake_case and the same across requests. The legacy ones are still accepted for
+/// clients written before the names were settled, and will be removed once no client sends them anymore:
+/// the server logs a warning each time one is used.
+pub trait LegacyFieldNames {
+    /// The legacy names of the fields, each with the canonical name it stands for.
+    const LEGACY_FIELD_NAMES: &'static [(&'static str, &'static str)] = &[];
+
+    /// Returns the legacy names used in `raw`, the request as received, each with its canonical name.
+    fn legacy_field_names(raw: &Value) -> Vec<(&'static str, &'static str)> {
+        Self::LEGACY_FIELD_NAMES.iter()
+            .filter(|(legacy, _)| raw.get(legacy).is_some())
+            .copied()
+            .collect()
+    }
+}
+
+/// The legacy name of `request_type`, shared by every request that has one.
+const ACTION: (&str, &str) = ("action", "request_type");
+
 #[derive(Serialize, Deserialize)]
 pub struct RegisterRequest {
+    /// Optional: a registration is told apart by its fields.
+    #[serde(default, alias = "action", skip_serializing_if = "Option::is_none")]
+    pub request_type: Option<String>,
     pub username: String,
-    pub bundle: String,
+    pub bundle: WireBundle,
+    /// Client-generated key reused across retries, so the server can replay the first outcome.
+    #[serde(default)]
+    pub idempotency_key: Option<String>,
+    /// Client-generated UUID telling this device apart from the other devices of the user.
+    /// Clients that predate devices have a single one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub device_id: Option<String>,
+}
+
+impl LegacyFieldNames for RegisterRequest {
+    const LEGACY_FIELD_NAMES: &'static [(&'static str, &'static str)] = &[ACTION];
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct SendMessageRequest {
+    #[serde(alias = "type")]
     pub msg_type: String,
     pub from: String,
     pub to: String,
     pub text: String,
     pub timestamp: String,
+    /// Set by senders that want to know whether the message was delivered, see [`ResponseCode`].
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    /// The group chat the message belongs to, relayed as is.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group_id: Option<String>,
+}
+
+impl LegacyFieldNames for SendMessageRequest {
+    const LEGACY_FIELD_NAMES: &'static [(&'static str, &'static str)] = &[("type", "msg_type")];
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct GetPreKeyBundleRequest {
-    pub who: String,
+    /// Optional: a request for a bundle is told apart by its fields.
+    #[serde(default, alias = "action", skip_serializing_if = "Option::is_none")]
+    pub request_type: Option<String>,
+    /// The user whose bundle is requested.
+    #[serde(alias = "who", alias = "user")]
+    pub username: String,
+    /// Answer with the serde form of the bundle rather than the legacy base64 string.
+    #[serde(default)]
+    pub typed: bool,
+    /// Client-generated key reused across retries, so the server can replay the first outcome.
+    #[serde(default)]
+    pub idempotency_key: Option<String>,
+}
+
+impl LegacyFieldNames for GetPreKeyBundleRequest {
+    const LEGACY_FIELD_NAMES: &'static [(&'static str, &'static str)] = &[ACTION, ("who", "username"), ("user", "username")];
+}
+
+/// Asks the server to rotate the session keys of the connection.
+#[derive(Serialize, Deserialize)]
+pub struct RekeyRequest {
+    #[serde(alias = "action")]
+    pub request_type: String,
+}
+
+impl LegacyFieldNames for RekeyRequest {
+    const LEGACY_FIELD_NAMES: &'static [(&'static str, &'static str)] = &[ACTION];
+}
+
+/// Asks the server to stop (or resume) relaying messages from `from` to the requesting user.
+#[derive(Serialize, Deserialize)]
+pub struct RelayFilterRequest {
+    #[serde(alias = "action")]
+    pub request_type: String,
+    pub from: String,
+    pub blocked: bool,
+}
+
+impl LegacyFieldNames for RelayFilterRequest {
+    const LEGACY_FIELD_NAMES: &'static [(&'static str, &'static str)] = &[ACTION];
+}
+
+/// Pushed by the server when a user is running low on one-time pre-keys.
+#[derive(Serialize, Deserialize)]
+pub struct ReplenishPreKeysMessage {
+    pub msg_type: String,
+    /// Number of one-time pre-keys the client should upload.
+    pub count: usize,
+}
+
+/// Appends base64-encoded one-time pre-keys to the bundle the server stores for the user.
+#[derive(Serialize, Deserialize)]
+pub struct UploadPreKeysRequest {
+    #[serde(alias = "action")]
+    pub request_type: String,
+    pub otpk: Vec<String>,
+    /// The ids of the keys in `otpk`, in the same order. Clients predating ids leave it out.
+    #[serde(default)]
+    pub otpk_ids: Vec<u32>,
+    /// The base64 signatures of the keys in `otpk` by the identity key, in the same order.
+    /// Clients predating signatures leave it out.
+    #[serde(default)]
+    pub otpk_sigs: Vec<String>,
+}
+
+impl LegacyFieldNames for UploadPreKeysRequest {
+    const LEGACY_FIELD_NAMES: &'static [(&'static str, &'static str)] = &[ACTION];
+}
+
+/// Replaces the signed pre-key in the bundle the server stores for the user, keeping its one-time pre-keys.
+#[derive(Serialize, Deserialize)]
+pub struct UploadSignedPreKeyRequest {
+    #[serde(alias = "action")]
+    pub request_type: String,
+    /// The base64 signed pre-key.
+    pub spk: String,
+    /// The base64 signature of `spk` by the identity key.
+    pub sig: String,
+    /// When `spk` was created, in seconds since the Unix epoch.
+    pub created_at: u64,
+}
+
+impl LegacyFieldNames for UploadSignedPreKeyRequest {
+    const LEGACY_FIELD_NAMES: &'static [(&'static str, &'static str)] = &[ACTION];
+}
+
+/// Asks the server for its [`ServerInfo`].
+#[derive(Serialize, Deserialize)]
+pub struct ServerInfoRequest {
+    #[serde(alias = "action")]
+    pub request_type: String,
+}
+
+impl LegacyFieldNames for ServerInfoRequest {
+    const LEGACY_FIELD_NAMES: &'static [(&'static str, &'static str)] = &[ACTION];
+}
+
+/// Asks the server which of the `usernames` are online. The response maps each of them,
+/// as written in the request, to whether it is connected.
+#[derive(Serialize, Deserialize)]
+pub struct GetPresenceRequest {
+    #[serde(alias = "action")]
+    pub request_type: String,
+    #[serde(alias = "who")]
+    pub usernames: Vec<String>,
+}
+
+impl LegacyFieldNames for GetPresenceRequest {
+    const LEGACY_FIELD_NAMES: &'static [(&'static str, &'static str)] = &[ACTION, ("who", "usernames")];
+}
+
+impl LegacyFieldNames for RequestWrapper {}
+
+/// Read-only information about the server, returned for a [`ServerInfoRequest`].
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct ServerInfo {
+    pub version: String,
+    pub uptime_secs: u64,
+    /// Number of registered users, rounded to the nearest ten.
+    pub registered_users: usize,
+    pub offline_storage: bool,
+    /// Maximum size in bytes of a relayed message, `None` if unlimited.
+    pub max_message_size: Option<usize>,
+    /// Maximum number of requests per minute for a connection, `None` if unlimited.
+    pub rate_limit_per_minute: Option<u32>,
+    /// Maximum number of registered users, `None` if unlimited.
+    #[serde(default)]
+    pub max_registered_users: Option<usize>,
 }
 
 #[derive(Clone, Deserialize)]
@@ -163,6 +437,77 @@ pub struct Config {
     public_key_server: String,
     log_level: String,
 
+    /// Seconds of inactivity after which opted-in chats are closed by the client.
+    #[serde(default)]
+    chat_idle_timeout: Option<u64>,
+
+    /// Seconds after which the client rotates its session keys with the server.
+    #[serde(default)]
+    session_rotation_interval: Option<u64>,
+
+    /// Seconds after which the client replaces its signed pre-key, counted from when the key was signed.
+    #[serde(default)]
+    signed_prekey_rotation_interval: Option<u64>,
+
+    /// Seconds without key presses after which the TUI shows the lock screen, opened with the passphrase of
+    /// the saved state, or else with a PIN chosen when the TUI starts.
+    #[serde(default)]
+    lock_timeout: Option<u64>,
+
+    /// Minimum WCAG contrast ratio of the chat accent colours against the TUI background, 4.5 if unset.
+    #[serde(default)]
+    accent_contrast: Option<f64>,
+
+    /// File where the client keeps its chats between runs, encrypted with the passphrase
+    /// in the `CLIENT_STATE_PASSPHRASE` environment variable.
+    #[serde(default)]
+    state_file: Option<String>,
+
+    /// Directory where the client writes the files sent by friends, `./downloads` if unset.
+    #[serde(default)]
+    download_dir: Option<String>,
+
+    /// Port on which the server streams its user store to a standby.
+    #[serde(default)]
+    replication_port: Option<String>,
+
+    /// Secret shared by a primary and its standby to authenticate the replication channel.
+    #[serde(default)]
+    replication_secret: Option<String>,
+
+    /// Milliseconds the server spends on a request before answering it with an error.
+    #[serde(default)]
+    request_deadline: Option<u64>,
+
+    /// Number of users the server takes before refusing registrations. Can be raised at runtime.
+    #[serde(default)]
+    max_registered_users: Option<usize>,
+
+    /// Number of users that can be registered from one address per day.
+    #[serde(default)]
+    daily_registrations_per_address: Option<u32>,
+
+    /// Refuse requests with unknown fields, or with ids that are not UUIDs.
+    #[serde(default)]
+    strict_requests: Option<bool>,
+
+    /// Bytes held for the users beyond which the server stops queuing messages for offline ones.
+    /// Can be changed at runtime.
+    #[serde(default)]
+    memory_soft_limit: Option<usize>,
+
+    /// Bytes held for the users beyond which the server stops accepting connections. Can be changed at runtime.
+    #[serde(default)]
+    memory_hard_limit: Option<usize>,
+
+    /// Seconds between the pings the server sends to each client, 30 if unset.
+    #[serde(default)]
+    ping_interval: Option<u64>,
+
+    /// Seconds the server waits past a ping interval for a pong before dropping the connection, 10 if unset.
+    #[serde(default)]
+    pong_timeout: Option<u64>,
+
     #[serde(skip_deserializing)]
     server_url: String,
 }
@@ -199,6 +544,280 @@ impl Config {
     pub fn get_server_url(&self) -> String {
         self.server_url.clone()
     }
+
+    pub fn get_chat_idle_timeout(&self) -> Option<u64> {
+        self.chat_idle_timeout
+    }
+
+    pub fn get_session_rotation_interval(&self) -> Option<u64> {
+        self.session_rotation_interval
+    }
+
+    pub fn get_signed_prekey_rotation_interval(&self) -> Option<u64> {
+        self.signed_prekey_rotation_interval
+    }
+
+    pub fn get_lock_timeout(&self) -> Option<u64> {
+        self.lock_timeout
+    }
+
+    pub fn get_accent_contrast(&self) -> Option<f64> {
+        self.accent_contrast
+    }
+
+    pub fn get_state_file(&self) -> Option<String> {
+        self.state_file.clone()
+    }
+
+    pub fn get_download_dir(&self) -> Option<String> {
+        self.download_dir.clone()
+    }
+
+    pub fn get_replication_port(&self) -> Option<String> {
+        self.replication_port.clone()
+    }
+
+    pub fn get_request_deadline(&self) -> Option<u64> {
+        self.request_deadline
+    }
+
+    pub fn get_replication_secret(&self) -> Option<String> {
+        self.replication_secret.clone()
+    }
+
+    pub fn get_max_registered_users(&self) -> Option<usize> {
+        self.max_registered_users
+    }
+
+    pub fn get_daily_registrations_per_address(&self) -> Option<u32> {
+        self.daily_registrations_per_address
+    }
+
+    pub fn get_strict_requests(&self) -> bool {
+        self.strict_requests.unwrap_or(false)
+    }
+
+    pub fn get_memory_soft_limit(&self) -> Option<usize> {
+        self.memory_soft_limit
+    }
+
+    pub fn get_memory_hard_limit(&self) -> Option<usize> {
+        self.memory_hard_limit
+    }
+
+    pub fn get_ping_interval(&self) -> Option<u64> {
+        self.ping_interval
+    }
+
+    pub fn get_pong_timeout(&self) -> Option<u64> {
+        self.pong_timeout
+    }
 }
 
-pub static CONFIG: LazyLock<Config> = LazyLock::new(|| Config::new("./config/config.toml"));
\ No newline at end of file
+pub static CONFIG: LazyLock<Config> = LazyLock::new(|| Config::new("./config/config.toml"));
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use protocol::curve::Curve;
+    use protocol::utils::{EncryptionKey, PrivateKey, PublicKey, SharedSecret};
+    use serde_json::json;
+
+    #[test]
+    fn test_decrypt_request_errors() {
+        let dk = DecryptionKey::from(SharedSecret::from([1u8; 32]));
+        let ek = EncryptionKey::from(SharedSecret::from([1u8; 32]));
+        let associated = AssociatedData::new(PublicKey::from(&PrivateKey::new()), PublicKey::from(&PrivateKey::new()));
+        let aad = associated.to_bytes();
+
+        // Shorter than the nonce
+        let truncated = general_purpose::STANDARD.encode([0u8; AES256_NONCE_LENGTH - 1]);
+        assert!(matches!(decrypt_request(&truncated, &dk, &associated), Err(CommonError::TooShort(11))));
+        assert!(matches!(decrypt_request("not base64!", &dk, &associated), Err(CommonError::Base64(_))));
+
+        let other = DecryptionKey::from(SharedSecret::from([2u8; 32]));
+        let frame = ek.encrypt(br#"{"ok":true}"#, &aad).unwrap();
+        assert!(matches!(decrypt_request(&frame, &other, &associated), Err(CommonError::Aead(_))));
+        assert_eq!(decrypt_request(&frame, &dk, &associated).unwrap(), json!({ "ok": true }));
+
+        let frame = ek.encrypt(&[0xff, 0xfe], &aad).unwrap();
+        assert!(matches!(decrypt_request(&frame, &dk, &associated), Err(CommonError::Utf8(_))));
+        let frame = ek.encrypt(b"not json", &aad).unwrap();
+        assert!(matches!(decrypt_request(&frame, &dk, &associated), Err(CommonError::Json(_))));
+    }
+
+    /// Replays the corpus of the `decrypt_request` fuzz target, with every truncation and byte flip of its files.
+    #[test]
+    fn test_decrypt_request_corpus() {
+        let dk = DecryptionKey::from(SharedSecret::from([1u8; 32]));
+        // A fixed key of the size of the active curve, so that the corpus replays the same way on every run
+        let seed = vec![7u8; Curve::ACTIVE.secret_length()];
+        let ik = PublicKey::from(&PrivateKey::from_base64(general_purpose::STANDARD.encode(seed)).unwrap());
+        let associated = AssociatedData::new(ik.clone(), ik);
+
+        let dir = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("../fuzz/corpus/decrypt_request");
+        for entry in std::fs::read_dir(dir).unwrap() {
+            let seed = std::fs::read(entry.unwrap().path()).unwrap();
+            let flips = (0..seed.len()).map(|i| {
+                let mut flipped = seed.clone();
+                flipped[i] ^= 0xff;
+                flipped
+            });
+            let inputs = (0..=seed.len()).map(|length| seed[..length].to_vec()).chain(flips);
+            for data in inputs {
+                let _ = decrypt_request(&general_purpose::STANDARD.encode(&data), &dk, &associated);
+                if let Ok(text) = std::str::from_utf8(&data) {
+                    let _ = decrypt_request(text, &dk, &associated);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_frames_do_not_carry_the_identity_keys() {
+        let dk = DecryptionKey::from(SharedSecret::from([1u8; 32]));
+        let ek = EncryptionKey::from(SharedSecret::from([1u8; 32]));
+        let (ik_a, ik_b) = (PublicKey::from(&PrivateKey::new()), PublicKey::from(&PrivateKey::new()));
+        let associated = AssociatedData::new(ik_a.clone(), ik_b.clone());
+        let aad = associated.to_bytes();
+
+        let frame = ek.encrypt(br#"{"ok":true}"#, &aad).unwrap();
+        let bytes = general_purpose::STANDARD.decode(&frame).unwrap();
+        for key in [ik_a.as_ref(), ik_b.as_ref()] {
+            assert!(!bytes.windows(key.len()).any(|w| w == key));
+        }
+        let stranger = AssociatedData::new(ik_b, ik_a);
+        assert!(matches!(decrypt_request(&frame, &dk, &stranger), Err(CommonError::Aead(_))));
+
+        // A frame of an older peer, with the associated data after the nonce
+        let mut legacy = bytes[..AES256_NONCE_LENGTH].to_vec();
+        legacy.extend_from_slice(&aad);
+        legacy.extend_from_slice(&bytes[AES256_NONCE_LENGTH..]);
+        let legacy = general_purpose::STANDARD.encode(legacy);
+        assert_eq!(decrypt_request(&legacy, &dk, &associated).unwrap(), json!({ "ok": true }));
+    }
+
+    #[test]
+    fn test_serde_server_info() {
+        let info = ServerInfo {
+            version: "0.1.0".to_string(),
+            uptime_secs: 42,
+            registered_users: 10,
+            offline_storage: false,
+            max_message_size: None,
+            rate_limit_per_minute: Some(60),
+            max_registered_users: Some(1000),
+        };
+        let json = serde_json::to_string(&info).unwrap();
+        assert_eq!(serde_json::from_str::<ServerInfo>(&json).unwrap(), info);
+
+        let value: Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(value["max_message_size"], Value::Null);
+        assert_eq!(value["rate_limit_per_minute"], 60);
+    }
+
+    #[test]
+    fn test_normalize_username() {
+        assert_eq!(normalize_username("bob").unwrap(), "bob");
+        assert_eq!(normalize_username("Bob").unwrap(), "bob");
+        assert_eq!(normalize_username(" bob \n").unwrap(), "bob");
+        assert_eq!(normalize_username("   "), Err(UsernameError::Empty));
+        assert_eq!(normalize_username("bo b"), Err(UsernameError::InvalidCharacter(' ')));
+        assert_eq!(normalize_username("bób"), Err(UsernameError::InvalidCharacter('ó')));
+    }
+
+    #[test]
+    fn test_validate_username() {
+        assert_eq!(validate_username("bob"), Ok(()));
+        assert_eq!(validate_username(" Bob "), Ok(()));
+        assert_eq!(validate_username(&"a".repeat(32)), Ok(()));
+        assert_eq!(validate_username(""), Err(UsernameError::Empty));
+        assert_eq!(validate_username("bo"), Err(UsernameError::InvalidLength(2)));
+        assert_eq!(validate_username(&"a".repeat(64)), Err(UsernameError::InvalidLength(64)));
+        // A combining accent would make "bob" and "bo\u{301}b" look alike
+        assert_eq!(validate_username("bo\u{301}b"), Err(UsernameError::InvalidCharacter('\u{301}')));
+        // As would a Cyrillic "о"
+        assert_eq!(validate_username("b\u{43e}b"), Err(UsernameError::InvalidCharacter('\u{43e}')));
+    }
+
+    #[test]
+    fn test_serde_server_info_request() {
+        let request: ServerInfoRequest = serde_json::from_value(json!({ "request_type": "server_info" })).unwrap();
+        assert_eq!(request.request_type, "server_info");
+        assert!(serde_json::from_value::<ServerInfoRequest>(json!({ "who": "bob" })).is_err());
+    }
+
+    #[test]
+    fn test_serde_get_presence_request() {
+        let body = json!({ "request_type": "presence", "who": ["bob", "carol"] });
+        let request: GetPresenceRequest = serde_json::from_value(body.clone()).unwrap();
+        assert_eq!(request.usernames, vec!["bob", "carol"]);
+        // Not mistaken for a request of a single pre-key bundle
+        assert!(serde_json::from_value::<GetPreKeyBundleRequest>(body).is_err());
+    }
+
+    #[test]
+    fn test_legacy_field_names_are_read() {
+        fn same<T: Serialize + for<'de> Deserialize<'de> + LegacyFieldNames>(legacy: Value, canonical: Value, names: &[(&str, &str)]) {
+            let read: T = serde_json::from_value(legacy.clone()).unwrap();
+            assert_eq!(serde_json::to_value(read).unwrap(), canonical);
+            assert_eq!(T::legacy_field_names(&legacy), names);
+            assert!(T::legacy_field_names(&canonical).is_empty());
+        }
+
+        same::<GetPreKeyBundleRequest>(
+            json!({ "who": "bob", "typed": true, "idempotency_key": null }),
+            json!({ "username": "bob", "typed": true, "idempotency_key": null }),
+            &[("who", "username")],
+        );
+        same::<GetPreKeyBundleRequest>(
+            json!({ "action": "get_prekey_bundle", "user": "bob", "typed": false, "idempotency_key": null }),
+            json!({ "request_type": "get_prekey_bundle", "username": "bob", "typed": false, "idempotency_key": null }),
+            &[("action", "request_type"), ("user", "username")],
+        );
+        same::<GetPresenceRequest>(
+            json!({ "action": "presence", "who": ["bob"] }),
+            json!({ "request_type": "presence", "usernames": ["bob"] }),
+            &[("action", "request_type"), ("who", "usernames")],
+        );
+        same::<SendMessageRequest>(
+            json!({ "type": "chat", "from": "alice", "to": "bob", "text": "hi", "timestamp": "now" }),
+            json!({ "msg_type": "chat", "from": "alice", "to": "bob", "text": "hi", "timestamp": "now" }),
+            &[("type", "msg_type")],
+        );
+        same::<RekeyRequest>(json!({ "action": "rekey" }), json!({ "request_type": "rekey" }), &[ACTION]);
+        same::<ServerInfoRequest>(json!({ "action": "server_info" }), json!({ "request_type": "server_info" }), &[ACTION]);
+        same::<RelayFilterRequest>(
+            json!({ "action": "relay_filter", "from": "bob", "blocked": true }),
+            json!({ "request_type": "relay_filter", "from": "bob", "blocked": true }),
+            &[ACTION],
+        );
+        same::<UploadPreKeysRequest>(
+            json!({ "action": "upload_prekeys", "otpk": [], "otpk_ids": [], "otpk_sigs": [] }),
+            json!({ "request_type": "upload_prekeys", "otpk": [], "otpk_ids": [], "otpk_sigs": [] }),
+            &[ACTION],
+        );
+        same::<UploadSignedPreKeyRequest>(
+            json!({ "action": "upload_signed_prekey", "spk": "a", "sig": "b", "created_at": 1 }),
+            json!({ "request_type": "upload_signed_prekey", "spk": "a", "sig": "b", "created_at": 1 }),
+            &[ACTION],
+        );
+        let bundle = serde_json::to_value(WireBundle::Legacy("bundle".to_string())).unwrap();
+        same::<RegisterRequest>(
+            json!({ "action": "register", "username": "alice", "bundle": bundle, "idempotency_key": null }),
+            json!({ "request_type": "register", "username": "alice", "bundle": bundle, "idempotency_key": null }),
+            &[ACTION],
+        );
+    }
+
+    #[test]
+    fn test_server_response_serde_matches_display() {
+        let response = ServerResponse::new(ResponseCode::Forbidden, "bob".to_string());
+        assert_eq!(serde_json::to_string(&response).unwrap(), response.to_string());
+        assert_eq!(serde_json::to_value(&response).unwrap(), json!({ "code": "403", "message": "bob" }));
+
+        // The form the derive used to write is still read
+        let legacy = ServerResponse::from_json(json!({ "code": "Forbidden", "text": "bob" }).to_string()).unwrap();
+        assert_eq!(legacy.to_string(), response.to_string());
+        assert!(ServerResponse::from_json(json!({ "code": "299", "message": "bob" }).to_string()).is_none());
+    }
+}
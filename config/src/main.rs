@@ -14,6 +14,22 @@ struct Config {
     private_key_server: String,
     public_key_server: String,
     log_level: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    chat_idle_timeout: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    session_rotation_interval: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    signed_prekey_rotation_interval: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    lock_timeout: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    state_file: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    replication_port: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    replication_secret: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    request_deadline: Option<u64>,
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
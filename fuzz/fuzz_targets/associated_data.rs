@@ -0,0 +1,13 @@
+//! Feeds arbitrary bytes to the parsers of the associated data of a session.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use protocol::utils::AssociatedData;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(bytes) = <&[u8; AssociatedData::SIZE]>::try_from(data) {
+        let _ = AssociatedData::try_from(bytes);
+    }
+    let _ = serde_json::from_slice::<AssociatedData>(data);
+});
@@ -0,0 +1,18 @@
+//! Feeds arbitrary text, and arbitrary bytes encoded in base64, as an encrypted request to the server.
+
+#![no_main]
+
+use base64::{engine::general_purpose, Engine as _};
+use common::decrypt_request;
+use libfuzzer_sys::fuzz_target;
+use protocol::utils::{AssociatedData, DecryptionKey, PrivateKey, PublicKey, SharedSecret};
+
+fuzz_target!(|data: &[u8]| {
+    let dk = DecryptionKey::from(SharedSecret::from([1u8; 32]));
+    let ik = PublicKey::from(&PrivateKey::from_base64(general_purpose::STANDARD.encode([7u8; 32])).unwrap());
+    let aad = AssociatedData::new(ik.clone(), ik);
+    let _ = decrypt_request(&general_purpose::STANDARD.encode(data), &dk, &aad);
+    if let Ok(text) = std::str::from_utf8(data) {
+        let _ = decrypt_request(text, &dk, &aad);
+    }
+});
@@ -0,0 +1,14 @@
+//! Feeds arbitrary bytes to the parser of an initial message, in its legacy base64 packing and its JSON form.
+
+#![no_main]
+
+use base64::{engine::general_purpose, Engine as _};
+use libfuzzer_sys::fuzz_target;
+use protocol::utils::InitialMessage;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = InitialMessage::try_from(general_purpose::STANDARD.encode(data));
+    if let Ok(text) = std::str::from_utf8(data) {
+        let _ = InitialMessage::try_from(text.to_string());
+    }
+});
@@ -0,0 +1,19 @@
+//! Feeds arbitrary bytes to the parsers of a pre-key bundle fetched from the server, in its base64 packing and
+//! its JSON form, and checks the bundles that parse.
+
+#![no_main]
+
+use base64::{engine::general_purpose, Engine as _};
+use libfuzzer_sys::fuzz_target;
+use protocol::utils::PreKeyBundle;
+
+fuzz_target!(|data: &[u8]| {
+    let bundles = [
+        PreKeyBundle::try_from(general_purpose::STANDARD.encode(data)).ok(),
+        serde_json::from_slice::<PreKeyBundle>(data).ok(),
+        std::str::from_utf8(data).ok().and_then(|text| PreKeyBundle::try_from(text.to_string()).ok()),
+    ];
+    for bundle in bundles.into_iter().flatten() {
+        let _ = bundle.validate();
+    }
+});
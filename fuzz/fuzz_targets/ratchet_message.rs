@@ -0,0 +1,25 @@
+//! Feeds arbitrary bytes as a ratchet message, nonce, header and ciphertext, to ratchets with and without
+//! header encryption.
+
+#![no_main]
+
+use base64::{engine::general_purpose, Engine as _};
+use libfuzzer_sys::fuzz_target;
+use protocol::ratchet::{Ratchet, RatchetKeyPair};
+use protocol::utils::{PrivateKey, PublicKey, SharedSecret};
+
+fn key_pair() -> RatchetKeyPair {
+    let private_key = PrivateKey::from_base64(general_purpose::STANDARD.encode([7u8; 32])).unwrap();
+    let public_key = PublicKey::from(&private_key);
+    RatchetKeyPair::new_from(private_key, public_key)
+}
+
+fuzz_target!(|data: &[u8]| {
+    let mut ratchet = Ratchet::init_bob(SharedSecret::from([1u8; 32]), key_pair());
+    let _ = ratchet.decrypt_bytes(data, b"aad");
+    let mut ratchet = Ratchet::init_bob_with_header_encryption(SharedSecret::from([1u8; 32]), key_pair());
+    let _ = ratchet.decrypt_bytes(data, b"aad");
+    if let Ok(text) = std::str::from_utf8(data) {
+        let _ = ratchet.decrypt(text.to_string(), b"aad");
+    }
+});
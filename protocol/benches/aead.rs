@@ -0,0 +1,38 @@
+//! Throughput of [`EncryptionKey`] and [`DecryptionKey`], which key their cipher once, against keying it again
+//! for every message as they used to.
+//!
+//! Run with `cargo bench -p protocol --bench aead`.
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use protocol::utils::{DecryptionKey, EncryptionKey, SharedSecret};
+
+const KEY: [u8; 32] = [7; 32];
+const AAD: &[u8] = b"benchmark";
+
+fn aead(c: &mut Criterion) {
+    let mut group = c.benchmark_group("aead");
+    for size in [1024, 64 * 1024] {
+        let payload = vec![0x42u8; size];
+        let ek = EncryptionKey::from(SharedSecret::from(KEY));
+        let dk = DecryptionKey::from(SharedSecret::from(KEY));
+        let frame = ek.encrypt_bytes(&payload, AAD).unwrap();
+        group.throughput(Throughput::Bytes(size as u64));
+
+        group.bench_with_input(BenchmarkId::new("encrypt/cached", size), &payload, |b, payload| {
+            b.iter(|| ek.encrypt_bytes(black_box(payload), AAD).unwrap())
+        });
+        group.bench_with_input(BenchmarkId::new("encrypt/rekeyed", size), &payload, |b, payload| {
+            b.iter(|| EncryptionKey::from(SharedSecret::from(KEY)).encrypt_bytes(black_box(payload), AAD).unwrap())
+        });
+        group.bench_with_input(BenchmarkId::new("decrypt/cached", size), &frame, |b, frame| {
+            b.iter(|| dk.decrypt_frame(black_box(frame), AAD).unwrap())
+        });
+        group.bench_with_input(BenchmarkId::new("decrypt/rekeyed", size), &frame, |b, frame| {
+            b.iter(|| DecryptionKey::from(SharedSecret::from(KEY)).decrypt_frame(black_box(frame), AAD).unwrap())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, aead);
+criterion_main!(benches);
@@ -0,0 +1,312 @@
+//! Soak and regression run of the ratchet sessions between two parties.
+//!
+//! Alice and Bob exchange messages of random sizes over an in-process link that drops and reorders them,
+//! the way a flaky connection and the offline queue of the server would. Every so often both sides restore
+//! their ratchets from their serialized state, as a client does when it reconnects, and the session is
+//! reset with a fresh X3DH handshake. At the end the messages each side received must be exactly those the
+//! other one sent and the link did not drop, and the skipped keys and state of each ratchet must have stayed
+//! within their bounds all along.
+//!
+//! Only the protocol is exercised: the run drives [`Ratchet`] and the X3DH functions directly, with neither
+//! the client, its storage and connection, nor the server, which the soak tests of the client crate take on.
+//! The run is reproducible from its seed:
+//!
+//! ```text
+//! cargo run -p protocol --example soak -- --messages 10000 --seed 42
+//! ```
+
+use protocol::interop::{root_key, Conformance};
+use protocol::ratchet::{Ratchet, RatchetConfig, RatchetKeyPair};
+use protocol::utils::PrivateKey;
+use protocol::x3dh::{
+    generate_prekey_bundle_with_otpk, process_initial_message, process_prekey_bundle_with_conformance, Role,
+};
+use std::collections::BTreeMap;
+use std::fmt::Display;
+
+/// The skipped keys a ratchet keeps at most during the run.
+pub const SKIPPED_KEY_LIMIT: usize = 256;
+
+/// The serialized state a ratchet may reach at most, a fixed part plus its skipped keys.
+pub const STATE_LIMIT: usize = 4096 + SKIPPED_KEY_LIMIT * 128;
+
+/// The parameters of a run.
+#[derive(Debug, Clone, Copy)]
+pub struct SoakOptions {
+    /// The number of messages sent, by both sides together.
+    pub messages: usize,
+    /// The seed of every random choice of the run.
+    pub seed: u64,
+    /// The largest plaintext sent, in bytes.
+    pub max_size: usize,
+    /// The probability that the link drops a message.
+    pub drop_rate: f64,
+    /// The probability that the link holds a message back behind later ones.
+    pub reorder_rate: f64,
+    /// The messages between two reconnects of both sides.
+    pub reconnect_every: usize,
+    /// The messages between two session resets.
+    pub reset_every: usize,
+}
+
+impl Default for SoakOptions {
+    fn default() -> Self {
+        Self {
+            messages: 10_000,
+            seed: 0,
+            max_size: 4096,
+            drop_rate: 0.02,
+            reorder_rate: 0.1,
+            reconnect_every: 500,
+            reset_every: 2500,
+        }
+    }
+}
+
+/// What a run went through.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SoakReport {
+    pub sent: usize,
+    pub delivered: usize,
+    pub dropped: usize,
+    pub reordered: usize,
+    /// Messages delivered after the key of their position was evicted, lost like dropped ones.
+    pub evicted: usize,
+    pub reconnects: usize,
+    pub resets: usize,
+    pub max_skipped_keys: usize,
+    pub max_state_bytes: usize,
+}
+
+impl Display for SoakReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} sent, {} delivered, {} dropped, {} reordered, {} evicted, {} reconnects, {} resets, \
+             at most {} skipped keys and {} state bytes",
+            self.sent, self.delivered, self.dropped, self.reordered, self.evicted,
+            self.reconnects, self.resets, self.max_skipped_keys, self.max_state_bytes
+        )
+    }
+}
+
+/// A splitmix64 generator, so that a seed gives the same run on every platform.
+struct Rng(u64);
+
+impl Rng {
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// A number in `0..n`.
+    fn below(&mut self, n: usize) -> usize {
+        (self.next_u64() % n as u64) as usize
+    }
+
+    fn chance(&mut self, p: f64) -> bool {
+        ((self.next_u64() >> 11) as f64 / (1u64 << 53) as f64) < p
+    }
+}
+
+/// One side of the conversation.
+struct Peer {
+    ratchet: Ratchet,
+    /// The messages sent, by sequence number.
+    sent: BTreeMap<u64, Vec<u8>>,
+    /// The messages received from the other side, by sequence number.
+    received: BTreeMap<u64, Vec<u8>>,
+}
+
+/// A message on its way, with the sequence number it carries.
+struct InFlight {
+    seq: u64,
+    ciphertext: String,
+}
+
+/// The link from one side to the other, dropping and reordering what it carries.
+#[derive(Default)]
+struct Link {
+    queue: Vec<InFlight>,
+    /// The sequence numbers of the messages dropped.
+    dropped: Vec<u64>,
+}
+
+/// Runs the X3DH handshake of a new session, Alice being the initiator.
+fn handshake(conformance: Conformance) -> Result<(Ratchet, Ratchet, Vec<u8>), String> {
+    let (bundle, ik, spk, otpk) = generate_prekey_bundle_with_otpk(1);
+    let bob_spk = RatchetKeyPair::new_from(spk.clone(), bundle.spk.clone());
+    let (im, alice_ek, alice_dk) = process_prekey_bundle_with_conformance(PrivateKey::new(), bundle.clone(), conformance)
+        .map_err(|e| format!("Alice could not process the bundle: {}", e))?;
+    let (bob_ek, bob_dk) = process_initial_message(ik, spk, otpk.last().cloned(), im.clone())
+        .map_err(|e| format!("Bob could not process the initial message: {}", e))?;
+
+    let config = RatchetConfig { conformance, max_skipped_keys: SKIPPED_KEY_LIMIT, ..RatchetConfig::default() };
+    let alice_sk = root_key(conformance, Role::Initiator, alice_ek, alice_dk);
+    let bob_sk = root_key(conformance, Role::Responder, bob_ek, bob_dk);
+    let alice = Ratchet::init_alice_with_config(alice_sk, bundle.spk, config);
+    let bob = Ratchet::init_bob_with_config(bob_sk, bob_spk, config);
+    Ok((alice, bob, im.associated_data.to_bytes()))
+}
+
+/// Restores a ratchet from its serialized state, as a client does when it reconnects.
+fn restore(ratchet: &Ratchet) -> Result<Ratchet, String> {
+    Ratchet::from_bytes(&ratchet.to_bytes()).map_err(|e| format!("Could not restore a session: {}", e))
+}
+
+/// Hands the messages of `link` to `to`, in their current order.
+fn drain(link: &mut Link, to: &mut Peer, aad: &[u8], report: &mut SoakReport) -> Result<(), String> {
+    for message in std::mem::take(&mut link.queue) {
+        deliver(message, to, aad, report)?;
+    }
+    Ok(())
+}
+
+fn deliver(message: InFlight, to: &mut Peer, aad: &[u8], report: &mut SoakReport) -> Result<(), String> {
+    match to.ratchet.decrypt(message.ciphertext, aad) {
+        Ok(plaintext) => {
+            report.delivered += 1;
+            if to.received.insert(message.seq, plaintext).is_some() {
+                return Err(format!("Message {} delivered twice", message.seq));
+            }
+        }
+        Err(protocol::errors::RatchetError::SkippedKeyEvicted) => report.evicted += 1,
+        Err(e) => return Err(format!("Could not decrypt message {}: {}", message.seq, e)),
+    }
+    report.max_skipped_keys = report.max_skipped_keys.max(to.ratchet.skipped_key_count());
+    report.max_state_bytes = report.max_state_bytes.max(to.ratchet.to_bytes().len());
+    if to.ratchet.skipped_key_count() > SKIPPED_KEY_LIMIT {
+        return Err(format!("{} skipped keys kept", to.ratchet.skipped_key_count()));
+    }
+    Ok(())
+}
+
+/// Runs a soak with `options`.
+///
+/// # Errors
+///
+/// Returns a description of the first failure: a handshake or decryption error, a message delivered twice,
+/// a ratchet beyond its bounds, or histories that differ at the end.
+pub fn run(options: &SoakOptions) -> Result<SoakReport, String> {
+    let mut rng = Rng(options.seed);
+    let mut report = SoakReport::default();
+    let (alice, bob, mut aad) = handshake(Conformance::Native)?;
+    let mut peers = [
+        Peer { ratchet: alice, sent: BTreeMap::new(), received: BTreeMap::new() },
+        Peer { ratchet: bob, sent: BTreeMap::new(), received: BTreeMap::new() },
+    ];
+    // links[i] carries the messages of peers[i]
+    let mut links = [Link::default(), Link::default()];
+
+    for seq in 0..options.messages as u64 {
+        let step = seq as usize;
+        if step > 0 && step.is_multiple_of(options.reset_every) {
+            // What is in flight arrives before the old session is forgotten
+            for (from, link) in links.iter_mut().enumerate() {
+                drain(link, &mut peers[1 - from], &aad, &mut report)?;
+            }
+            let conformance = if report.resets % 2 == 0 { Conformance::Spec } else { Conformance::Native };
+            let (alice, bob, new_aad) = handshake(conformance)?;
+            peers[0].ratchet = alice;
+            peers[1].ratchet = bob;
+            aad = new_aad;
+            report.resets += 1;
+        } else if step > 0 && step.is_multiple_of(options.reconnect_every) {
+            for peer in peers.iter_mut() {
+                peer.ratchet = restore(&peer.ratchet)?;
+            }
+            report.reconnects += 1;
+        }
+
+        let from = rng.below(2);
+        let size = 1 + rng.below(options.max_size);
+        let plaintext = (0..size).map(|_| b'a' + rng.below(26) as u8).collect::<Vec<_>>();
+        let ciphertext = peers[from]
+            .ratchet
+            .encrypt(&plaintext, &aad)
+            .map_err(|e| format!("Could not encrypt message {}: {}", seq, e))?;
+        peers[from].sent.insert(seq, plaintext);
+        report.sent += 1;
+
+        let link = &mut links[from];
+        if rng.chance(options.drop_rate) {
+            link.dropped.push(seq);
+            report.dropped += 1;
+        } else if !link.queue.is_empty() && rng.chance(options.reorder_rate) {
+            let at = rng.below(link.queue.len());
+            link.queue.insert(at, InFlight { seq, ciphertext });
+            report.reordered += 1;
+        } else {
+            link.queue.push(InFlight { seq, ciphertext });
+        }
+
+        // The link delivers a random number of the oldest messages in flight
+        for (from, link) in links.iter_mut().enumerate() {
+            let n = rng.below(link.queue.len() + 1);
+            for message in link.queue.drain(..n).collect::<Vec<_>>() {
+                deliver(message, &mut peers[1 - from], &aad, &mut report)?;
+            }
+        }
+    }
+    for (from, link) in links.iter_mut().enumerate() {
+        drain(link, &mut peers[1 - from], &aad, &mut report)?;
+    }
+
+    let mut lost = 0;
+    for (from, link) in links.iter().enumerate() {
+        let to = &peers[1 - from];
+        let mut expected = peers[from].sent.clone();
+        for seq in &link.dropped {
+            expected.remove(seq);
+        }
+        lost += expected.keys().filter(|seq| !to.received.contains_key(seq)).count();
+        if expected.iter().any(|(seq, m)| to.received.get(seq).is_some_and(|r| r != m)) {
+            return Err("A message was received altered".to_string());
+        }
+        if to.received.keys().any(|seq| !expected.contains_key(seq)) {
+            return Err("A message was received that was never sent or was dropped".to_string());
+        }
+    }
+    // Every message the link did not drop was either received or had its key evicted
+    if lost != report.evicted {
+        return Err(format!("{} messages lost, {} of them evicted", lost, report.evicted));
+    }
+    if report.max_state_bytes > STATE_LIMIT {
+        return Err(format!("A ratchet state reached {} bytes", report.max_state_bytes));
+    }
+    Ok(report)
+}
+
+fn parse_args() -> Result<SoakOptions, String> {
+    let mut options = SoakOptions::default();
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        let value = args.next().ok_or_else(|| format!("Missing value for {}", arg))?;
+        match arg.as_str() {
+            "--messages" => options.messages = value.parse().map_err(|_| format!("Invalid message count {}", value))?,
+            "--seed" => options.seed = value.parse().map_err(|_| format!("Invalid seed {}", value))?,
+            _ => return Err(format!("Unknown argument {}, expected --messages or --seed", arg)),
+        }
+    }
+    Ok(options)
+}
+
+fn main() {
+    let options = match parse_args() {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    };
+    match run(&options) {
+        Ok(report) => println!("Soak passed with seed {}: {}", options.seed, report),
+        Err(e) => {
+            eprintln!("Soak failed with seed {}: {}", options.seed, e);
+            std::process::exit(1);
+        }
+    }
+}
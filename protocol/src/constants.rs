@@ -2,12 +2,23 @@
 //! These constants specify fixed byte lengths for keys, hashes, nonces, and other cryptographic primitives,
 //! ensuring consistent sizing and preventing common errors related to buffer overflows or incorrect key derivations.
 
-/// Byte size of a Curve25519 private key.
+use crate::curve::Curve;
+
+/// Byte size of an Ed25519 signing key, the same as a Curve25519 private key.
 pub(crate) const CURVE25519_SECRET_LENGTH: usize = 32;
 
-/// Byte size of a Curve25519 public key.
+/// Byte size of an Ed25519 verifying key, the same as a Curve25519 public key.
 pub(crate) const CURVE25519_PUBLIC_LENGTH: usize = CURVE25519_SECRET_LENGTH;
 
+/// Byte size of a private key on the [`Curve::ACTIVE`] curve.
+pub(crate) const DH_SECRET_LENGTH: usize = Curve::ACTIVE.secret_length();
+
+/// Byte size of a public key on the [`Curve::ACTIVE`] curve.
+pub(crate) const DH_PUBLIC_LENGTH: usize = Curve::ACTIVE.public_length();
+
+/// Byte size of the output of a Diffie-Hellman exchange on the [`Curve::ACTIVE`] curve.
+pub(crate) const DH_OUTPUT_LENGTH: usize = DH_PUBLIC_LENGTH;
+
 /// Byte size of a signature.
 pub(crate) const SIGNATURE_LENGTH: usize = 64;
 
@@ -17,11 +28,41 @@ pub(crate) const SHA256_HASH_LENGTH: usize = 32;
 /// Byte size of an AES-256 key.
 pub(crate) const AES256_SECRET_LENGTH: usize = 32;
 
-/// Byte size of an AES-256 nonce.
+/// Byte size of an AES-256 nonce, the same as a ChaCha20-Poly1305 nonce.
 pub const AES256_NONCE_LENGTH: usize = 12;
 
-/// Byte size of a challenge.
-pub(crate) const CHALLENGE_LENGTH: usize = 48;
+/// Byte size of an AES-256-GCM authentication tag, the same as a Poly1305 tag.
+pub(crate) const AES256_GCM_TAG_LENGTH: usize = 16;
+
+/// Byte size of a challenge: nonce, encrypted identity key and authentication tag.
+pub(crate) const CHALLENGE_LENGTH: usize = AES256_NONCE_LENGTH + DH_PUBLIC_LENGTH + AES256_GCM_TAG_LENGTH;
+
+/// Byte size of a challenge encrypted under the former fixed nonce, which was not carried in the message.
+pub(crate) const LEGACY_CHALLENGE_LENGTH: usize = DH_PUBLIC_LENGTH + AES256_GCM_TAG_LENGTH;
 
 /// Maximum number of allowed skips.
 pub(crate) const MAX_SKIPS: u64 = 1000;
+
+/// Maximum number of message keys derived, without storing them, to check whether a message that came after
+/// more than the allowed number of skips is genuine.
+pub(crate) const MAX_LOST_MESSAGES: u64 = 10 * MAX_SKIPS;
+
+/// Maximum number of skipped message keys stored across all receiving chains.
+pub(crate) const MAX_SKIPPED_KEYS: usize = 2000;
+
+/// Maximum byte size of a plaintext accepted by the encryption functions, and upper bound of the
+/// limit configured on a [`crate::ratchet::Ratchet`].
+///
+/// A ratchet message is base64-encoded inside a chat message, which is encrypted and encoded again
+/// for the server, so a plaintext of this size takes about 1.8 MiB on the wire: well below the
+/// 16 MiB frame limit of the WebSocket connections.
+pub const MAX_PLAINTEXT_LENGTH: usize = 1024 * 1024;
+
+/// Maximum number of one-time pre-keys accepted in a pre-key bundle.
+pub const MAX_ONE_TIME_PREKEYS: usize = 100;
+
+/// Byte size of an ML-KEM-768 encapsulation key, the KEM pre-key of a hybrid key agreement.
+pub(crate) const KEM_PUBLIC_LENGTH: usize = 1184;
+
+/// Byte size of an ML-KEM-768 ciphertext.
+pub(crate) const KEM_CIPHERTEXT_LENGTH: usize = 1088;
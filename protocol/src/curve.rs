@@ -0,0 +1,181 @@
+//! The elliptic curve of the Diffie-Hellman exchanges of X3DH and of the ratchet.
+//!
+//! The curve is chosen when the crate is built: X25519, or X448 with the `x448` feature. The sizes of
+//! the keys, and of the [`crate::utils::PreKeyBundle`] and [`crate::utils::InitialMessage`] that carry
+//! them, follow from [`Curve::ACTIVE`]. Parties built for different curves cannot agree on a key: the
+//! serde forms name their curve, so that a bundle or initial message of the other one is refused with
+//! [`X3DHError::CurveMismatch`] rather than misread.
+//!
+//! Signatures are Ed25519 with either curve.
+
+use std::fmt::Display;
+use crate::constants::{DH_OUTPUT_LENGTH, DH_PUBLIC_LENGTH, DH_SECRET_LENGTH};
+use crate::errors::X3DHError;
+
+/// An elliptic curve the Diffie-Hellman exchanges can be performed on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Curve {
+    /// Curve25519, with 32-byte keys, as recommended by the X3DH specification.
+    X25519,
+
+    /// Curve448, with 56-byte keys, for a higher security level at a higher cost.
+    X448,
+}
+
+impl Curve {
+
+    /// The curve this build of the crate uses.
+    #[cfg(not(feature = "x448"))]
+    pub const ACTIVE: Curve = Curve::X25519;
+
+    /// The curve this build of the crate uses.
+    #[cfg(feature = "x448")]
+    pub const ACTIVE: Curve = Curve::X448;
+
+    /// Returns the id of the curve in a serialized [`crate::utils::PreKeyBundle`] or [`crate::utils::InitialMessage`].
+    pub fn id(&self) -> u8 {
+        match self {
+            Curve::X25519 => 0,
+            Curve::X448 => 1,
+        }
+    }
+
+    /// Returns the name of the curve, as it appears in the cipher suite of a ratchet.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Curve::X25519 => "X25519",
+            Curve::X448 => "X448",
+        }
+    }
+
+    /// Returns the byte size of a private key on the curve.
+    pub const fn secret_length(&self) -> usize {
+        match self {
+            Curve::X25519 => 32,
+            Curve::X448 => 56,
+        }
+    }
+
+    /// Returns the byte size of a public key on the curve, which is also the size of a Diffie-Hellman output.
+    pub const fn public_length(&self) -> usize {
+        match self {
+            Curve::X25519 => 32,
+            Curve::X448 => 56,
+        }
+    }
+
+    /// Returns the byte size of the `0xFF` prefix of the HKDF input key material, which separates the
+    /// key derivations from the signatures of XEdDSA (section 2.2 of the X3DH specification).
+    pub const fn kdf_prefix_length(&self) -> usize {
+        match self {
+            Curve::X25519 => 32,
+            Curve::X448 => 57,
+        }
+    }
+
+    /// Checks that keys written for this curve can be read by this build.
+    ///
+    /// # Errors
+    ///
+    /// * [`X3DHError::CurveMismatch`] - Returned if this is not the [`Curve::ACTIVE`] one.
+    pub(crate) fn check_active(self) -> Result<(), X3DHError> {
+        if self != Curve::ACTIVE {
+            return Err(X3DHError::CurveMismatch(self));
+        }
+        Ok(())
+    }
+}
+
+impl TryFrom<u8> for Curve {
+    type Error = X3DHError;
+
+    /// Converts an id written by [`Curve::id`] back into a [`Curve`].
+    ///
+    /// # Errors
+    ///
+    /// * [`X3DHError::UnknownCurve`] - Returned if `value` is not the id of a known curve.
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Curve::X25519),
+            1 => Ok(Curve::X448),
+            _ => Err(X3DHError::UnknownCurve(value)),
+        }
+    }
+}
+
+impl Display for Curve {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// The HKDF input key material prefix of the active curve, see [`Curve::kdf_prefix_length`].
+pub(crate) fn kdf_prefix() -> Vec<u8> {
+    vec![0xFFu8; Curve::ACTIVE.kdf_prefix_length()]
+}
+
+#[cfg(not(feature = "x448"))]
+mod backend {
+    use rand::rngs::OsRng;
+    use x25519_dalek::{PublicKey, StaticSecret};
+    use super::{DH_OUTPUT_LENGTH, DH_PUBLIC_LENGTH, DH_SECRET_LENGTH};
+
+    pub(crate) fn generate_secret() -> [u8; DH_SECRET_LENGTH] {
+        StaticSecret::random_from_rng(&mut OsRng).to_bytes()
+    }
+
+    pub(crate) fn public_key(secret: &[u8; DH_SECRET_LENGTH]) -> [u8; DH_PUBLIC_LENGTH] {
+        PublicKey::from(&StaticSecret::from(*secret)).to_bytes()
+    }
+
+    pub(crate) fn diffie_hellman(secret: &[u8; DH_SECRET_LENGTH], public: &[u8; DH_PUBLIC_LENGTH]) -> [u8; DH_OUTPUT_LENGTH] {
+        StaticSecret::from(*secret).diffie_hellman(&PublicKey::from(*public)).to_bytes()
+    }
+
+    pub(crate) fn is_contributory(public: &[u8; DH_PUBLIC_LENGTH]) -> bool {
+        let probe = StaticSecret::from([1u8; DH_SECRET_LENGTH]);
+        probe.diffie_hellman(&PublicKey::from(*public)).was_contributory()
+    }
+
+    /// The identity key is used as the Ed25519 seed as it is, so existing identities keep their signatures.
+    pub(crate) fn signing_seed(secret: &[u8; DH_SECRET_LENGTH]) -> [u8; 32] {
+        *secret
+    }
+}
+
+#[cfg(feature = "x448")]
+mod backend {
+    use rand::RngCore;
+    use rand::rngs::OsRng;
+    use sha2::{Digest, Sha256};
+    use super::{DH_OUTPUT_LENGTH, DH_PUBLIC_LENGTH, DH_SECRET_LENGTH};
+
+    /// Prefixed to an X448 identity key before hashing it into the seed of its Ed25519 signing key.
+    const SIGNING_SEED_CONTEXT: &[u8] = b"X448 identity signing key";
+
+    pub(crate) fn generate_secret() -> [u8; DH_SECRET_LENGTH] {
+        let mut secret = [0u8; DH_SECRET_LENGTH];
+        OsRng.fill_bytes(&mut secret);
+        secret
+    }
+
+    pub(crate) fn public_key(secret: &[u8; DH_SECRET_LENGTH]) -> [u8; DH_PUBLIC_LENGTH] {
+        x448::x448_unchecked(*secret, x448::X448_BASEPOINT_BYTES)
+    }
+
+    /// Points of low order give an all-zero output, which [`is_contributory`] lets callers refuse beforehand.
+    pub(crate) fn diffie_hellman(secret: &[u8; DH_SECRET_LENGTH], public: &[u8; DH_PUBLIC_LENGTH]) -> [u8; DH_OUTPUT_LENGTH] {
+        x448::x448_unchecked(*secret, *public)
+    }
+
+    pub(crate) fn is_contributory(public: &[u8; DH_PUBLIC_LENGTH]) -> bool {
+        x448::x448([1u8; DH_SECRET_LENGTH], *public).is_some()
+    }
+
+    /// An X448 key is too long to be an Ed25519 seed, so the seed is derived from it.
+    pub(crate) fn signing_seed(secret: &[u8; DH_SECRET_LENGTH]) -> [u8; 32] {
+        Sha256::new().chain_update(SIGNING_SEED_CONTEXT).chain_update(secret).finalize().into()
+    }
+}
+
+pub(crate) use backend::{diffie_hellman, generate_secret, is_contributory, public_key, signing_seed};
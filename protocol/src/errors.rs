@@ -1,8 +1,11 @@
 //! This module defines the custom error types used throughout the cryptographic protocol implementation.
 //! It provides two main error enums: `X3DHError` for errors specific to the X3DH key agreement protocol,
-//! and `RatchetError` for errors encountered during the Double Ratchet message encryption protocol.
+//! and `RatchetError` for errors encountered during the Double Ratchet message encryption protocol,
+//! as well as `KeystoreError` for the on-disk storage of private keys.
 //! These enums ensure precise error reporting and handling for various cryptographic operations.
 
+use crate::curve::Curve;
+use crate::utils::AeadSuite;
 use aes::cipher::crypto_common;
 use ed25519_dalek::SignatureError;
 use std::fmt::{Display, Formatter};
@@ -43,6 +46,65 @@ pub enum X3DHError {
     
     /// Error indicating that the challenge in the X3DH protocol is invalid.
     InvalidChallenge,
+
+    /// Error indicating that the identity key of a [`crate::utils::PreKeyBundle`] is not a valid curve point.
+    InvalidIdentityKey,
+
+    /// Error indicating that the signed pre-key of a [`crate::utils::PreKeyBundle`] is not a valid curve point.
+    InvalidSignedPreKey,
+
+    /// Error indicating that the signed pre-key of a [`crate::utils::PreKeyBundle`] is the identity key.
+    SignedPreKeyIsIdentityKey,
+
+    /// Error indicating that the one-time pre-key at the given index is not a valid curve point.
+    InvalidOneTimePreKey(usize),
+
+    /// Error indicating that the one-time pre-key at the given index appears earlier in the bundle.
+    DuplicateOneTimePreKey(usize),
+
+    /// Error indicating that the signature of a one-time pre-key does not verify with the identity signing key.
+    InvalidOtpkSignature,
+
+    /// Error indicating that a bundle carries more one-time pre-keys than allowed.
+    TooManyOneTimePreKeys(usize),
+
+    /// Error indicating that a plaintext of the given length exceeds [`crate::constants::MAX_PLAINTEXT_LENGTH`].
+    PlaintextTooLong(usize),
+
+    /// Error indicating that a serialized [`crate::utils::PreKeyBundle`] has a newer format version than supported.
+    UnsupportedBundleVersion(u8),
+
+    /// Error indicating that a serialized [`crate::utils::InitialMessage`] has a newer format version than supported.
+    UnsupportedInitialMessageVersion(u8),
+
+    /// Error indicating that a [`crate::utils::PreKeyBundle`] or an [`crate::utils::InitialMessage`] names an
+    /// [`AeadSuite`] by an id this crate does not know.
+    UnknownAeadSuite(u8),
+
+    /// Error indicating that the peer chose an [`AeadSuite`] other than the expected one, as `(expected, found)`.
+    AeadSuiteMismatch(AeadSuite, AeadSuite),
+
+    /// Error indicating that the KEM pre-key of a [`crate::utils::PreKeyBundle`] has the wrong size, or no signature.
+    InvalidKemPreKey,
+
+    /// Error indicating that the signature of a KEM pre-key does not verify with the identity signing key.
+    InvalidKemSignature,
+
+    /// Error indicating that an [`crate::utils::InitialMessage`] carries a KEM ciphertext, but the responder
+    /// has no KEM pre-key to decapsulate it with.
+    MissingKemPreKey,
+
+    /// Error indicating that a [`crate::utils::PreKeyBundle`] or an [`crate::utils::InitialMessage`] holds
+    /// keys on a [`Curve`] other than [`Curve::ACTIVE`].
+    CurveMismatch(Curve),
+
+    /// Error indicating that a [`crate::utils::PreKeyBundle`] or an [`crate::utils::InitialMessage`] names a
+    /// [`Curve`] by an id this crate does not know.
+    UnknownCurve(u8),
+
+    /// Error indicating that an [`crate::utils::InitialMessage`] or a ratchet state names a
+    /// [`crate::interop::Conformance`] by an id this crate does not know.
+    UnknownConformance(u8),
 }
 
 impl Display for X3DHError {
@@ -67,7 +129,25 @@ impl Display for X3DHError {
             X3DHError::InvalidPrivateKey => write!(f, "Invalid private key"),
             X3DHError::InvalidPublicKey => write!(f, "Invalid public key"),
             X3DHError::InvalidKey => write!(f, "Invalid key"),
-            X3DHError::InvalidChallenge => write!(f, "Invalid challenge length")
+            X3DHError::InvalidIdentityKey => write!(f, "Invalid identity key"),
+            X3DHError::InvalidSignedPreKey => write!(f, "Invalid signed pre-key"),
+            X3DHError::SignedPreKeyIsIdentityKey => write!(f, "Signed pre-key is the identity key"),
+            X3DHError::InvalidOneTimePreKey(i) => write!(f, "Invalid one-time pre-key at index {}", i),
+            X3DHError::DuplicateOneTimePreKey(i) => write!(f, "Duplicate one-time pre-key at index {}", i),
+            X3DHError::InvalidOtpkSignature => write!(f, "Invalid one-time pre-key signature"),
+            X3DHError::TooManyOneTimePreKeys(n) => write!(f, "Too many one-time pre-keys: {}", n),
+            X3DHError::PlaintextTooLong(n) => write!(f, "Plaintext too long: {} bytes", n),
+            X3DHError::UnsupportedBundleVersion(v) => write!(f, "Unsupported prekey bundle version: {}", v),
+            X3DHError::UnsupportedInitialMessageVersion(v) => write!(f, "Unsupported initial message version: {}", v),
+            X3DHError::UnknownAeadSuite(id) => write!(f, "Unknown AEAD suite: {}", id),
+            X3DHError::AeadSuiteMismatch(expected, found) => write!(f, "AEAD suite mismatch: expected {}, found {}", expected, found),
+            X3DHError::InvalidKemPreKey => write!(f, "Invalid KEM pre-key"),
+            X3DHError::InvalidKemSignature => write!(f, "Invalid KEM pre-key signature"),
+            X3DHError::MissingKemPreKey => write!(f, "Missing KEM pre-key"),
+            X3DHError::CurveMismatch(found) => write!(f, "Curve mismatch: expected {}, found {}", Curve::ACTIVE, found),
+            X3DHError::UnknownCurve(id) => write!(f, "Unknown curve: {}", id),
+            X3DHError::UnknownConformance(id) => write!(f, "Unknown conformance: {}", id),
+            X3DHError::InvalidChallenge => write!(f, "Invalid challenge")
         }
     }
 }
@@ -122,12 +202,52 @@ pub enum RatchetError {
     /// Error occurring during message decryption, wrapping an X3DHError.
     DecryptionError(X3DHError),
     
-    /// Error indicating that the maximum number of skipped messages has been exceeded,
-    /// which could indicate a replay attack.
-    MaxSkipsExceeded,
+    /// Error indicating that the maximum number of skipped messages has been exceeded by a message that
+    /// did not authenticate, which could indicate a forged header. Carries the number of messages the header
+    /// claims would have been skipped.
+    MaxSkipsExceeded(u64),
+
+    /// Error indicating that a genuine message came after more messages than the ratchet may skip.
+    /// Carries the number of messages whose keys are out of reach, which are lost for good.
+    MessagesLost(u64),
     
     /// Error indicating a failure in data type conversion.
     ConversionError,
+
+    /// Error indicating that a message sent by this ratchet was received back,
+    /// which could indicate a reflection attack.
+    ReflectedMessage,
+
+    /// Error indicating that a serialized ratchet state is malformed.
+    InvalidState,
+
+    /// Error when the key of a skipped message was evicted, or already used, before the message arrived.
+    SkippedKeyEvicted,
+
+    /// Error indicating that a plaintext of the given length exceeds the limit of the ratchet.
+    PlaintextTooLong(usize),
+
+    /// Error indicating that a message was padded, if `true`, or not, unlike the ratchet that received it,
+    /// or that its header has flags this ratchet does not know.
+    PaddingMismatch(bool),
+
+    /// Error indicating that the padding of a decrypted message is malformed.
+    InvalidPadding,
+
+    /// Error indicating that the chunk size of a stream is zero or larger than the plaintext limit of the ratchet.
+    InvalidChunkSize(usize),
+
+    /// Error indicating that a chunk of a stream came out of order, or after the last one.
+    ChunkOutOfOrder { expected: u64, found: u64 },
+
+    /// Error indicating that a stream ended before its last chunk.
+    TruncatedStream,
+
+    /// Error indicating that a chunk carries the id of another stream than the first chunk.
+    ForeignChunk,
+
+    /// Error reading the payload of a stream, or writing it.
+    StreamIo(std::io::Error),
 }
 
 impl Display for RatchetError {
@@ -146,8 +266,21 @@ impl Display for RatchetError {
             RatchetError::HkdfInvalidLengthError(e) => write!(f, "Invalid length: {}", e),
             RatchetError::InvalidHeaderLength(e) => write!(f, "Invalid header length: {}", e),
             RatchetError::DecryptionError(e) => write!(f, "Decryption error: {}", e),
-            RatchetError::MaxSkipsExceeded => write!(f, "Max skips exceeded"),
+            RatchetError::MaxSkipsExceeded(gap) => write!(f, "Max skips exceeded: {} messages skipped", gap),
+            RatchetError::MessagesLost(gap) => write!(f, "Messages lost: {} messages out of reach", gap),
             RatchetError::ConversionError => write!(f, "Conversion error"),
+            RatchetError::ReflectedMessage => write!(f, "Reflected message"),
+            RatchetError::InvalidState => write!(f, "Invalid ratchet state"),
+            RatchetError::SkippedKeyEvicted => write!(f, "Skipped message key is no longer available"),
+            RatchetError::PlaintextTooLong(n) => write!(f, "Plaintext too long: {} bytes", n),
+            RatchetError::PaddingMismatch(true) => write!(f, "Padded message received by a ratchet without padding"),
+            RatchetError::PaddingMismatch(false) => write!(f, "Unpadded message received by a ratchet with padding"),
+            RatchetError::InvalidPadding => write!(f, "Invalid message padding"),
+            RatchetError::InvalidChunkSize(n) => write!(f, "Invalid chunk size: {} bytes", n),
+            RatchetError::ChunkOutOfOrder { expected, found } => write!(f, "Chunk {} received, expected chunk {}", found, expected),
+            RatchetError::TruncatedStream => write!(f, "Stream ended before its last chunk"),
+            RatchetError::ForeignChunk => write!(f, "Chunk of another stream"),
+            RatchetError::StreamIo(e) => write!(f, "Stream I/O error: {}", e),
         }
     }
 }
@@ -167,4 +300,75 @@ impl From<X3DHError> for RatchetError {
     fn from(value: X3DHError) -> Self {
         RatchetError::DecryptionError(value)
     }
-}
\ No newline at end of file
+}
+
+/// Represents errors that can occur while saving or loading a [`crate::keystore::Keystore`].
+#[derive(Debug)]
+pub enum KeystoreError {
+
+    /// Error reading or writing the keystore file.
+    Io(std::io::Error),
+
+    /// Error indicating that the file is shorter than its header says, carrying the bytes found.
+    Truncated(usize),
+
+    /// Error indicating that the file was written in a layout version this crate does not know.
+    UnsupportedVersion(u8),
+
+    /// Error indicating that the keys were stored for another [`Curve`], by id.
+    CurveMismatch(u8),
+
+    /// Error indicating that the key derivation parameters are out of range.
+    InvalidParams(argon2::Error),
+
+    /// Error indicating that the key derivation parameters cost more than a keystore may ask for.
+    CostTooHigh,
+
+    /// Error indicating that the file does not decrypt with the passphrase: either the passphrase is wrong,
+    /// or the file was altered.
+    WrongPassphrase,
+
+    /// Error indicating that the decrypted keys are malformed.
+    Corrupted,
+}
+
+impl Display for KeystoreError {
+    /// Formats the error message for display.
+    ///
+    /// # Arguments
+    ///
+    /// * `f` - A formatter used to write the error message.
+    ///
+    /// # Returns
+    ///
+    /// * `fmt::Result` - Indicating whether the operation succeeded or failed.
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match &self {
+            KeystoreError::Io(e) => write!(f, "Keystore I/O error: {}", e),
+            KeystoreError::Truncated(n) => write!(f, "Truncated keystore: {} bytes", n),
+            KeystoreError::UnsupportedVersion(v) => write!(f, "Unsupported keystore version: {}", v),
+            KeystoreError::CurveMismatch(id) => write!(f, "Keystore curve mismatch: expected {}, found curve {}", Curve::ACTIVE, id),
+            KeystoreError::InvalidParams(e) => write!(f, "Invalid key derivation parameters: {}", e),
+            KeystoreError::CostTooHigh => write!(f, "Key derivation parameters too costly"),
+            KeystoreError::WrongPassphrase => write!(f, "Wrong passphrase or altered keystore"),
+            KeystoreError::Corrupted => write!(f, "Corrupted keystore"),
+        }
+    }
+}
+
+/// Implements the standard error trait for [`KeystoreError`].
+impl std::error::Error for KeystoreError {}
+
+/// Conversion from an I/O error to [`KeystoreError::Io`].
+impl From<std::io::Error> for KeystoreError {
+    fn from(value: std::io::Error) -> Self {
+        KeystoreError::Io(value)
+    }
+}
+
+/// Conversion from an Argon2 error to [`KeystoreError::InvalidParams`].
+impl From<argon2::Error> for KeystoreError {
+    fn from(value: argon2::Error) -> Self {
+        KeystoreError::InvalidParams(value)
+    }
+}
@@ -0,0 +1,141 @@
+//! This module derives the safety number of a session, which two users compare out of band, by reading it
+//! aloud or side by side, to check that the server did not hand them keys of its own.
+//!
+//! Each party gets a 30-digit fingerprint, the iterated SHA-512 hash of a version, its identity key and its
+//! username, the way Signal derives its numeric fingerprints. The safety number is both fingerprints in a
+//! fixed order, so that the two sides of a session display the same 60 digits.
+
+use crate::utils::PublicKey;
+use sha2::{Digest, Sha512};
+
+/// The version of the fingerprint derivation, hashed first so that a later derivation gives other numbers.
+pub const FINGERPRINT_VERSION: u16 = 0;
+
+/// The number of SHA-512 iterations of a fingerprint, which makes finding a key with a given fingerprint costly.
+pub const FINGERPRINT_ITERATIONS: usize = 5200;
+
+/// The number of digits of the fingerprint of one party.
+pub const FINGERPRINT_DIGITS: usize = 30;
+
+/// The number of digits of a safety number, the fingerprints of both parties.
+pub const SAFETY_NUMBER_DIGITS: usize = 2 * FINGERPRINT_DIGITS;
+
+/// The number of digits of each group in [`format_safety_number`].
+const GROUP_DIGITS: usize = 5;
+
+/// Derives the displayable fingerprint of a party.
+///
+/// Every 5 bytes of the first 30 bytes of the hash, read as a big-endian integer modulo 100000,
+/// give a group of 5 digits.
+///
+/// # Arguments
+///
+/// * `identity_key` - The identity public key of the party.
+/// * `username` - The username of the party.
+///
+/// # Returns
+///
+/// * `String` - The [`FINGERPRINT_DIGITS`] digits of the fingerprint.
+fn fingerprint(identity_key: &PublicKey, username: &str) -> String {
+    let mut digest = Sha512::new()
+        .chain_update(FINGERPRINT_VERSION.to_be_bytes())
+        .chain_update(identity_key.as_ref())
+        .chain_update(username.as_bytes())
+        .finalize();
+    for _ in 0..FINGERPRINT_ITERATIONS {
+        digest = Sha512::new()
+            .chain_update(digest)
+            .chain_update(identity_key.as_ref())
+            .finalize();
+    }
+    digest[..FINGERPRINT_DIGITS]
+        .chunks(GROUP_DIGITS)
+        .map(|chunk| {
+            let group = chunk.iter().fold(0u64, |acc, byte| acc << 8 | u64::from(*byte));
+            format!("{:05}", group % 100_000)
+        })
+        .collect()
+}
+
+/// Computes the safety number of the session between two users.
+///
+/// The number does not depend on which side computes it: the two fingerprints are ordered by value,
+/// not by who is local.
+///
+/// # Arguments
+///
+/// * `local_ik` - Our identity public key.
+/// * `local_name` - Our username.
+/// * `remote_ik` - The identity public key of the other party.
+/// * `remote_name` - The username of the other party.
+///
+/// # Returns
+///
+/// * `String` - The [`SAFETY_NUMBER_DIGITS`] digits of the safety number.
+pub fn compute_safety_number(local_ik: &PublicKey, local_name: &str, remote_ik: &PublicKey, remote_name: &str) -> String {
+    let local = fingerprint(local_ik, local_name);
+    let remote = fingerprint(remote_ik, remote_name);
+    if local <= remote {
+        local + &remote
+    } else {
+        remote + &local
+    }
+}
+
+/// Splits a safety number into groups of 5 digits separated by spaces, for display.
+///
+/// # Arguments
+///
+/// * `safety_number` - A safety number, see [`compute_safety_number`].
+///
+/// # Returns
+///
+/// * `String` - The groups of the safety number.
+pub fn format_safety_number(safety_number: &str) -> String {
+    safety_number
+        .as_bytes()
+        .chunks(GROUP_DIGITS)
+        .map(|group| String::from_utf8_lossy(group).into_owned())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::PrivateKey;
+
+    #[test]
+    fn test_safety_number_is_symmetric() {
+        let alice = PublicKey::from(&PrivateKey::new());
+        let bob = PublicKey::from(&PrivateKey::new());
+        let on_alice = compute_safety_number(&alice, "alice", &bob, "bob");
+        let on_bob = compute_safety_number(&bob, "bob", &alice, "alice");
+        assert_eq!(on_alice, on_bob);
+        assert_eq!(on_alice.len(), SAFETY_NUMBER_DIGITS);
+        assert!(on_alice.bytes().all(|b| b.is_ascii_digit()));
+    }
+
+    #[test]
+    fn test_safety_number_depends_on_keys_and_names() {
+        let alice = PublicKey::from(&PrivateKey::new());
+        let bob = PublicKey::from(&PrivateKey::new());
+        let mallory = PublicKey::from(&PrivateKey::new());
+        let number = compute_safety_number(&alice, "alice", &bob, "bob");
+        assert_ne!(number, compute_safety_number(&alice, "alice", &mallory, "bob"));
+        assert_ne!(number, compute_safety_number(&alice, "alice", &bob, "carol"));
+        // Only the fingerprint of the party whose key changed differs
+        let changed = compute_safety_number(&alice, "alice", &mallory, "bob");
+        let alice_fingerprint = fingerprint(&alice, "alice");
+        assert!(number.contains(&alice_fingerprint) && changed.contains(&alice_fingerprint));
+    }
+
+    #[test]
+    fn test_safety_number_is_formatted_in_groups() {
+        let number = "0123456789".repeat(6);
+        let formatted = format_safety_number(&number);
+        assert_eq!(formatted.split(' ').count(), SAFETY_NUMBER_DIGITS / GROUP_DIGITS);
+        assert!(formatted.starts_with("01234 56789 01234"));
+        assert_eq!(formatted.replace(' ', ""), number);
+    }
+}
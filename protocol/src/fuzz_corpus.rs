@@ -0,0 +1,90 @@
+//! Replays the corpora of the fuzz targets in `fuzz/` through the same parsers, with every truncation and
+//! byte flip of the seeds, so that `cargo test` keeps trying the inputs that once made a parser panic
+//! without needing `cargo fuzz`. A panic fails the test, an error is the expected outcome.
+//!
+//! Inputs found by a fuzzing run are kept by copying them next to the seeds, under a name starting with `seed-`.
+
+use std::fs;
+use std::path::PathBuf;
+use base64::engine::general_purpose;
+use base64::Engine;
+use crate::constants::DH_SECRET_LENGTH;
+use crate::ratchet::{Ratchet, RatchetKeyPair};
+use crate::utils::{AssociatedData, InitialMessage, PreKeyBundle, PrivateKey, PublicKey, SharedSecret};
+
+/// Returns the files of the corpus of `target`, with every truncation and byte flip of each of them.
+pub(crate) fn corpus(target: &str) -> Vec<Vec<u8>> {
+    let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("../fuzz/corpus").join(target);
+    let seeds: Vec<Vec<u8>> = fs::read_dir(&dir)
+        .unwrap_or_else(|e| panic!("Cannot read the corpus in {}: {}", dir.display(), e))
+        .map(|entry| fs::read(entry.unwrap().path()).unwrap())
+        .collect();
+    assert!(!seeds.is_empty(), "The corpus of {} is empty", target);
+
+    let mut inputs = Vec::new();
+    for seed in seeds {
+        for length in 0..seed.len() {
+            inputs.push(seed[..length].to_vec());
+        }
+        for i in 0..seed.len() {
+            let mut flipped = seed.clone();
+            flipped[i] ^= 0xff;
+            inputs.push(flipped);
+        }
+        inputs.push(seed);
+    }
+    inputs
+}
+
+fn key_pair() -> RatchetKeyPair {
+    let private_key = PrivateKey::from_base64(general_purpose::STANDARD.encode([7u8; DH_SECRET_LENGTH])).unwrap();
+    let public_key = PublicKey::from(&private_key);
+    RatchetKeyPair::new_from(private_key, public_key)
+}
+
+#[test]
+fn test_prekey_bundle_corpus() {
+    for data in corpus("prekey_bundle") {
+        let bundles = [
+            PreKeyBundle::try_from(general_purpose::STANDARD.encode(&data)).ok(),
+            serde_json::from_slice::<PreKeyBundle>(&data).ok(),
+            std::str::from_utf8(&data).ok().and_then(|text| PreKeyBundle::try_from(text.to_string()).ok()),
+        ];
+        for bundle in bundles.into_iter().flatten() {
+            let _ = bundle.validate();
+        }
+    }
+}
+
+#[test]
+fn test_initial_message_corpus() {
+    for data in corpus("initial_message") {
+        let _ = InitialMessage::try_from(general_purpose::STANDARD.encode(&data));
+        if let Ok(text) = std::str::from_utf8(&data) {
+            let _ = InitialMessage::try_from(text.to_string());
+        }
+    }
+}
+
+#[test]
+fn test_ratchet_message_corpus() {
+    for data in corpus("ratchet_message") {
+        let mut ratchet = Ratchet::init_bob(SharedSecret::from([1u8; 32]), key_pair());
+        let _ = ratchet.decrypt_bytes(&data, b"aad");
+        let mut ratchet = Ratchet::init_bob_with_header_encryption(SharedSecret::from([1u8; 32]), key_pair());
+        let _ = ratchet.decrypt_bytes(&data, b"aad");
+        if let Ok(text) = std::str::from_utf8(&data) {
+            let _ = ratchet.decrypt(text.to_string(), b"aad");
+        }
+    }
+}
+
+#[test]
+fn test_associated_data_corpus() {
+    for data in corpus("associated_data") {
+        if let Ok(bytes) = <&[u8; AssociatedData::SIZE]>::try_from(data.as_slice()) {
+            let _ = AssociatedData::try_from(bytes);
+        }
+        let _ = serde_json::from_slice::<AssociatedData>(&data);
+    }
+}
@@ -0,0 +1,227 @@
+//! This module records where the protocol departs from the Signal specifications, and holds the switch
+//! between the native derivations and the spec-conformant alternates, a first step towards a bridge to
+//! implementations such as libsignal.
+//!
+//! The deviations, each listed in [`DEVIATIONS`]:
+//!
+//! * **Challenge** - The [`InitialMessage`] carries the identity key of the initiator encrypted under the
+//!   initiator's sending key, which the responder checks before accepting the session. The X3DH specification
+//!   sends the first ratchet message instead, and authenticates the session when it decrypts.
+//!   [`Conformance::Spec`] sends no challenge.
+//! * **X3DH output** - The key agreement expands two directional keys, one per [`Role`], with the labels
+//!   `X3DH initiator->responder` and `X3DH responder->initiator`, and the ratchet root key is the hash of both.
+//!   The X3DH specification expands a single secret `SK` with an application info string, and uses it as the
+//!   root key. [`Conformance::Spec`] expands `SK` with [`SPEC_X3DH_INFO`], see [`root_key`].
+//!   The salt, 32 zero bytes, and the `0xFF` prefix of the input are those of the specification in both modes.
+//! * **Root KDF** - The Double Ratchet `KDF_RK` feeds `F || rk || dh` to HKDF with `rk` as salt and the info
+//!   `RatchtetInfo`, misspelt since the first release. The specification feeds `dh` alone, with `rk` as salt.
+//!   [`Conformance::Spec`] does the latter with [`SPEC_RATCHET_INFO`].
+//! * **Associated data** - The [`AssociatedData`] of a session is the raw identity key of the initiator followed
+//!   by that of the responder. libsignal encodes each key with a leading type byte, and the two layouts
+//!   cannot be bridged without re-encoding.
+//! * **Signatures** - Pre-keys are signed with a separate Ed25519 key of the bundle, where the specification
+//!   signs with XEdDSA under the identity key itself.
+//! * **Pre-key names** - The [`InitialMessage`] names the one-time pre-key it used by id or by hash, and the
+//!   signed pre-key by hash. libsignal names both by id.
+//! * **Bob's first chain** - The responder derives a sending chain from `SK` at once, so that he can send before
+//!   hearing from the initiator. In the specification he waits for the first message.
+//! * **Header encryption** - Headers are sent in the clear by default, which the specification allows. The
+//!   header encryption variant, when enabled, follows the native derivations whatever the conformance.
+//!
+//! Only the first three have an alternate. The others are part of the wire formats, and a bridge would
+//! translate them rather than switch them.
+
+use crate::errors::X3DHError;
+use crate::utils::{AssociatedData, DecryptionKey, EncryptionKey, InitialMessage, SharedSecret};
+use crate::x3dh::Role;
+use std::fmt::Display;
+
+/// HKDF info string of the single secret `SK` of a spec-conformant X3DH key agreement, the one of libsignal.
+pub const SPEC_X3DH_INFO: &[u8] = b"WhisperText";
+
+/// HKDF info string of the spec-conformant `KDF_RK` of the Double Ratchet, the one of libsignal.
+pub const SPEC_RATCHET_INFO: &[u8] = b"WhisperRatchet";
+
+/// HKDF info string of the native `KDF_RK` of the Double Ratchet.
+pub const NATIVE_RATCHET_INFO: &[u8] = b"RatchtetInfo";
+
+/// The derivations a session follows, chosen by the initiator and carried in the [`InitialMessage`].
+///
+/// Both parties of a session must use the same one. The conformance is kept in the ratchet state,
+/// so that a restored session keeps deriving its keys the way it started.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum Conformance {
+    /// The derivations of this crate, with the challenge and the directional X3DH keys.
+    #[default]
+    Native,
+
+    /// The derivations of the X3DH and Double Ratchet specifications, without a challenge.
+    Spec,
+}
+
+impl Conformance {
+
+    /// Returns the id of the conformance in a serialized [`InitialMessage`] or ratchet state.
+    pub fn id(&self) -> u8 {
+        match self {
+            Conformance::Native => 0,
+            Conformance::Spec => 1,
+        }
+    }
+
+    /// Returns `true` if the [`InitialMessage`] of a session carries a challenge.
+    pub fn has_challenge(&self) -> bool {
+        *self == Conformance::Native
+    }
+}
+
+impl TryFrom<u8> for Conformance {
+    type Error = X3DHError;
+
+    /// Converts an id written by [`Conformance::id`] back into a [`Conformance`].
+    ///
+    /// # Errors
+    ///
+    /// * [`X3DHError::UnknownConformance`] - Returned if `value` is not the id of a known conformance.
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Conformance::Native),
+            1 => Ok(Conformance::Spec),
+            _ => Err(X3DHError::UnknownConformance(value)),
+        }
+    }
+}
+
+impl Display for Conformance {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Conformance::Native => write!(f, "native"),
+            Conformance::Spec => write!(f, "spec"),
+        }
+    }
+}
+
+/// A departure from the Signal specifications.
+#[derive(Clone, Copy, Debug)]
+pub struct Deviation {
+    /// A short name of the deviation.
+    pub name: &'static str,
+
+    /// What the specifications do.
+    pub spec: &'static str,
+
+    /// What this crate does natively.
+    pub native: &'static str,
+
+    /// Whether [`Conformance::Spec`] follows the specifications instead.
+    pub alternate: bool,
+}
+
+/// Every departure from the Signal specifications, see the module documentation.
+pub const DEVIATIONS: &[Deviation] = &[
+    Deviation {
+        name: "challenge",
+        spec: "the initial message carries the first ratchet message",
+        native: "the initial message carries the initiator's identity key encrypted under its sending key",
+        alternate: true,
+    },
+    Deviation {
+        name: "x3dh-output",
+        spec: "a single secret SK, expanded with an application info string and used as the root key",
+        native: "two directional keys expanded with per-role labels, the root key being the hash of both",
+        alternate: true,
+    },
+    Deviation {
+        name: "root-kdf",
+        spec: "HKDF(salt = rk, ikm = dh, info)",
+        native: "HKDF(salt = rk, ikm = F || rk || dh, info = \"RatchtetInfo\")",
+        alternate: true,
+    },
+    Deviation {
+        name: "associated-data",
+        spec: "Encode(IK_A) || Encode(IK_B), each key with a leading type byte",
+        native: "the raw identity keys of the initiator and the responder",
+        alternate: false,
+    },
+    Deviation {
+        name: "signatures",
+        spec: "XEdDSA signatures under the identity key",
+        native: "Ed25519 signatures under a separate signing key of the bundle",
+        alternate: false,
+    },
+    Deviation {
+        name: "prekey-names",
+        spec: "pre-keys named by id",
+        native: "one-time pre-keys named by id or hash, signed pre-keys by hash",
+        alternate: false,
+    },
+    Deviation {
+        name: "responder-first-chain",
+        spec: "the responder sends once the initiator's first message is received",
+        native: "the responder derives a sending chain from SK at once",
+        alternate: false,
+    },
+    Deviation {
+        name: "header-encryption",
+        spec: "optional, with KDF_RK_HE deriving the next header keys",
+        native: "off by default, and following the native derivations when enabled",
+        alternate: false,
+    },
+];
+
+/// Derives the root key of the ratchet from the keys of the X3DH key agreement.
+///
+/// In [`Conformance::Native`] this is [`SharedSecret::from_session_keys`] for the `role` of the caller.
+/// In [`Conformance::Spec`] both keys are the secret `SK` of the specification, which is the root key itself.
+///
+/// # Arguments
+///
+/// * `conformance` - The [`Conformance`] of the session, see [`InitialMessage::conformance`].
+/// * `role` - The [`Role`] of the caller.
+/// * `ek` - The encryption key returned by the key agreement.
+/// * `dk` - The decryption key returned by the key agreement.
+///
+/// # Returns
+///
+/// * [`SharedSecret`] - The root key, the same on both sides.
+pub fn root_key(conformance: Conformance, role: Role, ek: EncryptionKey, dk: DecryptionKey) -> SharedSecret {
+    match conformance {
+        Conformance::Spec => SharedSecret::from(*ek.as_ref()),
+        Conformance::Native => SharedSecret::from_session_keys(role, &ek, &dk),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    #[test]
+    fn test_conformance_ids_round_trip() {
+        for conformance in [Conformance::Native, Conformance::Spec] {
+            assert_eq!(Conformance::try_from(conformance.id()).unwrap(), conformance);
+        }
+        assert_eq!(Conformance::default(), Conformance::Native);
+        assert!(matches!(Conformance::try_from(2), Err(X3DHError::UnknownConformance(2))));
+    }
+
+    #[test]
+    fn test_deviations_are_named_once() {
+        let names = DEVIATIONS.iter().map(|d| d.name).collect::<HashSet<_>>();
+        assert_eq!(names.len(), DEVIATIONS.len());
+        assert!(names.contains("challenge") && names.contains("x3dh-output") && names.contains("root-kdf"));
+        assert_eq!(DEVIATIONS.iter().filter(|d| d.alternate).count(), 3);
+    }
+
+    #[test]
+    fn test_spec_root_key_is_sk() {
+        let sk = SharedSecret::from([7u8; 32]);
+        let ek = EncryptionKey::from(sk.clone());
+        let dk = DecryptionKey::from(sk.clone());
+        let initiator = root_key(Conformance::Spec, Role::Initiator, ek.clone(), dk.clone());
+        let responder = root_key(Conformance::Spec, Role::Responder, ek.clone(), dk.clone());
+        assert_eq!(initiator.as_ref(), sk.as_ref());
+        assert_eq!(responder.as_ref(), sk.as_ref());
+        assert_ne!(root_key(Conformance::Native, Role::Initiator, ek, dk).as_ref(), sk.as_ref());
+    }
+}
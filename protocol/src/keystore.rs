@@ -0,0 +1,353 @@
+//! This module persists the private keys of a user, the identity key, the signed pre-key and the one-time
+//! pre-keys, in a single file encrypted under a passphrase.
+//!
+//! The key of the file is derived from the passphrase with Argon2id, under a random salt. The layout of the
+//! file is a header in the clear followed by the AES-256-GCM frame of the keys, the header being the
+//! associated data of the frame:
+//!
+//! ```text
+//! version (1) | curve (1) | memory cost (4) | time cost (4) | parallelism (4) | salt (16) | length (4) | frame
+//! ```
+//!
+//! The integers are big-endian, and the length is that of the frame. Keeping the Argon2 parameters in the
+//! header lets a keystore written with stronger ones be read back without knowing them in advance.
+//!
+//! A keystore is written to a temporary file next to it and renamed over it, so that a crash while saving
+//! leaves either the old keys or the new ones.
+
+use crate::constants::{AES256_GCM_TAG_LENGTH, AES256_NONCE_LENGTH, AES256_SECRET_LENGTH, DH_SECRET_LENGTH};
+use crate::curve::Curve;
+use crate::errors::KeystoreError;
+use crate::utils::{DecryptionKey, EncryptionKey, PrivateKey, SharedSecret};
+use argon2::{Algorithm, Argon2, Params, Version};
+use arrayref::array_ref;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::Path;
+use zeroize::Zeroizing;
+
+/// Version of the keystore layout, written first in the clear.
+pub const KEYSTORE_VERSION: u8 = 1;
+
+/// Byte size of the random salt of the key derivation.
+const SALT_LENGTH: usize = 16;
+
+/// Byte size of the header: version, curve, the three Argon2 parameters, salt and frame length.
+const HEADER_LENGTH: usize = 1 + 1 + 3 * 4 + SALT_LENGTH + 4;
+
+/// The largest Argon2 memory cost accepted from a file, in KiB, so that a forged header cannot
+/// make loading allocate without bound.
+const MAX_MEMORY_COST: u32 = 1024 * 1024;
+
+/// The largest Argon2 time cost accepted from a file, so that a forged header cannot make loading
+/// run for hours.
+const MAX_TIME_COST: u32 = 64;
+
+/// The largest Argon2 parallelism accepted from a file.
+const MAX_PARALLELISM: u32 = 64;
+
+/// The Argon2id parameters of the key derivation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct KeystoreParams {
+    /// The memory used by the derivation, in KiB.
+    pub memory_cost: u32,
+
+    /// The number of passes over the memory.
+    pub time_cost: u32,
+
+    /// The number of lanes.
+    pub parallelism: u32,
+}
+
+impl Default for KeystoreParams {
+    /// The parameters recommended by the Argon2 crate: 19 MiB, 2 passes and 1 lane.
+    fn default() -> Self {
+        Self {
+            memory_cost: Params::DEFAULT_M_COST,
+            time_cost: Params::DEFAULT_T_COST,
+            parallelism: Params::DEFAULT_P_COST,
+        }
+    }
+}
+
+impl KeystoreParams {
+
+    /// Derives the key of a keystore from `passphrase` and `salt`.
+    ///
+    /// # Errors
+    ///
+    /// * [`KeystoreError::InvalidParams`] - Returned if the parameters are out of the range of Argon2.
+    /// * [`KeystoreError::CostTooHigh`] - Returned if the memory cost, time cost or parallelism is beyond
+    ///   [`MAX_MEMORY_COST`], [`MAX_TIME_COST`] or [`MAX_PARALLELISM`].
+    fn derive_key(&self, passphrase: &str, salt: &[u8]) -> Result<SharedSecret, KeystoreError> {
+        if self.memory_cost > MAX_MEMORY_COST || self.time_cost > MAX_TIME_COST || self.parallelism > MAX_PARALLELISM {
+            return Err(KeystoreError::CostTooHigh);
+        }
+        let params = Params::new(self.memory_cost, self.time_cost, self.parallelism, Some(AES256_SECRET_LENGTH))?;
+        let mut key = Zeroizing::new([0u8; AES256_SECRET_LENGTH]);
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params).hash_password_into(passphrase.as_bytes(), salt, key.as_mut())?;
+        Ok(SharedSecret::from(*key))
+    }
+}
+
+/// The private keys of a user. They are zeroized when the keystore is dropped.
+#[derive(Clone)]
+pub struct Keystore {
+    /// The identity key.
+    pub identity_key: PrivateKey,
+
+    /// The signed pre-key.
+    pub signed_prekey: PrivateKey,
+
+    /// The one-time pre-keys not used yet, by id.
+    pub one_time_prekeys: BTreeMap<u32, PrivateKey>,
+}
+
+impl Keystore {
+
+    /// Creates a keystore of the given keys.
+    pub fn new(identity_key: PrivateKey, signed_prekey: PrivateKey, one_time_prekeys: BTreeMap<u32, PrivateKey>) -> Self {
+        Self { identity_key, signed_prekey, one_time_prekeys }
+    }
+
+    /// Encrypts the keys under `passphrase` and writes them to `path`, with the default [`KeystoreParams`].
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The file to write, replaced at once if it exists.
+    /// * `passphrase` - The passphrase the key of the file is derived from.
+    ///
+    /// # Errors
+    ///
+    /// * [`KeystoreError::Io`] - Returned if the file cannot be written.
+    pub fn save(&self, path: &Path, passphrase: &str) -> Result<(), KeystoreError> {
+        self.save_with_params(path, passphrase, KeystoreParams::default())
+    }
+
+    /// Encrypts the keys under `passphrase` and writes them to `path`, deriving the key with `params`.
+    ///
+    /// # Errors
+    ///
+    /// * [`KeystoreError::Io`] - Returned if the file cannot be written.
+    /// * [`KeystoreError::InvalidParams`] - Returned if `params` are out of range.
+    /// * [`KeystoreError::CostTooHigh`] - Returned if `params` cost more than a keystore may ask for.
+    pub fn save_with_params(&self, path: &Path, passphrase: &str, params: KeystoreParams) -> Result<(), KeystoreError> {
+        let bytes = self.to_bytes(passphrase, params)?;
+        let mut temp = path.as_os_str().to_owned();
+        temp.push(".tmp");
+        let temp = Path::new(&temp);
+        let written = File::create(temp)
+            .and_then(|mut file| {
+                file.write_all(&bytes)?;
+                file.sync_all()
+            })
+            .and_then(|_| fs::rename(temp, path));
+        if written.is_err() {
+            let _ = fs::remove_file(temp);
+        }
+        Ok(written?)
+    }
+
+    /// Reads the keystore at `path` and decrypts it with `passphrase`.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The file written by [`Keystore::save`].
+    /// * `passphrase` - The passphrase the file was saved with.
+    ///
+    /// # Returns
+    ///
+    /// * [`Keystore`] - The keys of the file.
+    ///
+    /// # Errors
+    ///
+    /// * [`KeystoreError::Io`] - Returned if the file cannot be read.
+    /// * [`KeystoreError::Truncated`] - Returned if the file is shorter than its header says.
+    /// * [`KeystoreError::UnsupportedVersion`] - Returned if the file has a layout version this crate does not know.
+    /// * [`KeystoreError::CurveMismatch`] - Returned if the keys are for another curve than [`Curve::ACTIVE`].
+    /// * [`KeystoreError::InvalidParams`] - Returned if the Argon2 parameters of the header are out of range.
+    /// * [`KeystoreError::CostTooHigh`] - Returned if the Argon2 parameters of the header cost too much.
+    /// * [`KeystoreError::WrongPassphrase`] - Returned if the file does not decrypt with `passphrase`,
+    ///   which is also the case if it was altered.
+    /// * [`KeystoreError::Corrupted`] - Returned if the decrypted keys are malformed.
+    pub fn load(path: &Path, passphrase: &str) -> Result<Self, KeystoreError> {
+        Self::from_bytes(&fs::read(path)?, passphrase)
+    }
+
+    /// Serializes and encrypts the keys, see the module documentation for the layout.
+    fn to_bytes(&self, passphrase: &str, params: KeystoreParams) -> Result<Vec<u8>, KeystoreError> {
+        let mut salt = [0u8; SALT_LENGTH];
+        OsRng.fill_bytes(&mut salt);
+        let key = EncryptionKey::from(params.derive_key(passphrase, &salt)?);
+
+        // Sized up front, so that no reallocation leaves a copy of the keys behind
+        let entry_length = 4 + DH_SECRET_LENGTH;
+        let mut plaintext = Zeroizing::new(Vec::with_capacity(2 * DH_SECRET_LENGTH + 4 + self.one_time_prekeys.len() * entry_length));
+        plaintext.extend_from_slice(self.identity_key.as_ref());
+        plaintext.extend_from_slice(self.signed_prekey.as_ref());
+        plaintext.extend_from_slice(&(self.one_time_prekeys.len() as u32).to_be_bytes());
+        for (id, otpk) in &self.one_time_prekeys {
+            plaintext.extend_from_slice(&id.to_be_bytes());
+            plaintext.extend_from_slice(otpk.as_ref());
+        }
+
+        let frame_length = (AES256_NONCE_LENGTH + plaintext.len() + AES256_GCM_TAG_LENGTH) as u32;
+        let mut header = Vec::with_capacity(HEADER_LENGTH);
+        header.push(KEYSTORE_VERSION);
+        header.push(Curve::ACTIVE.id());
+        header.extend_from_slice(&params.memory_cost.to_be_bytes());
+        header.extend_from_slice(&params.time_cost.to_be_bytes());
+        header.extend_from_slice(&params.parallelism.to_be_bytes());
+        header.extend_from_slice(&salt);
+        header.extend_from_slice(&frame_length.to_be_bytes());
+
+        let frame = key.encrypt_bytes(&plaintext, &header).map_err(|_| KeystoreError::Corrupted)?;
+        debug_assert_eq!(frame.len(), frame_length as usize);
+        header.extend_from_slice(&frame);
+        Ok(header)
+    }
+
+    /// Decrypts and parses the bytes written by [`Keystore::to_bytes`].
+    fn from_bytes(bytes: &[u8], passphrase: &str) -> Result<Self, KeystoreError> {
+        if bytes.len() < HEADER_LENGTH {
+            return Err(KeystoreError::Truncated(bytes.len()));
+        }
+        let (header, frame) = bytes.split_at(HEADER_LENGTH);
+        if header[0] != KEYSTORE_VERSION {
+            return Err(KeystoreError::UnsupportedVersion(header[0]));
+        }
+        if header[1] != Curve::ACTIVE.id() {
+            return Err(KeystoreError::CurveMismatch(header[1]));
+        }
+        let params = KeystoreParams {
+            memory_cost: u32::from_be_bytes(*array_ref!(header, 2, 4)),
+            time_cost: u32::from_be_bytes(*array_ref!(header, 6, 4)),
+            parallelism: u32::from_be_bytes(*array_ref!(header, 10, 4)),
+        };
+        let salt = &header[14..14 + SALT_LENGTH];
+        let frame_length = u32::from_be_bytes(*array_ref!(header, 14 + SALT_LENGTH, 4)) as usize;
+        if frame.len() < frame_length {
+            return Err(KeystoreError::Truncated(bytes.len()));
+        }
+        if frame.len() > frame_length {
+            return Err(KeystoreError::Corrupted);
+        }
+
+        let key = DecryptionKey::from(params.derive_key(passphrase, salt)?);
+        let plaintext = Zeroizing::new(key.decrypt_frame(frame, header).map_err(|_| KeystoreError::WrongPassphrase)?);
+        Self::parse(&plaintext)
+    }
+
+    /// Parses the decrypted keys.
+    fn parse(plaintext: &[u8]) -> Result<Self, KeystoreError> {
+        let fixed = 2 * DH_SECRET_LENGTH + 4;
+        if plaintext.len() < fixed {
+            return Err(KeystoreError::Corrupted);
+        }
+        let identity_key = PrivateKey::from(*array_ref!(plaintext, 0, DH_SECRET_LENGTH));
+        let signed_prekey = PrivateKey::from(*array_ref!(plaintext, DH_SECRET_LENGTH, DH_SECRET_LENGTH));
+        let count = u32::from_be_bytes(*array_ref!(plaintext, 2 * DH_SECRET_LENGTH, 4)) as usize;
+        let entries = &plaintext[fixed..];
+        let entry_length = 4 + DH_SECRET_LENGTH;
+        if entries.len() != count * entry_length {
+            return Err(KeystoreError::Corrupted);
+        }
+        let mut one_time_prekeys = BTreeMap::new();
+        for entry in entries.chunks_exact(entry_length) {
+            let id = u32::from_be_bytes(*array_ref!(entry, 0, 4));
+            let otpk = PrivateKey::from(*array_ref!(entry, 4, DH_SECRET_LENGTH));
+            if one_time_prekeys.insert(id, otpk).is_some() {
+                return Err(KeystoreError::Corrupted);
+            }
+        }
+        Ok(Self { identity_key, signed_prekey, one_time_prekeys })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::PublicKey;
+
+    /// Cheap parameters, so that the tests do not spend their time deriving keys.
+    const TEST_PARAMS: KeystoreParams = KeystoreParams { memory_cost: 256, time_cost: 1, parallelism: 1 };
+
+    fn keystore() -> Keystore {
+        let otpks = (0..5).map(|id| (id * 3, PrivateKey::new())).collect();
+        Keystore::new(PrivateKey::new(), PrivateKey::new(), otpks)
+    }
+
+    fn public(key: &PrivateKey) -> PublicKey {
+        PublicKey::from(key)
+    }
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("keystore-{}-{}", name, std::process::id()))
+    }
+
+    #[test]
+    fn test_keystore_round_trip() {
+        let path = temp_path("round-trip");
+        let keystore = keystore();
+        keystore.save_with_params(&path, "correct horse", TEST_PARAMS).unwrap();
+        let loaded = Keystore::load(&path, "correct horse").unwrap();
+        // Saving again replaces the file, leaving no temporary file behind
+        keystore.save_with_params(&path, "correct horse", TEST_PARAMS).unwrap();
+        let mut temp = path.clone().into_os_string();
+        temp.push(".tmp");
+        assert!(!Path::new(&temp).exists());
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(public(&loaded.identity_key), public(&keystore.identity_key));
+        assert_eq!(public(&loaded.signed_prekey), public(&keystore.signed_prekey));
+        assert_eq!(loaded.one_time_prekeys.keys().collect::<Vec<_>>(), vec![&0, &3, &6, &9, &12]);
+        for (id, otpk) in &keystore.one_time_prekeys {
+            assert_eq!(public(&loaded.one_time_prekeys[id]), public(otpk));
+        }
+        // The keys are not written in the clear
+        let bytes = keystore.to_bytes("correct horse", TEST_PARAMS).unwrap();
+        assert!(!bytes.windows(DH_SECRET_LENGTH).any(|w| w == keystore.identity_key.as_ref()));
+    }
+
+    #[test]
+    fn test_keystore_rejects_a_wrong_passphrase() {
+        let bytes = keystore().to_bytes("correct horse", TEST_PARAMS).unwrap();
+        assert!(matches!(Keystore::from_bytes(&bytes, "battery staple"), Err(KeystoreError::WrongPassphrase)));
+    }
+
+    #[test]
+    fn test_keystore_detects_tampering() {
+        let bytes = keystore().to_bytes("correct horse", TEST_PARAMS).unwrap();
+        // A flipped bit anywhere in the salt or the frame fails to authenticate
+        for at in [HEADER_LENGTH - 5, HEADER_LENGTH + 2, HEADER_LENGTH + 40, bytes.len() - 1] {
+            let mut tampered = bytes.clone();
+            tampered[at] ^= 0x01;
+            assert!(matches!(Keystore::from_bytes(&tampered, "correct horse"), Err(KeystoreError::WrongPassphrase)));
+        }
+        let mut tampered = bytes.clone();
+        tampered[0] = KEYSTORE_VERSION + 1;
+        assert!(matches!(Keystore::from_bytes(&tampered, "correct horse"), Err(KeystoreError::UnsupportedVersion(_))));
+        // A header asking for more memory, passes or lanes than allowed is refused before any derivation
+        for at in [2, 6, 10] {
+            let mut tampered = bytes.clone();
+            tampered[at] = 0xFF;
+            assert!(matches!(Keystore::from_bytes(&tampered, "correct horse"), Err(KeystoreError::CostTooHigh)));
+        }
+    }
+
+    #[test]
+    fn test_keystore_detects_truncation() {
+        let bytes = keystore().to_bytes("correct horse", TEST_PARAMS).unwrap();
+        for length in [0, HEADER_LENGTH - 1, HEADER_LENGTH, bytes.len() - 1] {
+            assert!(matches!(
+                Keystore::from_bytes(&bytes[..length], "correct horse"),
+                Err(KeystoreError::Truncated(n)) if n == length
+            ));
+        }
+        let path = temp_path("missing");
+        assert!(matches!(Keystore::load(&path, "correct horse"), Err(KeystoreError::Io(_))));
+    }
+}
@@ -1,6 +1,16 @@
 #![allow(warnings)]
 pub mod utils;
 pub mod constants;
+pub mod curve;
 pub mod x3dh;
 pub mod errors;
-pub mod ratchet;
\ No newline at end of file
+pub mod ratchet;
+pub mod interop;
+pub mod fingerprint;
+pub mod keystore;
+pub mod stream;
+#[cfg(all(test, not(feature = "x448")))]
+mod test_vectors;
+#[cfg(test)]
+mod fuzz_corpus;
+
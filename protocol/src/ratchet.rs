@@ -5,18 +5,22 @@
 //! For more information, see the [Signal Protocol specification: The Double Ratchet Algorithm](https://signal.org/docs/specifications/doubleratchet/).
 
 use std::cmp::PartialEq;
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::fmt::Debug;
 use aes_gcm::aead::Buffer;
 use arrayref::array_ref;
 use base64::Engine;
 use base64::engine::general_purpose;
 use zeroize::{Zeroize, ZeroizeOnDrop};
-use crate::utils::{AssociatedData, DecryptionKey, EncryptionKey, PrivateKey, PublicKey, SharedSecret};
+use crate::utils::{AeadSuite, AssociatedData, DecryptionKey, DhOutput, EncryptionKey, PrivateKey, PublicKey, SharedSecret};
 use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use sha2::Sha256;
-use crate::constants::{AES256_NONCE_LENGTH, AES256_SECRET_LENGTH, CURVE25519_PUBLIC_LENGTH, MAX_SKIPS};
+use crate::constants::{AES256_GCM_TAG_LENGTH, AES256_NONCE_LENGTH, AES256_SECRET_LENGTH, DH_PUBLIC_LENGTH, DH_SECRET_LENGTH, MAX_LOST_MESSAGES, MAX_PLAINTEXT_LENGTH, MAX_SKIPPED_KEYS, MAX_SKIPS};
+use crate::curve::{self, Curve};
 use crate::errors::RatchetError;
+use crate::interop::{Conformance, NATIVE_RATCHET_INFO, SPEC_RATCHET_INFO};
 use crate::errors::RatchetError::ConversionError;
 
 /// A [`RatchetKeyPair`] consists of a public and private key, 
@@ -33,6 +37,9 @@ pub struct RatchetKeyPair {
 }
 
 impl RatchetKeyPair {
+    /// The byte length of a serialized [`RatchetKeyPair`]: the private key followed by the public key.
+    const SERIALIZED_LENGTH: usize = DH_SECRET_LENGTH + DH_PUBLIC_LENGTH;
+
     /// Generates a new [`RatchetKeyPair`] with a freshly created private key
     /// and its corresponding public key.
     ///
@@ -80,15 +87,61 @@ impl RatchetKeyPair {
     /// 
     /// # Returns
     /// 
-    /// * [`SharedSecret`] - A [`SharedSecret`] derived from this key pair's private key and the given public key.
+    /// * [`DhOutput`] - The output of the exchange between this key pair's private key and the given public key.
     fn diffie_hellman(
         &self,
         other_public_key: &PublicKey,
-    ) -> SharedSecret {
+    ) -> DhOutput {
         self.private_key.diffie_hellman(other_public_key)
     }
 }
 
+impl Zeroize for RatchetKeyPair {
+
+    /// Wipes the private key. The public key is not secret and is left untouched.
+    fn zeroize(&mut self) {
+        self.private_key.zeroize();
+    }
+}
+
+impl Serialize for RatchetKeyPair {
+
+    /// Serializes the [`RatchetKeyPair`] as the private key bytes followed by the public key bytes.
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut bytes = self.private_key.to_bytes();
+        bytes.extend_from_slice(self.public_key.as_ref());
+        let result = serializer.serialize_bytes(&bytes);
+        bytes.zeroize();
+        result
+    }
+}
+
+impl<'de> Deserialize<'de> for RatchetKeyPair {
+
+    /// Deserializes a [`RatchetKeyPair`] serialized with its [`Serialize`] implementation.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the length is wrong or the public key does not match the private key.
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let mut bytes = serde_bytes::ByteBuf::deserialize(deserializer)?.into_vec();
+        let result = match bytes.len() {
+            Self::SERIALIZED_LENGTH => {
+                let private_key = PrivateKey::from(*array_ref!(bytes, 0, DH_SECRET_LENGTH));
+                let public_key = PublicKey::from(array_ref!(bytes, DH_SECRET_LENGTH, DH_PUBLIC_LENGTH));
+                if PublicKey::from(&private_key).ct_eq(&public_key) {
+                    Ok(Self::new_from(private_key, public_key))
+                } else {
+                    Err(serde::de::Error::custom(RatchetError::InvalidState))
+                }
+            }
+            len => Err(serde::de::Error::invalid_length(len, &"a private key followed by its public key")),
+        };
+        bytes.zeroize();
+        result
+    }
+}
+
 /// A [`Header`] represents a Double Ratchet header containing key and message state metadata for the encrypted message.
 #[derive(Clone)]
 struct Header {
@@ -102,14 +155,27 @@ struct Header {
 
     /// The current message number in the sending chain.
     ns: u64,
+
+    /// The options the message was encrypted with, see [`Header::FLAG_PADDED`].
+    flags: u8,
 }
 
 impl Header {
 
+    /// Set in [`Header::flags`] when the plaintext was padded, see [`Padding`].
+    const FLAG_PADDED: u8 = 0x01;
+
+    /// The bits of the `pn` field holding the counter. The top byte holds the flags, so that the header of a
+    /// message without flags is the one of earlier releases.
+    const COUNTER_MASK: u64 = (1 << 56) - 1;
+
     /// The total byte length of the serialized [`Header`], which includes:
-    /// * the length of the public key ([`AES256_SECRET_LENGTH`])
-    /// * two `u64` values (`pn` and `ns`)
-    const LENGTH: usize = AES256_SECRET_LENGTH + size_of::<u64>() * 2;
+    /// * the length of the public key ([`DH_PUBLIC_LENGTH`])
+    /// * two `u64` values (`pn` and `ns`), the flags in the top byte of `pn`
+    const LENGTH: usize = DH_PUBLIC_LENGTH + size_of::<u64>() * 2;
+
+    /// The byte length of an encrypted [`Header`]: nonce, serialized header and authentication tag.
+    const ENCRYPTED_LENGTH: usize = AES256_NONCE_LENGTH + Self::LENGTH + AES256_GCM_TAG_LENGTH;
 
     /// Constructs a new [`Header`] with the given public key and message counters.
     ///
@@ -123,7 +189,7 @@ impl Header {
     ///
     /// * [`Header`] - A new [`Header`] instance containing the provided values.
     pub fn new(dhs: PublicKey, pn: u64, ns: u64) -> Self {
-        Self { dhs, pn, ns }
+        Self { dhs, pn, ns, flags: 0 }
     }
 
     /// Converts each element of the [`Header`] into bytes.
@@ -134,13 +200,162 @@ impl Header {
     pub fn to_bytes(&self) -> Vec<u8> {
         let mut bytes = Vec::new();
         bytes.extend_from_slice(self.dhs.as_ref());
-        bytes.extend_from_slice(&self.pn.to_le_bytes());
+        bytes.extend_from_slice(&((self.pn & Self::COUNTER_MASK) | (self.flags as u64) << 56).to_le_bytes());
         bytes.extend_from_slice(&self.ns.to_le_bytes());
         bytes
     }
+
+    /// Encrypts the [`Header`] with a header key.
+    ///
+    /// # Arguments
+    ///
+    /// * `hk` - The header key of the sending chain.
+    /// * `suite` - The [`AeadSuite`] of the session.
+    ///
+    /// # Returns
+    ///
+    /// * `Vec<u8>` - The nonce followed by the encrypted header, [`Header::ENCRYPTED_LENGTH`] bytes long.
+    ///
+    /// # Errors
+    ///
+    /// * [`RatchetError::DecryptionError`] - Returned if the encryption fails.
+    fn encrypt(&self, hk: &SharedSecret, suite: AeadSuite) -> Result<Vec<u8>, RatchetError> {
+        // With header encryption the plaintext header is hidden from the relay, so it is wiped like key material
+        let mut bytes = self.to_bytes();
+        let encrypted = EncryptionKey::from(hk.clone()).with_suite(suite).encrypt(&bytes, &[]);
+        bytes.zeroize();
+        general_purpose::STANDARD.decode(encrypted?).map_err(|_| ConversionError)
+    }
+
+    /// Decrypts a [`Header`] encrypted with [`Header::encrypt`].
+    ///
+    /// # Arguments
+    ///
+    /// * `hk` - The header key to try.
+    /// * `suite` - The [`AeadSuite`] of the session.
+    /// * `encrypted` - The nonce followed by the encrypted header.
+    ///
+    /// # Returns
+    ///
+    /// * [`Header`] - The decrypted header.
+    ///
+    /// # Errors
+    ///
+    /// * [`RatchetError::DecryptionError`] - Returned if the header was not encrypted with `hk`.
+    fn decrypt(hk: &SharedSecret, suite: AeadSuite, encrypted: &[u8; Self::ENCRYPTED_LENGTH]) -> Result<Self, RatchetError> {
+        let nonce = array_ref!(encrypted, 0, AES256_NONCE_LENGTH);
+        let mut bytes = DecryptionKey::from(hk.clone()).with_suite(suite).decrypt(&encrypted[AES256_NONCE_LENGTH..], nonce, &[])?;
+        let header = match <&[u8; Self::LENGTH]>::try_from(bytes.as_slice()) {
+            Ok(header) => Header::try_from(header),
+            Err(_) => Err(RatchetError::InvalidHeaderLength(bytes.len())),
+        };
+        bytes.zeroize();
+        header
+    }
+}
+
+/// The header keys of a [`Ratchet`] created with header encryption.
+///
+/// Each sending chain has its own header key, so the relay only sees encrypted headers
+/// and cannot link messages to ratchet public keys or counters.
+#[derive(Clone)]
+struct HeaderKeys {
+    /// The header key of the current sending chain.
+    sending: SharedSecret,
+
+    /// The header key of the current receiving chain, `None` before the first message is received.
+    receiving: Option<SharedSecret>,
+
+    /// The header key of the next sending chain, used after the next DH ratchet step.
+    next_sending: SharedSecret,
+
+    /// The header key of the next receiving chain, used to detect the next DH ratchet step.
+    next_receiving: SharedSecret,
+
+    /// The header keys of the receiving chains with skipped message keys, indexed by sender public key.
+    skipped: HashMap<PublicKey, SharedSecret>,
+}
+
+impl Zeroize for HeaderKeys {
+
+    /// Wipes every header key, including those of the chains with skipped messages.
+    fn zeroize(&mut self) {
+        self.sending.zeroize();
+        self.receiving.zeroize();
+        self.next_sending.zeroize();
+        self.next_receiving.zeroize();
+        self.skipped.values_mut().for_each(Zeroize::zeroize);
+        self.skipped.clear();
+    }
+}
+
+/// The sending state of a [`Ratchet`] before its first sending chain.
+///
+/// It is kept while no message was sent on the new sending chain: the peer cannot know the new ratchet key yet,
+/// so a ratchet key it introduces in the meantime was derived against this state. A rotation requested with
+/// [`Ratchet::force_rekey`] captures the state the same way, but only while the message that performs it is encrypted.
+#[derive(Clone)]
+struct UnsentChain {
+    /// The root key before the sending half.
+    root_key: SharedSecret,
+
+    /// The sending key pair before the sending half, the one the peer last saw.
+    dh_sending: RatchetKeyPair,
+
+    /// The sending and next sending header keys before the sending half, if the ratchet uses header encryption.
+    header_keys: Option<(SharedSecret, SharedSecret)>,
+}
+
+impl Zeroize for UnsentChain {
+
+    /// Wipes the root key, the private key and the header keys.
+    fn zeroize(&mut self) {
+        self.root_key.zeroize();
+        self.dh_sending.zeroize();
+        if let Some((sending, next_sending)) = self.header_keys.as_mut() {
+            sending.zeroize();
+            next_sending.zeroize();
+        }
+    }
+}
+
+/// The receiving half of a DH ratchet step, derived by [`Ratchet::receiving_step`] before it is performed.
+struct ReceivingStep {
+    /// The new ratchet public key of the peer.
+    dh_receiving: PublicKey,
+
+    /// The root key after the step.
+    root_key: SharedSecret,
+
+    /// The chain key of the new receiving chain.
+    receiving_chain_key: SharedSecret,
+
+    /// The next receiving header key, if the ratchet uses header encryption.
+    next_header_key: Option<SharedSecret>,
+}
+
+/// What decrypting a message changes in a [`Ratchet`], staged by [`Ratchet::stage_message`] and kept by
+/// [`Ratchet::commit_message`] once the message authenticates. A message that does not leaves the state as it was.
+enum StagedMessage {
+    /// The message was decrypted with the stored skipped key of this sender key and message number, which is dropped.
+    Skipped((PublicKey, u64)),
+
+    /// The message was decrypted on a receiving chain, the current one or a new one.
+    Chain {
+        /// The keys skipped at the end of the receiving chain that a DH ratchet step closes.
+        closed_chain: Vec<((PublicKey, u64), SharedSecret)>,
+        /// The DH ratchet step, if the message starts a new receiving chain.
+        step: Option<ReceivingStep>,
+        /// The keys skipped on the chain of the message, before it.
+        skipped: Vec<((PublicKey, u64), SharedSecret)>,
+        /// The receiving chain key after the message.
+        receiving_chain_key: SharedSecret,
+        /// The number of messages received on the chain after the message.
+        n_messages_received: u64,
+    },
 }
 
-impl TryFrom<&[u8; 48]> for Header {
+impl TryFrom<&[u8; Header::LENGTH]> for Header {
 
     type Error = RatchetError;
 
@@ -153,26 +368,194 @@ impl TryFrom<&[u8; 48]> for Header {
     /// # Errors
     ///
     /// * [`RatchetError::InvalidHeaderLength`] - Returned if `value` does not match the expected length of [`Header`] ([`Header::LENGTH`]).
-    fn try_from(value: &[u8; 48]) -> Result<Self, Self::Error> {
+    fn try_from(value: &[u8; Header::LENGTH]) -> Result<Self, Self::Error> {
         if value.len() != Self::LENGTH {
             return Err(RatchetError::InvalidHeaderLength(value.len()))
         }
-        let dhs = PublicKey::from(array_ref!(value, 0, CURVE25519_PUBLIC_LENGTH));
+        let dhs = PublicKey::from(array_ref!(value, 0, DH_PUBLIC_LENGTH));
         let pn = u64::from_le_bytes(
             *array_ref!(
                 value,
-                CURVE25519_PUBLIC_LENGTH,
+                DH_PUBLIC_LENGTH,
                 size_of::<u64>()
             )
         );
         let ns = u64::from_le_bytes(
             *array_ref!(
                 value,
-                CURVE25519_PUBLIC_LENGTH + size_of::<u64>(),
+                DH_PUBLIC_LENGTH + size_of::<u64>(),
                 size_of::<u64>()
             )
         );
-        Ok(Self { dhs, pn, ns })
+        Ok(Self { dhs, pn: pn & Self::COUNTER_MASK, ns, flags: (pn >> 56) as u8 })
+    }
+}
+
+/// The chain key derivation (`KDF_CK`) used by a [`Ratchet`]. Both parties of a session must use the same one.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ChainKdf {
+    /// HMAC-SHA256 keyed with the chain key, over the constant `0x01` for the message key
+    /// and `0x02` for the next chain key, as recommended by the Double Ratchet specification.
+    #[default]
+    Hmac,
+
+    /// Two HKDF expansions of the chain key, as derived by sessions created before [`ChainKdf::Hmac`].
+    LegacyHkdf,
+}
+
+impl ChainKdf {
+    /// The name of the cipher suite of a [`Ratchet`] deriving message keys with this function and encrypting with `aead_suite`.
+    fn cipher_suite(&self, aead_suite: AeadSuite) -> String {
+        let kdf = match self {
+            ChainKdf::Hmac => "HMAC_SHA256",
+            ChainKdf::LegacyHkdf => "HKDF_SHA256",
+        };
+        format!("{}_{}_{}", Curve::ACTIVE, aead_suite.name(), kdf)
+    }
+}
+
+/// The padding of plaintexts before encryption by a [`Ratchet`], which hides their exact length from the relay:
+/// the AEAD ciphertext is as long as the plaintext plus a constant.
+///
+/// Padded messages are marked in their header, and both parties of a session must use padding or not:
+/// a message of the other kind fails with [`RatchetError::PaddingMismatch`]. The bucket sizes may differ.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Padding {
+    /// Plaintexts are encrypted as they are.
+    #[default]
+    None,
+
+    /// Plaintexts are padded ISO/IEC 7816-4 style, a `0x80` byte followed by zeros, up to the next multiple
+    /// of the given number of bytes. A plaintext filling a bucket takes the whole next one, and the last
+    /// bucket ends at [`MAX_PLAINTEXT_LENGTH`].
+    Buckets(usize),
+}
+
+impl Padding {
+    /// The header flags of a message encrypted with this padding.
+    fn header_flags(&self) -> u8 {
+        match self {
+            Padding::None => 0,
+            Padding::Buckets(_) => Header::FLAG_PADDED,
+        }
+    }
+
+    /// The byte size of a plaintext of `length` bytes once padded.
+    ///
+    /// The last bucket is cut short at [`MAX_PLAINTEXT_LENGTH`], so that padding keeps a message within the
+    /// frame limit of the server, unless the plaintext leaves no room for the padding byte.
+    fn padded_length(&self, length: usize) -> usize {
+        match *self {
+            Padding::None => length,
+            Padding::Buckets(bucket) => ((length / bucket + 1) * bucket).min(MAX_PLAINTEXT_LENGTH).max(length + 1),
+        }
+    }
+
+    /// Returns `plaintext` padded to its bucket, or a copy of it without padding.
+    fn pad(&self, plaintext: &[u8]) -> Vec<u8> {
+        let mut padded = plaintext.to_vec();
+        if let Padding::Buckets(_) = self {
+            padded.push(0x80);
+            padded.resize(self.padded_length(plaintext.len()), 0);
+        }
+        padded
+    }
+
+    /// Strips the ISO/IEC 7816-4 padding of a decrypted plaintext in place.
+    ///
+    /// # Errors
+    ///
+    /// * [`RatchetError::InvalidPadding`] - Returned if the plaintext does not end with `0x80` and zeros.
+    fn unpad(plaintext: &mut Vec<u8>) -> Result<(), RatchetError> {
+        let end = plaintext.iter().rposition(|b| *b != 0).ok_or(RatchetError::InvalidPadding)?;
+        if plaintext[end] != 0x80 {
+            return Err(RatchetError::InvalidPadding);
+        }
+        plaintext.truncate(end);
+        Ok(())
+    }
+}
+
+/// The header values a message was bound to, returned by [`Ratchet::encrypt_with_meta`] and [`Ratchet::decrypt_with_meta`].
+///
+/// Nothing in it is secret: the counters and ratchet key travel in the message header.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MessageMeta {
+    /// The hex SHA-256 fingerprint of the sender's ratchet public key, which identifies the DH ratchet generation.
+    pub ratchet_key: String,
+
+    /// The number of messages in the sender's previous sending chain (`pn` of the header).
+    pub previous_chain_length: u64,
+
+    /// The number of the message in the sender's current sending chain (`ns` of the header).
+    pub message_number: u64,
+
+    /// Whether the header was encrypted.
+    pub header_encrypted: bool,
+
+    /// The cipher suite the message was encrypted with.
+    pub cipher_suite: String,
+}
+
+/// The position of a [`Ratchet`] in its chains, returned by [`Ratchet::state_snapshot`] to log or compare
+/// the progress of both parties of a session.
+///
+/// Nothing in it is secret: the sending key is public and the counters travel in the message headers.
+/// The root, chain, message and header keys are never part of it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RatchetSnapshot {
+    /// The current sending ratchet public key. After a DH ratchet step it is the one the peer last saw,
+    /// until the next message starts a sending chain with a new one.
+    pub sending_key: PublicKey,
+
+    /// The number of messages sent in the current sending chain, the `ns` of the next message.
+    pub messages_sent: u64,
+
+    /// The number of messages received in the current receiving chain.
+    pub messages_received: u64,
+
+    /// The number of messages sent in the previous sending chain, the `pn` of the next message.
+    pub previous_chain_length: u64,
+}
+
+/// The tunable limits and options of a [`Ratchet`],
+/// see [`Ratchet::init_alice_with_config`] and [`Ratchet::init_bob_with_config`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RatchetConfig {
+    /// The maximum number of message keys skipped in a single receiving chain, [`MAX_SKIPS`] by default.
+    pub max_skips: u64,
+
+    /// The maximum number of skipped message keys stored across all chains, [`MAX_SKIPPED_KEYS`] by default.
+    pub max_skipped_keys: usize,
+
+    /// The derivation of message keys from chain keys, [`ChainKdf::Hmac`] by default.
+    pub chain_kdf: ChainKdf,
+
+    /// The AEAD suite of messages and headers, [`AeadSuite::Aes256Gcm`] by default.
+    pub aead_suite: AeadSuite,
+
+    /// The maximum byte size of an encrypted plaintext, at most and by default [`MAX_PLAINTEXT_LENGTH`].
+    pub max_plaintext_length: usize,
+
+    /// The derivation of root keys, [`Conformance::Native`] by default.
+    /// It must be the [`crate::utils::InitialMessage::conformance`] of the session.
+    pub conformance: Conformance,
+
+    /// The padding of plaintexts, [`Padding::None`] by default.
+    pub padding: Padding,
+}
+
+impl Default for RatchetConfig {
+    fn default() -> Self {
+        Self {
+            max_skips: MAX_SKIPS,
+            max_skipped_keys: MAX_SKIPPED_KEYS,
+            chain_kdf: ChainKdf::default(),
+            aead_suite: AeadSuite::default(),
+            max_plaintext_length: MAX_PLAINTEXT_LENGTH,
+            conformance: Conformance::default(),
+            padding: Padding::default(),
+        }
     }
 }
 
@@ -211,11 +594,58 @@ pub struct Ratchet {
     /// A map of skipped message keys indexed by (sender public key, message number).
     /// For more information, see [`PublicKey`] and [`SharedSecret`].
     mk_skipped: HashMap<(PublicKey, u64), SharedSecret>,
+
+    /// The keys of `mk_skipped` in insertion order, oldest first.
+    mk_skipped_order: VecDeque<(PublicKey, u64)>,
+
+    /// For each receiving chain with evicted keys, the message number below which keys are no longer stored.
+    mk_evicted: HashMap<PublicKey, u64>,
+
+    /// The header keys, if the ratchet encrypts headers.
+    /// For more information, see [`HeaderKeys`].
+    header_keys: Option<HeaderKeys>,
+
+    /// The maximum number of message keys skipped in a single receiving chain, [`MAX_SKIPS`] by default.
+    max_skips: u64,
+
+    /// The maximum number of skipped message keys stored across all chains, [`MAX_SKIPPED_KEYS`] by default.
+    max_skipped_keys: usize,
+
+    /// The derivation of message keys from chain keys, [`ChainKdf::Hmac`] by default.
+    chain_kdf: ChainKdf,
+
+    /// The AEAD suite of messages and headers, negotiated during the X3DH handshake, [`AeadSuite::Aes256Gcm`] by default.
+    aead_suite: AeadSuite,
+
+    /// The maximum byte size of an encrypted plaintext, at most and by default [`MAX_PLAINTEXT_LENGTH`].
+    max_plaintext_length: usize,
+
+    /// The sending state before the first sending chain, while nothing was sent on it.
+    /// For more information, see [`UnsentChain`].
+    unsent_chain: Option<UnsentChain>,
+
+    /// The key pair of a rotation requested with [`Ratchet::force_rekey`], used on the next encryption.
+    pending_rekey: Option<RatchetKeyPair>,
+
+    /// The derivation of root keys, [`Conformance::Native`] by default, see [`crate::interop`].
+    conformance: Conformance,
+
+    /// The padding of plaintexts, [`Padding::None`] by default.
+    padding: Padding,
 }
 
 
 impl Ratchet {
 
+    /// The version of the serialized state produced by [`Ratchet::to_bytes`].
+    /// Version 1 states predate [`ChainKdf`] and are restored with [`ChainKdf::LegacyHkdf`],
+    /// versions 1 and 2 predate the plaintext limit and are restored with [`MAX_PLAINTEXT_LENGTH`],
+    /// versions 1 to 3 predate [`Ratchet::force_rekey`] and are restored without an unsent chain or a pending rotation,
+    /// versions 1 to 4 predate [`AeadSuite`] and are restored with [`AeadSuite::Aes256Gcm`],
+    /// versions 1 to 5 predate [`Conformance`] and are restored with [`Conformance::Native`],
+    /// versions 1 to 6 predate [`Padding`] and are restored with [`Padding::None`].
+    const STATE_VERSION: u8 = 7;
+
     /// Initializes the ratchet state for Alice (the initiator).
     ///
     /// # Arguments
@@ -226,18 +656,34 @@ impl Ratchet {
     /// # Returns
     ///
     /// * [`Ratchet`] - A [`Ratchet`] instance with sending and receiving chain keys set.
+    ///   The receiving chain is the initial chain of Bob, see [`Ratchet::init_bob`].
     pub fn init_alice(shared_secret: SharedSecret, bob_pk: PublicKey) -> Self {
-        // TODO: make sure that also bob start the conversation
-        let dh_sending = RatchetKeyPair::new();
+        Self::init_alice_as(shared_secret, bob_pk, Conformance::Native)
+    }
+
+    /// Initializes the ratchet state for Alice like [`Ratchet::init_alice`], deriving her first sending chain
+    /// with the root key derivation of `conformance`.
+    fn init_alice_as(shared_secret: SharedSecret, bob_pk: PublicKey, conformance: Conformance) -> Self {
+        Self::init_alice_from(shared_secret, bob_pk, RatchetKeyPair::new(), conformance)
+    }
+
+    /// Initializes the ratchet state for Alice like [`Ratchet::init_alice_as`], with `dh_sending` as her first
+    /// ratchet key pair instead of a new one, so that known-answer tests can replay her side of a conversation.
+    pub(crate) fn init_alice_from(shared_secret: SharedSecret, bob_pk: PublicKey, dh_sending: RatchetKeyPair, conformance: Conformance) -> Self {
         let dh = dh_sending.diffie_hellman(&bob_pk);
         let dh_receiving = Some(bob_pk);
-        let (root_key, sending_chain_key) = hkdf_rk(shared_secret.clone(), dh).unwrap();
-        let (receiving_chain_key, _) = hkdf_ck(shared_secret).unwrap();
+        let (root_key, sending_chain_key) = hkdf_rk(conformance, shared_secret.clone(), dh).unwrap();
+        let receiving_chain_key = initial_chain_key(&shared_secret).unwrap();
 
         let n_messages_sent: u64 = 0;
         let n_messages_received: u64 = 0;
         let pn: u64 = 0;
         let mk_skipped = HashMap::new();
+        let unsent_chain = Some(UnsentChain {
+            root_key: shared_secret.clone(),
+            dh_sending: dh_sending.clone(),
+            header_keys: None,
+        });
         Self {
             dh_sending,
             dh_receiving,
@@ -247,12 +693,99 @@ impl Ratchet {
             n_messages_sent,
             n_messages_received,
             pn,
-            mk_skipped
+            mk_skipped,
+            header_keys: None,
+            max_skips: MAX_SKIPS,
+            mk_skipped_order: VecDeque::new(),
+            mk_evicted: HashMap::new(),
+            max_skipped_keys: MAX_SKIPPED_KEYS,
+            chain_kdf: ChainKdf::default(),
+            aead_suite: AeadSuite::default(),
+            max_plaintext_length: MAX_PLAINTEXT_LENGTH,
+            unsent_chain,
+            pending_rekey: None,
+            conformance,
+            padding: Padding::None,
+        }
+    }
+
+    /// Initializes the ratchet state for Alice (the initiator) with the given limits and options.
+    ///
+    /// # Arguments
+    ///
+    /// * `shared_secret` – The pre-shared secret derived during X3DH or initial key exchange.
+    /// * `bob_pk` – Bob's initial public key.
+    /// * `config` – The limits and options of the ratchet.
+    ///
+    /// # Returns
+    ///
+    /// * [`Ratchet`] - A [`Ratchet`] instance with sending and receiving chain keys set.
+    pub fn init_alice_with_config(shared_secret: SharedSecret, bob_pk: PublicKey, config: RatchetConfig) -> Self {
+        Self::init_alice_as(shared_secret, bob_pk, config.conformance).with_config(config)
+    }
+
+    /// Initializes the ratchet state for Alice (the initiator) with header encryption.
+    ///
+    /// Both parties must enable header encryption, see [`Ratchet::init_bob_with_header_encryption`].
+    ///
+    /// # Arguments
+    ///
+    /// * `shared_secret` – The pre-shared secret derived during X3DH or initial key exchange.
+    /// * `bob_pk` – Bob's initial public key.
+    ///
+    /// # Returns
+    ///
+    /// * [`Ratchet`] - A [`Ratchet`] instance that encrypts the header of every message.
+    pub fn init_alice_with_header_encryption(shared_secret: SharedSecret, bob_pk: PublicKey) -> Self {
+        let (alice_hk, bob_nhk, bob_hk) = hkdf_header_keys(&shared_secret).unwrap();
+        let dh_sending = RatchetKeyPair::new();
+        let dh = dh_sending.diffie_hellman(&bob_pk);
+        let (root_key, sending_chain_key, next_sending) = hkdf_rk_he(shared_secret.clone(), dh).unwrap();
+        let receiving_chain_key = initial_chain_key(&shared_secret).unwrap();
+        let unsent_chain = Some(UnsentChain {
+            root_key: shared_secret.clone(),
+            dh_sending: dh_sending.clone(),
+            header_keys: Some((alice_hk.clone(), alice_hk.clone())),
+        });
+
+        Self {
+            dh_sending,
+            dh_receiving: Some(bob_pk),
+            root_key,
+            sending_chain_key: Some(sending_chain_key),
+            receiving_chain_key: Some(receiving_chain_key),
+            n_messages_sent: 0,
+            n_messages_received: 0,
+            pn: 0,
+            mk_skipped: HashMap::new(),
+            header_keys: Some(HeaderKeys {
+                sending: alice_hk,
+                receiving: Some(bob_hk),
+                next_sending,
+                next_receiving: bob_nhk,
+                skipped: HashMap::new(),
+            }),
+            max_skips: MAX_SKIPS,
+            mk_skipped_order: VecDeque::new(),
+            mk_evicted: HashMap::new(),
+            max_skipped_keys: MAX_SKIPPED_KEYS,
+            chain_kdf: ChainKdf::default(),
+            aead_suite: AeadSuite::default(),
+            max_plaintext_length: MAX_PLAINTEXT_LENGTH,
+            unsent_chain,
+            pending_rekey: None,
+            conformance: Conformance::Native,
+            padding: Padding::None,
         }
     }
 
     /// Initializes the ratchet state for Bob (the receiver).
     ///
+    /// Bob starts with his signed pre-key as ratchet key pair, which Alice already knows, and with an initial
+    /// sending chain derived from the shared secret, so that he can send before hearing from Alice.
+    /// His messages on that chain carry his ratchet public key like any other, and the first message he
+    /// receives from Alice performs his first DH ratchet step, which replaces the initial chain.
+    ///
     /// # Arguments
     ///
     /// * `shared_secret` – The pre-shared secret derived during X3DH or initial key exchange.
@@ -265,7 +798,7 @@ impl Ratchet {
         let dh_sending = dk_sending;
         let dh_receiving = None;
         let root_key = shared_secret.clone();
-        let (sending_chain_key, _) = hkdf_ck(shared_secret).unwrap();
+        let sending_chain_key = initial_chain_key(&shared_secret).unwrap();
         let receiving_chain_key = None;
         let n_messages_sent: u64 = 0;
         let n_messages_received: u64 = 0;
@@ -281,318 +814,3069 @@ impl Ratchet {
             n_messages_sent,
             n_messages_received,
             pn,
-            mk_skipped
+            mk_skipped,
+            header_keys: None,
+            max_skips: MAX_SKIPS,
+            mk_skipped_order: VecDeque::new(),
+            mk_evicted: HashMap::new(),
+            max_skipped_keys: MAX_SKIPPED_KEYS,
+            chain_kdf: ChainKdf::default(),
+            aead_suite: AeadSuite::default(),
+            max_plaintext_length: MAX_PLAINTEXT_LENGTH,
+            unsent_chain: None,
+            pending_rekey: None,
+            conformance: Conformance::Native,
+            padding: Padding::None,
         }
     }
 
-    /// Encrypts a message using the current sending chain state.
+    /// Initializes the ratchet state for Bob (the receiver) with the given limits and options.
     ///
     /// # Arguments
     ///
-    /// * `plaintext` – The message to encrypt.
-    /// * `aad` – Associated data to authenticate (but not encrypt).
+    /// * `shared_secret` – The pre-shared secret derived during X3DH or initial key exchange.
+    /// * `dk_sending` – Bob's initial Diffie-Hellman key pair.
+    /// * `config` – The limits and options of the ratchet.
     ///
     /// # Returns
     ///
-    /// * `String` - A base64-encoded ciphertext string.
-    /// 
-    /// # Errors
-    /// 
-    /// * [`X3DHError::AesGcmInvalidLength`] - Returned if AES-GCM decryption fails due to an unexpected ciphertext length.
-    pub fn encrypt(&mut self, plaintext: &[u8], aad: &[u8]) -> Result<String, RatchetError> {
-        let (ck, mk) = hkdf_ck(self.sending_chain_key.clone().unwrap())?;
-        self.sending_chain_key = Some(ck);
-        let h = Header::new(self.dh_sending.public_key.clone(), self.pn, self.n_messages_sent);
-        self.n_messages_sent += 1;
-        let mk = EncryptionKey::from(mk);
-        // Generate a new aad prepending the header to the original aad
-        let mut new_aad = vec![];
-        new_aad.extend_from_slice(&h.to_bytes());
-        new_aad.extend_from_slice(&aad);
-        Ok(mk.encrypt(plaintext, &new_aad)?)
+    /// * [`Ratchet`] - A [`Ratchet`] instance with a sending chain key but without a receiving key yet.
+    pub fn init_bob_with_config(shared_secret: SharedSecret, dk_sending: RatchetKeyPair, config: RatchetConfig) -> Self {
+        Self::init_bob(shared_secret, dk_sending).with_config(config)
     }
 
-    /// Decrypts a received message, performing ratchet step if necessary.
+    /// Initializes the ratchet state for Bob (the receiver) with header encryption.
+    ///
+    /// Both parties must enable header encryption, see [`Ratchet::init_alice_with_header_encryption`].
     ///
     /// # Arguments
     ///
-    /// * `ciphertext` – The base64-encoded encrypted message.
+    /// * `shared_secret` – The pre-shared secret derived during X3DH or initial key exchange.
+    /// * `dk_sending` – Bob's initial Diffie-Hellman key pair.
     ///
     /// # Returns
     ///
-    /// * `Vec<u8>` - The decrypted plaintext message.
-    /// 
-    /// # Errors
-    /// 
-    /// * [`RatchetError::ConversionError`] - Returned if Base64 decoding of the ciphertext or conversion to `AssociatedData` fails.
-    /// * [`RatchetError::InvalidHeaderLength`] - Returned if `value` does not match the expected length of [`Header`] ([`Header::LENGTH`]).
-    /// * [`X3DHError::AesGcmInvalidLength`] - Returned if AES-GCM decryption fails due to an unexpected ciphertext length.
-    /// * [`RatchetError::MaxSkipsExceeded`] - Returned if the number of skipped messages exceeds the allowed maximum when attempting to handle out-of-order messages or advance the ratchet state.
-    pub fn decrypt(&mut self, ciphertext: String) -> Result<Vec<u8>, RatchetError> {
-        let ciphertext = general_purpose::STANDARD.decode(ciphertext).map_err(|_| {
-            ConversionError
-        })?;
-        let nonce = *array_ref!(&ciphertext, 0, AES256_NONCE_LENGTH);
-        let header = Header::try_from(array_ref!(&ciphertext, AES256_NONCE_LENGTH, Header::LENGTH))?;
-        let aad = AssociatedData::try_from(array_ref!(
-            &ciphertext,
-            AES256_NONCE_LENGTH + Header::LENGTH,
-            AssociatedData::SIZE
-        )).map_err(|_| ConversionError)?;
-
-        let ciphertext = &ciphertext[AES256_NONCE_LENGTH + Header::LENGTH + AssociatedData::SIZE..];
-        let plaintext = self.try_skipped_message_keys(header.clone(), ciphertext, aad.clone(), &nonce)?;
-        if plaintext.is_some() {
-            return Ok(plaintext.unwrap());
-        }
-        if self.sending_chain_key.is_none() || Some(header.dhs.clone()) != self.dh_receiving.clone() {
-            self.skip_message_keys(header.pn)?;
-            self.dh_ratchet(header.clone())?;
-        }
-        self.skip_message_keys(header.ns)?;
-        let (ckr, mk) = hkdf_ck(self.receiving_chain_key.clone().unwrap())?;
-        self.receiving_chain_key = Some(ckr);
-        let mk = DecryptionKey::from(mk);
-        self.n_messages_received += 1;
-        let mut new_aad = vec![];
-        new_aad.extend_from_slice(&header.to_bytes());
-        new_aad.extend_from_slice(&aad.clone().to_bytes());
-        Ok(mk.decrypt(ciphertext, &nonce, &new_aad)?)
+    /// * [`Ratchet`] - A [`Ratchet`] instance that encrypts the header of every message.
+    pub fn init_bob_with_header_encryption(shared_secret: SharedSecret, dk_sending: RatchetKeyPair) -> Self {
+        let (alice_hk, bob_nhk, bob_hk) = hkdf_header_keys(&shared_secret).unwrap();
+        let mut ratchet = Self::init_bob(shared_secret, dk_sending);
+        ratchet.header_keys = Some(HeaderKeys {
+            sending: bob_hk,
+            receiving: None,
+            next_sending: bob_nhk,
+            next_receiving: alice_hk,
+            skipped: HashMap::new(),
+        });
+        ratchet
+    }
 
+    /// Applies all the limits and options of `config`.
+    ///
+    /// The conformance has no public setter: Alice derives her first sending chain with it at initialization.
+    fn with_config(mut self, config: RatchetConfig) -> Self {
+        self.conformance = config.conformance;
+        self.with_max_skips(config.max_skips)
+            .with_max_skipped_keys(config.max_skipped_keys)
+            .with_chain_kdf(config.chain_kdf)
+            .with_aead_suite(config.aead_suite)
+            .with_max_plaintext_length(config.max_plaintext_length)
+            .with_padding(config.padding)
     }
 
-    /// Attempts to decrypt the message using any skipped keys.
-    /// This function checks whether a message key corresponding to the given header
-    /// and message number has been stored in the `mk_skipped` map. If found, it uses
-    /// that key to decrypt the message. This allows the receiver to handle out-of-order
-    /// messages or skipped messages without losing forward secrecy. 
-    /// 
+    /// Sets the maximum number of message keys that may be skipped in a single receiving chain.
+    ///
+    /// A small window bounds the work and memory an out-of-order or forged message can cause,
+    /// while a large one tolerates more loss on high-latency links.
+    ///
     /// # Arguments
     ///
-    /// * `header` - The message header containing the sender's public key and message number.
-    /// * `ciphertext` - The encrypted message payload (excluding nonce, header, and AAD).
-    /// * `aad` - The associated data used to authenticate the message.
-    /// * `nonce` - The nonce used during encryption.
+    /// * `max_skips` - The maximum number of skipped message keys per chain.
     ///
     /// # Returns
     ///
-    /// * `Ok(Some(plaintext))` - If a matching skipped message key was found and decryption succeeded.
-    /// * `Ok(None)` - If no matching skipped message key was found.
-    /// 
-    /// # Errors
-    /// 
-    /// * [`X3DHError::AesGcmInvalidLength`] - Returned if AES-GCM decryption fails due to an unexpected ciphertext length. 
-    fn try_skipped_message_keys(
-        &mut self,
-        header: Header,
-        ciphertext: &[u8],
-        aad: AssociatedData,
-        nonce: &[u8; AES256_NONCE_LENGTH]
-    ) -> Result<Option<Vec<u8>>, RatchetError> {
-        if self.mk_skipped.contains_key(&(header.dhs.clone(), header.ns)) {
-            let mk = self.mk_skipped.get(&(header.dhs.clone(), header.ns)).unwrap();
-            let mk = DecryptionKey::from(mk.clone());
-            self.mk_skipped.remove(&(header.dhs.clone(), header.ns));
-            let mut tmp = vec![];
-            tmp.extend_from_slice(&header.to_bytes());
-            tmp.extend_from_slice(&aad.to_bytes());
-            Ok(Some(mk.decrypt(ciphertext, nonce, &tmp)?))
-        } else {
-            Ok(None)
-        }
+    /// * [`Ratchet`] - The ratchet with the new limit.
+    pub fn with_max_skips(mut self, max_skips: u64) -> Self {
+        self.max_skips = max_skips;
+        self
+    }
+
+    /// Returns the maximum number of message keys that may be skipped in a single receiving chain.
+    pub fn max_skips(&self) -> u64 {
+        self.max_skips
     }
 
-    /// Skips message keys up to a given message number and stores them.
+    /// Sets the maximum number of skipped message keys stored across all receiving chains.
+    ///
+    /// Beyond the limit the oldest keys are evicted and zeroized: a late message for them fails with
+    /// [`RatchetError::SkippedKeyEvicted`].
     ///
     /// # Arguments
     ///
-    /// * `until` – The message number to skip up to (exclusive).
-    fn skip_message_keys(&mut self, until: u64) -> Result<(), RatchetError> {
-        if self.n_messages_received + MAX_SKIPS < until {
-            return Err(RatchetError::MaxSkipsExceeded);
-        } else if self.receiving_chain_key.is_some() {
-            while self.n_messages_received < until {
-                let (ck, mk) = hkdf_ck(self.receiving_chain_key.clone().unwrap())?;
-                self.receiving_chain_key = Some(ck);
-                let mk = SharedSecret::from(mk);
-                self.mk_skipped.insert(
-                    (self.dh_receiving.clone().unwrap(), self.n_messages_sent),
-                    mk,
-                );
-
-                self.n_messages_sent += 1;
-            }
-        }
-        Ok(())
+    /// * `max_skipped_keys` - The maximum number of stored skipped message keys.
+    ///
+    /// # Returns
+    ///
+    /// * [`Ratchet`] - The ratchet with the new limit.
+    pub fn with_max_skipped_keys(mut self, max_skipped_keys: usize) -> Self {
+        self.max_skipped_keys = max_skipped_keys;
+        self.evict_skipped_keys();
+        self
     }
 
-    /// Performs a DH ratchet step: updates keys and state for a new incoming public key.
+    /// Returns the maximum number of skipped message keys stored across all receiving chains.
+    pub fn max_skipped_keys(&self) -> usize {
+        self.max_skipped_keys
+    }
+
+    /// Sets the derivation of message keys from chain keys.
+    ///
+    /// Both parties must use the same derivation, so this is set right after initialization, before any message
+    /// is sent or received. The chain keys derived from the shared secret at initialization are not affected.
     ///
     /// # Arguments
     ///
-    /// * `header` – The header containing the new public key.
-    fn dh_ratchet(&mut self, header: Header) -> Result<(), RatchetError> {
-        self.pn = self.n_messages_sent;
-        self.n_messages_sent = 0;
-        self.n_messages_received = 0;
-        self.dh_receiving = Some(header.dhs);
-        let (rk, ckr) = hkdf_rk(
-            self.root_key.clone(),
-            self.dh_sending.diffie_hellman(&self.dh_receiving.clone().unwrap())
-        )?;
-
-        self.root_key = rk;
-        self.receiving_chain_key = Some(ckr);
-        self.dh_sending = RatchetKeyPair::new();
-        let (rk, cks) = hkdf_rk(
-            self.root_key.clone(),
-            self.dh_sending.diffie_hellman(&self.dh_receiving.clone().unwrap())
-        )?;
-        self.root_key = rk;
-        self.sending_chain_key = Some(cks);
-        Ok(())
+    /// * `chain_kdf` - The chain key derivation, [`ChainKdf::LegacyHkdf`] to keep talking to older sessions.
+    ///
+    /// # Returns
+    ///
+    /// * [`Ratchet`] - The ratchet with the new derivation.
+    pub fn with_chain_kdf(mut self, chain_kdf: ChainKdf) -> Self {
+        self.chain_kdf = chain_kdf;
+        self
     }
-}
 
-/// Derives a new root key and chain key from the current root key and a Diffie-Hellman shared secret.
-/// This function implements the `HKDF(rk, dh)` step from the Double Ratchet algorithm, using the current
-/// root key `rk` and a new shared secret `dh` as inputs. It applies HKDF with SHA-256 to produce two
-/// new secrets: a derived root key and a new receiving chain key.
-///
-/// # Arguments
-///
-/// * `rk` - The current root key (a shared secret).
-/// * `dh` - The Diffie-Hellman shared secret between the new and previous public keys.
-///
-/// # Returns
-///
-/// * ([`SharedSecret`], [`SharedSecret`]) - A tuple `(new_root_key, receiving_chain_key)` derived from HKDF.
-///
-/// # Errors
-///
-/// * [`RatchetError::KeyDerivationError`] - If the HKDF expand step fails.
-fn hkdf_rk(
-    rk: SharedSecret,
-    dh: SharedSecret,
-) -> Result<(SharedSecret, SharedSecret), RatchetError> {
-    let info = b"RatchtetInfo";
-    // HKDF input key material = F || KM, where KM is an input byte sequence containing secret key material, and F is a byte sequence containing 32 0xFF bytes if curve is X25519, and 57 0xFF bytes if curve is X448. F is used for cryptographic domain separation with XEdDSA [2].
-    let mut dhs = vec![0xFFu8; 32];
-    dhs.extend_from_slice(rk.as_ref());
-    dhs.extend_from_slice(dh.as_ref());
+    /// Returns the derivation of message keys from chain keys.
+    pub fn chain_kdf(&self) -> ChainKdf {
+        self.chain_kdf
+    }
 
-    // Use the shared secret as the salt as per the X3DH spec.
-    let hk = Hkdf::<Sha256>::new(Some(rk.as_ref()), dhs.as_ref());
-    let mut okm = [0u8; 2 * AES256_SECRET_LENGTH];
-    // HKDF info = The info parameter from Section 2.1.
-    hk.expand(info, &mut okm)?;
+    /// Sets the AEAD suite messages and headers are encrypted with.
+    ///
+    /// Both parties must use the same suite, the one agreed on in the X3DH handshake, see [`crate::utils::InitialMessage::aead_suite`].
+    /// Like [`Ratchet::with_chain_kdf`], this is set right after initialization, before any message is sent or received.
+    ///
+    /// # Arguments
+    ///
+    /// * `aead_suite` - The AEAD suite of the session.
+    ///
+    /// # Returns
+    ///
+    /// * [`Ratchet`] - The ratchet with the new suite.
+    pub fn with_aead_suite(mut self, aead_suite: AeadSuite) -> Self {
+        self.aead_suite = aead_suite;
+        self
+    }
 
-    let shared_key1 = SharedSecret::from(*array_ref!(okm, 0, AES256_SECRET_LENGTH));
-    let shared_key2 =
-        SharedSecret::from(*array_ref!(okm, AES256_SECRET_LENGTH, AES256_SECRET_LENGTH));
-    Ok((shared_key1, shared_key2))
-}
+    /// Returns the AEAD suite messages and headers are encrypted with.
+    pub fn aead_suite(&self) -> AeadSuite {
+        self.aead_suite
+    }
 
-/// Derives a new chain key and message key from the current chain key using HKDF.
-/// This function applies HKDF with SHA-256 to derive two secrets from a single chain key:
-/// the next chain key and a message encryption key. This step is used for each message sent or received
-/// in the Double Ratchet protocol to ensure forward secrecy.
-///
-/// # Arguments
-///
-/// * `ck` - The current chain key, a [`SharedSecret`] used as input key material for HKDF.
-///
-/// # Returns
-///
-/// * ([`SharedSecret`], [`SharedSecret`]) - A tuple `(next_chain_key, message_key)` used to continue the ratchet chain and encrypt/decrypt a message.
-///
-/// # Errors
-///
-/// * [`RatchetError::KeyDerivationError`] - if HKDF expansion fails.
-fn hkdf_ck(
-    ck: SharedSecret,
-) -> Result<(SharedSecret, SharedSecret), RatchetError> {
-    // HKDF salt = A zero-filled byte sequence with length equal to the hash output length.
-    let hk = Hkdf::<Sha256>::new(None, ck.as_ref());
-    let mut chain_key = [0u8; AES256_SECRET_LENGTH];
-    let mut message_key = [0u8; AES256_SECRET_LENGTH];
-    // HKDF info = The info parameter from Section 2.1.
-    hk.expand(b"ChainKey", &mut chain_key)?;
-    hk.expand(b"MessageKey", &mut message_key)?;
+    /// Returns the derivation of root keys, set with [`RatchetConfig::conformance`].
+    pub fn conformance(&self) -> Conformance {
+        self.conformance
+    }
 
-    let chain_key = SharedSecret::from(*array_ref!(chain_key, 0, AES256_SECRET_LENGTH));
-    let message_key =
-        SharedSecret::from(*array_ref!(message_key, 0, AES256_SECRET_LENGTH));
-    Ok((chain_key, message_key))
-}
+    /// Sets the maximum byte size of a plaintext accepted by [`Ratchet::encrypt`].
+    ///
+    /// The limit is capped at [`MAX_PLAINTEXT_LENGTH`], which keeps every message within the frame limit of the server.
+    ///
+    /// # Arguments
+    ///
+    /// * `max_plaintext_length` - The maximum plaintext size in bytes.
+    ///
+    /// # Returns
+    ///
+    /// * [`Ratchet`] - The ratchet with the new limit.
+    pub fn with_max_plaintext_length(mut self, max_plaintext_length: usize) -> Self {
+        self.max_plaintext_length = max_plaintext_length.min(MAX_PLAINTEXT_LENGTH);
+        self
+    }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::utils::SharedSecret;
-    use aes_gcm::{KeyInit};
+    /// Returns the maximum byte size of a plaintext accepted by [`Ratchet::encrypt`].
+    pub fn max_plaintext_length(&self) -> usize {
+        self.max_plaintext_length
+    }
 
-    #[test]
-    fn test_ratchet() {
+    /// Sets the padding of plaintexts before encryption.
+    ///
+    /// Both parties must pad or not, so like [`Ratchet::with_chain_kdf`] this is set right after initialization.
+    /// The bucket size is kept between 1 and [`MAX_PLAINTEXT_LENGTH`]. A padded plaintext takes up to a bucket
+    /// more than [`Ratchet::max_plaintext_length`], but never more than [`MAX_PLAINTEXT_LENGTH`].
+    ///
+    /// # Arguments
+    ///
+    /// * `padding` - The padding of the session.
+    ///
+    /// # Returns
+    ///
+    /// * [`Ratchet`] - The ratchet with the new padding.
+    pub fn with_padding(mut self, padding: Padding) -> Self {
+        self.padding = match padding {
+            Padding::None => Padding::None,
+            Padding::Buckets(bucket) => Padding::Buckets(bucket.clamp(1, MAX_PLAINTEXT_LENGTH)),
+        };
+        self
+    }
 
-        // test 1: simple ratchet exchange
-        let bob_ratchet = RatchetKeyPair::new();
-        let sh = SharedSecret::from([0u8; 32]);
-        let mut alice = Ratchet::init_alice(sh.clone(), bob_ratchet.public_key.clone());
-        let mut bob = Ratchet::init_bob(sh, bob_ratchet.clone());
-        let plaintext = b"Hello, Bob!";
-        let aad = AssociatedData{
-            initiator_identity_key: bob_ratchet.public_key.clone(),
-            responder_identity_key: alice.dh_sending.public_key.clone(),
+    /// Returns the padding of plaintexts before encryption.
+    pub fn padding(&self) -> Padding {
+        self.padding
+    }
+
+    /// Returns the number of skipped message keys currently stored.
+    pub fn skipped_key_count(&self) -> usize {
+        self.mk_skipped.len()
+    }
+
+    /// Returns the chain and number of each skipped message key, oldest first, without the keys themselves.
+    pub fn skipped_keys(&self) -> impl Iterator<Item = (&PublicKey, u64)> + '_ {
+        self.mk_skipped_order.iter().map(|(dhs, n)| (dhs, *n))
+    }
+
+    /// Returns the number of messages sent in the current sending chain.
+    pub fn messages_sent(&self) -> u64 {
+        self.n_messages_sent
+    }
+
+    /// Returns the number of messages received in the current receiving chain.
+    pub fn messages_received(&self) -> u64 {
+        self.n_messages_received
+    }
+
+    /// Returns the number of messages sent in the previous sending chain.
+    pub fn previous_chain_length(&self) -> u64 {
+        self.pn
+    }
+
+    /// Returns the ratchet public key of the peer, the one of the current receiving chain.
+    ///
+    /// It changes with every DH ratchet step, and is `None` on the responder side until the first message arrives.
+    pub fn current_remote_key(&self) -> Option<&PublicKey> {
+        self.dh_receiving.as_ref()
+    }
+
+    /// Zeroizes and drops every skipped message key, for instance on logout, keeping the ratchet usable.
+    ///
+    /// The messages the keys were kept for can no longer be decrypted: they are rejected with
+    /// [`RatchetError::SkippedKeyEvicted`], like those of keys evicted beyond [`Ratchet::max_skipped_keys`].
+    pub fn clear_skipped_keys(&mut self) {
+        while self.evict_oldest_skipped_key() {}
+    }
+
+    /// Returns `true` if the ratchet encrypts message headers.
+    pub fn has_header_encryption(&self) -> bool {
+        self.header_keys.is_some()
+    }
+
+    /// Returns the current message counters and sending ratchet key, without any secret material.
+    ///
+    /// A rotation requested with [`Ratchet::force_rekey`] is not reflected until the next encryption performs it.
+    pub fn state_snapshot(&self) -> RatchetSnapshot {
+        RatchetSnapshot {
+            sending_key: self.dh_sending.public_key.clone(),
+            messages_sent: self.n_messages_sent,
+            messages_received: self.n_messages_received,
+            previous_chain_length: self.pn,
+        }
+    }
+
+    /// Rotates the sending ratchet key without waiting for a message of the peer, for instance after a suspected
+    /// compromise of the device.
+    ///
+    /// A fresh key pair is generated right away and the next encryption performs the sending half of a DH ratchet
+    /// step with it. The new root, chain and header keys depend on a Diffie-Hellman output that the previous state
+    /// cannot compute, so a copy of the state taken before the rotation derives none of the keys used after it.
+    /// The peer follows the rotation like any DH ratchet step, provided it has received a message of the current
+    /// sending chain, if there is one, and has not sent on a new ratchet key of its own that is still in flight.
+    ///
+    /// The previous root, sending chain and header keys stay in the state until then, and are gone from it once the
+    /// message that performs the rotation is encrypted.
+    ///
+    /// Before the first message of the peer there is no ratchet key to rotate against and the rotation waits for it.
+    /// A DH ratchet step taken before the next encryption rotates the key anyway and replaces the pending rotation.
+    pub fn force_rekey(&mut self) {
+        supersede(&mut self.pending_rekey, Some(RatchetKeyPair::new()));
+    }
+
+    /// Returns `true` if a rotation requested with [`Ratchet::force_rekey`] has not been performed yet.
+    pub fn rekey_pending(&self) -> bool {
+        self.pending_rekey.is_some()
+    }
+
+    /// Performs the sending half of the latest DH ratchet step if it is due, see [`Ratchet::dh_ratchet`],
+    /// and the rotation requested with [`Ratchet::force_rekey`], once a ratchet key of the peer is known.
+    fn apply_pending_rekey(&mut self) -> Result<(), RatchetError> {
+        if self.dh_receiving.is_none() {
+            return Ok(());
+        }
+        if self.sending_chain_key.is_none() {
+            // Nothing was sent since the DH ratchet step, so the new key pair is used right away
+            let dh_sending = self.pending_rekey.take().unwrap_or_else(RatchetKeyPair::new);
+            return self.sending_half(dh_sending);
+        }
+        let Some(dh_sending) = self.pending_rekey.take() else { return Ok(()) };
+        // Replacing a chain nothing was sent on keeps `pn` pointing at the chain the peer knows
+        self.restore_unsent_chain();
+        let unsent_chain = UnsentChain {
+            root_key: self.root_key.clone(),
+            dh_sending: self.dh_sending.clone(),
+            header_keys: self.header_keys.as_ref().map(|keys| (keys.sending.clone(), keys.next_sending.clone())),
         };
-        let ciphertext = alice.encrypt(plaintext, &aad.to_bytes()).unwrap();
-        let decrypted = match bob.decrypt(ciphertext) {
-            Ok(dec) => dec,
-            Err(e) => {
-                panic!("Decryption failed: {:?}", e);
+        self.sending_half(dh_sending)?;
+        supersede(&mut self.unsent_chain, Some(unsent_chain));
+        Ok(())
+    }
+
+    /// Serializes the whole ratchet state, including the skipped message keys.
+    ///
+    /// The encoding is deterministic: skipped keys are written in insertion order, which drives their eviction,
+    /// followed by the header keys if the ratchet uses header encryption, and by the state kept for [`Ratchet::force_rekey`].
+    /// The result contains secret key material and must be encrypted before being stored.
+    ///
+    /// # Returns
+    ///
+    /// * `Vec<u8>` - The serialized state, to be restored with [`Ratchet::from_bytes`].
+    pub fn to_bytes(&self) -> Vec<u8> {
+        fn put_optional<const N: usize>(bytes: &mut Vec<u8>, value: Option<&[u8; N]>) {
+            match value {
+                Some(value) => {
+                    bytes.push(1);
+                    bytes.extend_from_slice(value);
+                }
+                None => bytes.push(0),
+            }
+        }
+
+        let mut bytes = vec![Self::STATE_VERSION];
+        bytes.extend_from_slice(self.dh_sending.private_key.as_ref());
+        bytes.extend_from_slice(self.dh_sending.public_key.as_ref());
+        put_optional(&mut bytes, self.dh_receiving.as_ref().map(|k| k.as_ref()));
+        bytes.extend_from_slice(self.root_key.as_ref());
+        put_optional(&mut bytes, self.sending_chain_key.as_ref().map(|k| k.as_ref()));
+        put_optional(&mut bytes, self.receiving_chain_key.as_ref().map(|k| k.as_ref()));
+        bytes.extend_from_slice(&self.n_messages_sent.to_le_bytes());
+        bytes.extend_from_slice(&self.n_messages_received.to_le_bytes());
+        bytes.extend_from_slice(&self.pn.to_le_bytes());
+        bytes.extend_from_slice(&self.max_skips.to_le_bytes());
+        bytes.extend_from_slice(&(self.max_skipped_keys as u64).to_le_bytes());
+
+        bytes.extend_from_slice(&(self.mk_skipped_order.len() as u64).to_le_bytes());
+        for (pk, n) in &self.mk_skipped_order {
+            bytes.extend_from_slice(pk.as_ref());
+            bytes.extend_from_slice(&n.to_le_bytes());
+            bytes.extend_from_slice(self.mk_skipped[&(pk.clone(), *n)].as_ref());
+        }
+        let mut evicted = self.mk_evicted.iter().collect::<Vec<_>>();
+        evicted.sort_by(|(pk1, _), (pk2, _)| pk1.as_ref().cmp(pk2.as_ref()));
+        bytes.extend_from_slice(&(evicted.len() as u64).to_le_bytes());
+        for (pk, until) in evicted {
+            bytes.extend_from_slice(pk.as_ref());
+            bytes.extend_from_slice(&until.to_le_bytes());
+        }
+
+        match &self.header_keys {
+            Some(keys) => {
+                bytes.push(1);
+                bytes.extend_from_slice(keys.sending.as_ref());
+                put_optional(&mut bytes, keys.receiving.as_ref().map(|k| k.as_ref()));
+                bytes.extend_from_slice(keys.next_sending.as_ref());
+                bytes.extend_from_slice(keys.next_receiving.as_ref());
+
+                let mut skipped = keys.skipped.iter().collect::<Vec<_>>();
+                skipped.sort_by(|(pk1, _), (pk2, _)| pk1.as_ref().cmp(pk2.as_ref()));
+                bytes.extend_from_slice(&(skipped.len() as u64).to_le_bytes());
+                for (pk, hk) in skipped {
+                    bytes.extend_from_slice(pk.as_ref());
+                    bytes.extend_from_slice(hk.as_ref());
+                }
+            }
+            None => bytes.push(0),
+        }
+        bytes.push(match self.chain_kdf {
+            ChainKdf::Hmac => 0,
+            ChainKdf::LegacyHkdf => 1,
+        });
+        bytes.extend_from_slice(&(self.max_plaintext_length as u64).to_le_bytes());
+        bytes.push(self.aead_suite.id());
+
+        match &self.unsent_chain {
+            Some(chain) => {
+                bytes.push(1);
+                bytes.extend_from_slice(chain.root_key.as_ref());
+                bytes.extend_from_slice(chain.dh_sending.private_key.as_ref());
+                bytes.extend_from_slice(chain.dh_sending.public_key.as_ref());
+                match &chain.header_keys {
+                    Some((sending, next_sending)) => {
+                        bytes.push(1);
+                        bytes.extend_from_slice(sending.as_ref());
+                        bytes.extend_from_slice(next_sending.as_ref());
+                    }
+                    None => bytes.push(0),
+                }
+            }
+            None => bytes.push(0),
+        }
+        match &self.pending_rekey {
+            Some(key_pair) => {
+                bytes.push(1);
+                bytes.extend_from_slice(key_pair.private_key.as_ref());
+                bytes.extend_from_slice(key_pair.public_key.as_ref());
             }
+            None => bytes.push(0),
+        }
+        bytes.push(self.conformance.id());
+        match self.padding {
+            Padding::None => bytes.push(0),
+            Padding::Buckets(bucket) => {
+                bytes.push(1);
+                bytes.extend_from_slice(&(bucket as u64).to_le_bytes());
+            }
+        }
+        bytes
+    }
+
+    /// Restores a ratchet state serialized with [`Ratchet::to_bytes`].
+    ///
+    /// # Arguments
+    ///
+    /// * `bytes` - The serialized state.
+    ///
+    /// # Returns
+    ///
+    /// * [`Ratchet`] - The restored ratchet.
+    ///
+    /// # Errors
+    ///
+    /// * [`RatchetError::InvalidState`] - Returned if `bytes` is truncated, has trailing data, an unknown version,
+    ///   an invalid flag, or a key pair whose public key does not match its private key.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, RatchetError> {
+        let mut reader = StateReader { bytes, offset: 0 };
+        let version = reader.take::<1>()?[0];
+        if !(1..=Self::STATE_VERSION).contains(&version) {
+            return Err(RatchetError::InvalidState);
+        }
+        let dh_sending = reader.key_pair()?;
+        let dh_receiving = reader.optional::<DH_PUBLIC_LENGTH>()?.map(|k| PublicKey::from(&k));
+        let root_key = SharedSecret::from(reader.take::<AES256_SECRET_LENGTH>()?);
+        let sending_chain_key = reader.optional()?.map(SharedSecret::from);
+        let receiving_chain_key = reader.optional()?.map(SharedSecret::from);
+        let n_messages_sent = reader.u64()?;
+        let n_messages_received = reader.u64()?;
+        let pn = reader.u64()?;
+        let max_skips = reader.u64()?;
+        let max_skipped_keys = usize::try_from(reader.u64()?).map_err(|_| RatchetError::InvalidState)?;
+
+        let n_skipped = reader.u64()?;
+        if n_skipped > max_skipped_keys as u64 {
+            return Err(RatchetError::InvalidState);
+        }
+        let mut mk_skipped = HashMap::new();
+        let mut mk_skipped_order = VecDeque::new();
+        for _ in 0..n_skipped {
+            let pk = PublicKey::from(&reader.take::<DH_PUBLIC_LENGTH>()?);
+            let n = reader.u64()?;
+            let mk = SharedSecret::from(reader.take::<AES256_SECRET_LENGTH>()?);
+            if mk_skipped.insert((pk.clone(), n), mk).is_some() {
+                return Err(RatchetError::InvalidState);
+            }
+            mk_skipped_order.push_back((pk, n));
+        }
+        let n_evicted = reader.u64()?;
+        let mut mk_evicted = HashMap::new();
+        for _ in 0..n_evicted {
+            let pk = PublicKey::from(&reader.take::<DH_PUBLIC_LENGTH>()?);
+            if mk_evicted.insert(pk, reader.u64()?).is_some() {
+                return Err(RatchetError::InvalidState);
+            }
+        }
+
+        let header_keys = match reader.take::<1>()?[0] {
+            0 => None,
+            1 => {
+                let sending = SharedSecret::from(reader.take::<AES256_SECRET_LENGTH>()?);
+                let receiving = reader.optional()?.map(SharedSecret::from);
+                let next_sending = SharedSecret::from(reader.take::<AES256_SECRET_LENGTH>()?);
+                let next_receiving = SharedSecret::from(reader.take::<AES256_SECRET_LENGTH>()?);
+                let n_skipped = reader.u64()?;
+                let mut skipped = HashMap::new();
+                for _ in 0..n_skipped {
+                    let pk = PublicKey::from(&reader.take::<DH_PUBLIC_LENGTH>()?);
+                    let hk = SharedSecret::from(reader.take::<AES256_SECRET_LENGTH>()?);
+                    if skipped.insert(pk, hk).is_some() {
+                        return Err(RatchetError::InvalidState);
+                    }
+                }
+                Some(HeaderKeys { sending, receiving, next_sending, next_receiving, skipped })
+            }
+            _ => return Err(RatchetError::InvalidState),
         };
-        assert_eq!(decrypted, plaintext);
-        let plaintext = b"Hello, Alice!";
-        let aad = AssociatedData{
-            initiator_identity_key: bob_ratchet.public_key.clone(),
-            responder_identity_key: alice.dh_sending.public_key.clone(),
+        let chain_kdf = match version {
+            1 => ChainKdf::LegacyHkdf,
+            _ => match reader.take::<1>()?[0] {
+                0 => ChainKdf::Hmac,
+                1 => ChainKdf::LegacyHkdf,
+                _ => return Err(RatchetError::InvalidState),
+            },
         };
-        let ciphertext = bob.encrypt(plaintext, &aad.to_bytes()).unwrap();
-        let decrypted = match alice.decrypt(ciphertext) {
-            Ok(dec) => dec,
-            Err(e) => {
-                panic!("Decryption failed: {:?}", e);
-            }
+        let max_plaintext_length = match version {
+            1 | 2 => MAX_PLAINTEXT_LENGTH,
+            _ => match usize::try_from(reader.u64()?) {
+                Ok(length) if length <= MAX_PLAINTEXT_LENGTH => length,
+                _ => return Err(RatchetError::InvalidState),
+            },
         };
-        assert_eq!(decrypted, plaintext);
-
-        // test 2: ratchet exchange with skipped message keys
-        let plaintext = b"How are you, Alice?";
-        let aad = AssociatedData{
-            initiator_identity_key: bob_ratchet.public_key.clone(),
-            responder_identity_key: alice.dh_sending.public_key.clone(),
+        let aead_suite = match version {
+            1..=4 => AeadSuite::Aes256Gcm,
+            _ => AeadSuite::try_from(reader.take::<1>()?[0]).map_err(|_| RatchetError::InvalidState)?,
         };
-        let ciphertext = bob.encrypt(plaintext, &aad.to_bytes()).unwrap();
-        let decrypted = match alice.decrypt(ciphertext) {
-            Ok(dec) => dec,
-            Err(e) => {
-                panic!("Decryption failed: {:?}", e);
+        let (unsent_chain, pending_rekey) = match version {
+            1..=3 => (None, None),
+            _ => {
+                let unsent_chain = match reader.flag()? {
+                    false => None,
+                    true => {
+                        let root_key = SharedSecret::from(reader.take::<AES256_SECRET_LENGTH>()?);
+                        let dh_sending = reader.key_pair()?;
+                        let chain_header_keys = match reader.flag()? {
+                            false => None,
+                            true => Some((
+                                SharedSecret::from(reader.take::<AES256_SECRET_LENGTH>()?),
+                                SharedSecret::from(reader.take::<AES256_SECRET_LENGTH>()?),
+                            )),
+                        };
+                        if chain_header_keys.is_some() != header_keys.is_some() {
+                            return Err(RatchetError::InvalidState);
+                        }
+                        Some(UnsentChain { root_key, dh_sending, header_keys: chain_header_keys })
+                    }
+                };
+                let pending_rekey = match reader.flag()? {
+                    false => None,
+                    true => Some(reader.key_pair()?),
+                };
+                (unsent_chain, pending_rekey)
             }
         };
-        assert_eq!(decrypted, plaintext);
-
-        let plaintext = b"All good, Bob!";
-        let aad = AssociatedData{
-            initiator_identity_key: bob_ratchet.public_key.clone(),
-            responder_identity_key: alice.dh_sending.public_key.clone(),
+        let conformance = match version {
+            1..=5 => Conformance::Native,
+            _ => Conformance::try_from(reader.take::<1>()?[0]).map_err(|_| RatchetError::InvalidState)?,
         };
-        let ciphertext = alice.encrypt(plaintext, &aad.to_bytes()).unwrap();
-        let decrypted = match bob.decrypt(ciphertext) {
-            Ok(dec) => dec,
-            Err(e) => {
-                panic!("Decryption failed: {:?}", e);
-            }
+        let padding = match version {
+            1..=6 => Padding::None,
+            _ => match reader.flag()? {
+                false => Padding::None,
+                true => match usize::try_from(reader.u64()?) {
+                    Ok(bucket) if (1..=MAX_PLAINTEXT_LENGTH).contains(&bucket) => Padding::Buckets(bucket),
+                    _ => return Err(RatchetError::InvalidState),
+                },
+            },
         };
+        if reader.offset != bytes.len() {
+            return Err(RatchetError::InvalidState);
+        }
+
+        Ok(Self {
+            dh_sending,
+            dh_receiving,
+            root_key,
+            sending_chain_key,
+            receiving_chain_key,
+            n_messages_sent,
+            n_messages_received,
+            pn,
+            mk_skipped,
+            header_keys,
+            max_skips,
+            mk_skipped_order,
+            mk_evicted,
+            max_skipped_keys,
+            chain_kdf,
+            aead_suite,
+            max_plaintext_length,
+            unsent_chain,
+            pending_rekey,
+            conformance,
+            padding,
+        })
+    }
+
+    /// Encrypts a message using the current sending chain state.
+    ///
+    /// # Arguments
+    ///
+    /// * `plaintext` – The message to encrypt.
+    /// * `aad` – Associated data to authenticate (but neither encrypt nor transmit).
+    ///
+    /// # Returns
+    ///
+    /// * `String` - A base64-encoded ciphertext string.
+    /// 
+    /// # Errors
+    /// 
+    /// * [`RatchetError::PlaintextTooLong`] - Returned if `plaintext` is longer than [`Ratchet::max_plaintext_length`]
+    ///   before padding, or than [`MAX_PLAINTEXT_LENGTH`] once padded. The check happens before any key derivation,
+    ///   so a rejected message does not use up a message key.
+    /// * [`X3DHError::AesGcmInvalidLength`] - Returned if AES-GCM decryption fails due to an unexpected ciphertext length.
+    pub fn encrypt(&mut self, plaintext: &[u8], aad: &[u8]) -> Result<String, RatchetError> {
+        Ok(general_purpose::STANDARD.encode(self.encrypt_bytes(plaintext, aad)?))
     }
-}
\ No newline at end of file
+
+    /// Encrypts a message like [`Ratchet::encrypt`], without base64-encoding the result.
+    ///
+    /// # Arguments
+    ///
+    /// * `plaintext` – The message to encrypt.
+    /// * `aad` – Associated data to authenticate (but not encrypt).
+    ///
+    /// # Returns
+    ///
+    /// * `Vec<u8>` - The encrypted message, laid out as `[nonce | header | ciphertext]`.
+    ///
+    /// # Errors
+    ///
+    /// See [`Ratchet::encrypt`].
+    pub fn encrypt_bytes(&mut self, plaintext: &[u8], aad: &[u8]) -> Result<Vec<u8>, RatchetError> {
+        self.check_plaintext_length(plaintext)?;
+        self.apply_pending_rekey()?;
+        let (ck, mk) = self.kdf_ck(self.sending_chain_key.clone().ok_or(RatchetError::InvalidState)?)?;
+        supersede(&mut self.sending_chain_key, Some(ck));
+        let mut h = Header::new(self.dh_sending.public_key.clone(), self.pn, self.n_messages_sent);
+        h.flags = self.padding.header_flags();
+        let h = match &self.header_keys {
+            Some(keys) => h.encrypt(&keys.sending, self.aead_suite)?,
+            None => h.to_bytes(),
+        };
+        self.n_messages_sent += 1;
+        // The peer learns the new ratchet key with this message
+        supersede(&mut self.unsent_chain, None);
+        let mk = EncryptionKey::from(mk).with_suite(self.aead_suite);
+        // Generate a new aad prepending the header to the original aad
+        let mut new_aad = vec![];
+        new_aad.extend_from_slice(&h);
+        new_aad.extend_from_slice(&aad);
+        // Only the header travels with the message, the peer knows the rest of the aad
+        let mut padded = self.padding.pad(plaintext);
+        let output = mk.encrypt_bytes(&padded, &new_aad);
+        padded.zeroize();
+        let mut output = output?;
+        output.splice(AES256_NONCE_LENGTH..AES256_NONCE_LENGTH, h);
+        Ok(output)
+    }
+
+    /// Fails with [`RatchetError::PlaintextTooLong`] if `plaintext` may not be encrypted, see [`Ratchet::encrypt`].
+    fn check_plaintext_length(&self, plaintext: &[u8]) -> Result<(), RatchetError> {
+        if plaintext.len() > self.max_plaintext_length || self.padding.padded_length(plaintext.len()) > MAX_PLAINTEXT_LENGTH {
+            return Err(RatchetError::PlaintextTooLong(plaintext.len()));
+        }
+        Ok(())
+    }
+
+    /// Encrypts a message like [`Ratchet::encrypt`], also returning the header values it was bound to.
+    ///
+    /// # Arguments
+    ///
+    /// * `plaintext` – The message to encrypt.
+    /// * `aad` – Associated data to authenticate (but not encrypt).
+    ///
+    /// # Returns
+    ///
+    /// * (`String`, [`MessageMeta`]) - The base64-encoded ciphertext and its metadata.
+    ///
+    /// # Errors
+    ///
+    /// See [`Ratchet::encrypt`].
+    pub fn encrypt_with_meta(&mut self, plaintext: &[u8], aad: &[u8]) -> Result<(String, MessageMeta), RatchetError> {
+        // Checked before the rekey, which a rejected message must not perform
+        self.check_plaintext_length(plaintext)?;
+        self.apply_pending_rekey()?;
+        let meta = self.meta(&Header::new(self.dh_sending.public_key.clone(), self.pn, self.n_messages_sent));
+        Ok((self.encrypt(plaintext, aad)?, meta))
+    }
+
+    /// Decrypts a received message, performing ratchet step if necessary.
+    ///
+    /// The message is authenticated against `aad`, which is not carried in the message. Messages of older
+    /// peers, which carry it after the header, are accepted if it is the same.
+    ///
+    /// # Arguments
+    ///
+    /// * `ciphertext` – The base64-encoded encrypted message.
+    /// * `aad` – The associated data the message was encrypted with.
+    ///
+    /// # Returns
+    ///
+    /// * `Vec<u8>` - The decrypted plaintext message.
+    /// 
+    /// # Errors
+    /// 
+    /// * [`RatchetError::ConversionError`] - Returned if Base64 decoding of the ciphertext fails, or it is too short to hold a header.
+    /// * [`RatchetError::InvalidHeaderLength`] - Returned if `value` does not match the expected length of [`Header`] ([`Header::LENGTH`]).
+    /// * [`RatchetError::ReflectedMessage`] - Returned if the message was sent by this ratchet.
+    /// * [`X3DHError::AesGcmInvalidLength`] - Returned if AES-GCM decryption fails due to an unexpected ciphertext length.
+    /// * [`RatchetError::MaxSkipsExceeded`] - Returned if the number of skipped messages exceeds the allowed maximum when attempting to handle out-of-order messages or advance the ratchet state.
+    /// * [`RatchetError::MessagesLost`] - Returned instead of [`RatchetError::MaxSkipsExceeded`] if the message authenticates, so the skipped messages are known to be lost.
+    /// * [`RatchetError::PaddingMismatch`] - Returned if the message was padded and the ratchet does not pad, or the other way round.
+    /// * [`RatchetError::InvalidPadding`] - Returned if the padding of the decrypted message is malformed.
+    pub fn decrypt(&mut self, ciphertext: String, aad: &[u8]) -> Result<Vec<u8>, RatchetError> {
+        let ciphertext = general_purpose::STANDARD.decode(ciphertext).map_err(|_| ConversionError)?;
+        self.decrypt_frame(&ciphertext, aad).map(|(plaintext, _)| plaintext)
+    }
+
+    /// Decrypts a received message like [`Ratchet::decrypt`], taking the raw bytes produced by [`Ratchet::encrypt_bytes`].
+    ///
+    /// # Arguments
+    ///
+    /// * `ciphertext` – The encrypted message, laid out as `[nonce | header | ciphertext]`.
+    /// * `aad` – The associated data the message was encrypted with.
+    ///
+    /// # Returns
+    ///
+    /// * `Vec<u8>` - The decrypted plaintext message.
+    ///
+    /// # Errors
+    ///
+    /// See [`Ratchet::decrypt`].
+    pub fn decrypt_bytes(&mut self, ciphertext: &[u8], aad: &[u8]) -> Result<Vec<u8>, RatchetError> {
+        self.decrypt_frame(ciphertext, aad).map(|(plaintext, _)| plaintext)
+    }
+
+    /// Decrypts a received message like [`Ratchet::decrypt`], with the associated data expected for the
+    /// incoming direction.
+    ///
+    /// Using direction-bound associated data (sender identity key first) makes a message
+    /// reflected back to its sender fail authentication.
+    ///
+    /// # Arguments
+    ///
+    /// * `ciphertext` – The base64-encoded encrypted message.
+    /// * `aad` – The associated data expected for messages received by this ratchet.
+    ///
+    /// # Returns
+    ///
+    /// * `Vec<u8>` - The decrypted plaintext message.
+    ///
+    /// # Errors
+    ///
+    /// See [`Ratchet::decrypt`].
+    pub fn decrypt_with_aad(&mut self, ciphertext: String, aad: &AssociatedData) -> Result<Vec<u8>, RatchetError> {
+        self.decrypt_with_meta(ciphertext, aad).map(|(plaintext, _)| plaintext)
+    }
+
+    /// Decrypts a received message like [`Ratchet::decrypt_with_aad`], also returning the header values it was bound to.
+    ///
+    /// # Arguments
+    ///
+    /// * `ciphertext` – The base64-encoded encrypted message.
+    /// * `aad` – The associated data expected for messages received by this ratchet.
+    ///
+    /// # Returns
+    ///
+    /// * (`Vec<u8>`, [`MessageMeta`]) - The decrypted plaintext message and its metadata.
+    ///
+    /// # Errors
+    ///
+    /// See [`Ratchet::decrypt`].
+    pub fn decrypt_with_meta(&mut self, ciphertext: String, aad: &AssociatedData) -> Result<(Vec<u8>, MessageMeta), RatchetError> {
+        let ciphertext = general_purpose::STANDARD.decode(ciphertext).map_err(|_| ConversionError)?;
+        self.decrypt_frame(&ciphertext, &aad.to_bytes())
+    }
+
+    /// The [`MessageMeta`] of a message sent or received with `header`.
+    fn meta(&self, header: &Header) -> MessageMeta {
+        MessageMeta {
+            ratchet_key: header.dhs.hash().0.iter().map(|b| format!("{:02x}", b)).collect(),
+            previous_chain_length: header.pn,
+            message_number: header.ns,
+            header_encrypted: self.header_keys.is_some(),
+            cipher_suite: self.chain_kdf.cipher_suite(self.aead_suite),
+        }
+    }
+
+    /// Parses a received message and decrypts it on a copy of the ratchet state.
+    /// The state is only updated if decryption succeeds, so a rejected message leaves the ratchet untouched.
+    ///
+    /// # Arguments
+    ///
+    /// * `ciphertext` – The encrypted message bytes.
+    /// * `aad` – The associated data to authenticate against.
+    ///
+    /// # Returns
+    ///
+    /// * (`Vec<u8>`, [`MessageMeta`]) - The decrypted plaintext message and its metadata.
+    ///
+    /// # Errors
+    ///
+    /// See [`Ratchet::decrypt`].
+    fn decrypt_frame(&mut self, ciphertext: &[u8], aad: &[u8]) -> Result<(Vec<u8>, MessageMeta), RatchetError> {
+        let header_length = match self.header_keys {
+            Some(_) => Header::ENCRYPTED_LENGTH,
+            None => Header::LENGTH,
+        };
+        if ciphertext.len() < AES256_NONCE_LENGTH + header_length {
+            return Err(ConversionError);
+        }
+        let nonce = *array_ref!(&ciphertext, 0, AES256_NONCE_LENGTH);
+        let header_bytes = &ciphertext[AES256_NONCE_LENGTH..AES256_NONCE_LENGTH + header_length];
+
+        let (header, new_chain) = match &self.header_keys {
+            Some(_) => self.decrypt_header(header_bytes.try_into().unwrap())?,
+            None => {
+                let header = Header::try_from(array_ref!(header_bytes, 0, Header::LENGTH))?;
+                let new_chain = Some(&header.dhs) != self.dh_receiving.as_ref();
+                (header, new_chain)
+            }
+        };
+        if header.dhs.ct_eq(&self.dh_sending.public_key) {
+            return Err(RatchetError::ReflectedMessage);
+        }
+
+        let ciphertext = &ciphertext[AES256_NONCE_LENGTH + header_length..];
+        let meta = self.meta(&header);
+        let flags = header.flags;
+        let (mk, staged) = self.stage_message(&header, header_bytes, new_chain, ciphertext, aad, &nonce)?;
+        let mk = DecryptionKey::from(mk).with_suite(self.aead_suite);
+        let mut new_aad = vec![];
+        new_aad.extend_from_slice(header_bytes);
+        new_aad.extend_from_slice(aad);
+        let mut result = mk.decrypt(ciphertext, &nonce, &new_aad).map_err(RatchetError::from);
+        // Older peers sent the aad after the header
+        let legacy = ciphertext.strip_prefix(aad).filter(|_| !aad.is_empty());
+        if let (Err(RatchetError::DecryptionError(_)), Some(legacy)) = (&result, legacy) {
+            result = mk.decrypt(legacy, &nonce, &new_aad).map_err(RatchetError::from);
+        }
+        let mut plaintext = result?;
+        // The flags are authenticated with the rest of the header, and checked before the state is kept
+        if flags != self.padding.header_flags() {
+            plaintext.zeroize();
+            return Err(RatchetError::PaddingMismatch(flags & Header::FLAG_PADDED != 0));
+        }
+        if self.padding != Padding::None {
+            if let Err(e) = Padding::unpad(&mut plaintext) {
+                plaintext.zeroize();
+                return Err(e);
+            }
+        }
+        self.commit_message(staged);
+        // A rotation the peer did not see yet is not kept past its next message, unlike the state before our first
+        // message, which the first ratchet key of the peer is derived against
+        if self.n_messages_sent > 0 || self.pn > 0 {
+            supersede(&mut self.unsent_chain, None);
+        }
+        Ok((plaintext, meta))
+    }
+
+    /// Trial-decrypts an encrypted header with the header keys of the chains it may belong to.
+    ///
+    /// The header keys of chains with skipped messages are tried first, then the one of the current
+    /// receiving chain, and finally the next one, which means the sender performed a DH ratchet step.
+    ///
+    /// # Arguments
+    ///
+    /// * `encrypted` - The encrypted header.
+    ///
+    /// # Returns
+    ///
+    /// * ([`Header`], `bool`) - The decrypted header, and whether it starts a new receiving chain.
+    ///
+    /// # Errors
+    ///
+    /// * [`RatchetError::DecryptionError`] - Returned if no header key decrypts the header.
+    fn decrypt_header(&self, encrypted: &[u8; Header::ENCRYPTED_LENGTH]) -> Result<(Header, bool), RatchetError> {
+        let keys = self.header_keys.as_ref().ok_or(ConversionError)?;
+        for (dhs, hk) in &keys.skipped {
+            if let Ok(header) = Header::decrypt(hk, self.aead_suite, encrypted) {
+                if header.dhs.ct_eq(dhs) && self.mk_skipped.contains_key(&(header.dhs.clone(), header.ns)) {
+                    return Ok((header, false));
+                }
+            }
+        }
+        if let Some(hk) = &keys.receiving {
+            if let Ok(header) = Header::decrypt(hk, self.aead_suite, encrypted) {
+                return Ok((header, false));
+            }
+        }
+        Header::decrypt(&keys.next_receiving, self.aead_suite, encrypted).map(|header| (header, true))
+    }
+
+    /// Derives the message key of a parsed message and stages what decrypting it changes in the ratchet,
+    /// including a DH ratchet step if the message starts a new receiving chain. The state is left as it is
+    /// until [`Ratchet::commit_message`].
+    ///
+    /// A stored skipped key is looked up with a single probe. Sender keys are compared in constant time when the
+    /// key is dropped, and the remaining variable-time work, the bucket probe of the randomly keyed hasher and the
+    /// comparison of message numbers, only depends on header values sent by the peer, not on secret material.
+    ///
+    /// # Arguments
+    ///
+    /// * `header` - The message header.
+    /// * `header_bytes` - The header as carried in the message, encrypted if the ratchet uses header encryption.
+    /// * `new_chain` - Whether the header starts a new receiving chain, requiring a DH ratchet step.
+    /// * `ciphertext` - The encrypted message payload (excluding nonce and header).
+    /// * `aad` - The associated data used to authenticate the message.
+    /// * `nonce` - The nonce used during encryption.
+    ///
+    /// # Returns
+    ///
+    /// * ([`SharedSecret`], [`StagedMessage`]) - The message key, and what to keep once the message authenticates.
+    ///
+    /// # Errors
+    ///
+    /// See [`Ratchet::decrypt`].
+    fn stage_message(
+        &self,
+        header: &Header,
+        header_bytes: &[u8],
+        new_chain: bool,
+        ciphertext: &[u8],
+        aad: &[u8],
+        nonce: &[u8; AES256_NONCE_LENGTH]
+    ) -> Result<(SharedSecret, StagedMessage), RatchetError> {
+        let key = (header.dhs.clone(), header.ns);
+        if let Some(mk) = self.mk_skipped.get(&key) {
+            return Ok((mk.clone(), StagedMessage::Skipped(key)));
+        }
+        if self.mk_evicted.get(&header.dhs).is_some_and(|until| header.ns < *until) {
+            return Err(RatchetError::SkippedKeyEvicted);
+        }
+        if let Err(RatchetError::MaxSkipsExceeded(gap)) = self.check_header_counters(header, new_chain) {
+            return Err(match self.authenticate_beyond_window(header, header_bytes, new_chain, ciphertext, aad, nonce, gap) {
+                true => RatchetError::MessagesLost(gap),
+                false => RatchetError::MaxSkipsExceeded(gap),
+            });
+        }
+        let mut closed_chain = vec![];
+        let (chain, from, ck, step) = if new_chain {
+            if let (Some(chain), Some(ck)) = (&self.dh_receiving, &self.receiving_chain_key) {
+                self.skip_message_keys(chain, ck.clone(), self.n_messages_received, header.pn, &mut closed_chain)?;
+            }
+            let step = self.receiving_step(&header.dhs)?;
+            (header.dhs.clone(), 0, step.receiving_chain_key.clone(), Some(step))
+        } else {
+            let chain = self.dh_receiving.clone().ok_or(RatchetError::InvalidState)?;
+            let ck = self.receiving_chain_key.clone().ok_or(RatchetError::InvalidState)?;
+            (chain, self.n_messages_received, ck, None)
+        };
+        let mut skipped = vec![];
+        let ck = self.skip_message_keys(&chain, ck, from, header.ns, &mut skipped)?;
+        let (receiving_chain_key, mk) = self.kdf_ck(ck)?;
+        let n_messages_received = from.max(header.ns) + 1;
+        Ok((mk, StagedMessage::Chain { closed_chain, step, skipped, receiving_chain_key, n_messages_received }))
+    }
+
+    /// Keeps what decrypting a message changed in the ratchet, once [`Ratchet::stage_message`] staged it and the
+    /// message authenticated.
+    ///
+    /// The keys skipped at the end of a closed receiving chain are stored before the DH ratchet step and those of
+    /// the new chain after it, so that they are evicted as if they had been derived in place.
+    fn commit_message(&mut self, staged: StagedMessage) {
+        match staged {
+            StagedMessage::Skipped(key) => {
+                if let Some(mut mk) = self.mk_skipped.remove(&key) {
+                    mk.zeroize();
+                }
+                if let Some(position) = self.mk_skipped_order.iter().position(|(dhs, n)| dhs.ct_eq(&key.0) && *n == key.1) {
+                    self.mk_skipped_order.remove(position);
+                }
+                self.forget_header_key(&key.0);
+            }
+            StagedMessage::Chain { closed_chain, step, skipped, receiving_chain_key, n_messages_received } => {
+                self.store_skipped_keys(closed_chain);
+                if let Some(step) = step {
+                    self.dh_ratchet(step);
+                }
+                self.store_skipped_keys(skipped);
+                supersede(&mut self.receiving_chain_key, Some(receiving_chain_key));
+                self.n_messages_received = n_messages_received;
+            }
+        }
+    }
+
+    /// Rejects a header whose counters would require skipping more than [`Ratchet::max_skips`] message keys.
+    ///
+    /// The counters are attacker-controlled, so they are checked before any key derivation:
+    /// a forged header is rejected in constant time instead of driving a loop proportional to its counters.
+    ///
+    /// # Arguments
+    ///
+    /// * `header` - The header of the received message.
+    /// * `new_chain` - Whether the header starts a new receiving chain.
+    ///
+    /// # Errors
+    ///
+    /// * [`RatchetError::MaxSkipsExceeded`] - Returned if `pn` or `ns` are too far ahead of the local counters,
+    ///   with the number of messages the header claims were sent in between.
+    fn check_header_counters(&self, header: &Header, new_chain: bool) -> Result<(), RatchetError> {
+        let within_window = |from: u64, until: u64| until <= from.saturating_add(self.max_skips);
+        let in_window = if new_chain {
+            // `pn` closes the current receiving chain and `ns` counts from the start of the new one
+            within_window(self.n_messages_received, header.pn) && within_window(0, header.ns)
+        } else {
+            within_window(self.n_messages_received, header.ns)
+        };
+        if in_window {
+            Ok(())
+        } else if new_chain {
+            Err(RatchetError::MaxSkipsExceeded(
+                header.pn.saturating_sub(self.n_messages_received).saturating_add(header.ns)
+            ))
+        } else {
+            Err(RatchetError::MaxSkipsExceeded(header.ns - self.n_messages_received))
+        }
+    }
+
+    /// Derives the message keys of a receiving chain up to a given message number, to be stored as skipped keys.
+    ///
+    /// Each key is named by the chain and the number the sender gave the message, which is how
+    /// [`Ratchet::stage_message`] looks them up. The state is left as it is, see [`Ratchet::store_skipped_keys`].
+    ///
+    /// # Arguments
+    ///
+    /// * `chain` – The ratchet key of the peer the receiving chain belongs to.
+    /// * `ck` – The chain key of the message numbered `from`.
+    /// * `from` – The number of messages received on the chain.
+    /// * `until` – The message number to skip up to (exclusive).
+    /// * `skipped` – Where the derived keys are appended.
+    ///
+    /// # Returns
+    ///
+    /// * [`SharedSecret`] - The chain key of the message numbered `until`, or `ck` if there is nothing to skip.
+    fn skip_message_keys(
+        &self,
+        chain: &PublicKey,
+        mut ck: SharedSecret,
+        from: u64,
+        until: u64,
+        skipped: &mut Vec<((PublicKey, u64), SharedSecret)>
+    ) -> Result<SharedSecret, RatchetError> {
+        if from.saturating_add(self.max_skips) < until {
+            return Err(RatchetError::MaxSkipsExceeded(until - from));
+        }
+        for n in from..until {
+            let (next, mk) = self.kdf_ck(ck)?;
+            ck = next;
+            skipped.push(((chain.clone(), n), mk));
+        }
+        Ok(ck)
+    }
+
+    /// Stores the message keys skipped on the current receiving chain, with its header key if the ratchet
+    /// encrypts headers, evicting the oldest keys beyond [`Ratchet::max_skipped_keys`].
+    fn store_skipped_keys(&mut self, skipped: Vec<((PublicKey, u64), SharedSecret)>) {
+        let Some(((chain, _), _)) = skipped.first() else { return };
+        if let Some(keys) = self.header_keys.as_mut() {
+            if let Some(hk) = keys.receiving.clone() {
+                keys.skipped.insert(chain.clone(), hk);
+            }
+        }
+        for (key, mk) in skipped {
+            self.mk_skipped.insert(key.clone(), mk);
+            self.mk_skipped_order.push_back(key);
+            self.evict_skipped_keys();
+        }
+    }
+
+    /// Evicts the oldest skipped message keys until at most [`Ratchet::max_skipped_keys`] are stored.
+    ///
+    /// Keys are inserted in increasing message number within a chain, so evicting by insertion order
+    /// drops the oldest chains first and the lowest message numbers within a chain.
+    fn evict_skipped_keys(&mut self) {
+        while self.mk_skipped.len() > self.max_skipped_keys && self.evict_oldest_skipped_key() {}
+    }
+
+    /// Zeroizes and drops the oldest skipped message key, remembering that messages up to it were evicted.
+    ///
+    /// # Returns
+    ///
+    /// * `bool` - `false` if no skipped message key was left.
+    fn evict_oldest_skipped_key(&mut self) -> bool {
+        let Some((dhs, n)) = self.mk_skipped_order.pop_front() else { return false };
+        if let Some(mut mk) = self.mk_skipped.remove(&(dhs.clone(), n)) {
+            mk.zeroize();
+        }
+        let until = self.mk_evicted.entry(dhs.clone()).or_insert(0);
+        *until = (*until).max(n + 1);
+        self.forget_header_key(&dhs);
+        true
+    }
+
+    /// Drops the header key of a receiving chain once none of its skipped message keys are left.
+    fn forget_header_key(&mut self, dhs: &PublicKey) {
+        if let Some(keys) = self.header_keys.as_mut() {
+            if !self.mk_skipped.keys().any(|(pk, _)| pk == dhs) {
+                keys.skipped.remove(dhs);
+            }
+        }
+    }
+
+    /// Checks whether a message rejected by [`Ratchet::check_header_counters`] is genuine, so the missed
+    /// messages are only reported as lost when the peer really sent them.
+    ///
+    /// The message key is derived without changing the state, and none of the skipped keys are kept. At most
+    /// [`MAX_LOST_MESSAGES`] keys are derived: beyond that, the header is treated as forged without any derivation.
+    ///
+    /// # Arguments
+    ///
+    /// * `header` - The message header.
+    /// * `header_bytes` - The header as carried in the message, encrypted if the ratchet uses header encryption.
+    /// * `new_chain` - Whether the header starts a new receiving chain.
+    /// * `ciphertext` - The encrypted message payload (excluding nonce and header).
+    /// * `aad` - The associated data used to authenticate the message.
+    /// * `nonce` - The nonce used during encryption.
+    /// * `gap` - The number of messages the header claims were sent in between.
+    ///
+    /// # Returns
+    ///
+    /// * `bool` - `true` if the message authenticates.
+    #[allow(clippy::too_many_arguments)]
+    fn authenticate_beyond_window(
+        &self,
+        header: &Header,
+        header_bytes: &[u8],
+        new_chain: bool,
+        ciphertext: &[u8],
+        aad: &[u8],
+        nonce: &[u8; AES256_NONCE_LENGTH],
+        gap: u64
+    ) -> bool {
+        if gap > MAX_LOST_MESSAGES {
+            return false;
+        }
+        let (mut ck, from) = if new_chain {
+            let Ok(step) = self.receiving_step(&header.dhs) else { return false };
+            (step.receiving_chain_key, 0)
+        } else {
+            let Some(ck) = self.receiving_chain_key.clone() else { return false };
+            (ck, self.n_messages_received)
+        };
+        for n in from..=header.ns {
+            let Ok((next, mk)) = self.kdf_ck(ck) else { return false };
+            ck = next;
+            if n == header.ns {
+                let mk = DecryptionKey::from(mk).with_suite(self.aead_suite);
+                let mut new_aad = vec![];
+                new_aad.extend_from_slice(header_bytes);
+                new_aad.extend_from_slice(aad);
+                return mk.decrypt(ciphertext, nonce, &new_aad).map(|mut plaintext| plaintext.zeroize()).is_ok();
+            }
+        }
+        false
+    }
+
+    /// Derives the receiving half of a DH ratchet step for a new incoming public key, without changing the state.
+    ///
+    /// Nothing may have been sent on the current sending chain, in which case the peer derived its new key
+    /// against the previous one, see [`Ratchet::restore_unsent_chain`].
+    ///
+    /// # Arguments
+    ///
+    /// * `dh_receiving` – The new public key of the peer.
+    fn receiving_step(&self, dh_receiving: &PublicKey) -> Result<ReceivingStep, RatchetError> {
+        let (root_key, dh_sending) = match &self.unsent_chain {
+            Some(chain) => (&chain.root_key, &chain.dh_sending),
+            None => (&self.root_key, &self.dh_sending),
+        };
+        let (root_key, receiving_chain_key, next_header_key) =
+            self.kdf_rk(root_key.clone(), dh_sending.diffie_hellman(dh_receiving))?;
+        Ok(ReceivingStep { dh_receiving: dh_receiving.clone(), root_key, receiving_chain_key, next_header_key })
+    }
+
+    /// Performs the receiving half of a DH ratchet step derived by [`Ratchet::receiving_step`].
+    ///
+    /// The sending half waits for the next encryption, see [`Ratchet::apply_pending_rekey`]: until then the state
+    /// holds no sending chain, and the sending key pair is the one the peer derived its new key against,
+    /// so a rotation of the peer in the meantime is followed as is.
+    ///
+    /// # Arguments
+    ///
+    /// * `step` – The step towards the new public key of the peer.
+    fn dh_ratchet(&mut self, step: ReceivingStep) {
+        let ReceivingStep { dh_receiving, root_key, receiving_chain_key, next_header_key } = step;
+        // Nothing was sent on the current sending chain, so the peer derived its new key against the previous one
+        self.restore_unsent_chain();
+        // The sending half of this step rotates the key anyway, which replaces a rotation that was not performed yet
+        supersede(&mut self.pending_rekey, None);
+        self.n_messages_received = 0;
+        self.dh_receiving = Some(dh_receiving);
+        // Eviction marks are only kept for chains that may still receive messages
+        let order = &self.mk_skipped_order;
+        self.mk_evicted.retain(|dhs, _| order.iter().any(|(pk, _)| pk == dhs));
+        if let Some(keys) = self.header_keys.as_mut() {
+            supersede(&mut keys.receiving, Some(keys.next_receiving.clone()));
+        }
+        supersede(&mut self.root_key, root_key);
+        supersede(&mut self.receiving_chain_key, Some(receiving_chain_key));
+        if let (Some(keys), Some(next_header_key)) = (self.header_keys.as_mut(), next_header_key) {
+            supersede(&mut keys.next_receiving, next_header_key);
+        }
+        supersede(&mut self.sending_chain_key, None);
+    }
+
+    /// Performs the sending half of a DH ratchet step: starts a new sending chain with `dh_sending`.
+    ///
+    /// # Arguments
+    ///
+    /// * `dh_sending` – The new sending key pair.
+    fn sending_half(&mut self, dh_sending: RatchetKeyPair) -> Result<(), RatchetError> {
+        let dh_receiving = self.dh_receiving.clone().ok_or(ConversionError)?;
+        self.pn = self.n_messages_sent;
+        self.n_messages_sent = 0;
+        if let Some(keys) = self.header_keys.as_mut() {
+            supersede(&mut keys.sending, keys.next_sending.clone());
+        }
+        supersede(&mut self.dh_sending, dh_sending);
+        let (rk, cks, nhks) = self.kdf_rk(self.root_key.clone(), self.dh_sending.diffie_hellman(&dh_receiving))?;
+        supersede(&mut self.root_key, rk);
+        supersede(&mut self.sending_chain_key, Some(cks));
+        if let (Some(keys), Some(nhks)) = (self.header_keys.as_mut(), nhks) {
+            supersede(&mut keys.next_sending, nhks);
+        }
+        Ok(())
+    }
+
+    /// Undoes the sending half of the latest DH ratchet step if nothing was sent on its chain,
+    /// so that the next step starts from the state the peer knows.
+    fn restore_unsent_chain(&mut self) {
+        let Some(chain) = self.unsent_chain.take() else { return };
+        let UnsentChain { root_key, dh_sending, header_keys } = chain;
+        supersede(&mut self.root_key, root_key);
+        supersede(&mut self.dh_sending, dh_sending);
+        // The next sending half moves the count back into `pn`
+        self.n_messages_sent = self.pn;
+        if let (Some(keys), Some((sending, next_sending))) = (self.header_keys.as_mut(), header_keys) {
+            supersede(&mut keys.sending, sending);
+            supersede(&mut keys.next_sending, next_sending);
+        }
+    }
+
+    /// Advances a root key with a Diffie-Hellman shared secret, see [`hkdf_rk`] and [`hkdf_rk_he`].
+    ///
+    /// # Arguments
+    ///
+    /// * `root_key` - The root key to advance, the current one unless a DH ratchet step is being derived.
+    /// * `dh` - The Diffie-Hellman shared secret.
+    ///
+    /// # Returns
+    ///
+    /// * ([`SharedSecret`], [`SharedSecret`], `Option<SharedSecret>`) - The new root key, the new chain key,
+    ///   and the next header key if the ratchet uses header encryption.
+    ///
+    /// # Errors
+    ///
+    /// * [`RatchetError::KeyDerivationError`] - If the HKDF expand step fails.
+    fn kdf_rk(&self, root_key: SharedSecret, dh: DhOutput) -> Result<(SharedSecret, SharedSecret, Option<SharedSecret>), RatchetError> {
+        match self.header_keys {
+            Some(_) => hkdf_rk_he(root_key, dh).map(|(rk, ck, nhk)| (rk, ck, Some(nhk))),
+            None => hkdf_rk(self.conformance, root_key, dh).map(|(rk, ck)| (rk, ck, None)),
+        }
+    }
+
+    /// Derives the next chain key and a message key with the [`ChainKdf`] of the ratchet.
+    ///
+    /// # Arguments
+    ///
+    /// * `ck` - The current chain key.
+    ///
+    /// # Returns
+    ///
+    /// * ([`SharedSecret`], [`SharedSecret`]) - A tuple `(next_chain_key, message_key)`.
+    ///
+    /// # Errors
+    ///
+    /// * [`RatchetError::HkdfInvalidLengthError`] - If the legacy HKDF expand step fails.
+    /// * [`RatchetError::ConversionError`] - If the chain key is rejected as an HMAC key.
+    fn kdf_ck(&self, ck: SharedSecret) -> Result<(SharedSecret, SharedSecret), RatchetError> {
+        match self.chain_kdf {
+            ChainKdf::Hmac => hmac_ck(ck),
+            ChainKdf::LegacyHkdf => hkdf_ck(ck),
+        }
+    }
+}
+
+impl Zeroize for Ratchet {
+
+    /// Wipes every secret of the ratchet: the sending key pair, the root and chain keys, the skipped message keys
+    /// and the header keys. The ratchet can no longer encrypt or decrypt afterwards.
+    fn zeroize(&mut self) {
+        self.dh_sending.zeroize();
+        self.root_key.zeroize();
+        self.sending_chain_key.zeroize();
+        self.receiving_chain_key.zeroize();
+        self.mk_skipped.values_mut().for_each(Zeroize::zeroize);
+        self.mk_skipped.clear();
+        self.mk_skipped_order.clear();
+        self.mk_evicted.clear();
+        if let Some(keys) = self.header_keys.as_mut() {
+            keys.zeroize();
+        }
+        self.unsent_chain.zeroize();
+        self.pending_rekey.zeroize();
+        self.n_messages_sent = 0;
+        self.n_messages_received = 0;
+        self.pn = 0;
+    }
+}
+
+impl Drop for Ratchet {
+    fn drop(&mut self) {
+        self.zeroize();
+    }
+}
+
+impl ZeroizeOnDrop for Ratchet {}
+
+impl Serialize for Ratchet {
+
+    /// Serializes the [`Ratchet`] as the bytes returned by [`Ratchet::to_bytes`].
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut bytes = self.to_bytes();
+        let result = serializer.serialize_bytes(&bytes);
+        bytes.zeroize();
+        result
+    }
+}
+
+impl<'de> Deserialize<'de> for Ratchet {
+
+    /// Deserializes a [`Ratchet`] from the bytes returned by [`Ratchet::to_bytes`].
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if [`Ratchet::from_bytes`] fails.
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let mut bytes = serde_bytes::ByteBuf::deserialize(deserializer)?.into_vec();
+        let result = Ratchet::from_bytes(&bytes).map_err(serde::de::Error::custom);
+        bytes.zeroize();
+        result
+    }
+}
+
+/// Reads the fields of a serialized [`Ratchet`] state, failing instead of panicking on truncated input.
+struct StateReader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl StateReader<'_> {
+
+    /// Reads the next `N` bytes.
+    ///
+    /// # Errors
+    ///
+    /// * [`RatchetError::InvalidState`] - Returned if fewer than `N` bytes are left.
+    fn take<const N: usize>(&mut self) -> Result<[u8; N], RatchetError> {
+        let end = self.offset.checked_add(N).ok_or(RatchetError::InvalidState)?;
+        let value = self.bytes.get(self.offset..end).ok_or(RatchetError::InvalidState)?;
+        self.offset = end;
+        Ok(value.try_into().unwrap())
+    }
+
+    /// Reads a little-endian `u64`.
+    ///
+    /// # Errors
+    ///
+    /// * [`RatchetError::InvalidState`] - Returned if fewer than 8 bytes are left.
+    fn u64(&mut self) -> Result<u64, RatchetError> {
+        Ok(u64::from_le_bytes(self.take()?))
+    }
+
+    /// Reads a presence flag.
+    ///
+    /// # Errors
+    ///
+    /// * [`RatchetError::InvalidState`] - Returned if the input is truncated or the flag is neither 0 nor 1.
+    fn flag(&mut self) -> Result<bool, RatchetError> {
+        match self.take::<1>()?[0] {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(RatchetError::InvalidState),
+        }
+    }
+
+    /// Reads a private key followed by its public key.
+    ///
+    /// # Errors
+    ///
+    /// * [`RatchetError::InvalidState`] - Returned if the input is truncated or the public key does not match the private key.
+    fn key_pair(&mut self) -> Result<RatchetKeyPair, RatchetError> {
+        let private_key = PrivateKey::from(self.take::<DH_SECRET_LENGTH>()?);
+        let public_key = PublicKey::from(&self.take::<DH_PUBLIC_LENGTH>()?);
+        if !PublicKey::from(&private_key).ct_eq(&public_key) {
+            return Err(RatchetError::InvalidState);
+        }
+        Ok(RatchetKeyPair::new_from(private_key, public_key))
+    }
+
+    /// Reads a presence flag followed, if set, by an `N`-byte value.
+    ///
+    /// # Errors
+    ///
+    /// * [`RatchetError::InvalidState`] - Returned if the input is truncated or the flag is neither 0 nor 1.
+    fn optional<const N: usize>(&mut self) -> Result<Option<[u8; N]>, RatchetError> {
+        match self.take::<1>()?[0] {
+            0 => Ok(None),
+            1 => Ok(Some(self.take()?)),
+            _ => Err(RatchetError::InvalidState),
+        }
+    }
+}
+
+/// Derives a new root key and chain key from the current root key and a Diffie-Hellman shared secret.
+/// This function implements the `HKDF(rk, dh)` step from the Double Ratchet algorithm, using the current
+/// root key `rk` and a new shared secret `dh` as inputs. It applies HKDF with SHA-256 to produce two
+/// new secrets: a derived root key and a new receiving chain key.
+///
+/// In [`Conformance::Spec`] the input key material is `dh` alone and the info [`SPEC_RATCHET_INFO`],
+/// as in `KDF_RK` of the specification.
+///
+/// # Arguments
+///
+/// * `conformance` - The [`Conformance`] of the session.
+/// * `rk` - The current root key (a shared secret).
+/// * `dh` - The Diffie-Hellman shared secret between the new and previous public keys.
+///
+/// # Returns
+///
+/// * ([`SharedSecret`], [`SharedSecret`]) - A tuple `(new_root_key, receiving_chain_key)` derived from HKDF.
+///
+/// # Errors
+///
+/// * [`RatchetError::KeyDerivationError`] - If the HKDF expand step fails.
+pub(crate) fn hkdf_rk(
+    conformance: Conformance,
+    rk: SharedSecret,
+    dh: DhOutput,
+) -> Result<(SharedSecret, SharedSecret), RatchetError> {
+    let (dhs, info) = match conformance {
+        Conformance::Native => {
+            // HKDF input key material = F || KM, where KM is an input byte sequence containing secret key material, and F is a byte sequence containing 32 0xFF bytes if curve is X25519, and 57 0xFF bytes if curve is X448. F is used for cryptographic domain separation with XEdDSA [2].
+            let mut dhs = curve::kdf_prefix();
+            dhs.extend_from_slice(rk.as_ref());
+            dhs.extend_from_slice(dh.as_ref());
+            (dhs, NATIVE_RATCHET_INFO)
+        }
+        Conformance::Spec => (dh.as_ref().to_vec(), SPEC_RATCHET_INFO),
+    };
+
+    // Use the shared secret as the salt as per the X3DH spec.
+    let hk = Hkdf::<Sha256>::new(Some(rk.as_ref()), dhs.as_ref());
+    let mut okm = [0u8; 2 * AES256_SECRET_LENGTH];
+    // HKDF info = The info parameter from Section 2.1.
+    hk.expand(info, &mut okm)?;
+
+    let shared_key1 = SharedSecret::from(*array_ref!(okm, 0, AES256_SECRET_LENGTH));
+    let shared_key2 =
+        SharedSecret::from(*array_ref!(okm, AES256_SECRET_LENGTH, AES256_SECRET_LENGTH));
+    Ok((shared_key1, shared_key2))
+}
+
+/// Derives a new root key, chain key and next header key, as `KDF_RK_HE` in the header encryption variant
+/// of the Double Ratchet. The derivation is the same as [`hkdf_rk`] with a distinct info string and a third output.
+///
+/// # Arguments
+///
+/// * `rk` - The current root key (a shared secret).
+/// * `dh` - The Diffie-Hellman shared secret between the new and previous public keys.
+///
+/// # Returns
+///
+/// * ([`SharedSecret`], [`SharedSecret`], [`SharedSecret`]) - A tuple `(new_root_key, chain_key, next_header_key)`.
+///
+/// # Errors
+///
+/// * [`RatchetError::KeyDerivationError`] - If the HKDF expand step fails.
+fn hkdf_rk_he(
+    rk: SharedSecret,
+    dh: DhOutput,
+) -> Result<(SharedSecret, SharedSecret, SharedSecret), RatchetError> {
+    let mut dhs = curve::kdf_prefix();
+    dhs.extend_from_slice(rk.as_ref());
+    dhs.extend_from_slice(dh.as_ref());
+
+    let hk = Hkdf::<Sha256>::new(Some(rk.as_ref()), dhs.as_ref());
+    let mut okm = [0u8; 3 * AES256_SECRET_LENGTH];
+    hk.expand(b"RatchetHeaderEncryptionInfo", &mut okm)?;
+
+    Ok((
+        SharedSecret::from(*array_ref!(okm, 0, AES256_SECRET_LENGTH)),
+        SharedSecret::from(*array_ref!(okm, AES256_SECRET_LENGTH, AES256_SECRET_LENGTH)),
+        SharedSecret::from(*array_ref!(okm, 2 * AES256_SECRET_LENGTH, AES256_SECRET_LENGTH)),
+    ))
+}
+
+/// Derives the initial header keys from the shared secret agreed with X3DH.
+///
+/// # Arguments
+///
+/// * `sk` - The shared secret.
+///
+/// # Returns
+///
+/// * ([`SharedSecret`], [`SharedSecret`], [`SharedSecret`]) - A tuple `(alice_header_key, bob_next_header_key, bob_header_key)`.
+///   Bob uses his header key only if he sends before receiving anything.
+///
+/// # Errors
+///
+/// * [`RatchetError::KeyDerivationError`] - If the HKDF expand step fails.
+fn hkdf_header_keys(
+    sk: &SharedSecret,
+) -> Result<(SharedSecret, SharedSecret, SharedSecret), RatchetError> {
+    let hk = Hkdf::<Sha256>::new(None, sk.as_ref());
+    let mut okm = [0u8; 3 * AES256_SECRET_LENGTH];
+    hk.expand(b"RatchetHeaderKeys", &mut okm)?;
+
+    Ok((
+        SharedSecret::from(*array_ref!(okm, 0, AES256_SECRET_LENGTH)),
+        SharedSecret::from(*array_ref!(okm, AES256_SECRET_LENGTH, AES256_SECRET_LENGTH)),
+        SharedSecret::from(*array_ref!(okm, 2 * AES256_SECRET_LENGTH, AES256_SECRET_LENGTH)),
+    ))
+}
+
+/// Derives the initial sending chain key of Bob from the shared secret agreed with X3DH,
+/// which is also the initial receiving chain key of Alice.
+///
+/// # Arguments
+///
+/// * `sk` - The shared secret.
+///
+/// # Returns
+///
+/// * [`SharedSecret`] - The initial chain key.
+///
+/// # Errors
+///
+/// * [`RatchetError::HkdfInvalidLengthError`] - If the HKDF expand step fails.
+fn initial_chain_key(sk: &SharedSecret) -> Result<SharedSecret, RatchetError> {
+    hkdf_ck(sk.clone()).map(|(ck, _)| ck)
+}
+
+/// Replaces a secret of the ratchet, wiping the superseded one in place first.
+///
+/// # Arguments
+///
+/// * `slot` - The secret to replace.
+/// * `value` - The new secret.
+fn supersede<T: Zeroize>(slot: &mut T, value: T) {
+    slot.zeroize();
+    *slot = value;
+}
+
+/// Derives a new chain key and message key from the current chain key using HKDF.
+/// This function applies HKDF with SHA-256 to derive two secrets from a single chain key:
+/// the next chain key and a message encryption key. This step is used for each message sent or received
+/// in the Double Ratchet protocol to ensure forward secrecy.
+///
+/// # Arguments
+///
+/// * `ck` - The current chain key, a [`SharedSecret`] used as input key material for HKDF.
+///
+/// # Returns
+///
+/// * ([`SharedSecret`], [`SharedSecret`]) - A tuple `(next_chain_key, message_key)` used to continue the ratchet chain and encrypt/decrypt a message.
+///
+/// # Errors
+///
+/// * [`RatchetError::KeyDerivationError`] - if HKDF expansion fails.
+pub(crate) fn hkdf_ck(
+    ck: SharedSecret,
+) -> Result<(SharedSecret, SharedSecret), RatchetError> {
+    // HKDF salt = A zero-filled byte sequence with length equal to the hash output length.
+    let hk = Hkdf::<Sha256>::new(None, ck.as_ref());
+    let mut chain_key = [0u8; AES256_SECRET_LENGTH];
+    let mut message_key = [0u8; AES256_SECRET_LENGTH];
+    // HKDF info = The info parameter from Section 2.1.
+    hk.expand(b"ChainKey", &mut chain_key)?;
+    hk.expand(b"MessageKey", &mut message_key)?;
+
+    let chain_key = SharedSecret::from(*array_ref!(chain_key, 0, AES256_SECRET_LENGTH));
+    let message_key =
+        SharedSecret::from(*array_ref!(message_key, 0, AES256_SECRET_LENGTH));
+    Ok((chain_key, message_key))
+}
+
+/// Derives a new chain key and message key from the current chain key, as `KDF_CK` in the Double Ratchet
+/// specification: HMAC-SHA256 keyed with the chain key, over the single byte `0x01` for the message key
+/// and `0x02` for the next chain key.
+///
+/// # Arguments
+///
+/// * `ck` - The current chain key, used as the HMAC key.
+///
+/// # Returns
+///
+/// * ([`SharedSecret`], [`SharedSecret`]) - A tuple `(next_chain_key, message_key)`.
+///
+/// # Errors
+///
+/// * [`RatchetError::ConversionError`] - If the chain key is rejected as an HMAC key.
+pub(crate) fn hmac_ck(
+    ck: SharedSecret,
+) -> Result<(SharedSecret, SharedSecret), RatchetError> {
+    let derive = |input: u8| -> Result<SharedSecret, RatchetError> {
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(ck.as_ref()).map_err(|_| ConversionError)?;
+        mac.update(&[input]);
+        Ok(SharedSecret::from(<[u8; AES256_SECRET_LENGTH]>::from(mac.finalize().into_bytes())))
+    };
+    let message_key = derive(0x01)?;
+    let chain_key = derive(0x02)?;
+    Ok((chain_key, message_key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::SharedSecret;
+    use aes_gcm::{KeyInit};
+
+    #[test]
+    fn test_ratchet() {
+
+        // test 1: simple ratchet exchange
+        let bob_ratchet = RatchetKeyPair::new();
+        let sh = SharedSecret::from([0u8; 32]);
+        let mut alice = Ratchet::init_alice(sh.clone(), bob_ratchet.public_key.clone());
+        let mut bob = Ratchet::init_bob(sh, bob_ratchet.clone());
+        let plaintext = b"Hello, Bob!";
+        let aad = AssociatedData{
+            initiator_identity_key: bob_ratchet.public_key.clone(),
+            responder_identity_key: alice.dh_sending.public_key.clone(),
+        };
+        let ciphertext = alice.encrypt(plaintext, &aad.to_bytes()).unwrap();
+        let decrypted = match bob.decrypt(ciphertext, &aad.to_bytes()) {
+            Ok(dec) => dec,
+            Err(e) => {
+                panic!("Decryption failed: {:?}", e);
+            }
+        };
+        assert_eq!(decrypted, plaintext);
+        let plaintext = b"Hello, Alice!";
+        let aad = AssociatedData{
+            initiator_identity_key: bob_ratchet.public_key.clone(),
+            responder_identity_key: alice.dh_sending.public_key.clone(),
+        };
+        let ciphertext = bob.encrypt(plaintext, &aad.to_bytes()).unwrap();
+        let decrypted = match alice.decrypt(ciphertext, &aad.to_bytes()) {
+            Ok(dec) => dec,
+            Err(e) => {
+                panic!("Decryption failed: {:?}", e);
+            }
+        };
+        assert_eq!(decrypted, plaintext);
+
+        // test 2: ratchet exchange with skipped message keys
+        let plaintext = b"How are you, Alice?";
+        let aad = AssociatedData{
+            initiator_identity_key: bob_ratchet.public_key.clone(),
+            responder_identity_key: alice.dh_sending.public_key.clone(),
+        };
+        let ciphertext = bob.encrypt(plaintext, &aad.to_bytes()).unwrap();
+        let decrypted = match alice.decrypt(ciphertext, &aad.to_bytes()) {
+            Ok(dec) => dec,
+            Err(e) => {
+                panic!("Decryption failed: {:?}", e);
+            }
+        };
+        assert_eq!(decrypted, plaintext);
+
+        let plaintext = b"All good, Bob!";
+        let aad = AssociatedData{
+            initiator_identity_key: bob_ratchet.public_key.clone(),
+            responder_identity_key: alice.dh_sending.public_key.clone(),
+        };
+        let ciphertext = alice.encrypt(plaintext, &aad.to_bytes()).unwrap();
+        let decrypted = match bob.decrypt(ciphertext, &aad.to_bytes()) {
+            Ok(dec) => dec,
+            Err(e) => {
+                panic!("Decryption failed: {:?}", e);
+            }
+        };
+    }
+
+    #[test]
+    fn test_reflected_message_is_rejected() {
+        let bob_ratchet = RatchetKeyPair::new();
+        let sh = SharedSecret::from([0u8; 32]);
+        let mut alice = Ratchet::init_alice(sh.clone(), bob_ratchet.public_key.clone());
+        let mut bob = Ratchet::init_bob(sh, bob_ratchet.clone());
+        let to_bob = AssociatedData::new(PublicKey::from(&PrivateKey::new()), PublicKey::from(&PrivateKey::new()));
+        let to_alice = to_bob.reversed();
+
+        let ciphertext = alice.encrypt(b"Hello, Bob!", &to_bob.to_bytes()).unwrap();
+
+        // The header carries Alice's own ratchet key
+        assert!(matches!(alice.decrypt(ciphertext.clone(), &to_bob.to_bytes()), Err(RatchetError::ReflectedMessage)));
+        // Associated data bound to the other direction does not authenticate
+        assert!(bob.decrypt_with_aad(ciphertext.clone(), &to_alice).is_err());
+
+        // Neither attempt changed the ratchet state
+        assert_eq!(bob.decrypt_with_aad(ciphertext, &to_bob).unwrap(), b"Hello, Bob!");
+        let reply = bob.encrypt(b"Hello, Alice!", &to_alice.to_bytes()).unwrap();
+        assert_eq!(alice.decrypt_with_aad(reply, &to_alice).unwrap(), b"Hello, Alice!");
+    }
+
+    /// Stores a skipped message key the way [`Ratchet::store_skipped_keys`] does.
+    fn insert_skipped(ratchet: &mut Ratchet, key: (PublicKey, u64), mk: SharedSecret) {
+        ratchet.mk_skipped.insert(key.clone(), mk);
+        ratchet.mk_skipped_order.push_back(key);
+    }
+
+    /// Returns `ciphertext` with the header counters replaced, and the sender key too if `dhs` is given.
+    fn forge_header(ciphertext: &str, dhs: Option<PublicKey>, pn: u64, ns: u64) -> String {
+        let mut bytes = general_purpose::STANDARD.decode(ciphertext).unwrap();
+        let header = Header::try_from(array_ref!(&bytes, AES256_NONCE_LENGTH, Header::LENGTH)).unwrap();
+        let forged = Header::new(dhs.unwrap_or(header.dhs), pn, ns);
+        bytes[AES256_NONCE_LENGTH..AES256_NONCE_LENGTH + Header::LENGTH].copy_from_slice(&forged.to_bytes());
+        general_purpose::STANDARD.encode(bytes)
+    }
+
+    #[test]
+    fn test_header_counter_window_boundaries() {
+        let bob_ratchet = RatchetKeyPair::new();
+        let sh = SharedSecret::from([0u8; 32]);
+        let mut alice = Ratchet::init_alice(sh, bob_ratchet.public_key.clone());
+        let current = alice.dh_receiving.clone().unwrap();
+        let other = PublicKey::from(&PrivateKey::new());
+
+        // Same receiving chain: `ns` may be at most MAX_SKIPS ahead
+        alice.n_messages_received = 5;
+        assert!(alice.check_header_counters(&Header::new(current.clone(), 0, 5 + MAX_SKIPS), false).is_ok());
+        assert!(matches!(
+            alice.check_header_counters(&Header::new(current.clone(), 0, 5 + MAX_SKIPS + 1), false),
+            Err(RatchetError::MaxSkipsExceeded(gap)) if gap == MAX_SKIPS + 1
+        ));
+
+        // New receiving chain: `pn` is relative to the current counter, `ns` to zero
+        assert!(alice.check_header_counters(&Header::new(other.clone(), 5 + MAX_SKIPS, MAX_SKIPS), true).is_ok());
+        assert!(matches!(
+            alice.check_header_counters(&Header::new(other.clone(), 5 + MAX_SKIPS + 1, 3), true),
+            Err(RatchetError::MaxSkipsExceeded(gap)) if gap == MAX_SKIPS + 4
+        ));
+        assert!(matches!(
+            alice.check_header_counters(&Header::new(other.clone(), 0, MAX_SKIPS + 1), true),
+            Err(RatchetError::MaxSkipsExceeded(gap)) if gap == MAX_SKIPS + 1
+        ));
+
+        // The window saturates instead of overflowing near the top of the counter range
+        alice.n_messages_received = u64::MAX - MAX_SKIPS + 1;
+        assert!(alice.check_header_counters(&Header::new(current.clone(), 0, u64::MAX), false).is_ok());
+        assert!(alice.check_header_counters(&Header::new(other.clone(), u64::MAX, 0), true).is_ok());
+        alice.n_messages_received = u64::MAX;
+        assert!(alice.check_header_counters(&Header::new(current, 0, u64::MAX), false).is_ok());
+        alice.n_messages_received = 0;
+        let ck = alice.receiving_chain_key.clone().unwrap();
+        assert!(alice.skip_message_keys(&other, ck, 0, u64::MAX, &mut vec![]).is_err());
+    }
+
+    #[test]
+    fn test_rejected_message_leaves_the_state_as_it_was() {
+        let tamper = |ciphertext: &str| {
+            let mut bytes = general_purpose::STANDARD.decode(ciphertext).unwrap();
+            *bytes.last_mut().unwrap() ^= 1;
+            general_purpose::STANDARD.encode(bytes)
+        };
+        let bob_ratchet = RatchetKeyPair::new();
+        let sh = SharedSecret::from([0u8; 32]);
+        let plain = (Ratchet::init_alice(sh.clone(), bob_ratchet.public_key.clone()), Ratchet::init_bob(sh, bob_ratchet));
+        for (mut alice, mut bob) in [plain, header_encrypted_pair()] {
+            let aad = AssociatedData::new(PublicKey::from(&PrivateKey::new()), PublicKey::from(&PrivateKey::new())).to_bytes();
+            let hello = alice.encrypt(b"hello", &aad).unwrap();
+            assert_eq!(bob.decrypt(hello, &aad).unwrap(), b"hello");
+            let reply = bob.encrypt(b"reply", &aad).unwrap();
+
+            // A new receiving chain, with two keys to skip
+            let (first, second) = (bob.encrypt(b"first", &aad).unwrap(), bob.encrypt(b"second", &aad).unwrap());
+            let before = alice.to_bytes();
+            assert!(alice.decrypt(tamper(&second), &aad).is_err());
+            assert_eq!(alice.to_bytes(), before);
+            assert_eq!(alice.decrypt(second, &aad).unwrap(), b"second");
+            assert_eq!(alice.skipped_key_count(), 2);
+
+            // A stored skipped key
+            let before = alice.to_bytes();
+            assert!(alice.decrypt(tamper(&first), &aad).is_err());
+            assert_eq!(alice.to_bytes(), before);
+            assert_eq!(alice.decrypt(first, &aad).unwrap(), b"first");
+            assert_eq!(alice.decrypt(reply, &aad).unwrap(), b"reply");
+            assert_eq!(alice.skipped_key_count(), 0);
+        }
+    }
+
+    #[test]
+    fn test_forged_counters_are_rejected_without_derivation() {
+        let bob_ratchet = RatchetKeyPair::new();
+        let sh = SharedSecret::from([0u8; 32]);
+        let mut alice = Ratchet::init_alice(sh.clone(), bob_ratchet.public_key.clone());
+        let mut bob = Ratchet::init_bob(sh, bob_ratchet);
+        let aad = AssociatedData::new(PublicKey::from(&PrivateKey::new()), PublicKey::from(&PrivateKey::new()));
+
+        let first = alice.encrypt(b"first", &aad.to_bytes()).unwrap();
+        bob.decrypt(first, &aad.to_bytes()).unwrap();
+        let second = alice.encrypt(b"second", &aad.to_bytes()).unwrap();
+
+        let mut extremes = vec![MAX_SKIPS + 2, u64::MAX / 2, u64::MAX - MAX_SKIPS, u64::MAX - 1, u64::MAX];
+        extremes.extend((0..64).map(|_| rand::random::<u64>() | (1 << 63)));
+
+        let start = std::time::Instant::now();
+        for &value in &extremes {
+            let forged = [
+                forge_header(&second, None, 0, value),
+                forge_header(&second, Some(PublicKey::from(&PrivateKey::new())), value, 0),
+                forge_header(&second, Some(PublicKey::from(&PrivateKey::new())), 0, value),
+            ];
+            for ciphertext in forged {
+                assert!(matches!(bob.decrypt(ciphertext, &aad.to_bytes()), Err(RatchetError::MaxSkipsExceeded(_))));
+                assert!(bob.mk_skipped.is_empty());
+            }
+        }
+        // A loop proportional to any of these counters would never finish
+        assert!(start.elapsed() < std::time::Duration::from_secs(5));
+
+        // The genuine message still decrypts
+        assert_eq!(bob.decrypt(second, &aad.to_bytes()).unwrap(), b"second");
+    }
+
+    #[test]
+    fn test_lost_messages_are_only_reported_for_genuine_messages() {
+        for (mut alice, bob) in [
+            {
+                let bob_ratchet = RatchetKeyPair::new();
+                let sh = SharedSecret::from([0u8; 32]);
+                (Ratchet::init_alice(sh.clone(), bob_ratchet.public_key.clone()), Ratchet::init_bob(sh, bob_ratchet))
+            },
+            header_encrypted_pair(),
+        ] {
+            let aad = AssociatedData::new(PublicKey::from(&PrivateKey::new()), PublicKey::from(&PrivateKey::new()));
+            for _ in 0..MAX_SKIPS + 1 {
+                alice.encrypt(b"lost", &aad.to_bytes()).unwrap();
+            }
+            let genuine = alice.encrypt(b"still there?", &aad.to_bytes()).unwrap();
+
+            // The message authenticates, so the skipped ones are known to be lost, and nothing is kept
+            let mut receiver = bob.clone();
+            assert!(matches!(
+                receiver.decrypt(genuine.clone(), &aad.to_bytes()),
+                Err(RatchetError::MessagesLost(gap)) if gap == MAX_SKIPS + 1
+            ));
+            assert_eq!(receiver.state_snapshot(), bob.state_snapshot());
+            assert!(receiver.mk_skipped.is_empty());
+
+            // A tampered message claiming the same gap does not
+            let mut tampered = general_purpose::STANDARD.decode(&genuine).unwrap();
+            *tampered.last_mut().unwrap() ^= 1;
+            assert!(matches!(
+                receiver.decrypt(general_purpose::STANDARD.encode(tampered), &aad.to_bytes()),
+                Err(RatchetError::MaxSkipsExceeded(gap)) if gap == MAX_SKIPS + 1
+            ));
+
+            // Too far ahead to be checked, a message is rejected without derivation
+            for _ in 0..MAX_LOST_MESSAGES {
+                alice.encrypt(b"lost", &aad.to_bytes()).unwrap();
+            }
+            let far = alice.encrypt(b"anyone?", &aad.to_bytes()).unwrap();
+            assert!(matches!(receiver.decrypt(far, &aad.to_bytes()), Err(RatchetError::MaxSkipsExceeded(_))));
+        }
+    }
+
+    #[test]
+    fn test_state_round_trip() {
+        let bob_ratchet = RatchetKeyPair::new();
+        let sh = SharedSecret::from([0u8; 32]);
+        let mut alice = Ratchet::init_alice(sh.clone(), bob_ratchet.public_key.clone());
+        let mut bob = Ratchet::init_bob(sh, bob_ratchet);
+        let aad = AssociatedData::new(PublicKey::from(&PrivateKey::new()), PublicKey::from(&PrivateKey::new()));
+
+        let ciphertext = alice.encrypt(b"before", &aad.to_bytes()).unwrap();
+        assert_eq!(bob.decrypt(ciphertext, &aad.to_bytes()).unwrap(), b"before");
+        insert_skipped(&mut bob, (PublicKey::from(&PrivateKey::new()), 7), SharedSecret::from([7u8; 32]));
+        insert_skipped(&mut bob, (PublicKey::from(&PrivateKey::new()), 3), SharedSecret::from([3u8; 32]));
+
+        let state = bob.to_bytes();
+        let mut restored = Ratchet::from_bytes(&state).unwrap();
+        // The encoding does not depend on the map iteration order
+        assert_eq!(restored.to_bytes(), state);
+        assert_eq!(restored.mk_skipped.len(), 2);
+
+        let ciphertext = alice.encrypt(b"after", &aad.to_bytes()).unwrap();
+        assert_eq!(restored.decrypt(ciphertext, &aad.to_bytes()).unwrap(), b"after");
+        let reply = restored.encrypt(b"reply", &aad.to_bytes()).unwrap();
+        assert_eq!(alice.decrypt(reply, &aad.to_bytes()).unwrap(), b"reply");
+    }
+
+    #[test]
+    fn test_malformed_state_is_rejected() {
+        let bob_ratchet = RatchetKeyPair::new();
+        let mut bob = Ratchet::init_bob(SharedSecret::from([0u8; 32]), bob_ratchet);
+        insert_skipped(&mut bob, (PublicKey::from(&PrivateKey::new()), 1), SharedSecret::from([1u8; 32]));
+        let state = bob.to_bytes();
+
+        for len in 0..state.len() {
+            assert!(matches!(Ratchet::from_bytes(&state[..len]), Err(RatchetError::InvalidState)));
+        }
+        let mut trailing = state.clone();
+        trailing.push(0);
+        assert!(matches!(Ratchet::from_bytes(&trailing), Err(RatchetError::InvalidState)));
+
+        let mut version = state.clone();
+        version[0] = 0xFF;
+        assert!(matches!(Ratchet::from_bytes(&version), Err(RatchetError::InvalidState)));
+
+        // Flipping a byte of the sending public key breaks the key pair
+        let mut key_pair = state.clone();
+        key_pair[1 + DH_SECRET_LENGTH] ^= 1;
+        assert!(matches!(Ratchet::from_bytes(&key_pair), Err(RatchetError::InvalidState)));
+
+        let mut flag = state;
+        flag[1 + DH_SECRET_LENGTH + DH_PUBLIC_LENGTH] = 2;
+        assert!(matches!(Ratchet::from_bytes(&flag), Err(RatchetError::InvalidState)));
+    }
+
+    #[test]
+    fn test_serde_round_trip() {
+        let bob_ratchet = RatchetKeyPair::new();
+        let sh = SharedSecret::from([0u8; 32]);
+        let mut alice = Ratchet::init_alice(sh.clone(), bob_ratchet.public_key.clone());
+        let mut bob = Ratchet::init_bob(sh, bob_ratchet.clone());
+        let aad = AssociatedData::new(PublicKey::from(&PrivateKey::new()), PublicKey::from(&PrivateKey::new()));
+
+        bob.decrypt(alice.encrypt(b"first", &aad.to_bytes()).unwrap(), &aad.to_bytes()).unwrap();
+        let skipped_key = (PublicKey::from(&PrivateKey::new()), 4);
+        insert_skipped(&mut alice, skipped_key.clone(), SharedSecret::from([4u8; 32]));
+
+        let serialized = serde_json::to_string(&alice).unwrap();
+        let mut restored: Ratchet = serde_json::from_str(&serialized).unwrap();
+        assert_eq!(restored.mk_skipped.get(&skipped_key).unwrap().as_ref(), &[4u8; 32]);
+
+        // The counterpart keeps decrypting what the restored copy sends
+        for text in [b"second", b"third!"] {
+            let ciphertext = restored.encrypt(text, &aad.to_bytes()).unwrap();
+            assert_eq!(bob.decrypt(ciphertext, &aad.to_bytes()).unwrap(), text);
+        }
+
+        let pair: RatchetKeyPair = serde_json::from_str(&serde_json::to_string(&bob_ratchet).unwrap()).unwrap();
+        assert!(pair.public_key == bob_ratchet.public_key);
+        let secret: SharedSecret = serde_json::from_str(&serde_json::to_string(&SharedSecret::from([9u8; 32])).unwrap()).unwrap();
+        assert_eq!(secret.as_ref(), &[9u8; 32]);
+
+        assert!(serde_json::from_str::<Ratchet>("[1, 2, 3]").is_err());
+        assert!(serde_json::from_str::<SharedSecret>("[1, 2, 3]").is_err());
+    }
+
+    fn header_encrypted_pair() -> (Ratchet, Ratchet) {
+        let bob_ratchet = RatchetKeyPair::new();
+        let sh = SharedSecret::from([0u8; 32]);
+        let alice = Ratchet::init_alice_with_header_encryption(sh.clone(), bob_ratchet.public_key.clone());
+        let bob = Ratchet::init_bob_with_header_encryption(sh, bob_ratchet);
+        (alice, bob)
+    }
+
+    #[test]
+    fn test_header_encryption_hides_header() {
+        let (mut alice, mut bob) = header_encrypted_pair();
+        let aad = AssociatedData::new(PublicKey::from(&PrivateKey::new()), PublicKey::from(&PrivateKey::new()));
+        assert!(alice.has_header_encryption());
+
+        let ciphertext = alice.encrypt(b"Hello, Bob!", &aad.to_bytes()).unwrap();
+        let bytes = general_purpose::STANDARD.decode(&ciphertext).unwrap();
+        let dhs = alice.dh_sending.public_key.as_ref().to_vec();
+        assert!(!bytes.windows(dhs.len()).any(|w| w == dhs.as_slice()));
+
+        // A plain ratchet cannot make sense of it
+        let bob_ratchet = RatchetKeyPair::new();
+        let mut plain = Ratchet::init_bob(SharedSecret::from([0u8; 32]), bob_ratchet);
+        assert!(plain.decrypt(ciphertext.clone(), &aad.to_bytes()).is_err());
+
+        assert_eq!(bob.decrypt(ciphertext, &aad.to_bytes()).unwrap(), b"Hello, Bob!");
+        let reply = bob.encrypt(b"Hello, Alice!", &aad.to_bytes()).unwrap();
+        assert_eq!(alice.decrypt(reply, &aad.to_bytes()).unwrap(), b"Hello, Alice!");
+        let again = alice.encrypt(b"How are you?", &aad.to_bytes()).unwrap();
+        assert_eq!(bob.decrypt(again, &aad.to_bytes()).unwrap(), b"How are you?");
+    }
+
+    #[test]
+    fn test_header_encryption_bob_sends_first() {
+        let (mut alice, mut bob) = header_encrypted_pair();
+        let aad = AssociatedData::new(PublicKey::from(&PrivateKey::new()), PublicKey::from(&PrivateKey::new()));
+
+        let ciphertext = bob.encrypt(b"first", &aad.to_bytes()).unwrap();
+        assert_eq!(alice.decrypt(ciphertext, &aad.to_bytes()).unwrap(), b"first");
+        let reply = alice.encrypt(b"second", &aad.to_bytes()).unwrap();
+        assert_eq!(bob.decrypt(reply, &aad.to_bytes()).unwrap(), b"second");
+    }
+
+    #[test]
+    fn test_header_encryption_out_of_order() {
+        let (mut alice, mut bob) = header_encrypted_pair();
+        let aad = AssociatedData::new(PublicKey::from(&PrivateKey::new()), PublicKey::from(&PrivateKey::new()));
+        let encrypt = |ratchet: &mut Ratchet, text: &str| ratchet.encrypt(text.as_bytes(), &aad.to_bytes()).unwrap();
+
+        let a0 = encrypt(&mut alice, "a0");
+        let a1 = encrypt(&mut alice, "a1");
+        let a2 = encrypt(&mut alice, "a2");
+        assert_eq!(bob.decrypt(a2, &aad.to_bytes()).unwrap(), b"a2");
+        assert_eq!(bob.decrypt(a0.clone(), &aad.to_bytes()).unwrap(), b"a0");
+
+        // Bob replies, so Alice's next messages start a new chain
+        let b0 = encrypt(&mut bob, "b0");
+        assert_eq!(alice.decrypt(b0, &aad.to_bytes()).unwrap(), b"b0");
+        let a3 = encrypt(&mut alice, "a3");
+        let a4 = encrypt(&mut alice, "a4");
+        assert_eq!(bob.decrypt(a4, &aad.to_bytes()).unwrap(), b"a4");
+
+        // Messages from the previous chain and the skipped one of the current chain still decrypt
+        assert_eq!(bob.decrypt(a1, &aad.to_bytes()).unwrap(), b"a1");
+        assert_eq!(bob.decrypt(a3, &aad.to_bytes()).unwrap(), b"a3");
+        assert!(bob.mk_skipped.is_empty());
+        assert!(bob.header_keys.as_ref().unwrap().skipped.is_empty());
+
+        // Replays are rejected
+        assert!(bob.decrypt(a0, &aad.to_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_header_encrypted_state_round_trip() {
+        let (mut alice, mut bob) = header_encrypted_pair();
+        let aad = AssociatedData::new(PublicKey::from(&PrivateKey::new()), PublicKey::from(&PrivateKey::new()));
+
+        let a0 = alice.encrypt(b"a0", &aad.to_bytes()).unwrap();
+        let a1 = alice.encrypt(b"a1", &aad.to_bytes()).unwrap();
+        assert_eq!(bob.decrypt(a1, &aad.to_bytes()).unwrap(), b"a1");
+
+        let state = bob.to_bytes();
+        let mut restored = Ratchet::from_bytes(&state).unwrap();
+        assert_eq!(restored.to_bytes(), state);
+        assert!(restored.has_header_encryption());
+        assert_eq!(restored.decrypt(a0, &aad.to_bytes()).unwrap(), b"a0");
+        let reply = restored.encrypt(b"reply", &aad.to_bytes()).unwrap();
+        assert_eq!(alice.decrypt(reply, &aad.to_bytes()).unwrap(), b"reply");
+
+        for len in 0..state.len() {
+            assert!(Ratchet::from_bytes(&state[..len]).is_err());
+        }
+    }
+
+    #[test]
+    fn test_configured_max_skips() {
+        for max_skips in [25, 5000] {
+            let bob_ratchet = RatchetKeyPair::new();
+            let sh = SharedSecret::from([0u8; 32]);
+            let mut alice = Ratchet::init_alice(sh.clone(), bob_ratchet.public_key.clone());
+            let bob = Ratchet::init_bob(sh, bob_ratchet)
+                .with_max_skips(max_skips)
+                .with_max_skipped_keys(max_skips as usize);
+            assert_eq!(bob.max_skips(), max_skips);
+            let aad = AssociatedData::new(PublicKey::from(&PrivateKey::new()), PublicKey::from(&PrivateKey::new()));
+
+            let messages = (0..max_skips + 2)
+                .map(|_| alice.encrypt(b"hello", &aad.to_bytes()).unwrap())
+                .collect::<Vec<_>>();
+
+            // Reaching message `max_skips` skips exactly `max_skips` keys
+            let mut within = bob.clone();
+            assert_eq!(within.decrypt(messages[max_skips as usize].clone(), &aad.to_bytes()).unwrap(), b"hello");
+            assert_eq!(within.mk_skipped.len() as u64, max_skips);
+
+            let mut beyond = bob.clone();
+            assert!(matches!(
+                beyond.decrypt(messages[max_skips as usize + 1].clone(), &aad.to_bytes()),
+                Err(RatchetError::MessagesLost(gap)) if gap == max_skips + 1
+            ));
+            assert!(beyond.mk_skipped.is_empty());
+
+            // The limit survives serialization
+            assert_eq!(Ratchet::from_bytes(&bob.to_bytes()).unwrap().max_skips(), max_skips);
+        }
+
+        let bob_ratchet = RatchetKeyPair::new();
+        assert_eq!(Ratchet::init_bob(SharedSecret::from([0u8; 32]), bob_ratchet).max_skips(), MAX_SKIPS);
+    }
+
+    #[test]
+    fn test_bob_sends_first() {
+        let bob_ratchet = RatchetKeyPair::new();
+        let sh = SharedSecret::from([0u8; 32]);
+        let mut alice = Ratchet::init_alice(sh.clone(), bob_ratchet.public_key.clone());
+        let mut bob = Ratchet::init_bob(sh, bob_ratchet.clone());
+        let aad = AssociatedData::new(PublicKey::from(&PrivateKey::new()), PublicKey::from(&PrivateKey::new()));
+
+        let first = bob.encrypt(b"bob first", &aad.to_bytes()).unwrap();
+        let second = bob.encrypt(b"bob second", &aad.to_bytes()).unwrap();
+        assert_eq!(alice.decrypt(second, &aad.to_bytes()).unwrap(), b"bob second");
+        assert_eq!(alice.decrypt(first, &aad.to_bytes()).unwrap(), b"bob first");
+
+        // Alice's reply is Bob's first DH ratchet step, after which both chains have moved on
+        let reply = alice.encrypt(b"alice", &aad.to_bytes()).unwrap();
+        assert_eq!(bob.decrypt(reply, &aad.to_bytes()).unwrap(), b"alice");
+        let next = bob.encrypt(b"bob again", &aad.to_bytes()).unwrap();
+        assert!(bob.dh_sending.public_key != bob_ratchet.public_key);
+        assert_eq!(alice.decrypt(next, &aad.to_bytes()).unwrap(), b"bob again");
+    }
+
+    #[test]
+    fn test_bob_first_chain_outlives_the_dh_step() {
+        let bob_ratchet = RatchetKeyPair::new();
+        let sh = SharedSecret::from([0u8; 32]);
+        let mut alice = Ratchet::init_alice(sh.clone(), bob_ratchet.public_key.clone());
+        let bob = Ratchet::init_bob(sh, bob_ratchet);
+        let aad = AssociatedData::new(PublicKey::from(&PrivateKey::new()), PublicKey::from(&PrivateKey::new()));
+
+        // Bob's state before he heard from Alice, with a sending chain and no receiving one, is kept as is
+        let mut bob = Ratchet::from_bytes(&bob.to_bytes()).unwrap();
+        let b0 = bob.encrypt(b"b0", &aad.to_bytes()).unwrap();
+        let mut bob = Ratchet::from_bytes(&bob.to_bytes()).unwrap();
+        assert!(bob.dh_receiving.is_none());
+
+        let a0 = alice.encrypt(b"a0", &aad.to_bytes()).unwrap();
+        assert_eq!(bob.decrypt(a0, &aad.to_bytes()).unwrap(), b"a0");
+        let b1 = bob.encrypt(b"b1", &aad.to_bytes()).unwrap();
+
+        // Bob's first message is overtaken by one of the chain after his DH step
+        assert_eq!(alice.decrypt(b1, &aad.to_bytes()).unwrap(), b"b1");
+        assert_eq!(alice.mk_skipped.len(), 1);
+        assert_eq!(alice.decrypt(b0, &aad.to_bytes()).unwrap(), b"b0");
+        assert!(alice.mk_skipped.is_empty());
+    }
+
+    #[test]
+    fn test_both_send_first() {
+        let bob_ratchet = RatchetKeyPair::new();
+        let sh = SharedSecret::from([0u8; 32]);
+        let mut alice = Ratchet::init_alice(sh.clone(), bob_ratchet.public_key.clone());
+        let mut bob = Ratchet::init_bob(sh, bob_ratchet);
+        let aad = AssociatedData::new(PublicKey::from(&PrivateKey::new()), PublicKey::from(&PrivateKey::new()));
+
+        // Alice and Bob write at the same time, then the messages cross
+        let from_alice = alice.encrypt(b"alice first", &aad.to_bytes()).unwrap();
+        let from_bob = bob.encrypt(b"bob first", &aad.to_bytes()).unwrap();
+        assert_eq!(bob.decrypt(from_alice, &aad.to_bytes()).unwrap(), b"alice first");
+        assert_eq!(alice.decrypt(from_bob, &aad.to_bytes()).unwrap(), b"bob first");
+
+        let from_bob = bob.encrypt(b"bob second", &aad.to_bytes()).unwrap();
+        assert_eq!(alice.decrypt(from_bob, &aad.to_bytes()).unwrap(), b"bob second");
+        let from_alice = alice.encrypt(b"alice second", &aad.to_bytes()).unwrap();
+        assert_eq!(bob.decrypt(from_alice, &aad.to_bytes()).unwrap(), b"alice second");
+    }
+
+    #[test]
+    fn test_init_with_config() {
+        let bob_ratchet = RatchetKeyPair::new();
+        let sh = SharedSecret::from([0u8; 32]);
+        let config = RatchetConfig { max_skips: 2, ..RatchetConfig::default() };
+        let mut alice = Ratchet::init_alice_with_config(sh.clone(), bob_ratchet.public_key.clone(), config);
+        let bob = Ratchet::init_bob_with_config(sh, bob_ratchet, config);
+        assert_eq!(alice.max_skips(), 2);
+        assert_eq!(bob.max_skipped_keys(), MAX_SKIPPED_KEYS);
+        let aad = AssociatedData::new(PublicKey::from(&PrivateKey::new()), PublicKey::from(&PrivateKey::new()));
+
+        let messages = (0..4)
+            .map(|_| alice.encrypt(b"hello", &aad.to_bytes()).unwrap())
+            .collect::<Vec<_>>();
+
+        // Two skipped messages are tolerated, a third is not
+        assert_eq!(bob.clone().decrypt(messages[2].clone(), &aad.to_bytes()).unwrap(), b"hello");
+        assert!(matches!(bob.clone().decrypt(messages[3].clone(), &aad.to_bytes()), Err(RatchetError::MessagesLost(3))));
+        assert_eq!(RatchetConfig::default().max_skips, MAX_SKIPS);
+    }
+
+    #[test]
+    fn test_out_of_order_delivery() {
+        let bob_ratchet = RatchetKeyPair::new();
+        let sh = SharedSecret::from([0u8; 32]);
+        let mut alice = Ratchet::init_alice(sh.clone(), bob_ratchet.public_key.clone());
+        let mut bob = Ratchet::init_bob(sh, bob_ratchet);
+        let aad = AssociatedData::new(PublicKey::from(&PrivateKey::new()), PublicKey::from(&PrivateKey::new()));
+
+        let messages = [b"one", b"two", b"six"]
+            .map(|text| (text, alice.encrypt(text, &aad.to_bytes()).unwrap()));
+        let sent_before = bob.n_messages_sent;
+
+        for index in [2, 0, 1] {
+            let (text, ciphertext) = &messages[index];
+            assert_eq!(bob.decrypt(ciphertext.clone(), &aad.to_bytes()).unwrap(), *text);
+        }
+        assert!(bob.mk_skipped.is_empty());
+        assert_eq!(bob.n_messages_received, 3);
+        // Skipping keys on the receiving chain leaves the sending counter alone
+        assert_eq!(bob.n_messages_sent, sent_before);
+
+        let reply = bob.encrypt(b"reply", &aad.to_bytes()).unwrap();
+        assert_eq!(alice.decrypt(reply, &aad.to_bytes()).unwrap(), b"reply");
+    }
+
+    #[test]
+    fn test_skipped_keys_are_indexed_by_the_receiving_counter() {
+        let bob_ratchet = RatchetKeyPair::new();
+        let sh = SharedSecret::from([0u8; 32]);
+        let mut alice = Ratchet::init_alice(sh.clone(), bob_ratchet.public_key.clone());
+        let mut bob = Ratchet::init_bob(sh, bob_ratchet);
+        let aad = AssociatedData::new(PublicKey::from(&PrivateKey::new()), PublicKey::from(&PrivateKey::new())).to_bytes();
+
+        // Bob sends more than he receives, so that his counters differ
+        let hello = alice.encrypt(b"hello", &aad).unwrap();
+        bob.decrypt(hello, &aad).unwrap();
+        for text in [b"one", b"two", b"six"] {
+            let ciphertext = bob.encrypt(text, &aad).unwrap();
+            assert_eq!(alice.decrypt(ciphertext, &aad).unwrap(), text);
+        }
+        assert_eq!((bob.n_messages_sent, bob.n_messages_received), (3, 1));
+
+        // A new receiving chain for Bob, delivered 3, 1, 2
+        let messages = [b"abc", b"def", b"ghi"].map(|text| (text, alice.encrypt(text, &aad).unwrap()));
+        let (text, ciphertext) = &messages[2];
+        assert_eq!(bob.decrypt(ciphertext.clone(), &aad).unwrap(), *text);
+        let dh_receiving = bob.dh_receiving.clone().unwrap();
+        assert!(bob.mk_skipped.contains_key(&(dh_receiving.clone(), 0)));
+        assert!(bob.mk_skipped.contains_key(&(dh_receiving, 1)));
+        assert_eq!((bob.n_messages_sent, bob.n_messages_received), (3, 3));
+        for index in [0, 1] {
+            let (text, ciphertext) = &messages[index];
+            assert_eq!(bob.decrypt(ciphertext.clone(), &aad).unwrap(), *text);
+        }
+        assert!(bob.mk_skipped.is_empty());
+    }
+
+    #[test]
+    fn test_byte_and_base64_apis_interoperate() {
+        let bob_ratchet = RatchetKeyPair::new();
+        let sh = SharedSecret::from([0u8; 32]);
+        let mut alice = Ratchet::init_alice(sh.clone(), bob_ratchet.public_key.clone());
+        let mut bob = Ratchet::init_bob(sh, bob_ratchet);
+        let aad = AssociatedData::new(PublicKey::from(&PrivateKey::new()), PublicKey::from(&PrivateKey::new()));
+
+        let bytes = alice.encrypt_bytes(b"raw", &aad.to_bytes()).unwrap();
+        // Same layout as the base64 API: [nonce | header | ciphertext], the ciphertext ending with a 16-byte tag
+        let header = Header::try_from(array_ref!(bytes, AES256_NONCE_LENGTH, Header::LENGTH)).unwrap();
+        assert_eq!(header.ns, 0);
+        assert_eq!(bytes.len(), AES256_NONCE_LENGTH + Header::LENGTH + b"raw".len() + 16);
+        assert_eq!(bob.decrypt(general_purpose::STANDARD.encode(&bytes), &aad.to_bytes()).unwrap(), b"raw");
+
+        let encoded = alice.encrypt(b"encoded", &aad.to_bytes()).unwrap();
+        let decoded = general_purpose::STANDARD.decode(encoded).unwrap();
+        assert_eq!(bob.decrypt_bytes(&decoded, &aad.to_bytes()).unwrap(), b"encoded");
+
+        let reply = bob.encrypt_bytes(b"reply", &aad.to_bytes()).unwrap();
+        assert!(matches!(alice.decrypt_bytes(&reply[..AES256_NONCE_LENGTH], &aad.to_bytes()), Err(RatchetError::ConversionError)));
+        assert_eq!(alice.decrypt_bytes(&reply, &aad.to_bytes()).unwrap(), b"reply");
+    }
+
+    #[test]
+    fn test_truncated_frames_are_rejected() {
+        let aad = b"associated data";
+        for header_encryption in [false, true] {
+            let bob_ratchet = RatchetKeyPair::new();
+            let sh = SharedSecret::from([0u8; 32]);
+            let (mut alice, mut bob) = if header_encryption {
+                (
+                    Ratchet::init_alice_with_header_encryption(sh.clone(), bob_ratchet.public_key.clone()),
+                    Ratchet::init_bob_with_header_encryption(sh, bob_ratchet),
+                )
+            } else {
+                (Ratchet::init_alice(sh.clone(), bob_ratchet.public_key.clone()), Ratchet::init_bob(sh, bob_ratchet))
+            };
+            let header_length = if header_encryption { Header::ENCRYPTED_LENGTH } else { Header::LENGTH };
+
+            // The first message is read last, from the skipped message keys
+            let first = alice.encrypt_bytes(b"first", aad).unwrap();
+            let second = alice.encrypt_bytes(b"second", aad).unwrap();
+            assert_eq!(bob.decrypt_bytes(&second, aad).unwrap(), b"second");
+
+            for length in 0..first.len() {
+                let result = bob.decrypt_bytes(&first[..length], aad);
+                if length < AES256_NONCE_LENGTH + header_length {
+                    assert!(matches!(result, Err(RatchetError::ConversionError)), "length {}", length);
+                } else {
+                    assert!(result.is_err(), "length {}", length);
+                }
+                let encoded = general_purpose::STANDARD.encode(&first[..length]);
+                assert!(bob.decrypt(encoded, aad).is_err(), "length {}", length);
+            }
+            assert_eq!(bob.decrypt_bytes(&first, aad).unwrap(), b"first");
+        }
+    }
+
+    #[test]
+    fn test_frames_do_not_carry_the_identity_keys() {
+        let bob_ratchet = RatchetKeyPair::new();
+        let sh = SharedSecret::from([0u8; 32]);
+        let mut alice = Ratchet::init_alice(sh.clone(), bob_ratchet.public_key.clone());
+        let mut bob = Ratchet::init_bob(sh, bob_ratchet);
+        let (alice_ik, bob_ik) = (PublicKey::from(&PrivateKey::new()), PublicKey::from(&PrivateKey::new()));
+        let aad = AssociatedData::new(alice_ik.clone(), bob_ik.clone());
+
+        let bytes = alice.encrypt_bytes(b"hello", &aad.to_bytes()).unwrap();
+        for key in [&alice_ik, &bob_ik] {
+            assert!(!bytes.windows(key.as_ref().len()).any(|window| window == key.as_ref()));
+        }
+        // The associated data is still authenticated
+        let other = AssociatedData::new(bob_ik.clone(), alice_ik.clone());
+        assert!(bob.decrypt_bytes(&bytes, &other.to_bytes()).is_err());
+        assert_eq!(bob.decrypt_bytes(&bytes, &aad.to_bytes()).unwrap(), b"hello");
+
+        // Frames of older peers carry it after the header
+        let mut legacy = alice.encrypt_bytes(b"legacy", &aad.to_bytes()).unwrap();
+        let offset = AES256_NONCE_LENGTH + Header::LENGTH;
+        legacy.splice(offset..offset, aad.to_bytes());
+        assert_eq!(bob.decrypt_bytes(&legacy, &aad.to_bytes()).unwrap(), b"legacy");
+    }
+
+    /// Sends `first_chain` messages from Alice, lets Bob reply after he read the first one, then
+    /// sends `second_chain` more on Alice's next chain. Bob reads the rest of the messages in `order`.
+    fn deliver_across_dh_step(first_chain: usize, second_chain: usize, order: &[usize]) {
+        let bob_ratchet = RatchetKeyPair::new();
+        let sh = SharedSecret::from([0u8; 32]);
+        let mut alice = Ratchet::init_alice(sh.clone(), bob_ratchet.public_key.clone());
+        let mut bob = Ratchet::init_bob(sh, bob_ratchet);
+        let aad = AssociatedData::new(PublicKey::from(&PrivateKey::new()), PublicKey::from(&PrivateKey::new()));
+        let text = |i: usize| format!("message {}", i).into_bytes();
+
+        let mut messages = (0..first_chain)
+            .map(|i| alice.encrypt(&text(i), &aad.to_bytes()).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(order[0], 0);
+        assert_eq!(bob.decrypt(messages[0].clone(), &aad.to_bytes()).unwrap(), text(0));
+
+        let reply = bob.encrypt(b"reply", &aad.to_bytes()).unwrap();
+        assert_eq!(alice.decrypt(reply, &aad.to_bytes()).unwrap(), b"reply");
+        messages.extend((first_chain..first_chain + second_chain)
+            .map(|i| alice.encrypt(&text(i), &aad.to_bytes()).unwrap()));
+
+        for &index in &order[1..] {
+            assert_eq!(bob.decrypt(messages[index].clone(), &aad.to_bytes()).unwrap(), text(index));
+        }
+        assert_eq!(bob.skipped_key_count(), 0);
+        // Both chains keep working after the late messages
+        let reply = bob.encrypt(b"done", &aad.to_bytes()).unwrap();
+        assert_eq!(alice.decrypt(reply, &aad.to_bytes()).unwrap(), b"done");
+    }
+
+    #[test]
+    fn test_out_of_order_across_dh_step() {
+        // Message 1 is left behind on the chain Alice ratcheted away from
+        deliver_across_dh_step(2, 1, &[0, 2, 1]);
+        // Message 3 skips the end of the old chain and the start of the new one
+        deliver_across_dh_step(2, 2, &[0, 3, 1, 2]);
+    }
+
+    #[test]
+    fn test_skipped_key_is_consumed_once() {
+        let bob_ratchet = RatchetKeyPair::new();
+        let sh = SharedSecret::from([0u8; 32]);
+        let mut alice = Ratchet::init_alice(sh.clone(), bob_ratchet.public_key.clone());
+        let mut bob = Ratchet::init_bob(sh, bob_ratchet);
+        let aad = AssociatedData::new(PublicKey::from(&PrivateKey::new()), PublicKey::from(&PrivateKey::new()));
+
+        let first = alice.encrypt(b"first", &aad.to_bytes()).unwrap();
+        let second = alice.encrypt(b"second", &aad.to_bytes()).unwrap();
+        assert_eq!(bob.decrypt(second, &aad.to_bytes()).unwrap(), b"second");
+        assert_eq!(bob.mk_skipped.len(), 1);
+
+        // A tampered message for the skipped slot fails without consuming the key
+        let mut tampered = general_purpose::STANDARD.decode(&first).unwrap();
+        let last = tampered.len() - 1;
+        tampered[last] ^= 1;
+        assert!(bob.decrypt(general_purpose::STANDARD.encode(tampered), &aad.to_bytes()).is_err());
+        assert_eq!(bob.mk_skipped.len(), 1);
+
+        assert_eq!(bob.decrypt(first.clone(), &aad.to_bytes()).unwrap(), b"first");
+        assert!(bob.mk_skipped.is_empty());
+        // The key is gone, so a replay falls through to the chain and is rejected
+        assert!(bob.decrypt(first, &aad.to_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_counters_follow_an_exchange_with_skips() {
+        let bob_ratchet = RatchetKeyPair::new();
+        let sh = SharedSecret::from([0u8; 32]);
+        let mut alice = Ratchet::init_alice(sh.clone(), bob_ratchet.public_key.clone());
+        let mut bob = Ratchet::init_bob(sh, bob_ratchet.clone());
+        let aad = AssociatedData::new(PublicKey::from(&PrivateKey::new()), PublicKey::from(&PrivateKey::new())).to_bytes();
+        assert_eq!(alice.current_remote_key(), Some(&bob_ratchet.public_key));
+        assert!(bob.current_remote_key().is_none());
+
+        let messages = (0..4).map(|_| alice.encrypt(b"ping", &aad).unwrap()).collect::<Vec<_>>();
+        assert_eq!(alice.messages_sent(), 4);
+        // The last one arrives first, leaving three keys behind
+        bob.decrypt(messages[3].clone(), &aad).unwrap();
+        assert_eq!(bob.messages_received(), 4);
+        assert_eq!(bob.current_remote_key(), Some(&alice.dh_sending.public_key));
+        let skipped = bob.skipped_keys().map(|(dhs, n)| (dhs.clone(), n)).collect::<Vec<_>>();
+        assert_eq!(skipped, (0..3).map(|n| (alice.dh_sending.public_key.clone(), n)).collect::<Vec<_>>());
+        bob.decrypt(messages[1].clone(), &aad).unwrap();
+        assert_eq!(bob.skipped_keys().map(|(_, n)| n).collect::<Vec<_>>(), vec![0, 2]);
+        assert_eq!(bob.messages_received(), 4);
+
+        // Bob's reply is a DH ratchet step for Alice, on a key she has not seen
+        let before = alice.current_remote_key().cloned();
+        let reply = bob.encrypt(b"pong", &aad).unwrap();
+        assert_eq!(bob.messages_sent(), 1);
+        alice.decrypt(reply, &aad).unwrap();
+        assert_ne!(alice.current_remote_key().cloned(), before);
+        assert_eq!(alice.messages_received(), 1);
+
+        // Her next message starts a new sending chain, the previous one having carried four
+        alice.encrypt(b"again", &aad).unwrap();
+        assert_eq!((alice.messages_sent(), alice.previous_chain_length()), (1, 4));
+        let snapshot = alice.state_snapshot();
+        assert_eq!((snapshot.messages_sent, snapshot.previous_chain_length), (1, 4));
+    }
+
+    #[test]
+    fn test_cleared_skipped_keys_no_longer_decrypt() {
+        let bob_ratchet = RatchetKeyPair::new();
+        let sh = SharedSecret::from([0u8; 32]);
+        let mut alice = Ratchet::init_alice(sh.clone(), bob_ratchet.public_key.clone());
+        let mut bob = Ratchet::init_bob(sh, bob_ratchet);
+        let aad = AssociatedData::new(PublicKey::from(&PrivateKey::new()), PublicKey::from(&PrivateKey::new()));
+
+        let messages = (0..4)
+            .map(|n| alice.encrypt(format!("message {}", n).as_bytes(), &aad.to_bytes()).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(bob.decrypt(messages[3].clone(), &aad.to_bytes()).unwrap(), b"message 3");
+        assert_eq!(bob.skipped_key_count(), 3);
+        assert_eq!(bob.decrypt(messages[1].clone(), &aad.to_bytes()).unwrap(), b"message 1");
+
+        bob.clear_skipped_keys();
+        assert_eq!(bob.skipped_key_count(), 0);
+        for late in [0, 2] {
+            assert!(matches!(bob.decrypt(messages[late].clone(), &aad.to_bytes()), Err(RatchetError::SkippedKeyEvicted)));
+        }
+        // Nor after a restore
+        let mut restored = Ratchet::from_bytes(&bob.to_bytes()).unwrap();
+        assert!(matches!(restored.decrypt(messages[0].clone(), &aad.to_bytes()), Err(RatchetError::SkippedKeyEvicted)));
+
+        // The session itself goes on
+        let next = alice.encrypt(b"next", &aad.to_bytes()).unwrap();
+        assert_eq!(bob.decrypt(next, &aad.to_bytes()).unwrap(), b"next");
+        let reply = bob.encrypt(b"reply", &aad.to_bytes()).unwrap();
+        assert_eq!(alice.decrypt(reply, &aad.to_bytes()).unwrap(), b"reply");
+    }
+
+    #[test]
+    fn test_skipped_keys_beyond_cap_are_evicted() {
+        let bob_ratchet = RatchetKeyPair::new();
+        let sh = SharedSecret::from([0u8; 32]);
+        let mut alice = Ratchet::init_alice(sh.clone(), bob_ratchet.public_key.clone());
+        let mut bob = Ratchet::init_bob(sh, bob_ratchet).with_max_skipped_keys(3);
+        assert_eq!(bob.max_skipped_keys(), 3);
+        let aad = AssociatedData::new(PublicKey::from(&PrivateKey::new()), PublicKey::from(&PrivateKey::new()));
+
+        let messages = (0..6)
+            .map(|n| alice.encrypt(format!("message {}", n).as_bytes(), &aad.to_bytes()).unwrap())
+            .collect::<Vec<_>>();
+
+        // Skipping five keys with room for three evicts the two oldest
+        assert_eq!(bob.decrypt(messages[5].clone(), &aad.to_bytes()).unwrap(), b"message 5");
+        assert_eq!(bob.skipped_key_count(), 3);
+        for late in [0, 1] {
+            assert!(matches!(bob.decrypt(messages[late].clone(), &aad.to_bytes()), Err(RatchetError::SkippedKeyEvicted)));
+        }
+        for late in [4, 2, 3] {
+            assert_eq!(bob.decrypt(messages[late].clone(), &aad.to_bytes()).unwrap(), format!("message {}", late).as_bytes());
+        }
+        assert_eq!(bob.skipped_key_count(), 0);
+
+        // The eviction state survives serialization
+        let state = bob.to_bytes();
+        let mut restored = Ratchet::from_bytes(&state).unwrap();
+        assert_eq!(restored.max_skipped_keys(), 3);
+        assert!(matches!(restored.decrypt(messages[0].clone(), &aad.to_bytes()), Err(RatchetError::SkippedKeyEvicted)));
+
+        let bob_ratchet = RatchetKeyPair::new();
+        assert_eq!(Ratchet::init_bob(SharedSecret::from([0u8; 32]), bob_ratchet).max_skipped_keys(), MAX_SKIPPED_KEYS);
+    }
+
+    #[test]
+    fn test_oversized_plaintext_does_not_advance_chain() {
+        let bob_ratchet = RatchetKeyPair::new();
+        let sh = SharedSecret::from([0u8; 32]);
+        let mut alice = Ratchet::init_alice(sh.clone(), bob_ratchet.public_key.clone()).with_max_plaintext_length(16);
+        let mut bob = Ratchet::init_bob(sh, bob_ratchet);
+        let aad = AssociatedData::new(PublicKey::from(&PrivateKey::new()), PublicKey::from(&PrivateKey::new()));
+        let chain_key = alice.sending_chain_key.clone().unwrap();
+
+        assert!(matches!(alice.encrypt(&[0u8; 17], &aad.to_bytes()), Err(RatchetError::PlaintextTooLong(17))));
+        assert_eq!(alice.n_messages_sent, 0);
+        assert_eq!(alice.sending_chain_key.as_ref().unwrap().as_ref(), chain_key.as_ref());
+
+        // The next message still uses the first key, so Bob reads it without skipping
+        let message = alice.encrypt(&[1u8; 16], &aad.to_bytes()).unwrap();
+        assert_eq!(bob.decrypt(message, &aad.to_bytes()).unwrap(), [1u8; 16]);
+        assert_eq!(bob.skipped_key_count(), 0);
+
+        // The limit is capped, and survives serialization
+        let restored = Ratchet::from_bytes(&alice.to_bytes()).unwrap();
+        assert_eq!(restored.max_plaintext_length(), 16);
+        assert_eq!(restored.with_max_plaintext_length(usize::MAX).max_plaintext_length(), MAX_PLAINTEXT_LENGTH);
+        assert_eq!(bob.max_plaintext_length(), MAX_PLAINTEXT_LENGTH);
+    }
+
+    /// A secret reporting through a shared flag whether it was zeroized.
+    struct Tracked(std::rc::Rc<std::cell::Cell<bool>>);
+
+    impl Zeroize for Tracked {
+        fn zeroize(&mut self) {
+            self.0.set(true);
+        }
+    }
+
+    #[test]
+    fn test_superseded_secrets_are_zeroized() {
+        let (old, new) = (Default::default(), Default::default());
+        let mut slot = Tracked(std::rc::Rc::clone(&old));
+        supersede(&mut slot, Tracked(std::rc::Rc::clone(&new)));
+        assert!(old.get());
+        assert!(!new.get());
+
+        let (mut alice, mut bob) = header_encrypted_pair();
+        let aad = AssociatedData::new(PublicKey::from(&PrivateKey::new()), PublicKey::from(&PrivateKey::new()));
+        let a0 = alice.encrypt(b"a0", &aad.to_bytes()).unwrap();
+        let a1 = alice.encrypt(b"a1", &aad.to_bytes()).unwrap();
+        bob.decrypt(a1, &aad.to_bytes()).unwrap();
+        let reply = bob.encrypt(b"reply", &aad.to_bytes()).unwrap();
+        let chain_keys = [alice.root_key.clone(), alice.sending_chain_key.clone().unwrap()];
+        let private_key = alice.dh_sending.private_key.to_bytes();
+
+        // Once a message is sent after the DH step the superseded keys are gone from the state,
+        // the previous key pair being kept until then in case bob rotates his key again
+        alice.decrypt(reply, &aad.to_bytes()).unwrap();
+        alice.encrypt(b"a2", &aad.to_bytes()).unwrap();
+        let state = alice.to_bytes();
+        for key in &chain_keys {
+            assert!(!state.windows(32).any(|window| window == key.as_ref()));
+        }
+        assert!(!state.windows(DH_SECRET_LENGTH).any(|window| window == private_key.as_slice()));
+
+        // Zeroizing wipes what is left, skipped message and header keys included
+        assert_eq!(bob.skipped_key_count(), 1);
+        bob.zeroize();
+        assert_eq!(bob.root_key.as_ref(), &[0u8; 32]);
+        assert_eq!(bob.dh_sending.private_key.to_bytes(), [0u8; DH_SECRET_LENGTH]);
+        assert!(bob.sending_chain_key.is_none() && bob.receiving_chain_key.is_none());
+        assert_eq!(bob.skipped_key_count(), 0);
+        let keys = bob.header_keys.as_ref().unwrap();
+        assert!(keys.skipped.is_empty());
+        for hk in [&keys.sending, &keys.next_sending, &keys.next_receiving] {
+            assert_eq!(hk.as_ref(), &[0u8; 32]);
+        }
+        assert!(bob.decrypt(a0, &aad.to_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_eviction_spans_dh_steps() {
+        let bob_ratchet = RatchetKeyPair::new();
+        let sh = SharedSecret::from([0u8; 32]);
+        let mut alice = Ratchet::init_alice(sh.clone(), bob_ratchet.public_key.clone());
+        let mut bob = Ratchet::init_bob(sh, bob_ratchet).with_max_skipped_keys(3);
+        let aad = AssociatedData::new(PublicKey::from(&PrivateKey::new()), PublicKey::from(&PrivateKey::new()));
+        let text = |i: usize| format!("message {}", i).into_bytes();
+
+        let mut messages = (0..4)
+            .map(|i| alice.encrypt(&text(i), &aad.to_bytes()).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(bob.decrypt(messages[0].clone(), &aad.to_bytes()).unwrap(), text(0));
+        let reply = bob.encrypt(b"reply", &aad.to_bytes()).unwrap();
+        assert_eq!(alice.decrypt(reply, &aad.to_bytes()).unwrap(), b"reply");
+        messages.extend((4..8).map(|i| alice.encrypt(&text(i), &aad.to_bytes()).unwrap()));
+
+        // Three keys are left behind on the old chain and three more skipped on the new one
+        assert_eq!(bob.decrypt(messages[7].clone(), &aad.to_bytes()).unwrap(), text(7));
+        assert_eq!(bob.skipped_key_count(), 3);
+        for late in 1..4 {
+            assert!(matches!(bob.decrypt(messages[late].clone(), &aad.to_bytes()), Err(RatchetError::SkippedKeyEvicted)));
+        }
+        for late in 4..7 {
+            assert_eq!(bob.decrypt(messages[late].clone(), &aad.to_bytes()).unwrap(), text(late));
+        }
+        assert_eq!(bob.skipped_key_count(), 0);
+    }
+
+    fn from_hex(hex: &str) -> [u8; 32] {
+        let bytes = (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap()).collect::<Vec<_>>();
+        *array_ref!(bytes, 0, 32)
+    }
+
+    #[test]
+    fn test_chain_kdf_known_answers() {
+        let ck: [u8; 32] = core::array::from_fn(|i| i as u8);
+
+        let (next, mk) = hmac_ck(SharedSecret::from(ck)).unwrap();
+        assert_eq!(next.as_ref(), &from_hex("4304c22c84a53755ab08ead8d97a8d429be5efa480682d7ad1da27f73e1fbe1d"));
+        assert_eq!(mk.as_ref(), &from_hex("9b4c8120a4823a95f47cde17a244f4507244ee6e3957d1fab9fa29b44d3829b7"));
+
+        let (next, mk) = hkdf_ck(SharedSecret::from(ck)).unwrap();
+        assert_eq!(next.as_ref(), &from_hex("a8a7b8c4c330729d60993c8a2ef5d7e6373329ee65e99de19954ef79ecd4bb59"));
+        assert_eq!(mk.as_ref(), &from_hex("9a7c69f31d0c3bb6d6b2728c20e6cde91f2fbd95433ec3c4577f4a75b0210f7b"));
+    }
+
+    #[test]
+    fn test_legacy_chain_kdf() {
+        let bob_ratchet = RatchetKeyPair::new();
+        let sh = SharedSecret::from([0u8; 32]);
+        let aad = AssociatedData::new(PublicKey::from(&PrivateKey::new()), PublicKey::from(&PrivateKey::new()));
+        let mut alice = Ratchet::init_alice(sh.clone(), bob_ratchet.public_key.clone()).with_chain_kdf(ChainKdf::LegacyHkdf);
+        let mut bob = Ratchet::init_bob(sh.clone(), bob_ratchet.clone()).with_chain_kdf(ChainKdf::LegacyHkdf);
+        assert_eq!(Ratchet::init_bob(sh.clone(), bob_ratchet.clone()).chain_kdf(), ChainKdf::Hmac);
+
+        let ciphertext = alice.encrypt(b"legacy", &aad.to_bytes()).unwrap();
+        assert_eq!(bob.decrypt(ciphertext, &aad.to_bytes()).unwrap(), b"legacy");
+
+        // A party using the other derivation cannot read the message
+        let mut spec_bob = Ratchet::init_bob(sh, bob_ratchet);
+        let ciphertext = alice.encrypt(b"mismatch", &aad.to_bytes()).unwrap();
+        assert!(spec_bob.decrypt(ciphertext, &aad.to_bytes()).is_err());
+
+        // States written before the flag existed keep the legacy derivation
+        let mut state = bob.to_bytes();
+        assert_eq!(Ratchet::from_bytes(&state).unwrap().chain_kdf(), ChainKdf::LegacyHkdf);
+        state[0] = 1;
+        // Drop the derivation flag, the plaintext limit, the AEAD suite, the rekey state: no unsent chain
+        // and no pending rotation, the conformance and the padding
+        state.truncate(state.len() - 10 - 1 - 1 - 1 - 1);
+        let mut restored = Ratchet::from_bytes(&state).unwrap();
+        assert_eq!(restored.chain_kdf(), ChainKdf::LegacyHkdf);
+        assert_eq!(restored.max_plaintext_length(), MAX_PLAINTEXT_LENGTH);
+        let reply = restored.encrypt(b"reply", &aad.to_bytes()).unwrap();
+        assert_eq!(alice.decrypt(reply, &aad.to_bytes()).unwrap(), b"reply");
+    }
+
+    #[test]
+    fn test_aead_suite_is_kept_in_the_state() {
+        let bob_ratchet = RatchetKeyPair::new();
+        let sh = SharedSecret::from([0u8; 32]);
+        let aad = AssociatedData::new(PublicKey::from(&PrivateKey::new()), PublicKey::from(&PrivateKey::new())).to_bytes();
+        let mut alice = Ratchet::init_alice(sh.clone(), bob_ratchet.public_key.clone()).with_aead_suite(AeadSuite::ChaCha20Poly1305);
+        let bob = Ratchet::init_bob(sh.clone(), bob_ratchet.clone()).with_aead_suite(AeadSuite::ChaCha20Poly1305);
+
+        // A party using the other suite cannot read the message
+        let ciphertext = alice.encrypt(b"chacha", &aad).unwrap();
+        assert!(Ratchet::init_bob(sh, bob_ratchet).decrypt(ciphertext.clone(), &aad).is_err());
+
+        let mut state = bob.to_bytes();
+        let mut restored = Ratchet::from_bytes(&state).unwrap();
+        assert_eq!(restored.aead_suite(), AeadSuite::ChaCha20Poly1305);
+        assert_eq!(restored.decrypt(ciphertext, &aad).unwrap(), b"chacha");
+
+        // The suite byte follows the plaintext limit, before the rekey state: no unsent chain, no pending rotation,
+        // the conformance and the padding
+        let suite_offset = state.len() - 1 - 1 - 1 - 1 - 1;
+        assert_eq!(state[suite_offset], AeadSuite::ChaCha20Poly1305.id());
+        state[suite_offset] = 7;
+        assert!(matches!(Ratchet::from_bytes(&state), Err(RatchetError::InvalidState)));
+
+        // States written before the suite existed are restored with AES-256-GCM
+        state[0] = 4;
+        state.truncate(state.len() - 2);
+        state.remove(suite_offset);
+        assert_eq!(Ratchet::from_bytes(&state).unwrap().aead_suite(), AeadSuite::Aes256Gcm);
+    }
+
+    #[test]
+    fn test_conformance_is_kept_in_the_state() {
+        let bob_ratchet = RatchetKeyPair::new();
+        let sh = SharedSecret::from([0u8; 32]);
+        let aad = AssociatedData::new(PublicKey::from(&PrivateKey::new()), PublicKey::from(&PrivateKey::new())).to_bytes();
+        let config = RatchetConfig { conformance: Conformance::Spec, ..RatchetConfig::default() };
+        let mut alice = Ratchet::init_alice_with_config(sh.clone(), bob_ratchet.public_key.clone(), config);
+        let bob = Ratchet::init_bob_with_config(sh.clone(), bob_ratchet.clone(), config);
+        assert_eq!(Ratchet::init_bob(sh.clone(), bob_ratchet.clone()).conformance(), Conformance::Native);
+
+        // A party deriving its root keys natively cannot read the message
+        let ciphertext = alice.encrypt(b"spec", &aad).unwrap();
+        assert!(Ratchet::init_bob(sh, bob_ratchet).decrypt(ciphertext.clone(), &aad).is_err());
+
+        let mut state = bob.to_bytes();
+        let mut restored = Ratchet::from_bytes(&state).unwrap();
+        assert_eq!(restored.conformance(), Conformance::Spec);
+        assert_eq!(restored.decrypt(ciphertext, &aad).unwrap(), b"spec");
+
+        // The conformance byte comes last but for the padding, a single byte without padding
+        let offset = state.len() - 2;
+        assert_eq!(state[offset], Conformance::Spec.id());
+        state[offset] = 7;
+        assert!(matches!(Ratchet::from_bytes(&state), Err(RatchetError::InvalidState)));
+
+        // States written before the conformance existed are restored natively
+        state[0] = 5;
+        state.truncate(offset);
+        assert_eq!(Ratchet::from_bytes(&state).unwrap().conformance(), Conformance::Native);
+    }
+
+    /// Returns a ratchet for Alice and one for Bob sharing a session, both with `padding`.
+    fn padded_pair(padding: Padding) -> (Ratchet, Ratchet) {
+        let bob_ratchet = RatchetKeyPair::new();
+        let sh = SharedSecret::from([0u8; 32]);
+        let config = RatchetConfig { padding, ..RatchetConfig::default() };
+        (
+            Ratchet::init_alice_with_config(sh.clone(), bob_ratchet.public_key.clone(), config),
+            Ratchet::init_bob_with_config(sh, bob_ratchet, config),
+        )
+    }
+
+    #[test]
+    fn test_padding_hides_lengths_within_a_bucket() {
+        let (mut alice, mut bob) = padded_pair(Padding::Buckets(256));
+        let aad = AssociatedData::new(PublicKey::from(&PrivateKey::new()), PublicKey::from(&PrivateKey::new())).to_bytes();
+        let overhead = alice.clone().with_padding(Padding::None).encrypt_bytes(b"", &aad).unwrap().len();
+
+        // The empty message, one filling a bucket but for the padding byte, and one filling it exactly
+        for (length, padded) in [(0, 256), (1, 256), (255, 256), (256, 512), (300, 512)] {
+            let plaintext = vec![0u8; length];
+            let ciphertext = alice.encrypt_bytes(&plaintext, &aad).unwrap();
+            assert_eq!(ciphertext.len(), overhead + padded, "{} bytes", length);
+            assert_eq!(bob.decrypt_bytes(&ciphertext, &aad).unwrap(), plaintext);
+        }
+
+        // Trailing zeros and 0x80 bytes of the plaintext are not taken for padding
+        let tricky = [b'a', 0x80, 0, 0x80, 0];
+        let ciphertext = bob.encrypt(&tricky, &aad).unwrap();
+        assert_eq!(alice.decrypt(ciphertext, &aad).unwrap(), tricky);
+    }
+
+    #[test]
+    fn test_padding_of_the_largest_plaintext() {
+        let (alice, bob) = padded_pair(Padding::Buckets(1000));
+        let (mut alice, mut bob) = (alice.with_max_plaintext_length(4500), bob.with_max_plaintext_length(4500));
+        let aad = AssociatedData::new(PublicKey::from(&PrivateKey::new()), PublicKey::from(&PrivateKey::new())).to_bytes();
+        let overhead = alice.clone().with_padding(Padding::None).encrypt_bytes(b"", &aad).unwrap().len();
+
+        // The largest plaintext takes the last bucket, and one byte more is refused before padding
+        let largest = vec![7u8; 4500];
+        let ciphertext = alice.encrypt_bytes(&largest, &aad).unwrap();
+        assert_eq!(ciphertext.len(), overhead + 5000);
+        assert_eq!(bob.decrypt_bytes(&ciphertext, &aad).unwrap(), largest);
+        assert!(matches!(alice.encrypt_bytes(&[7u8; 4501], &aad), Err(RatchetError::PlaintextTooLong(4501))));
+
+        // Buckets are capped at the plaintext limit
+        assert_eq!(alice.clone().with_padding(Padding::Buckets(0)).padding(), Padding::Buckets(1));
+        assert_eq!(alice.with_padding(Padding::Buckets(usize::MAX)).padding(), Padding::Buckets(MAX_PLAINTEXT_LENGTH));
+    }
+
+    #[test]
+    fn test_padding_stays_within_the_plaintext_limit() {
+        let (mut alice, mut bob) = padded_pair(Padding::Buckets(1000));
+        let aad = AssociatedData::new(PublicKey::from(&PrivateKey::new()), PublicKey::from(&PrivateKey::new())).to_bytes();
+        let overhead = alice.clone().with_padding(Padding::None).encrypt_bytes(b"", &aad).unwrap().len();
+        assert_ne!(MAX_PLAINTEXT_LENGTH % 1000, 0);
+
+        // The last bucket is cut short at the limit
+        let largest = vec![7u8; MAX_PLAINTEXT_LENGTH - 1];
+        let ciphertext = alice.encrypt_bytes(&largest, &aad).unwrap();
+        assert_eq!(ciphertext.len(), overhead + MAX_PLAINTEXT_LENGTH);
+        assert_eq!(bob.decrypt_bytes(&ciphertext, &aad).unwrap(), largest);
+
+        // And a plaintext leaving no room for the padding byte is refused
+        let full = vec![7u8; MAX_PLAINTEXT_LENGTH];
+        assert!(matches!(alice.encrypt_bytes(&full, &aad), Err(RatchetError::PlaintextTooLong(n)) if n == MAX_PLAINTEXT_LENGTH));
+    }
+
+    #[test]
+    fn test_mixed_padding_fails_loudly() {
+        let (mut alice, padded_bob) = padded_pair(Padding::Buckets(256));
+        let unpadded_bob = padded_bob.clone().with_padding(Padding::None);
+        let aad = AssociatedData::new(PublicKey::from(&PrivateKey::new()), PublicKey::from(&PrivateKey::new())).to_bytes();
+
+        let padded = alice.encrypt(b"padded", &aad).unwrap();
+        let mut bob = unpadded_bob.clone();
+        assert!(matches!(bob.decrypt(padded.clone(), &aad), Err(RatchetError::PaddingMismatch(true))));
+        // The rejected message leaves the ratchet untouched
+        assert_eq!(bob.messages_received(), 0);
+
+        let mut unpadded_alice = alice.clone().with_padding(Padding::None);
+        let unpadded = unpadded_alice.encrypt(b"unpadded", &aad).unwrap();
+        assert!(matches!(padded_bob.clone().decrypt(unpadded, &aad), Err(RatchetError::PaddingMismatch(false))));
+
+        // The padding is kept in the state
+        let mut restored = Ratchet::from_bytes(&padded_bob.to_bytes()).unwrap();
+        assert_eq!(restored.padding(), Padding::Buckets(256));
+        assert_eq!(restored.decrypt(padded, &aad).unwrap(), b"padded");
+    }
+
+    #[test]
+    fn test_state_snapshot_follows_the_chains() {
+        let bob_ratchet = RatchetKeyPair::new();
+        let sh = SharedSecret::from([0u8; 32]);
+        let mut alice = Ratchet::init_alice(sh.clone(), bob_ratchet.public_key.clone());
+        let mut bob = Ratchet::init_bob(sh, bob_ratchet.clone());
+        let aad = AssociatedData::new(PublicKey::from(&PrivateKey::new()), PublicKey::from(&PrivateKey::new())).to_bytes();
+        assert_eq!(bob.state_snapshot(), RatchetSnapshot {
+            sending_key: bob_ratchet.public_key.clone(),
+            messages_sent: 0,
+            messages_received: 0,
+            previous_chain_length: 0,
+        });
+
+        for _ in 0..2 {
+            let ciphertext = alice.encrypt(b"ping", &aad).unwrap();
+            bob.decrypt(ciphertext, &aad).unwrap();
+        }
+        let alice_key = alice.state_snapshot().sending_key;
+        assert_eq!(alice.state_snapshot().messages_sent, 2);
+        assert_eq!(bob.state_snapshot().messages_received, 2);
+
+        // The reply of Bob comes from a new ratchet key, and the next message of Alice closes her first chain
+        let ciphertext = bob.encrypt(b"pong", &aad).unwrap();
+        assert_ne!(bob.state_snapshot().sending_key, bob_ratchet.public_key);
+        assert_eq!(bob.state_snapshot().messages_sent, 1);
+        alice.decrypt(ciphertext, &aad).unwrap();
+        let snapshot = alice.state_snapshot();
+        assert_eq!(snapshot.sending_key, alice_key);
+        assert_eq!((snapshot.messages_sent, snapshot.messages_received, snapshot.previous_chain_length), (2, 1, 0));
+        alice.encrypt(b"ping", &aad).unwrap();
+        let snapshot = alice.state_snapshot();
+        assert_ne!(snapshot.sending_key, alice_key);
+        assert_eq!((snapshot.messages_sent, snapshot.messages_received, snapshot.previous_chain_length), (1, 1, 2));
+    }
+
+    #[test]
+    fn test_message_meta_matches_header() {
+        let bob_ratchet = RatchetKeyPair::new();
+        let sh = SharedSecret::from([0u8; 32]);
+        let mut alice = Ratchet::init_alice(sh.clone(), bob_ratchet.public_key.clone());
+        let mut bob = Ratchet::init_bob(sh, bob_ratchet);
+        let aad = AssociatedData::new(PublicKey::from(&PrivateKey::new()), PublicKey::from(&PrivateKey::new()));
+        let header_of = |ciphertext: &str| {
+            let bytes = general_purpose::STANDARD.decode(ciphertext).unwrap();
+            Header::try_from(array_ref!(bytes, AES256_NONCE_LENGTH, Header::LENGTH)).unwrap()
+        };
+
+        // Two messages from Alice, a reply from Bob, then Alice again on a new DH generation
+        let turns: [(bool, &[u8]); 4] = [(true, b"one"), (true, b"two"), (false, b"three"), (true, b"four")];
+        let mut alice_keys = vec![];
+        for (from_alice, plaintext) in turns {
+            let (sender, receiver) = if from_alice { (&mut alice, &mut bob) } else { (&mut bob, &mut alice) };
+            let (ciphertext, sent) = sender.encrypt_with_meta(plaintext, &aad.to_bytes()).unwrap();
+            let (decrypted, received) = receiver.decrypt_with_meta(ciphertext.clone(), &aad).unwrap();
+            assert_eq!(decrypted, plaintext);
+            assert_eq!(sent, received);
+
+            let header = header_of(&ciphertext);
+            assert_eq!(sent.ratchet_key, header.dhs.hash().0.iter().map(|b| format!("{:02x}", b)).collect::<String>());
+            assert_eq!(sent.previous_chain_length, header.pn);
+            assert_eq!(sent.message_number, header.ns);
+            assert!(!sent.header_encrypted);
+            assert_eq!(sent.cipher_suite, format!("{}_AES256GCM_HMAC_SHA256", Curve::ACTIVE));
+            if from_alice {
+                alice_keys.push(sent);
+            }
+        }
+        assert_eq!(alice_keys[0].ratchet_key, alice_keys[1].ratchet_key);
+        assert_eq!((alice_keys[1].previous_chain_length, alice_keys[1].message_number), (0, 1));
+        assert_ne!(alice_keys[1].ratchet_key, alice_keys[2].ratchet_key);
+        assert_eq!((alice_keys[2].previous_chain_length, alice_keys[2].message_number), (2, 0));
+    }
+
+    #[test]
+    fn test_message_meta_with_header_encryption() {
+        let (mut alice, mut bob) = header_encrypted_pair();
+        let aad = AssociatedData::new(PublicKey::from(&PrivateKey::new()), PublicKey::from(&PrivateKey::new()));
+
+        let (ciphertext, sent) = alice.encrypt_with_meta(b"hidden", &aad.to_bytes()).unwrap();
+        let (_, received) = bob.decrypt_with_meta(ciphertext, &aad).unwrap();
+        assert_eq!(sent, received);
+        assert!(received.header_encrypted);
+        assert_eq!(received.message_number, 0);
+    }
+
+    /// Rotates the key of `alice` while bob still has a message of her current chain to receive, and checks that
+    /// bob follows the rotation, out of order, and that both keep talking afterwards.
+    fn converse_across_rekey(mut alice: Ratchet, mut bob: Ratchet) {
+        let aad = AssociatedData::new(PublicKey::from(&PrivateKey::new()), PublicKey::from(&PrivateKey::new()));
+        let (a1, before) = alice.encrypt_with_meta(b"a1", &aad.to_bytes()).unwrap();
+        let a2 = alice.encrypt(b"a2", &aad.to_bytes()).unwrap();
+        assert_eq!(bob.decrypt(a1, &aad.to_bytes()).unwrap(), b"a1");
+
+        alice.force_rekey();
+        assert!(alice.rekey_pending());
+        let (a3, after) = alice.encrypt_with_meta(b"a3", &aad.to_bytes()).unwrap();
+        let a4 = alice.encrypt(b"a4", &aad.to_bytes()).unwrap();
+        assert!(!alice.rekey_pending());
+        assert_ne!(after.ratchet_key, before.ratchet_key);
+        assert_eq!((after.previous_chain_length, after.message_number), (2, 0));
+
+        assert_eq!(bob.decrypt(a4, &aad.to_bytes()).unwrap(), b"a4");
+        assert_eq!(bob.decrypt(a3, &aad.to_bytes()).unwrap(), b"a3");
+        assert_eq!(bob.decrypt(a2, &aad.to_bytes()).unwrap(), b"a2");
+        let reply = bob.encrypt(b"reply", &aad.to_bytes()).unwrap();
+        assert_eq!(alice.decrypt(reply, &aad.to_bytes()).unwrap(), b"reply");
+        let again = alice.encrypt(b"again", &aad.to_bytes()).unwrap();
+        assert_eq!(bob.decrypt(again, &aad.to_bytes()).unwrap(), b"again");
+    }
+
+    #[test]
+    fn test_oversized_plaintext_does_not_perform_a_pending_rekey() {
+        let (mut alice, mut bob) = header_encrypted_pair();
+        let aad = AssociatedData::new(PublicKey::from(&PrivateKey::new()), PublicKey::from(&PrivateKey::new())).to_bytes();
+        let hello = alice.encrypt(b"hello", &aad).unwrap();
+        assert_eq!(bob.decrypt(hello, &aad).unwrap(), b"hello");
+
+        bob.force_rekey();
+        let before = bob.state_snapshot();
+        let oversized = vec![0u8; bob.max_plaintext_length() + 1];
+        assert!(matches!(bob.encrypt_with_meta(&oversized, &aad), Err(RatchetError::PlaintextTooLong(_))));
+        assert!(matches!(bob.encrypt(&oversized, &aad), Err(RatchetError::PlaintextTooLong(_))));
+        assert_eq!(bob.state_snapshot(), before);
+        assert!(bob.rekey_pending());
+
+        // The next message performs the rotation
+        let (reply, _) = bob.encrypt_with_meta(b"reply", &aad).unwrap();
+        assert_ne!(bob.state_snapshot().sending_key, before.sending_key);
+        assert_eq!(alice.decrypt(reply, &aad).unwrap(), b"reply");
+    }
+
+    #[test]
+    fn test_force_rekey_is_followed_by_the_peer() {
+        let bob_ratchet = RatchetKeyPair::new();
+        let sh = SharedSecret::from([0u8; 32]);
+        let alice = Ratchet::init_alice(sh.clone(), bob_ratchet.public_key.clone());
+        let bob = Ratchet::init_bob(sh, bob_ratchet);
+        converse_across_rekey(alice, bob);
+
+        let (alice, bob) = header_encrypted_pair();
+        converse_across_rekey(alice, bob);
+    }
+
+    #[test]
+    fn test_rekey_locks_out_the_previous_sending_chain() {
+        let bob_ratchet = RatchetKeyPair::new();
+        let sh = SharedSecret::from([0u8; 32]);
+        let mut alice = Ratchet::init_alice(sh.clone(), bob_ratchet.public_key.clone());
+        let mut bob = Ratchet::init_bob(sh, bob_ratchet);
+        let aad = AssociatedData::new(PublicKey::from(&PrivateKey::new()), PublicKey::from(&PrivateKey::new())).to_bytes();
+        let first = alice.encrypt(b"first", &aad).unwrap();
+        assert_eq!(bob.decrypt(first, &aad).unwrap(), b"first");
+
+        // Whoever stole Alice's state holds the keys of her sending chain, but none of Bob's private keys
+        let mut stolen = bob.clone();
+        supersede(&mut stolen.dh_sending, RatchetKeyPair::new());
+        supersede(&mut stolen.unsent_chain, None);
+        let before = alice.clone();
+        let on_old_chain = before.clone().encrypt(b"old", &aad).unwrap();
+        assert_eq!(stolen.clone().decrypt(on_old_chain, &aad).unwrap(), b"old");
+
+        alice.force_rekey();
+        let (ciphertext, meta) = alice.encrypt_with_meta(b"new", &aad).unwrap();
+        assert!(!alice.rekey_pending());
+        assert_ne!(alice.state_snapshot().sending_key, before.state_snapshot().sending_key);
+        assert_eq!(alice.previous_chain_length(), 1);
+        let (_, old_meta) = before.clone().encrypt_with_meta(b"old", &aad).unwrap();
+        assert_ne!(meta.ratchet_key, old_meta.ratchet_key);
+        assert_eq!((meta.previous_chain_length, meta.message_number), (1, 0));
+        assert!(stolen.decrypt(ciphertext.clone(), &aad).is_err());
+        assert_eq!(bob.decrypt(ciphertext, &aad).unwrap(), b"new");
+
+        // Before the first message of the peer the rotation waits for it
+        let bob_ratchet = RatchetKeyPair::new();
+        let mut responder = Ratchet::init_bob(SharedSecret::from([1u8; 32]), bob_ratchet);
+        responder.force_rekey();
+        responder.encrypt(b"early", &aad).unwrap();
+        assert!(responder.rekey_pending());
+    }
+
+    #[test]
+    fn test_force_rekey_before_sending_replaces_the_unsent_chain() {
+        let (mut alice, mut bob) = header_encrypted_pair();
+        let aad = AssociatedData::new(PublicKey::from(&PrivateKey::new()), PublicKey::from(&PrivateKey::new()));
+
+        // Bob has no key of alice to rotate against yet, so his initial chain is kept
+        bob.force_rekey();
+        let early = bob.encrypt(b"early", &aad.to_bytes()).unwrap();
+        assert!(bob.rekey_pending());
+        assert_eq!(alice.decrypt(early, &aad.to_bytes()).unwrap(), b"early");
+
+        // The DH step taken on alice's message rotates his key anyway
+        let hello = alice.encrypt(b"hello", &aad.to_bytes()).unwrap();
+        assert_eq!(bob.decrypt(hello, &aad.to_bytes()).unwrap(), b"hello");
+        assert!(!bob.rekey_pending());
+
+        // Nothing was sent on bob's new chain, so rotating twice replaces it twice
+        bob.force_rekey();
+        bob.force_rekey();
+        let reply = bob.encrypt(b"reply", &aad.to_bytes()).unwrap();
+        assert_eq!(alice.decrypt(reply, &aad.to_bytes()).unwrap(), b"reply");
+
+        // Alice rotates before answering, then answers
+        alice.force_rekey();
+        let answer = alice.encrypt(b"answer", &aad.to_bytes()).unwrap();
+        assert_eq!(bob.decrypt(answer, &aad.to_bytes()).unwrap(), b"answer");
+    }
+
+    #[test]
+    fn test_dh_step_keeps_no_previous_sending_state() {
+        let (mut alice, mut bob) = header_encrypted_pair();
+        let aad = AssociatedData::new(PublicKey::from(&PrivateKey::new()), PublicKey::from(&PrivateKey::new())).to_bytes();
+        let a1 = alice.encrypt(b"a1", &aad).unwrap();
+        assert_eq!(bob.decrypt(a1, &aad).unwrap(), b"a1");
+
+        // The sending half of Bob's step waits for his next message, and no previous state is kept meanwhile
+        assert!(bob.unsent_chain.is_none() && bob.sending_chain_key.is_none());
+        assert_eq!(Ratchet::from_bytes(&bob.to_bytes()).unwrap().to_bytes(), bob.to_bytes());
+
+        // Alice rotates before Bob answers, and Bob follows with the key she last saw
+        alice.force_rekey();
+        let a2 = alice.encrypt(b"a2", &aad).unwrap();
+        assert_eq!(bob.decrypt(a2, &aad).unwrap(), b"a2");
+        let reply = bob.encrypt(b"reply", &aad).unwrap();
+        assert_eq!(alice.decrypt(reply, &aad).unwrap(), b"reply");
+
+        // Nothing of the state before a rotation is kept once the message that performs it is sent
+        bob.force_rekey();
+        assert!(bob.rekey_pending());
+        let rotated = bob.encrypt(b"rotated", &aad).unwrap();
+        assert!(bob.unsent_chain.is_none() && !bob.rekey_pending());
+        assert_eq!(alice.decrypt(rotated, &aad).unwrap(), b"rotated");
+        let a3 = alice.encrypt(b"a3", &aad).unwrap();
+        assert_eq!(bob.decrypt(a3, &aad).unwrap(), b"a3");
+        let again = bob.encrypt(b"again", &aad).unwrap();
+        assert_eq!(alice.decrypt(again, &aad).unwrap(), b"again");
+    }
+
+    #[test]
+    fn test_force_rekey_forgets_the_previous_chain() {
+        let (mut alice, mut bob) = header_encrypted_pair();
+        let aad = AssociatedData::new(PublicKey::from(&PrivateKey::new()), PublicKey::from(&PrivateKey::new()));
+        let a1 = alice.encrypt(b"a1", &aad.to_bytes()).unwrap();
+        bob.decrypt(a1, &aad.to_bytes()).unwrap();
+        let reply = bob.encrypt(b"reply", &aad.to_bytes()).unwrap();
+        alice.decrypt(reply, &aad.to_bytes()).unwrap();
+        let a2 = alice.encrypt(b"a2", &aad.to_bytes()).unwrap();
+        bob.decrypt(a2, &aad.to_bytes()).unwrap();
+
+        // What a copy of the state taken before the rotation holds
+        let stolen = alice.clone();
+        let keys = stolen.header_keys.as_ref().unwrap();
+        let secrets = [
+            stolen.root_key.as_ref().to_vec(),
+            stolen.sending_chain_key.as_ref().unwrap().as_ref().to_vec(),
+            stolen.dh_sending.private_key.to_bytes().to_vec(),
+            keys.sending.as_ref().to_vec(),
+        ];
+
+        alice.force_rekey();
+        let a3 = alice.encrypt(b"a3", &aad.to_bytes()).unwrap();
+        assert_eq!(bob.decrypt(a3, &aad.to_bytes()).unwrap(), b"a3");
+        let state = alice.to_bytes();
+        for secret in &secrets {
+            assert!(!state.windows(32).any(|window| window == secret.as_slice()));
+        }
+
+        // The next message key of the stolen chain is not the one of the rotated chain
+        let (_, stolen_mk) = stolen.kdf_ck(stolen.sending_chain_key.clone().unwrap()).unwrap();
+        let (_, mk) = alice.kdf_ck(alice.sending_chain_key.clone().unwrap()).unwrap();
+        assert_ne!(stolen_mk.as_ref(), mk.as_ref());
+    }
+
+    #[test]
+    fn test_pending_rekey_survives_serialization() {
+        let (mut alice, mut bob) = header_encrypted_pair();
+        let aad = AssociatedData::new(PublicKey::from(&PrivateKey::new()), PublicKey::from(&PrivateKey::new()));
+        let hello = alice.encrypt(b"hello", &aad.to_bytes()).unwrap();
+        assert_eq!(bob.decrypt(hello, &aad.to_bytes()).unwrap(), b"hello");
+
+        // Bob keeps his unsent chain and a pending rotation across a restart
+        bob.force_rekey();
+        let mut restored = Ratchet::from_bytes(&bob.to_bytes()).unwrap();
+        assert!(restored.rekey_pending());
+        assert_eq!(restored.to_bytes(), bob.to_bytes());
+        let reply = restored.encrypt(b"reply", &aad.to_bytes()).unwrap();
+        assert_eq!(alice.decrypt(reply, &aad.to_bytes()).unwrap(), b"reply");
+    }
+}
@@ -0,0 +1,334 @@
+//! This module encrypts payloads too large to be held twice in memory, such as attachments, as a stream of
+//! chunks over a [`Ratchet`].
+//!
+//! Each chunk is a ratchet message of its own, so it is encrypted with the next message key of the sending
+//! chain, and is framed with its index in the stream, whether it is the last one, and the random id of the stream:
+//!
+//! ```text
+//! index (8) | last (1) | stream id (16) | ratchet message
+//! ```
+//!
+//! The index is little-endian. The fields are in the clear, for the receiver to check the order before
+//! decrypting, and are appended to the associated data of the message, so that a chunk moved, dropped or
+//! marked last by the relay fails authentication. The stream id is that of the first chunk for every other one,
+//! so chunks of two streams sent on the same chain cannot be spliced together. A stream that ends before its
+//! last chunk is rejected.
+
+use std::io::{Read, Write};
+use arrayref::array_ref;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use zeroize::Zeroizing;
+use crate::errors::RatchetError;
+use crate::ratchet::Ratchet;
+
+/// Byte size of the random id shared by the chunks of a stream.
+pub const STREAM_ID_LENGTH: usize = 16;
+
+/// Byte size of the frame of a chunk before its ratchet message: the index, the last flag and the stream id.
+pub const CHUNK_HEADER_LENGTH: usize = 8 + 1 + STREAM_ID_LENGTH;
+
+/// The associated data of a chunk: that of the stream followed by the frame of the chunk.
+fn chunk_aad(aad: &[u8], frame: &[u8; CHUNK_HEADER_LENGTH]) -> Vec<u8> {
+    let mut chunk_aad = aad.to_vec();
+    chunk_aad.extend_from_slice(frame);
+    chunk_aad
+}
+
+/// The frame of the chunk at `index` of the stream `stream_id`.
+fn chunk_header(index: u64, last: bool, stream_id: &[u8; STREAM_ID_LENGTH]) -> [u8; CHUNK_HEADER_LENGTH] {
+    let mut frame = [0u8; CHUNK_HEADER_LENGTH];
+    frame[..8].copy_from_slice(&index.to_le_bytes());
+    frame[8] = last as u8;
+    frame[9..].copy_from_slice(stream_id);
+    frame
+}
+
+/// The chunks of a payload encrypted by [`Ratchet::encrypt_stream`], read from `reader` one at a time.
+///
+/// The iterator ends after the last chunk, or after the first error.
+pub struct ChunkEncryptor<'a, R: Read> {
+    ratchet: &'a mut Ratchet,
+    reader: R,
+    chunk_size: usize,
+    aad: Vec<u8>,
+    /// The random id carried by every chunk.
+    stream_id: [u8; STREAM_ID_LENGTH],
+    /// The index of the next chunk.
+    index: u64,
+    /// The plaintext of the next chunk, read ahead to know whether the current one is the last.
+    next: Option<Zeroizing<Vec<u8>>>,
+    done: bool,
+}
+
+impl<R: Read> ChunkEncryptor<'_, R> {
+    /// Reads up to a chunk of plaintext, less only at the end of the payload.
+    fn read_chunk(&mut self) -> Result<Zeroizing<Vec<u8>>, RatchetError> {
+        let mut chunk = Zeroizing::new(Vec::with_capacity(self.chunk_size));
+        (&mut self.reader).take(self.chunk_size as u64).read_to_end(&mut chunk).map_err(RatchetError::StreamIo)?;
+        Ok(chunk)
+    }
+
+    /// Encrypts the next chunk, see [`ChunkEncryptor`].
+    fn encrypt_next(&mut self) -> Result<Vec<u8>, RatchetError> {
+        let chunk = match self.next.take() {
+            Some(chunk) => chunk,
+            None => self.read_chunk()?,
+        };
+        // A full chunk may be followed by nothing, only reading on tells
+        let next = match chunk.len() == self.chunk_size {
+            true => self.read_chunk()?,
+            false => Zeroizing::new(Vec::new()),
+        };
+        let last = next.is_empty();
+        let frame = chunk_header(self.index, last, &self.stream_id);
+        let message = self.ratchet.encrypt_bytes(&chunk, &chunk_aad(&self.aad, &frame))?;
+
+        self.index += 1;
+        self.done = last;
+        self.next = Some(next).filter(|_| !last);
+        let mut output = frame.to_vec();
+        output.extend_from_slice(&message);
+        Ok(output)
+    }
+}
+
+impl<R: Read> Iterator for ChunkEncryptor<'_, R> {
+    type Item = Result<Vec<u8>, RatchetError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let chunk = self.encrypt_next();
+        self.done |= chunk.is_err();
+        Some(chunk)
+    }
+}
+
+impl Ratchet {
+    /// Encrypts the payload read from `reader` as a stream of chunks of `chunk_size` bytes, the last one
+    /// shorter, each with its own message key of the sending chain. See the [module documentation](self).
+    ///
+    /// The payload is read as the chunks are taken from the iterator, so at most two chunks of plaintext and
+    /// one of ciphertext are held at a time. An empty payload is sent as a single empty chunk.
+    ///
+    /// # Arguments
+    ///
+    /// * `reader` - The payload.
+    /// * `chunk_size` - The byte size of the plaintext of a chunk.
+    /// * `aad` - Associated data to authenticate every chunk with.
+    ///
+    /// # Returns
+    ///
+    /// * [`ChunkEncryptor`] - The iterator over the encrypted chunks.
+    ///
+    /// # Errors
+    ///
+    /// * [`RatchetError::InvalidChunkSize`] - Returned if `chunk_size` is zero or larger than [`Ratchet::max_plaintext_length`].
+    /// * [`RatchetError::StreamIo`] - Yielded if reading the payload fails.
+    /// * See [`Ratchet::encrypt`] for the errors yielded when encrypting a chunk.
+    pub fn encrypt_stream<R: Read>(&mut self, reader: R, chunk_size: usize, aad: &[u8]) -> Result<ChunkEncryptor<'_, R>, RatchetError> {
+        if chunk_size == 0 || chunk_size > self.max_plaintext_length() {
+            return Err(RatchetError::InvalidChunkSize(chunk_size));
+        }
+        let mut stream_id = [0u8; STREAM_ID_LENGTH];
+        OsRng.fill_bytes(&mut stream_id);
+        Ok(ChunkEncryptor { ratchet: self, reader, chunk_size, aad: aad.to_vec(), stream_id, index: 0, next: None, done: false })
+    }
+
+    /// Decrypts a stream of chunks produced by [`Ratchet::encrypt_stream`], writing the payload to `writer`
+    /// one chunk at a time.
+    ///
+    /// The chunks must come in order, carry the stream id of the first one, and the stream must end with its last
+    /// chunk. The chunks decrypted before an error have been written, and their message keys used.
+    ///
+    /// # Arguments
+    ///
+    /// * `chunks` - The encrypted chunks, in the order they were produced.
+    /// * `writer` - Where the payload is written.
+    /// * `aad` - The associated data the chunks were encrypted with.
+    ///
+    /// # Returns
+    ///
+    /// * `u64` - The byte size of the payload.
+    ///
+    /// # Errors
+    ///
+    /// * [`RatchetError::ChunkOutOfOrder`] - Returned if a chunk is not the one expected, or follows the last one.
+    /// * [`RatchetError::ForeignChunk`] - Returned if a chunk belongs to another stream than the first one.
+    /// * [`RatchetError::TruncatedStream`] - Returned if the chunks end before the last one.
+    /// * [`RatchetError::ConversionError`] - Returned if a chunk is too short to hold its frame.
+    /// * [`RatchetError::StreamIo`] - Returned if writing the payload fails.
+    /// * See [`Ratchet::decrypt`] for the errors of decrypting a chunk.
+    pub fn decrypt_stream<I, W>(&mut self, chunks: I, mut writer: W, aad: &[u8]) -> Result<u64, RatchetError>
+    where
+        I: IntoIterator,
+        I::Item: AsRef<[u8]>,
+        W: Write,
+    {
+        let mut expected = 0u64;
+        let mut stream_id = None;
+        let mut finished = false;
+        let mut length = 0u64;
+        for chunk in chunks {
+            let chunk = chunk.as_ref();
+            if chunk.len() < CHUNK_HEADER_LENGTH {
+                return Err(RatchetError::ConversionError);
+            }
+            let frame = array_ref!(chunk, 0, CHUNK_HEADER_LENGTH);
+            let index = u64::from_le_bytes(*array_ref!(frame, 0, 8));
+            if finished || index != expected {
+                return Err(RatchetError::ChunkOutOfOrder { expected, found: index });
+            }
+            let id = *array_ref!(frame, 9, STREAM_ID_LENGTH);
+            if *stream_id.get_or_insert(id) != id {
+                return Err(RatchetError::ForeignChunk);
+            }
+            let plaintext = Zeroizing::new(self.decrypt_bytes(&chunk[CHUNK_HEADER_LENGTH..], &chunk_aad(aad, frame))?);
+            writer.write_all(&plaintext).map_err(RatchetError::StreamIo)?;
+
+            length += plaintext.len() as u64;
+            finished = frame[8] != 0;
+            expected += 1;
+        }
+        if !finished {
+            return Err(RatchetError::TruncatedStream);
+        }
+        writer.flush().map_err(RatchetError::StreamIo)?;
+        Ok(length)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ratchet::RatchetKeyPair;
+    use crate::utils::{AssociatedData, PrivateKey, PublicKey, SharedSecret};
+    use sha2::{Digest, Sha256};
+
+    fn session() -> (Ratchet, Ratchet, Vec<u8>) {
+        let bob_key = PrivateKey::new();
+        let bob_public = PublicKey::from(&bob_key);
+        let sh = SharedSecret::from([0u8; 32]);
+        let alice = Ratchet::init_alice(sh.clone(), bob_public.clone());
+        let bob = Ratchet::init_bob(sh, RatchetKeyPair::new_from(bob_key, bob_public));
+        let aad = AssociatedData::new(PublicKey::from(&PrivateKey::new()), PublicKey::from(&PrivateKey::new())).to_bytes();
+        (alice, bob, aad)
+    }
+
+    /// Hashes what is written to it, so that the payload is checked without being kept.
+    struct HashWriter(Sha256);
+
+    impl Write for HashWriter {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.update(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_large_payload_round_trip() {
+        let (mut alice, mut bob, aad) = session();
+        let payload = (0..10 * 1024 * 1024).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+
+        // The chunks go from one ratchet to the other as they are produced, the ciphertext is never whole
+        let mut count = 0;
+        let chunks = alice.encrypt_stream(payload.as_slice(), 64 * 1024, &aad).unwrap()
+            .inspect(|_| count += 1)
+            .map(Result::unwrap);
+        let mut hash = HashWriter(Sha256::new());
+        assert_eq!(bob.decrypt_stream(chunks, &mut hash, &aad).unwrap(), payload.len() as u64);
+        assert_eq!(hash.0.finalize(), Sha256::digest(&payload));
+        assert_eq!(count, 160);
+
+        // The session goes on after the stream
+        let reply = bob.encrypt(b"got it", &aad).unwrap();
+        assert_eq!(alice.decrypt(reply, &aad).unwrap(), b"got it");
+    }
+
+    #[test]
+    fn test_chunk_boundaries() {
+        for (length, chunks) in [(0, 1), (1, 1), (99, 1), (100, 1), (101, 2), (300, 3)] {
+            let (mut alice, mut bob, aad) = session();
+            let payload = vec![7u8; length];
+            let encrypted = alice.encrypt_stream(payload.as_slice(), 100, &aad).unwrap().collect::<Result<Vec<_>, _>>().unwrap();
+            assert_eq!(encrypted.len(), chunks, "{} bytes", length);
+            assert_eq!(encrypted.iter().filter(|c| c[8] == 1).count(), 1);
+
+            let mut output = Vec::new();
+            assert_eq!(bob.decrypt_stream(&encrypted, &mut output, &aad).unwrap(), length as u64);
+            assert_eq!(output, payload);
+        }
+
+        let (mut alice, _, aad) = session();
+        assert!(matches!(alice.encrypt_stream(&[][..], 0, &aad), Err(RatchetError::InvalidChunkSize(0))));
+        let too_large = alice.max_plaintext_length() + 1;
+        assert!(matches!(alice.encrypt_stream(&[][..], too_large, &aad), Err(RatchetError::InvalidChunkSize(_))));
+    }
+
+    #[test]
+    fn test_misordered_and_missing_chunks_are_rejected() {
+        let (mut alice, bob, aad) = session();
+        let payload = vec![1u8; 250];
+        let chunks = alice.encrypt_stream(payload.as_slice(), 100, &aad).unwrap().collect::<Result<Vec<_>, _>>().unwrap();
+
+        let swapped = [&chunks[0], &chunks[2], &chunks[1]];
+        assert!(matches!(
+            bob.clone().decrypt_stream(swapped, Vec::new(), &aad),
+            Err(RatchetError::ChunkOutOfOrder { expected: 1, found: 2 })
+        ));
+        assert!(matches!(bob.clone().decrypt_stream(&chunks[..2], Vec::new(), &aad), Err(RatchetError::TruncatedStream)));
+        let repeated = [&chunks[0], &chunks[1], &chunks[2], &chunks[2]];
+        assert!(matches!(
+            bob.clone().decrypt_stream(repeated, Vec::new(), &aad),
+            Err(RatchetError::ChunkOutOfOrder { expected: 3, found: 2 })
+        ));
+
+        // Renumbering a chunk to hide a dropped one, or marking a chunk last to cut the stream, breaks its authentication
+        let mut renumbered = chunks[2].clone();
+        renumbered[..8].copy_from_slice(&1u64.to_le_bytes());
+        let mut cut = chunks[1].clone();
+        cut[8] = 1;
+        for forged in [renumbered, cut] {
+            assert!(matches!(
+                bob.clone().decrypt_stream([&chunks[0], &forged], Vec::new(), &aad),
+                Err(RatchetError::DecryptionError(_))
+            ));
+        }
+
+        let mut output = Vec::new();
+        bob.clone().decrypt_stream(&chunks, &mut output, &aad).unwrap();
+        assert_eq!(output, payload);
+    }
+
+    #[test]
+    fn test_chunks_of_another_stream_are_rejected() {
+        let (mut alice, bob, aad) = session();
+        let first = alice.encrypt_stream(&[1u8; 250][..], 100, &aad).unwrap().collect::<Result<Vec<_>, _>>().unwrap();
+        let second = alice.encrypt_stream(&[2u8; 250][..], 100, &aad).unwrap().collect::<Result<Vec<_>, _>>().unwrap();
+        assert_ne!(first[0][9..CHUNK_HEADER_LENGTH], second[0][9..CHUNK_HEADER_LENGTH]);
+
+        // The chunks of both streams follow each other on the chain, their indexes alone would let them be spliced
+        let spliced = [&first[0], &second[1], &second[2]];
+        assert!(matches!(bob.clone().decrypt_stream(spliced, Vec::new(), &aad), Err(RatchetError::ForeignChunk)));
+
+        // Giving a chunk the id of the other stream breaks its authentication
+        let mut relabelled = second[1].clone();
+        relabelled[9..CHUNK_HEADER_LENGTH].copy_from_slice(&first[0][9..CHUNK_HEADER_LENGTH]);
+        assert!(matches!(
+            bob.clone().decrypt_stream([&first[0], &relabelled], Vec::new(), &aad),
+            Err(RatchetError::DecryptionError(_))
+        ));
+
+        let mut bob = bob;
+        let mut output = Vec::new();
+        bob.decrypt_stream(&first, &mut output, &aad).unwrap();
+        bob.decrypt_stream(&second, &mut output, &aad).unwrap();
+        assert_eq!(output, [[1u8; 250], [2u8; 250]].concat());
+    }
+}
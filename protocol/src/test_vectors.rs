@@ -0,0 +1,445 @@
+//! Known-answer tests of the key derivations of X3DH and of the ratchet, and of a scripted conversation,
+//! against the vectors in `tests/vectors/`. The other tests of the crate encrypt and decrypt with the same
+//! code, so a change to a KDF or to the layout of the associated data goes unnoticed there as long as both
+//! sides change together.
+//!
+//! Each test checks one derivation and names the value that diverged, the derivations lower down (the
+//! Diffie-Hellman outputs, then the HKDF outputs) having tests of their own.
+//!
+//! The vectors are written by [`generate_vectors`], which is ignored so that they only change deliberately:
+//!
+//! ```text
+//! cargo test -p protocol generate_vectors -- --ignored
+//! ```
+//!
+//! The vectors are for X25519, the module is left out with the `x448` feature.
+
+use std::fs;
+use std::path::PathBuf;
+use base64::engine::general_purpose;
+use base64::Engine;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use crate::constants::{AES256_SECRET_LENGTH, DH_OUTPUT_LENGTH};
+use crate::interop::Conformance;
+use crate::ratchet::{hkdf_ck, hkdf_rk, hmac_ck, Ratchet, RatchetKeyPair};
+use crate::utils::{AeadSuite, AssociatedData, DhOutput, InitialMessage, PreKeyBundle, PrivateKey, PublicKey, SharedSecret};
+use crate::x3dh::{hkdf, process_initial_message, process_prekey_bundle, Role};
+
+const X3DH_VECTORS: &str = "x3dh.json";
+const RATCHET_VECTORS: &str = "ratchet.json";
+
+#[derive(Serialize, Deserialize)]
+struct X3dhVectors {
+    /// The private keys, base64-encoded.
+    keys: X3dhKeys,
+    dh: DhVectors,
+    hkdf: Vec<HkdfVector>,
+    responder: ResponderVector,
+}
+
+#[derive(Serialize, Deserialize)]
+struct X3dhKeys {
+    initiator_identity_key: String,
+    initiator_ephemeral_key: String,
+    responder_identity_key: String,
+    responder_signed_prekey: String,
+    responder_one_time_prekey: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct DhVectors {
+    /// DH(IKA, SPKB)
+    dh1: String,
+    /// DH(EKA, IKB)
+    dh2: String,
+    /// DH(EKA, SPKB)
+    dh3: String,
+    /// DH(EKA, OPKB)
+    dh4: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct HkdfVector {
+    role: String,
+    conformance: String,
+    one_time_prekey: bool,
+    sending: String,
+    receiving: String,
+}
+
+/// An initial message built against the keys of the responder, and the keys the initiator derived for it.
+#[derive(Serialize, Deserialize)]
+struct ResponderVector {
+    initial_message: String,
+    initiator_sending: String,
+    initiator_receiving: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct RatchetVectors {
+    hkdf_rk: Vec<RootKeyVector>,
+    hkdf_ck: ChainKeyVector,
+    hmac_ck: ChainKeyVector,
+    conversation: Conversation,
+}
+
+#[derive(Serialize, Deserialize)]
+struct RootKeyVector {
+    conformance: String,
+    root_key: String,
+    /// The private keys of the exchange, and its output.
+    private_key: String,
+    other_private_key: String,
+    dh: String,
+    next_root_key: String,
+    chain_key: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ChainKeyVector {
+    chain_key: String,
+    next_chain_key: String,
+    message_key: String,
+}
+
+/// Alice sends on her first chain, Bob reads her messages out of order and answers on a chain of his own.
+///
+/// The first ratchet keys of both parties are in the vectors, and the keys drawn during the conversation only
+/// ever encrypt, so that ratchets started from the vectors decrypt every message.
+#[derive(Serialize, Deserialize)]
+struct Conversation {
+    shared_secret: String,
+    alice_ratchet_key: String,
+    bob_ratchet_key: String,
+    aad: String,
+    messages: Vec<ConversationMessage>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ConversationMessage {
+    from: String,
+    plaintext: String,
+    ciphertext: String,
+}
+
+fn vectors_path(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests").join("vectors").join(name)
+}
+
+fn load<T: DeserializeOwned>(name: &str) -> T {
+    let path = vectors_path(name);
+    let json = fs::read_to_string(&path).unwrap_or_else(|e| panic!("Cannot read {}: {}", path.display(), e));
+    serde_json::from_str(&json).unwrap_or_else(|e| panic!("Malformed vectors in {}: {}", path.display(), e))
+}
+
+fn b64(bytes: &[u8]) -> String {
+    general_purpose::STANDARD.encode(bytes)
+}
+
+fn private_key(value: &str) -> PrivateKey {
+    PrivateKey::from_base64(value.to_string()).unwrap()
+}
+
+fn secret(value: &str) -> SharedSecret {
+    let bytes = general_purpose::STANDARD.decode(value).unwrap();
+    SharedSecret::from(<[u8; AES256_SECRET_LENGTH]>::try_from(bytes.as_slice()).unwrap())
+}
+
+fn dh_output(value: &str) -> DhOutput {
+    let bytes = general_purpose::STANDARD.decode(value).unwrap();
+    DhOutput::from(<[u8; DH_OUTPUT_LENGTH]>::try_from(bytes.as_slice()).unwrap())
+}
+
+fn role_name(role: Role) -> &'static str {
+    match role {
+        Role::Initiator => "initiator",
+        Role::Responder => "responder",
+    }
+}
+
+fn role(name: &str) -> Role {
+    match name {
+        "initiator" => Role::Initiator,
+        "responder" => Role::Responder,
+        _ => panic!("Unknown role {} in the vectors", name),
+    }
+}
+
+fn conformance_name(conformance: Conformance) -> &'static str {
+    match conformance {
+        Conformance::Native => "native",
+        Conformance::Spec => "spec",
+    }
+}
+
+fn conformance(name: &str) -> Conformance {
+    match name {
+        "native" => Conformance::Native,
+        "spec" => Conformance::Spec,
+        _ => panic!("Unknown conformance {} in the vectors", name),
+    }
+}
+
+/// Fails with the name of the derivation if `actual` is not the base64-encoded `expected`.
+fn check(derivation: &str, actual: &[u8], expected: &str) {
+    assert!(
+        b64(actual) == expected,
+        "{} diverged from the vectors: expected {}, got {}",
+        derivation, expected, b64(actual)
+    );
+}
+
+impl X3dhKeys {
+    fn dh(&self) -> [DhOutput; 4] {
+        let ika = private_key(&self.initiator_identity_key);
+        let eka = private_key(&self.initiator_ephemeral_key);
+        [
+            ika.diffie_hellman(&PublicKey::from(&private_key(&self.responder_signed_prekey))),
+            eka.diffie_hellman(&PublicKey::from(&private_key(&self.responder_identity_key))),
+            eka.diffie_hellman(&PublicKey::from(&private_key(&self.responder_signed_prekey))),
+            eka.diffie_hellman(&PublicKey::from(&private_key(&self.responder_one_time_prekey))),
+        ]
+    }
+}
+
+fn hkdf_vector(dh: &[DhOutput; 4], role: Role, conformance: Conformance, one_time_prekey: bool) -> HkdfVector {
+    let dh4 = one_time_prekey.then(|| dh[3].clone());
+    let (ek, dk) = hkdf(role, conformance, AeadSuite::default(), dh[0].clone(), dh[1].clone(), dh[2].clone(), dh4, None).unwrap();
+    HkdfVector {
+        role: role_name(role).to_string(),
+        conformance: conformance_name(conformance).to_string(),
+        one_time_prekey,
+        sending: b64(ek.as_ref()),
+        receiving: b64(dk.as_ref()),
+    }
+}
+
+fn root_key_vector(conformance: Conformance, root_key: SharedSecret, private_key: PrivateKey, other_private_key: PrivateKey) -> RootKeyVector {
+    let dh = private_key.diffie_hellman(&PublicKey::from(&other_private_key));
+    let (next_root_key, chain_key) = hkdf_rk(conformance, root_key.clone(), dh.clone()).unwrap();
+    RootKeyVector {
+        conformance: conformance_name(conformance).to_string(),
+        root_key: b64(root_key.as_ref()),
+        private_key: private_key.to_base64(),
+        other_private_key: other_private_key.to_base64(),
+        dh: b64(dh.as_ref()),
+        next_root_key: b64(next_root_key.as_ref()),
+        chain_key: b64(chain_key.as_ref()),
+    }
+}
+
+fn chain_key_vector(kdf: fn(SharedSecret) -> Result<(SharedSecret, SharedSecret), crate::errors::RatchetError>, chain_key: SharedSecret) -> ChainKeyVector {
+    let (next_chain_key, message_key) = kdf(chain_key.clone()).unwrap();
+    ChainKeyVector {
+        chain_key: b64(chain_key.as_ref()),
+        next_chain_key: b64(next_chain_key.as_ref()),
+        message_key: b64(message_key.as_ref()),
+    }
+}
+
+fn random_secret() -> SharedSecret {
+    SharedSecret::from(rand::random::<[u8; AES256_SECRET_LENGTH]>())
+}
+
+fn key_pair(private_key: PrivateKey) -> RatchetKeyPair {
+    let public_key = PublicKey::from(&private_key);
+    RatchetKeyPair::new_from(private_key, public_key)
+}
+
+/// The ratchets of Alice and Bob at the start of `conversation`.
+fn conversation_start(conversation: &Conversation) -> (Ratchet, Ratchet) {
+    let alice_key = private_key(&conversation.alice_ratchet_key);
+    let bob_key = private_key(&conversation.bob_ratchet_key);
+    let alice = Ratchet::init_alice_from(
+        secret(&conversation.shared_secret),
+        PublicKey::from(&bob_key),
+        key_pair(alice_key),
+        Conformance::Native
+    );
+    let bob = Ratchet::init_bob(secret(&conversation.shared_secret), key_pair(bob_key));
+    (alice, bob)
+}
+
+fn conversation() -> Conversation {
+    let mut conversation = Conversation {
+        shared_secret: b64(random_secret().as_ref()),
+        alice_ratchet_key: PrivateKey::new().to_base64(),
+        bob_ratchet_key: PrivateKey::new().to_base64(),
+        aad: b64(&AssociatedData::new(PublicKey::from(&PrivateKey::new()), PublicKey::from(&PrivateKey::new())).to_bytes()),
+        messages: Vec::new(),
+    };
+    let aad = general_purpose::STANDARD.decode(&conversation.aad).unwrap();
+    let (mut alice, mut bob) = conversation_start(&conversation);
+
+    let sent: Vec<String> = ["Hello Bob", "How are you?", "Call me back"]
+        .iter()
+        .map(|text| alice.encrypt(text.as_bytes(), &aad).unwrap())
+        .collect();
+    // Bob reads the second message last, from a skipped message key
+    for (i, text) in [(0, "Hello Bob"), (2, "Call me back"), (1, "How are you?")] {
+        assert_eq!(bob.decrypt(sent[i].clone(), &aad).unwrap(), text.as_bytes());
+        conversation.messages.push(ConversationMessage {
+            from: "alice".to_string(),
+            plaintext: text.to_string(),
+            ciphertext: sent[i].clone(),
+        });
+    }
+    for text in ["Fine, and you?", "Calling now"] {
+        let ciphertext = bob.encrypt(text.as_bytes(), &aad).unwrap();
+        assert_eq!(alice.decrypt(ciphertext.clone(), &aad).unwrap(), text.as_bytes());
+        conversation.messages.push(ConversationMessage {
+            from: "bob".to_string(),
+            plaintext: text.to_string(),
+            ciphertext,
+        });
+    }
+    conversation
+}
+
+fn write<T: Serialize>(name: &str, vectors: &T) {
+    let path = vectors_path(name);
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    fs::write(&path, serde_json::to_string_pretty(vectors).unwrap() + "\n").unwrap();
+}
+
+/// Writes new vectors from random keys. Every other test of this module fails against vectors of an older
+/// derivation, so regenerating them is a change of the protocol.
+#[test]
+#[ignore]
+fn generate_vectors() {
+    let keys = X3dhKeys {
+        initiator_identity_key: PrivateKey::new().to_base64(),
+        initiator_ephemeral_key: PrivateKey::new().to_base64(),
+        responder_identity_key: PrivateKey::new().to_base64(),
+        responder_signed_prekey: PrivateKey::new().to_base64(),
+        responder_one_time_prekey: PrivateKey::new().to_base64(),
+    };
+    let dh = keys.dh();
+    let mut hkdf = Vec::new();
+    for role in [Role::Initiator, Role::Responder] {
+        for conformance in [Conformance::Native, Conformance::Spec] {
+            for one_time_prekey in [false, true] {
+                hkdf.push(hkdf_vector(&dh, role, conformance, one_time_prekey));
+            }
+        }
+    }
+    let bundle = PreKeyBundle::new_with_otpk(
+        &private_key(&keys.responder_identity_key),
+        PublicKey::from(&private_key(&keys.responder_signed_prekey)),
+        vec![PublicKey::from(&private_key(&keys.responder_one_time_prekey))]
+    );
+    let (message, ek, dk) = process_prekey_bundle(private_key(&keys.initiator_identity_key), bundle).unwrap();
+    let responder = ResponderVector {
+        initial_message: message.to_base64(),
+        initiator_sending: b64(ek.as_ref()),
+        initiator_receiving: b64(dk.as_ref()),
+    };
+    let dh = DhVectors {
+        dh1: b64(dh[0].as_ref()),
+        dh2: b64(dh[1].as_ref()),
+        dh3: b64(dh[2].as_ref()),
+        dh4: b64(dh[3].as_ref()),
+    };
+    write(X3DH_VECTORS, &X3dhVectors { keys, dh, hkdf, responder });
+
+    let ratchet = RatchetVectors {
+        hkdf_rk: vec![
+            root_key_vector(Conformance::Native, random_secret(), PrivateKey::new(), PrivateKey::new()),
+            root_key_vector(Conformance::Spec, random_secret(), PrivateKey::new(), PrivateKey::new()),
+        ],
+        hkdf_ck: chain_key_vector(hkdf_ck, random_secret()),
+        hmac_ck: chain_key_vector(hmac_ck, random_secret()),
+        conversation: conversation(),
+    };
+    write(RATCHET_VECTORS, &ratchet);
+}
+
+#[test]
+fn test_x3dh_dh_vectors() {
+    let vectors: X3dhVectors = load(X3DH_VECTORS);
+    let [dh1, dh2, dh3, dh4] = vectors.keys.dh();
+    check("DH1 = DH(IKA, SPKB)", dh1.as_ref(), &vectors.dh.dh1);
+    check("DH2 = DH(EKA, IKB)", dh2.as_ref(), &vectors.dh.dh2);
+    check("DH3 = DH(EKA, SPKB)", dh3.as_ref(), &vectors.dh.dh3);
+    check("DH4 = DH(EKA, OPKB)", dh4.as_ref(), &vectors.dh.dh4);
+}
+
+#[test]
+fn test_x3dh_hkdf_vectors() {
+    let vectors: X3dhVectors = load(X3DH_VECTORS);
+    let dh = [&vectors.dh.dh1, &vectors.dh.dh2, &vectors.dh.dh3, &vectors.dh.dh4].map(|dh| dh_output(dh));
+    assert_eq!(vectors.hkdf.len(), 8);
+    for expected in &vectors.hkdf {
+        let actual = hkdf_vector(&dh, role(&expected.role), conformance(&expected.conformance), expected.one_time_prekey);
+        let name = format!(
+            "X3DH hkdf of the {} ({}, {} one-time pre-key)",
+            expected.role, expected.conformance, if expected.one_time_prekey { "with" } else { "without" }
+        );
+        assert!(actual.sending == expected.sending, "{}: sending key diverged from the vectors", name);
+        assert!(actual.receiving == expected.receiving, "{}: receiving key diverged from the vectors", name);
+    }
+}
+
+#[test]
+fn test_x3dh_responder_vectors() {
+    let vectors: X3dhVectors = load(X3DH_VECTORS);
+    let message = InitialMessage::try_from(vectors.responder.initial_message.clone()).unwrap();
+    let (ek, dk) = process_initial_message(
+        private_key(&vectors.keys.responder_identity_key),
+        private_key(&vectors.keys.responder_signed_prekey),
+        Some(private_key(&vectors.keys.responder_one_time_prekey)),
+        message
+    ).expect("X3DH process_initial_message rejected the initial message of the vectors");
+    check("X3DH responder sending key", ek.as_ref(), &vectors.responder.initiator_receiving);
+    check("X3DH responder receiving key", dk.as_ref(), &vectors.responder.initiator_sending);
+}
+
+#[test]
+fn test_ratchet_kdf_vectors() {
+    let vectors: RatchetVectors = load(RATCHET_VECTORS);
+    for expected in &vectors.hkdf_rk {
+        let dh = private_key(&expected.private_key).diffie_hellman(&PublicKey::from(&private_key(&expected.other_private_key)));
+        check(&format!("DH of the {} hkdf_rk vector", expected.conformance), dh.as_ref(), &expected.dh);
+        let (root_key, chain_key) = hkdf_rk(conformance(&expected.conformance), secret(&expected.root_key), dh).unwrap();
+        check(&format!("hkdf_rk ({}) root key", expected.conformance), root_key.as_ref(), &expected.next_root_key);
+        check(&format!("hkdf_rk ({}) chain key", expected.conformance), chain_key.as_ref(), &expected.chain_key);
+    }
+
+    let (chain_key, message_key) = hkdf_ck(secret(&vectors.hkdf_ck.chain_key)).unwrap();
+    check("hkdf_ck chain key", chain_key.as_ref(), &vectors.hkdf_ck.next_chain_key);
+    check("hkdf_ck message key", message_key.as_ref(), &vectors.hkdf_ck.message_key);
+
+    let (chain_key, message_key) = hmac_ck(secret(&vectors.hmac_ck.chain_key)).unwrap();
+    check("hmac_ck chain key", chain_key.as_ref(), &vectors.hmac_ck.next_chain_key);
+    check("hmac_ck message key", message_key.as_ref(), &vectors.hmac_ck.message_key);
+}
+
+#[test]
+fn test_conversation_vectors() {
+    let vectors: RatchetVectors = load(RATCHET_VECTORS);
+    let conversation = vectors.conversation;
+    let aad = general_purpose::STANDARD.decode(&conversation.aad).unwrap();
+    let (mut alice, mut bob) = conversation_start(&conversation);
+    // Alice sends again, so that her ratchet moves on like when the vectors were written
+    let (_, mut replayed_bob) = conversation_start(&conversation);
+
+    for (i, message) in conversation.messages.iter().enumerate() {
+        let (receiver, sender) = match message.from.as_str() {
+            "alice" => (&mut bob, &mut alice),
+            _ => (&mut alice, &mut bob),
+        };
+        let plaintext = receiver
+            .decrypt(message.ciphertext.clone(), &aad)
+            .unwrap_or_else(|e| panic!("Message {} from {} of the conversation does not decrypt: {:?}", i, message.from, e));
+        assert!(plaintext == message.plaintext.as_bytes(), "Message {} from {} decrypts to another plaintext", i, message.from);
+
+        if message.from == "alice" {
+            let replayed = sender.encrypt(message.plaintext.as_bytes(), &aad).unwrap();
+            assert!(
+                replayed_bob.decrypt(replayed, &aad).is_ok(),
+                "Message {} from alice, encrypted again, does not decrypt from the state of the vectors", i
+            );
+        }
+    }
+}
@@ -4,26 +4,39 @@
 //! These utilities encapsulate common cryptographic operations and data representations,
 //! supporting the X3DH and Double Ratchet implementations.
 
-use crate::constants::{AES256_NONCE_LENGTH, AES256_SECRET_LENGTH, CHALLENGE_LENGTH, CURVE25519_PUBLIC_LENGTH, CURVE25519_SECRET_LENGTH, SHA256_HASH_LENGTH, SIGNATURE_LENGTH};
+use crate::constants::{AES256_NONCE_LENGTH, AES256_SECRET_LENGTH, CHALLENGE_LENGTH, LEGACY_CHALLENGE_LENGTH, CURVE25519_PUBLIC_LENGTH, DH_OUTPUT_LENGTH, DH_PUBLIC_LENGTH, DH_SECRET_LENGTH, KEM_CIPHERTEXT_LENGTH, KEM_PUBLIC_LENGTH, MAX_ONE_TIME_PREKEYS, MAX_PLAINTEXT_LENGTH, SHA256_HASH_LENGTH, SIGNATURE_LENGTH};
+use crate::curve::{self, Curve};
 use crate::errors::X3DHError;
+use crate::interop::Conformance;
+use crate::x3dh::Role;
 use aes_gcm::aead::{Aead, Buffer, Payload};
-use aes_gcm::{AeadCore, Aes256Gcm, KeyInit, Nonce};
+use aes_gcm::{Aes256Gcm, KeyInit, Nonce};
 use arrayref::array_ref;
 use base64::{engine::general_purpose, Engine as _};
+use chacha20poly1305::ChaCha20Poly1305;
 use ed25519_dalek::ed25519::signature::SignerMut;
 use ed25519_dalek::Verifier;
+#[cfg(feature = "pqxdh")]
+use ml_kem::kem::{Decapsulate, Encapsulate};
+#[cfg(feature = "pqxdh")]
+use ml_kem::{Encoded, EncodedSizeUser, KemCore, MlKem768};
 use rand::rngs::OsRng;
 use serde::{Deserialize, Serialize};
 use serde_bytes;
+use hkdf::Hkdf;
 use sha2::{Digest, Sha256};
 use std::hash::{Hash, Hasher};
 use rand::Rng;
-use x25519_dalek::StaticSecret;
+use subtle::ConstantTimeEq;
 use zeroize::{Zeroize, ZeroizeOnDrop};
 
 /// A [`PreKeyBundle`] contains the public keys and signature published by a recipient,
 /// used by an initiator to establish a shared secret using the X3DH key agreement protocol.
-#[derive(Clone, Debug)]
+///
+/// With serde, the bundle is a versioned map whose keys are raw bytes, see [`PreKeyBundle::SERDE_VERSION`].
+/// [`PreKeyBundle::to_base64`] and its [`TryFrom<String>`] counterpart are kept for peers using the legacy packing.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(into = "PreKeyBundleRepr", try_from = "PreKeyBundleRepr")]
 pub struct PreKeyBundle {
     /// The recipient's identity signing key (Ed25519), used to verify `sig`.
     /// For more information, see [`VerifyingKey`].
@@ -41,20 +54,182 @@ pub struct PreKeyBundle {
     /// For more information, see [`Signature`].
     pub sig: Signature,
 
-    /// One or more ephemeral one-time pre-keys, X25519 public keys.
+    /// When the `spk` was created, in seconds since the Unix epoch, as claimed by the owner of the bundle:
+    /// the timestamp is not covered by `sig`.
+    /// `None` for bundles that predate it, such as those in the legacy packing.
+    pub spk_created_at: Option<u64>,
+
+    /// One or more ephemeral one-time pre-keys, public keys on the [`Curve::ACTIVE`] curve.
     /// If present, the initiator may use one to enhance forward secrecy.
     /// For more information, see [`PublicKey`].
     pub otpk: Vec<PublicKey>,
+
+    /// The ids of the one-time pre-keys, in the order of `otpk`, which the initiator names instead of hashing the key.
+    /// Either as long as `otpk`, or empty for bundles without ids, such as those in the legacy packing.
+    pub otpk_ids: Vec<u32>,
+
+    /// The signatures of the one-time pre-keys by the identity signing key, in the order of `otpk`,
+    /// see [`PreKeyBundle::sign_otpk`].
+    /// Either as long as `otpk`, or empty for bundles whose one-time pre-keys are not signed, such as
+    /// those in the legacy packing.
+    pub otpk_sigs: Vec<Signature>,
+
+    /// The AEAD suite the owner of the bundle encrypts its sessions with, which the initiator adopts.
+    /// [`AeadSuite::Aes256Gcm`] for bundles that predate it, such as those in the legacy packing.
+    pub aead_suite: AeadSuite,
+
+    /// The ML-KEM pre-key of the recipient, for a hybrid key agreement that stays secret even if the curve is
+    /// later broken, see [`PreKeyBundle::set_kem_prekey`]. Initiators built without the `pqxdh` feature ignore it.
+    /// `None` for classic bundles, such as those in the legacy packing.
+    pub kem_prekey: Option<KemPreKey>,
+
+    /// Whether the owner of the bundle reads the versioned JSON form of an [`InitialMessage`], see
+    /// [`InitialMessage::to_json`]. Initiators send the legacy base64 form to owners that do not.
+    /// `false` for bundles that predate it, such as those in the legacy packing.
+    pub json_initial_message: bool,
+}
+
+/// A one-time pre-key taken out of a [`PreKeyBundle`], with its id and signature if the bundle has them.
+#[derive(Clone, Debug)]
+pub struct OneTimePreKey {
+    pub key: PublicKey,
+    pub id: Option<u32>,
+    pub sig: Option<Signature>,
+}
+
+/// The ML-KEM pre-key of a [`PreKeyBundle`], with its signature by the identity signing key.
+#[derive(Clone, Debug)]
+pub struct KemPreKey {
+    pub key: KemPublicKey,
+    pub sig: Signature,
+}
+
+/// The serde form of a [`PreKeyBundle`].
+///
+/// Fields added in later versions must have a default, so that older bundles still deserialize.
+/// The curve keys are read whatever their size, so that keys on another curve are refused as such.
+#[derive(Serialize, Deserialize)]
+struct PreKeyBundleRepr {
+    version: u8,
+    #[serde(default)]
+    curve: u8,
+    #[serde(with = "serde_bytes")]
+    verifying_key: [u8; CURVE25519_PUBLIC_LENGTH],
+    ik: serde_bytes::ByteBuf,
+    spk: serde_bytes::ByteBuf,
+    #[serde(with = "serde_bytes")]
+    sig: [u8; SIGNATURE_LENGTH],
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    spk_created_at: Option<u64>,
+    #[serde(default)]
+    otpk: Vec<serde_bytes::ByteBuf>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    otpk_ids: Vec<u32>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    otpk_sigs: Vec<serde_bytes::ByteArray<SIGNATURE_LENGTH>>,
+    #[serde(default)]
+    aead_suite: u8,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    kem_prekey: Option<serde_bytes::ByteBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    kem_prekey_sig: Option<serde_bytes::ByteArray<SIGNATURE_LENGTH>>,
+    #[serde(default)]
+    json_initial_message: bool,
+}
+
+impl From<PreKeyBundle> for PreKeyBundleRepr {
+    fn from(bundle: PreKeyBundle) -> Self {
+        PreKeyBundleRepr {
+            version: PreKeyBundle::SERDE_VERSION,
+            curve: Curve::ACTIVE.id(),
+            verifying_key: bundle.verifying_key.0,
+            ik: serde_bytes::ByteBuf::from(bundle.ik.0.to_vec()),
+            spk: serde_bytes::ByteBuf::from(bundle.spk.0.to_vec()),
+            sig: bundle.sig.0,
+            spk_created_at: bundle.spk_created_at,
+            otpk: bundle.otpk.into_iter().map(|k| serde_bytes::ByteBuf::from(k.0.to_vec())).collect(),
+            otpk_ids: bundle.otpk_ids,
+            otpk_sigs: bundle.otpk_sigs.into_iter().map(|s| serde_bytes::ByteArray::new(s.0)).collect(),
+            aead_suite: bundle.aead_suite.id(),
+            kem_prekey: bundle.kem_prekey.as_ref().map(|kem| serde_bytes::ByteBuf::from(kem.key.0.clone())),
+            kem_prekey_sig: bundle.kem_prekey.map(|kem| serde_bytes::ByteArray::new(kem.sig.0)),
+            json_initial_message: bundle.json_initial_message,
+        }
+    }
+}
+
+impl TryFrom<PreKeyBundleRepr> for PreKeyBundle {
+    type Error = X3DHError;
+
+    /// Converts the serde form back into a [`PreKeyBundle`].
+    ///
+    /// # Errors
+    ///
+    /// * [`X3DHError::UnsupportedBundleVersion`] - Returned if the bundle was written by a newer version.
+    /// * [`X3DHError::UnknownCurve`] - Returned if the bundle names a curve this crate does not know.
+    /// * [`X3DHError::CurveMismatch`] - Returned if the keys of the bundle are on a curve other than [`Curve::ACTIVE`].
+    /// * [`X3DHError::InvalidPreKeyBundle`] - Returned if a key has the wrong size, or if some one-time pre-keys
+    ///   have an id, or a signature, and others do not.
+    /// * [`X3DHError::UnknownAeadSuite`] - Returned if the bundle names an AEAD suite this crate does not know.
+    /// * [`X3DHError::InvalidKemPreKey`] - Returned if the KEM pre-key has the wrong size, or comes without its signature.
+    fn try_from(repr: PreKeyBundleRepr) -> Result<Self, Self::Error> {
+        if repr.version == 0 || repr.version > PreKeyBundle::SERDE_VERSION {
+            return Err(X3DHError::UnsupportedBundleVersion(repr.version));
+        }
+        Curve::try_from(repr.curve)?.check_active()?;
+        if !repr.otpk_ids.is_empty() && repr.otpk_ids.len() != repr.otpk.len() {
+            return Err(X3DHError::InvalidPreKeyBundle);
+        }
+        if !repr.otpk_sigs.is_empty() && repr.otpk_sigs.len() != repr.otpk.len() {
+            return Err(X3DHError::InvalidPreKeyBundle);
+        }
+        let kem_prekey = match (repr.kem_prekey, repr.kem_prekey_sig) {
+            (Some(key), Some(sig)) => Some(KemPreKey {
+                key: KemPublicKey::try_from(key.as_slice())?,
+                sig: Signature(sig.into_array()),
+            }),
+            (None, None) => None,
+            _ => return Err(X3DHError::InvalidKemPreKey),
+        };
+        Ok(PreKeyBundle {
+            verifying_key: VerifyingKey(repr.verifying_key),
+            ik: PublicKey::try_from(repr.ik.as_slice()).map_err(|_| X3DHError::InvalidPreKeyBundle)?,
+            spk: PublicKey::try_from(repr.spk.as_slice()).map_err(|_| X3DHError::InvalidPreKeyBundle)?,
+            sig: Signature(repr.sig),
+            spk_created_at: repr.spk_created_at,
+            otpk: repr.otpk
+                .iter()
+                .map(|k| PublicKey::try_from(k.as_slice()).map_err(|_| X3DHError::InvalidPreKeyBundle))
+                .collect::<Result<_, _>>()?,
+            otpk_ids: repr.otpk_ids,
+            otpk_sigs: repr.otpk_sigs.into_iter().map(|s| Signature(s.into_array())).collect(),
+            aead_suite: AeadSuite::try_from(repr.aead_suite)?,
+            kem_prekey,
+            json_initial_message: repr.json_initial_message,
+        })
+    }
 }
 
 impl PreKeyBundle {
 
-    /// The total byte size of the pre-key bundle, which includes three Curve25519 public keys
-    /// and one signature.
+    /// The format version written by the serde implementation of [`PreKeyBundle`].
+    /// Version 2 adds [`PreKeyBundle::aead_suite`], version 3 [`PreKeyBundle::kem_prekey`], version 4 the
+    /// [`Curve`] of the keys, which older bundles leave to [`Curve::X25519`].
+    pub const SERDE_VERSION: u8 = 4;
+
+    /// Prefixed to a one-time pre-key before signing it, so that its signature cannot pass for the
+    /// one of a signed pre-key.
+    const OTPK_SIGNATURE_CONTEXT: &'static [u8] = b"X3DH one-time pre-key";
+
+    /// Prefixed to a KEM pre-key before signing it, for the same reason as [`Self::OTPK_SIGNATURE_CONTEXT`].
+    const KEM_SIGNATURE_CONTEXT: &'static [u8] = b"PQXDH KEM pre-key";
+
+    /// The total byte size of the pre-key bundle, which includes the verifying key, two public keys
+    /// on the [`Curve::ACTIVE`] curve and one signature.
     /// This constant is used to verify the expected size of a `PreKeyBundle`.
     pub(crate) const BASE_SIZE: usize = CURVE25519_PUBLIC_LENGTH
-        + CURVE25519_PUBLIC_LENGTH
-        + CURVE25519_PUBLIC_LENGTH
+        + DH_PUBLIC_LENGTH
+        + DH_PUBLIC_LENGTH
         + SIGNATURE_LENGTH;
 
     /// Generates a new pre-key bundle.
@@ -80,12 +255,18 @@ impl PreKeyBundle {
             ik: PublicKey::from(ik),
             spk,
             sig,
+            spk_created_at: Some(unix_time()),
             otpk: vec![],
+            otpk_ids: vec![],
+            otpk_sigs: vec![],
+            aead_suite: AeadSuite::default(),
+            kem_prekey: None,
+            json_initial_message: true,
         }
     }
 
     /// Generates a new pre-key bundle,
-    /// including one-time pre-keys.
+    /// including one-time pre-keys, each signed with the identity key.
     ///
     /// For a version that excludes one-time pre-keys, see [`PreKeyBundle::new`].
     /// 
@@ -100,22 +281,145 @@ impl PreKeyBundle {
     pub fn new_with_otpk(ik: &PrivateKey, spk: PublicKey, otpk: Vec<PublicKey>) -> Self {
         let ik_signing = SigningKey::from(ik);
         let sig = ik_signing.sign(&spk.0);
+        let otpk_sigs = otpk.iter().map(|k| Self::sign_otpk(ik, k)).collect();
         PreKeyBundle {
             verifying_key: VerifyingKey::from(&ik_signing),
             ik: PublicKey::from(ik),
             spk,
             sig,
+            spk_created_at: Some(unix_time()),
             otpk,
+            otpk_ids: vec![],
+            otpk_sigs,
+            aead_suite: AeadSuite::default(),
+            kem_prekey: None,
+            json_initial_message: true,
         }
     }
 
-    /// Adds a one-time pre-key
+    /// Replaces the signed pre-key with a new one, signed with the identity key.
+    ///
+    /// The one-time pre-keys are kept.
+    ///
+    /// # Arguments
+    ///
+    /// * `ik` - The identity key of the owner of the bundle.
+    /// * `spk` - The new signed pre-key.
+    /// * `created_at` - When `spk` was created, in seconds since the Unix epoch.
+    pub fn replace_spk(&mut self, ik: &PrivateKey, spk: PublicKey, created_at: u64) {
+        self.sig = SigningKey::from(ik).sign(&spk.0);
+        self.spk = spk;
+        self.spk_created_at = Some(created_at);
+    }
+
+    /// Signs a one-time pre-key with the identity key, so that initiators can tell it was not
+    /// replaced on the way.
+    ///
+    /// # Arguments
+    ///
+    /// * `ik` - The identity key of the owner of the bundle.
+    /// * `otpk` - The one-time pre-key to be signed.
+    ///
+    /// # Returns
+    ///
+    /// * [`Signature`] - The signature to publish along with `otpk`.
+    pub fn sign_otpk(ik: &PrivateKey, otpk: &PublicKey) -> Signature {
+        SigningKey::from(ik).sign(&[Self::OTPK_SIGNATURE_CONTEXT, otpk.0.as_ref()].concat())
+    }
+
+    /// Verifies the signature of a one-time pre-key against the identity of the bundle.
+    ///
+    /// # Arguments
+    ///
+    /// * `otpk` - The one-time pre-key.
+    /// * `sig` - Its signature, see [`PreKeyBundle::sign_otpk`].
+    ///
+    /// # Errors
+    ///
+    /// * [`X3DHError::InvalidOtpkSignature`] - Returned if the signature does not verify.
+    pub fn verify_otpk(&self, otpk: &PublicKey, sig: &Signature) -> Result<(), X3DHError> {
+        self.verifying_key
+            .verify(sig, &[Self::OTPK_SIGNATURE_CONTEXT, otpk.0.as_ref()].concat())
+            .map_err(|_| X3DHError::InvalidOtpkSignature)
+    }
+
+    /// Offers a KEM pre-key for hybrid key agreements, signed with the identity key, replacing any previous one.
+    ///
+    /// # Arguments
+    ///
+    /// * `ik` - The identity key of the owner of the bundle.
+    /// * `key` - The public half of the KEM pre-key, see [`KemPrivateKey::public_key`].
+    pub fn set_kem_prekey(&mut self, ik: &PrivateKey, key: KemPublicKey) {
+        let sig = SigningKey::from(ik).sign(&[Self::KEM_SIGNATURE_CONTEXT, key.as_ref()].concat());
+        self.kem_prekey = Some(KemPreKey { key, sig });
+    }
+
+    /// Verifies the signature of a KEM pre-key against the identity of the bundle.
+    ///
+    /// # Arguments
+    ///
+    /// * `kem_prekey` - The KEM pre-key with its signature, see [`PreKeyBundle::set_kem_prekey`].
+    ///
+    /// # Errors
+    ///
+    /// * [`X3DHError::InvalidKemSignature`] - Returned if the signature does not verify.
+    pub fn verify_kem_prekey(&self, kem_prekey: &KemPreKey) -> Result<(), X3DHError> {
+        self.verifying_key
+            .verify(&kem_prekey.sig, &[Self::KEM_SIGNATURE_CONTEXT, kem_prekey.key.as_ref()].concat())
+            .map_err(|_| X3DHError::InvalidKemSignature)
+    }
+
+    /// Adds a one-time pre-key without an id or a signature.
+    ///
+    /// The ids and signatures of the other one-time pre-keys are dropped, see [`PreKeyBundle::push_otpk`].
     ///
     /// # Arguments
     ///
     /// * `otpk` - The one-time pre-key to be added.
     pub fn add_otpk(&mut self, otpk: PublicKey) {
-        self.otpk.push(otpk);
+        self.push_otpk(OneTimePreKey { key: otpk, id: None, sig: None });
+    }
+
+    /// Adds a one-time pre-key with its id, but without a signature.
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - The id the owner of the bundle knows the one-time pre-key by.
+    /// * `otpk` - The one-time pre-key to be added.
+    pub fn add_otpk_with_id(&mut self, id: u32, otpk: PublicKey) {
+        self.push_otpk(OneTimePreKey { key: otpk, id: Some(id), sig: None });
+    }
+
+    /// Adds a one-time pre-key with its id and signature, if it has them.
+    ///
+    /// Ids and signatures are only kept while every key has one: adding a key without an id drops
+    /// the ids of the other keys, and adding one without a signature drops their signatures.
+    ///
+    /// # Arguments
+    ///
+    /// * `otpk` - The one-time pre-key to be added.
+    pub fn push_otpk(&mut self, otpk: OneTimePreKey) {
+        match otpk.id {
+            Some(id) if self.otpk_ids.len() == self.otpk.len() => self.otpk_ids.push(id),
+            _ => self.otpk_ids.clear(),
+        }
+        match otpk.sig {
+            Some(sig) if self.otpk_sigs.len() == self.otpk.len() => self.otpk_sigs.push(sig),
+            _ => self.otpk_sigs.clear(),
+        }
+        self.otpk.push(otpk.key);
+    }
+
+    /// Removes the last one-time pre-key.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<OneTimePreKey>` - The one-time pre-key, with its id and signature if the bundle has them,
+    ///   or `None` if the bundle has no one-time pre-keys left.
+    pub fn pop_otpk(&mut self) -> Option<OneTimePreKey> {
+        let id = if self.otpk_ids.len() == self.otpk.len() { self.otpk_ids.pop() } else { None };
+        let sig = if self.otpk_sigs.len() == self.otpk.len() { self.otpk_sigs.pop() } else { None };
+        self.otpk.pop().map(|key| OneTimePreKey { key, id, sig })
     }
 
     /// Calculates the size of the pre-key bundle.
@@ -124,7 +428,7 @@ impl PreKeyBundle {
     ///
     /// * `usize` - The number of elements in the pre-key bundle.
     pub fn size(&self) -> usize {
-        CURVE25519_SECRET_LENGTH * 3 + SIGNATURE_LENGTH + self.otpk.len() * CURVE25519_PUBLIC_LENGTH
+        Self::BASE_SIZE + self.otpk.len() * DH_PUBLIC_LENGTH
     }
 
     /// Converts each element of the pre-key bundle into bytes.
@@ -148,12 +452,147 @@ impl PreKeyBundle {
 
     /// Calculates the base64 of the pre-key bundle.
     ///
+    /// The legacy packing carries no one-time pre-key ids or signatures, see [`PreKeyBundle::otpk_ids`]
+    /// and [`PreKeyBundle::otpk_sigs`]: older peers would read the signatures as more keys.
+    /// Nor does it carry the [`PreKeyBundle::kem_prekey`], so the key agreement falls back to the classic one,
+    /// nor the [`Curve`] of the keys, so both ends must be built for the same one.
+    ///
     /// # Returns
     ///
     /// * `String` - The base64-encoded string of the pre-key bundle.
-    pub fn to_base64(self) -> String {
+    pub fn to_base64(&self) -> String {
         general_purpose::STANDARD.encode(self.to_bytes())
     }
+
+    /// Whether `other` carries the same identity, both the identity key and the verifying key, as this bundle.
+    ///
+    /// # Arguments
+    ///
+    /// * `other` - The bundle to compare against, such as one of another device of the same user.
+    ///
+    /// # Returns
+    ///
+    /// * `bool` - `true` if both halves of the identity are the same.
+    pub fn same_identity(&self, other: &PreKeyBundle) -> bool {
+        self.ik.ct_eq(&other.ik) && self.verifying_key.0 == other.verifying_key.0
+    }
+
+    /// Verifies the signed pre-key against the identity of the bundle.
+    ///
+    /// The signature over the signed pre-key must verify with the verifying key, and both halves
+    /// of the identity must be usable: the verifying key must be a valid Ed25519 point that is not
+    /// of small order, and the identity key must be a valid curve point. The bundle format carries
+    /// no proof that the two halves belong to the same private key, since the Ed25519 scalar is
+    /// derived by hashing the key, so that binding is only as good as the server handing it out.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(())` - If the signed pre-key can be trusted.
+    ///
+    /// # Errors
+    ///
+    /// * [`X3DHError::InvalidSignature`] - Returned if the signed pre-key signature verification fails.
+    /// * [`X3DHError::InvalidIdentityKey`] - Returned if the verifying key or the identity key is not a valid point.
+    pub fn verify(&self) -> Result<(), X3DHError> {
+        self.verifying_key.verify(&self.sig, &self.spk.0)?;
+        if !self.verifying_key.is_valid_point() || !self.ik.is_valid_point() {
+            return Err(X3DHError::InvalidIdentityKey);
+        }
+        Ok(())
+    }
+
+    /// Checks that the pre-key bundle can be safely used in an X3DH key agreement.
+    ///
+    /// The signature of the signed pre-key must verify, every key must be a valid curve point,
+    /// the signed pre-key must differ from the identity key and the one-time pre-keys must be
+    /// unique, at most [`MAX_ONE_TIME_PREKEYS`] and, if signed, carry valid signatures.
+    /// A KEM pre-key must carry a valid signature.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(BundleReport)` - If the bundle is usable, with the non-fatal findings.
+    ///
+    /// # Errors
+    ///
+    /// * [`X3DHError::InvalidSignature`] - Returned if the signed pre-key signature verification fails.
+    /// * [`X3DHError::InvalidIdentityKey`] - Returned if the identity key is not a valid curve point.
+    /// * [`X3DHError::InvalidSignedPreKey`] - Returned if the signed pre-key is not a valid curve point.
+    /// * [`X3DHError::SignedPreKeyIsIdentityKey`] - Returned if the signed pre-key is the identity key.
+    /// * [`X3DHError::TooManyOneTimePreKeys`] - Returned if there are more than [`MAX_ONE_TIME_PREKEYS`] one-time pre-keys.
+    /// * [`X3DHError::InvalidOneTimePreKey`] - Returned if a one-time pre-key is not a valid curve point.
+    /// * [`X3DHError::DuplicateOneTimePreKey`] - Returned if a one-time pre-key, or its id, appears twice.
+    /// * [`X3DHError::InvalidPreKeyBundle`] - Returned if some one-time pre-keys have an id, or a signature, and others do not.
+    /// * [`X3DHError::InvalidOtpkSignature`] - Returned if the signature of a one-time pre-key does not verify.
+    /// * [`X3DHError::InvalidKemSignature`] - Returned if the signature of the KEM pre-key does not verify.
+    pub fn validate(&self) -> Result<BundleReport, X3DHError> {
+        self.verify()?;
+        if !self.spk.is_valid_point() {
+            return Err(X3DHError::InvalidSignedPreKey);
+        }
+        if self.spk == self.ik {
+            return Err(X3DHError::SignedPreKeyIsIdentityKey);
+        }
+        if self.otpk.len() > MAX_ONE_TIME_PREKEYS {
+            return Err(X3DHError::TooManyOneTimePreKeys(self.otpk.len()));
+        }
+        for (i, otpk) in self.otpk.iter().enumerate() {
+            if !otpk.is_valid_point() {
+                return Err(X3DHError::InvalidOneTimePreKey(i));
+            }
+            if self.otpk[..i].contains(otpk) {
+                return Err(X3DHError::DuplicateOneTimePreKey(i));
+            }
+        }
+        if !self.otpk_ids.is_empty() && self.otpk_ids.len() != self.otpk.len() {
+            return Err(X3DHError::InvalidPreKeyBundle);
+        }
+        for (i, id) in self.otpk_ids.iter().enumerate() {
+            if self.otpk_ids[..i].contains(id) {
+                return Err(X3DHError::DuplicateOneTimePreKey(i));
+            }
+        }
+        if !self.otpk_sigs.is_empty() && self.otpk_sigs.len() != self.otpk.len() {
+            return Err(X3DHError::InvalidPreKeyBundle);
+        }
+        for (otpk, sig) in self.otpk.iter().zip(&self.otpk_sigs) {
+            self.verify_otpk(otpk, sig)?;
+        }
+        if let Some(kem_prekey) = &self.kem_prekey {
+            self.verify_kem_prekey(kem_prekey)?;
+        }
+
+        let mut report = BundleReport::default();
+        if self.otpk.is_empty() {
+            report.warnings.push(BundleWarning::NoOneTimePreKeys);
+        } else if self.otpk_sigs.is_empty() {
+            report.warnings.push(BundleWarning::UnsignedOneTimePreKeys);
+        }
+        Ok(report)
+    }
+}
+
+/// The non-fatal findings of [`PreKeyBundle::validate`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BundleReport {
+    /// Issues that weaken the key agreement without making the bundle unusable.
+    pub warnings: Vec<BundleWarning>,
+}
+
+impl BundleReport {
+
+    /// Returns `true` if the validation produced no warnings.
+    pub fn is_clean(&self) -> bool {
+        self.warnings.is_empty()
+    }
+}
+
+/// A non-fatal finding about a [`PreKeyBundle`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BundleWarning {
+    /// The bundle has no one-time pre-keys, so the key agreement will not use DH4.
+    NoOneTimePreKeys,
+    /// The one-time pre-keys are not signed, so they could have been replaced on the way.
+    UnsignedOneTimePreKeys,
 }
 
 impl TryFrom<String> for PreKeyBundle {
@@ -179,24 +618,24 @@ impl TryFrom<String> for PreKeyBundle {
         let identity_key = PublicKey(*array_ref![
             bytes,
             CURVE25519_PUBLIC_LENGTH,
-            CURVE25519_PUBLIC_LENGTH
+            DH_PUBLIC_LENGTH
         ]);
         let signed_prekey = PublicKey(*array_ref![
             bytes,
-            2 * CURVE25519_PUBLIC_LENGTH,
-            CURVE25519_PUBLIC_LENGTH
+            CURVE25519_PUBLIC_LENGTH + DH_PUBLIC_LENGTH,
+            DH_PUBLIC_LENGTH
         ]);
         let prekey_signature = Signature(*array_ref![
             bytes,
-            3 * CURVE25519_PUBLIC_LENGTH,
+            CURVE25519_PUBLIC_LENGTH + 2 * DH_PUBLIC_LENGTH,
             SIGNATURE_LENGTH
         ]);
         if bytes.len() > Self::BASE_SIZE {
             let mut one_time_keys = Vec::new();
-            for i in 0..(bytes.len() - Self::BASE_SIZE) / CURVE25519_PUBLIC_LENGTH {
-                let start = Self::BASE_SIZE + i * CURVE25519_PUBLIC_LENGTH;
+            for i in 0..(bytes.len() - Self::BASE_SIZE) / DH_PUBLIC_LENGTH {
+                let start = Self::BASE_SIZE + i * DH_PUBLIC_LENGTH;
                 let one_time_prekey =
-                    PublicKey(*array_ref![bytes, start, CURVE25519_PUBLIC_LENGTH]);
+                    PublicKey(*array_ref![bytes, start, DH_PUBLIC_LENGTH]);
                 one_time_keys.push(one_time_prekey);
             }
             Ok(Self {
@@ -204,7 +643,13 @@ impl TryFrom<String> for PreKeyBundle {
                 ik: identity_key,
                 spk: signed_prekey,
                 sig: prekey_signature,
+                spk_created_at: None,
                 otpk: one_time_keys,
+                otpk_ids: vec![],
+                otpk_sigs: vec![],
+                aead_suite: AeadSuite::Aes256Gcm,
+                kem_prekey: None,
+                json_initial_message: false,
             })
         } else {
             Ok(Self {
@@ -212,7 +657,13 @@ impl TryFrom<String> for PreKeyBundle {
                 ik: identity_key,
                 spk: signed_prekey,
                 sig: prekey_signature,
+                spk_created_at: None,
                 otpk: vec![],
+                otpk_ids: vec![],
+                otpk_sigs: vec![],
+                aead_suite: AeadSuite::Aes256Gcm,
+                kem_prekey: None,
+                json_initial_message: false,
             })
         }
     }
@@ -232,6 +683,10 @@ pub struct SessionKeys {
     /// Optional associated data used for authentication and context binding.
     /// For more information, see [`AssociatedData`].
     aad: Option<AssociatedData>,
+
+    /// The decryption key replaced by the last [`SessionKeys::rotate`], kept to decrypt
+    /// messages that were already in flight when the keys were rotated.
+    previous_dk: Option<DecryptionKey>,
 }
 
 impl SessionKeys {
@@ -249,6 +704,7 @@ impl SessionKeys {
             ek: None,
             dk: None,
             aad: None,
+            previous_dk: None,
         }
     }
 
@@ -274,6 +730,7 @@ impl SessionKeys {
             ek: Some(ek),
             dk: Some(dk),
             aad,
+            previous_dk: None,
         }
     }
 
@@ -337,47 +794,162 @@ impl SessionKeys {
         self.aad = Some(aad);
     }
 
+    /// Returns the [`DecryptionKey`] that was in use before the last rotation, if any.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<DecryptionKey>`
+    ///   * `Some(DecryptionKey)` - If the session keys have been rotated at least once.
+    ///   * `None` - If the session keys have never been rotated.
+    pub fn get_previous_decryption_key(&self) -> Option<DecryptionKey> {
+        self.previous_dk.clone()
+    }
+
+    /// Rotates the session keys in place.
+    ///
+    /// Each key is replaced by an HKDF-SHA256 derivation of itself, so both parties of the
+    /// session reach the same new keys without exchanging any key material: the new encryption
+    /// key of one side is the new decryption key of the other. The old decryption key is kept
+    /// and returned by [`SessionKeys::get_previous_decryption_key`] until the next rotation.
+    ///
+    /// # Errors
+    ///
+    /// * [`X3DHError::HkdfInvalidLengthError`] - Returned if HKDF expansion fails due to an invalid output length.
+    /// * [`X3DHError::InvalidKey`] - Returned if the session has not been initialized.
+    pub fn rotate(&mut self) -> Result<(), X3DHError> {
+        let (ek, dk) = match (&self.ek, &self.dk) {
+            (Some(ek), Some(dk)) => (ek, dk),
+            _ => return Err(X3DHError::InvalidKey),
+        };
+        let ek = EncryptionKey::from(SharedSecret::from(next_session_key(ek.as_ref())?));
+        let dk = DecryptionKey::from(SharedSecret::from(next_session_key(dk.as_ref())?));
+        self.previous_dk = self.dk.replace(dk);
+        self.ek = Some(ek);
+        Ok(())
+    }
+
 }
 
-/// A 256-bit secret shared between two parties after performing a key agreement (in this case, Diffie-Hellman).
-#[derive(Clone, Zeroize, ZeroizeOnDrop, Debug)]
-pub struct SharedSecret([u8; AES256_SECRET_LENGTH]);
+/// Derives the key that replaces `key` when a [`SessionKeys`] is rotated.
+fn next_session_key(key: &[u8; AES256_SECRET_LENGTH]) -> Result<[u8; AES256_SECRET_LENGTH], X3DHError> {
+    let hk = Hkdf::<Sha256>::new(None, key);
+    let mut next = [0u8; AES256_SECRET_LENGTH];
+    hk.expand(b"session key rotation", &mut next)?;
+    Ok(next)
+}
 
-impl From<(EncryptionKey, DecryptionKey)> for SharedSecret {
+/// The current time in seconds since the Unix epoch, as stamped on new signed pre-keys.
+fn unix_time() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_secs())
+}
 
-    /// Derives a [`SharedSecret`] from an [`EncryptionKey`] and a [`DecryptionKey`].
-    /// 
+/// The output of a Diffie-Hellman exchange on the [`Curve::ACTIVE`] curve, only ever used as HKDF input.
+#[derive(Clone, Zeroize, ZeroizeOnDrop)]
+pub(crate) struct DhOutput([u8; DH_OUTPUT_LENGTH]);
+
+impl From<[u8; DH_OUTPUT_LENGTH]> for DhOutput {
+
+    /// Wraps the raw output of a Diffie-Hellman exchange.
+    ///
     /// # Arguments
-    /// 
-    /// * `ek` - The encryption key.
-    /// * `dk` - The decryption key.
-    /// 
+    ///
+    /// * `value` - The bytes of the output.
+    ///
     /// # Returns
-    /// 
-    /// * [`SharedSecret`] - The derived shared secret.
-    fn from((ek, dk): (EncryptionKey, DecryptionKey)) -> SharedSecret {
-        let mut vec = ek.as_ref().to_vec();
-        vec.extend_from_slice(dk.as_ref());
-        SharedSecret(*array_ref!(vec, 0, AES256_SECRET_LENGTH))
+    ///
+    /// * [`DhOutput`] - The wrapped output.
+    fn from(value: [u8; DH_OUTPUT_LENGTH]) -> DhOutput {
+        DhOutput(value)
     }
 }
 
-impl From<(DecryptionKey, EncryptionKey)> for SharedSecret {
+impl AsRef<[u8]> for DhOutput {
 
-    /// Derives a [`SharedSecret`] from a [`DecryptionKey`] and an [`EncryptionKey`].
-    /// 
+    /// Returns the bytes of the current [`DhOutput`].
+    ///
+    /// # Returns
+    ///
+    /// * `&[u8]` - The shared reference.
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+/// A 256-bit secret shared between two parties after performing a key agreement, derived with HKDF.
+#[derive(Clone, Zeroize, ZeroizeOnDrop, Debug)]
+pub struct SharedSecret([u8; AES256_SECRET_LENGTH]);
+
+impl SharedSecret {
+
+    /// Derives the root key of a ratchet from the two directional keys agreed with X3DH.
+    ///
+    /// HKDF runs over both keys, initiator-to-responder first, so that the root key depends on all of the
+    /// key material and both parties derive the same value whichever direction they send in.
+    ///
     /// # Arguments
-    /// 
-    /// * `dk` - The decryption key.
-    /// * `ek` - The encryption key.
-    /// 
+    ///
+    /// * `initiator_key` - The key of the messages sent by the initiator.
+    /// * `responder_key` - The key of the messages sent by the responder.
+    ///
     /// # Returns
-    /// 
-    /// * [`SharedSecret`] - The derived shared secret.
-    fn from((dk, ek): (DecryptionKey, EncryptionKey)) -> SharedSecret {
-        let mut vec = dk.as_ref().to_vec();
-        vec.extend_from_slice(ek.as_ref());
-        SharedSecret(*array_ref!(vec, 0, AES256_SECRET_LENGTH))
+    ///
+    /// * [`SharedSecret`] - The root key.
+    fn root_key(initiator_key: &[u8; AES256_SECRET_LENGTH], responder_key: &[u8; AES256_SECRET_LENGTH]) -> SharedSecret {
+        let mut ikm = initiator_key.to_vec();
+        ikm.extend_from_slice(responder_key);
+        let hk = Hkdf::<Sha256>::new(None, &ikm);
+        let mut okm = [0u8; AES256_SECRET_LENGTH];
+        hk.expand(b"X3DHRatchetRootKey", &mut okm).expect("32 bytes is a valid HKDF output length");
+        ikm.zeroize();
+        SharedSecret(okm)
+    }
+
+    /// Derives the ratchet root key from the keys agreed with X3DH, as returned by
+    /// [`crate::x3dh::process_prekey_bundle`] to the initiator or [`crate::x3dh::process_initial_message`]
+    /// to the responder. Both parties derive the same key from their own `role`.
+    ///
+    /// # Arguments
+    ///
+    /// * `role` - The side of the handshake we are on.
+    /// * `ek` - Our encryption key.
+    /// * `dk` - Our decryption key.
+    ///
+    /// # Returns
+    ///
+    /// * [`SharedSecret`] - The root key.
+    pub fn from_session_keys(role: Role, ek: &EncryptionKey, dk: &DecryptionKey) -> SharedSecret {
+        match role {
+            Role::Initiator => SharedSecret::root_key(ek.as_ref(), dk.as_ref()),
+            Role::Responder => SharedSecret::root_key(dk.as_ref(), ek.as_ref()),
+        }
+    }
+}
+
+impl Serialize for SharedSecret {
+
+    /// Serializes the [`SharedSecret`] as its raw bytes.
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(&self.0)
+    }
+}
+
+impl<'de> Deserialize<'de> for SharedSecret {
+
+    /// Deserializes a [`SharedSecret`] from its raw bytes.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the length is not [`AES256_SECRET_LENGTH`].
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let mut bytes = serde_bytes::ByteBuf::deserialize(deserializer)?.into_vec();
+        let result = match bytes.len() {
+            AES256_SECRET_LENGTH => Ok(SharedSecret(*array_ref!(bytes, 0, AES256_SECRET_LENGTH))),
+            len => Err(serde::de::Error::invalid_length(len, &"32 bytes")),
+        };
+        bytes.zeroize();
+        result
     }
 }
 
@@ -449,6 +1021,7 @@ impl From<&SigningKey> for VerifyingKey {
     }
 }
 
+#[cfg(not(feature = "x448"))]
 impl From<PublicKey> for VerifyingKey {
 
     /// Derives a [`VerifyingKey`] from a [`PublicKey`].
@@ -465,6 +1038,7 @@ impl From<PublicKey> for VerifyingKey {
     }
 }
 
+#[cfg(not(feature = "x448"))]
 impl From<&PublicKey> for VerifyingKey {
 
     /// Derives a [`VerifyingKey`] from a shared reference to a [`PublicKey`].
@@ -518,6 +1092,15 @@ impl VerifyingKey {
         let dalek_signature = ed25519_dalek::Signature::from(signature.0);
         dalek_public_key.verify(message, &dalek_signature)
     }
+
+    /// Checks that the [`VerifyingKey`] is a valid Ed25519 point that is not of small order.
+    ///
+    /// # Returns
+    ///
+    /// * `bool` - `true` if the key can be used to verify signatures.
+    pub(crate) fn is_valid_point(&self) -> bool {
+        ed25519_dalek::VerifyingKey::from_bytes(&self.0).is_ok_and(|key| !key.is_weak())
+    }
 }
 
 /// An Ed25519 signing key used to create digital signatures in the X3DH protocol.
@@ -551,24 +1134,6 @@ impl SigningKey {
         let signature = dalek_private_key.sign(message);
         Signature(signature.to_bytes())
     }
-
-    /// Computes a Diffie-Hellman shared secret using the current private key and a public key.
-    /// This function uses the X25519 elliptic curve Diffie-Hellman key exchange algorithm
-    /// via the [`x25519_dalek`] crate to securely derive a shared secret.
-    /// 
-    /// # Arguments
-    ///
-    /// * `public_key` - A reference to the public key of the other party.
-    ///
-    /// # Returns
-    ///
-    /// * [`SharedSecret`] - The resulting shared secret derived from the key exchange.
-    pub(crate) fn diffie_hellman(&self, public_key: &PublicKey) -> SharedSecret {
-        let dalek_private_key = StaticSecret::from(self.0);
-        let dalek_public_key = x25519_dalek::PublicKey::from(public_key.0);
-        let shared_secret = dalek_private_key.diffie_hellman(&dalek_public_key);
-        SharedSecret(shared_secret.to_bytes())
-    }
 }
 
 impl From<PrivateKey> for SigningKey {
@@ -583,7 +1148,7 @@ impl From<PrivateKey> for SigningKey {
     ///
     /// * [`SigningKey`] - The derived verifying key.
     fn from(private_key: PrivateKey) -> SigningKey {
-        SigningKey(private_key.0)
+        SigningKey(curve::signing_seed(&private_key.0))
     }
 }
 
@@ -591,6 +1156,8 @@ impl From<&PrivateKey> for SigningKey {
 
     /// Derives a [`SigningKey`] from a shared reference to a [`PrivateKey`].
     ///
+    /// With X25519 the private key is the signing key as it is; an X448 key is hashed into one.
+    ///
     /// # Arguments
     ///
     /// * `private_key` - The shared reference to the private key from which the signing key is derived.
@@ -599,7 +1166,7 @@ impl From<&PrivateKey> for SigningKey {
     ///
     /// * [`SigningKey`] - The derived verifying key.
     fn from(private_key: &PrivateKey) -> SigningKey {
-        SigningKey(private_key.0)
+        SigningKey(curve::signing_seed(&private_key.0))
     }
 }
 
@@ -617,7 +1184,7 @@ pub(crate) struct SignedPreKey {
 impl SignedPreKey {
 
     /// Generates a new [`SignedPreKey`] key pair.
-    /// This function creates a new Curve25519 private key and derives the corresponding public key,
+    /// This function creates a new private key on the [`Curve::ACTIVE`] curve and derives the corresponding public key,
     /// forming a complete signed pre-key pair used in the X3DH protocol.
     ///
     /// # Returns
@@ -633,27 +1200,26 @@ impl SignedPreKey {
     }
 }
 
-/// A Curve25519 private key used in the X3DH key exchange for computing shared secrets.
+/// A private key on the [`Curve::ACTIVE`] curve used in the X3DH key exchange for computing shared secrets.
 #[derive(Clone, Zeroize, ZeroizeOnDrop)]
-pub struct PrivateKey([u8; CURVE25519_SECRET_LENGTH]);
+pub struct PrivateKey([u8; DH_SECRET_LENGTH]);
 
 impl PrivateKey {
 
-    /// Generates a new Curve25519 private key.
+    /// Generates a new private key on the [`Curve::ACTIVE`] curve.
     /// This function uses a cryptographically secure random number generator to produce
-    /// a new X25519 `StaticSecret`, returning it as a [`PrivateKey`] for use in key exchanges.
+    /// the key, returning it as a [`PrivateKey`] for use in key exchanges.
     ///
     /// # Returns
     ///
-    /// * [`PrivateKey`] - A randomly generated Curve25519 private key.
+    /// * [`PrivateKey`] - A randomly generated private key.
     pub fn new() -> PrivateKey {
-        let key = StaticSecret::random_from_rng(&mut OsRng);
-        PrivateKey(key.to_bytes())
+        PrivateKey(curve::generate_secret())
     }
 
     /// Performs a Diffie-Hellman key exchange with a given public key.
     /// This function computes the shared secret between this private key and a peer’s [`PublicKey`],
-    /// returning the resulting [`SharedSecret`] as a byte array.
+    /// returning the resulting [`DhOutput`], to be fed to HKDF.
     ///
     /// # Arguments
     ///
@@ -661,12 +1227,9 @@ impl PrivateKey {
     ///
     /// # Returns
     ///
-    /// * [`SharedSecret`] - The derived shared secret.
-    pub(crate) fn diffie_hellman(&self, public_key: &PublicKey) -> SharedSecret {
-        let dalek_private_key = StaticSecret::from(self.0);
-        let dalek_public_key = x25519_dalek::PublicKey::from(public_key.0);
-        let shared_secret = dalek_private_key.diffie_hellman(&dalek_public_key);
-        SharedSecret(shared_secret.to_bytes())
+    /// * [`DhOutput`] - The output of the exchange.
+    pub(crate) fn diffie_hellman(&self, public_key: &PublicKey) -> DhOutput {
+        DhOutput(curve::diffie_hellman(&self.0, &public_key.0))
     }
 
     /// Converts the current [`PrivateKey`] into bytes.
@@ -700,33 +1263,50 @@ impl PrivateKey {
     /// # Errors
     ///
     /// * [`X3DHError::Base64DecodeError`] - Returned if `value` is not a valid Base64 string.
-    /// * [`X3DHError::InvalidPrivateKey`] - Returned if the decoded byte vector does not match the expected size of [`CURVE25519_SECRET_LENGTH`].
+    /// * [`X3DHError::InvalidPrivateKey`] - Returned if the decoded byte vector does not match the expected size of [`DH_SECRET_LENGTH`].
     pub fn from_base64(value: String) -> Result<PrivateKey, X3DHError> {
         let bytes = general_purpose::STANDARD.decode(value)?;
-        if bytes.len() != CURVE25519_SECRET_LENGTH {
+        if bytes.len() != DH_SECRET_LENGTH {
             return Err(X3DHError::InvalidPrivateKey);
         }
-        let mut arr = [0u8; CURVE25519_SECRET_LENGTH];
+        let mut arr = [0u8; DH_SECRET_LENGTH];
         arr.copy_from_slice(&bytes);
         Ok(PrivateKey(arr))
     }
 }
 
-impl AsRef<[u8; CURVE25519_SECRET_LENGTH]> for PrivateKey {
+impl From<[u8; DH_SECRET_LENGTH]> for PrivateKey {
+
+    /// Creates a [`PrivateKey`] from its raw bytes.
+    ///
+    /// # Arguments
+    ///
+    /// * `value` - The bytes of the private key.
+    ///
+    /// # Returns
+    ///
+    /// * [`PrivateKey`] - The private key.
+    fn from(value: [u8; DH_SECRET_LENGTH]) -> PrivateKey {
+        PrivateKey(value)
+    }
+}
+
+impl AsRef<[u8; DH_SECRET_LENGTH]> for PrivateKey {
 
     /// Returns a shared reference to the current [`PrivateKey`].
     /// 
     /// # Returns
     /// 
-    /// * `&[u8; CURVE25519_SECRET_LENGTH]` - The shared reference.
-    fn as_ref(&self) -> &[u8; CURVE25519_SECRET_LENGTH] {
+    /// * `&[u8; DH_SECRET_LENGTH]` - The shared reference.
+    fn as_ref(&self) -> &[u8; DH_SECRET_LENGTH] {
         &self.0
     }
 }
 
+#[cfg(not(feature = "x448"))]
 impl From<SigningKey> for PrivateKey {
 
-    /// Derives a [`PrivateKey`] from a [`SigningKey`].
+    /// Derives a [`PrivateKey`] from a [`SigningKey`], which is a valid X25519 key as it is.
     /// 
     /// # Arguments
     /// 
@@ -736,11 +1316,11 @@ impl From<SigningKey> for PrivateKey {
     /// 
     /// * [`PrivateKey`] - The derived private key.
     fn from(private_key: SigningKey) -> PrivateKey {
-        let dalek_private_key = StaticSecret::from(private_key.0);
-        PrivateKey(dalek_private_key.to_bytes())
+        PrivateKey(private_key.0)
     }
 }
 
+#[cfg(not(feature = "x448"))]
 impl From<&SigningKey> for PrivateKey {
 
     /// Derives a [`PrivateKey`] from a shared reference to a [`SigningKey`].
@@ -753,15 +1333,14 @@ impl From<&SigningKey> for PrivateKey {
     /// 
     /// * [`PrivateKey`] - The derived private key.
     fn from(private_key: &SigningKey) -> PrivateKey {
-        let dalek_private_key = StaticSecret::from(private_key.0);
-        PrivateKey(dalek_private_key.to_bytes())
+        PrivateKey(private_key.0)
     }
 }
 
-/// A Curve25519 public key used in the X3DH protocol to represent identity, ephemeral, and pre-keys.
+/// A public key on the [`Curve::ACTIVE`] curve used in the X3DH protocol to represent identity, ephemeral, and pre-keys.
 /// This type can be derived from private or signing keys and is hashable and comparable.
-#[derive(Clone, Debug, Eq, Hash)]
-pub struct PublicKey(pub [u8; CURVE25519_PUBLIC_LENGTH]);
+#[derive(Clone, Debug, Eq)]
+pub struct PublicKey(pub [u8; DH_PUBLIC_LENGTH]);
 
 impl From<PrivateKey> for PublicKey {
 
@@ -775,9 +1354,7 @@ impl From<PrivateKey> for PublicKey {
     /// 
     /// * [`PublicKey`] - The derived public key.
     fn from(private_key: PrivateKey) -> PublicKey {
-        let dalek_private_key = x25519_dalek::StaticSecret::from(private_key.0);
-        let dalek_public_key = x25519_dalek::PublicKey::from(&dalek_private_key);
-        PublicKey(dalek_public_key.to_bytes())
+        PublicKey(curve::public_key(&private_key.0))
     }
 }
 
@@ -793,12 +1370,11 @@ impl From<&PrivateKey> for PublicKey {
     /// 
     /// * [`PublicKey`] - The derived public key.
     fn from(private_key: &PrivateKey) -> PublicKey {
-        let dalek_private_key = x25519_dalek::StaticSecret::from(private_key.0);
-        let dalek_public_key = x25519_dalek::PublicKey::from(&dalek_private_key);
-        PublicKey(dalek_public_key.to_bytes())
+        PublicKey(curve::public_key(&private_key.0))
     }
 }
 
+#[cfg(not(feature = "x448"))]
 impl From<VerifyingKey> for PublicKey {
 
     /// Derives a [`PublicKey`] from a [`VerifyingKey`].
@@ -815,6 +1391,7 @@ impl From<VerifyingKey> for PublicKey {
     }
 }
 
+#[cfg(not(feature = "x448"))]
 impl From<&VerifyingKey> for PublicKey {
 
     /// Derives a [`PublicKey`] from a shared reference to a [`VerifyingKey`].
@@ -831,6 +1408,7 @@ impl From<&VerifyingKey> for PublicKey {
     }
 }
 
+#[cfg(not(feature = "x448"))]
 impl From<SigningKey> for PublicKey {
 
     /// Derives a [`PublicKey`] from a [`SigningKey`].
@@ -848,6 +1426,7 @@ impl From<SigningKey> for PublicKey {
     }
 }
 
+#[cfg(not(feature = "x448"))]
 impl From<&SigningKey> for PublicKey {
 
     /// Derives a [`PublicKey`] from a shared reference to a [`SigningKey`].
@@ -865,9 +1444,9 @@ impl From<&SigningKey> for PublicKey {
     }
 }
 
-impl From<&[u8; CURVE25519_PUBLIC_LENGTH]> for PublicKey {
+impl From<&[u8; DH_PUBLIC_LENGTH]> for PublicKey {
 
-    /// Derives a [`PublicKey`] from a shared reference to a `[u8; `[CURVE25519_PUBLIC_LENGTH]`]`.
+    /// Derives a [`PublicKey`] from a shared reference to a `[u8; `[DH_PUBLIC_LENGTH]`]`.
     /// 
     /// # Arguments
     /// 
@@ -876,27 +1455,46 @@ impl From<&[u8; CURVE25519_PUBLIC_LENGTH]> for PublicKey {
     /// # Returns
     /// 
     /// * [`PublicKey`] - The derived public key.
-    fn from(value: &[u8; CURVE25519_PUBLIC_LENGTH]) -> PublicKey {
+    fn from(value: &[u8; DH_PUBLIC_LENGTH]) -> PublicKey {
         PublicKey(value.clone())
     }
 
 }
 
-impl AsRef<[u8; CURVE25519_PUBLIC_LENGTH]> for PublicKey {
+impl TryFrom<&[u8]> for PublicKey {
+    type Error = X3DHError;
+
+    /// Derives a [`PublicKey`] from its raw bytes.
+    ///
+    /// # Errors
+    ///
+    /// * [`X3DHError::InvalidPublicKey`] - Returned if `value` does not match the expected size of [`DH_PUBLIC_LENGTH`].
+    fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
+        if value.len() != DH_PUBLIC_LENGTH {
+            return Err(X3DHError::InvalidPublicKey);
+        }
+        Ok(PublicKey(*array_ref!(value, 0, DH_PUBLIC_LENGTH)))
+    }
+}
+
+impl AsRef<[u8; DH_PUBLIC_LENGTH]> for PublicKey {
 
     /// Returns a shared reference to the current [`PublicKey`].
     /// 
     /// # Returns
     /// 
-    /// * `&[u8; CURVE25519_PUBLIC_LENGTH]` - The shared reference.
-    fn as_ref(&self) -> &[u8; CURVE25519_PUBLIC_LENGTH] {
+    /// * `&[u8; DH_PUBLIC_LENGTH]` - The shared reference.
+    fn as_ref(&self) -> &[u8; DH_PUBLIC_LENGTH] {
         &self.0
     }
 }
 
 impl PartialEq for PublicKey {
 
-    /// Compares two [`PublicKey`] instances for equality.
+    /// Compares two [`PublicKey`] instances for equality in constant time.
+    ///
+    /// Public keys index the skipped message keys of a [`crate::ratchet::Ratchet`], so the comparison
+    /// must not reveal how many leading bytes of a probed key match a stored one.
     ///
     /// # Arguments
     ///
@@ -906,19 +1504,71 @@ impl PartialEq for PublicKey {
     ///
     /// * `bool` - `true` if the underlying byte representations of both keys are equal, otherwise `false`.
     fn eq(&self, other: &Self) -> bool {
-        self.as_ref() == other.as_ref()
+        self.ct_eq(other)
     }
 }
 
-impl PublicKey {
+impl Hash for PublicKey {
 
-    /// Returns the SHA-256 hash of the current [`PublicKey`].
+    /// Hashes the byte representation of the key, which equal keys share.
     ///
-    /// # Returns
+    /// # Arguments
+    ///
+    /// * `state` - The hasher to feed.
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.0.hash(state);
+    }
+}
+
+impl PublicKey {
+
+    /// Compares the current [`PublicKey`] with another one in constant time.
+    ///
+    /// # Arguments
+    ///
+    /// * `other` - The other [`PublicKey`] to compare against.
+    ///
+    /// # Returns
+    ///
+    /// * `bool` - `true` if both keys have the same bytes.
+    pub fn ct_eq(&self, other: &PublicKey) -> bool {
+        self.ct_eq_bytes(other.as_ref())
+    }
+
+    /// Compares the current [`PublicKey`] with raw bytes in constant time, such as a decrypted challenge.
+    ///
+    /// Only the content is compared in constant time: bytes of another length differ at once.
+    ///
+    /// # Arguments
+    ///
+    /// * `bytes` - The bytes to compare against.
+    ///
+    /// # Returns
+    ///
+    /// * `bool` - `true` if `bytes` are the bytes of the key.
+    pub fn ct_eq_bytes(&self, bytes: &[u8]) -> bool {
+        self.0[..].ct_eq(bytes).into()
+    }
+
+    /// Checks that the [`PublicKey`] is not a low-order curve point.
+    ///
+    /// A Diffie-Hellman exchange with a low-order point yields a fixed shared secret
+    /// regardless of the private key, so such keys must be rejected.
+    ///
+    /// # Returns
+    ///
+    /// * `bool` - `true` if a Diffie-Hellman exchange with the key is contributory.
+    pub(crate) fn is_valid_point(&self) -> bool {
+        curve::is_contributory(&self.0)
+    }
+
+    /// Returns the SHA-256 hash of the current [`PublicKey`].
+    ///
+    /// # Returns
     ///
     /// * [`Sha256Hash`] - The SHA-256 digest of the public key.
     pub fn hash(&self) -> Sha256Hash {
-        let digest = Sha256::digest(self.0.as_ref());
+        let digest = Sha256::digest(self.0);
         Sha256Hash(*array_ref![digest, 0, SHA256_HASH_LENGTH])
     }
 
@@ -944,15 +1594,10 @@ impl PublicKey {
     /// # Errors
     ///
     /// * [`X3DHError::Base64DecodeError`] - Returned if `value` is not a valid Base64 string.
-    /// * [`X3DHError::InvalidPublicKey`] - Returned if the decoded byte vector does not match the expected size of [`CURVE25519_PUBLIC_LENGTH`].
+    /// * [`X3DHError::InvalidPublicKey`] - Returned if the decoded byte vector does not match the expected size of [`DH_PUBLIC_LENGTH`].
     pub fn from_base64(value: String) -> Result<PublicKey, X3DHError> {
         let bytes = general_purpose::STANDARD.decode(value)?;
-        if bytes.len() != CURVE25519_PUBLIC_LENGTH {
-            return Err(X3DHError::InvalidPublicKey);
-        }
-        let mut arr = [0u8; CURVE25519_PUBLIC_LENGTH];
-        arr.copy_from_slice(&bytes);
-        Ok(PublicKey(arr))
+        PublicKey::try_from(bytes.as_slice())
     }
 }
 
@@ -960,6 +1605,38 @@ impl PublicKey {
 #[derive(Clone, Debug)]
 pub struct Signature(pub [u8; SIGNATURE_LENGTH]);
 
+impl Signature {
+
+    /// Converts the current [`Signature`] into a base64-encoded string.
+    ///
+    /// # Returns
+    ///
+    /// * `String` - The base64-encoded string of the current [`Signature`].
+    pub fn to_base64(&self) -> String {
+        general_purpose::STANDARD.encode(self.0)
+    }
+
+    /// Converts a base64-encoded string into a [`Signature`].
+    ///
+    /// # Arguments
+    ///
+    /// * `value` - The base64-encoded string to be converted.
+    ///
+    /// # Returns
+    ///
+    /// * [`Signature`] - The decoded signature.
+    ///
+    /// # Errors
+    ///
+    /// * [`X3DHError::Base64DecodeError`] - Returned if `value` is not a valid Base64 string.
+    /// * [`X3DHError::InvalidKey`] - Returned if the decoded byte vector does not match the expected size of [`SIGNATURE_LENGTH`].
+    pub fn from_base64(value: String) -> Result<Signature, X3DHError> {
+        let bytes = general_purpose::STANDARD.decode(value)?;
+        let sig: [u8; SIGNATURE_LENGTH] = bytes.try_into().map_err(|_| X3DHError::InvalidKey)?;
+        Ok(Signature(sig))
+    }
+}
+
 impl AsRef<[u8; SIGNATURE_LENGTH]> for Signature {
 
     /// Returns a shared reference to the current [`Signature`].
@@ -1002,14 +1679,14 @@ pub struct AssociatedData {
 impl AssociatedData {
 
     /// Total size in bytes of the associated data, which is the sum of the two public key lengths
-    pub const SIZE: usize = CURVE25519_PUBLIC_LENGTH + CURVE25519_PUBLIC_LENGTH;
+    pub const SIZE: usize = DH_PUBLIC_LENGTH + DH_PUBLIC_LENGTH;
 
     /// Converts the current [`AssociatedData`] into bytes.
     ///
     /// # Returns
     ///
     /// * `Vec<u8>` - A vector of bytes derived from the current [`AssociatedData`].
-    pub fn to_bytes(self) -> Vec<u8> {
+    pub fn to_bytes(&self) -> Vec<u8> {
         let mut out = Vec::new();
         out.extend_from_slice(self.initiator_identity_key.0.as_ref());
         out.extend_from_slice(self.responder_identity_key.0.as_ref());
@@ -1032,6 +1709,44 @@ impl AssociatedData {
             responder_identity_key: spk,
         }
     }
+
+    /// Returns the identity public key of the initiator, the sender of the messages bound to this associated data.
+    pub fn initiator_identity_key(&self) -> &PublicKey {
+        &self.initiator_identity_key
+    }
+
+    /// Returns the identity public key of the responder, the recipient of the messages bound to this associated data.
+    pub fn responder_identity_key(&self) -> &PublicKey {
+        &self.responder_identity_key
+    }
+
+    /// Returns the safety number of the session bound to this associated data, see
+    /// [`crate::fingerprint::compute_safety_number`]. It is the same on both sides, whichever direction the
+    /// associated data is in, provided both sides name the parties alike.
+    ///
+    /// # Arguments
+    ///
+    /// * `initiator_name` - The username of the party whose identity key is [`AssociatedData::initiator_identity_key`].
+    /// * `responder_name` - The username of the party whose identity key is [`AssociatedData::responder_identity_key`].
+    ///
+    /// # Returns
+    ///
+    /// * `String` - The digits of the safety number.
+    pub fn safety_number(&self, initiator_name: &str, responder_name: &str) -> String {
+        crate::fingerprint::compute_safety_number(&self.initiator_identity_key, initiator_name, &self.responder_identity_key, responder_name)
+    }
+
+    /// Returns the [`AssociatedData`] of the opposite direction, with the two identity keys swapped.
+    ///
+    /// # Returns
+    ///
+    /// * [`AssociatedData`] - A new instance with the responder's identity key first.
+    pub fn reversed(&self) -> Self {
+        Self {
+            initiator_identity_key: self.responder_identity_key.clone(),
+            responder_identity_key: self.initiator_identity_key.clone(),
+        }
+    }
 }
 
 impl TryFrom<&[u8; Self::SIZE]> for AssociatedData {
@@ -1047,11 +1762,11 @@ impl TryFrom<&[u8; Self::SIZE]> for AssociatedData {
     ///
     /// * `Ok(AssociatedData)` - If the conversion is successful.
     fn try_from(value: &[u8; Self::SIZE]) -> Result<Self, Self::Error> {
-        let initiator_identity_key = PublicKey(*array_ref![value, 0, CURVE25519_PUBLIC_LENGTH]);
+        let initiator_identity_key = PublicKey(*array_ref![value, 0, DH_PUBLIC_LENGTH]);
         let responder_identity_key = PublicKey(*array_ref![
             value,
-            CURVE25519_PUBLIC_LENGTH,
-            CURVE25519_PUBLIC_LENGTH
+            DH_PUBLIC_LENGTH,
+            DH_PUBLIC_LENGTH
         ]);
         Ok(AssociatedData {
             initiator_identity_key,
@@ -1060,6 +1775,29 @@ impl TryFrom<&[u8; Self::SIZE]> for AssociatedData {
     }
 }
 
+impl Serialize for AssociatedData {
+
+    /// Serializes the [`AssociatedData`] as its bytes, see [`AssociatedData::to_bytes`].
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(&self.to_bytes())
+    }
+}
+
+impl<'de> Deserialize<'de> for AssociatedData {
+
+    /// Deserializes an [`AssociatedData`] from its bytes.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the length is not [`AssociatedData::SIZE`].
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let bytes = serde_bytes::ByteBuf::deserialize(deserializer)?;
+        let bytes = <&[u8; Self::SIZE]>::try_from(bytes.as_slice())
+            .map_err(|_| serde::de::Error::invalid_length(bytes.len(), &"the two identity keys"))?;
+        AssociatedData::try_from(bytes).map_err(serde::de::Error::custom)
+    }
+}
+
 /// A SHA-256 hash used for identifying and verifying keys or values in the X3DH protocol.
 #[derive(Clone, Eq, Debug)]
 pub struct Sha256Hash(pub [u8; SHA256_HASH_LENGTH]);
@@ -1095,7 +1833,7 @@ impl Hash for Sha256Hash {
 
 impl PartialEq for Sha256Hash {
 
-    /// Compares two [`Sha256Hash`] values for equality based on their byte content.
+    /// Compares two [`Sha256Hash`] values for equality based on their byte content, in constant time.
     ///
     /// # Arguments
     ///
@@ -1105,7 +1843,23 @@ impl PartialEq for Sha256Hash {
     ///
     /// * `true` if the internal byte arrays are equal, otherwise `false`.
     fn eq(&self, other: &Self) -> bool {
-        self.0 == other.0
+        self.ct_eq(other)
+    }
+}
+
+impl Sha256Hash {
+
+    /// Compares the current [`Sha256Hash`] with another one in constant time.
+    ///
+    /// # Arguments
+    ///
+    /// * `other` - The other [`Sha256Hash`] to compare against.
+    ///
+    /// # Returns
+    ///
+    /// * `bool` - `true` if both hashes have the same bytes.
+    pub fn ct_eq(&self, other: &Sha256Hash) -> bool {
+        self.0.ct_eq(&other.0).into()
     }
 }
 
@@ -1155,7 +1909,11 @@ impl TryFrom<&[u8]> for Challenge {
 }
 
 /// A message sent by the initiator in the X3DH key exchange protocol.
-#[derive(Clone)]
+///
+/// With serde, the message is a versioned map whose keys are raw bytes, see [`InitialMessage::SERDE_VERSION`].
+/// The base64 packing of [`InitialMessage::to_base64`] is the legacy format, read as version 0.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(into = "InitialMessageRepr", try_from = "InitialMessageRepr")]
 pub struct InitialMessage {
     /// The initiator’s identity public key.
     pub identity_key: PublicKey,
@@ -1166,25 +1924,144 @@ pub struct InitialMessage {
     /// The SHA-256 hash of the responder’s signed pre-key.
     pub prekey_hash: Sha256Hash,
 
-    /// Optional SHA-256 hash of the responder’s one-time pre-key.
+    /// Optional SHA-256 hash of the responder’s one-time pre-key, used when the bundle had no one-time pre-key ids.
     pub one_time_key_hash: Option<Sha256Hash>,
 
-    /// A challenge generated by the initiator for authentication.
-    pub challenge: Challenge,
+    /// Optional id of the responder’s one-time pre-key, see [`PreKeyBundle::otpk_ids`].
+    pub one_time_key_id: Option<u32>,
+
+    /// A challenge generated by the initiator for authentication, absent in [`Conformance::Spec`].
+    pub challenge: Option<Challenge>,
 
     /// Associated identity key data for both parties.
     pub associated_data: AssociatedData,
+
+    /// The AEAD suite of the session, taken from the responder's [`PreKeyBundle::aead_suite`].
+    /// The challenge is encrypted with it, so a message whose suite was changed on the way fails to authenticate.
+    pub aead_suite: AeadSuite,
+
+    /// The secret encapsulated to the responder's [`PreKeyBundle::kem_prekey`], if the key agreement is hybrid.
+    /// The secret is part of the derived keys, so a message whose ciphertext was stripped fails to authenticate.
+    pub kem_ciphertext: Option<KemCiphertext>,
+
+    /// The derivations of the session, chosen by the initiator, see [`crate::interop`].
+    /// The keys depend on it, so a message whose conformance was changed on the way fails to authenticate.
+    pub conformance: Conformance,
+}
+
+/// The serde form of an [`InitialMessage`].
+///
+/// Fields added in later versions must have a default, so that older messages still deserialize.
+/// The curve keys are read whatever their size, so that keys on another curve are refused as such.
+#[derive(Serialize, Deserialize)]
+struct InitialMessageRepr {
+    version: u8,
+    #[serde(default)]
+    curve: u8,
+    identity_key: serde_bytes::ByteBuf,
+    ephemeral_key: serde_bytes::ByteBuf,
+    #[serde(with = "serde_bytes")]
+    prekey_hash: [u8; SHA256_HASH_LENGTH],
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    one_time_key_hash: Option<serde_bytes::ByteArray<SHA256_HASH_LENGTH>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    one_time_key_id: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    challenge: Option<serde_bytes::ByteArray<CHALLENGE_LENGTH>>,
+    initiator_identity_key: serde_bytes::ByteBuf,
+    responder_identity_key: serde_bytes::ByteBuf,
+    #[serde(default)]
+    aead_suite: u8,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    kem_ciphertext: Option<serde_bytes::ByteBuf>,
+    #[serde(default)]
+    conformance: u8,
+}
+
+impl From<InitialMessage> for InitialMessageRepr {
+    fn from(message: InitialMessage) -> Self {
+        InitialMessageRepr {
+            version: InitialMessage::SERDE_VERSION,
+            curve: Curve::ACTIVE.id(),
+            identity_key: serde_bytes::ByteBuf::from(message.identity_key.0.to_vec()),
+            ephemeral_key: serde_bytes::ByteBuf::from(message.ephemeral_key.0.to_vec()),
+            prekey_hash: message.prekey_hash.0,
+            one_time_key_hash: message.one_time_key_hash.map(|h| serde_bytes::ByteArray::new(h.0)),
+            one_time_key_id: message.one_time_key_id,
+            challenge: message.challenge.map(|c| serde_bytes::ByteArray::new(c.0)),
+            initiator_identity_key: serde_bytes::ByteBuf::from(message.associated_data.initiator_identity_key.0.to_vec()),
+            responder_identity_key: serde_bytes::ByteBuf::from(message.associated_data.responder_identity_key.0.to_vec()),
+            aead_suite: message.aead_suite.id(),
+            kem_ciphertext: message.kem_ciphertext.map(|ct| serde_bytes::ByteBuf::from(ct.0)),
+            conformance: message.conformance.id(),
+        }
+    }
+}
+
+impl TryFrom<InitialMessageRepr> for InitialMessage {
+    type Error = X3DHError;
+
+    /// Converts the serde form back into an [`InitialMessage`].
+    ///
+    /// # Errors
+    ///
+    /// * [`X3DHError::UnsupportedInitialMessageVersion`] - Returned if the message was written by a newer version,
+    ///   or claims to be in the legacy format.
+    /// * [`X3DHError::UnknownCurve`] - Returned if the message names a curve this crate does not know.
+    /// * [`X3DHError::CurveMismatch`] - Returned if the keys of the message are on a curve other than [`Curve::ACTIVE`].
+    /// * [`X3DHError::UnknownAeadSuite`] - Returned if the message names an AEAD suite this crate does not know.
+    /// * [`X3DHError::UnknownConformance`] - Returned if the message names a conformance this crate does not know.
+    /// * [`X3DHError::InvalidInitialMessage`] - Returned if a key or the KEM ciphertext has the wrong size,
+    ///   or if the message has a challenge although its conformance sends none, or the other way round.
+    fn try_from(repr: InitialMessageRepr) -> Result<Self, Self::Error> {
+        if repr.version == InitialMessage::LEGACY_VERSION || repr.version > InitialMessage::SERDE_VERSION {
+            return Err(X3DHError::UnsupportedInitialMessageVersion(repr.version));
+        }
+        Curve::try_from(repr.curve)?.check_active()?;
+        let conformance = Conformance::try_from(repr.conformance)?;
+        if conformance.has_challenge() != repr.challenge.is_some() {
+            return Err(X3DHError::InvalidInitialMessage);
+        }
+        let key = |bytes: &serde_bytes::ByteBuf| {
+            PublicKey::try_from(bytes.as_slice()).map_err(|_| X3DHError::InvalidInitialMessage)
+        };
+        Ok(InitialMessage {
+            identity_key: key(&repr.identity_key)?,
+            ephemeral_key: key(&repr.ephemeral_key)?,
+            prekey_hash: Sha256Hash(repr.prekey_hash),
+            one_time_key_hash: repr.one_time_key_hash.map(|h| Sha256Hash(h.into_array())),
+            one_time_key_id: repr.one_time_key_id,
+            challenge: repr.challenge.map(|c| Challenge(c.into_array())),
+            associated_data: AssociatedData {
+                initiator_identity_key: key(&repr.initiator_identity_key)?,
+                responder_identity_key: key(&repr.responder_identity_key)?,
+            },
+            aead_suite: AeadSuite::try_from(repr.aead_suite)?,
+            kem_ciphertext: repr.kem_ciphertext.map(|ct| KemCiphertext::try_from(ct.as_slice())).transpose()?,
+            conformance,
+        })
+    }
 }
 
 impl InitialMessage {
-    
+
+    /// The version of the base64 packing of [`InitialMessage::to_base64`], which carries no version byte.
+    pub const LEGACY_VERSION: u8 = 0;
+
+    /// The format version written by the serde implementation of [`InitialMessage`].
+    /// Version 2 adds [`InitialMessage::one_time_key_id`], version 3 [`InitialMessage::aead_suite`],
+    /// version 4 [`InitialMessage::kem_ciphertext`], version 5 the [`Curve`] of the keys, which older
+    /// messages leave to [`Curve::X25519`], version 6 [`InitialMessage::conformance`], which older messages
+    /// leave to [`Conformance::Native`], and makes the challenge optional.
+    pub const SERDE_VERSION: u8 = 6;
+
     /// The base byte size without an optional one-time prekey hash.
-    pub(crate) const BASE_SIZE: usize = CURVE25519_PUBLIC_LENGTH
-        + CURVE25519_PUBLIC_LENGTH
+    pub(crate) const BASE_SIZE: usize = DH_PUBLIC_LENGTH
+        + DH_PUBLIC_LENGTH
         + SHA256_HASH_LENGTH
         + CHALLENGE_LENGTH
-        + CURVE25519_PUBLIC_LENGTH
-        + CURVE25519_PUBLIC_LENGTH;
+        + DH_PUBLIC_LENGTH
+        + DH_PUBLIC_LENGTH;
 
     /// The total byte size of the message when the one-time prekey hash is included.
     pub(crate) const SIZE_WITH_OTPK: usize = Self::BASE_SIZE + SHA256_HASH_LENGTH;
@@ -1198,34 +2075,57 @@ impl InitialMessage {
         self.associated_data.clone()
     }
 
+    /// Returns `true` if the initiator used one of the responder's one-time pre-keys, named by id or by hash.
+    pub fn uses_one_time_prekey(&self) -> bool {
+        self.one_time_key_id.is_some() || self.one_time_key_hash.is_some()
+    }
+
     /// Converts the current [`InitialMessage`] into bytes.
     ///
     /// # Returns
     ///
     /// * `Vec<u8>` - A vector of bytes derived from the current [`InitialMessage`].
-    pub fn to_bytes(self) -> Vec<u8> {
+    pub fn to_bytes(&self) -> Vec<u8> {
         let mut out = Vec::new();
         out.extend_from_slice(self.identity_key.0.as_ref());
         out.extend_from_slice(self.ephemeral_key.0.as_ref());
         out.extend_from_slice(self.prekey_hash.0.as_ref());
 
-        if let Some(one_time_key_hash) = self.one_time_key_hash {
+        if let Some(one_time_key_hash) = &self.one_time_key_hash {
             out.extend_from_slice(one_time_key_hash.0.as_ref());
         }
-        out.extend_from_slice(self.challenge.0.as_ref());
+        if let Some(challenge) = &self.challenge {
+            out.extend_from_slice(challenge.0.as_ref());
+        }
         out.extend_from_slice(self.associated_data.to_bytes().as_ref());
         out
     }
 
     /// Converts the current [`InitialMessage`] into a base64-encoded string.
     ///
+    /// This is the legacy format, see [`InitialMessage::to_json`] for the versioned one.
+    /// It cannot carry [`InitialMessage::one_time_key_id`], only the hash of the one-time pre-key,
+    /// nor [`InitialMessage::aead_suite`], which is read back as [`AeadSuite::Aes256Gcm`],
+    /// nor [`InitialMessage::kem_ciphertext`], so it only fits classic key agreements,
+    /// nor the [`Curve`] of the keys, so both ends must be built for the same one,
+    /// nor [`InitialMessage::conformance`], so it only fits [`Conformance::Native`] messages, with a challenge.
+    ///
     /// # Returns
     ///
     /// * `String` - The base64-encoded string of the current [`InitialMessage`].
-    pub fn to_base64(self) -> String {
+    pub fn to_base64(&self) -> String {
         general_purpose::STANDARD.encode(self.to_bytes())
     }
 
+    /// Converts the current [`InitialMessage`] into its versioned JSON form.
+    ///
+    /// # Returns
+    ///
+    /// * `String` - The JSON serialization of the current [`InitialMessage`], at [`Self::SERDE_VERSION`].
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("Serializing an initial message cannot fail")
+    }
+
     /// Calculates the size of the current [`InitialMessage`].
     ///
     /// # Returns
@@ -1245,11 +2145,11 @@ impl InitialMessage {
 impl TryFrom<String> for InitialMessage {
     type Error = X3DHError;
 
-    /// Derives a [`InitialMessage`] from base64-encoded string.
+    /// Derives a [`InitialMessage`] from its versioned JSON form, or from the legacy base64-encoded string.
     ///
     /// # Arguments
     ///
-    /// * `value` - A base64-encoded string.
+    /// * `value` - A JSON object as written by [`InitialMessage::to_json`], or a base64-encoded string.
     ///
     /// # Returns
     ///
@@ -1257,41 +2157,54 @@ impl TryFrom<String> for InitialMessage {
     /// 
     /// # Errors
     /// 
+    /// * [`X3DHError::UnsupportedInitialMessageVersion`] - Returned if the JSON form has a version this crate cannot read.
+    /// * [`X3DHError::UnknownAeadSuite`] - Returned if the JSON form names an AEAD suite this crate does not know.
     /// * [`X3DHError::Base64DecodeError`] - Returned if `value` is not a valid Base64 string.
-    /// * [`X3DHError::InvalidInitialMessage`] - Returned if the decoded byte vector does not match the expected size of [`Self::BASE_SIZE`] or [`Self::SIZE_WITH_OTPK`].
+    /// * [`X3DHError::InvalidChallenge`] - Returned if the message has the size of the former format, whose challenge has no nonce.
+    /// * [`X3DHError::InvalidInitialMessage`] - Returned if the JSON form is malformed, or if the decoded byte vector does not
+    ///   match the expected size of [`Self::BASE_SIZE`] or [`Self::SIZE_WITH_OTPK`].
     fn try_from(value: String) -> Result<Self, Self::Error> {
+        if value.trim_start().starts_with('{') {
+            return serde_json::from_str::<InitialMessageRepr>(&value)
+                .map_err(|_| X3DHError::InvalidInitialMessage)
+                .and_then(InitialMessage::try_from);
+        }
         let bytes = general_purpose::STANDARD.decode(value)?;
+        let legacy_offset = CHALLENGE_LENGTH - LEGACY_CHALLENGE_LENGTH;
+        if bytes.len() == Self::BASE_SIZE - legacy_offset || bytes.len() == Self::SIZE_WITH_OTPK - legacy_offset {
+            return Err(X3DHError::InvalidChallenge);
+        }
         if bytes.len() != Self::BASE_SIZE && bytes.len() != Self::SIZE_WITH_OTPK {
             return Err(X3DHError::InvalidInitialMessage);
         }
 
-        let identity_key = PublicKey(*array_ref![bytes, 0, CURVE25519_PUBLIC_LENGTH]);
+        let identity_key = PublicKey(*array_ref![bytes, 0, DH_PUBLIC_LENGTH]);
         let ephemeral_key = PublicKey(*array_ref![
             bytes,
-            CURVE25519_PUBLIC_LENGTH,
-            CURVE25519_PUBLIC_LENGTH
+            DH_PUBLIC_LENGTH,
+            DH_PUBLIC_LENGTH
         ]);
         let prekey_hash = Sha256Hash(*array_ref![
             bytes,
-            2 * CURVE25519_PUBLIC_LENGTH,
+            2 * DH_PUBLIC_LENGTH,
             SHA256_HASH_LENGTH
         ]);
 
         if bytes.len() == Self::SIZE_WITH_OTPK {
             let one_time_key_hash = Sha256Hash(*array_ref![
                 bytes,
-                2 * CURVE25519_PUBLIC_LENGTH + SHA256_HASH_LENGTH,
+                2 * DH_PUBLIC_LENGTH + SHA256_HASH_LENGTH,
                 SHA256_HASH_LENGTH
             ]);
             let challenge = Challenge(*array_ref![
                 bytes,
-                2 * CURVE25519_PUBLIC_LENGTH + 2 * SHA256_HASH_LENGTH,
+                2 * DH_PUBLIC_LENGTH + 2 * SHA256_HASH_LENGTH,
                 CHALLENGE_LENGTH
             ]);
             let associated_data = AssociatedData::try_from(array_ref![
                 bytes,
-                2 * CURVE25519_PUBLIC_LENGTH + 2 * SHA256_HASH_LENGTH + CHALLENGE_LENGTH,
-                2 * CURVE25519_PUBLIC_LENGTH
+                2 * DH_PUBLIC_LENGTH + 2 * SHA256_HASH_LENGTH + CHALLENGE_LENGTH,
+                2 * DH_PUBLIC_LENGTH
             ])?;
 
             Ok(Self {
@@ -1299,27 +2212,35 @@ impl TryFrom<String> for InitialMessage {
                 ephemeral_key,
                 prekey_hash,
                 one_time_key_hash: Some(one_time_key_hash),
-                challenge,
+                one_time_key_id: None,
+                challenge: Some(challenge),
                 associated_data,
+                aead_suite: AeadSuite::Aes256Gcm,
+                kem_ciphertext: None,
+                conformance: Conformance::Native,
             })
         } else {
             let challenge = Challenge(*array_ref![
                 bytes,
-                2 * CURVE25519_PUBLIC_LENGTH + SHA256_HASH_LENGTH,
+                2 * DH_PUBLIC_LENGTH + SHA256_HASH_LENGTH,
                 CHALLENGE_LENGTH
             ]);
             let associated_data = AssociatedData::try_from(array_ref![
                 bytes,
-                2 * CURVE25519_PUBLIC_LENGTH + SHA256_HASH_LENGTH + CHALLENGE_LENGTH,
-                2 * CURVE25519_PUBLIC_LENGTH
+                2 * DH_PUBLIC_LENGTH + SHA256_HASH_LENGTH + CHALLENGE_LENGTH,
+                2 * DH_PUBLIC_LENGTH
             ])?;
             Ok(Self {
                 identity_key,
                 ephemeral_key,
                 prekey_hash,
                 one_time_key_hash: None,
-                challenge,
+                one_time_key_id: None,
+                challenge: Some(challenge),
                 associated_data,
+                aead_suite: AeadSuite::Aes256Gcm,
+                kem_ciphertext: None,
+                conformance: Conformance::Native,
             })
         }
     }
@@ -1327,46 +2248,317 @@ impl TryFrom<String> for InitialMessage {
 
 
 
-/// A 256-bit AES key used for encrypting messages in the X3DH session.
+/// An ML-KEM-768 encapsulation key, the public half of a [`KemPrivateKey`], published in a [`PreKeyBundle`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KemPublicKey(Vec<u8>);
+
+impl AsRef<[u8]> for KemPublicKey {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl TryFrom<&[u8]> for KemPublicKey {
+    type Error = X3DHError;
+
+    /// Reads an encoded ML-KEM-768 encapsulation key.
+    ///
+    /// # Errors
+    ///
+    /// * [`X3DHError::InvalidKemPreKey`] - Returned if `value` is not [`KEM_PUBLIC_LENGTH`] bytes long.
+    fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
+        if value.len() != KEM_PUBLIC_LENGTH {
+            return Err(X3DHError::InvalidKemPreKey);
+        }
+        Ok(KemPublicKey(value.to_vec()))
+    }
+}
+
+#[cfg(feature = "pqxdh")]
+impl KemPublicKey {
+
+    /// Encapsulates a fresh secret to this key.
+    ///
+    /// # Returns
+    ///
+    /// * `(KemCiphertext, SharedSecret)` - The ciphertext for the owner of the key, and the secret it encapsulates.
+    pub(crate) fn encapsulate(&self) -> (KemCiphertext, SharedSecret) {
+        let encoded = Encoded::<<MlKem768 as KemCore>::EncapsulationKey>::try_from(self.0.as_slice())
+            .expect("The length of a KEM public key is checked when it is read");
+        let (ct, secret) = <MlKem768 as KemCore>::EncapsulationKey::from_bytes(&encoded)
+            .encapsulate(&mut OsRng)
+            .expect("ML-KEM encapsulation cannot fail");
+        (KemCiphertext(ct.to_vec()), SharedSecret(*array_ref!(secret, 0, AES256_SECRET_LENGTH)))
+    }
+}
+
+/// An ML-KEM-768 ciphertext, carried in the [`InitialMessage`] of a hybrid key agreement.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KemCiphertext(Vec<u8>);
+
+impl AsRef<[u8]> for KemCiphertext {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl TryFrom<&[u8]> for KemCiphertext {
+    type Error = X3DHError;
+
+    /// Reads an ML-KEM-768 ciphertext.
+    ///
+    /// # Errors
+    ///
+    /// * [`X3DHError::InvalidInitialMessage`] - Returned if `value` is not [`KEM_CIPHERTEXT_LENGTH`] bytes long.
+    fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
+        if value.len() != KEM_CIPHERTEXT_LENGTH {
+            return Err(X3DHError::InvalidInitialMessage);
+        }
+        Ok(KemCiphertext(value.to_vec()))
+    }
+}
+
+/// An ML-KEM-768 decapsulation key, kept by the owner of a [`PreKeyBundle`] whose
+/// [`PreKeyBundle::kem_prekey`] is its public half.
+#[cfg(feature = "pqxdh")]
+#[derive(Clone, Zeroize, ZeroizeOnDrop)]
+pub struct KemPrivateKey(Vec<u8>);
+
+#[cfg(feature = "pqxdh")]
+impl KemPrivateKey {
+
+    /// Generates a new ML-KEM-768 key pair.
+    ///
+    /// # Returns
+    ///
+    /// * [`KemPrivateKey`] - The decapsulation key, from which [`KemPrivateKey::public_key`] is derived.
+    pub fn new() -> KemPrivateKey {
+        let (dk, _) = MlKem768::generate(&mut OsRng);
+        KemPrivateKey(dk.as_bytes().to_vec())
+    }
+
+    fn decapsulation_key(&self) -> <MlKem768 as KemCore>::DecapsulationKey {
+        let encoded = Encoded::<<MlKem768 as KemCore>::DecapsulationKey>::try_from(self.0.as_slice())
+            .expect("The length of a KEM private key is checked when it is read");
+        <MlKem768 as KemCore>::DecapsulationKey::from_bytes(&encoded)
+    }
+
+    /// Returns the encapsulation key to publish with [`PreKeyBundle::set_kem_prekey`].
+    pub fn public_key(&self) -> KemPublicKey {
+        KemPublicKey(self.decapsulation_key().encapsulation_key().as_bytes().to_vec())
+    }
+
+    /// Decapsulates the secret of a ciphertext sent by an initiator.
+    ///
+    /// ML-KEM rejects implicitly: a ciphertext that was not encapsulated to this key yields an unrelated
+    /// secret rather than an error, so the mismatch shows when the challenge fails to decrypt.
+    pub(crate) fn decapsulate(&self, ct: &KemCiphertext) -> SharedSecret {
+        let ct = ml_kem::Ciphertext::<MlKem768>::try_from(ct.0.as_slice())
+            .expect("The length of a KEM ciphertext is checked when it is read");
+        let secret = self.decapsulation_key()
+            .decapsulate(&ct)
+            .expect("ML-KEM decapsulation cannot fail");
+        SharedSecret(*array_ref!(secret, 0, AES256_SECRET_LENGTH))
+    }
+
+    /// Converts the current [`KemPrivateKey`] into bytes, the encoded decapsulation key.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.0.clone()
+    }
+}
+
+#[cfg(feature = "pqxdh")]
+impl TryFrom<&[u8]> for KemPrivateKey {
+    type Error = X3DHError;
+
+    /// Reads an encoded ML-KEM-768 decapsulation key, as written by [`KemPrivateKey::to_bytes`].
+    ///
+    /// # Errors
+    ///
+    /// * [`X3DHError::InvalidPrivateKey`] - Returned if `value` does not have the size of a decapsulation key.
+    fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
+        Encoded::<<MlKem768 as KemCore>::DecapsulationKey>::try_from(value)
+            .map_err(|_| X3DHError::InvalidPrivateKey)?;
+        Ok(KemPrivateKey(value.to_vec()))
+    }
+}
+
+/// The AEAD algorithm a session encrypts with, offered in the [`PreKeyBundle`] of the responder and
+/// confirmed in the [`InitialMessage`]. Both parties of a session must use the same one.
+///
+/// Both algorithms take a 256-bit key and a 96-bit nonce and append a 128-bit tag, so their frames have the same layout.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum AeadSuite {
+    /// AES-256 in Galois/Counter Mode, the suite of sessions that predate the negotiation.
+    #[default]
+    Aes256Gcm,
+
+    /// ChaCha20-Poly1305, faster than AES-256-GCM on devices without AES instructions.
+    ChaCha20Poly1305,
+}
+
+impl AeadSuite {
+
+    /// Returns the id of the suite in a serialized [`PreKeyBundle`], [`InitialMessage`] or ratchet state.
+    pub fn id(&self) -> u8 {
+        match self {
+            AeadSuite::Aes256Gcm => 0,
+            AeadSuite::ChaCha20Poly1305 => 1,
+        }
+    }
+
+    /// Returns the name of the suite, as it appears in the cipher suite of a ratchet, such as `X25519_AES256GCM_HMAC_SHA256`.
+    pub fn name(&self) -> &'static str {
+        match self {
+            AeadSuite::Aes256Gcm => "AES256GCM",
+            AeadSuite::ChaCha20Poly1305 => "CHACHA20POLY1305",
+        }
+    }
+
+    /// Keys the suite with `key`, expanding the key schedule once for all the messages encrypted under it.
+    fn cipher(&self, key: &[u8; AES256_SECRET_LENGTH]) -> AeadCipher {
+        match self {
+            AeadSuite::Aes256Gcm => AeadCipher::Aes256Gcm(Aes256Gcm::new(key.into())),
+            AeadSuite::ChaCha20Poly1305 => AeadCipher::ChaCha20Poly1305(ChaCha20Poly1305::new(key.into())),
+        }
+    }
+}
+
+/// An [`AeadSuite`] keyed by an [`EncryptionKey`] or [`DecryptionKey`]. Both ciphers wipe their key schedule when dropped.
+#[derive(Clone)]
+enum AeadCipher {
+    Aes256Gcm(Aes256Gcm),
+    ChaCha20Poly1305(ChaCha20Poly1305),
+}
+
+impl AeadCipher {
+
+    /// Encrypts `payload` under `nonce`, returning the ciphertext followed by the tag.
+    fn seal(&self, nonce: &[u8; AES256_NONCE_LENGTH], payload: Payload) -> Result<Vec<u8>, X3DHError> {
+        let nonce = Nonce::from_slice(nonce);
+        Ok(match self {
+            AeadCipher::Aes256Gcm(cipher) => cipher.encrypt(nonce, payload)?,
+            AeadCipher::ChaCha20Poly1305(cipher) => cipher.encrypt(nonce, payload)?,
+        })
+    }
+
+    /// Decrypts and authenticates `payload` under `nonce`, the inverse of [`AeadCipher::seal`].
+    fn open(&self, nonce: &[u8; AES256_NONCE_LENGTH], payload: Payload) -> Result<Vec<u8>, X3DHError> {
+        let nonce = Nonce::from_slice(nonce);
+        Ok(match self {
+            AeadCipher::Aes256Gcm(cipher) => cipher.decrypt(nonce, payload)?,
+            AeadCipher::ChaCha20Poly1305(cipher) => cipher.decrypt(nonce, payload)?,
+        })
+    }
+}
+
+impl TryFrom<u8> for AeadSuite {
+    type Error = X3DHError;
+
+    /// Converts an id written by [`AeadSuite::id`] back into an [`AeadSuite`].
+    ///
+    /// # Errors
+    ///
+    /// * [`X3DHError::UnknownAeadSuite`] - Returned if `value` is not the id of a known suite, for instance one
+    ///   added by a newer version.
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(AeadSuite::Aes256Gcm),
+            1 => Ok(AeadSuite::ChaCha20Poly1305),
+            _ => Err(X3DHError::UnknownAeadSuite(value)),
+        }
+    }
+}
+
+impl std::fmt::Display for AeadSuite {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// A 256-bit key used for encrypting messages in the X3DH session, with the [`AeadSuite`] it encrypts with.
+///
+/// The cipher is keyed once, when the key is derived or its suite set, rather than for every message.
 #[derive(Zeroize, ZeroizeOnDrop, Clone)]
-pub struct EncryptionKey([u8; AES256_SECRET_LENGTH]);
+pub struct EncryptionKey([u8; AES256_SECRET_LENGTH], #[zeroize(skip)] AeadSuite, #[zeroize(skip)] AeadCipher);
 
 impl EncryptionKey {
 
-    /// Encrypts the given `data` using AES-256-GCM with the given additional authenticated data (AAD).
-    /// The output format is: `[nonce | aad | ciphertext]`, all base64-encoded.
+    /// Returns the key, encrypting with `suite` from now on.
+    pub fn with_suite(mut self, suite: AeadSuite) -> Self {
+        self.1 = suite;
+        self.2 = suite.cipher(&self.0);
+        self
+    }
+
+    /// Returns the [`AeadSuite`] the key encrypts with, [`AeadSuite::Aes256Gcm`] unless set with [`EncryptionKey::with_suite`].
+    pub fn suite(&self) -> AeadSuite {
+        self.1
+    }
+
+    /// Encrypts the given `data` with the [`AeadSuite`] of the key and the given additional authenticated data (AAD).
+    /// The output format is: `[nonce | ciphertext]`, all base64-encoded.
+    ///
+    /// The AAD is authenticated but not part of the output: the receiver must know it already, see
+    /// [`DecryptionKey::decrypt_frame`]. Frames used to carry it after the nonce, which exposed the identity keys
+    /// it is usually made of.
+    ///
+    /// This is [`EncryptionKey::encrypt_bytes`] encoded for a text frame.
     /// 
     /// # Arguments
     ///
     /// * `data`: The plaintext data to be encrypted.
-    /// * `aad`: Additional data to authenticate but not encrypt.
+    /// * `aad`: Additional data to authenticate but neither encrypt nor transmit.
     ///
     /// # Returns
     ///
-    /// * `Ok(String)` - The base64-encoded ciphertext, nonce, and AAD.
+    /// * `Ok(String)` - The base64-encoded nonce and ciphertext.
     /// 
     /// # Errors
     /// 
+    /// * [`X3DHError::PlaintextTooLong`] - Returned if `data` is longer than [`MAX_PLAINTEXT_LENGTH`].
     /// * [`X3DHError::AesGcmInvalidLength`] - Returned if AES-GCM decryption fails due to an unexpected ciphertext length.
     pub fn encrypt(&self, data: &[u8], aad: &[u8]) -> Result<String, X3DHError> {
-        let nonce = &Aes256Gcm::generate_nonce(&mut OsRng);
-        let cipher = Aes256Gcm::new_from_slice(&self.0);
+        let output = self.encrypt_bytes(data, aad)?;
+        let b64 = general_purpose::STANDARD.encode(output);
+
+        Ok(b64)
+    }
+
+    /// Encrypts the given `data` with the [`AeadSuite`] of the key and the given additional authenticated data (AAD),
+    /// like [`EncryptionKey::encrypt`] but without encoding the output.
+    ///
+    /// # Arguments
+    ///
+    /// * `data`: The plaintext data to be encrypted.
+    /// * `aad`: Additional data to authenticate but neither encrypt nor transmit.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Vec<u8>)` - The `[nonce | ciphertext]` frame, to be read with [`DecryptionKey::decrypt_frame`].
+    ///
+    /// # Errors
+    ///
+    /// * [`X3DHError::PlaintextTooLong`] - Returned if `data` is longer than [`MAX_PLAINTEXT_LENGTH`].
+    /// * [`X3DHError::AesGcmInvalidLength`] - Returned if AES-GCM decryption fails due to an unexpected ciphertext length.
+    pub fn encrypt_bytes(&self, data: &[u8], aad: &[u8]) -> Result<Vec<u8>, X3DHError> {
+        if data.len() > MAX_PLAINTEXT_LENGTH {
+            return Err(X3DHError::PlaintextTooLong(data.len()));
+        }
+        let nonce: [u8; AES256_NONCE_LENGTH] = OsRng.gen();
         let payload = Payload {
-            aad: &aad.clone(),
+            aad,
             msg: data,
         };
-        let encrypt_msg = cipher?.encrypt(nonce, payload)?;
+        let encrypt_msg = self.2.seal(&nonce, payload)?;
         let mut output = vec![];
-        output.extend_from_slice(&nonce.to_vec());
-        output.extend_from_slice(&aad.clone());
+        output.extend_from_slice(&nonce);
         output.extend_from_slice(&encrypt_msg);
-        let b64 = general_purpose::STANDARD.encode(output);
-
-        Ok(b64)
+        Ok(output)
     }
 
-    /// Encrypts a short `data` slice deterministically to form a `Challenge`.
-    /// This uses a fixed nonce (`"hello world!"`).
+    /// Encrypts a short `data` slice to form a [`Challenge`].
+    /// A random nonce is generated and stored in front of the ciphertext.
     ///
     /// # Arguments
     ///
@@ -1379,12 +2571,12 @@ impl EncryptionKey {
     /// # Errors
     /// 
     /// * [`X3DHError::AesGcmInvalidLength`] - Returned if AES-GCM decryption fails due to an unexpected ciphertext length.
+    /// * [`X3DHError::InvalidChallenge`] - Returned if `data` is not the size of a public key.
     pub(crate) fn encrypt_challenge(&self, data: &[u8]) -> Result<Challenge, X3DHError> {
-        let nonce = b"hello world!";
-        let nonce = Nonce::from_slice(nonce);
-        let cipher = Aes256Gcm::new_from_slice(&self.0);
-        let encrypt_msg = cipher?.encrypt(nonce, data)?;
+        let nonce: [u8; AES256_NONCE_LENGTH] = OsRng.gen();
+        let encrypt_msg = self.2.seal(&nonce, Payload::from(data))?;
         let mut output = vec![];
+        output.extend_from_slice(&nonce);
         output.extend_from_slice(encrypt_msg.as_ref());
         Ok(Challenge::try_from(output.as_slice())?)
     }
@@ -1392,7 +2584,7 @@ impl EncryptionKey {
 
 impl From<SharedSecret> for EncryptionKey {
 
-    /// Derives an [`EncryptionKey`] from a [`SharedSecret`].
+    /// Derives an [`EncryptionKey`] from a [`SharedSecret`], encrypting with [`AeadSuite::Aes256Gcm`].
     ///
     /// # Arguments
     ///
@@ -1402,7 +2594,9 @@ impl From<SharedSecret> for EncryptionKey {
     ///
     /// * [`EncryptionKey`] - The derived encryption key.
     fn from(value: SharedSecret) -> EncryptionKey {
-        EncryptionKey(value.0)
+        let suite = AeadSuite::default();
+        let cipher = suite.cipher(&value.0);
+        EncryptionKey(value.0, suite, cipher)
     }
 }
 
@@ -1418,13 +2612,27 @@ impl AsRef<[u8; AES256_SECRET_LENGTH]> for EncryptionKey {
     }
 }
 
-/// A 256-bit AES key used for decrypting messages in the X3DH session.
+/// A 256-bit key used for decrypting messages in the X3DH session, with the [`AeadSuite`] they were encrypted with.
+///
+/// The cipher is keyed once, when the key is derived or its suite set, rather than for every message.
 #[derive(Zeroize, ZeroizeOnDrop, Clone)]
-pub struct DecryptionKey([u8; AES256_SECRET_LENGTH]);
+pub struct DecryptionKey([u8; AES256_SECRET_LENGTH], #[zeroize(skip)] AeadSuite, #[zeroize(skip)] AeadCipher);
 
 impl DecryptionKey {
 
-    /// Decrypts AES-GCM encrypted `data` using the provided `nonce` and additional authenticated data (AAD).
+    /// Returns the key, decrypting with `suite` from now on.
+    pub fn with_suite(mut self, suite: AeadSuite) -> Self {
+        self.1 = suite;
+        self.2 = suite.cipher(&self.0);
+        self
+    }
+
+    /// Returns the [`AeadSuite`] the key decrypts with, [`AeadSuite::Aes256Gcm`] unless set with [`DecryptionKey::with_suite`].
+    pub fn suite(&self) -> AeadSuite {
+        self.1
+    }
+
+    /// Decrypts `data`, encrypted with the [`AeadSuite`] of the key, using the provided `nonce` and additional authenticated data (AAD).
     ///
     /// # Arguments
     ///
@@ -1445,19 +2653,49 @@ impl DecryptionKey {
         nonce: &[u8; AES256_NONCE_LENGTH],
         aad: &[u8],
     ) -> Result<Vec<u8>, X3DHError> {
-        let cipher = Aes256Gcm::new_from_slice(&self.0);
-        let nonce = Nonce::from_slice(nonce);
         let payload = Payload {
-            aad: &aad.clone(),
+            aad,
             msg: data,
         };
-        let output = cipher?.decrypt(nonce, payload)?;
-        Ok(output)
+        self.2.open(nonce, payload)
+    }
+
+    /// Decrypts a `[nonce | ciphertext]` frame produced by [`EncryptionKey::encrypt_bytes`], authenticating it
+    /// against `aad_expected`. The nonce is taken from the front of the frame.
+    ///
+    /// Frames of older peers, laid out as `[nonce | aad | ciphertext]`, are accepted too if the AAD they
+    /// carry is `aad_expected`.
+    ///
+    /// # Arguments
+    ///
+    /// * `frame` - The nonce followed by the ciphertext.
+    /// * `aad_expected` - The additional authenticated data that was passed to the encrypting function.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Vec<u8>)` - The decrypted plaintext if decryption is successful.
+    ///
+    /// # Errors
+    ///
+    /// * [`X3DHError::AesGcmError`] - Returned if `frame` is shorter than a nonce, or does not authenticate
+    ///   in either layout.
+    pub fn decrypt_frame(&self, frame: &[u8], aad_expected: &[u8]) -> Result<Vec<u8>, X3DHError> {
+        if frame.len() < AES256_NONCE_LENGTH {
+            return Err(X3DHError::AesGcmError(aes_gcm::Error));
+        }
+        let nonce = array_ref!(frame, 0, AES256_NONCE_LENGTH);
+        let body = &frame[AES256_NONCE_LENGTH..];
+        match self.decrypt(body, nonce, aad_expected) {
+            Err(e) => match body.strip_prefix(aad_expected) {
+                Some(legacy) if !aad_expected.is_empty() => self.decrypt(legacy, nonce, aad_expected),
+                _ => Err(e),
+            },
+            plaintext => plaintext,
+        }
     }
 
-    /// Decrypts a [`Challenge`] value using a fixed nonce.
-    /// This is the inverse of `EncryptionKey::encrypt_challenge` and is only valid if
-    /// the challenge was encrypted with the same key and the static nonce `"hello world!"`.
+    /// Decrypts a [`Challenge`] value using the nonce stored in front of it.
+    /// This is the inverse of `EncryptionKey::encrypt_challenge`.
     ///
     /// # Arguments
     ///
@@ -1471,17 +2709,14 @@ impl DecryptionKey {
     /// 
     /// * [`X3DHError::AesGcmInvalidLength`] - Returned if AES-GCM decryption fails due to an unexpected ciphertext length.
     pub(crate) fn decrypt_challenge(&self, data: &Challenge) -> Result<Vec<u8>, X3DHError> {
-        let nonce = b"hello world!";
-        let nonce = Nonce::from_slice(nonce);
-        let cipher = Aes256Gcm::new_from_slice(&self.0);
-        let output = cipher?.decrypt(nonce, data.0.as_ref())?;
-        Ok(output)
+        let nonce = array_ref!(data.0, 0, AES256_NONCE_LENGTH);
+        self.2.open(nonce, Payload::from(&data.0[AES256_NONCE_LENGTH..]))
     }
 }
 
 impl From<SharedSecret> for DecryptionKey {
 
-    /// Derives an [`DecryptionKey`] from a [`SharedSecret`].
+    /// Derives an [`DecryptionKey`] from a [`SharedSecret`], decrypting with [`AeadSuite::Aes256Gcm`].
     ///
     /// # Arguments
     ///
@@ -1491,7 +2726,9 @@ impl From<SharedSecret> for DecryptionKey {
     ///
     /// * [`DecryptionKey`] - The derived decryption key.
     fn from(value: SharedSecret) -> DecryptionKey {
-        DecryptionKey(value.0)
+        let suite = AeadSuite::default();
+        let cipher = suite.cipher(&value.0);
+        DecryptionKey(value.0, suite, cipher)
     }
 }
 
@@ -1518,13 +2755,292 @@ mod tests {
 
         let pb1 = PreKeyBundle::new(&ik1, spk.public_key);
 
-        let b64 = pb1.clone().to_base64();
+        let b64 = pb1.to_base64();
         let pb2 = PreKeyBundle::try_from(b64).unwrap();
         assert_eq!(pb1.ik.0, pb2.ik.0);
         assert_eq!(pb1.spk.0, pb2.spk.0);
         assert_eq!(pb1.sig.0, pb2.sig.0);
     }
 
+    #[test]
+    fn test_byte_frames_round_trip() {
+        let ek = EncryptionKey::from(SharedSecret::from([7u8; 32]));
+        let dk = DecryptionKey::from(SharedSecret::from([7u8; 32]));
+        for data in [&b""[..], b"hello", &[0u8; 1024]] {
+            let frame = ek.encrypt_bytes(data, b"aad").unwrap();
+            assert_eq!(frame.len(), AES256_NONCE_LENGTH + data.len() + 16);
+            assert_eq!(dk.decrypt_frame(&frame, b"aad").unwrap(), data);
+        }
+
+        // The text form is the same frame, encoded
+        let frame = general_purpose::STANDARD.decode(ek.encrypt(b"hello", b"aad").unwrap()).unwrap();
+        assert_eq!(dk.decrypt_frame(&frame, b"aad").unwrap(), b"hello");
+    }
+
+    #[test]
+    fn test_tampered_byte_frames_are_refused() {
+        let ek = EncryptionKey::from(SharedSecret::from([7u8; 32]));
+        let dk = DecryptionKey::from(SharedSecret::from([7u8; 32]));
+        let frame = ek.encrypt_bytes(b"hello", b"aad").unwrap();
+
+        for i in [0, AES256_NONCE_LENGTH, frame.len() - 1] {
+            let mut tampered = frame.clone();
+            tampered[i] ^= 1;
+            assert!(dk.decrypt_frame(&tampered, b"aad").is_err());
+        }
+        assert!(dk.decrypt_frame(&frame[..frame.len() - 1], b"aad").is_err());
+        assert!(dk.decrypt_frame(&frame[..AES256_NONCE_LENGTH - 1], b"aad").is_err());
+        assert!(dk.decrypt_frame(&frame, b"other").is_err());
+        assert!(DecryptionKey::from(SharedSecret::from([8u8; 32])).decrypt_frame(&frame, b"aad").is_err());
+    }
+
+    fn random_otpks(n: usize) -> Vec<PublicKey> {
+        (0..n).map(|_| PublicKey::from(&PrivateKey::new())).collect()
+    }
+
+    #[test]
+    fn test_serde_prekey_bundle_round_trip() {
+        let ik = PrivateKey::new();
+        for n in [0, 1, 5] {
+            let pb = PreKeyBundle::new_with_otpk(&ik, SignedPreKey::new().public_key, random_otpks(n));
+            let json = serde_json::to_string(&pb).unwrap();
+            let restored: PreKeyBundle = serde_json::from_str(&json).unwrap();
+            assert_eq!(restored.to_bytes(), pb.to_bytes());
+            assert_eq!(restored.otpk.len(), n);
+            restored.verify().unwrap();
+        }
+    }
+
+    #[test]
+    fn test_associated_data_round_trip_and_safety_number() {
+        let alice = PublicKey::from(&PrivateKey::new());
+        let bob = PublicKey::from(&PrivateKey::new());
+        let aad = AssociatedData::new(alice.clone(), bob.clone());
+
+        let json = serde_json::to_string(&aad).unwrap();
+        let restored: AssociatedData = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored.initiator_identity_key(), &alice);
+        assert_eq!(restored.responder_identity_key(), &bob);
+        assert!(serde_json::from_str::<AssociatedData>("[1,2,3]").is_err());
+
+        // Each side holds the associated data of its own direction
+        let on_alice = aad.safety_number("alice", "bob");
+        let on_bob = aad.reversed().safety_number("bob", "alice");
+        assert_eq!(on_alice, on_bob);
+        assert_ne!(on_alice, aad.safety_number("alice", "mallory"));
+    }
+
+    #[test]
+    fn test_serde_prekey_bundle_keeps_otpk_ids() {
+        let mut pb = PreKeyBundle::new(&PrivateKey::new(), SignedPreKey::new().public_key);
+        for (id, otpk) in random_otpks(3).into_iter().enumerate() {
+            pb.add_otpk_with_id(10 + id as u32, otpk);
+        }
+        let mut value = serde_json::to_value(&pb).unwrap();
+        assert_eq!(serde_json::from_value::<PreKeyBundle>(value.clone()).unwrap().otpk_ids, vec![10, 11, 12]);
+
+        // The legacy packing has no ids, and a partial list is refused
+        assert!(PreKeyBundle::try_from(pb.to_base64()).unwrap().otpk_ids.is_empty());
+        value["otpk_ids"] = serde_json::json!([10]);
+        assert!(serde_json::from_value::<PreKeyBundle>(value).is_err());
+    }
+
+    #[test]
+    fn test_serde_prekey_bundle_keeps_otpk_sigs() {
+        let pb = PreKeyBundle::new_with_otpk(&PrivateKey::new(), SignedPreKey::new().public_key, random_otpks(3));
+        assert_eq!(pb.otpk_sigs.len(), 3);
+        let mut value = serde_json::to_value(&pb).unwrap();
+        let restored = serde_json::from_value::<PreKeyBundle>(value.clone()).unwrap();
+        assert_eq!(restored.otpk_sigs.len(), 3);
+        assert!(restored.validate().unwrap().is_clean());
+
+        // The legacy packing has no signatures, and a partial list is refused
+        let legacy = PreKeyBundle::try_from(pb.to_base64()).unwrap();
+        assert!(legacy.otpk_sigs.is_empty());
+        assert_eq!(legacy.validate().unwrap().warnings, vec![BundleWarning::UnsignedOneTimePreKeys]);
+        value["otpk_sigs"].as_array_mut().unwrap().pop();
+        assert!(serde_json::from_value::<PreKeyBundle>(value).is_err());
+    }
+
+    #[test]
+    fn test_serde_prekey_bundle_keeps_kem_prekey() {
+        let ik = PrivateKey::new();
+        let mut pb = PreKeyBundle::new(&ik, SignedPreKey::new().public_key);
+        let key = KemPublicKey::try_from(&[7u8; KEM_PUBLIC_LENGTH][..]).unwrap();
+        pb.set_kem_prekey(&ik, key.clone());
+        let mut value = serde_json::to_value(&pb).unwrap();
+        let restored = serde_json::from_value::<PreKeyBundle>(value.clone()).unwrap();
+        assert_eq!(restored.kem_prekey.as_ref().unwrap().key, key);
+        assert!(restored.validate().is_ok());
+
+        // The legacy packing has no KEM pre-key
+        assert!(PreKeyBundle::try_from(pb.to_base64()).unwrap().kem_prekey.is_none());
+
+        // A key signed by another identity does not verify
+        let mut forged = pb.clone();
+        forged.set_kem_prekey(&PrivateKey::new(), key);
+        assert!(matches!(forged.validate(), Err(X3DHError::InvalidKemSignature)));
+
+        // A key without its signature, or of the wrong size, is refused
+        let mut unsigned = value.clone();
+        unsigned.as_object_mut().unwrap().remove("kem_prekey_sig");
+        assert!(serde_json::from_value::<PreKeyBundle>(unsigned).is_err());
+        value["kem_prekey"] = serde_json::json!(vec![7u8; 32]);
+        assert!(serde_json::from_value::<PreKeyBundle>(value).is_err());
+        assert!(matches!(KemPublicKey::try_from(&[7u8; 32][..]), Err(X3DHError::InvalidKemPreKey)));
+    }
+
+    #[test]
+    fn test_serde_prekey_bundle_advertises_json_initial_message() {
+        let pb = PreKeyBundle::new(&PrivateKey::new(), SignedPreKey::new().public_key);
+        assert!(pb.json_initial_message);
+        let mut value = serde_json::to_value(&pb).unwrap();
+        assert!(serde_json::from_value::<PreKeyBundle>(value.clone()).unwrap().json_initial_message);
+
+        // Neither the legacy packing nor older serde bundles advertise it
+        assert!(!PreKeyBundle::try_from(pb.to_base64()).unwrap().json_initial_message);
+        value.as_object_mut().unwrap().remove("json_initial_message");
+        assert!(!serde_json::from_value::<PreKeyBundle>(value).unwrap().json_initial_message);
+    }
+
+    #[test]
+    fn test_replace_spk_keeps_one_time_prekeys() {
+        let ik = PrivateKey::new();
+        let mut pb = PreKeyBundle::new_with_otpk(&ik, SignedPreKey::new().public_key, random_otpks(2));
+        assert!(pb.spk_created_at.is_some());
+        let spk = SignedPreKey::new();
+        pb.replace_spk(&ik, spk.public_key.clone(), 1_700_000_000);
+        assert_eq!(pb.spk, spk.public_key);
+        assert_eq!(pb.otpk.len(), 2);
+        assert!(pb.validate().unwrap().is_clean());
+
+        // The timestamp survives serde, but not the legacy packing
+        let restored = serde_json::from_value::<PreKeyBundle>(serde_json::to_value(&pb).unwrap()).unwrap();
+        assert_eq!(restored.spk_created_at, Some(1_700_000_000));
+        assert_eq!(PreKeyBundle::try_from(pb.to_base64()).unwrap().spk_created_at, None);
+
+        // A key signed by another identity does not verify
+        pb.replace_spk(&PrivateKey::new(), SignedPreKey::new().public_key, 1_700_000_001);
+        assert!(pb.verify().is_err());
+    }
+
+    #[test]
+    fn test_serde_prekey_bundle_versions() {
+        let pb = PreKeyBundle::new(&PrivateKey::new(), SignedPreKey::new().public_key);
+        let mut value = serde_json::to_value(&pb).unwrap();
+        assert_eq!(value["version"], PreKeyBundle::SERDE_VERSION);
+
+        // Bundles without one-time pre-keys may leave the field out
+        value.as_object_mut().unwrap().remove("otpk");
+        assert!(serde_json::from_value::<PreKeyBundle>(value.clone()).unwrap().otpk.is_empty());
+
+        value["version"] = (PreKeyBundle::SERDE_VERSION + 1).into();
+        let err = serde_json::from_value::<PreKeyBundle>(value).unwrap_err();
+        assert!(err.to_string().contains("Unsupported prekey bundle version"));
+    }
+
+    #[test]
+    fn test_validate_clean_bundle() {
+        let ik = PrivateKey::new();
+        let spk = SignedPreKey::new();
+        let pb = PreKeyBundle::new_with_otpk(&ik, spk.public_key, random_otpks(3));
+        assert!(pb.validate().unwrap().is_clean());
+    }
+
+    #[test]
+    fn test_validate_warns_without_otpks() {
+        let ik = PrivateKey::new();
+        let spk = SignedPreKey::new();
+        let pb = PreKeyBundle::new(&ik, spk.public_key);
+        let report = pb.validate().unwrap();
+        assert_eq!(report.warnings, vec![BundleWarning::NoOneTimePreKeys]);
+    }
+
+    #[test]
+    fn test_validate_rejects_bad_signature() {
+        let ik = PrivateKey::new();
+        let mut pb = PreKeyBundle::new(&ik, SignedPreKey::new().public_key);
+        pb.spk = SignedPreKey::new().public_key;
+        assert!(matches!(pb.validate(), Err(X3DHError::InvalidSignature(_))));
+    }
+
+    #[test]
+    fn test_verify_accepts_signed_prekey() {
+        let ik = PrivateKey::new();
+        let pb = PreKeyBundle::new_with_otpk(&ik, SignedPreKey::new().public_key, random_otpks(1));
+        assert!(pb.verify().is_ok());
+    }
+
+    #[test]
+    fn test_verify_rejects_tampered_signed_prekey() {
+        let ik = PrivateKey::new();
+        let mut pb = PreKeyBundle::new(&ik, SignedPreKey::new().public_key);
+        pb.spk.0[0] ^= 1;
+        assert!(matches!(pb.verify(), Err(X3DHError::InvalidSignature(_))));
+    }
+
+    #[test]
+    fn test_verify_rejects_small_order_verifying_key() {
+        let ik = PrivateKey::new();
+        let mut pb = PreKeyBundle::new(&ik, SignedPreKey::new().public_key);
+        // The Ed25519 identity point, which accepts any signature under a lax verifier
+        let mut identity = [0u8; CURVE25519_PUBLIC_LENGTH];
+        identity[0] = 1;
+        pb.verifying_key = VerifyingKey(identity);
+        assert!(pb.verify().is_err());
+    }
+
+    #[test]
+    fn test_validate_rejects_low_order_identity_key() {
+        let ik = PrivateKey::new();
+        let mut pb = PreKeyBundle::new(&ik, SignedPreKey::new().public_key);
+        pb.ik = PublicKey::from(&[0u8; DH_PUBLIC_LENGTH]);
+        assert!(matches!(pb.validate(), Err(X3DHError::InvalidIdentityKey)));
+    }
+
+    #[test]
+    fn test_validate_rejects_low_order_signed_prekey() {
+        let ik = PrivateKey::new();
+        let pb = PreKeyBundle::new(&ik, PublicKey::from(&[0u8; DH_PUBLIC_LENGTH]));
+        assert!(matches!(pb.validate(), Err(X3DHError::InvalidSignedPreKey)));
+    }
+
+    #[test]
+    fn test_validate_rejects_identity_key_as_signed_prekey() {
+        let ik = PrivateKey::new();
+        let pb = PreKeyBundle::new(&ik, PublicKey::from(&ik));
+        assert!(matches!(pb.validate(), Err(X3DHError::SignedPreKeyIsIdentityKey)));
+    }
+
+    #[test]
+    fn test_validate_rejects_low_order_otpk() {
+        let ik = PrivateKey::new();
+        let mut otpk = random_otpks(2);
+        otpk.push(PublicKey::from(&[0u8; DH_PUBLIC_LENGTH]));
+        let pb = PreKeyBundle::new_with_otpk(&ik, SignedPreKey::new().public_key, otpk);
+        assert!(matches!(pb.validate(), Err(X3DHError::InvalidOneTimePreKey(2))));
+    }
+
+    #[test]
+    fn test_validate_rejects_duplicate_otpk() {
+        let ik = PrivateKey::new();
+        let mut otpk = random_otpks(2);
+        otpk.push(otpk[0].clone());
+        let pb = PreKeyBundle::new_with_otpk(&ik, SignedPreKey::new().public_key, otpk);
+        assert!(matches!(pb.validate(), Err(X3DHError::DuplicateOneTimePreKey(2))));
+    }
+
+    #[test]
+    fn test_validate_rejects_too_many_otpks() {
+        let ik = PrivateKey::new();
+        let otpk = random_otpks(MAX_ONE_TIME_PREKEYS + 1);
+        let pb = PreKeyBundle::new_with_otpk(&ik, SignedPreKey::new().public_key, otpk);
+        assert!(matches!(
+            pb.validate(),
+            Err(X3DHError::TooManyOneTimePreKeys(n)) if n == MAX_ONE_TIME_PREKEYS + 1
+        ));
+    }
+
     #[test]
     fn test_hash_public_key() {
         let key1 = PublicKey::from(PrivateKey::new());
@@ -1532,6 +3048,25 @@ mod tests {
         assert_ne!(key1.hash().0, key2.hash().0);
     }
 
+    #[test]
+    fn test_constant_time_comparisons_agree_with_equality() {
+        let key = PublicKey::from(PrivateKey::new());
+        let same = PublicKey::from(key.as_ref());
+        let mut close = key.clone();
+        close.0[DH_PUBLIC_LENGTH - 1] ^= 1;
+        assert!(key.ct_eq(&same) && key == same);
+        assert!(!key.ct_eq(&close) && key != close);
+
+        assert!(key.ct_eq_bytes(key.as_ref()));
+        assert!(!key.ct_eq_bytes(close.as_ref()));
+        // Bytes of another length never match, whatever their prefix
+        assert!(!key.ct_eq_bytes(&key.as_ref()[..DH_PUBLIC_LENGTH - 1]));
+        assert!(!key.ct_eq_bytes(&[key.as_ref().as_slice(), &[0]].concat()));
+
+        assert!(key.hash().ct_eq(&same.hash()) && key.hash() == same.hash());
+        assert!(!key.hash().ct_eq(&close.hash()) && key.hash() != close.hash());
+    }
+
     #[test]
     fn test_sign_verify() {
         let ik = SigningKey::new();
@@ -1541,4 +3076,33 @@ mod tests {
         let sig = ik.sign(data.as_bytes());
         assert!(p_ik.verify(&sig, data.as_bytes()).is_ok());
     }
+
+    #[test]
+    fn test_rotate_session_keys() {
+        let aad = AssociatedData::new(PublicKey::from(&PrivateKey::new()), PublicKey::from(&PrivateKey::new()));
+        let k1 = SharedSecret::from([1u8; AES256_SECRET_LENGTH]);
+        let k2 = SharedSecret::from([2u8; AES256_SECRET_LENGTH]);
+        let mut alice = SessionKeys::new_with_keys(EncryptionKey::from(k1.clone()), DecryptionKey::from(k2.clone()), Some(aad.clone()));
+        let mut bob = SessionKeys::new_with_keys(EncryptionKey::from(k2), DecryptionKey::from(k1), Some(aad));
+        let old_ek = alice.get_encryption_key().unwrap();
+
+        alice.rotate().unwrap();
+        bob.rotate().unwrap();
+
+        assert_ne!(alice.get_encryption_key().unwrap().as_ref(), old_ek.as_ref());
+        assert_eq!(alice.get_encryption_key().unwrap().as_ref(), bob.get_decryption_key().unwrap().as_ref());
+        assert_eq!(bob.get_encryption_key().unwrap().as_ref(), alice.get_decryption_key().unwrap().as_ref());
+        assert_eq!(bob.get_previous_decryption_key().unwrap().as_ref(), old_ek.as_ref());
+        assert!(SessionKeys::new().rotate().is_err());
+    }
+
+    #[test]
+    fn test_encrypt_rejects_oversized_plaintext() {
+        let ek = EncryptionKey::from(SharedSecret::from([1u8; AES256_SECRET_LENGTH]));
+        assert!(ek.encrypt(&[0u8; MAX_PLAINTEXT_LENGTH], b"aad").is_ok());
+        assert!(matches!(
+            ek.encrypt(&[0u8; MAX_PLAINTEXT_LENGTH + 1], b"aad"),
+            Err(X3DHError::PlaintextTooLong(length)) if length == MAX_PLAINTEXT_LENGTH + 1
+        ));
+    }
 }
@@ -5,23 +5,30 @@
 //! For more information, see the [Signal Protocol specification: The X3DH Key Agreement Protocol](https://signal.org/docs/specifications/x3dh/).
 
 use crate::constants::AES256_SECRET_LENGTH;
+use crate::curve::{self, Curve};
 use crate::errors::X3DHError;
+use crate::interop::{Conformance, SPEC_X3DH_INFO};
 use crate::utils::{
+    AeadSuite,
     AssociatedData,
     DecryptionKey,
+    DhOutput,
     EncryptionKey,
     InitialMessage,
+    KemCiphertext,
+    OneTimePreKey,
     PreKeyBundle,
     PrivateKey,
     PublicKey,
     SharedSecret,
     SignedPreKey
 };
-use arrayref::array_ref;
+#[cfg(feature = "pqxdh")]
+use crate::utils::KemPrivateKey;
 use hkdf::Hkdf;
 use sha2::Sha256;
 
-/// Generates a new Curve25519 pre-key bundle along with its associated private keys.
+/// Generates a new pre-key bundle on the [`Curve::ACTIVE`] curve along with its associated private keys.
 /// 
 /// This function does not generate one-time pre-keys.  
 /// For that functionality, see [`generate_prekey_bundle_with_otpk`].
@@ -46,7 +53,7 @@ pub fn generate_prekey_bundle()
     )
 }
 
-/// Generates a new Curve25519 pre-key bundle along with its associated private keys,
+/// Generates a new pre-key bundle on the [`Curve::ACTIVE`] curve along with its associated private keys,
 /// including one-time pre-keys.
 ///
 /// For a version that excludes one-time pre-keys, see [`generate_prekey_bundle`].
@@ -61,7 +68,7 @@ pub fn generate_prekey_bundle()
 ///     * [`PreKeyBundle`].
 ///     * The first [`PrivateKey`] - The identity key.
 ///     * The second [`PrivateKey`] - The signed pre-key.
-///     * Vec<[`PrivateKey`]> - The list of generated one-time pre-keys.
+///     * Vec<[`PrivateKey`]> - The list of generated one-time pre-keys, the one at index `i` having the id `i`.
 pub fn generate_prekey_bundle_with_otpk(n: u32) -> (PreKeyBundle, PrivateKey, PrivateKey, Vec<PrivateKey>) {
 
     let mut otpk_private = Vec::new();
@@ -74,11 +81,12 @@ pub fn generate_prekey_bundle_with_otpk(n: u32) -> (PreKeyBundle, PrivateKey, Pr
 
     let ik = PrivateKey::new();
     let spk = SignedPreKey::new();
-    let pb = PreKeyBundle::new_with_otpk(
+    let mut pb = PreKeyBundle::new_with_otpk(
         &ik,
         spk.public_key,
         otpk_public
     );
+    pb.otpk_ids = (0..n).collect();
 
     (pb, ik, spk.private_key, otpk_private)
 }
@@ -105,7 +113,44 @@ pub fn generate_prekey_bundle_with_otpk(n: u32) -> (PreKeyBundle, PrivateKey, Pr
 /// # Errors
 ///
 /// * [`X3DHError::InvalidSignature`] - Returned if the recipient's signed pre-key signature verification fails.
-pub fn process_prekey_bundle(ik: PrivateKey, mut bundle: PreKeyBundle)
+/// * [`X3DHError::InvalidOtpkSignature`] - Returned if the one-time pre-key is signed and its signature does not verify.
+///
+/// * [`X3DHError::InvalidKemSignature`] - Returned if the bundle offers a KEM pre-key whose signature does not verify.
+///
+/// The session encrypts with the [`AeadSuite`] of the bundle. To insist on a given suite, see
+/// [`process_prekey_bundle_with_suite`].
+///
+/// Built with the `pqxdh` feature, the key agreement is hybrid when the bundle offers a KEM pre-key:
+/// a secret encapsulated to it is mixed into the keys, and its ciphertext sent in the [`InitialMessage`].
+/// Bundles without a KEM pre-key, and builds without the feature, fall back to the classic key agreement.
+///
+/// The key agreement follows the native derivations, see [`process_prekey_bundle_with_conformance`]
+/// for the spec-conformant ones.
+pub fn process_prekey_bundle(ik: PrivateKey, bundle: PreKeyBundle)
+                            -> Result<(InitialMessage, EncryptionKey, DecryptionKey), X3DHError> {
+    process_prekey_bundle_with_conformance(ik, bundle, Conformance::Native)
+}
+
+/// Processes a received pre-key bundle like [`process_prekey_bundle`], following the derivations of `conformance`.
+///
+/// In [`Conformance::Spec`] the initial message carries no challenge, and both returned keys are the secret `SK`
+/// of the X3DH specification. They are meant for the root key of the ratchet, see [`crate::interop::root_key`],
+/// not to encrypt with.
+///
+/// # Arguments
+///
+/// * `ik` - The initiator’s private identity key.
+/// * `bundle` - The recipient’s `PreKeyBundle`, containing public identity and pre-keys.
+/// * `conformance` - The [`Conformance`] of the session, sent in the [`InitialMessage`].
+///
+/// # Returns
+///
+/// See [`process_prekey_bundle`].
+///
+/// # Errors
+///
+/// See [`process_prekey_bundle`].
+pub fn process_prekey_bundle_with_conformance(ik: PrivateKey, mut bundle: PreKeyBundle, conformance: Conformance)
                             -> Result<(InitialMessage, EncryptionKey, DecryptionKey), X3DHError> {
     // process the prekey bundle
     bundle.verifying_key.verify(&bundle.sig, &bundle.spk.0)?;
@@ -122,20 +167,28 @@ pub fn process_prekey_bundle(ik: PrivateKey, mut bundle: PreKeyBundle)
     // DH3 = DH(EKA, SPKB)
     let dh3 = ek.diffie_hellman(&bundle.spk);
 
-    let otpk = bundle.otpk.pop();
-
+    // Bundles in the legacy packing carry no signatures: an unsigned one-time pre-key could have been swapped
+    // by the server, so the handshake goes on without DH4 rather than trusting it
+    let otpk = bundle.pop_otpk().filter(|otpk| otpk.sig.is_some());
+    if let Some(OneTimePreKey { key, sig: Some(sig), .. }) = &otpk {
+        bundle.verify_otpk(key, sig)?;
+    }
+    let (kem_ciphertext, kem_secret) = encapsulate(&bundle)?;
 
-    let (sk1, sk2) = hkdf(
-        "X3DH".to_string(),
+    let (ek, dk) = hkdf(
+        Role::Initiator,
+        conformance,
+        bundle.aead_suite,
         dh1,
         dh2,
         dh3,
         if let Some(otpk) = &otpk {
             // DH4 = DH(EKA, OTPK)
-            Some(ek.diffie_hellman(otpk))
+            Some(ek.diffie_hellman(&otpk.key))
         } else {
             None
         },
+        kem_secret,
     )?;
 
 
@@ -144,9 +197,10 @@ pub fn process_prekey_bundle(ik: PrivateKey, mut bundle: PreKeyBundle)
         responder_identity_key: bundle.ik,
     };
 
-    let ek = EncryptionKey::from(sk1);
-    let dk = DecryptionKey::from(sk2);
-    let challenge  = ek.encrypt_challenge(PublicKey::from(&ik).as_ref())?;
+    let challenge = match conformance.has_challenge() {
+        true => Some(ek.encrypt_challenge(PublicKey::from(&ik).as_ref())?),
+        false => None,
+    };
 
     Ok(
         (
@@ -154,13 +208,17 @@ pub fn process_prekey_bundle(ik: PrivateKey, mut bundle: PreKeyBundle)
                 identity_key: PublicKey::from(&ik),
                 ephemeral_key: p_ek,
                 prekey_hash: bundle.spk.hash(),
-                one_time_key_hash: if let Some(otpk) = otpk {
-                    Some(otpk.hash())
-                } else {
-                    None
+                // The key is named by its id when the bundle has one, the hash is left for bundles without ids
+                one_time_key_hash: match &otpk {
+                    Some(OneTimePreKey { key, id: None, .. }) => Some(key.hash()),
+                    _ => None,
                 },
+                one_time_key_id: otpk.and_then(|otpk| otpk.id),
                 challenge,
-                associated_data: ad
+                associated_data: ad,
+                aead_suite: bundle.aead_suite,
+                kem_ciphertext,
+                conformance,
             },
             ek,
             dk
@@ -168,57 +226,164 @@ pub fn process_prekey_bundle(ik: PrivateKey, mut bundle: PreKeyBundle)
     )
 }
 
+/// Processes a received pre-key bundle like [`process_prekey_bundle`], provided the bundle offers `suite`.
+///
+/// # Arguments
+///
+/// * `ik` - The initiator’s private identity key.
+/// * `bundle` - The recipient’s `PreKeyBundle`, containing public identity and pre-keys.
+/// * `suite` - The [`AeadSuite`] the initiator is willing to use.
+///
+/// # Returns
+///
+/// See [`process_prekey_bundle`].
+///
+/// # Errors
+///
+/// * [`X3DHError::AeadSuiteMismatch`] - Returned if the bundle offers another suite, before any key is derived.
+/// * Any error of [`process_prekey_bundle`].
+pub fn process_prekey_bundle_with_suite(ik: PrivateKey, bundle: PreKeyBundle, suite: AeadSuite)
+                            -> Result<(InitialMessage, EncryptionKey, DecryptionKey), X3DHError> {
+    if bundle.aead_suite != suite {
+        return Err(X3DHError::AeadSuiteMismatch(suite, bundle.aead_suite));
+    }
+    process_prekey_bundle(ik, bundle)
+}
+
+/// Encapsulates a secret to the KEM pre-key of `bundle`, if it has one.
+///
+/// # Returns
+///
+/// * `Ok((Option<KemCiphertext>, Option<SharedSecret>))` - The ciphertext to send and the secret, or `None` for both
+///   if the key agreement is classic.
+///
+/// # Errors
+///
+/// * [`X3DHError::InvalidKemSignature`] - Returned if the signature of the KEM pre-key does not verify.
+#[cfg(feature = "pqxdh")]
+fn encapsulate(bundle: &PreKeyBundle) -> Result<(Option<KemCiphertext>, Option<SharedSecret>), X3DHError> {
+    let Some(kem_prekey) = &bundle.kem_prekey else { return Ok((None, None)) };
+    bundle.verify_kem_prekey(kem_prekey)?;
+    let (ct, secret) = kem_prekey.key.encapsulate();
+    Ok((Some(ct), Some(secret)))
+}
+
+/// Without post-quantum support the KEM pre-key of a bundle is left unused, and the key agreement is classic.
+#[cfg(not(feature = "pqxdh"))]
+fn encapsulate(_: &PreKeyBundle) -> Result<(Option<KemCiphertext>, Option<SharedSecret>), X3DHError> {
+    Ok((None, None))
+}
+
+/// HKDF info label of the key used by the initiator to send messages to the responder.
+const INITIATOR_TO_RESPONDER: &[u8] = b"X3DH initiator->responder";
+
+/// HKDF info label of the key used by the responder to send messages to the initiator.
+const RESPONDER_TO_INITIATOR: &[u8] = b"X3DH responder->initiator";
+
+/// The side of the X3DH handshake a party is on.
+///
+/// Each party derives its sending and receiving keys from its own role, so the two directions
+/// never depend on the order in which the keys are returned.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Role {
+    /// The party that processed the [`PreKeyBundle`] and sent the [`InitialMessage`].
+    Initiator,
+
+    /// The party that published the [`PreKeyBundle`] and received the [`InitialMessage`].
+    Responder,
+}
+
+impl Role {
+
+    /// Returns the HKDF info labels for this role's directions.
+    ///
+    /// # Returns
+    ///
+    /// * `(&[u8], &[u8])` - A tuple `(sending_label, receiving_label)`.
+    fn labels(self) -> (&'static [u8], &'static [u8]) {
+        match self {
+            Role::Initiator => (INITIATOR_TO_RESPONDER, RESPONDER_TO_INITIATOR),
+            Role::Responder => (RESPONDER_TO_INITIATOR, INITIATOR_TO_RESPONDER),
+        }
+    }
+}
+
 /// HMAC-based Key Derivation Function (HKDF) used in the X3DH protocol.
 ///
 /// This function combines the results of multiple Diffie-Hellman operations to derive
-/// two symmetric shared secrets.
+/// the two directional session keys of the caller.
 ///
-/// The function first concatenates a fixed domain separation constant (32 bytes of 0xFF for Curve25519),
-/// followed by the raw bytes of the DH results. If a one-time pre-key is used, its DH output is included as well.
-/// This input key material is passed through the HKDF using SHA-256 to produce two derived keys.
+/// The function first concatenates a fixed domain separation constant (0xFF bytes, see [`Curve::kdf_prefix_length`]),
+/// followed by the raw bytes of the DH results. If a one-time pre-key is used, its DH output is included as well,
+/// and in a hybrid key agreement the KEM secret comes last.
+/// This input key material is passed through the HKDF using SHA-256, and each direction is expanded
+/// with its own info label ([`INITIATOR_TO_RESPONDER`] and [`RESPONDER_TO_INITIATOR`]).
+/// In [`Conformance::Spec`] a single secret is expanded with [`SPEC_X3DH_INFO`] instead, and returned as both keys.
 ///
 /// # Arguments
 ///
-/// * `info` - An ASCII string that identifies the purpose or context of the derived keys (used as the HKDF `info` parameter).
+/// * `role` - The [`Role`] of the caller, selecting which direction is used for sending.
+/// * `conformance` - The [`Conformance`] of the session.
+/// * `suite` - The [`AeadSuite`] the keys encrypt with.
 /// * `dh1` - The result of DH(SPKB, IKA), initiator's identity key with responder's signed pre-key.
 /// * `dh2` - The result of DH(IKB, EKA), responder's identity key with initiator's ephemeral key.
 /// * `dh3` - The result of DH(SPKB, EKA), responder's signed pre-key with initiator's ephemeral key.
 /// * `dh4` - The result of DH(OTPK, EKA), if a one-time pre-key was used.
+/// * `kem` - The secret encapsulated to the KEM pre-key, if the key agreement is hybrid.
 ///
 /// # Returns
 ///
-/// * `Ok((SharedSecret, SharedSecret))` - A tuple where:
-///     * The first [`SharedSecret`] - The encryption key used by the initiator.
-///     * The second [`SharedSecret`] - The decryption key used by the responder.
+/// * `Ok((EncryptionKey, DecryptionKey))` - A tuple where:
+///     * [`EncryptionKey`] - The key the caller uses to send messages.
+///     * [`DecryptionKey`] - The key the caller uses to receive messages.
 ///
 /// # Errors
 ///
 /// * [`X3DHError::HkdfInvalidLengthError`] - Returned if HKDF expansion fails due to an invalid output length.
-fn hkdf(
-    info: String,
-    dh1: SharedSecret,
-    dh2: SharedSecret,
-    dh3: SharedSecret,
-    dh4: Option<SharedSecret>,
-) -> Result<(SharedSecret, SharedSecret), X3DHError> {
+pub(crate) fn hkdf(
+    role: Role,
+    conformance: Conformance,
+    suite: AeadSuite,
+    dh1: DhOutput,
+    dh2: DhOutput,
+    dh3: DhOutput,
+    dh4: Option<DhOutput>,
+    kem: Option<SharedSecret>,
+) -> Result<(EncryptionKey, DecryptionKey), X3DHError> {
     // HKDF input key material = F || KM, where KM is an input byte sequence containing secret key material, and F is a byte sequence containing 32 0xFF bytes if curve is X25519, and 57 0xFF bytes if curve is X448. F is used for cryptographic domain separation with XEdDSA [2].
-    let mut dhs = vec![0xFFu8; 32];
+    let mut dhs = curve::kdf_prefix();
     dhs.extend_from_slice(dh1.as_ref());
     dhs.extend_from_slice(dh2.as_ref());
     dhs.extend_from_slice(dh3.as_ref());
     if let Some(dh4) = dh4 {
         dhs.extend_from_slice(dh4.as_ref());
     }
+    if let Some(kem) = kem {
+        dhs.extend_from_slice(kem.as_ref());
+    }
     // HKDF salt = A zero-filled byte sequence with length equal to the hash output length.
     let hk = Hkdf::<Sha256>::new(Some(&[0u8; 32]), dhs.as_ref());
-    let mut okm: [u8; 64] = [0u8; 2 * AES256_SECRET_LENGTH];
-    // HKDF info = The info parameter from Section 2.1.
-    hk.expand(info.as_bytes(), &mut okm)?;
-
-    let shared_key1 = SharedSecret::from(*array_ref!(okm, 0, AES256_SECRET_LENGTH));
-    let shared_key2 =
-        SharedSecret::from(*array_ref!(okm, AES256_SECRET_LENGTH, AES256_SECRET_LENGTH));
-    Ok((shared_key1, shared_key2))
+
+    if conformance == Conformance::Spec {
+        // SK = HKDF(F || KM), expanded with the info string of the application
+        let mut sk = [0u8; AES256_SECRET_LENGTH];
+        hk.expand(SPEC_X3DH_INFO, &mut sk)?;
+        return Ok((
+            EncryptionKey::from(SharedSecret::from(sk)).with_suite(suite),
+            DecryptionKey::from(SharedSecret::from(sk)).with_suite(suite),
+        ));
+    }
+
+    let (sending_label, receiving_label) = role.labels();
+    let mut sending = [0u8; AES256_SECRET_LENGTH];
+    let mut receiving = [0u8; AES256_SECRET_LENGTH];
+    hk.expand(sending_label, &mut sending)?;
+    hk.expand(receiving_label, &mut receiving)?;
+
+    Ok((
+        EncryptionKey::from(SharedSecret::from(sending)).with_suite(suite),
+        DecryptionKey::from(SharedSecret::from(receiving)).with_suite(suite),
+    ))
 }
 
 /// Processes the initial message sent by the initiator in the X3DH key exchange protocol.
@@ -229,7 +394,11 @@ fn hkdf(
 /// using an encrypted challenge.
 ///
 /// The derived keys are used to establish a secure communication channel between the initiator
-/// and the responder.
+/// and the responder. They encrypt with the [`AeadSuite`] of the message, which the challenge authenticates;
+/// to insist on the suite of the responder's bundle, see [`process_initial_message_with_suite`].
+///
+/// The keys follow the [`InitialMessage::conformance`] of the initiator. A [`Conformance::Spec`] message has no
+/// challenge, and the session is only authenticated once its first ratchet message decrypts.
 ///
 /// # Arguments
 ///
@@ -249,11 +418,61 @@ fn hkdf(
 /// * [`X3DHError::HkdfInvalidLengthError`] - Returned if HKDF fails due to incorrect output keying material length.
 /// * [`X3DHError::AesGcmInvalidLength`] - Returned if AES-GCM decryption fails due to an unexpected ciphertext length.
 /// * [`X3DHError::InvalidKey`] - Returned if the decrypted challenge does not match the initiator's identity key.
+/// * [`X3DHError::InvalidInitialMessage`] - Returned if the message lacks a challenge its conformance requires, or the other way round.
+/// * [`X3DHError::MissingKemPreKey`] - Returned if the key agreement is hybrid, see [`process_hybrid_initial_message`].
 pub fn process_initial_message(
     identity_key: PrivateKey,
     signed_prekey: PrivateKey,
     one_time_prekey: Option<PrivateKey>,
     msg: InitialMessage,
+) -> Result<(EncryptionKey, DecryptionKey), X3DHError> {
+    if msg.kem_ciphertext.is_some() {
+        return Err(X3DHError::MissingKemPreKey);
+    }
+    respond(identity_key, signed_prekey, one_time_prekey, None, msg)
+}
+
+/// Processes the initial message of a key agreement whose bundle offered a KEM pre-key, like
+/// [`process_initial_message`], decapsulating the secret of the initiator with `kem_prekey`.
+///
+/// Initiators built without the `pqxdh` feature, or that were handed the bundle in the legacy packing,
+/// send no ciphertext: the key agreement is then classic, and still succeeds.
+///
+/// # Arguments
+///
+/// * `identity_key` - The responder's identity private key.
+/// * `signed_prekey` - The responder's signed pre-key private key.
+/// * `one_time_prekey` - An optional one-time pre-key private key, used if included by the initiator.
+/// * `kem_prekey` - The private half of the responder's [`PreKeyBundle::kem_prekey`].
+/// * `msg` - The initial message from the initiator containing public keys and an encrypted challenge.
+///
+/// # Returns
+///
+/// See [`process_initial_message`].
+///
+/// # Errors
+///
+/// * Any error of [`process_initial_message`] but [`X3DHError::MissingKemPreKey`]. A ciphertext that was not
+///   encapsulated to `kem_prekey` fails as a wrong challenge does.
+#[cfg(feature = "pqxdh")]
+pub fn process_hybrid_initial_message(
+    identity_key: PrivateKey,
+    signed_prekey: PrivateKey,
+    one_time_prekey: Option<PrivateKey>,
+    kem_prekey: &KemPrivateKey,
+    msg: InitialMessage,
+) -> Result<(EncryptionKey, DecryptionKey), X3DHError> {
+    let kem_secret = msg.kem_ciphertext.as_ref().map(|ct| kem_prekey.decapsulate(ct));
+    respond(identity_key, signed_prekey, one_time_prekey, kem_secret, msg)
+}
+
+/// Derives the keys of the responder and checks the challenge of the initiator, see [`process_initial_message`].
+fn respond(
+    identity_key: PrivateKey,
+    signed_prekey: PrivateKey,
+    one_time_prekey: Option<PrivateKey>,
+    kem_secret: Option<SharedSecret>,
+    msg: InitialMessage,
 ) -> Result<(EncryptionKey, DecryptionKey), X3DHError> {
     // DH1 = DH(SPKB, IKA)
     let dh1 = signed_prekey.diffie_hellman(&msg.identity_key);
@@ -262,25 +481,33 @@ pub fn process_initial_message(
     // DH3 = DH(SPKB, EKA)
     let dh3 = signed_prekey.diffie_hellman(&msg.ephemeral_key);
 
-    let (sk1, sk2) = hkdf(
-        "X3DH".to_string(),
+    let (ek, dk) = hkdf(
+        Role::Responder,
+        msg.conformance,
+        msg.aead_suite,
         dh1,
         dh2,
         dh3,
-        if msg.one_time_key_hash.is_some() {
+        if msg.uses_one_time_prekey() {
             // DH4 = DH(OTPK, EKA)
-            let dh4 = one_time_prekey.unwrap().diffie_hellman(&msg.ephemeral_key);
+            let dh4 = one_time_prekey.ok_or(X3DHError::InvalidKey)?.diffie_hellman(&msg.ephemeral_key);
             Some(dh4)
         } else {
             None
         },
+        kem_secret,
     )?;
-    let ek = EncryptionKey::from(sk2);
-    let dk = DecryptionKey::from(sk1);
 
-    let challenge = dk.decrypt_challenge(&msg.challenge)?;
-    if challenge != msg.identity_key.as_ref() {
-        return Err(X3DHError::InvalidKey);
+    // Without a challenge, as in the specification, the session is authenticated by its first message
+    match (msg.conformance, &msg.challenge) {
+        (Conformance::Native, Some(challenge)) => {
+            let challenge = dk.decrypt_challenge(challenge)?;
+            if !msg.identity_key.ct_eq_bytes(&challenge) {
+                return Err(X3DHError::InvalidKey);
+            }
+        }
+        (Conformance::Spec, None) => {}
+        _ => return Err(X3DHError::InvalidInitialMessage),
     }
 
     Ok((
@@ -289,6 +516,38 @@ pub fn process_initial_message(
     ))
 }
 
+/// Processes the initial message sent by the initiator like [`process_initial_message`], provided the
+/// initiator chose `suite`, usually the [`PreKeyBundle::aead_suite`] of the responder.
+///
+/// # Arguments
+///
+/// * `identity_key` - The responder's identity private key.
+/// * `signed_prekey` - The responder's signed pre-key private key.
+/// * `one_time_prekey` - An optional one-time pre-key private key, used if included by the initiator.
+/// * `suite` - The [`AeadSuite`] the responder offered.
+/// * `msg` - The initial message from the initiator containing public keys and an encrypted challenge.
+///
+/// # Returns
+///
+/// See [`process_initial_message`].
+///
+/// # Errors
+///
+/// * [`X3DHError::AeadSuiteMismatch`] - Returned if the message names another suite, before any key is derived.
+/// * Any error of [`process_initial_message`].
+pub fn process_initial_message_with_suite(
+    identity_key: PrivateKey,
+    signed_prekey: PrivateKey,
+    one_time_prekey: Option<PrivateKey>,
+    suite: AeadSuite,
+    msg: InitialMessage,
+) -> Result<(EncryptionKey, DecryptionKey), X3DHError> {
+    if msg.aead_suite != suite {
+        return Err(X3DHError::AeadSuiteMismatch(suite, msg.aead_suite));
+    }
+    process_initial_message(identity_key, signed_prekey, one_time_prekey, msg)
+}
+
 /// Processes the initial message sent by the initiator in the X3DH key exchange protocol,
 /// with additional validation to ensure the initiator's identity key matches the expected server identity.
 ///
@@ -324,7 +583,7 @@ pub fn process_server_initial_message(
     msg: InitialMessage,
 ) -> Result<(EncryptionKey, DecryptionKey), X3DHError> {
 
-    if msg.identity_key.hash() != server_ik.hash(){
+    if !msg.identity_key.ct_eq(server_ik) {
         return Err(X3DHError::InvalidInitialMessage);
     }
     process_initial_message(identity_key, signed_prekey, one_time_prekey, msg)
@@ -336,8 +595,12 @@ mod tests {
     use base64::Engine;
 
     use super::*;
-    use crate::constants::{AES256_NONCE_LENGTH, CURVE25519_PUBLIC_LENGTH, SHA256_HASH_LENGTH};
+    use crate::constants::{AES256_NONCE_LENGTH, CHALLENGE_LENGTH, DH_OUTPUT_LENGTH, DH_PUBLIC_LENGTH, SHA256_HASH_LENGTH};
+    use crate::interop::root_key;
+    use crate::ratchet::{Ratchet, RatchetConfig, RatchetKeyPair};
     use crate::utils::SignedPreKey;
+    #[cfg(not(feature = "pqxdh"))]
+    use crate::{constants::KEM_PUBLIC_LENGTH, utils::KemPublicKey};
     use std::convert::TryFrom;
 
     #[test]
@@ -348,7 +611,7 @@ mod tests {
         let pb1_bytes = pb1.to_bytes();
         assert_eq!(pb1_bytes.len(), pb1.size());
 
-        let pb1_base64 = pb1.clone().to_base64();
+        let pb1_base64 = pb1.to_base64();
         let pb2 = PreKeyBundle::try_from(pb1_base64).unwrap();
         assert_eq!(pb2.spk.as_ref(), pb1.spk.as_ref());
     }
@@ -368,16 +631,11 @@ mod tests {
         assert_eq!(encryption_key.as_ref().len(), AES256_SECRET_LENGTH);
         assert_eq!(decryption_key.as_ref().len(), AES256_SECRET_LENGTH);
 
-        let im_bytes = initial_message.clone().to_bytes();
-        assert_eq!(
-            im_bytes.len(),
-            4 * CURVE25519_PUBLIC_LENGTH + SHA256_HASH_LENGTH
-        );
+        // Without a one-time pre-key hash, but with the challenge
+        let im_bytes = initial_message.to_bytes();
+        assert_eq!(im_bytes.len(), InitialMessage::BASE_SIZE);
 
-        assert_eq!(
-            initial_message.size(),
-            4 * CURVE25519_PUBLIC_LENGTH + SHA256_HASH_LENGTH
-        );
+        assert_eq!(initial_message.size(), InitialMessage::BASE_SIZE);
     }
 
     #[test]
@@ -419,11 +677,7 @@ mod tests {
                 return;
             }
         };
-        let end = cipher_text.len();
-        let nonce = *array_ref!(cipher_text, 0, AES256_NONCE_LENGTH);
-        let add = *array_ref!(cipher_text, AES256_NONCE_LENGTH, AssociatedData::SIZE);
-        let cipher_text = &cipher_text[AES256_NONCE_LENGTH + AssociatedData::SIZE..end];
-        let clear_text = match decryption_key2.decrypt(&cipher_text, &nonce, &aad.to_bytes()) {
+        let clear_text = match decryption_key2.decrypt_frame(&cipher_text, &aad.to_bytes()) {
             Ok(d) => d,
             Err(e) => {
                 println!("Error in decryption: {}", e);
@@ -433,6 +687,35 @@ mod tests {
         assert_eq!(data.to_vec(), clear_text);
     }
 
+    #[test]
+    fn test_both_parties_derive_the_same_root_key() {
+        let bob_identity_key = PrivateKey::new();
+        let bob_prekey = SignedPreKey::new();
+        let pb = PreKeyBundle::new(&bob_identity_key, bob_prekey.public_key.clone());
+        let (initial_message, alice_ek, alice_dk) = process_prekey_bundle(PrivateKey::new(), pb).unwrap();
+        let (bob_ek, bob_dk) = process_initial_message(
+            bob_identity_key,
+            bob_prekey.private_key.clone(),
+            None,
+            initial_message.clone()
+        ).unwrap();
+
+        let alice_sk = SharedSecret::from_session_keys(Role::Initiator, &alice_ek, &alice_dk);
+        let bob_sk = SharedSecret::from_session_keys(Role::Responder, &bob_ek, &bob_dk);
+        assert_eq!(alice_sk.as_ref(), bob_sk.as_ref());
+        // The root key depends on both directional keys, it is neither of them
+        assert_ne!(alice_sk.as_ref(), alice_ek.as_ref());
+        assert_ne!(alice_sk.as_ref(), alice_dk.as_ref());
+
+        let mut alice = Ratchet::init_alice(alice_sk, bob_prekey.public_key.clone());
+        let mut bob = Ratchet::init_bob(bob_sk, RatchetKeyPair::new_from(bob_prekey.private_key, bob_prekey.public_key));
+        let aad = initial_message.associated_data.to_bytes();
+        let ciphertext = alice.encrypt(b"hello", &aad).unwrap();
+        assert_eq!(bob.decrypt(ciphertext, &aad).unwrap(), b"hello");
+        let ciphertext = bob.encrypt(b"hi", &aad).unwrap();
+        assert_eq!(alice.decrypt(ciphertext, &aad).unwrap(), b"hi");
+    }
+
     #[test]
     fn test_generate_process_key_bundle() {
         let pb = generate_prekey_bundle();
@@ -450,9 +733,21 @@ mod tests {
         let pik = PublicKey::from(&ik);
         let b64 = pb.to_base64();
         let pb = PreKeyBundle::try_from(b64).unwrap();
-        let (im, ek, dk) = process_prekey_bundle(ik, pb).unwrap();
+        let (im, ek, dk) = process_prekey_bundle(ik.clone(), pb).unwrap();
         assert_eq!(im.identity_key.as_ref(), pik.as_ref());
-        assert_eq!(im.one_time_key_hash.unwrap(), PublicKey::from(&otpk[0]).hash());
+        // The legacy packing drops the signatures, so its one-time pre-keys are left out of the handshake
+        assert!(im.one_time_key_hash.is_none() && im.one_time_key_id.is_none());
+
+        // A signed one, the last of the bundle, is consumed
+        let (pb, _, _, otpk) = generate_prekey_bundle_with_otpk(5);
+        let (im, _, _) = process_prekey_bundle(ik, pb).unwrap();
+        assert_eq!(im.one_time_key_id, Some(otpk.len() as u32 - 1));
+
+        // And an unsigned one is not, whether or not it has an id
+        let (mut pb, _, _, _) = generate_prekey_bundle_with_otpk(0);
+        pb.add_otpk_with_id(7, PublicKey::from(&PrivateKey::new()));
+        let (im, _, _) = process_prekey_bundle(PrivateKey::new(), pb).unwrap();
+        assert!(im.one_time_key_hash.is_none() && im.one_time_key_id.is_none());
     }
 
 
@@ -469,4 +764,514 @@ mod tests {
         assert_eq!(ek1.as_ref(), dk.as_ref());
         assert_eq!(ek.as_ref(), dk1.as_ref());
     }
+
+    #[test]
+    fn test_directional_keys_depend_on_role() {
+        let dh = || DhOutput::from([7u8; DH_OUTPUT_LENGTH]);
+        let (ek_i, dk_i) = hkdf(Role::Initiator, Conformance::Native, AeadSuite::Aes256Gcm, dh(), dh(), dh(), None, None).unwrap();
+        let (ek_r, dk_r) = hkdf(Role::Responder, Conformance::Native, AeadSuite::Aes256Gcm, dh(), dh(), dh(), None, None).unwrap();
+
+        // Each direction is bound to its own label
+        assert_eq!(ek_i.as_ref(), dk_r.as_ref());
+        assert_eq!(ek_r.as_ref(), dk_i.as_ref());
+        assert_ne!(ek_i.as_ref(), dk_i.as_ref());
+
+        let aad = b"associated data";
+        let ciphertext = general_purpose::STANDARD.decode(ek_i.encrypt(b"hello", aad).unwrap()).unwrap();
+        assert_eq!(dk_r.decrypt_frame(&ciphertext, aad).unwrap(), b"hello");
+    }
+
+    #[test]
+    fn test_both_initiators_cannot_communicate() {
+        let dh = || DhOutput::from([7u8; DH_OUTPUT_LENGTH]);
+        let (ek_a, _) = hkdf(Role::Initiator, Conformance::Native, AeadSuite::Aes256Gcm, dh(), dh(), dh(), None, None).unwrap();
+        let (_, dk_b) = hkdf(Role::Initiator, Conformance::Native, AeadSuite::Aes256Gcm, dh(), dh(), dh(), None, None).unwrap();
+
+        let aad = b"associated data";
+        let ciphertext = general_purpose::STANDARD.decode(ek_a.encrypt(b"hello", aad).unwrap()).unwrap();
+        assert!(dk_b.decrypt_frame(&ciphertext, aad).is_err());
+    }
+
+    #[test]
+    fn test_challenge_nonce_is_random() {
+        let dh = || DhOutput::from([7u8; DH_OUTPUT_LENGTH]);
+        let (ek, dk) = hkdf(Role::Initiator, Conformance::Native, AeadSuite::Aes256Gcm, dh(), dh(), dh(), None, None).unwrap();
+        let key = PublicKey::from(&PrivateKey::new());
+        let first = ek.encrypt_challenge(key.as_ref()).unwrap();
+        let second = ek.encrypt_challenge(key.as_ref()).unwrap();
+        assert_ne!(first.0, second.0);
+
+        // The responder reads the nonce from the challenge itself
+        let (_, dk_r) = hkdf(Role::Responder, Conformance::Native, AeadSuite::Aes256Gcm, dh(), dh(), dh(), None, None).unwrap();
+        assert_eq!(dk_r.decrypt_challenge(&first).unwrap(), key.as_ref());
+        assert_eq!(dk_r.decrypt_challenge(&second).unwrap(), key.as_ref());
+        assert!(dk.decrypt_challenge(&first).is_err());
+    }
+
+    #[test]
+    fn test_tampered_and_legacy_challenges_are_rejected() {
+        let bob_identity_key = PrivateKey::new();
+        let bob_prekey = SignedPreKey::new();
+        let pb = PreKeyBundle::new(&bob_identity_key, bob_prekey.public_key.clone());
+        let (initial_message, _, _) = process_prekey_bundle(PrivateKey::new(), pb).unwrap();
+
+        // Flipping a nonce byte breaks authentication
+        let mut tampered = initial_message.clone();
+        tampered.challenge.as_mut().unwrap().0[0] ^= 1;
+        assert!(process_initial_message(bob_identity_key.clone(), bob_prekey.private_key.clone(), None, tampered).is_err());
+
+        // A message in the former layout, without a nonce in the challenge
+        let bytes = initial_message.to_bytes();
+        let challenge_start = 2 * DH_PUBLIC_LENGTH + SHA256_HASH_LENGTH;
+        let mut legacy = bytes[..challenge_start].to_vec();
+        legacy.extend_from_slice(&bytes[challenge_start + AES256_NONCE_LENGTH..]);
+        assert!(matches!(
+            InitialMessage::try_from(general_purpose::STANDARD.encode(legacy)),
+            Err(X3DHError::InvalidChallenge)
+        ));
+    }
+
+    #[test]
+    fn test_initial_message_layout_carries_challenge_nonce() {
+        let (mut pb, ik, spk, otpk) = generate_prekey_bundle_with_otpk(1);
+        // Without ids, as in the legacy packing, the one-time pre-key is named by its hash
+        pb.otpk_ids.clear();
+        let (im, _, _) = process_prekey_bundle(PrivateKey::new(), pb).unwrap();
+        assert_eq!(CHALLENGE_LENGTH, AES256_NONCE_LENGTH + DH_PUBLIC_LENGTH + 16);
+
+        let bytes = im.to_bytes();
+        assert_eq!(bytes.len(), InitialMessage::SIZE_WITH_OTPK);
+        let challenge_start = 2 * DH_PUBLIC_LENGTH + 2 * SHA256_HASH_LENGTH;
+        assert_eq!(&bytes[challenge_start..challenge_start + CHALLENGE_LENGTH], &im.challenge.as_ref().unwrap().0[..]);
+
+        let im = InitialMessage::try_from(im.to_base64()).unwrap();
+        assert!(process_initial_message(ik, spk, otpk.last().cloned(), im).is_ok());
+    }
+
+    #[test]
+    fn test_initial_message_formats_are_interchangeable() {
+        for n in [0, 1] {
+            let (mut pb, ik, spk, otpk) = generate_prekey_bundle_with_otpk(n);
+            pb.otpk_ids.clear();
+            let (im, _, _) = process_prekey_bundle(PrivateKey::new(), pb).unwrap();
+            assert_eq!(im.one_time_key_hash.is_some(), n == 1);
+
+            let json = im.to_json();
+            let versioned = InitialMessage::try_from(json.clone()).unwrap();
+            let legacy = InitialMessage::try_from(im.to_base64()).unwrap();
+            assert_eq!(versioned.to_bytes(), im.to_bytes());
+            assert_eq!(legacy.to_bytes(), im.to_bytes());
+
+            for parsed in [versioned, legacy] {
+                assert!(process_initial_message(ik.clone(), spk.clone(), otpk.last().cloned(), parsed).is_ok());
+            }
+
+            // Messages without a one-time pre-key leave the field out
+            let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+            assert_eq!(value["version"], InitialMessage::SERDE_VERSION);
+            assert_eq!(value.get("one_time_key_hash").is_some(), n == 1);
+        }
+    }
+
+    #[test]
+    fn test_server_initial_message_accepts_both_formats() {
+        let server_ik = PrivateKey::new();
+        let (mut pb, ik, spk, otpk) = generate_prekey_bundle_with_otpk(1);
+        pb.otpk_ids.clear();
+        let (im, _, _) = process_prekey_bundle(server_ik.clone(), pb).unwrap();
+        for encoded in [im.to_json(), im.to_base64()] {
+            let parsed = InitialMessage::try_from(encoded).unwrap();
+            assert!(process_server_initial_message(
+                ik.clone(),
+                spk.clone(),
+                otpk.last().cloned(),
+                &PublicKey::from(&server_ik),
+                parsed,
+            ).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_initial_message_names_one_time_prekey_by_id() {
+        let (pb, ik, spk, otpk) = generate_prekey_bundle_with_otpk(3);
+        assert_eq!(pb.otpk_ids, vec![0, 1, 2]);
+        let (im, ek, dk) = process_prekey_bundle(PrivateKey::new(), pb).unwrap();
+        assert_eq!(im.one_time_key_id, Some(2));
+        assert!(im.one_time_key_hash.is_none());
+
+        let json = im.to_json();
+        let value: serde_json::Value = serde_json::from_str(&json).unwrap();
+        assert_eq!(value["one_time_key_id"], 2);
+        assert!(value.get("one_time_key_hash").is_none());
+
+        let parsed = InitialMessage::try_from(json).unwrap();
+        let (ek1, dk1) = process_initial_message(ik.clone(), spk.clone(), otpk.get(2).cloned(), parsed.clone()).unwrap();
+        assert_eq!(ek1.as_ref(), dk.as_ref());
+        assert_eq!(ek.as_ref(), dk1.as_ref());
+
+        // The one-time pre-key is required once the message names it
+        assert!(matches!(process_initial_message(ik, spk, None, parsed), Err(X3DHError::InvalidKey)));
+    }
+
+    #[test]
+    fn test_tampered_otpk_is_rejected() {
+        let (mut pb, _, _, _) = generate_prekey_bundle_with_otpk(2);
+        pb.otpk[1].0[0] ^= 1;
+        assert!(matches!(pb.validate(), Err(X3DHError::InvalidOtpkSignature)));
+        assert!(matches!(process_prekey_bundle(PrivateKey::new(), pb), Err(X3DHError::InvalidOtpkSignature)));
+
+        // A one-time pre-key signed by someone else is no better
+        let (mut pb, _, _, _) = generate_prekey_bundle_with_otpk(1);
+        let (other, _, _, _) = generate_prekey_bundle_with_otpk(1);
+        pb.otpk[0] = other.otpk[0].clone();
+        pb.otpk_sigs[0] = other.otpk_sigs[0].clone();
+        assert!(matches!(process_prekey_bundle(PrivateKey::new(), pb), Err(X3DHError::InvalidOtpkSignature)));
+    }
+
+    #[test]
+    fn test_otpk_signature_is_not_a_signed_prekey_signature() {
+        let ik = PrivateKey::new();
+        let otpk = PublicKey::from(&PrivateKey::new());
+        let mut pb = PreKeyBundle::new(&ik, SignedPreKey::new().public_key);
+        // Presenting a signed one-time pre-key as the signed pre-key fails
+        pb.spk = otpk.clone();
+        pb.sig = PreKeyBundle::sign_otpk(&ik, &otpk);
+        assert!(matches!(pb.verify(), Err(X3DHError::InvalidSignature(_))));
+    }
+
+    #[test]
+    fn test_pop_otpk_keeps_ids_in_step() {
+        let (mut pb, _, _, _) = generate_prekey_bundle_with_otpk(2);
+        let last = pb.otpk[1].clone();
+        let popped = pb.pop_otpk().unwrap();
+        assert_eq!((popped.key, popped.id), (last, Some(1)));
+        assert!(popped.sig.is_some());
+
+        // A key without an id leaves the bundle without ids
+        pb.add_otpk(PublicKey::from(&PrivateKey::new()));
+        assert!(pb.otpk_ids.is_empty());
+        pb.add_otpk_with_id(7, PublicKey::from(&PrivateKey::new()));
+        assert!(pb.otpk_ids.is_empty());
+        assert!(matches!(pb.pop_otpk(), Some(OneTimePreKey { id: None, .. })));
+        assert!(pb.validate().is_ok());
+
+        let mut pb = PreKeyBundle::new(&PrivateKey::new(), PublicKey::from(&PrivateKey::new()));
+        pb.add_otpk_with_id(4, PublicKey::from(&PrivateKey::new()));
+        pb.add_otpk_with_id(4, PublicKey::from(&PrivateKey::new()));
+        assert!(matches!(pb.validate(), Err(X3DHError::DuplicateOneTimePreKey(1))));
+    }
+
+    #[test]
+    fn test_initial_message_versions() {
+        let (pb, _, _) = generate_prekey_bundle();
+        let (im, _, _) = process_prekey_bundle(PrivateKey::new(), pb).unwrap();
+        let mut value: serde_json::Value = serde_json::from_str(&im.to_json()).unwrap();
+
+        for version in [InitialMessage::LEGACY_VERSION, InitialMessage::SERDE_VERSION + 1] {
+            value["version"] = version.into();
+            assert!(matches!(
+                InitialMessage::try_from(value.to_string()),
+                Err(X3DHError::UnsupportedInitialMessageVersion(v)) if v == version
+            ));
+        }
+        value.as_object_mut().unwrap().remove("challenge");
+        value["version"] = InitialMessage::SERDE_VERSION.into();
+        assert!(matches!(InitialMessage::try_from(value.to_string()), Err(X3DHError::InvalidInitialMessage)));
+    }
+
+    #[test]
+    fn test_sessions_in_each_aead_suite() {
+        for suite in [AeadSuite::Aes256Gcm, AeadSuite::ChaCha20Poly1305] {
+            let bob_identity_key = PrivateKey::new();
+            let bob_prekey = SignedPreKey::new();
+            let mut pb = PreKeyBundle::new(&bob_identity_key, bob_prekey.public_key.clone());
+            pb.aead_suite = suite;
+            // The suite survives the serde form of the bundle and of the initial message
+            let pb: PreKeyBundle = serde_json::from_str(&serde_json::to_string(&pb).unwrap()).unwrap();
+            let (im, alice_ek, alice_dk) = process_prekey_bundle_with_suite(PrivateKey::new(), pb, suite).unwrap();
+            let im = InitialMessage::try_from(im.to_json()).unwrap();
+            assert_eq!(im.aead_suite, suite);
+            let (bob_ek, bob_dk) = process_initial_message_with_suite(
+                bob_identity_key,
+                bob_prekey.private_key.clone(),
+                None,
+                suite,
+                im.clone()
+            ).unwrap();
+            assert_eq!(alice_ek.suite(), suite);
+            assert_eq!(bob_dk.suite(), suite);
+
+            let frame = alice_ek.encrypt_bytes(b"session", b"aad").unwrap();
+            assert_eq!(bob_dk.decrypt_frame(&frame, b"aad").unwrap(), b"session");
+            // The same key in the other suite cannot read the frame
+            let other = match suite {
+                AeadSuite::Aes256Gcm => AeadSuite::ChaCha20Poly1305,
+                AeadSuite::ChaCha20Poly1305 => AeadSuite::Aes256Gcm,
+            };
+            assert!(bob_dk.clone().with_suite(other).decrypt_frame(&frame, b"aad").is_err());
+
+            let alice_sk = SharedSecret::from_session_keys(Role::Initiator, &alice_ek, &alice_dk);
+            let bob_sk = SharedSecret::from_session_keys(Role::Responder, &bob_ek, &bob_dk);
+            let bob_ratchet = RatchetKeyPair::new_from(bob_prekey.private_key, bob_prekey.public_key.clone());
+            let mut alice = Ratchet::init_alice_with_header_encryption(alice_sk, bob_prekey.public_key).with_aead_suite(suite);
+            let mut bob = Ratchet::init_bob_with_header_encryption(bob_sk, bob_ratchet).with_aead_suite(suite);
+            let aad = im.associated_data.to_bytes();
+            let (ciphertext, meta) = alice.encrypt_with_meta(b"hello", &aad).unwrap();
+            assert_eq!(meta.cipher_suite, format!("{}_{}_HMAC_SHA256", Curve::ACTIVE, suite.name()));
+            assert_eq!(bob.decrypt(ciphertext, &aad).unwrap(), b"hello");
+            let ciphertext = bob.encrypt(b"hi", &aad).unwrap();
+            assert_eq!(alice.decrypt(ciphertext, &aad).unwrap(), b"hi");
+            let ciphertext = alice.encrypt(b"again", &aad).unwrap();
+            assert_eq!(bob.decrypt(ciphertext, &aad).unwrap(), b"again");
+        }
+    }
+
+    #[test]
+    fn test_aead_suite_mismatch_fails_at_handshake() {
+        let bob_identity_key = PrivateKey::new();
+        let bob_prekey = SignedPreKey::new();
+        let pb = PreKeyBundle::new(&bob_identity_key, bob_prekey.public_key.clone());
+        assert_eq!(pb.aead_suite, AeadSuite::Aes256Gcm);
+
+        // An initiator insisting on ChaCha20-Poly1305 refuses an AES-256-GCM bundle
+        assert!(matches!(
+            process_prekey_bundle_with_suite(PrivateKey::new(), pb.clone(), AeadSuite::ChaCha20Poly1305),
+            Err(X3DHError::AeadSuiteMismatch(AeadSuite::ChaCha20Poly1305, AeadSuite::Aes256Gcm))
+        ));
+
+        // A responder refuses a suite other than the one of its bundle
+        let mut offered = pb.clone();
+        offered.aead_suite = AeadSuite::ChaCha20Poly1305;
+        let (im, _, _) = process_prekey_bundle(PrivateKey::new(), offered).unwrap();
+        assert!(matches!(
+            process_initial_message_with_suite(bob_identity_key.clone(), bob_prekey.private_key.clone(), None, AeadSuite::Aes256Gcm, im.clone()),
+            Err(X3DHError::AeadSuiteMismatch(AeadSuite::Aes256Gcm, AeadSuite::ChaCha20Poly1305))
+        ));
+
+        // The challenge is encrypted with the suite, so a suite changed on the way does not authenticate
+        let (mut im, _, _) = process_prekey_bundle(PrivateKey::new(), pb).unwrap();
+        im.aead_suite = AeadSuite::ChaCha20Poly1305;
+        assert!(process_initial_message(bob_identity_key, bob_prekey.private_key, None, im).is_err());
+    }
+
+    #[test]
+    fn test_unknown_aead_suites_are_rejected() {
+        assert!(matches!(AeadSuite::try_from(7), Err(X3DHError::UnknownAeadSuite(7))));
+        for suite in [AeadSuite::Aes256Gcm, AeadSuite::ChaCha20Poly1305] {
+            assert_eq!(AeadSuite::try_from(suite.id()).unwrap(), suite);
+        }
+
+        let (pb, _, _) = generate_prekey_bundle();
+        let mut value = serde_json::to_value(&pb).unwrap();
+        value["aead_suite"] = 7.into();
+        let err = serde_json::from_value::<PreKeyBundle>(value).unwrap_err();
+        assert!(err.to_string().contains("Unknown AEAD suite: 7"));
+
+        let (im, _, _) = process_prekey_bundle(PrivateKey::new(), pb).unwrap();
+        let mut value: serde_json::Value = serde_json::from_str(&im.to_json()).unwrap();
+        value["aead_suite"] = 7.into();
+        assert!(matches!(InitialMessage::try_from(value.to_string()), Err(X3DHError::UnknownAeadSuite(7))));
+        // Messages that predate the suite are read as AES-256-GCM
+        value.as_object_mut().unwrap().remove("aead_suite");
+        assert_eq!(InitialMessage::try_from(value.to_string()).unwrap().aead_suite, AeadSuite::Aes256Gcm);
+    }
+
+    #[test]
+    fn test_handshake_in_each_conformance() {
+        for conformance in [Conformance::Native, Conformance::Spec] {
+            let (pb, ik, spk, otpk) = generate_prekey_bundle_with_otpk(1);
+            let bob_spk = RatchetKeyPair::new_from(spk.clone(), pb.spk.clone());
+            let (im, alice_ek, alice_dk) = process_prekey_bundle_with_conformance(PrivateKey::new(), pb.clone(), conformance).unwrap();
+            let im = InitialMessage::try_from(im.to_json()).unwrap();
+            assert_eq!(im.conformance, conformance);
+            assert_eq!(im.challenge.is_some(), conformance == Conformance::Native);
+            let (bob_ek, bob_dk) = process_initial_message(ik, spk, otpk.last().cloned(), im.clone()).unwrap();
+
+            let alice_sk = root_key(conformance, Role::Initiator, alice_ek.clone(), alice_dk);
+            let bob_sk = root_key(conformance, Role::Responder, bob_ek, bob_dk);
+            assert_eq!(alice_sk.as_ref(), bob_sk.as_ref());
+            // The spec-conformant SK is the root key itself
+            assert_eq!(alice_sk.as_ref() == alice_ek.as_ref(), conformance == Conformance::Spec);
+
+            let config = RatchetConfig { conformance, ..RatchetConfig::default() };
+            let mut alice = Ratchet::init_alice_with_config(alice_sk, pb.spk, config);
+            let mut bob = Ratchet::init_bob_with_config(bob_sk, bob_spk, config);
+            let aad = im.associated_data.to_bytes();
+            let ciphertext = alice.encrypt(b"hello", &aad).unwrap();
+            assert_eq!(bob.decrypt(ciphertext, &aad).unwrap(), b"hello");
+
+            // The conformance survives the persistence of both sessions
+            let mut alice = Ratchet::from_bytes(&alice.to_bytes()).unwrap();
+            let mut bob = Ratchet::from_bytes(&bob.to_bytes()).unwrap();
+            assert_eq!(alice.conformance(), conformance);
+            assert_eq!(bob.conformance(), conformance);
+            let ciphertext = bob.encrypt(b"hi", &aad).unwrap();
+            assert_eq!(alice.decrypt(ciphertext, &aad).unwrap(), b"hi");
+            let ciphertext = alice.encrypt(b"again", &aad).unwrap();
+            assert_eq!(bob.decrypt(ciphertext, &aad).unwrap(), b"again");
+        }
+    }
+
+    #[test]
+    fn test_conformance_is_bound_to_the_initial_message() {
+        let (pb, ik, spk) = generate_prekey_bundle();
+        let (im, _, _) = process_prekey_bundle_with_conformance(PrivateKey::new(), pb.clone(), Conformance::Spec).unwrap();
+        let mut value: serde_json::Value = serde_json::from_str(&im.to_json()).unwrap();
+        assert!(value.get("challenge").is_none());
+
+        // A spec-conformant message cannot pass for a native one without its challenge, and the other way round
+        value["conformance"] = Conformance::Native.id().into();
+        assert!(matches!(InitialMessage::try_from(value.to_string()), Err(X3DHError::InvalidInitialMessage)));
+        value["conformance"] = 7.into();
+        assert!(matches!(InitialMessage::try_from(value.to_string()), Err(X3DHError::UnknownConformance(7))));
+        let mut relabelled = im.clone();
+        relabelled.conformance = Conformance::Native;
+        assert!(matches!(
+            process_initial_message(ik.clone(), spk.clone(), None, relabelled),
+            Err(X3DHError::InvalidInitialMessage)
+        ));
+
+        // A native message stripped of its challenge derives other keys than the initiator's
+        let (im, alice_ek, _) = process_prekey_bundle(PrivateKey::new(), pb).unwrap();
+        let mut stripped = im.clone();
+        stripped.challenge = None;
+        stripped.conformance = Conformance::Spec;
+        let (_, bob_dk) = process_initial_message(ik, spk, None, stripped).unwrap();
+        assert_ne!(alice_ek.as_ref(), bob_dk.as_ref());
+    }
+
+    #[test]
+    fn test_handshake_on_the_active_curve() {
+        let (pb, ik, spk, otpk) = generate_prekey_bundle_with_otpk(1);
+        assert_eq!(pb.ik.as_ref().len(), Curve::ACTIVE.public_length());
+        assert_eq!(ik.to_bytes().len(), Curve::ACTIVE.secret_length());
+        assert!(pb.validate().is_ok());
+
+        let pb: PreKeyBundle = serde_json::from_str(&serde_json::to_string(&pb).unwrap()).unwrap();
+        let (im, alice_ek, alice_dk) = process_prekey_bundle(PrivateKey::new(), pb).unwrap();
+        let im = InitialMessage::try_from(im.to_json()).unwrap();
+        let (bob_ek, bob_dk) = process_initial_message(ik, spk, otpk.last().cloned(), im).unwrap();
+        assert_eq!(alice_ek.as_ref(), bob_dk.as_ref());
+        assert_eq!(alice_dk.as_ref(), bob_ek.as_ref());
+    }
+
+    #[test]
+    fn test_keys_on_another_curve_are_rejected() {
+        let other = match Curve::ACTIVE {
+            Curve::X25519 => Curve::X448,
+            Curve::X448 => Curve::X25519,
+        };
+        assert!(matches!(Curve::try_from(7), Err(X3DHError::UnknownCurve(7))));
+
+        let (pb, _, _) = generate_prekey_bundle();
+        let mut value = serde_json::to_value(&pb).unwrap();
+        assert_eq!(value["curve"], Curve::ACTIVE.id());
+        value["curve"] = other.id().into();
+        let err = serde_json::from_value::<PreKeyBundle>(value.clone()).unwrap_err();
+        assert!(err.to_string().contains(&format!("Curve mismatch: expected {}, found {}", Curve::ACTIVE, other)));
+        // Keys of the size of the other curve are refused even under the right id
+        value["curve"] = Curve::ACTIVE.id().into();
+        value["spk"] = vec![9u8; other.public_length()].into();
+        let err = serde_json::from_value::<PreKeyBundle>(value).unwrap_err();
+        assert!(err.to_string().contains("Invalid prekey bundle"));
+
+        let (im, _, _) = process_prekey_bundle(PrivateKey::new(), pb).unwrap();
+        let mut value: serde_json::Value = serde_json::from_str(&im.to_json()).unwrap();
+        value["curve"] = other.id().into();
+        assert!(matches!(InitialMessage::try_from(value.to_string()), Err(X3DHError::CurveMismatch(curve)) if curve == other));
+        value["curve"] = 7.into();
+        assert!(matches!(InitialMessage::try_from(value.to_string()), Err(X3DHError::UnknownCurve(7))));
+        // Messages that predate the curve are read as X25519
+        value.as_object_mut().unwrap().remove("curve");
+        assert_eq!(InitialMessage::try_from(value.to_string()).is_ok(), Curve::ACTIVE == Curve::X25519);
+    }
+
+    /// A bundle offering a KEM pre-key, with the private keys of its owner.
+    #[cfg(feature = "pqxdh")]
+    fn hybrid_bundle() -> (PreKeyBundle, PrivateKey, SignedPreKey, KemPrivateKey) {
+        let ik = PrivateKey::new();
+        let spk = SignedPreKey::new();
+        let kem = KemPrivateKey::new();
+        let mut pb = PreKeyBundle::new(&ik, spk.public_key.clone());
+        pb.set_kem_prekey(&ik, kem.public_key());
+        (pb, ik, spk, kem)
+    }
+
+    #[cfg(feature = "pqxdh")]
+    #[test]
+    fn test_hybrid_handshake() {
+        let (pb, bob_ik, bob_spk, bob_kem) = hybrid_bundle();
+        let pb: PreKeyBundle = serde_json::from_str(&serde_json::to_string(&pb).unwrap()).unwrap();
+        let (im, alice_ek, alice_dk) = process_prekey_bundle(PrivateKey::new(), pb).unwrap();
+        let im = InitialMessage::try_from(im.to_json()).unwrap();
+        assert!(im.kem_ciphertext.is_some());
+
+        // Without the KEM pre-key the responder cannot take part
+        assert!(matches!(
+            process_initial_message(bob_ik.clone(), bob_spk.private_key.clone(), None, im.clone()),
+            Err(X3DHError::MissingKemPreKey)
+        ));
+        let (bob_ek, bob_dk) = process_hybrid_initial_message(bob_ik, bob_spk.private_key, None, &bob_kem, im).unwrap();
+        assert_eq!(alice_ek.as_ref(), bob_dk.as_ref());
+        assert_eq!(alice_dk.as_ref(), bob_ek.as_ref());
+
+        // The KEM private key survives its serialization
+        let restored = KemPrivateKey::try_from(bob_kem.to_bytes().as_slice()).unwrap();
+        assert_eq!(restored.public_key(), bob_kem.public_key());
+        assert!(matches!(KemPrivateKey::try_from(&[0u8; 32][..]), Err(X3DHError::InvalidPrivateKey)));
+    }
+
+    #[cfg(feature = "pqxdh")]
+    #[test]
+    fn test_hybrid_initiator_with_classic_responder() {
+        // A bundle without a KEM pre-key makes for a classic key agreement
+        let bob_ik = PrivateKey::new();
+        let bob_spk = SignedPreKey::new();
+        let pb = PreKeyBundle::new(&bob_ik, bob_spk.public_key.clone());
+        let (im, alice_ek, _) = process_prekey_bundle(PrivateKey::new(), pb.clone()).unwrap();
+        assert!(im.kem_ciphertext.is_none());
+        let (_, bob_dk) = process_initial_message(bob_ik.clone(), bob_spk.private_key.clone(), None, im).unwrap();
+        assert_eq!(alice_ek.as_ref(), bob_dk.as_ref());
+
+        // As does a bundle in the legacy packing, even from a responder holding a KEM pre-key
+        let (mut pb, bob_ik, bob_spk, bob_kem) = hybrid_bundle();
+        pb = PreKeyBundle::try_from(pb.to_base64()).unwrap();
+        let (im, alice_ek, _) = process_prekey_bundle(PrivateKey::new(), pb).unwrap();
+        assert!(im.kem_ciphertext.is_none());
+        let (_, bob_dk) = process_hybrid_initial_message(bob_ik, bob_spk.private_key, None, &bob_kem, im).unwrap();
+        assert_eq!(alice_ek.as_ref(), bob_dk.as_ref());
+    }
+
+    #[cfg(feature = "pqxdh")]
+    #[test]
+    fn test_hybrid_handshake_is_bound_to_the_kem_secret() {
+        let (pb, bob_ik, bob_spk, bob_kem) = hybrid_bundle();
+
+        // A ciphertext stripped on the way changes the keys, so the challenge fails
+        let (mut im, _, _) = process_prekey_bundle(PrivateKey::new(), pb.clone()).unwrap();
+        im.kem_ciphertext = None;
+        assert!(process_hybrid_initial_message(bob_ik.clone(), bob_spk.private_key.clone(), None, &bob_kem, im).is_err());
+
+        // As does a ciphertext for another KEM pre-key
+        let (im, _, _) = process_prekey_bundle(PrivateKey::new(), pb.clone()).unwrap();
+        assert!(process_hybrid_initial_message(bob_ik, bob_spk.private_key, None, &KemPrivateKey::new(), im).is_err());
+
+        // A KEM pre-key replaced on the way is refused before anything is encapsulated
+        let mut forged = pb;
+        forged.kem_prekey.as_mut().unwrap().key = KemPrivateKey::new().public_key();
+        assert!(matches!(process_prekey_bundle(PrivateKey::new(), forged), Err(X3DHError::InvalidKemSignature)));
+    }
+
+    #[cfg(not(feature = "pqxdh"))]
+    #[test]
+    fn test_classic_initiator_ignores_kem_prekey() {
+        let bob_ik = PrivateKey::new();
+        let bob_spk = SignedPreKey::new();
+        let mut pb = PreKeyBundle::new(&bob_ik, bob_spk.public_key.clone());
+        pb.set_kem_prekey(&bob_ik, KemPublicKey::try_from(&[7u8; KEM_PUBLIC_LENGTH][..]).unwrap());
+        let (im, alice_ek, _) = process_prekey_bundle(PrivateKey::new(), pb).unwrap();
+        assert!(im.kem_ciphertext.is_none());
+        let (_, bob_dk) = process_initial_message(bob_ik, bob_spk.private_key, None, im).unwrap();
+        assert_eq!(alice_ek.as_ref(), bob_dk.as_ref());
+    }
 }
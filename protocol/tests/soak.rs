@@ -0,0 +1,35 @@
+//! A short run of the soak example, see `examples/soak.rs` for the long one.
+
+#[allow(dead_code)]
+#[path = "../examples/soak.rs"]
+mod soak;
+
+use soak::{run, SoakOptions, SKIPPED_KEY_LIMIT, STATE_LIMIT};
+
+fn short(seed: u64) -> SoakOptions {
+    SoakOptions {
+        messages: 1000,
+        seed,
+        max_size: 512,
+        reconnect_every: 100,
+        reset_every: 400,
+        ..SoakOptions::default()
+    }
+}
+
+#[test]
+fn test_short_soak() {
+    let report = run(&short(42)).unwrap();
+    assert_eq!(report.sent, 1000);
+    assert_eq!(report.delivered + report.dropped + report.evicted, report.sent);
+    assert!(report.dropped > 0 && report.reordered > 0);
+    assert_eq!((report.reconnects, report.resets), (7, 2));
+    assert!(report.max_skipped_keys <= SKIPPED_KEY_LIMIT);
+    assert!(report.max_state_bytes <= STATE_LIMIT);
+}
+
+#[test]
+fn test_soak_is_reproducible_from_its_seed() {
+    assert_eq!(run(&short(7)).unwrap(), run(&short(7)).unwrap());
+    assert_ne!(run(&short(7)).unwrap(), run(&short(8)).unwrap());
+}
@@ -0,0 +1,91 @@
+//! Limits on the registrations a server accepts, shared by all the connections of a server.
+//!
+//! The cap on registered users can be changed while the server runs, see [`Capacity::set_max_users`].
+
+use chrono::NaiveDate;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+/// Why a registration was refused by the [`Capacity`] of the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Refusal {
+    /// As many users are registered as the server takes.
+    Full,
+    /// The address registered as many users today as it may.
+    QuotaExceeded,
+}
+
+impl Refusal {
+    /// The name of the counter recorded in the [`crate::metrics::Metrics`] for this refusal.
+    pub(crate) fn counter(&self) -> &'static str {
+        match self {
+            Refusal::Full => "registrations_refused_full",
+            Refusal::QuotaExceeded => "registrations_refused_quota",
+        }
+    }
+}
+
+/// The maximum number of registered users, and of registrations per address and day.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Capacity {
+    max_users: Arc<Mutex<Option<usize>>>,
+    daily_quota: Option<u32>,
+    /// Registrations accepted from each address on the day they were counted.
+    registrations: Arc<Mutex<HashMap<String, (NaiveDate, u32)>>>,
+}
+
+impl Capacity {
+    /// A capacity of `max_users` registered users, each address registering at most
+    /// `daily_quota` of them per day. `None` means unlimited.
+    pub(crate) fn new(max_users: Option<usize>, daily_quota: Option<u32>) -> Self {
+        Self {
+            max_users: Arc::new(Mutex::new(max_users)),
+            daily_quota,
+            registrations: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    pub(crate) fn max_users(&self) -> Option<usize> {
+        *self.max_users.lock().unwrap()
+    }
+
+    /// Changes the cap on registered users for every connection, without a restart.
+    /// Users already registered beyond a lowered cap are kept.
+    pub(crate) fn set_max_users(&self, max_users: Option<usize>) {
+        *self.max_users.lock().unwrap() = max_users;
+    }
+
+    /// Decides whether a new user may register from `addr` while `users` are registered, and
+    /// counts the registration against the quota of `addr` on `today` if so.
+    pub(crate) fn admit(&self, users: usize, addr: &str, today: NaiveDate) -> Result<(), Refusal> {
+        self.readmit(users)?;
+        self.admit_device(addr, today)
+    }
+
+    /// Decides whether a user who registered before may come back while `users` are registered.
+    /// Nothing is counted against the quota.
+    pub(crate) fn readmit(&self, users: usize) -> Result<(), Refusal> {
+        if self.max_users().is_some_and(|max| users >= max) {
+            return Err(Refusal::Full);
+        }
+        Ok(())
+    }
+
+    /// Decides whether another device of a registered user may register from `addr`, and counts the
+    /// registration against the quota of `addr` on `today` if so. Devices do not count as users.
+    pub(crate) fn admit_device(&self, addr: &str, today: NaiveDate) -> Result<(), Refusal> {
+        let Some(quota) = self.daily_quota else { return Ok(()) };
+        // Connections from the same host differ only by port
+        let host = addr.parse::<SocketAddr>().map_or(addr.to_string(), |a| a.ip().to_string());
+        let mut registrations = self.registrations.lock().unwrap();
+        // Counts from previous days are of no use
+        registrations.retain(|_, (day, _)| *day == today);
+        let (_, count) = registrations.entry(host).or_insert((today, 0));
+        if *count >= quota {
+            return Err(Refusal::QuotaExceeded);
+        }
+        *count += 1;
+        Ok(())
+    }
+}
@@ -1,4 +1,5 @@
-#[allow(warnings)]
+use crate::capacity::Refusal;
+use common::{CommonError, UsernameError};
 use protocol::errors::X3DHError;
 use std::env;
 use std::fmt::Display;
@@ -12,10 +13,17 @@ pub(crate) enum ServerError {
     UserAlreadyExists,
     InvalidPreKeyBundle,
     InvalidRequest,
+    UndecryptableRequest(CommonError),
     Base64DecodeError(base64::DecodeError),
     GenericError(Error),
     TokioTungsteniteError(tokio_tungstenite::tungstenite::Error),
-    SendError(String)
+    SendError(String),
+    RelayBlocked,
+    InvalidUsername(UsernameError),
+    ReplicationError(String),
+    ReplicaBehind(u64),
+    RegistrationRefused(Refusal),
+    MemoryPressure,
 }
 
 impl Display for ServerError {
@@ -27,10 +35,18 @@ impl Display for ServerError {
             ServerError::UserAlreadyExists => write!(f, "User already exists"),
             ServerError::InvalidPreKeyBundle => write!(f, "Invalid prekey bundle"),
             ServerError::InvalidRequest => write!(f, "Invalid request"),
+            ServerError::UndecryptableRequest(e) => write!(f, "Undecryptable request: {}", e),
             ServerError::Base64DecodeError(decode_error) => write!(f, "Error: {}", decode_error),
             ServerError::GenericError(e) => write!(f, "Generic error: {}", e),
             ServerError::TokioTungsteniteError(e) => write!(f, "Tokio Tungstenite error: {}", e),
             ServerError::SendError(e) => write!(f, "Send error: {}", e),
+            ServerError::RelayBlocked => write!(f, "Recipient closed the chat"),
+            ServerError::InvalidUsername(e) => write!(f, "Invalid username: {}", e),
+            ServerError::ReplicationError(e) => write!(f, "Replication error: {}", e),
+            ServerError::ReplicaBehind(lag) => write!(f, "Standby is {} changes behind the primary", lag),
+            ServerError::RegistrationRefused(Refusal::Full) => write!(f, "Registration refused, the server is full"),
+            ServerError::RegistrationRefused(Refusal::QuotaExceeded) => write!(f, "Registration refused, daily quota exceeded"),
+            ServerError::MemoryPressure => write!(f, "Refused under memory pressure"),
         }
     }
 }
@@ -60,6 +76,12 @@ impl From<env::VarError> for ServerError {
     }
 }
 
+impl From<CommonError> for ServerError {
+    fn from(value: CommonError) -> Self {
+        ServerError::UndecryptableRequest(value)
+    }
+}
+
 impl From<base64::DecodeError> for ServerError {
     fn from(value: base64::DecodeError) -> Self {
         ServerError::Base64DecodeError(value)
@@ -1,24 +1,154 @@
 #![allow(warnings)]
 mod utils;
 
+mod capacity;
 mod errors;
+mod memory;
+mod metrics;
+mod replication;
+#[cfg(test)]
 mod tests;
 
-use crate::utils::Server;
+use crate::capacity::Capacity;
+use crate::memory::MemoryLimits;
+use crate::replication::Replica;
+use crate::utils::{PeerMap, Server};
 use common::CONFIG;
+use log::{error, info, warn};
 use std::env;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, BufReader, Lines, Stdin};
+use tokio::sync::mpsc;
+
+/// The lines of the standard input, read by the standby and then by the admin commands.
+type AdminInput = Lines<BufReader<Stdin>>;
+
+/// A standby is not promoted while it misses more changes than this.
+const MAX_PROMOTION_LAG: u64 = 16;
 
 #[tokio::main]
 async fn main() {
     env::set_var("RUST_LOG", CONFIG.get_log_level());
     env_logger::init();
-    let mut server = if CONFIG.get_server_ip() == "server" {
-        Server::new("0.0.0.0".to_string(), CONFIG.get_server_port())
+    let addr = if CONFIG.get_server_ip() == "server" {
+        "0.0.0.0".to_string()
     } else {
-        Server::new(CONFIG.get_server_ip(), CONFIG.get_server_port())
+        CONFIG.get_server_ip()
+    };
+
+    // `--standby <url>` follows the primary at the replication url until promoted
+    let args: Vec<String> = env::args().collect();
+    let mut input = BufReader::new(tokio::io::stdin()).lines();
+    let server = match args.iter().position(|arg| arg == "--standby").and_then(|i| args.get(i + 1)) {
+        Some(url) => {
+            let peers = run_standby(url.clone(), &mut input).await;
+            Server::with_peers(addr, CONFIG.get_server_port(), peers)
+        }
+        None => Server::new(addr, CONFIG.get_server_port()),
+    };
+
+    let capacity = Capacity::new(CONFIG.get_max_registered_users(), CONFIG.get_daily_registrations_per_address());
+    let memory = MemoryLimits::new(CONFIG.get_memory_soft_limit(), CONFIG.get_memory_hard_limit());
+    tokio::spawn(run_admin(input, capacity.clone(), memory.clone(), server.peers.clone()));
+    let mut server = server.with_capacity(capacity).with_memory_limits(memory);
+    let interrupted = async {
+        match tokio::signal::ctrl_c().await {
+            Ok(()) => info!("Received Ctrl-C"),
+            Err(e) => {
+                error!("Cannot listen for Ctrl-C, the server only stops when killed: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
     };
+    // Dropping `listen` stops accepting connections
+    tokio::select! {
+        _ = server.listen() => {}
+        _ = interrupted => {}
+    }
+    server.shutdown().await;
+}
 
-    server.listen().await;
+/// Reads admin commands from the standard input while the server runs.
+///
+/// `max_users <n>` changes the cap on registered users, `max_users none` removes it.
+/// `memory_soft <bytes>` and `memory_hard <bytes>` change the memory limits, `none` removes them.
+/// `memory` shows the bytes held for the users, `memory <user>` those held for one of them.
+async fn run_admin(mut input: AdminInput, capacity: Capacity, memory: MemoryLimits, peers: PeerMap) {
+    while let Ok(Some(line)) = input.next_line().await {
+        match line.split_whitespace().collect::<Vec<_>>().as_slice() {
+            ["max_users", "none"] => {
+                capacity.set_max_users(None);
+                info!("Registered users are no longer capped");
+            }
+            ["max_users", n] => match n.parse() {
+                Ok(max) => {
+                    capacity.set_max_users(Some(max));
+                    info!("Registered users capped at {}", max);
+                }
+                Err(_) => warn!("Not a number of users: {}", n),
+            },
+            ["memory"] => {
+                let usage = peers.read().await.memory_usage();
+                info!(
+                    "Holding {}, pressure {:?}, soft limit {:?}, hard limit {:?}",
+                    usage, memory.pressure(&usage), memory.soft_limit(), memory.hard_limit()
+                );
+            }
+            ["memory", user] => info!("Holding {} for {}", peers.read().await.memory_usage_of(user), user),
+            [limit @ ("memory_soft" | "memory_hard"), bytes] => {
+                let bytes = match *bytes {
+                    "none" => None,
+                    bytes => match bytes.parse() {
+                        Ok(bytes) => Some(bytes),
+                        Err(_) => {
+                            warn!("Not a number of bytes: {}", bytes);
+                            continue;
+                        }
+                    },
+                };
+                if *limit == "memory_soft" {
+                    memory.set_soft_limit(bytes);
+                } else {
+                    memory.set_hard_limit(bytes);
+                }
+                info!("{} set to {:?} bytes", limit, bytes);
+            }
+            [] => {}
+            _ => warn!("Unknown command: {}", line.trim()),
+        }
+    }
 }
 
+/// Follows the primary at `url` until the `promote` command is entered on `input`, or the process
+/// receives SIGUSR1, and returns the replicated store.
+async fn run_standby(url: String, input: &mut AdminInput) -> utils::PeerMap {
+    let secret = CONFIG.get_replication_secret().expect("A standby needs the replication secret");
+    let replica = Replica::new();
+    let follower = replica.clone();
+    tokio::spawn(async move {
+        while !follower.is_promoted() {
+            if let Err(e) = follower.follow(&url, secret.as_bytes()).await {
+                error!("Lost the primary: {}", e);
+            }
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+    });
 
+    info!("Running as a standby, enter `promote` or send SIGUSR1 to serve clients");
+    replica.promote_on_request(MAX_PROMOTION_LAG, input, &mut promotion_signals()).await
+}
+
+/// Forwards every SIGUSR1 the process receives, so that a standby without a terminal can be promoted.
+fn promotion_signals() -> mpsc::UnboundedReceiver<()> {
+    let (tx, rx) = mpsc::unbounded_channel();
+    #[cfg(unix)]
+    match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::user_defined1()) {
+        Ok(mut signals) => {
+            tokio::spawn(async move {
+                while signals.recv().await.is_some() && tx.send(()).is_ok() {}
+            });
+        }
+        Err(e) => error!("Cannot listen for SIGUSR1, the standby is only promoted from the standard input: {}", e),
+    }
+    rx
+}
@@ -0,0 +1,182 @@
+//! Coarse accounting of the memory a server holds for its users, and the limits beyond which it pushes back.
+//!
+//! Beyond the soft limit, messages for offline users are no longer queued and the queues already held are
+//! cut down to [`PRESSURE_QUEUE_LIMIT`]. At the hard limit, new connections are refused. Both limits can be
+//! changed while the server runs, see [`MemoryLimits::set_soft_limit`] and [`MemoryLimits::set_hard_limit`].
+
+use crate::utils::OFFLINE_QUEUE_LIMIT;
+use log::{info, warn};
+use protocol::utils::{PreKeyBundle, PublicKey, Signature};
+use std::fmt::Display;
+use std::mem::size_of;
+use std::ops::Add;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Messages kept for a user while they are offline once the soft limit is reached.
+pub(crate) const PRESSURE_QUEUE_LIMIT: usize = OFFLINE_QUEUE_LIMIT / 4;
+
+/// The bytes held for the users of a server, or for one of them.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct MemoryUsage {
+    /// Relayed messages handed to the connections and not written to their socket yet.
+    pub(crate) queued: usize,
+    /// The stored pre-key bundles, one-time pre-keys included.
+    pub(crate) bundles: usize,
+    /// The messages kept for offline users.
+    pub(crate) offline: usize,
+}
+
+impl MemoryUsage {
+    pub(crate) fn total(&self) -> usize {
+        self.queued + self.bundles + self.offline
+    }
+}
+
+impl Add for MemoryUsage {
+    type Output = MemoryUsage;
+
+    fn add(self, other: MemoryUsage) -> MemoryUsage {
+        MemoryUsage {
+            queued: self.queued + other.queued,
+            bundles: self.bundles + other.bundles,
+            offline: self.offline + other.offline,
+        }
+    }
+}
+
+impl Display for MemoryUsage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} bytes ({} queued, {} in bundles, {} offline)",
+            self.total(), self.queued, self.bundles, self.offline
+        )
+    }
+}
+
+/// The bytes a stored bundle takes, one-time pre-keys and their ids and signatures included.
+pub(crate) fn bundle_size(bundle: &PreKeyBundle) -> usize {
+    size_of::<PreKeyBundle>()
+        + bundle.otpk.len() * size_of::<PublicKey>()
+        + bundle.otpk_ids.len() * size_of::<u32>()
+        + bundle.otpk_sigs.len() * size_of::<Signature>()
+        + bundle.kem_prekey.as_ref().map_or(0, |kem| kem.key.as_ref().len())
+}
+
+/// Bytes relayed to a connection and not written to its socket yet, counted towards the total of the server.
+///
+/// Bytes are counted before they are handed to the connection and uncounted once written, so the total
+/// never falls below what the connections hold.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct PendingBytes {
+    connection: Arc<AtomicUsize>,
+    server: Arc<AtomicUsize>,
+}
+
+impl PendingBytes {
+    /// A counter for a new connection, adding to `server`.
+    pub(crate) fn new(server: Arc<AtomicUsize>) -> Self {
+        Self { connection: Arc::new(AtomicUsize::new(0)), server }
+    }
+
+    /// The bytes the connection has yet to write.
+    pub(crate) fn get(&self) -> usize {
+        self.connection.load(Ordering::SeqCst)
+    }
+
+    pub(crate) fn add(&self, bytes: usize) {
+        self.server.fetch_add(bytes, Ordering::SeqCst);
+        self.connection.fetch_add(bytes, Ordering::SeqCst);
+    }
+
+    /// Uncounts `bytes` written by the connection. Messages not counted when relayed, such as replenish
+    /// requests, must not wrap the counts.
+    pub(crate) fn sub(&self, bytes: usize) {
+        let previous = self.connection
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |pending| Some(pending.saturating_sub(bytes)))
+            .unwrap();
+        self.uncount(previous.min(bytes));
+    }
+
+    /// Uncounts every byte of the connection, whose messages will not be written any more.
+    pub(crate) fn release(&self) {
+        self.uncount(self.connection.swap(0, Ordering::SeqCst));
+    }
+
+    fn uncount(&self, bytes: usize) {
+        let _ = self.server.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |total| Some(total.saturating_sub(bytes)));
+    }
+}
+
+/// How close the server is to its memory limits, from the least to the most pressing.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum Pressure {
+    /// Below the soft limit.
+    #[default]
+    Normal,
+    /// At or beyond the soft limit: messages for offline users are refused.
+    Soft,
+    /// At or beyond the hard limit: new connections are refused as well.
+    Hard,
+}
+
+/// The soft and hard limits on the bytes held by a server, shared by all its connections.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct MemoryLimits {
+    soft: Arc<Mutex<Option<usize>>>,
+    hard: Arc<Mutex<Option<usize>>>,
+    /// The pressure last found, so that only changes are logged.
+    last: Arc<Mutex<Pressure>>,
+}
+
+impl MemoryLimits {
+    /// Limits of `soft` and `hard` bytes. `None` means unlimited.
+    pub(crate) fn new(soft: Option<usize>, hard: Option<usize>) -> Self {
+        Self {
+            soft: Arc::new(Mutex::new(soft)),
+            hard: Arc::new(Mutex::new(hard)),
+            last: Arc::new(Mutex::new(Pressure::Normal)),
+        }
+    }
+
+    pub(crate) fn soft_limit(&self) -> Option<usize> {
+        *self.soft.lock().unwrap()
+    }
+
+    pub(crate) fn hard_limit(&self) -> Option<usize> {
+        *self.hard.lock().unwrap()
+    }
+
+    /// Changes the soft limit for every connection, without a restart.
+    pub(crate) fn set_soft_limit(&self, soft: Option<usize>) {
+        *self.soft.lock().unwrap() = soft;
+    }
+
+    /// Changes the hard limit for every connection, without a restart.
+    pub(crate) fn set_hard_limit(&self, hard: Option<usize>) {
+        *self.hard.lock().unwrap() = hard;
+    }
+
+    /// The pressure the server is under while holding `usage`, logged when it changes.
+    pub(crate) fn pressure(&self, usage: &MemoryUsage) -> Pressure {
+        let total = usage.total();
+        let pressure = if self.hard_limit().is_some_and(|hard| total >= hard) {
+            Pressure::Hard
+        } else if self.soft_limit().is_some_and(|soft| total >= soft) {
+            Pressure::Soft
+        } else {
+            Pressure::Normal
+        };
+        let mut last = self.last.lock().unwrap();
+        if *last != pressure {
+            match pressure {
+                Pressure::Normal => info!("Memory pressure relieved, holding {}", usage),
+                Pressure::Soft => warn!("Soft memory limit reached, refusing offline messages, holding {}", usage),
+                Pressure::Hard => warn!("Hard memory limit reached, refusing connections, holding {}", usage),
+            }
+            *last = pressure;
+        }
+        pressure
+    }
+}
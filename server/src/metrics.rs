@@ -0,0 +1,72 @@
+//! Latency histograms of the request handlers and event counters, shared by all the connections of a server.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Upper bounds of the latency buckets, in milliseconds. Slower requests fall in a last, unbounded bucket.
+pub(crate) const LATENCY_BUCKETS_MS: [u64; 8] = [1, 5, 10, 50, 100, 500, 1000, 5000];
+
+/// The number of requests that completed within each of the [`LATENCY_BUCKETS_MS`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct Histogram {
+    buckets: [u64; LATENCY_BUCKETS_MS.len() + 1],
+    total_ms: u64,
+}
+
+impl Histogram {
+    fn record(&mut self, elapsed: Duration) {
+        let ms = elapsed.as_millis() as u64;
+        let bucket = LATENCY_BUCKETS_MS.iter().position(|&bound| ms <= bound).unwrap_or(LATENCY_BUCKETS_MS.len());
+        self.buckets[bucket] += 1;
+        self.total_ms += ms;
+    }
+
+    /// The number of requests recorded.
+    pub(crate) fn count(&self) -> u64 {
+        self.buckets.iter().sum()
+    }
+
+    /// The number of requests per bucket, the last one counting those slower than every bound.
+    pub(crate) fn buckets(&self) -> &[u64] {
+        &self.buckets
+    }
+
+    /// The mean latency of the recorded requests.
+    pub(crate) fn mean(&self) -> Duration {
+        Duration::from_millis(self.total_ms.checked_div(self.count()).unwrap_or(0))
+    }
+}
+
+/// The latency histogram of every request type, and the number of times each counted event happened.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Metrics {
+    latencies: Arc<Mutex<HashMap<&'static str, Histogram>>>,
+    counters: Arc<Mutex<HashMap<&'static str, u64>>>,
+}
+
+impl Metrics {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records that a request of `request_type` took `elapsed`, including requests that missed their deadline.
+    pub(crate) fn record(&self, request_type: &'static str, elapsed: Duration) {
+        self.latencies.lock().unwrap().entry(request_type).or_default().record(elapsed);
+    }
+
+    /// Returns a copy of the histogram of `request_type`.
+    pub(crate) fn latency(&self, request_type: &str) -> Histogram {
+        self.latencies.lock().unwrap().get(request_type).cloned().unwrap_or_default()
+    }
+
+    /// Counts one more occurrence of the event `name`.
+    pub(crate) fn increment(&self, name: &'static str) {
+        *self.counters.lock().unwrap().entry(name).or_default() += 1;
+    }
+
+    /// The number of occurrences of the event `name`.
+    pub(crate) fn counter(&self, name: &str) -> u64 {
+        self.counters.lock().unwrap().get(name).copied().unwrap_or(0)
+    }
+}
@@ -0,0 +1,522 @@
+//! Replication of the user store from a primary server to a standby.
+//!
+//! Every change to the [`PeerMap`] is recorded in a [`MutationLog`]. A standby connects to the
+//! replication port of the primary, authenticates with the shared secret, receives a snapshot
+//! of the store and then follows the log. It can be promoted to serve clients when the primary
+//! is gone, as long as it is not too far behind.
+
+use crate::errors::ServerError;
+use crate::utils::{Device, Peer, PeerMap, Peers, QueuedMessage};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use futures_util::{Sink, SinkExt, Stream, StreamExt};
+use hmac::{Hmac, Mac};
+use log::{debug, error, info};
+use protocol::utils::PreKeyBundle;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncBufRead, Lines};
+use tokio::net::TcpListener;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::{mpsc, RwLock};
+use tokio_tungstenite::tungstenite::{self, Message, Utf8Bytes};
+use tokio_tungstenite::{accept_async, connect_async};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Entries kept for standbys that are slower than the primary. A standby that falls further
+/// behind is disconnected and starts over from a snapshot.
+const MUTATION_LOG_CAPACITY: usize = 4096;
+
+/// Interval at which the primary tells its standbys how far the log has gone.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Length in bytes of the nonces exchanged during the handshake.
+const HANDSHAKE_NONCE_LENGTH: usize = 32;
+
+/// A change to the user store, as replicated to a standby.
+///
+/// Changes to a device name it by its id, empty for devices of clients that predate them and in
+/// changes sent by older primaries.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "mutation", rename_all = "snake_case")]
+pub(crate) enum Mutation {
+    Register {
+        username: String,
+        #[serde(default)]
+        device_id: String,
+        bundle: String,
+    },
+    Unregister {
+        username: String,
+        #[serde(default)]
+        device_id: String,
+    },
+    BundleUpdated {
+        username: String,
+        #[serde(default)]
+        device_id: String,
+        bundle: String,
+    },
+    OtpkConsumed {
+        username: String,
+        #[serde(default)]
+        device_id: String,
+    },
+    MessageQueued { username: String, message: QueuedMessage },
+    QueueFlushed {
+        username: String,
+        #[serde(default)]
+        device_id: String,
+    },
+}
+
+impl Mutation {
+    /// Applies the change to `peers`. Users are added without a connection, so they reach the
+    /// promoted standby by registering again.
+    fn apply(self, peers: &mut Peers) -> Result<(), ServerError> {
+        match self {
+            Mutation::Register { username, device_id, bundle } => {
+                let device = Device { username, device_id };
+                let bundle = decode_bundle(bundle)?;
+                peers.remember_device(&device, &bundle.ik);
+                peers.insert(device, Peer::detached(bundle));
+            }
+            Mutation::Unregister { username, device_id } => {
+                peers.remove(&Device { username, device_id });
+            }
+            Mutation::BundleUpdated { username, device_id, bundle } => {
+                let device = Device { username, device_id };
+                let bundle = decode_bundle(bundle)?;
+                peers.update(&device, |peer| peer.pb = bundle).ok_or(ServerError::UserNotFoundError)?;
+            }
+            Mutation::OtpkConsumed { username, device_id } => {
+                let device = Device { username, device_id };
+                peers.update(&device, |peer| peer.pb.pop_otpk()).ok_or(ServerError::UserNotFoundError)?;
+            }
+            Mutation::MessageQueued { username, message } => {
+                peers.enqueue(&username, message);
+            }
+            Mutation::QueueFlushed { username, device_id } => {
+                peers.take_queued(&Device { username, device_id });
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Sequence of the changes made to the user store, numbered from 1.
+#[derive(Clone)]
+pub(crate) struct MutationLog {
+    head: Arc<AtomicU64>,
+    entries: broadcast::Sender<(u64, Mutation)>,
+}
+
+impl MutationLog {
+    pub(crate) fn new() -> Self {
+        let (entries, _) = broadcast::channel(MUTATION_LOG_CAPACITY);
+        Self { head: Arc::new(AtomicU64::new(0)), entries }
+    }
+
+    /// Records `mutation` and returns its sequence number.
+    ///
+    /// Must be called while the peer map is locked for writing, so that entries are numbered in
+    /// the order the changes were made and a snapshot never misses one.
+    pub(crate) fn append(&self, mutation: Mutation) -> u64 {
+        let seq = self.head.fetch_add(1, Ordering::SeqCst) + 1;
+        // Nobody listens until a standby connects
+        let _ = self.entries.send((seq, mutation));
+        seq
+    }
+
+    /// Sequence number of the last recorded change, 0 if there was none.
+    pub(crate) fn head(&self) -> u64 {
+        self.head.load(Ordering::SeqCst)
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<(u64, Mutation)> {
+        self.entries.subscribe()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Handshake {
+    Challenge { nonce: String },
+    Hello { nonce: String, mac: String },
+    Welcome { mac: String },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub(crate) enum Update {
+    /// The whole store as of `seq`, pairing usernames with their bundles and offline queues.
+    Snapshot {
+        seq: u64,
+        users: Vec<(String, String)>,
+        /// The device ids of the `users`, in the same order. Missing from the snapshots of older primaries.
+        #[serde(default)]
+        devices: Vec<String>,
+        #[serde(default)]
+        offline: Vec<(String, Vec<QueuedMessage>)>,
+    },
+    Entry { seq: u64, head: u64, mutation: Mutation },
+    Heartbeat { head: u64 },
+}
+
+/// An [`Update`] with a MAC over the session key and the number of frames sent before it.
+#[derive(Debug, Serialize, Deserialize)]
+struct Frame {
+    body: String,
+    mac: String,
+}
+
+/// Encodes a bundle for a [`Mutation`] or a snapshot, in the serde form that keeps the one-time pre-key ids.
+pub(crate) fn encode_bundle(bundle: &PreKeyBundle) -> String {
+    serde_json::to_string(bundle).expect("Serializing a bundle cannot fail")
+}
+
+/// Decodes a bundle written by [`encode_bundle`], or the legacy base64 string sent by older primaries.
+fn decode_bundle(bundle: String) -> Result<PreKeyBundle, ServerError> {
+    if bundle.starts_with('{') {
+        serde_json::from_str(&bundle).map_err(|_| ServerError::InvalidPreKeyBundle)
+    } else {
+        PreKeyBundle::try_from(bundle).map_err(|_| ServerError::InvalidPreKeyBundle)
+    }
+}
+
+fn mac(key: &[u8], parts: &[&[u8]]) -> Vec<u8> {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts keys of any length");
+    parts.iter().for_each(|part| mac.update(part));
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Checks `tag` against the MAC of `parts` in constant time.
+fn verify(key: &[u8], parts: &[&[u8]], tag: &str) -> bool {
+    let Ok(tag) = BASE64.decode(tag) else { return false };
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts keys of any length");
+    parts.iter().for_each(|part| mac.update(part));
+    mac.verify_slice(&tag).is_ok()
+}
+
+fn random_nonce() -> [u8; HANDSHAKE_NONCE_LENGTH] {
+    let mut nonce = [0u8; HANDSHAKE_NONCE_LENGTH];
+    OsRng.fill_bytes(&mut nonce);
+    nonce
+}
+
+fn decode_nonce(nonce: &str) -> Result<Vec<u8>, ServerError> {
+    match BASE64.decode(nonce)? {
+        nonce if nonce.len() == HANDSHAKE_NONCE_LENGTH => Ok(nonce),
+        _ => Err(ServerError::ReplicationError("Invalid handshake nonce".to_string())),
+    }
+}
+
+/// A replication WebSocket, authenticated once the handshake is over.
+struct Channel<S> {
+    ws: S,
+    key: Vec<u8>,
+    sent: u64,
+    received: u64,
+}
+
+impl<S> Channel<S>
+where
+    S: Stream<Item = Result<Message, tungstenite::Error>> + Sink<Message, Error = tungstenite::Error> + Unpin,
+{
+    fn new(ws: S) -> Self {
+        Self { ws, key: Vec::new(), sent: 0, received: 0 }
+    }
+
+    async fn send_handshake(&mut self, message: &Handshake) -> Result<(), ServerError> {
+        let text = serde_json::to_string(message).unwrap();
+        self.ws.send(Message::Text(Utf8Bytes::from(text))).await?;
+        Ok(())
+    }
+
+    async fn recv_handshake(&mut self) -> Result<Handshake, ServerError> {
+        let text = self.recv_text().await?;
+        serde_json::from_str(&text)
+            .map_err(|_| ServerError::ReplicationError("Invalid handshake message".to_string()))
+    }
+
+    async fn send_update(&mut self, update: &Update) -> Result<(), ServerError> {
+        let body = serde_json::to_string(update).unwrap();
+        let tag = mac(&self.key, &[&self.sent.to_be_bytes(), body.as_bytes()]);
+        let frame = Frame { body, mac: BASE64.encode(tag) };
+        self.ws.send(Message::Text(Utf8Bytes::from(serde_json::to_string(&frame).unwrap()))).await?;
+        self.sent += 1;
+        Ok(())
+    }
+
+    async fn recv_update(&mut self) -> Result<Update, ServerError> {
+        let text = self.recv_text().await?;
+        let frame: Frame = serde_json::from_str(&text)
+            .map_err(|_| ServerError::ReplicationError("Invalid replication frame".to_string()))?;
+        if !verify(&self.key, &[&self.received.to_be_bytes(), frame.body.as_bytes()], &frame.mac) {
+            return Err(ServerError::ReplicationError("Replication frame failed authentication".to_string()));
+        }
+        self.received += 1;
+        serde_json::from_str(&frame.body)
+            .map_err(|_| ServerError::ReplicationError("Invalid replication update".to_string()))
+    }
+
+    async fn recv_text(&mut self) -> Result<String, ServerError> {
+        loop {
+            match self.ws.next().await {
+                Some(Ok(Message::Text(text))) => return Ok(text.to_string()),
+                Some(Ok(Message::Close(_))) | None => {
+                    return Err(ServerError::ReplicationError("Replication channel closed".to_string()))
+                }
+                Some(Ok(_)) => continue,
+                Some(Err(e)) => return Err(e.into()),
+            }
+        }
+    }
+}
+
+/// Accepts standbys on `listener` and streams the store behind `peers` to each of them.
+pub(crate) async fn serve_replication(listener: TcpListener, secret: Vec<u8>, peers: PeerMap, log: MutationLog) {
+    while let Ok((stream, addr)) = listener.accept().await {
+        let (secret, peers, log) = (secret.clone(), peers.clone(), log.clone());
+        tokio::spawn(async move {
+            let ws = match accept_async(stream).await {
+                Ok(ws) => ws,
+                Err(e) => {
+                    error!("Replication handshake failed with {}: {}", addr, e);
+                    return;
+                }
+            };
+            info!("Standby connected: {}", addr);
+            if let Err(e) = serve_standby(ws, &secret, peers, log).await {
+                error!("Replication to {} stopped: {}", addr, e);
+            }
+        });
+    }
+}
+
+async fn serve_standby<S>(ws: S, secret: &[u8], peers: PeerMap, log: MutationLog) -> Result<(), ServerError>
+where
+    S: Stream<Item = Result<Message, tungstenite::Error>> + Sink<Message, Error = tungstenite::Error> + Unpin,
+{
+    let mut channel = Channel::new(ws);
+    let primary_nonce = random_nonce();
+    channel.send_handshake(&Handshake::Challenge { nonce: BASE64.encode(primary_nonce) }).await?;
+    let Handshake::Hello { nonce, mac: standby_mac } = channel.recv_handshake().await? else {
+        return Err(ServerError::ReplicationError("Unexpected handshake message".to_string()));
+    };
+    let standby_nonce = decode_nonce(&nonce)?;
+    if !verify(secret, &[b"standby", &primary_nonce, &standby_nonce], &standby_mac) {
+        return Err(ServerError::ReplicationError("Standby failed to authenticate".to_string()));
+    }
+    let primary_mac = mac(secret, &[b"primary", &primary_nonce, &standby_nonce]);
+    channel.send_handshake(&Handshake::Welcome { mac: BASE64.encode(primary_mac) }).await?;
+    channel.key = mac(secret, &[b"session", &primary_nonce, &standby_nonce]);
+
+    // Mutations are appended under the write lock, so every entry received from this
+    // subscription comes after the snapshot.
+    let (snapshot, mut entries) = {
+        let peers = peers.read().await;
+        let entries = log.subscribe();
+        let users = peers.iter()
+            .map(|(device, peer)| (device.username.clone(), encode_bundle(&peer.pb)))
+            .collect();
+        let devices = peers.keys().map(|device| device.device_id.clone()).collect();
+        let offline = peers.offline_queues()
+            .map(|(username, queue)| (username.clone(), queue.iter().cloned().collect()))
+            .collect();
+        (Update::Snapshot { seq: log.head(), users, devices, offline }, entries)
+    };
+    channel.send_update(&snapshot).await?;
+
+    let mut heartbeat = tokio::time::interval(HEARTBEAT_INTERVAL);
+    loop {
+        let update = tokio::select! {
+            entry = entries.recv() => match entry {
+                Ok((seq, mutation)) => Update::Entry { seq, head: log.head(), mutation },
+                Err(RecvError::Lagged(_)) => {
+                    return Err(ServerError::ReplicationError("Standby fell behind the mutation log".to_string()));
+                }
+                Err(RecvError::Closed) => return Ok(()),
+            },
+            _ = heartbeat.tick() => Update::Heartbeat { head: log.head() },
+        };
+        channel.send_update(&update).await?;
+    }
+}
+
+#[derive(Debug, Default)]
+struct Progress {
+    /// Sequence number of the last change applied to the standby store.
+    applied: u64,
+    /// Highest sequence number the primary is known to have reached.
+    head: u64,
+    synced: bool,
+    promoted: bool,
+}
+
+/// The standby side: a copy of the primary store, kept up to date while following it.
+#[derive(Clone)]
+pub(crate) struct Replica {
+    peers: PeerMap,
+    progress: Arc<Mutex<Progress>>,
+}
+
+impl Replica {
+    pub(crate) fn new() -> Self {
+        Self {
+            peers: Arc::new(RwLock::new(Peers::default())),
+            progress: Arc::new(Mutex::new(Progress::default())),
+        }
+    }
+
+    /// Number of changes made on the primary that are not applied here yet.
+    pub(crate) fn lag(&self) -> u64 {
+        let progress = self.progress.lock().unwrap();
+        progress.head - progress.applied
+    }
+
+    pub(crate) fn applied(&self) -> u64 {
+        self.progress.lock().unwrap().applied
+    }
+
+    pub(crate) fn is_promoted(&self) -> bool {
+        self.progress.lock().unwrap().promoted
+    }
+
+    /// Connects to the primary at `url` and applies its changes until the connection is lost
+    /// or the replica is promoted.
+    pub(crate) async fn follow(&self, url: &str, secret: &[u8]) -> Result<(), ServerError> {
+        let (ws, _) = connect_async(url).await?;
+        let mut channel = Channel::new(ws);
+        let Handshake::Challenge { nonce } = channel.recv_handshake().await? else {
+            return Err(ServerError::ReplicationError("Unexpected handshake message".to_string()));
+        };
+        let primary_nonce = decode_nonce(&nonce)?;
+        let standby_nonce = random_nonce();
+        let standby_mac = mac(secret, &[b"standby", &primary_nonce, &standby_nonce]);
+        channel.send_handshake(&Handshake::Hello {
+            nonce: BASE64.encode(standby_nonce),
+            mac: BASE64.encode(standby_mac),
+        }).await?;
+        let Handshake::Welcome { mac: primary_mac } = channel.recv_handshake().await? else {
+            return Err(ServerError::ReplicationError("Unexpected handshake message".to_string()));
+        };
+        if !verify(secret, &[b"primary", &primary_nonce, &standby_nonce], &primary_mac) {
+            return Err(ServerError::ReplicationError("Primary failed to authenticate".to_string()));
+        }
+        channel.key = mac(secret, &[b"session", &primary_nonce, &standby_nonce]);
+        debug!("Following primary at {}", url);
+
+        loop {
+            let update = channel.recv_update().await?;
+            if !self.apply(update).await? {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Applies `update` to the store. Returns `false` if the replica was promoted and stopped
+    /// following the primary.
+    pub(crate) async fn apply(&self, update: Update) -> Result<bool, ServerError> {
+        let mut peers = self.peers.write().await;
+        let mut progress = self.progress.lock().unwrap();
+        if progress.promoted {
+            return Ok(false);
+        }
+        match update {
+            Update::Snapshot { seq, users, devices, offline } => {
+                let mut store = Peers::default();
+                let devices = devices.into_iter().chain(std::iter::repeat(String::new()));
+                for ((username, bundle), device_id) in users.into_iter().zip(devices) {
+                    Mutation::Register { username, device_id, bundle }.apply(&mut store)?;
+                }
+                for (username, queue) in offline {
+                    for message in queue {
+                        store.enqueue(&username, message);
+                    }
+                }
+                peers.restore(store);
+                progress.applied = seq;
+                progress.head = progress.head.max(seq);
+                progress.synced = true;
+            }
+            Update::Entry { seq, head, mutation } => {
+                if !progress.synced || seq != progress.applied + 1 {
+                    // Start over from a new snapshot
+                    progress.synced = false;
+                    return Err(ServerError::ReplicationError(format!("Missing changes before {}", seq)));
+                }
+                mutation.apply(&mut peers)?;
+                progress.applied = seq;
+                progress.head = progress.head.max(head);
+            }
+            Update::Heartbeat { head } => {
+                progress.head = progress.head.max(head);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Stops following the primary and returns the store, so that it can be served to clients.
+    /// Refuses if the replica never received a snapshot or is more than `max_lag` changes behind.
+    pub(crate) async fn promote(&self, max_lag: u64) -> Result<PeerMap, ServerError> {
+        // Wait for any change being applied
+        let _peers = self.peers.write().await;
+        let mut progress = self.progress.lock().unwrap();
+        if !progress.synced {
+            return Err(ServerError::ReplicationError("Standby has not synchronised with the primary".to_string()));
+        }
+        let lag = progress.head - progress.applied;
+        if lag > max_lag {
+            return Err(ServerError::ReplicaBehind(lag));
+        }
+        progress.promoted = true;
+        info!("Standby promoted at change {}", progress.applied);
+        Ok(self.peers.clone())
+    }
+
+    /// Promotes the replica, see [`Replica::promote`], once asked to: by a `promote` line on `input`,
+    /// or by a message on `requests`. Requests refused for the lag are logged, and the next one is waited for.
+    ///
+    /// A closed `input`, such as `/dev/null` under a service manager, leaves the `requests`. With both
+    /// closed the replica is never promoted, and keeps following the primary.
+    pub(crate) async fn promote_on_request<R: AsyncBufRead + Unpin>(
+        &self,
+        max_lag: u64,
+        input: &mut Lines<R>,
+        requests: &mut mpsc::UnboundedReceiver<()>,
+    ) -> PeerMap {
+        let (mut reading, mut listening) = (true, true);
+        loop {
+            let asked = tokio::select! {
+                line = input.next_line(), if reading => match line {
+                    Ok(Some(line)) => line.trim() == "promote",
+                    _ => {
+                        info!("Standard input closed, the standby is only promoted by a signal");
+                        reading = false;
+                        false
+                    }
+                },
+                request = requests.recv(), if listening => {
+                    listening = request.is_some();
+                    listening
+                },
+                else => std::future::pending().await,
+            };
+            if !asked {
+                continue;
+            }
+            match self.promote(max_lag).await {
+                Ok(peers) => return peers,
+                Err(e) => error!("Cannot promote the standby: {}", e),
+            }
+        }
+    }
+}
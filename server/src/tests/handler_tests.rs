@@ -0,0 +1,878 @@
+use super::support::{capped_client, chat_body, connected_client, device_body, limited_client, peer_map, pinged_client, register_body, served_client, timed_client};
+use crate::utils::{Device, Peer, QueuedMessage, Server};
+use crate::capacity::Capacity;
+use crate::memory::{bundle_size, MemoryLimits, MemoryUsage, Pressure, PRESSURE_QUEUE_LIMIT};
+use crate::metrics::Metrics;
+use common::{ResponseCode, ServerInfo, ServerResponse};
+use serde_json::json;
+use std::collections::HashMap;
+use protocol::utils::{PreKeyBundle, PrivateKey, PublicKey};
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+use crate::utils::OFFLINE_QUEUE_LIMIT;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use protocol::constants::AES256_NONCE_LENGTH;
+
+#[tokio::test]
+async fn test_server_info() {
+    let peers = peer_map();
+    let started_at = Instant::now() - Duration::from_secs(120);
+    let mut registered = vec![];
+    for i in 0..7 {
+        let mut client = connected_client(peers.clone(), started_at).await;
+        let response = client.request(register_body(&format!("user{}", i))).await;
+        assert!(matches!(response.code, ResponseCode::Ok));
+        registered.push(client);
+    }
+
+    let mut client = connected_client(peers, started_at).await;
+    let response = client.request(json!({ "request_type": "server_info" })).await;
+    assert!(matches!(response.code, ResponseCode::Ok));
+
+    let info: ServerInfo = serde_json::from_str(&response.text).unwrap();
+    assert_eq!(info.version, env!("CARGO_PKG_VERSION"));
+    assert!(info.uptime_secs >= 120);
+    // Seven users are reported as ten
+    assert_eq!(info.registered_users, 10);
+    assert!(info.offline_storage);
+}
+
+#[tokio::test]
+async fn test_presence() {
+    let peers = peer_map();
+    let mut bob = connected_client(peers.clone(), Instant::now()).await;
+    bob.request(register_body("bob")).await;
+
+    let mut alice = connected_client(peers, Instant::now()).await;
+    let response = alice.request(json!({ "request_type": "presence", "who": ["Bob", "carol", "not valid"] })).await;
+    assert!(matches!(response.code, ResponseCode::Ok));
+
+    let presence: HashMap<String, bool> = serde_json::from_str(&response.text).unwrap();
+    assert_eq!(presence.len(), 3);
+    // Names are answered as they were asked
+    assert!(presence["Bob"]);
+    assert!(!presence["carol"]);
+    assert!(!presence["not valid"]);
+}
+
+#[tokio::test]
+async fn test_shutdown_closes_every_connection() {
+    let mut server = Server::new("127.0.0.1".to_string(), "0".to_string());
+    let mut alice = served_client(&mut server).await;
+    let mut bob = served_client(&mut server).await;
+    alice.request(register_body("alice")).await;
+    bob.request(register_body("bob")).await;
+    // Connected, but not registered
+    let mut carol = served_client(&mut server).await;
+    carol.request(json!({ "request_type": "server_info" })).await;
+
+    assert_eq!(server.shutdown().await, 2);
+    assert_eq!(alice.closed().await.as_deref(), Some("Server shutting down"));
+    assert_eq!(bob.closed().await.as_deref(), Some("Server shutting down"));
+    assert_eq!(carol.closed().await.as_deref(), Some("Server shutting down"));
+    assert!(server.peers.read().await.is_empty());
+    assert!(server.connections.is_empty());
+
+    // Shutting down again does nothing
+    assert_eq!(server.shutdown().await, 0);
+}
+
+#[tokio::test]
+async fn test_repeated_registration_is_replayed() {
+    let peers = peer_map();
+    let mut client = connected_client(peers.clone(), Instant::now()).await;
+    let mut body = register_body("alice");
+    body["idempotency_key"] = json!("registration-1");
+
+    let first = client.request(body.clone()).await;
+    let retry = client.request(body).await;
+    assert!(matches!(first.code, ResponseCode::Ok));
+    // Without the idempotency key the retry would conflict with our own registration
+    assert!(matches!(retry.code, ResponseCode::Ok));
+    assert_eq!(peers.read().await.len(), 1);
+}
+
+#[tokio::test]
+async fn test_repeated_bundle_fetch_consumes_one_otpk() {
+    let peers = peer_map();
+    let mut bob = connected_client(peers.clone(), Instant::now()).await;
+    let (bundle, _, _, _) = protocol::x3dh::generate_prekey_bundle_with_otpk(5);
+    let response = bob.request(json!({ "username": "bob", "bundle": bundle.to_base64() })).await;
+    assert!(matches!(response.code, ResponseCode::Ok));
+
+    let mut alice = connected_client(peers.clone(), Instant::now()).await;
+    let body = json!({ "who": "bob", "idempotency_key": "fetch-1" });
+    let first = alice.request(body.clone()).await;
+    let retry = alice.request(body).await;
+    assert_eq!(first.text, retry.text);
+    assert_eq!(peers.read().await.get(&Device::new("bob", "")).unwrap().pb.otpk.len(), 4);
+
+    // A new key is a new operation
+    alice.request(json!({ "who": "bob", "idempotency_key": "fetch-2" })).await;
+    assert_eq!(peers.read().await.get(&Device::new("bob", "")).unwrap().pb.otpk.len(), 3);
+}
+
+#[tokio::test]
+async fn test_relay_filter_blocks_closed_chat() {
+    let peers = peer_map();
+    let mut alice = connected_client(peers.clone(), Instant::now()).await;
+    let mut bob = connected_client(peers.clone(), Instant::now()).await;
+    alice.request(register_body("alice")).await;
+    bob.request(register_body("bob")).await;
+
+    let blocked = alice.request(json!({ "request_type": "relay_filter", "from": "bob", "blocked": true })).await;
+    assert!(matches!(blocked.code, ResponseCode::Ok));
+
+    // The sender is told which chat was closed
+    bob.send(chat_body("bob", "alice", "dropped")).await;
+    let refused = bob.next_frame().await;
+    let response = ServerResponse::from_json(refused["body"].to_string()).unwrap();
+    assert!(matches!(response.code, ResponseCode::Forbidden));
+    assert_eq!(response.text, "alice");
+
+    let resumed = alice.request(json!({ "request_type": "relay_filter", "from": "bob", "blocked": false })).await;
+    assert!(matches!(resumed.code, ResponseCode::Ok));
+    assert!(peers.read().await.get(&Device::new("alice", "")).unwrap().blocked.is_empty());
+
+    bob.send(chat_body("bob", "alice", "relayed")).await;
+    assert_eq!(alice.next_frame().await["text"], "relayed");
+}
+
+#[tokio::test]
+async fn test_relay_filter_requires_registration() {
+    let mut client = connected_client(peer_map(), Instant::now()).await;
+    let response = client.request(json!({ "request_type": "relay_filter", "from": "bob", "blocked": true })).await;
+    assert!(matches!(response.code, ResponseCode::BadRequest));
+}
+
+#[tokio::test]
+async fn test_replenish_requested_once_per_threshold_crossing() {
+    let peers = peer_map();
+    let mut bob = connected_client(peers.clone(), Instant::now()).await;
+    let (bundle, _, _, _) = protocol::x3dh::generate_prekey_bundle_with_otpk(7);
+    bob.request(json!({ "username": "bob", "bundle": bundle.to_base64() })).await;
+
+    // Drain the bundle, and keep fetching once it is empty
+    let mut alice = connected_client(peers.clone(), Instant::now()).await;
+    for _ in 0..9 {
+        alice.request(json!({ "who": "bob" })).await;
+    }
+    let replenish = bob.next_frame().await;
+    assert_eq!(replenish["msg_type"], "replenish_prekeys");
+    assert_eq!(replenish["count"], 20);
+
+    // The next frame is the upload response, so no further request was pushed
+    let otpk: Vec<String> = (0..5).map(|_| PublicKey::from(&PrivateKey::new()).to_base64()).collect();
+    let uploaded = bob.request(json!({ "request_type": "upload_prekeys", "otpk": otpk })).await;
+    assert!(matches!(uploaded.code, ResponseCode::Ok));
+    assert_eq!(uploaded.text, "5");
+
+    // Dropping below the threshold again asks again
+    alice.request(json!({ "who": "bob" })).await;
+    alice.request(json!({ "who": "bob" })).await;
+    assert_eq!(bob.next_frame().await["msg_type"], "replenish_prekeys");
+    let info = bob.request(json!({ "request_type": "server_info" })).await;
+    assert!(matches!(info.code, ResponseCode::Ok));
+}
+
+#[tokio::test]
+async fn test_invalid_prekey_upload_is_rejected() {
+    let peers = peer_map();
+    let mut bob = connected_client(peers.clone(), Instant::now()).await;
+    let (bundle, _, _, _) = protocol::x3dh::generate_prekey_bundle_with_otpk(2);
+    let existing = bundle.otpk[0].to_base64();
+    bob.request(json!({ "username": "bob", "bundle": bundle.to_base64() })).await;
+
+    let response = bob.request(json!({ "request_type": "upload_prekeys", "otpk": [existing] })).await;
+    assert!(matches!(response.code, ResponseCode::BadRequest));
+    assert_eq!(peers.read().await.get(&Device::new("bob", "")).unwrap().pb.otpk.len(), 2);
+}
+
+#[tokio::test]
+async fn test_signed_prekey_is_replaced() {
+    let peers = peer_map();
+    let mut bob = connected_client(peers.clone(), Instant::now()).await;
+    let (bundle, ik, _, _) = protocol::x3dh::generate_prekey_bundle_with_otpk(3);
+    bob.request(json!({ "username": "bob", "bundle": bundle.to_base64() })).await;
+
+    // A key signed by another identity is refused
+    let spk = PublicKey::from(&PrivateKey::new());
+    let mut forged = bundle.clone();
+    forged.replace_spk(&PrivateKey::new(), spk.clone(), 1_700_000_000);
+    let upload = |pb: &PreKeyBundle| json!({
+        "request_type": "upload_signed_prekey",
+        "spk": pb.spk.to_base64(),
+        "sig": pb.sig.to_base64(),
+        "created_at": pb.spk_created_at.unwrap(),
+    });
+    let response = bob.request(upload(&forged)).await;
+    assert!(matches!(response.code, ResponseCode::BadRequest));
+    assert_eq!(peers.read().await.get(&Device::new("bob", "")).unwrap().pb.spk, bundle.spk);
+
+    let mut rotated = bundle.clone();
+    rotated.replace_spk(&ik, spk.clone(), 1_700_000_000);
+    let response = bob.request(upload(&rotated)).await;
+    assert!(matches!(response.code, ResponseCode::Ok));
+
+    // Bundles are handed out with the new key, and the one-time pre-keys are kept
+    let mut alice = connected_client(peers.clone(), Instant::now()).await;
+    let response = alice.request(json!({ "who": "bob", "typed": true })).await;
+    let handed_out: PreKeyBundle = serde_json::from_str(&response.text).unwrap();
+    assert_eq!(handed_out.spk, spk);
+    assert_eq!(handed_out.spk_created_at, Some(1_700_000_000));
+    assert!(handed_out.validate().is_ok());
+    assert_eq!(peers.read().await.get(&Device::new("bob", "")).unwrap().pb.otpk.len(), 2);
+}
+
+#[tokio::test]
+async fn test_racing_registrations_have_one_winner() {
+    let peers = peer_map();
+    let mut first = connected_client(peers.clone(), Instant::now()).await;
+    let mut second = connected_client(peers.clone(), Instant::now()).await;
+
+    let (a, b) = tokio::join!(
+        first.request(register_body("alice")),
+        second.request(register_body("alice"))
+    );
+    let (mut winner, mut loser) = match (&a.code, &b.code) {
+        (ResponseCode::Ok, ResponseCode::Conflict) => (first, second),
+        (ResponseCode::Conflict, ResponseCode::Ok) => (second, first),
+        _ => panic!("Expected one Ok and one Conflict, got {} and {}", a.code, b.code),
+    };
+    assert_eq!(peers.read().await.len(), 1);
+
+    // Messages for alice reach the winner's connection
+    let mut bob = connected_client(peers.clone(), Instant::now()).await;
+    bob.request(register_body("bob")).await;
+    bob.send(chat_body("bob", "alice", "hello")).await;
+    assert_eq!(winner.next_frame().await["text"], "hello");
+
+    // The loser is not registered and can pick another name
+    let retry = loser.request(register_body("alice2")).await;
+    assert!(matches!(retry.code, ResponseCode::Ok));
+}
+
+#[tokio::test]
+async fn test_session_rejection_is_relayed() {
+    let peers = peer_map();
+    let mut alice = connected_client(peers.clone(), Instant::now()).await;
+    let mut bob = connected_client(peers.clone(), Instant::now()).await;
+    alice.request(register_body("alice")).await;
+    bob.request(register_body("bob")).await;
+
+    let mut rejection = chat_body("bob", "alice", "unknown_prekey");
+    rejection["msg_type"] = json!("session_rejected");
+    bob.send(rejection).await;
+
+    let relayed = alice.next_frame().await;
+    assert_eq!(relayed["msg_type"], "session_rejected");
+    assert_eq!(relayed["from"], "bob");
+    assert_eq!(relayed["text"], "unknown_prekey");
+}
+
+#[tokio::test]
+async fn test_messages_are_only_relayed_from_the_registered_user() {
+    let peers = peer_map();
+    let mut alice = connected_client(peers.clone(), Instant::now()).await;
+    let mut bob = connected_client(peers.clone(), Instant::now()).await;
+    alice.request(register_body("alice")).await;
+    bob.request(register_body("bob")).await;
+
+    let mut forged = chat_body("carol", "alice", "hi");
+    forged["request_id"] = json!("forged");
+    bob.send(forged.clone()).await;
+    let refusal = bob.next_frame().await;
+    assert_eq!(refusal["request_id"], "forged");
+    assert_eq!(refusal["body"]["code"], "400");
+
+    // Nor from a connection that registered no one
+    let mut anonymous = connected_client(peers.clone(), Instant::now()).await;
+    anonymous.send(forged).await;
+    assert_eq!(anonymous.next_frame().await["body"]["code"], "400");
+
+    bob.send(chat_body("bob", "alice", "from bob")).await;
+    assert_eq!(alice.next_frame().await["text"], "from bob");
+}
+
+#[tokio::test]
+async fn test_sender_is_told_whether_message_was_delivered() {
+    let peers = peer_map();
+    let mut bob = connected_client(peers.clone(), Instant::now()).await;
+    bob.request(register_body("bob")).await;
+    let mut alice = connected_client(peers.clone(), Instant::now()).await;
+    alice.request(register_body("alice")).await;
+
+    let mut message = chat_body("bob", "alice", "hi");
+    message["request_id"] = json!("delivered");
+    bob.send(message).await;
+    let ack = bob.next_frame().await;
+    assert_eq!(ack["request_id"], "delivered");
+    assert_eq!(ack["body"]["code"], "200");
+    assert_eq!(ack["body"]["message"], "Delivered");
+    // The id is of no use to the recipient
+    let relayed = alice.next_frame().await;
+    assert_eq!(relayed["text"], "hi");
+    assert!(relayed.get("request_id").is_none());
+
+    let mut message = chat_body("bob", "carol", "hi");
+    message["request_id"] = json!("queued");
+    bob.send(message).await;
+    let ack = bob.next_frame().await;
+    assert_eq!(ack["request_id"], "queued");
+    assert_eq!(ack["body"]["message"], "Queued");
+}
+
+#[tokio::test]
+async fn test_messages_to_offline_user_are_delivered_on_registration() {
+    let peers = peer_map();
+    let mut bob = connected_client(peers.clone(), Instant::now()).await;
+    bob.request(register_body("bob")).await;
+
+    // Delivered in the order they were sent, whatever their timestamps say
+    for (text, timestamp) in [("first", "2025-01-01T00:00:02+00:00"), ("second", "2025-01-01T00:00:01+00:00")] {
+        let mut message = chat_body("bob", "alice", text);
+        message["timestamp"] = json!(timestamp);
+        bob.send(message).await;
+    }
+    // Requests of a connection are handled in order, so both messages are queued by now
+    bob.request(json!({ "request_type": "server_info" })).await;
+    assert_eq!(peers.read().await.queued("alice"), 2);
+
+    let mut alice = connected_client(peers.clone(), Instant::now()).await;
+    let response = alice.request(register_body("alice")).await;
+    assert!(matches!(response.code, ResponseCode::Ok));
+    assert_eq!(alice.next_frame().await["text"], "first");
+    assert_eq!(alice.next_frame().await["text"], "second");
+    assert_eq!(peers.read().await.queued("alice"), 0);
+}
+
+#[tokio::test]
+async fn test_messages_keep_their_order_across_a_reconnect() {
+    let peers = peer_map();
+    let mut bob = connected_client(peers.clone(), Instant::now()).await;
+    bob.request(register_body("bob")).await;
+    let mut alice = connected_client(peers.clone(), Instant::now()).await;
+    alice.request(register_body("alice")).await;
+
+    bob.send(chat_body("bob", "alice", "1")).await;
+    assert_eq!(alice.next_frame().await["text"], "1");
+    alice.close().await;
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while peers.read().await.is_registered("alice") {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }).await.expect("Alice was not unregistered");
+
+    // The clock of the sender is no guide to the order it sent in
+    for (text, timestamp) in [("2", "2025-01-01T00:00:02+00:00"), ("3", "2025-01-01T00:00:01+00:00")] {
+        let mut message = chat_body("bob", "alice", text);
+        message["timestamp"] = json!(timestamp);
+        bob.send(message).await;
+    }
+    bob.request(json!({ "request_type": "server_info" })).await;
+    assert_eq!(peers.read().await.queued("alice"), 2);
+
+    // Messages sent while the queue is delivered come after it, live or queued
+    let mut alice = connected_client(peers.clone(), Instant::now()).await;
+    let live = async {
+        for text in ["4", "5", "6"] {
+            bob.send(chat_body("bob", "alice", text)).await;
+        }
+    };
+    let (response, _) = tokio::join!(alice.request(register_body("alice")), live);
+    assert!(matches!(response.code, ResponseCode::Ok));
+    for text in ["2", "3", "4", "5", "6"] {
+        assert_eq!(alice.next_frame().await["text"], text);
+    }
+}
+
+#[tokio::test]
+async fn test_offline_queue_drops_oldest_messages() {
+    let peers = peer_map();
+    let mut bob = connected_client(peers.clone(), Instant::now()).await;
+    bob.request(register_body("bob")).await;
+    for i in 0..OFFLINE_QUEUE_LIMIT + 2 {
+        let mut message = chat_body("bob", "alice", &i.to_string());
+        message["timestamp"] = json!(format!("2025-01-01T00:{:02}:{:02}+00:00", i / 60, i % 60));
+        bob.send(message).await;
+    }
+    bob.request(json!({ "request_type": "server_info" })).await;
+    assert_eq!(peers.read().await.queued("alice"), OFFLINE_QUEUE_LIMIT);
+
+    let mut alice = connected_client(peers.clone(), Instant::now()).await;
+    alice.request(register_body("alice")).await;
+    assert_eq!(alice.next_frame().await["text"], "2");
+}
+
+#[tokio::test]
+async fn test_usernames_are_normalized_in_lookups() {
+    let peers = peer_map();
+    let mut alice = connected_client(peers.clone(), Instant::now()).await;
+    let mut bob = connected_client(peers.clone(), Instant::now()).await;
+    let response = alice.request(register_body(" Alice ")).await;
+    assert!(matches!(response.code, ResponseCode::Ok));
+    assert!(peers.read().await.is_registered("alice"));
+    bob.request(register_body("bob")).await;
+
+    let response = bob.request(json!({ "who": "ALICE" })).await;
+    assert!(matches!(response.code, ResponseCode::Ok));
+
+    bob.send(chat_body("Bob", "alice ", "hello")).await;
+    let relayed = alice.next_frame().await;
+    assert_eq!(relayed["to"], "alice");
+    assert_eq!(relayed["from"], "bob");
+    assert_eq!(relayed["text"], "hello");
+
+    let mut impostor = connected_client(peers.clone(), Instant::now()).await;
+    let response = impostor.request(register_body("alice")).await;
+    assert!(matches!(response.code, ResponseCode::Conflict));
+}
+
+#[tokio::test]
+async fn test_refused_username_names_the_problem() {
+    let mut client = connected_client(peer_map(), Instant::now()).await;
+    let response = client.request(register_body("al ice")).await;
+    assert!(matches!(response.code, ResponseCode::BadRequest));
+    assert!(response.text.contains("' '"));
+
+    let response = client.request(json!({ "who": "  " })).await;
+    assert!(matches!(response.code, ResponseCode::BadRequest));
+    assert!(response.text.contains("empty"));
+
+    let response = client.request(register_body(&"a".repeat(64))).await;
+    assert!(matches!(response.code, ResponseCode::BadRequest));
+    assert!(response.text.contains("64 characters"));
+    let response = client.request(register_body("bo\u{301}b")).await;
+    assert!(matches!(response.code, ResponseCode::BadRequest));
+    assert!(response.text.contains("\\u{301}"));
+}
+
+#[tokio::test]
+async fn test_slow_request_is_answered_after_deadline() {
+    let peers = peer_map();
+    let metrics = Metrics::new();
+    let mut client = timed_client(peers.clone(), Duration::from_millis(100), metrics.clone()).await;
+    let mut bob = connected_client(peers.clone(), Instant::now()).await;
+    bob.request(register_body("bob")).await;
+    bob.send(chat_body("bob", "alice", "queued")).await;
+    bob.request(json!({ "request_type": "server_info" })).await;
+
+    // Holding the peer map stalls every handler that touches the storage
+    let storage = peers.write().await;
+    let response = client.request(register_body("alice")).await;
+    assert!(matches!(response.code, ResponseCode::InternalServerError));
+    drop(storage);
+
+    // The connection is still served, and the abandoned registration left nothing behind
+    let response = client.request(register_body("alice")).await;
+    assert!(matches!(response.code, ResponseCode::Ok));
+    assert_eq!(client.next_frame().await["text"], "queued");
+    bob.send(chat_body("bob", "alice", "live")).await;
+    assert_eq!(client.next_frame().await["text"], "live");
+    let register = metrics.latency("register");
+    assert_eq!(register.count(), 2);
+    assert!(register.mean() >= Duration::from_millis(50));
+}
+
+#[tokio::test]
+async fn test_typed_bundle_register_and_fetch() {
+    let peers = peer_map();
+    let mut bob = connected_client(peers.clone(), Instant::now()).await;
+    let (bundle, _, _, _) = protocol::x3dh::generate_prekey_bundle_with_otpk(3);
+    let response = bob.request(json!({ "username": "bob", "bundle": bundle })).await;
+    assert!(matches!(response.code, ResponseCode::Ok));
+
+    let mut alice = connected_client(peers.clone(), Instant::now()).await;
+    let response = alice.request(json!({ "who": "bob", "typed": true })).await;
+    assert!(matches!(response.code, ResponseCode::Ok));
+    let fetched: protocol::utils::PreKeyBundle = serde_json::from_str(&response.text).unwrap();
+    fetched.verify().unwrap();
+    assert_eq!(fetched.otpk.len(), 1);
+    assert!(fetched.ik == bundle.ik);
+
+    // Clients that did not ask for the typed form still get the legacy string
+    let response = alice.request(json!({ "who": "bob" })).await;
+    assert!(protocol::utils::PreKeyBundle::try_from(response.text).is_ok());
+}
+
+#[tokio::test]
+async fn test_bundle_is_handed_out_with_otpk_ids() {
+    let peers = peer_map();
+    let mut bob = connected_client(peers.clone(), Instant::now()).await;
+    let (bundle, _, _, _) = protocol::x3dh::generate_prekey_bundle_with_otpk(2);
+    bob.request(json!({ "username": "bob", "bundle": bundle })).await;
+    let upload = json!({
+        "request_type": "upload_prekeys",
+        "otpk": [PublicKey::from(&PrivateKey::new()).to_base64()],
+        "otpk_ids": [7],
+    });
+    assert!(matches!(bob.request(upload).await.code, ResponseCode::Ok));
+
+    let mut alice = connected_client(peers.clone(), Instant::now()).await;
+    for expected in [7, 1, 0] {
+        let response = alice.request(json!({ "who": "bob", "typed": true })).await;
+        let fetched: protocol::utils::PreKeyBundle = serde_json::from_str(&response.text).unwrap();
+        assert_eq!(fetched.otpk_ids, vec![expected]);
+    }
+
+    // Keys uploaded without ids are handed out without one
+    let upload = json!({ "request_type": "upload_prekeys", "otpk": [PublicKey::from(&PrivateKey::new()).to_base64()] });
+    assert!(matches!(bob.request(upload).await.code, ResponseCode::Ok));
+    let response = alice.request(json!({ "who": "bob", "typed": true })).await;
+    let fetched: protocol::utils::PreKeyBundle = serde_json::from_str(&response.text).unwrap();
+    assert_eq!(fetched.otpk.len(), 1);
+    assert!(fetched.otpk_ids.is_empty());
+}
+
+#[tokio::test]
+async fn test_bundle_is_handed_out_with_otpk_sigs() {
+    let peers = peer_map();
+    let mut bob = connected_client(peers.clone(), Instant::now()).await;
+    let (bundle, ik, _, _) = protocol::x3dh::generate_prekey_bundle_with_otpk(1);
+    bob.request(json!({ "username": "bob", "bundle": bundle })).await;
+
+    // A signature made by another identity does not verify
+    let otpk = PublicKey::from(&PrivateKey::new());
+    let forged = PreKeyBundle::sign_otpk(&PrivateKey::new(), &otpk);
+    let upload = json!({
+        "request_type": "upload_prekeys",
+        "otpk": [otpk.to_base64()],
+        "otpk_ids": [7],
+        "otpk_sigs": [forged.to_base64()],
+    });
+    assert!(matches!(bob.request(upload).await.code, ResponseCode::BadRequest));
+
+    let upload = json!({
+        "request_type": "upload_prekeys",
+        "otpk": [otpk.to_base64()],
+        "otpk_ids": [7],
+        "otpk_sigs": [PreKeyBundle::sign_otpk(&ik, &otpk).to_base64()],
+    });
+    assert!(matches!(bob.request(upload).await.code, ResponseCode::Ok));
+
+    let mut alice = connected_client(peers, Instant::now()).await;
+    for _ in 0..2 {
+        let response = alice.request(json!({ "who": "bob", "typed": true })).await;
+        let fetched: PreKeyBundle = serde_json::from_str(&response.text).unwrap();
+        assert_eq!(fetched.otpk_sigs.len(), 1);
+        assert!(fetched.validate().unwrap().is_clean());
+    }
+}
+
+#[tokio::test]
+async fn test_registrations_beyond_capacity_are_refused() {
+    let peers = peer_map();
+    let metrics = Metrics::new();
+    let capacity = Capacity::new(Some(2), None);
+    let mut clients = vec![];
+    for name in ["alice", "bob"] {
+        let mut client = capped_client(peers.clone(), capacity.clone(), metrics.clone()).await;
+        assert!(matches!(client.request(register_body(name)).await.code, ResponseCode::Ok));
+        clients.push(client);
+    }
+
+    let mut carol = capped_client(peers.clone(), capacity.clone(), metrics.clone()).await;
+    let response = carol.request(register_body("carol")).await;
+    assert!(matches!(response.code, ResponseCode::ServiceUnavailable));
+    assert!(response.text.contains("full"));
+    assert_eq!(metrics.counter("registrations_refused_full"), 1);
+    assert_eq!(peers.read().await.len(), 2);
+
+    let info = carol.request(json!({ "request_type": "server_info" })).await;
+    let info: ServerInfo = serde_json::from_str(&info.text).unwrap();
+    assert_eq!(info.max_registered_users, Some(2));
+
+    // Raising the cap takes effect on open connections
+    capacity.set_max_users(Some(3));
+    let response = carol.request(register_body("carol")).await;
+    assert!(matches!(response.code, ResponseCode::Ok));
+    assert_eq!(peers.read().await.len(), 3);
+}
+
+#[tokio::test]
+async fn test_daily_registrations_per_address_are_limited() {
+    let peers = peer_map();
+    let metrics = Metrics::new();
+    let capacity = Capacity::new(None, Some(1));
+    let mut alice = capped_client(peers.clone(), capacity.clone(), metrics.clone()).await;
+    assert!(matches!(alice.request(register_body("alice")).await.code, ResponseCode::Ok));
+
+    // Every test client connects from the loopback address
+    let mut bob = capped_client(peers.clone(), capacity, metrics.clone()).await;
+    let response = bob.request(register_body("bob")).await;
+    assert!(matches!(response.code, ResponseCode::ServiceUnavailable));
+    assert_eq!(metrics.counter("registrations_refused_quota"), 1);
+    assert_eq!(metrics.counter("registrations_refused_full"), 0);
+}
+
+#[tokio::test]
+async fn test_memory_usage_is_accounted_per_user() {
+    let peers = peer_map();
+    let (bundle, _, _) = protocol::x3dh::generate_prekey_bundle();
+    let mut store = peers.write().await;
+    let alice = Peer::detached(bundle.clone()).with_pending(store.pending_bytes());
+    alice.pending.add(50);
+    store.insert(Device::new("alice", ""), alice);
+    store.enqueue("bob", QueuedMessage { timestamp: 0, seq: 1, payload: "x".repeat(100), pending: Default::default() });
+    store.enqueue("alice", QueuedMessage { timestamp: 0, seq: 2, payload: "x".repeat(30), pending: Default::default() });
+
+    let alice = MemoryUsage { queued: 50, bundles: bundle_size(&bundle), offline: 30 };
+    let bob = MemoryUsage { queued: 0, bundles: 0, offline: 100 };
+    assert_eq!(store.memory_usage_of("alice"), alice);
+    assert_eq!(store.memory_usage_of("bob"), bob);
+    assert_eq!(store.memory_usage_of("carol"), MemoryUsage::default());
+    let usage = store.memory_usage();
+    assert_eq!(usage, alice + bob);
+
+    // Each limit applies from the byte it names
+    let total = usage.total();
+    let memory = MemoryLimits::new(Some(total + 1), Some(total + 2));
+    assert_eq!(memory.pressure(&usage), Pressure::Normal);
+    memory.set_soft_limit(Some(total));
+    assert_eq!(memory.pressure(&usage), Pressure::Soft);
+    memory.set_hard_limit(Some(total));
+    assert_eq!(memory.pressure(&usage), Pressure::Hard);
+    memory.set_soft_limit(None);
+    memory.set_hard_limit(None);
+    assert_eq!(memory.pressure(&usage), Pressure::Normal);
+
+    // The counts follow the devices and messages that go
+    store.remove(&Device::new("alice", ""));
+    store.take_queued(&Device::new("bob", ""));
+    assert_eq!(store.memory_usage(), MemoryUsage { offline: 30, ..MemoryUsage::default() });
+}
+
+#[tokio::test]
+async fn test_offline_messages_are_refused_beyond_soft_limit() {
+    let peers = peer_map();
+    let metrics = Metrics::new();
+    let memory = MemoryLimits::default();
+    let mut bob = limited_client(peers.clone(), memory.clone(), metrics.clone()).await;
+    bob.request(register_body("bob")).await;
+    for i in 0..OFFLINE_QUEUE_LIMIT {
+        bob.send(chat_body("bob", "alice", &i.to_string())).await;
+    }
+    bob.request(json!({ "request_type": "server_info" })).await;
+    assert_eq!(peers.read().await.queued("alice"), OFFLINE_QUEUE_LIMIT);
+
+    let usage = peers.read().await.memory_usage();
+    assert_eq!(usage.offline, peers.read().await.memory_usage_of("alice").offline);
+    memory.set_soft_limit(Some(usage.total()));
+    let mut message = chat_body("bob", "alice", "refused");
+    message["request_id"] = json!("refused");
+    bob.send(message).await;
+    let ack = bob.next_frame().await;
+    assert_eq!(ack["request_id"], "refused");
+    assert_eq!(ack["body"]["code"], "503");
+    assert_eq!(metrics.counter("offline_enqueues_refused_memory"), 1);
+    // The queues held are cut down to make room, keeping the newest messages
+    assert_eq!(peers.read().await.queued("alice"), PRESSURE_QUEUE_LIMIT);
+
+    // Messages to connected users are still relayed live
+    let mut carol = limited_client(peers.clone(), memory.clone(), metrics.clone()).await;
+    carol.request(register_body("carol")).await;
+    let mut message = chat_body("bob", "carol", "hi");
+    message["request_id"] = json!("delivered");
+    bob.send(message).await;
+    assert_eq!(bob.next_frame().await["body"]["message"], "Delivered");
+    assert_eq!(carol.next_frame().await["text"], "hi");
+
+    let mut alice = limited_client(peers.clone(), memory, metrics).await;
+    alice.request(register_body("alice")).await;
+    assert_eq!(alice.next_frame().await["text"], (OFFLINE_QUEUE_LIMIT - PRESSURE_QUEUE_LIMIT).to_string());
+}
+
+#[tokio::test]
+async fn test_connections_are_refused_at_hard_limit() {
+    let server = Server::new("127.0.0.1".to_string(), "0".to_string())
+        .with_memory_limits(MemoryLimits::new(None, Some(1)));
+    assert!(server.admits_connection().await);
+
+    let (bundle, _, _) = protocol::x3dh::generate_prekey_bundle();
+    server.peers.write().await.insert(Device::new("alice", ""), Peer::detached(bundle));
+    assert!(!server.admits_connection().await);
+    assert_eq!(server.metrics.counter("connections_refused_memory"), 1);
+
+    // Raising the limit takes effect on the next connection
+    server.memory.set_hard_limit(None);
+    assert!(server.admits_connection().await);
+    assert_eq!(server.metrics.counter("connections_refused_memory"), 1);
+}
+
+#[tokio::test]
+async fn test_every_device_of_a_user_receives_a_message() {
+    let peers = peer_map();
+    let identity_key = PrivateKey::new();
+    let mut devices = vec![];
+    for _ in 0..2 {
+        let mut device = connected_client(peers.clone(), Instant::now()).await;
+        let body = device_body("alice", &identity_key);
+        assert!(matches!(device.request(body.clone()).await.code, ResponseCode::Ok));
+        devices.push((device, body));
+    }
+    // Registering a device twice still conflicts
+    let mut again = connected_client(peers.clone(), Instant::now()).await;
+    assert!(matches!(again.request(devices[0].1.clone()).await.code, ResponseCode::Conflict));
+    let mut body = register_body("alice");
+    body["device_id"] = json!("phone");
+    assert!(matches!(again.request(body).await.code, ResponseCode::BadRequest));
+
+    let mut bob = connected_client(peers.clone(), Instant::now()).await;
+    bob.request(register_body("bob")).await;
+    assert_eq!(peers.read().await.len(), 3);
+    assert_eq!(peers.read().await.users(), 2);
+
+    let mut message = chat_body("bob", "alice", "hi");
+    message["request_id"] = json!("fan-out");
+    bob.send(message).await;
+    assert_eq!(bob.next_frame().await["body"]["message"], "Delivered");
+    for (device, _) in devices.iter_mut() {
+        assert_eq!(device.next_frame().await["text"], "hi");
+    }
+
+    // Either device hands out its bundle
+    let response = bob.request(json!({ "username": "alice", "typed": true })).await;
+    let bundle: PreKeyBundle = serde_json::from_str(&response.text).unwrap();
+    assert!(peers.read().await.devices("alice").any(|(_, peer)| peer.pb.ik == bundle.ik));
+}
+
+#[tokio::test]
+async fn test_client_that_stops_answering_pings_is_dropped() {
+    let peers = peer_map();
+    let metrics = Metrics::new();
+    let (interval, timeout) = (Duration::from_millis(50), Duration::from_millis(50));
+    let mut alice = pinged_client(peers.clone(), interval, timeout, metrics.clone()).await;
+    let mut bob = pinged_client(peers.clone(), interval, timeout, metrics.clone()).await;
+    alice.request(register_body("alice")).await;
+    bob.request(register_body("bob")).await;
+
+    // Alice keeps reading, and her socket answers the pings, while Bob's frames pile up unread
+    alice.idle(Duration::from_millis(400)).await;
+    assert!(peers.read().await.is_registered("alice"));
+    assert!(!peers.read().await.is_registered("bob"));
+    assert_eq!(metrics.counter("connections_timed_out"), 1);
+
+    // Messages for Bob are kept for him instead of vanishing into the dead connection
+    alice.send(chat_body("alice", "bob", "still there?")).await;
+    alice.request(json!({ "request_type": "server_info" })).await;
+    assert_eq!(peers.read().await.queued("bob"), 1);
+}
+
+#[tokio::test]
+async fn test_truncated_frames_do_not_drop_the_connection() {
+    let peers = peer_map();
+    let mut alice = connected_client(peers.clone(), Instant::now()).await;
+    alice.request(register_body("alice")).await;
+
+    let frame = [0u8; AES256_NONCE_LENGTH + 32];
+    for length in [0, 10, AES256_NONCE_LENGTH, AES256_NONCE_LENGTH + 1, frame.len()] {
+        alice.send_raw(&BASE64.encode(&frame[..length])).await;
+    }
+    alice.send_raw("not base64").await;
+
+    let response = alice.request(json!({ "request_type": "server_info" })).await;
+    assert!(matches!(response.code, ResponseCode::Ok));
+    assert!(peers.read().await.is_registered("alice"));
+}
+
+#[tokio::test]
+async fn test_device_with_another_identity_is_refused() {
+    let peers = peer_map();
+    let identity_key = PrivateKey::new();
+    let mut alice = connected_client(peers.clone(), Instant::now()).await;
+    alice.request(device_body("alice", &identity_key)).await;
+
+    // Anyone can pick a new device id, but not the identity of the user
+    let mut mallory = connected_client(peers.clone(), Instant::now()).await;
+    let response = mallory.request(device_body("alice", &PrivateKey::new())).await;
+    assert!(matches!(response.code, ResponseCode::Conflict));
+    assert_eq!(peers.read().await.devices("alice").count(), 1);
+
+    let mut bob = connected_client(peers.clone(), Instant::now()).await;
+    bob.request(register_body("bob")).await;
+    bob.send(chat_body("bob", "alice", "hi")).await;
+    assert_eq!(alice.next_frame().await["text"], "hi");
+    let response = bob.request(json!({ "username": "alice", "typed": true })).await;
+    let bundle: PreKeyBundle = serde_json::from_str(&response.text).unwrap();
+    assert!(bundle.ik.ct_eq(&PublicKey::from(&identity_key)));
+}
+
+#[tokio::test]
+async fn test_extra_devices_count_against_the_daily_quota() {
+    let peers = peer_map();
+    let metrics = Metrics::new();
+    let capacity = Capacity::new(Some(1), Some(2));
+    let identity_key = PrivateKey::new();
+    let mut devices = vec![];
+    for _ in 0..2 {
+        let mut device = capped_client(peers.clone(), capacity.clone(), metrics.clone()).await;
+        // A second device is not a second user, so the cap on users leaves it alone
+        assert!(matches!(device.request(device_body("alice", &identity_key)).await.code, ResponseCode::Ok));
+        devices.push(device);
+    }
+    let mut third = capped_client(peers.clone(), capacity, metrics.clone()).await;
+    let response = third.request(device_body("alice", &identity_key)).await;
+    assert!(matches!(response.code, ResponseCode::ServiceUnavailable));
+    assert_eq!(metrics.counter("registrations_refused_quota"), 1);
+    assert_eq!(peers.read().await.devices("alice").count(), 2);
+}
+
+#[tokio::test]
+async fn test_returning_devices_do_not_count_against_the_daily_quota() {
+    let peers = peer_map();
+    let metrics = Metrics::new();
+    let capacity = Capacity::new(None, Some(1));
+    let identity_key = PrivateKey::new();
+    let body = device_body("alice", &identity_key);
+    for _ in 0..3 {
+        let mut device = capped_client(peers.clone(), capacity.clone(), metrics.clone()).await;
+        assert!(matches!(device.request(body.clone()).await.code, ResponseCode::Ok));
+        device.close().await;
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while peers.read().await.is_registered("alice") {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        }).await.expect("Alice was not unregistered");
+    }
+
+    // The same device id under another identity is a new registration
+    let mut other = device_body("alice", &PrivateKey::new());
+    other["device_id"] = body["device_id"].clone();
+    let mut impostor = capped_client(peers.clone(), capacity, metrics.clone()).await;
+    assert!(matches!(impostor.request(other).await.code, ResponseCode::ServiceUnavailable));
+    assert_eq!(metrics.counter("registrations_refused_quota"), 1);
+}
+
+#[tokio::test]
+async fn test_offline_queue_is_delivered_to_every_device() {
+    let peers = peer_map();
+    let identity_key = PrivateKey::new();
+    let bodies = [device_body("alice", &identity_key), device_body("alice", &identity_key)];
+    for body in &bodies {
+        let mut device = connected_client(peers.clone(), Instant::now()).await;
+        device.request(body.clone()).await;
+        device.close().await;
+    }
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while peers.read().await.is_registered("alice") {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }).await.expect("Alice was not unregistered");
+
+    let mut bob = connected_client(peers.clone(), Instant::now()).await;
+    bob.request(register_body("bob")).await;
+    for text in ["1", "2"] {
+        bob.send(chat_body("bob", "alice", text)).await;
+    }
+    bob.request(json!({ "request_type": "server_info" })).await;
+
+    // The first device back takes the queue, which stays until the other one took it too
+    for (i, body) in bodies.iter().enumerate() {
+        let mut device = connected_client(peers.clone(), Instant::now()).await;
+        assert!(matches!(device.request(body.clone()).await.code, ResponseCode::Ok));
+        assert_eq!(device.next_frame().await["text"], "1");
+        assert_eq!(device.next_frame().await["text"], "2");
+        assert_eq!(peers.read().await.queued("alice"), if i == 0 { 2 } else { 0 });
+    }
+}
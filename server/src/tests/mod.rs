@@ -1 +1,5 @@
-pub mod unit_tests;
\ No newline at end of file
+pub mod unit_tests;
+pub mod handler_tests;
+pub mod request_tests;
+pub mod replication_tests;
+pub mod support;
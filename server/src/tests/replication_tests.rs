@@ -0,0 +1,139 @@
+use super::support::{chat_body, connected_client, logged_client, peer_map, register_body};
+use crate::errors::ServerError;
+use crate::replication::{serve_replication, Mutation, MutationLog, Replica, Update};
+use crate::utils::Device;
+use common::ResponseCode;
+use protocol::utils::PreKeyBundle;
+use serde_json::json;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+
+const SECRET: &[u8] = b"replication secret";
+
+/// Starts a replication listener for `peers` and returns its url.
+async fn primary(peers: crate::utils::PeerMap, log: MutationLog) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("ws://{}", listener.local_addr().unwrap());
+    tokio::spawn(serve_replication(listener, SECRET.to_vec(), peers, log));
+    url
+}
+
+async fn wait_for(replica: &Replica, seq: u64) {
+    tokio::time::timeout(Duration::from_secs(5), async {
+        while replica.applied() < seq {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }).await.expect("Standby did not catch up with the primary");
+}
+
+#[tokio::test]
+async fn test_promoted_standby_serves_bundles() {
+    let peers = peer_map();
+    let log = MutationLog::new();
+    let url = primary(peers.clone(), log.clone()).await;
+
+    // Alice registers before the standby connects, and is part of its snapshot
+    let mut alice = logged_client(peers.clone(), log.clone(), Instant::now()).await;
+    let (bundle, _, _, _) = protocol::x3dh::generate_prekey_bundle_with_otpk(3);
+    let response = alice.request(json!({ "username": "alice", "bundle": bundle.to_base64() })).await;
+    assert!(matches!(response.code, ResponseCode::Ok));
+
+    let replica = Replica::new();
+    let follower = replica.clone();
+    tokio::spawn(async move { follower.follow(&url, SECRET).await });
+    wait_for(&replica, log.head()).await;
+
+    // Bob registers and consumes one of Alice's one-time pre-keys while the standby follows
+    let mut bob = logged_client(peers.clone(), log.clone(), Instant::now()).await;
+    let response = bob.request(register_body("bob")).await;
+    assert!(matches!(response.code, ResponseCode::Ok));
+    let response = bob.request(json!({ "who": "alice" })).await;
+    assert!(matches!(response.code, ResponseCode::Ok));
+    // Messages queued for offline users move to the standby too
+    bob.send(chat_body("bob", "dave", "hello")).await;
+    bob.request(json!({ "request_type": "server_info" })).await;
+    assert_eq!(log.head(), 4);
+    wait_for(&replica, 4).await;
+    assert_eq!(replica.lag(), 0);
+
+    let standby = replica.promote(0).await.unwrap();
+    assert!(replica.is_promoted());
+    assert_eq!(standby.read().await.len(), 2);
+    assert_eq!(standby.read().await.queued("dave"), 1);
+
+    let mut carol = connected_client(standby.clone(), Instant::now()).await;
+    let response = carol.request(register_body("carol")).await;
+    assert!(matches!(response.code, ResponseCode::Ok));
+    let response = carol.request(json!({ "who": "alice" })).await;
+    assert!(matches!(response.code, ResponseCode::Ok));
+    let served = PreKeyBundle::try_from(response.text).unwrap();
+    let primary_alice = peers.read().await.get(&Device::new("alice", "")).unwrap().pb.clone();
+    // The standby hands out the key the primary would have handed out next
+    assert_eq!(served.otpk, vec![primary_alice.otpk.last().unwrap().clone()]);
+
+    // Replicated users take their username back by registering with the standby, with their own identity
+    let mut alice_again = connected_client(standby.clone(), Instant::now()).await;
+    let response = alice_again.request(register_body("alice")).await;
+    assert!(matches!(response.code, ResponseCode::Conflict));
+    let response = alice_again.request(json!({ "username": "alice", "bundle": bundle.to_base64() })).await;
+    assert!(matches!(response.code, ResponseCode::Ok));
+    let response = alice_again.request(register_body("carol")).await;
+    assert!(matches!(response.code, ResponseCode::Conflict));
+}
+
+#[tokio::test]
+async fn test_standby_with_wrong_secret_is_refused() {
+    let url = primary(peer_map(), MutationLog::new()).await;
+    let replica = Replica::new();
+    let result = replica.follow(&url, b"wrong secret").await;
+    assert!(result.is_err());
+    assert!(matches!(replica.promote(16).await, Err(ServerError::ReplicationError(_))));
+}
+
+#[tokio::test]
+async fn test_promotion_refused_when_behind() {
+    let replica = Replica::new();
+    replica.apply(Update::Snapshot { seq: 5, users: vec![], devices: vec![], offline: vec![] }).await.unwrap();
+    let bundle = register_body("alice")["bundle"].as_str().unwrap().to_string();
+    let mutation = Mutation::Register { username: "alice".to_string(), device_id: String::new(), bundle };
+    replica.apply(Update::Entry { seq: 6, head: 6, mutation: mutation.clone() }).await.unwrap();
+
+    // The primary reached change 15 before it died
+    replica.apply(Update::Heartbeat { head: 15 }).await.unwrap();
+    assert_eq!(replica.lag(), 9);
+    assert!(matches!(replica.promote(4).await, Err(ServerError::ReplicaBehind(9))));
+    assert!(!replica.is_promoted());
+    assert!(replica.promote(9).await.is_ok());
+
+    // A change that does not follow the last applied one needs a new snapshot before promotion
+    let replica = Replica::new();
+    replica.apply(Update::Snapshot { seq: 5, users: vec![], devices: vec![], offline: vec![] }).await.unwrap();
+    assert!(replica.apply(Update::Entry { seq: 7, head: 7, mutation }).await.is_err());
+    assert!(matches!(replica.promote(16).await, Err(ServerError::ReplicationError(_))));
+}
+
+#[tokio::test]
+async fn test_promotion_on_request() {
+    let replica = Replica::new();
+    replica.apply(Update::Snapshot { seq: 5, users: vec![], devices: vec![], offline: vec![] }).await.unwrap();
+    let (tx, mut requests) = mpsc::unbounded_channel();
+
+    // Other lines are ignored, and `promote` promotes
+    let mut input = BufReader::new(&b"memory\npromote\n"[..]).lines();
+    replica.promote_on_request(0, &mut input, &mut requests).await;
+    assert!(replica.is_promoted());
+
+    // A closed input, as under a service manager, waits for a request instead of giving up
+    let replica = Replica::new();
+    replica.apply(Update::Snapshot { seq: 5, users: vec![], devices: vec![], offline: vec![] }).await.unwrap();
+    let mut input = BufReader::new(&b""[..]).lines();
+    let promoted = tokio::time::timeout(Duration::from_millis(100), replica.promote_on_request(0, &mut input, &mut requests)).await;
+    assert!(promoted.is_err());
+    assert!(!replica.is_promoted());
+
+    tx.send(()).unwrap();
+    tokio::time::timeout(Duration::from_secs(5), replica.promote_on_request(0, &mut input, &mut requests)).await.unwrap();
+    assert!(replica.is_promoted());
+}
@@ -0,0 +1,120 @@
+use crate::utils::{decrypt_client_request, RequestType, MAX_REQUEST_ID_LENGTH};
+use protocol::utils::{AssociatedData, DecryptionKey, EncryptionKey, PrivateKey, PublicKey, SharedSecret};
+use serde_json::{json, Value};
+use uuid::Uuid;
+
+/// Encrypts `frame` the way a client does and decrypts it as the server would.
+fn decrypt(frame: Value, strict: bool) -> Option<(RequestType, String)> {
+    let secret = SharedSecret::from([1u8; 32]);
+    let aad = AssociatedData::new(PublicKey::from(&PrivateKey::new()), PublicKey::from(&PrivateKey::new()));
+    let enc = EncryptionKey::from(secret.clone()).encrypt(frame.to_string().as_bytes(), &aad.to_bytes()).unwrap();
+    decrypt_client_request(&enc, &DecryptionKey::from(secret), &aad, strict).ok()
+}
+
+fn wrapped(request_id: &str, body: Value) -> Value {
+    json!({ "request_id": request_id, "body": body })
+}
+
+#[test]
+fn test_uuid_request_id_is_accepted() {
+    let id = Uuid::new_v4().to_string();
+    for strict in [false, true] {
+        let (request, request_id) = decrypt(wrapped(&id, json!({ "request_type": "server_info" })), strict).unwrap();
+        assert!(matches!(request, RequestType::ServerInfo(_)));
+        assert_eq!(request_id, id);
+    }
+}
+
+#[test]
+fn test_oversized_request_id_is_refused() {
+    let id = "a".repeat(MAX_REQUEST_ID_LENGTH + 1);
+    let body = json!({ "request_type": "server_info" });
+    assert!(decrypt(wrapped(&id, body.clone()), false).is_none());
+    assert!(decrypt(wrapped(&id, body), true).is_none());
+}
+
+#[test]
+fn test_request_id_must_be_a_uuid_when_strict() {
+    let body = json!({ "request_type": "server_info" });
+    assert!(decrypt(wrapped("request-1", body.clone()), false).is_some());
+    assert!(decrypt(wrapped("request-1", body), true).is_none());
+}
+
+#[test]
+fn test_nested_wrapper_is_refused() {
+    let id = Uuid::new_v4().to_string();
+    let inner = wrapped(&Uuid::new_v4().to_string(), json!({ "request_type": "server_info" }));
+    // Also when the outer body would otherwise be a valid request
+    let mut smuggled = inner.clone();
+    smuggled["request_type"] = json!("rekey");
+    for strict in [false, true] {
+        assert!(decrypt(wrapped(&id, inner.clone()), strict).is_none());
+        assert!(decrypt(wrapped(&id, smuggled.clone()), strict).is_none());
+    }
+}
+
+#[test]
+fn test_unknown_fields_are_refused_when_strict() {
+    let id = Uuid::new_v4().to_string();
+    let body = json!({ "request_type": "presence", "who": ["bob"], "note": "hi" });
+    assert!(matches!(decrypt(wrapped(&id, body.clone()), false), Some((RequestType::GetPresence(_), _))));
+    assert!(decrypt(wrapped(&id, body), true).is_none());
+
+    let mut frame = wrapped(&id, json!({ "request_type": "server_info" }));
+    frame["extra"] = json!(1);
+    assert!(decrypt(frame.clone(), false).is_some());
+    assert!(decrypt(frame, true).is_none());
+
+    let message = json!({
+        "msg_type": "chat",
+        "from": "alice",
+        "to": "bob",
+        "text": "hi",
+        "timestamp": "2025-01-01T00:00:00+00:00",
+        "extra": true,
+    });
+    assert!(matches!(decrypt(message.clone(), false), Some((RequestType::SendMessage(_), _))));
+    assert!(decrypt(message, true).is_none());
+}
+
+#[test]
+fn test_optional_fields_are_known_when_strict() {
+    let id = Uuid::new_v4().to_string();
+    let body = json!({ "who": "bob", "typed": true, "idempotency_key": "fetch-1" });
+    assert!(matches!(decrypt(wrapped(&id, body), true), Some((RequestType::GetPrekeyBundle(_), _))));
+    let body = json!({ "who": "bob" });
+    assert!(matches!(decrypt(wrapped(&id, body), true), Some((RequestType::GetPrekeyBundle(_), _))));
+}
+
+#[test]
+fn test_legacy_field_names_are_accepted() {
+    let id = Uuid::new_v4().to_string();
+    let message = |msg_type: &str| {
+        let mut message = json!({ "from": "alice", "to": "bob", "text": "hi", "timestamp": "2025-01-01T00:00:00+00:00" });
+        message[msg_type] = json!("chat");
+        message
+    };
+    let cases = [
+        (json!({ "action": "server_info" }), "server_info", vec![("action", "request_type")]),
+        (json!({ "action": "rekey" }), "rekey", vec![("action", "request_type")]),
+        (json!({ "action": "relay_filter", "from": "bob", "blocked": true }), "relay_filter", vec![("action", "request_type")]),
+        (json!({ "action": "presence", "who": ["bob"] }), "presence", vec![("action", "request_type"), ("who", "usernames")]),
+        (json!({ "request_type": "presence", "usernames": ["bob"] }), "presence", vec![]),
+        (json!({ "who": "bob" }), "get_prekey_bundle", vec![("who", "username")]),
+        (json!({ "user": "bob" }), "get_prekey_bundle", vec![("user", "username")]),
+        (json!({ "action": "get_prekey_bundle", "username": "bob" }), "get_prekey_bundle", vec![("action", "request_type")]),
+        (json!({ "username": "bob" }), "get_prekey_bundle", vec![]),
+    ];
+    for strict in [false, true] {
+        for (body, name, legacy) in &cases {
+            let (request, _) = decrypt(wrapped(&id, body.clone()), strict)
+                .unwrap_or_else(|| panic!("{} was refused", body));
+            assert_eq!(request.name(), *name);
+            assert_eq!(request.legacy_field_names(body), *legacy);
+        }
+        let (request, _) = decrypt(message("type"), strict).unwrap();
+        assert!(matches!(request, RequestType::SendMessage(_)));
+        assert_eq!(request.legacy_field_names(&message("type")), vec![("type", "msg_type")]);
+        assert!(request.legacy_field_names(&message("msg_type")).is_empty());
+    }
+}
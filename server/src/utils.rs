@@ -1,10 +1,22 @@
+use crate::capacity::{Capacity, Refusal};
+use crate::memory::{bundle_size, MemoryLimits, MemoryUsage, PendingBytes, Pressure, PRESSURE_QUEUE_LIMIT};
 use crate::errors::ServerError;
-use common::{GetPreKeyBundleRequest, RegisterRequest, RequestWrapper, ResponseCode, ResponseWrapper, SendMessageRequest, ServerResponse, CONFIG};
+use crate::metrics::Metrics;
+use crate::replication::{encode_bundle, serve_replication, Mutation, MutationLog};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use common::{normalize_username, validate_username, UsernameError, GetPreKeyBundleRequest, LegacyFieldNames, GetPresenceRequest, WireBundle, RegisterRequest, RekeyRequest, RelayFilterRequest, ReplenishPreKeysMessage, ServerInfo, ServerInfoRequest, RequestWrapper, ResponseCode, ResponseWrapper, SendMessageRequest, ServerResponse, UploadPreKeysRequest, UploadSignedPreKeyRequest, CONFIG};
 use log::{debug, error, info, warn};
-use protocol::utils::{DecryptionKey, PreKeyBundle, PrivateKey, SessionKeys};
-use std::collections::HashMap;
+use protocol::utils::{AssociatedData, DecryptionKey, OneTimePreKey, PreKeyBundle, PrivateKey, PublicKey, SessionKeys, Signature};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::fmt::Display;
+use std::ops::Deref;
+use chrono::{DateTime, Utc};
 use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
 use futures_util::stream::{SplitSink, SplitStream};
+use futures_util::future::join_all;
 use futures_util::{SinkExt, StreamExt};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
@@ -12,71 +24,477 @@ use tokio::net::{TcpListener, TcpStream};
 use tokio::sync::{mpsc, Mutex, RwLock};
 
 use tokio::task::JoinHandle;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::protocol::CloseFrame;
 use tokio_tungstenite::tungstenite::{Message, Utf8Bytes};
 use tokio_tungstenite::{accept_async, WebSocketStream};
 use protocol::x3dh::process_prekey_bundle;
+use uuid::Uuid;
 
 pub(crate) type Tx = mpsc::UnboundedSender<Message>;
 pub(crate) type Rx = mpsc::UnboundedReceiver<Message>;
-pub(crate) type PeerMap = Arc<RwLock<HashMap<String, Peer>>>;
+pub(crate) type PeerMap = Arc<RwLock<Peers>>;
 pub(crate) type Session = Arc<RwLock<SessionKeys>>;
 type SharedSink = Arc<Mutex<SplitSink<WebSocketStream<TcpStream>, Message>>>;
 
+/// Number of completed idempotent requests remembered per session.
+const IDEMPOTENCY_CACHE_SIZE: usize = 16;
+
+/// A peer is asked for more one-time pre-keys when fewer than this are left.
+const OTPK_REPLENISH_THRESHOLD: usize = 5;
+
+/// Number of one-time pre-keys a peer is asked to upload.
+const OTPK_REPLENISH_BATCH: usize = 20;
+
+/// Time a request may take before it is answered with an error, unless configured otherwise.
+const DEFAULT_REQUEST_DEADLINE: Duration = Duration::from_secs(5);
+/// Time the connections get to close on their own when the server shuts down, before they are aborted.
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(2);
+/// Time between the pings sent to a client, unless configured otherwise.
+const DEFAULT_PING_INTERVAL: Duration = Duration::from_secs(30);
+/// Time a client gets past a ping interval to answer with a pong, unless configured otherwise.
+const DEFAULT_PONG_TIMEOUT: Duration = Duration::from_secs(10);
+/// Longest request id accepted, since it is echoed back in the response. UUIDs are 36 characters.
+pub(crate) const MAX_REQUEST_ID_LENGTH: usize = 64;
+
+/// Messages kept for a user while they are offline. The oldest are dropped beyond this.
+pub(crate) const OFFLINE_QUEUE_LIMIT: usize = 100;
+
+/// A device of a user, keying the [`Peers`]. Each device of a user has its own connection and bundle.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub(crate) struct Device {
+    pub(crate) username: String,
+    /// The UUID the client sent at registration, empty for clients that predate devices.
+    pub(crate) device_id: String,
+}
+
+impl Device {
+    pub(crate) fn new(username: &str, device_id: &str) -> Self {
+        Self { username: username.to_string(), device_id: device_id.to_string() }
+    }
+}
+
+impl Display for Device {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.device_id.is_empty() {
+            write!(f, "{}", self.username)
+        } else {
+            write!(f, "{} ({})", self.username, self.device_id)
+        }
+    }
+}
+
+/// The connected devices of the users, and the messages waiting for the users that have none.
+///
+/// A message is relayed to every device of its recipient, see [`Peers::relay`]. Messages queued
+/// while a user is offline are delivered to each of the devices the user had registered, as they
+/// register again, see [`Peers::take_queued`].
+///
+/// Messages from one sender to one recipient are delivered in the order the relay received them:
+/// each is numbered on arrival (see [`Peers::next_sequence`]), queues are delivered in that order,
+/// and a device is handed its queue in the same write of the map that registers it, so nothing can be
+/// relayed to it live before.
+///
+/// The bytes held are counted as devices and messages come and go, see [`Peers::memory_usage`].
+///
+/// Dereferences to the map of connected devices, ordered so that the devices of a user are next to each other.
+#[derive(Debug, Default)]
+pub(crate) struct Peers {
+    connected: BTreeMap<Device, Peer>,
+    offline: HashMap<String, VecDeque<QueuedMessage>>,
+    /// Bytes of the stored bundles.
+    bundle_bytes: usize,
+    /// Bytes of the messages in the offline queues.
+    offline_bytes: usize,
+    /// Bytes relayed to the connections and not written yet, counted by their [`PendingBytes`].
+    queued_bytes: Arc<AtomicUsize>,
+    /// The device ids each user registered, kept after they disconnect to deliver their offline queues.
+    known_devices: HashMap<String, BTreeSet<String>>,
+    /// The identity key each user last registered a device with.
+    known_identities: HashMap<String, PublicKey>,
+    /// Sequence number given to the next relayed message.
+    next_seq: AtomicU64,
+}
+
+impl Peers {
+    /// Numbers a message received by the relay. Requests of a connection are handled one at a time,
+    /// so the messages of a sender are numbered in the order they were sent.
+    pub(crate) fn next_sequence(&self) -> u64 {
+        self.next_seq.fetch_add(1, Ordering::SeqCst) + 1
+    }
+
+    /// Queues `message` for `username`, dropping the oldest queued message if the queue is full.
+    pub(crate) fn enqueue(&mut self, username: &str, message: QueuedMessage) {
+        // Queues restored from a replica carry numbers this store has not given yet
+        self.next_seq.fetch_max(message.seq, Ordering::SeqCst);
+        let queue = self.offline.entry(username.to_string()).or_default();
+        if queue.len() == OFFLINE_QUEUE_LIMIT {
+            if let Some(dropped) = queue.pop_front() {
+                self.offline_bytes -= dropped.payload.len();
+            }
+            warn!("Offline queue of {} is full, dropped its oldest message", username);
+        }
+        self.offline_bytes += message.payload.len();
+        queue.push_back(message);
+    }
+
+    /// Returns the messages queued for `device` in the order they were received, messages queued
+    /// before they were numbered first and in timestamp order.
+    ///
+    /// A message stays queued until every device it was queued for took it. Messages queued without
+    /// devices, by older servers, go to the first device that takes them.
+    pub(crate) fn take_queued(&mut self, device: &Device) -> Vec<QueuedMessage> {
+        let Some(queue) = self.offline.get_mut(&device.username) else { return vec![] };
+        let mut queued = vec![];
+        let mut taken = 0;
+        queue.retain_mut(|message| {
+            if !message.pending.is_empty() && !message.pending.remove(&device.device_id) {
+                return true;
+            }
+            queued.push(message.clone());
+            if message.pending.is_empty() {
+                taken += message.payload.len();
+            }
+            !message.pending.is_empty()
+        });
+        self.offline_bytes -= taken;
+        if queue.is_empty() {
+            self.offline.remove(&device.username);
+        }
+        queued.sort_by_key(|message| (message.seq, message.timestamp));
+        queued
+    }
+
+    pub(crate) fn queued(&self, username: &str) -> usize {
+        self.offline.get(username).map_or(0, VecDeque::len)
+    }
+
+    /// Records that `device` registered with the identity key `ik`, so that messages queued for its user wait for it too.
+    pub(crate) fn remember_device(&mut self, device: &Device, ik: &PublicKey) {
+        self.known_devices.entry(device.username.clone()).or_default().insert(device.device_id.clone());
+        self.known_identities.insert(device.username.clone(), ik.clone());
+    }
+
+    /// Whether `device` registered before with the identity key `ik`, and is only coming back.
+    pub(crate) fn is_returning(&self, device: &Device, ik: &PublicKey) -> bool {
+        self.known_devices.get(&device.username).is_some_and(|ids| ids.contains(&device.device_id))
+            && self.known_identities.get(&device.username).is_some_and(|known| known.ct_eq(ik))
+    }
+
+    /// The device ids `username` registered, which messages queued for it are delivered to.
+    pub(crate) fn known_devices(&self, username: &str) -> BTreeSet<String> {
+        self.known_devices.get(username).cloned().unwrap_or_default()
+    }
+
+    /// The devices of `username`, connected or known from a replicated store.
+    pub(crate) fn devices<'a>(&'a self, username: &'a str) -> impl Iterator<Item = (&'a Device, &'a Peer)> + 'a {
+        self.connected.range(Device::new(username, "")..).take_while(move |(device, _)| device.username == username)
+    }
+
+    /// Whether `username` has a device, connected or not.
+    pub(crate) fn is_registered(&self, username: &str) -> bool {
+        self.devices(username).next().is_some()
+    }
+
+    /// The number of registered users, whatever their number of devices.
+    pub(crate) fn users(&self) -> usize {
+        let mut users = self.connected.keys().map(|device| &device.username).collect::<Vec<_>>();
+        users.dedup();
+        users.len()
+    }
+
+    /// Relays `text` from `from` to every device of `to` that takes it live, returning how many did.
+    ///
+    /// Devices whose connection is gone are skipped.
+    ///
+    /// # Errors
+    ///
+    /// * [`ServerError::RelayBlocked`] - Returned if every device of `to` closed the chat with `from`.
+    pub(crate) fn relay(&self, from: &str, to: &str, text: &str) -> Result<usize, ServerError> {
+        let devices = self.devices(to).map(|(_, peer)| peer).collect::<Vec<_>>();
+        if !devices.is_empty() && devices.iter().all(|peer| peer.blocked.contains(from)) {
+            return Err(ServerError::RelayBlocked);
+        }
+        Ok(devices.into_iter()
+            .filter(|peer| !peer.blocked.contains(from))
+            .filter(|peer| peer.relay(text.to_string()))
+            .count())
+    }
+
+    /// Drops the oldest messages of every offline queue longer than `limit`, returning how many were dropped.
+    pub(crate) fn shrink_queues(&mut self, limit: usize) -> usize {
+        let mut dropped = 0;
+        for queue in self.offline.values_mut() {
+            let excess = queue.len().saturating_sub(limit);
+            self.offline_bytes -= queue.drain(..excess).map(|message| message.payload.len()).sum::<usize>();
+            dropped += excess;
+        }
+        dropped
+    }
+
+    /// Stores `peer` as the device `device`, returning the peer it replaces.
+    pub(crate) fn insert(&mut self, device: Device, peer: Peer) -> Option<Peer> {
+        self.bundle_bytes += bundle_size(&peer.pb);
+        let replaced = self.connected.insert(device, peer);
+        if let Some(replaced) = &replaced {
+            self.forget(replaced);
+        }
+        replaced
+    }
+
+    /// Removes the device `device`, returning its peer.
+    pub(crate) fn remove(&mut self, device: &Device) -> Option<Peer> {
+        let removed = self.connected.remove(device);
+        if let Some(removed) = &removed {
+            self.forget(removed);
+        }
+        removed
+    }
+
+    /// Removes every device, leaving the offline queues.
+    pub(crate) fn clear(&mut self) {
+        for peer in std::mem::take(&mut self.connected).values() {
+            self.forget(peer);
+        }
+    }
+
+    /// Applies `change` to the peer of `device`, returning its result, or `None` if there is no such device.
+    pub(crate) fn update<R>(&mut self, device: &Device, change: impl FnOnce(&mut Peer) -> R) -> Option<R> {
+        let peer = self.connected.get_mut(device)?;
+        let before = bundle_size(&peer.pb);
+        let result = change(peer);
+        self.bundle_bytes = self.bundle_bytes - before + bundle_size(&peer.pb);
+        Some(result)
+    }
+
+    /// Replaces the whole store with `store`, such as a snapshot of a primary, uncounting the
+    /// bytes the connections of the devices it replaces have yet to write.
+    pub(crate) fn restore(&mut self, store: Peers) {
+        self.clear();
+        let queued_bytes = self.queued_bytes.clone();
+        *self = Peers { queued_bytes, ..store };
+    }
+
+    /// A counter for the bytes relayed to a new connection, adding to those of the server.
+    pub(crate) fn pending_bytes(&self) -> PendingBytes {
+        PendingBytes::new(self.queued_bytes.clone())
+    }
+
+    /// Uncounts the bytes held for a peer that was removed.
+    fn forget(&mut self, peer: &Peer) {
+        self.bundle_bytes -= bundle_size(&peer.pb);
+        peer.pending.release();
+    }
+
+    /// The bytes held for all the users, connected or not, as counted while they were stored.
+    pub(crate) fn memory_usage(&self) -> MemoryUsage {
+        MemoryUsage {
+            queued: self.queued_bytes.load(Ordering::SeqCst),
+            bundles: self.bundle_bytes,
+            offline: self.offline_bytes,
+        }
+    }
+
+    /// The bytes held for `username`: messages its connections have yet to write, its bundles and its offline queue.
+    pub(crate) fn memory_usage_of(&self, username: &str) -> MemoryUsage {
+        MemoryUsage {
+            queued: self.devices(username).map(|(_, peer)| peer.pending.get()).sum(),
+            bundles: self.devices(username).map(|(_, peer)| bundle_size(&peer.pb)).sum(),
+            offline: self.offline.get(username).map_or(0, |queue| queue.iter().map(|message| message.payload.len()).sum()),
+        }
+    }
+
+    /// Iterates over the offline queues, for snapshots of the store.
+    pub(crate) fn offline_queues(&self) -> impl Iterator<Item = (&String, &VecDeque<QueuedMessage>)> {
+        self.offline.iter()
+    }
+}
+
+impl Deref for Peers {
+    type Target = BTreeMap<Device, Peer>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.connected
+    }
+}
+
+/// A relayed message waiting for its recipient to connect.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub(crate) struct QueuedMessage {
+    /// Timestamp of the message in milliseconds, as set by the sender.
+    pub(crate) timestamp: i64,
+    /// Sequence number given by the relay on arrival, 0 for messages queued by older servers.
+    #[serde(default)]
+    pub(crate) seq: u64,
+    /// The serialized [`SendMessageRequest`], its text still end-to-end encrypted.
+    pub(crate) payload: String,
+    /// The device ids of the recipient that have yet to take the message, empty if the first to take it will do.
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub(crate) pending: BTreeSet<String>,
+}
+
+impl QueuedMessage {
+    fn new(request: &SendMessageRequest, seq: u64, pending: BTreeSet<String>) -> Self {
+        let timestamp = DateTime::parse_from_rfc3339(&request.timestamp)
+            .map(|t| t.timestamp_millis())
+            .unwrap_or_else(|_| Utc::now().timestamp_millis());
+        Self { timestamp, seq, payload: serde_json::to_string(request).unwrap(), pending }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub(crate) struct Peer {
     pub(crate) sender: Tx,
     pub(crate) pb: PreKeyBundle,
+    /// Users whose messages are not relayed to this peer, because it closed the chat with them.
+    pub(crate) blocked: HashSet<String>,
+    /// Bytes relayed to the connection of the peer and not written to its socket yet.
+    pub(crate) pending: PendingBytes,
 }
 
 impl Peer {
     pub(crate) fn new(sender: Tx, pb: PreKeyBundle) -> Self {
-        Self { sender, pb }
+        Self { sender, pb, blocked: HashSet::new(), pending: PendingBytes::default() }
+    }
+
+    /// Counts the bytes relayed to the peer in `pending`, shared with the connection that writes them.
+    pub(crate) fn with_pending(mut self, pending: PendingBytes) -> Self {
+        self.pending = pending;
+        self
+    }
+
+    /// Hands `text` to the connection of the peer, returning `false` if it is gone.
+    pub(crate) fn relay(&self, text: String) -> bool {
+        // Counted first, so the connection cannot write the message before it is counted
+        let length = text.len();
+        self.pending.add(length);
+        if self.sender.send(Message::Text(Utf8Bytes::from(text))).is_err() {
+            self.pending.sub(length);
+            return false;
+        }
+        true
+    }
+
+    /// A peer known from a replicated store, without a connection to this server.
+    pub(crate) fn detached(pb: PreKeyBundle) -> Self {
+        let (sender, _) = mpsc::unbounded_channel();
+        Self::new(sender, pb)
     }
 
     pub(crate) fn get_bundle(&mut self) -> PreKeyBundle {
         let mut old_bundle = self.pb.clone();
 
         // We need at least one key in 'otpk' to split
-        let last_key = old_bundle.otpk.pop();
+        let last_key = old_bundle.pop_otpk();
 
-        // Build a new PreKeyBundle that just contains the last key in its 'otpk'
-        let new_bundle_with_last = PreKeyBundle {
+        // Build a new PreKeyBundle that just contains the last key in its 'otpk', with its id and signature if it has them
+        let mut new_bundle_with_last = PreKeyBundle {
             verifying_key: old_bundle.verifying_key.clone(),
             ik: old_bundle.ik.clone(),
             spk: old_bundle.spk.clone(),
             sig: old_bundle.sig.clone(),
-            otpk: if last_key.is_some() {
-                vec![last_key.unwrap()]
-            } else {
-                vec![]
-            },
+            spk_created_at: old_bundle.spk_created_at,
+            otpk: vec![],
+            otpk_ids: vec![],
+            otpk_sigs: vec![],
+            aead_suite: old_bundle.aead_suite,
+            kem_prekey: old_bundle.kem_prekey.clone(),
+            json_initial_message: old_bundle.json_initial_message,
         };
+        if let Some(otpk) = last_key {
+            new_bundle_with_last.push_otpk(otpk);
+        }
 
         // Now update the *peer's* bundle (remove last key from its 'otpk').
         // old_bundle no longer has the last key, because we popped it above.
         self.pb = old_bundle;
+
+        // Ask only once, when the count drops below the threshold
+        if self.pb.otpk.len() + 1 == OTPK_REPLENISH_THRESHOLD && !new_bundle_with_last.otpk.is_empty() {
+            self.request_replenish();
+        }
         new_bundle_with_last
     }
+
+    fn request_replenish(&self) {
+        let message = ReplenishPreKeysMessage {
+            msg_type: "replenish_prekeys".to_string(),
+            count: OTPK_REPLENISH_BATCH,
+        };
+        let serialized = serde_json::to_string(&message).unwrap();
+        if self.sender.send(Message::Text(Utf8Bytes::from(serialized))).is_err() {
+            error!("Failed to ask peer for one-time pre-keys");
+        }
+    }
 }
 
 pub(crate) struct Server {
     pub(crate) addr: String,
     pub(crate) port: String,
     pub(crate) peers: PeerMap,
-    pub(crate) connections: Vec<JoinHandle<()>>,
+    pub(crate) log: MutationLog,
+    pub(crate) metrics: Metrics,
+    pub(crate) capacity: Capacity,
+    pub(crate) memory: MemoryLimits,
+    /// The accepted connections, each with the sender of the messages written to its client.
+    pub(crate) connections: Vec<(Tx, JoinHandle<()>)>,
+    pub(crate) started_at: Instant,
+    /// Set by [`Server::shutdown`], after which no connection is accepted.
+    pub(crate) stopped: bool,
 }
 
 impl Server {
     pub(crate) fn new(addr: String, port: String) -> Self {
+        Self::with_peers(addr, port, Arc::new(RwLock::new(Peers::default())))
+    }
+
+    /// A server for an existing user store, such as the one of a promoted standby.
+    pub(crate) fn with_peers(addr: String, port: String, peers: PeerMap) -> Self {
         Self {
             addr,
             port,
-            peers: Arc::new(RwLock::new(HashMap::new())),
+            peers,
+            log: MutationLog::new(),
+            metrics: Metrics::new(),
+            capacity: Capacity::default(),
+            memory: MemoryLimits::default(),
             connections: Vec::new(),
+            started_at: Instant::now(),
+            stopped: false,
         }
     }
 
+    /// Limits the registrations of every connection to `capacity`.
+    pub(crate) fn with_capacity(mut self, capacity: Capacity) -> Self {
+        self.capacity = capacity;
+        self
+    }
+
+    /// Pushes back on every connection once the server holds more than `memory` allows.
+    pub(crate) fn with_memory_limits(mut self, memory: MemoryLimits) -> Self {
+        self.memory = memory;
+        self
+    }
+
+    /// Whether a new connection may be accepted, which it may not once the hard memory limit is reached.
+    pub(crate) async fn admits_connection(&self) -> bool {
+        let usage = self.peers.read().await.memory_usage();
+        if self.memory.pressure(&usage) == Pressure::Hard {
+            self.metrics.increment("connections_refused_memory");
+            return false;
+        }
+        true
+    }
+
     pub(crate) async fn listen(&mut self) {
+        if self.stopped {
+            return;
+        }
+        if let (Some(port), Some(secret)) = (CONFIG.get_replication_port(), CONFIG.get_replication_secret()) {
+            let listener = TcpListener::bind(format!("{}:{}", &self.addr, port)).await.unwrap();
+            info!("Accepting standbys on port {}", port);
+            tokio::spawn(serve_replication(listener, secret.into_bytes(), self.peers.clone(), self.log.clone()));
+        }
         let listener = TcpListener::bind(format!("{}:{}", &self.addr, &self.port)).await.unwrap();
         while let Ok((stream, _)) = listener.accept().await {
             let peers = self.peers.clone();
@@ -84,6 +502,11 @@ impl Server {
                 Ok(addr) => addr.to_string(),
                 Err(_) => "Unknown".to_string(),
             };
+            // Dropping the stream closes it before the WebSocket handshake
+            if !self.admits_connection().await {
+                warn!("Refused the connection of {}, the server holds too much memory", &addr);
+                continue;
+            }
 
             info!("Incoming WebSocket connection: {}", &addr);
 
@@ -96,69 +519,156 @@ impl Server {
             };
             let mut new_connection = Connection::new(
                 peers,
-                addr
-            );
+                self.log.clone(),
+                addr,
+                self.started_at,
+            )
+            .with_metrics(self.metrics.clone())
+            .with_capacity(self.capacity.clone())
+            .with_memory_limits(self.memory.clone())
+            .with_deadline(CONFIG.get_request_deadline().map_or(DEFAULT_REQUEST_DEADLINE, Duration::from_millis))
+            .with_heartbeat(
+                CONFIG.get_ping_interval().map_or(DEFAULT_PING_INTERVAL, Duration::from_secs),
+                CONFIG.get_pong_timeout().map_or(DEFAULT_PONG_TIMEOUT, Duration::from_secs),
+            )
+            .with_strict_requests(CONFIG.get_strict_requests());
 
-            self.connections.push(tokio::spawn(async move {
+            let tx = new_connection.sender();
+            self.connections.push((tx, tokio::spawn(async move {
                         new_connection.run(ws_stream).await;
                     }
-                )
+                ))
             );
         }
     }
 }
 
+impl Server {
+    /// Closes every connection, registered or not, and forgets the peers, returning how many of them were told.
+    ///
+    /// Connections that do not close within [`SHUTDOWN_GRACE`] are aborted. The users are not
+    /// unregistered from the replication log, so a standby promoted afterwards still knows them.
+    /// Shutting down a stopped server does nothing.
+    pub(crate) async fn shutdown(&mut self) -> usize {
+        if self.stopped {
+            return 0;
+        }
+        self.stopped = true;
+
+        let mut peers = self.peers.write().await;
+        let close = Message::Close(Some(CloseFrame {
+            code: CloseCode::Away,
+            reason: Utf8Bytes::from_static("Server shutting down"),
+        }));
+        // Peers known from a replicated store have no connection to close
+        let notified = peers.values().filter(|peer| peer.sender.send(close.clone()).is_ok()).count();
+        peers.clear();
+        drop(peers);
+        info!("Shutting down, closed the connection of {} peers", notified);
+
+        // Connections that never registered have no peer to be told through, those of peers ignore a second close
+        let (senders, mut connections): (Vec<Tx>, Vec<JoinHandle<()>>) = std::mem::take(&mut self.connections).into_iter().unzip();
+        for tx in senders {
+            let _ = tx.send(close.clone());
+        }
+        let closed = join_all(connections.iter_mut());
+        if tokio::time::timeout(SHUTDOWN_GRACE, closed).await.is_err() {
+            warn!("Aborting the connections still open after {:?}", SHUTDOWN_GRACE);
+        }
+        for connection in connections {
+            connection.abort();
+        }
+        notified
+    }
+}
+
 pub(crate) struct Receiver{
     session: Session,
     peers: PeerMap,
+    log: MutationLog,
     reader: SplitStream<WebSocketStream<TcpStream>>,
     writer: SharedSink,
     tx: Tx,
-    user: Option<String>,
+    /// The device registered over this connection.
+    user: Option<Device>,
+    started_at: Instant,
+    /// Responses of the last idempotent requests of this session, oldest first.
+    completed: VecDeque<(String, String)>,
+    /// Idempotency key of the request being handled, recorded with its response.
+    idempotency_key: Option<String>,
+    metrics: Metrics,
+    capacity: Capacity,
+    memory: MemoryLimits,
+    /// Bytes relayed to this connection and not written yet, shared with its [`Sender`].
+    pending: PendingBytes,
+    /// Address of the client, counted against the daily registration quota.
+    addr: String,
+    /// Time a request may take before it is answered with an error.
+    deadline: Duration,
+    /// Whether requests with unknown fields or ids other than UUIDs are refused.
+    strict: bool,
+    /// Time between the pings of the [`Sender`].
+    ping_interval: Duration,
+    /// Time the client gets past a ping interval to answer with a pong.
+    pong_timeout: Duration,
+    /// When the client last answered a ping, or when the connection was opened.
+    last_pong: Instant,
 }
 
 impl Receiver {
     async fn receive(&mut self){
-        while let Some(Ok(msg_result)) = StreamExt::next(&mut self.reader).await {
+        loop {
+            // A client that stopped answering pings is gone, even if its socket was never closed
+            let deadline = self.last_pong + self.ping_interval + self.pong_timeout;
+            let msg_result = match tokio::time::timeout_at(deadline.into(), StreamExt::next(&mut self.reader)).await {
+                Ok(Some(Ok(msg_result))) => msg_result,
+                Ok(_) => break,
+                Err(_) => {
+                    warn!("No pong from {} since {:?}, dropping the connection", self.addr, self.last_pong.elapsed());
+                    self.metrics.increment("connections_timed_out");
+                    self.disconnect().await;
+                    return;
+                }
+            };
             match msg_result {
                 Message::Text(msg) => {
                     debug!("Received message: {}", msg);
-                    let dk = self.session.read().await.get_decryption_key();
-                    if dk.is_some() {
-                        let dk = dk.unwrap();
-                        match decrypt_client_request(&msg.to_string(), &dk) {
+                    let (dk, previous_dk, aad) = {
+                        let session = self.session.read().await;
+                        (session.get_decryption_key(), session.get_previous_decryption_key(), session.get_associated_data())
+                    };
+                    if let (Some(dk), Some(aad)) = (dk, aad) {
+                        // Requests encrypted before the last rotation may still be in flight
+                        let decrypted = match (decrypt_client_request(&msg.to_string(), &dk, &aad, self.strict), previous_dk) {
+                            (Err(_), Some(previous)) => decrypt_client_request(&msg.to_string(), &previous, &aad, self.strict),
+                            (result, _) => result,
+                        };
+                        match decrypted {
                             Ok((request, id)) => {
-                                match request {
-                                    RequestType::Register(register_request) => {
-                                        match self.handle_registration(register_request, id).await {
-                                            Ok(_) => {
-                                                debug!("Registration successful");
-                                            }
-                                            Err(e) => {
-                                                error!("Failed to register: {}", e);
-                                            }
-                                        }
-                                    }
-                                    RequestType::SendMessage(send_message_request) => {
-                                        match self.handle_send_message(send_message_request, id).await {
-                                            Ok(_) => {
-                                                debug!("Message sent successfully");
-                                            }
-                                            Err(e) => {
-                                                error!("Failed to send message: {}", e);
-                                            }
-                                        }
-                                    }
-                                    RequestType::GetPrekeyBundle(request) => {
-                                        // Handle prekey bundle request
-                                        match self.handle_get_prekey_bundle(request, id).await {
-                                            Ok(_) => {
-                                                debug!("Prekey bundle sent successfully");
-                                            }
-                                            Err(e) => {
-                                                error!("Failed to send prekey bundle: {}", e);
-                                            }
-                                        }
+                                // Rotating the keys cannot be abandoned halfway, the client switches on the acknowledgement
+                                if let RequestType::Rekey(_) = request {
+                                    self.dispatch(request, id).await;
+                                    continue;
+                                }
+                                let request_type = request.name();
+                                let started = Instant::now();
+                                // Handlers may be dropped at any await, so each makes its changes to the store
+                                // in one step, see `handle_registration`
+                                let handled = tokio::time::timeout(self.deadline, self.dispatch(request, id.clone())).await;
+                                let elapsed = started.elapsed();
+                                self.metrics.record(request_type, elapsed);
+                                if handled.is_err() {
+                                    warn!(
+                                        "slow_request request_type={} request_id={} elapsed_ms={}",
+                                        request_type, id, elapsed.as_millis()
+                                    );
+                                    self.idempotency_key = None;
+                                    let response = ServerResponse::new(
+                                        ResponseCode::InternalServerError,
+                                        "The request took too long".to_string()
+                                    );
+                                    if let Err(e) = self.send_response(response, Some(id)).await {
+                                        error!("Failed to report the expired request: {}", e);
                                     }
                                 }
                             }
@@ -182,12 +692,11 @@ impl Receiver {
                 }
                 Message::Binary(_) => {}
                 Message::Ping(_) => {}
-                Message::Pong(_) => {}
+                Message::Pong(_) => {
+                    self.last_pong = Instant::now();
+                }
                 Message::Close(_) => {
-                    self.tx.send(Message::Close(None)).unwrap();
-                    if let Some(user ) = self.user.take(){
-                        self.peers.write().await.remove(&user);
-                        info!("Connection closed with {}", user);
+                    if self.disconnect().await {
                         return;
                     }
                 }
@@ -197,6 +706,132 @@ impl Receiver {
         }
     }
 
+    /// Stops the [`Sender`] and removes the registered device from the peers, returning whether there was one.
+    async fn disconnect(&mut self) -> bool {
+        // The sender is gone already if the server closed the connection
+        let _ = self.tx.send(Message::Close(None));
+        let Some(user) = self.user.take() else { return false };
+        let mut peers = self.peers.write().await;
+        if peers.remove(&user).is_some() {
+            self.log.append(Mutation::Unregister {
+                username: user.username.clone(),
+                device_id: user.device_id.clone(),
+            });
+        }
+        drop(peers);
+        info!("Connection closed with {}", user);
+        true
+    }
+
+    /// Handles a decrypted request, logging its outcome.
+    async fn dispatch(&mut self, request: RequestType, id: String) {
+        match request {
+            RequestType::Register(register_request) => {
+                if self.replay(&register_request.idempotency_key, &id).await {
+                    return;
+                }
+                self.idempotency_key = register_request.idempotency_key.clone();
+                let result = self.handle_registration(register_request, id).await;
+                self.idempotency_key = None;
+                match result {
+                    Ok(_) => {
+                        debug!("Registration successful");
+                    }
+                    Err(e) => {
+                        error!("Failed to register: {}", e);
+                    }
+                }
+            }
+            RequestType::SendMessage(send_message_request) => {
+                match self.handle_send_message(send_message_request, id).await {
+                    Ok(_) => {
+                        debug!("Message sent successfully");
+                    }
+                    Err(e) => {
+                        error!("Failed to send message: {}", e);
+                    }
+                }
+            }
+            RequestType::GetPrekeyBundle(request) => {
+                if self.replay(&request.idempotency_key, &id).await {
+                    return;
+                }
+                self.idempotency_key = request.idempotency_key.clone();
+                // Handle prekey bundle request
+                let result = self.handle_get_prekey_bundle(request, id).await;
+                self.idempotency_key = None;
+                match result {
+                    Ok(_) => {
+                        debug!("Prekey bundle sent successfully");
+                    }
+                    Err(e) => {
+                        error!("Failed to send prekey bundle: {}", e);
+                    }
+                }
+            }
+            RequestType::RelayFilter(request) => {
+                match self.handle_relay_filter(request, id).await {
+                    Ok(_) => {
+                        debug!("Relay filter updated");
+                    }
+                    Err(e) => {
+                        error!("Failed to update relay filter: {}", e);
+                    }
+                }
+            }
+            RequestType::UploadPreKeys(request) => {
+                match self.handle_upload_prekeys(request, id).await {
+                    Ok(_) => {
+                        debug!("One-time pre-keys uploaded");
+                    }
+                    Err(e) => {
+                        error!("Failed to upload one-time pre-keys: {}", e);
+                    }
+                }
+            }
+            RequestType::UploadSignedPreKey(request) => {
+                match self.handle_upload_signed_prekey(request, id).await {
+                    Ok(_) => {
+                        debug!("Signed pre-key replaced");
+                    }
+                    Err(e) => {
+                        error!("Failed to replace signed pre-key: {}", e);
+                    }
+                }
+            }
+            RequestType::ServerInfo(_) => {
+                match self.handle_server_info(id).await {
+                    Ok(_) => {
+                        debug!("Server info sent successfully");
+                    }
+                    Err(e) => {
+                        error!("Failed to send server info: {}", e);
+                    }
+                }
+            }
+            RequestType::GetPresence(request) => {
+                match self.handle_get_presence(request, id).await {
+                    Ok(_) => {
+                        debug!("Presence sent successfully");
+                    }
+                    Err(e) => {
+                        error!("Failed to send presence: {}", e);
+                    }
+                }
+            }
+            RequestType::Rekey(_) => {
+                match self.handle_rekey(id).await {
+                    Ok(_) => {
+                        debug!("Session keys rotated");
+                    }
+                    Err(e) => {
+                        error!("Failed to rotate session keys: {}", e);
+                    }
+                }
+            }
+        }
+    }
+
     async fn handle_establish_connection(
         &mut self,
         request: EstablishConnectionRequest,
@@ -210,7 +845,8 @@ impl Receiver {
                     self.session.write().await.set_decryption_key(dk);
                     self.session.write().await.set_associated_data(im.get_associated_data());
 
-                    let response = ServerResponse::new(ResponseCode::Ok, im.to_base64());
+                    let text = if request.typed { im.to_json() } else { im.to_base64() };
+                    let response = ServerResponse::new(ResponseCode::Ok, text);
                     self.send_response(response, None).await?;
                     Ok(())
                 }
@@ -244,18 +880,113 @@ impl Receiver {
         request: RegisterRequest,
         id: String,
     ) -> Result<(), ServerError> {
-        let is_alphanumeric = !request.username.is_empty() &&
-            request.username.chars().all(char::is_alphanumeric);
-        if is_alphanumeric && !self.peers.read().await.contains_key(&request.username) {
+        // Only registrations are held to the length of a username, lookups still reach older users
+        let username = self.check_username(&request.username, &id, |raw| {
+            validate_username(raw).and_then(|_| normalize_username(raw))
+        }).await?;
+        let device_id = request.device_id.unwrap_or_default();
+        if !device_id.is_empty() && Uuid::parse_str(&device_id).is_err() {
+            debug!("Refused a device id that is not a UUID");
+            let response = ServerResponse::new(ResponseCode::BadRequest, "Invalid device id".to_string());
+            self.send_response(response, Some(id)).await?;
+            return Err(ServerError::InvalidRequest);
+        }
+        let device = Device::new(&username, &device_id);
+        let taken = self.peers.read().await
+            .get(&device)
+            .is_some_and(|peer| !peer.sender.is_closed());
+        if !taken {
             if let Ok(bundle) = PreKeyBundle::try_from(request.bundle) {
                 debug!("Key bundle parsed correctly");
-                let peer = Peer::new(self.tx.clone(), bundle);
-                let username = request.username.clone();
-                self.peers.write().await.insert(request.username, peer);
-                let response = ServerResponse::new(ResponseCode::Ok, "User registered successfully!".to_string());
-                self.send_response(response, Some(id)).await?;
-                self.user = Some(username.clone());
-                Ok(())
+                match bundle.validate() {
+                    Ok(report) if !report.is_clean() => {
+                        debug!("Key bundle accepted with warnings: {:?}", report.warnings);
+                    }
+                    Ok(_) => {}
+                    Err(e) => {
+                        error!("Rejected prekey bundle: {}", e);
+                        let response = ServerResponse::new(
+                            ResponseCode::BadRequest,
+                            format!("Invalid prekey bundle: {}", e)
+                        );
+                        self.send_response(response, Some(id)).await?;
+                        return Err(ServerError::InvalidRequest);
+                    }
+                }
+                let mutation = Mutation::Register {
+                    username: username.clone(),
+                    device_id: device_id.clone(),
+                    bundle: encode_bundle(&bundle),
+                };
+                let ik = bundle.ik.clone();
+                let peer = Peer::new(self.tx.clone(), bundle).with_pending(self.pending.clone());
+                // The check above is only a fast path: another connection may have registered the same
+                // device since, in which case the first insert wins and this connection gets a Conflict
+                let peers = self.peers.clone();
+                let mut peers = peers.write().await;
+                let users = peers.users();
+                // Another device of a registered user is not a new user, but it must be the same user
+                let known = peers.is_registered(&username);
+                if peers.devices(&username).any(|(_, other)| !other.pb.same_identity(&peer.pb)) {
+                    drop(peers);
+                    warn!("Refused a device of {} with another identity, from {}", username, self.addr);
+                    let response = ServerResponse::new(ResponseCode::Conflict, "Username already exists".to_string());
+                    self.send_response(response, Some(id)).await?;
+                    return Err(ServerError::InvalidRequest);
+                }
+                let returning = peers.is_returning(&device, &peer.pb.ik);
+                let admitted = match peers.get(&device).map(|other| other.sender.is_closed()) {
+                    None => {
+                        let today = Utc::now().date_naive();
+                        // A device registering again, say after a lost connection, is no new registration
+                        let admitted = match (known, returning) {
+                            (true, true) => Ok(()),
+                            (true, false) => self.capacity.admit_device(&self.addr, today),
+                            (false, true) => self.capacity.readmit(users),
+                            (false, false) => self.capacity.admit(users, &self.addr, today),
+                        };
+                        if admitted.is_ok() {
+                            peers.insert(device.clone(), peer);
+                        }
+                        admitted.map(|_| true)
+                    }
+                    // Users replicated from a primary have no connection until they register again,
+                    // and already count towards the capacity
+                    Some(true) => {
+                        peers.insert(device.clone(), peer);
+                        Ok(true)
+                    }
+                    Some(false) => Ok(false),
+                };
+                if admitted == Ok(true) {
+                    peers.remember_device(&device, &ik);
+                    self.log.append(mutation);
+                    // Nothing is awaited from here on, so a request past its deadline cannot leave the
+                    // device registered with its queue undelivered
+                    self.user = Some(device.clone());
+                    let response = ServerResponse::new(ResponseCode::Ok, "User registered successfully!".to_string());
+                    self.queue_response(response, id);
+                    return self.deliver_queue(&mut peers, &device);
+                }
+                drop(peers);
+                match admitted {
+                    Ok(_) => {
+                        let response = ServerResponse::new(ResponseCode::Conflict, "Username already exists".to_string());
+                        self.send_response(response, Some(id)).await?;
+                        Err(ServerError::InvalidRequest)
+                    }
+                    Err(refusal) => {
+                        warn!("Refused to register {} from {}: {:?}", username, self.addr, refusal);
+                        self.metrics.increment(refusal.counter());
+                        let reason = match refusal {
+                            Refusal::Full => "Server full, no more users can register",
+                            Refusal::QuotaExceeded => "Too many registrations from this address today",
+                        };
+                        let response = ServerResponse::new(ResponseCode::ServiceUnavailable, reason.to_string());
+                        self.send_response(response, Some(id)).await?;
+                        Err(ServerError::RegistrationRefused(refusal))
+                    }
+                }
             } else {
                 error!("Failed to parse prekey bundle");
                 self.send_response(
@@ -267,63 +998,161 @@ impl Receiver {
                 ).await?;
                 Err(ServerError::InvalidRequest)
             }
-        } else if is_alphanumeric {
+        } else {
             let response = ServerResponse::new(ResponseCode::Conflict, "Username already exists".to_string());
             self.send_response(response, Some(id)).await?;
             Err(ServerError::InvalidRequest)
         }
-        else {
-            let response = ServerResponse::new(ResponseCode::BadRequest, "Invalid username".to_string());
-            self.send_response(response, Some(id)).await?;
-            Err(ServerError::InvalidRequest)
+    }
+
+    /// Delivers the messages queued for `device` while it was offline, with `peers` locked by the
+    /// registration of the device, so that no message can be relayed live before them.
+    fn deliver_queue(&self, peers: &mut Peers, device: &Device) -> Result<(), ServerError> {
+        let queued = peers.take_queued(device);
+        if !queued.is_empty() {
+            debug!("Delivering {} queued messages to {}", queued.len(), device);
+            self.log.append(Mutation::QueueFlushed {
+                username: device.username.clone(),
+                device_id: device.device_id.clone(),
+            });
+        }
+        let Some(peer) = peers.get(device) else { return Ok(()) };
+        for message in queued {
+            if !peer.relay(message.payload) {
+                return Err(ServerError::SendError("Failed to deliver queued message".to_string()));
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the canonical form of `raw`, or answers request `id` with the reason it is refused.
+    async fn normalize_username(&mut self, raw: &str, id: &str) -> Result<String, ServerError> {
+        self.check_username(raw, id, normalize_username).await
+    }
+
+    /// Returns the canonical form of `raw` given by `check`, or answers request `id` with the reason it is refused.
+    async fn check_username(
+        &mut self,
+        raw: &str,
+        id: &str,
+        check: impl Fn(&str) -> Result<String, UsernameError>,
+    ) -> Result<String, ServerError> {
+        match check(raw) {
+            Ok(username) => Ok(username),
+            Err(e) => {
+                debug!("Refused username {:?}: {}", raw, e);
+                let response = ServerResponse::new(ResponseCode::BadRequest, format!("Invalid username: {}", e));
+                self.send_response(response, Some(id.to_string())).await?;
+                Err(ServerError::InvalidUsername(e))
+            }
         }
     }
 
     async fn handle_send_message(
         &mut self,
-        request: SendMessageRequest,
+        mut request: SendMessageRequest,
         id: String,
     ) -> Result<(), ServerError> {
-        match self.peers.read().await.get(&request.to) {
-            Some(peer) => {
-                let serialized = serde_json::to_string(&request).unwrap();
-                peer.sender.send(Message::Text(Utf8Bytes::from(serialized))).map_err(|_| {
-                    error!("Failed to send message to peer");
-                    ServerError::SendError("Failed to send message to peer".to_string())
-                })?;
-                Ok(())
-            }
-            None => {
-                debug!("Peer not found: {}", request.to);
-                debug!("User {} not found", request.to);
+        request.to = self.normalize_username(&request.to, &id).await?;
+        request.from = self.normalize_username(&request.from, &id).await?;
+        // Messages are only relayed from the user registered over this connection
+        if self.user.as_ref().map(|device| &device.username) != Some(&request.from) {
+            debug!("Refused a message from {} over a connection registered as {:?}", request.from, self.user);
+            let response = ServerResponse::new(ResponseCode::BadRequest, "Sender is not the registered user".to_string());
+            self.send_response(response, Some(request.request_id.take().unwrap_or(id))).await?;
+            return Err(ServerError::InvalidRequest);
+        }
+        // The id is for the sender only, the recipient gets the message as legacy senders send it
+        let ack = request.request_id.take();
+        let peers = self.peers.clone();
+        let peers = peers.read().await;
+        let seq = peers.next_sequence();
+        let serialized = serde_json::to_string(&request).unwrap();
+        match peers.relay(&request.from, &request.to, &serialized) {
+            Err(ServerError::RelayBlocked) => {
+                debug!("User {} closed the chat with {}", request.to, request.from);
+                // The text names the closed chat, since legacy chat messages carry no request id
                 self.send_response(
-                    ServerResponse::new(
-                        ResponseCode::NotFound,
-                        "User not found".to_string()
-                    ),
-                    Some(id)
+                    ServerResponse::new(ResponseCode::Forbidden, request.to.clone()),
+                    Some(ack.unwrap_or(id))
                 ).await?;
+                return Err(ServerError::RelayBlocked);
+            }
+            Ok(devices) if devices > 0 => {
+                drop(peers);
+                return self.acknowledge(ack, "Delivered").await;
+            }
+            // No device connected, their connections are gone, or they have older messages to receive first
+            _ => {}
+        }
+        drop(peers);
 
-                return Err(ServerError::UserNotFoundError);
+        // A device of the recipient may have registered since the read lock was released
+        let mut peers = self.peers.write().await;
+        if peers.relay(&request.from, &request.to, &serialized).is_ok_and(|devices| devices > 0) {
+            drop(peers);
+            return self.acknowledge(ack, "Delivered").await;
+        }
+        debug!("User {} is offline or has queued messages to receive first, queuing the message", request.to);
+        let usage = peers.memory_usage();
+        if self.memory.pressure(&usage) >= Pressure::Soft {
+            let dropped = peers.shrink_queues(PRESSURE_QUEUE_LIMIT);
+            drop(peers);
+            if dropped > 0 {
+                warn!("Dropped the {} oldest offline messages beyond {} per user", dropped, PRESSURE_QUEUE_LIMIT);
             }
+            self.metrics.increment("offline_enqueues_refused_memory");
+            self.send_response(
+                ServerResponse::new(ResponseCode::ServiceUnavailable, "Server under memory pressure, the message was not queued".to_string()),
+                Some(ack.unwrap_or(id))
+            ).await?;
+            return Err(ServerError::MemoryPressure);
+        }
+        let message = QueuedMessage::new(&request, seq, peers.known_devices(&request.to));
+        self.log.append(Mutation::MessageQueued { username: request.to.clone(), message: message.clone() });
+        peers.enqueue(&request.to, message);
+        drop(peers);
+        self.acknowledge(ack, "Queued").await
+    }
+
+    /// Tells the sender of a chat message what became of it, if it asked by giving a request id.
+    async fn acknowledge(&mut self, ack: Option<String>, status: &str) -> Result<(), ServerError> {
+        match ack {
+            Some(id) => self.send_response(ServerResponse::new(ResponseCode::Ok, status.to_string()), Some(id)).await,
+            None => Ok(()),
         }
     }
 
     async fn handle_get_prekey_bundle(
         &mut self,
-        request: GetPreKeyBundleRequest,
+        mut request: GetPreKeyBundleRequest,
         id: String,
     ) -> Result<(), ServerError> {
-        if self.user != Some(request.who.clone()) {
-            match self.peers.write().await.get_mut(&request.who) {
-                Some(peer) => {
-                    let bundle = peer.get_bundle();
-                    let response = ServerResponse::new(ResponseCode::Ok, bundle.to_base64());
+        request.username = self.normalize_username(&request.username, &id).await?;
+        if self.user.as_ref().map(|device| &device.username) != Some(&request.username) {
+            let peers = self.peers.clone();
+            let mut peers = peers.write().await;
+            // Any device will do, preferably one that is online to replenish its one-time pre-keys
+            let device = peers.devices(&request.username)
+                .find(|(_, peer)| !peer.sender.is_closed())
+                .or_else(|| peers.devices(&request.username).next())
+                .map(|(device, _)| device.clone());
+            match device.and_then(|device| peers.update(&device, Peer::get_bundle).map(|bundle| (device, bundle))) {
+                Some((device, bundle)) => {
+                    if !bundle.otpk.is_empty() {
+                        self.log.append(Mutation::OtpkConsumed { username: device.username, device_id: device.device_id });
+                    }
+                    let text = if request.typed {
+                        serde_json::to_string(&bundle).map_err(|_| ServerError::InvalidPreKeyBundle)?
+                    } else {
+                        bundle.to_base64()
+                    };
+                    let response = ServerResponse::new(ResponseCode::Ok, text);
                     self.send_response(response, Some(id)).await?;
                     Ok(())
                 }
                 None => {
-                    debug!("User {} not found", request.who);
+                    debug!("User {} not found", request.username);
                     self.send_response(
                         ServerResponse::new(
                             ResponseCode::NotFound,
@@ -335,7 +1164,7 @@ impl Receiver {
                 }
             }
         } else {
-            debug!("User {} is asking for its own bundle", request.who);
+            debug!("User {} is asking for its own bundle", request.username);
             self.send_response(
                 ServerResponse::new(
                     ResponseCode::BadRequest,
@@ -347,9 +1176,219 @@ impl Receiver {
         }
     }
 
-    async fn send_response(&self, response: ServerResponse, id: Option<String>)-> Result<(), ServerError> {
+    async fn handle_relay_filter(
+        &mut self,
+        mut request: RelayFilterRequest,
+        id: String,
+    ) -> Result<(), ServerError> {
+        request.from = self.normalize_username(&request.from, &id).await?;
+        let peers = self.peers.clone();
+        let mut peers = peers.write().await;
+        let updated = self.user.as_ref().and_then(|user| peers.update(user, |peer| {
+            if request.blocked {
+                peer.blocked.insert(request.from);
+            } else {
+                peer.blocked.remove(&request.from);
+            }
+        }));
+        match updated {
+            Some(()) => {
+                let response = ServerResponse::new(ResponseCode::Ok, "Relay filter updated".to_string());
+                self.send_response(response, Some(id)).await
+            }
+            None => {
+                debug!("Relay filter requested before registration");
+                self.send_response(
+                    ServerResponse::new(
+                        ResponseCode::BadRequest,
+                        "You must register first".to_string()
+                    ),
+                    Some(id)
+                ).await?;
+                Err(ServerError::InvalidRequest)
+            }
+        }
+    }
+
+    async fn handle_upload_prekeys(
+        &mut self,
+        request: UploadPreKeysRequest,
+        id: String,
+    ) -> Result<(), ServerError> {
+        let peers = self.peers.clone();
+        let mut peers = peers.write().await;
+        let Some(peer) = self.user.as_ref().and_then(|user| peers.get(user)) else {
+            debug!("One-time pre-keys uploaded before registration");
+            self.send_response(
+                ServerResponse::new(
+                    ResponseCode::BadRequest,
+                    "You must register first".to_string()
+                ),
+                Some(id)
+            ).await?;
+            return Err(ServerError::InvalidRequest);
+        };
+
+        // Check the bundle as it would be after the upload, and only then store it
+        let mut bundle = peer.pb.clone();
+        let mut valid = (request.otpk_ids.is_empty() || request.otpk_ids.len() == request.otpk.len())
+            && (request.otpk_sigs.is_empty() || request.otpk_sigs.len() == request.otpk.len());
+        let mut ids = request.otpk_ids.into_iter();
+        let mut sigs = request.otpk_sigs.into_iter().map(Signature::from_base64);
+        for otpk in request.otpk {
+            // Keys uploaded without ids are named by their hash
+            match (PublicKey::from_base64(otpk), ids.next(), sigs.next().transpose()) {
+                (Ok(key), id, Ok(sig)) => bundle.push_otpk(OneTimePreKey { key, id, sig }),
+                _ => valid = false,
+            }
+        }
+        if !valid || bundle.validate().is_err() {
+            self.send_response(
+                ServerResponse::new(
+                    ResponseCode::BadRequest,
+                    "Invalid one-time pre-keys".to_string()
+                ),
+                Some(id)
+            ).await?;
+            return Err(ServerError::InvalidPreKeyBundle);
+        }
+        let count = bundle.otpk.len();
+        let device = self.user.clone().unwrap();
+        self.log.append(Mutation::BundleUpdated {
+            username: device.username.clone(),
+            device_id: device.device_id.clone(),
+            bundle: encode_bundle(&bundle),
+        });
+        peers.update(&device, |peer| peer.pb = bundle);
+        self.send_response(ServerResponse::new(ResponseCode::Ok, count.to_string()), Some(id)).await
+    }
+
+    /// Replaces the signed pre-key of the user, once its signature verifies against the identity of the stored bundle.
+    async fn handle_upload_signed_prekey(
+        &mut self,
+        request: UploadSignedPreKeyRequest,
+        id: String,
+    ) -> Result<(), ServerError> {
+        let peers = self.peers.clone();
+        let mut peers = peers.write().await;
+        let Some(peer) = self.user.as_ref().and_then(|user| peers.get(user)) else {
+            debug!("Signed pre-key uploaded before registration");
+            self.send_response(
+                ServerResponse::new(
+                    ResponseCode::BadRequest,
+                    "You must register first".to_string()
+                ),
+                Some(id)
+            ).await?;
+            return Err(ServerError::InvalidRequest);
+        };
+
+        // Check the bundle as it would be after the upload, and only then store it
+        let mut bundle = peer.pb.clone();
+        let valid = match (PublicKey::from_base64(request.spk), Signature::from_base64(request.sig)) {
+            (Ok(spk), Ok(sig)) => {
+                bundle.spk = spk;
+                bundle.sig = sig;
+                bundle.spk_created_at = Some(request.created_at);
+                bundle.validate().is_ok()
+            }
+            _ => false,
+        };
+        if !valid {
+            self.send_response(
+                ServerResponse::new(
+                    ResponseCode::BadRequest,
+                    "Invalid signed pre-key".to_string()
+                ),
+                Some(id)
+            ).await?;
+            return Err(ServerError::InvalidPreKeyBundle);
+        }
+        let device = self.user.clone().unwrap();
+        self.log.append(Mutation::BundleUpdated {
+            username: device.username.clone(),
+            device_id: device.device_id.clone(),
+            bundle: encode_bundle(&bundle),
+        });
+        peers.update(&device, |peer| peer.pb = bundle);
+        self.send_response(ServerResponse::new(ResponseCode::Ok, "Signed pre-key replaced".to_string()), Some(id)).await
+    }
+
+    /// Answers a retried request with the response of its first attempt.
+    /// Returns `false` if no request with this idempotency key was completed in this session.
+    async fn replay(&mut self, key: &Option<String>, id: &str) -> bool {
+        let Some(key) = key else { return false };
+        let cached = self.completed
+            .iter()
+            .find(|(k, _)| k == key)
+            .and_then(|(_, response)| ServerResponse::from_json(response.clone()));
+        match cached {
+            Some(response) => {
+                debug!("Replaying response for idempotency key {}", key);
+                if let Err(e) = self.send_response(response, Some(id.to_string())).await {
+                    error!("Failed to replay response: {}", e);
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    async fn handle_server_info(&mut self, id: String) -> Result<(), ServerError> {
+        // Round the number of users so that it cannot be used to detect single registrations
+        let users = self.peers.read().await.users();
+        let info = ServerInfo {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            uptime_secs: self.started_at.elapsed().as_secs(),
+            registered_users: (users + 5) / 10 * 10,
+            offline_storage: true,
+            max_message_size: None,
+            rate_limit_per_minute: None,
+            max_registered_users: self.capacity.max_users(),
+        };
+        let response = ServerResponse::new(ResponseCode::Ok, serde_json::to_string(&info).unwrap());
+        self.send_response(response, Some(id)).await
+    }
+
+    async fn handle_get_presence(&mut self, request: GetPresenceRequest, id: String) -> Result<(), ServerError> {
+        let peers = self.peers.read().await;
+        // Keyed by the names as written in the request, so that the client finds them again.
+        // Users replicated from another server have no live connection here.
+        let presence: HashMap<String, bool> = request.usernames.into_iter()
+            .map(|raw| {
+                let online = normalize_username(&raw)
+                    .is_ok_and(|username| peers.devices(&username).any(|(_, peer)| !peer.sender.is_closed()));
+                (raw, online)
+            })
+            .collect();
+        drop(peers);
+        let response = ServerResponse::new(ResponseCode::Ok, serde_json::to_string(&presence).unwrap());
+        self.send_response(response, Some(id)).await
+    }
+
+    async fn handle_rekey(&mut self, id: String) -> Result<(), ServerError> {
+        // Hold the session for writing until the keys are rotated, so that nothing else is
+        // encrypted between the acknowledgement and the switch.
+        let mut session = self.session.write().await;
+        let ek = session.get_encryption_key().ok_or(ServerError::InvalidRequest)?;
+        let aad = session.get_associated_data().ok_or(ServerError::InvalidRequest)?;
+        let response = ResponseWrapper {
+            request_id: id,
+            body: serde_json::from_str(
+                &ServerResponse::new(ResponseCode::Ok, "Session keys rotated".to_string()).to_string()
+            ).unwrap(),
+        };
+        let response = serde_json::to_string(&response).unwrap();
+        let enc = ek.encrypt(response.as_bytes(), &aad.to_bytes())?;
+        self.writer.lock().await.send(Message::Text(Utf8Bytes::from(enc))).await?;
+        session.rotate()?;
+        Ok(())
+    }
+
+    async fn send_response(&mut self, response: ServerResponse, id: Option<String>)-> Result<(), ServerError> {
         debug!("response: {}", response.to_string());
         if let Some(req_id) = id {
+            self.remember_response(&response);
             if let Some(ek) = self.session.read().await.get_encryption_key() {
                 let aad = self.session.read().await.get_associated_data().unwrap();
                 let response = ResponseWrapper {
@@ -372,45 +1411,95 @@ impl Receiver {
         self.writer.lock().await.send(Message::Text(Utf8Bytes::from(response.to_string()))).await?;
         Ok(())
     }
+
+    /// Hands the response to request `id` to the [`Sender`] of the connection, which encrypts it, instead of
+    /// writing it at once like [`Receiver::send_response`]. Frames relayed to the connection afterwards
+    /// are written after it, and nothing is awaited.
+    fn queue_response(&mut self, response: ServerResponse, id: String) {
+        debug!("response: {}", response.to_string());
+        self.remember_response(&response);
+        let response = ResponseWrapper {
+            request_id: id,
+            body: serde_json::from_str(&response.to_string()).unwrap(),
+        };
+        let response = serde_json::to_string(&response).unwrap();
+        if self.tx.send(Message::Text(Utf8Bytes::from(response))).is_err() {
+            error!("Failed to queue the response, the connection is gone");
+        }
+    }
+
+    /// Records `response` for the idempotency key of the request being handled, if it has one.
+    fn remember_response(&mut self, response: &ServerResponse) {
+        if let Some(key) = self.idempotency_key.take() {
+            if self.completed.len() == IDEMPOTENCY_CACHE_SIZE {
+                self.completed.pop_front();
+            }
+            self.completed.push_back((key, response.to_string()));
+        }
+    }
 }
 
 pub(crate) struct Sender {
     session: Session,
     peers: PeerMap,
     rx: Rx,
-    writer: SharedSink
+    writer: SharedSink,
+    /// Bytes relayed to this connection and not written yet, shared with its [`Receiver`].
+    pending: PendingBytes,
+    /// Time between the pings sent to the client, whose pongs the [`Receiver`] waits for.
+    ping_interval: Duration,
 }
 impl Sender {
     async fn send(mut self) {
+        let start = tokio::time::Instant::now() + self.ping_interval;
+        let mut ping = tokio::time::interval_at(start, self.ping_interval);
         loop {
+            let msg_result = tokio::select! {
+                msg_result = self.rx.recv() => msg_result,
+                _ = ping.tick() => {
+                    if self.writer.lock().await.send(Message::Ping(Default::default())).await.is_err() {
+                        debug!("Failed to send a ping");
+                    }
+                    continue;
+                }
+            };
 
-            if let Some(msg_result) = self.rx.recv().await {
-
-                match msg_result {
-                    Message::Text(msg) => {
-                        if let Some(ek) = self.session.read().await.get_encryption_key() {
-                            let aad = self.session.read().await.get_associated_data().unwrap();
-                            match ek.encrypt(&msg.to_string().into_bytes(), &aad.to_bytes()) {
-                                Ok(enc) => {
-                                    if self.writer.lock().await.send(Message::Text(Utf8Bytes::from(enc))).await.is_err() {
-                                        error!("Failed to send message.");
-                                    } else {
-                                        debug!("Message sent: {}", msg.to_string());
-                                    }
-                                },
-                                _ => {}
-                            }
-                        } else {
-                            debug!("Session encryption key not found");
+            // Every handle to the channel is gone once the peer was removed and the receiver stopped
+            let Some(msg_result) = msg_result else { return };
+
+            match msg_result {
+                Message::Text(msg) => {
+                    self.pending.sub(msg.len());
+                    // Keep the session locked until the frame is written, so a concurrent
+                    // rekey cannot slip in between encryption and sending.
+                    let session = self.session.read().await;
+                    if let Some(ek) = session.get_encryption_key() {
+                        let aad = session.get_associated_data().unwrap();
+                        match ek.encrypt_bytes(msg.as_bytes(), &aad.to_bytes()) {
+                            Ok(frame) => {
+                                let enc = BASE64.encode(frame);
+                                if self.writer.lock().await.send(Message::Text(Utf8Bytes::from(enc))).await.is_err() {
+                                    error!("Failed to send message.");
+                                } else {
+                                    debug!("Message sent: {}", msg.to_string());
+                                }
+                            },
+                            _ => {}
                         }
+                    } else {
+                        debug!("Session encryption key not found");
                     }
+                }
 
-                    Message::Close(_) => {
-                        self.rx.close();
-                        return;
+                Message::Close(frame) => {
+                    // Only the server's own close carries a frame, the client's is answered by the socket
+                    if frame.is_some() && self.writer.lock().await.send(Message::Close(frame)).await.is_err() {
+                        debug!("Failed to send the close frame");
                     }
-                    _ => {}
+                    self.rx.close();
+                    return;
                 }
+                _ => {}
             }
         }
     }
@@ -419,56 +1508,141 @@ impl Sender {
 pub(crate) struct Connection {
     pub(crate) session: Session,
     pub(crate) peers: PeerMap,
+    pub(crate) log: MutationLog,
     pub(crate) addr: String,
-
+    pub(crate) started_at: Instant,
+    pub(crate) metrics: Metrics,
+    pub(crate) capacity: Capacity,
+    pub(crate) memory: MemoryLimits,
+    pub(crate) deadline: Duration,
+    pub(crate) strict: bool,
+    pub(crate) ping_interval: Duration,
+    pub(crate) pong_timeout: Duration,
+    /// The messages written to the client, taken by [`Connection::run`].
+    tx: Tx,
+    rx: Option<Rx>,
 }
 
 impl Connection {
     pub(crate) fn new(
         peers: PeerMap,
+        log: MutationLog,
         addr: String,
-
+        started_at: Instant,
     ) -> Self {
 
         let session =  Arc::new(RwLock::new(SessionKeys::new()));
+        let (tx, rx) = mpsc::unbounded_channel::<Message>();
         Self {
             session,
             peers: peers.clone() ,
-            addr
+            log,
+            addr,
+            started_at,
+            metrics: Metrics::new(),
+            capacity: Capacity::default(),
+            memory: MemoryLimits::default(),
+            deadline: DEFAULT_REQUEST_DEADLINE,
+            strict: false,
+            ping_interval: DEFAULT_PING_INTERVAL,
+            pong_timeout: DEFAULT_PONG_TIMEOUT,
+            tx,
+            rx: Some(rx),
         }
     }
 
-    async fn run(&mut self, stream: WebSocketStream<TcpStream>,) {
-        let (tx, rx) = mpsc::unbounded_channel::<Message>();
+    /// The sender of the messages written to the client, through which the server closes the connection.
+    pub(crate) fn sender(&self) -> Tx {
+        self.tx.clone()
+    }
+
+    /// Records the latency of the requests in `metrics`, shared with other connections.
+    pub(crate) fn with_metrics(mut self, metrics: Metrics) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// Refuses registrations beyond `capacity`, shared with other connections.
+    pub(crate) fn with_capacity(mut self, capacity: Capacity) -> Self {
+        self.capacity = capacity;
+        self
+    }
+
+    /// Refuses to queue messages for offline users beyond the soft limit of `memory`, shared with other connections.
+    pub(crate) fn with_memory_limits(mut self, memory: MemoryLimits) -> Self {
+        self.memory = memory;
+        self
+    }
+
+    /// Answers requests that take longer than `deadline` with an error.
+    pub(crate) fn with_deadline(mut self, deadline: Duration) -> Self {
+        self.deadline = deadline;
+        self
+    }
+
+    /// Refuses requests with unknown fields, or with ids that are not UUIDs, if `strict`.
+    pub(crate) fn with_strict_requests(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// Pings the client every `ping_interval`, and drops the connection if no pong arrives within
+    /// `pong_timeout` past an interval.
+    pub(crate) fn with_heartbeat(mut self, ping_interval: Duration, pong_timeout: Duration) -> Self {
+        self.ping_interval = ping_interval;
+        self.pong_timeout = pong_timeout;
+        self
+    }
+
+    pub(crate) async fn run(&mut self, stream: WebSocketStream<TcpStream>,) {
+        let (tx, rx) = (self.tx.clone(), self.rx.take().expect("A connection runs only once"));
         let (writer, reader) = stream.split();
         let writer = Arc::new(Mutex::new(writer));
+        let pending = self.peers.read().await.pending_bytes();
         let sender = Sender {
             session: self.session.clone(),
             peers: self.peers.clone(),
             rx,
-            writer: writer.clone()
+            writer: writer.clone(),
+            pending: pending.clone(),
+            ping_interval: self.ping_interval,
         };
 
         let mut receiver =  Receiver {
             session: self.session.clone(),
             peers: self.peers.clone(),
+            log: self.log.clone(),
             tx,
             writer: writer.clone(),
             reader,
             user: None,
+            started_at: self.started_at,
+            completed: VecDeque::new(),
+            idempotency_key: None,
+            metrics: self.metrics.clone(),
+            capacity: self.capacity.clone(),
+            memory: self.memory.clone(),
+            pending,
+            addr: self.addr.clone(),
+            deadline: self.deadline,
+            strict: self.strict,
+            ping_interval: self.ping_interval,
+            pong_timeout: self.pong_timeout,
+            last_pong: Instant::now(),
         };
 
         let task_receive = tokio::spawn(async move {
             receiver.receive().await;
         });
 
-        let task_send = tokio::spawn(async move {
+        let mut task_send = tokio::spawn(async move {
             sender.send().await;
         });
 
+        // A sender stuck writing to a dead socket would never read the close of the receiver
         tokio::select! {
-            _ = task_receive => (),
-            _ = task_send => (),
+            _ = task_receive => task_send.abort(),
+            _ = &mut task_send => (),
         }
     }
 
@@ -479,39 +1653,187 @@ impl Connection {
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub(crate) struct EstablishConnectionRequest{
     request_type: String,
-    bundle: String,
+    bundle: WireBundle,
+    /// Answer with the versioned form of the initial message rather than the legacy base64 string.
+    #[serde(default)]
+    typed: bool,
 }
 
 
+/// Decrypts a request and tells which one it is, along with its id.
+///
+/// The id, also that of a chat message, is echoed back in the response, so it is refused if longer than
+/// [`MAX_REQUEST_ID_LENGTH`], and requests wrapped in another request are refused. If `strict`,
+/// the id must also be a UUID and no field may be left unread.
 pub(crate) fn decrypt_client_request(
     req: &str,
     dk: &DecryptionKey,
+    aad: &AssociatedData,
+    strict: bool,
 ) -> Result<(RequestType, String), ServerError> {
-    let decrypted = match common::decrypt_request(req, dk) {
-        Ok((dec, _ )) => dec,
-        Err(_) => return Err(ServerError::InvalidRequest),
-    };
-    if let Ok(message) = serde_json::from_str::<SendMessageRequest>(&decrypted.to_string()) {
-        Ok((RequestType::SendMessage(message), "".to_string()))
-    } else if let Ok(req) = serde_json::from_str::<RequestWrapper>(&decrypted.to_string()) {
+    let decrypted = common::decrypt_request(req, dk, aad)?;
+    if let Ok(message) = serde_json::from_value::<SendMessageRequest>(decrypted.clone()) {
+        if strict && has_unknown_fields(&message, &decrypted) {
+            debug!("Refused a chat message with unknown fields");
+            return Err(ServerError::InvalidRequest);
+        }
+        if let Some(id) = &message.request_id {
+            check_request_id(id, strict)?;
+        }
+        let request = RequestType::SendMessage(message);
+        warn_legacy_field_names(&request, &decrypted);
+        Ok((request, "".to_string()))
+    } else if let Ok(req) = serde_json::from_value::<RequestWrapper>(decrypted.clone()) {
+        if strict && has_unknown_fields(&req, &decrypted) {
+            debug!("Refused a request with unknown fields");
+            return Err(ServerError::InvalidRequest);
+        }
         let id = req.request_id;
         let body = req.body;
+        check_request_id(&id, strict)?;
+        if body.get("request_id").is_some() || body.get("body").is_some() {
+            debug!("Refused a request wrapped in another request");
+            return Err(ServerError::InvalidRequest);
+        }
         debug!("Decrypted request: {}", body.to_string());
-        if let Ok(registration) = serde_json::from_str::<RegisterRequest>(&body.to_string()) {
-            Ok((RequestType::Register(registration), id))
-        }  else if let Ok(who) = serde_json::from_str::<GetPreKeyBundleRequest>(&body.to_string()) {
-            Ok((RequestType::GetPrekeyBundle(who), id))
-        } else {
-            Err(ServerError::InvalidRequest)
+        let request = parse_request_body(&body)?;
+        if strict && request.has_unknown_fields(&body) {
+            debug!("Refused a {} request with unknown fields", request.name());
+            return Err(ServerError::InvalidRequest);
         }
+        warn_legacy_field_names(&request, &body);
+        Ok((request, id))
     } else  {
         error!("Failed to decrypt request");
         Err(ServerError::InvalidRequest)
     }
 }
 
+fn check_request_id(id: &str, strict: bool) -> Result<(), ServerError> {
+    if id.len() > MAX_REQUEST_ID_LENGTH {
+        debug!("Refused a request id of {} bytes", id.len());
+        return Err(ServerError::InvalidRequest);
+    }
+    if strict && Uuid::parse_str(id).is_err() {
+        debug!("Refused a request id that is not a UUID");
+        return Err(ServerError::InvalidRequest);
+    }
+    Ok(())
+}
+
+fn parse_request_body(body: &Value) -> Result<RequestType, ServerError> {
+    if let Ok(registration) = serde_json::from_value::<RegisterRequest>(body.clone()) {
+        Ok(RequestType::Register(registration))
+    }  else if let Ok(who) = serde_json::from_value::<GetPreKeyBundleRequest>(body.clone()) {
+        Ok(RequestType::GetPrekeyBundle(who))
+    } else if let Some(request_type) = body.get("request_type").or_else(|| body.get("action")).and_then(Value::as_str) {
+        let body = body.clone();
+        match request_type {
+            "rekey" => serde_json::from_value::<RekeyRequest>(body)
+                .map(RequestType::Rekey)
+                .map_err(|_| ServerError::InvalidRequest),
+            "relay_filter" => serde_json::from_value::<RelayFilterRequest>(body)
+                .map(RequestType::RelayFilter)
+                .map_err(|_| ServerError::InvalidRequest),
+            "upload_prekeys" => serde_json::from_value::<UploadPreKeysRequest>(body)
+                .map(RequestType::UploadPreKeys)
+                .map_err(|_| ServerError::InvalidRequest),
+            "upload_signed_prekey" => serde_json::from_value::<UploadSignedPreKeyRequest>(body)
+                .map(RequestType::UploadSignedPreKey)
+                .map_err(|_| ServerError::InvalidRequest),
+            "server_info" => serde_json::from_value::<ServerInfoRequest>(body)
+                .map(RequestType::ServerInfo)
+                .map_err(|_| ServerError::InvalidRequest),
+            "presence" => serde_json::from_value::<GetPresenceRequest>(body)
+                .map(RequestType::GetPresence)
+                .map_err(|_| ServerError::InvalidRequest),
+            _ => Err(ServerError::InvalidRequest),
+        }
+    } else {
+        Err(ServerError::InvalidRequest)
+    }
+}
+
+/// Whether `raw` has fields that were not read into `parsed`.
+///
+/// The request types ignore unknown fields, so that lenient servers keep accepting them: the
+/// check is made after parsing, against the fields `parsed` serializes back to, under their
+/// canonical or legacy names.
+fn has_unknown_fields<T: Serialize + LegacyFieldNames>(parsed: &T, raw: &Value) -> bool {
+    let legacy = T::legacy_field_names(raw);
+    match (serde_json::to_value(parsed), raw.as_object()) {
+        (Ok(Value::Object(known)), Some(raw)) => raw.keys().any(|key| {
+            !known.contains_key(key) && !legacy.iter().any(|(name, canonical)| name == key && known.contains_key(*canonical))
+        }),
+        _ => false,
+    }
+}
+
+/// Logs a deprecation warning for each legacy field name used in `raw`, see [`LegacyFieldNames`].
+fn warn_legacy_field_names(request: &RequestType, raw: &Value) {
+    for (legacy, canonical) in request.legacy_field_names(raw) {
+        warn!(
+            "deprecated_field request_type={} field={} canonical={}",
+            request.name(), legacy, canonical
+        );
+    }
+}
+
 pub(crate) enum RequestType {
     Register(RegisterRequest),
     SendMessage(SendMessageRequest),
     GetPrekeyBundle(GetPreKeyBundleRequest),
+    Rekey(RekeyRequest),
+    RelayFilter(RelayFilterRequest),
+    UploadPreKeys(UploadPreKeysRequest),
+    UploadSignedPreKey(UploadSignedPreKeyRequest),
+    ServerInfo(ServerInfoRequest),
+    GetPresence(GetPresenceRequest),
+}
+
+impl RequestType {
+    /// The name of the request type, as logged and recorded in the [`Metrics`].
+    pub(crate) fn name(&self) -> &'static str {
+        match self {
+            RequestType::Register(_) => "register",
+            RequestType::SendMessage(_) => "send_message",
+            RequestType::GetPrekeyBundle(_) => "get_prekey_bundle",
+            RequestType::Rekey(_) => "rekey",
+            RequestType::RelayFilter(_) => "relay_filter",
+            RequestType::UploadPreKeys(_) => "upload_prekeys",
+            RequestType::UploadSignedPreKey(_) => "upload_signed_prekey",
+            RequestType::ServerInfo(_) => "server_info",
+            RequestType::GetPresence(_) => "presence",
+        }
+    }
+
+    /// The legacy field names used in `body`, each with its canonical name.
+    pub(crate) fn legacy_field_names(&self, body: &Value) -> Vec<(&'static str, &'static str)> {
+        match self {
+            RequestType::Register(_) => RegisterRequest::legacy_field_names(body),
+            RequestType::SendMessage(_) => SendMessageRequest::legacy_field_names(body),
+            RequestType::GetPrekeyBundle(_) => GetPreKeyBundleRequest::legacy_field_names(body),
+            RequestType::Rekey(_) => RekeyRequest::legacy_field_names(body),
+            RequestType::RelayFilter(_) => RelayFilterRequest::legacy_field_names(body),
+            RequestType::UploadPreKeys(_) => UploadPreKeysRequest::legacy_field_names(body),
+            RequestType::UploadSignedPreKey(_) => UploadSignedPreKeyRequest::legacy_field_names(body),
+            RequestType::ServerInfo(_) => ServerInfoRequest::legacy_field_names(body),
+            RequestType::GetPresence(_) => GetPresenceRequest::legacy_field_names(body),
+        }
+    }
+
+    /// Whether `body` has fields that were not read into the request.
+    fn has_unknown_fields(&self, body: &Value) -> bool {
+        match self {
+            RequestType::Register(request) => has_unknown_fields(request, body),
+            RequestType::SendMessage(request) => has_unknown_fields(request, body),
+            RequestType::GetPrekeyBundle(request) => has_unknown_fields(request, body),
+            RequestType::Rekey(request) => has_unknown_fields(request, body),
+            RequestType::RelayFilter(request) => has_unknown_fields(request, body),
+            RequestType::UploadPreKeys(request) => has_unknown_fields(request, body),
+            RequestType::UploadSignedPreKey(request) => has_unknown_fields(request, body),
+            RequestType::ServerInfo(request) => has_unknown_fields(request, body),
+            RequestType::GetPresence(request) => has_unknown_fields(request, body),
+        }
+    }
 }
@@ -0,0 +1,175 @@
+//! Accent colours and avatar glyphs that tell chats apart at a glance.
+//!
+//! Both are derived from the fingerprint of the friend's identity key, so a friend looks the same
+//! across sessions and devices, and a friend whose identity key changed looks different: a weak
+//! visual cue, not a substitute for comparing fingerprints.
+
+use ratatui::style::Color;
+use sha2::{Digest, Sha256};
+
+/// The minimum contrast ratio of an accent against the background when the configuration sets none,
+/// the WCAG level for normal text.
+pub(crate) const DEFAULT_ACCENT_CONTRAST: f64 = 4.5;
+
+/// The background the accents are drawn on, see [`crate::ui::render`].
+const BACKGROUND: [u8; 3] = [31, 29, 46];
+
+/// The characters of the avatar glyphs, without those easily mistaken for one another (0/O, 1/I/L).
+const GLYPH_ALPHABET: &[u8] = b"ABCDEFGHJKMNPQRSTUVWXYZ23456789";
+
+/// The levels of each channel in the 6×6×6 colour cube of the 256-colour terminal palette.
+const CUBE_LEVELS: [u8; 6] = [0, 95, 135, 175, 215, 255];
+
+/// How the chat with a friend is told apart from the others.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Accent {
+    /// The colour of the chat's entry in the list, of its title and of the friend's messages prefix.
+    pub(crate) color: Color,
+    /// Two characters standing for the friend.
+    pub(crate) glyph: String,
+}
+
+/// Derives the accent of the friend whose identity key has the hex `fingerprint`.
+///
+/// The fingerprint picks a hue, and a saturation and lightness within a range that reads well on the
+/// dark background. The colour drawn is the nearest one of the 256-colour palette whose contrast
+/// ratio against the background is at least `min_contrast`, or white if none is.
+pub(crate) fn accent(fingerprint: &str, min_contrast: f64) -> Accent {
+    let bytes = fingerprint_bytes(fingerprint);
+    let hue = f64::from(u16::from_be_bytes([bytes[0], bytes[1]]) % 360);
+    let saturation = 0.45 + 0.35 * f64::from(bytes[2]) / 255.0;
+    let lightness = 0.55 + 0.20 * f64::from(bytes[3]) / 255.0;
+    let glyph = [bytes[4], bytes[5]]
+        .iter()
+        .map(|b| GLYPH_ALPHABET[*b as usize % GLYPH_ALPHABET.len()] as char)
+        .collect();
+    Accent {
+        color: nearest_palette_color(hsl_to_rgb(hue, saturation, lightness), min_contrast),
+        glyph,
+    }
+}
+
+/// The bytes of a hex fingerprint, or a hash of it if it is not one.
+fn fingerprint_bytes(fingerprint: &str) -> Vec<u8> {
+    let decoded = (0..fingerprint.len())
+        .step_by(2)
+        .map(|i| fingerprint.get(i..i + 2).and_then(|pair| u8::from_str_radix(pair, 16).ok()))
+        .collect::<Option<Vec<u8>>>();
+    match decoded {
+        Some(bytes) if bytes.len() >= 6 => bytes,
+        _ => Sha256::digest(fingerprint.as_bytes()).to_vec(),
+    }
+}
+
+/// Converts a colour from HSL, the hue in degrees and the rest in `0.0..=1.0`, to RGB.
+fn hsl_to_rgb(hue: f64, saturation: f64, lightness: f64) -> [u8; 3] {
+    let chroma = (1.0 - (2.0 * lightness - 1.0).abs()) * saturation;
+    let h = hue / 60.0;
+    let x = chroma * (1.0 - (h % 2.0 - 1.0).abs());
+    let (r, g, b) = match h as u32 {
+        0 => (chroma, x, 0.0),
+        1 => (x, chroma, 0.0),
+        2 => (0.0, chroma, x),
+        3 => (0.0, x, chroma),
+        4 => (x, 0.0, chroma),
+        _ => (chroma, 0.0, x),
+    };
+    let m = lightness - chroma / 2.0;
+    [r, g, b].map(|c| ((c + m) * 255.0).round() as u8)
+}
+
+/// The RGB value of each colour of the 256-colour palette past the 16 the terminal theme defines.
+fn palette() -> impl Iterator<Item = (u8, [u8; 3])> {
+    let cube = (0..216u16).map(|i| {
+        let level = |n: u16| CUBE_LEVELS[(n % 6) as usize];
+        (16 + i as u8, [level(i / 36), level(i / 6), level(i)])
+    });
+    let grays = (0..24u8).map(|i| (232 + i, [8 + 10 * i; 3]));
+    cube.chain(grays)
+}
+
+/// The palette colour nearest to `rgb` among those with at least `min_contrast` against the background.
+fn nearest_palette_color(rgb: [u8; 3], min_contrast: f64) -> Color {
+    let distance = |other: [u8; 3]| -> i32 {
+        rgb.iter().zip(other).map(|(a, b)| (i32::from(*a) - i32::from(b)).pow(2)).sum()
+    };
+    palette()
+        .filter(|(_, other)| contrast(*other, BACKGROUND) >= min_contrast)
+        .min_by_key(|(_, other)| distance(*other))
+        .map_or(Color::White, |(index, _)| Color::Indexed(index))
+}
+
+/// The WCAG contrast ratio of two colours, from 1 to 21.
+fn contrast(a: [u8; 3], b: [u8; 3]) -> f64 {
+    let (a, b) = (luminance(a), luminance(b));
+    (a.max(b) + 0.05) / (a.min(b) + 0.05)
+}
+
+/// The WCAG relative luminance of an sRGB colour.
+fn luminance(rgb: [u8; 3]) -> f64 {
+    let [r, g, b] = rgb.map(|c| {
+        let c = f64::from(c) / 255.0;
+        if c <= 0.03928 { c / 12.92 } else { ((c + 0.055) / 1.055).powf(2.4) }
+    });
+    0.2126 * r + 0.7152 * g + 0.0722 * b
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rgb_of(color: Color) -> [u8; 3] {
+        match color {
+            Color::Indexed(index) => palette().find(|(i, _)| *i == index).unwrap().1,
+            Color::White => [255; 3],
+            other => panic!("unexpected colour {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_accent_is_pinned_for_fixed_fingerprints() {
+        let pinned = [
+            ("00".repeat(32), 137, "AA"),
+            ("ff".repeat(32), 216, "HH"),
+            ("5a".repeat(32), 149, "77"),
+            ("123456789abcdef0".repeat(4), 168, "9C"),
+        ];
+        for (fingerprint, color, glyph) in pinned {
+            let expected = Accent { color: Color::Indexed(color), glyph: glyph.to_string() };
+            assert_eq!(accent(&fingerprint, DEFAULT_ACCENT_CONTRAST), expected);
+        }
+    }
+
+    #[test]
+    fn test_accent_is_deterministic_and_tells_friends_apart() {
+        let alice = Sha256::digest(b"alice").iter().map(|b| format!("{:02x}", b)).collect::<String>();
+        let bob = Sha256::digest(b"bob").iter().map(|b| format!("{:02x}", b)).collect::<String>();
+        assert_eq!(accent(&alice, DEFAULT_ACCENT_CONTRAST), accent(&alice, DEFAULT_ACCENT_CONTRAST));
+        assert_ne!(accent(&alice, DEFAULT_ACCENT_CONTRAST), accent(&bob, DEFAULT_ACCENT_CONTRAST));
+        // Anything but a hex fingerprint is hashed rather than refused
+        assert_eq!(accent("not hex", DEFAULT_ACCENT_CONTRAST), accent("not hex", DEFAULT_ACCENT_CONTRAST));
+    }
+
+    #[test]
+    fn test_accent_meets_the_contrast_floor() {
+        for floor in [1.0, 3.0, DEFAULT_ACCENT_CONTRAST, 7.0, 12.0] {
+            for seed in 0..64u8 {
+                let fingerprint = Sha256::digest([seed]).iter().map(|b| format!("{:02x}", b)).collect::<String>();
+                let accent = accent(&fingerprint, floor);
+                assert!(contrast(rgb_of(accent.color), BACKGROUND) >= floor);
+                assert_eq!(accent.glyph.len(), 2);
+                assert!(accent.glyph.bytes().all(|c| GLYPH_ALPHABET.contains(&c)));
+            }
+        }
+        // A floor no colour meets falls back to white
+        assert_eq!(accent(&"00".repeat(32), 22.0).color, Color::White);
+    }
+
+    #[test]
+    fn test_hsl_to_rgb() {
+        assert_eq!(hsl_to_rgb(0.0, 1.0, 0.5), [255, 0, 0]);
+        assert_eq!(hsl_to_rgb(120.0, 1.0, 0.5), [0, 255, 0]);
+        assert_eq!(hsl_to_rgb(240.0, 1.0, 0.5), [0, 0, 255]);
+        assert_eq!(hsl_to_rgb(0.0, 0.0, 1.0), [255, 255, 255]);
+    }
+}
@@ -1,18 +1,45 @@
+use std::collections::HashMap;
 use std::error;
+use std::time::{Duration, Instant};
 use tokio::sync::RwLock;
 use std::sync::Arc;
-use client::{ChatMessage, Client};
+use client::{ChatMessage, Client, Connection};
+use client::errors::ClientError;
+use common::ServerInfo;
+use crate::accent::DEFAULT_ACCENT_CONTRAST;
 use crate::errors::TuiError;
+use crate::lock::{InactivityLock, LockSecret};
 
 // Application result type
 pub type AppResult<T> = Result<T, Box<dyn error::Error>>;
 
+/// Minimum time between two typing notifications sent to the same friend.
+pub(crate) const TYPING_DEBOUNCE: Duration = Duration::from_secs(2);
+/// How long a friend is shown as typing after their last notification.
+pub(crate) const TYPING_TIMEOUT: Duration = Duration::from_secs(5);
+/// How often the server is asked which friends are online.
+pub(crate) const PRESENCE_INTERVAL: Duration = Duration::from_secs(30);
+/// Sent in a chat, rotates our ratchet key with the friend instead of sending a message.
+pub(crate) const REKEY_COMMAND: &str = ":rekey";
+/// Sent in a chat followed by a path, sends the file at that path to the friend.
+pub(crate) const SEND_FILE_COMMAND: &str = ":send ";
+/// Sent in a chat followed by a name and usernames, creates a group chat with those users.
+pub(crate) const GROUP_COMMAND: &str = ":group ";
+/// Rows of history scrolled by PageUp and PageDown.
+pub(crate) const SCROLL_PAGE: usize = 10;
+/// Rows of history scrolled by a turn of the mouse wheel.
+pub(crate) const SCROLL_LINES: usize = 3;
+/// Where the files sent by friends are written if the configuration names no directory.
+pub(crate) const DEFAULT_DOWNLOAD_DIR: &str = "./downloads";
+
 #[derive(Debug, Clone, Copy, Default)]
 pub(crate) enum AppState {
     #[default]
     Register,
 
     Chats,
+
+    Locked,
 }
 
 impl PartialEq for AppState {
@@ -20,6 +47,7 @@ impl PartialEq for AppState {
         match (self, other) {
             (AppState::Register, AppState::Register) => true,
             (AppState::Chats, AppState::Chats) => true,
+            (AppState::Locked, AppState::Locked) => true,
             _ => false,
         }
     }
@@ -39,9 +67,37 @@ pub struct App {
     pub(crate) active_window: usize,
     pub(crate) selected_chat: usize,
     pub(crate) active_chat: usize,
+    /// The message selected in the history of the active chat, counted from the oldest.
+    pub(crate) selected_message: Option<usize>,
     pub(crate) show_popup: bool,
+    /// Whether the friend added from the popup gets an ephemeral chat.
+    pub(crate) incognito: bool,
+    pub(crate) show_diagnostics: bool,
+    /// Whether the encryption info of the selected message is shown.
+    pub(crate) show_message_info: bool,
+    /// The friend whose safety number is shown, to verify their identity key.
+    pub(crate) verifying: Option<String>,
+    pub(crate) server_info: Option<ServerInfo>,
     chat_listener: Option<tokio::task::JoinHandle<()>>,
     pub(crate) incoming_messages: Arc<RwLock<Vec<ChatMessage>>>,
+    pub(crate) lock: InactivityLock,
+    unlocked_state: AppState,
+    /// When each friend last told us they were typing.
+    pub(crate) typing: HashMap<String, Instant>,
+    /// The friend we last sent a typing notification to, and when.
+    pub(crate) typing_sent: Option<(String, Instant)>,
+    /// Whether the connection to the server was lost and is being opened again.
+    pub(crate) reconnecting: bool,
+    /// The new connection being opened in the background, see [`Client::dial`].
+    dialing: Option<tokio::task::JoinHandle<Result<Connection, ClientError>>>,
+    /// Whether each friend was online when the server was last asked.
+    pub(crate) presence: HashMap<String, bool>,
+    presence_checked: Option<Instant>,
+    /// Minimum contrast of the chat accents against the background, see [`crate::accent::accent`].
+    pub(crate) accent_contrast: f64,
+    /// The rows each history is scrolled up from its newest message, by friend or group id.
+    /// Chats left out follow new messages.
+    pub(crate) scroll: HashMap<String, usize>,
 
 
 }
@@ -63,7 +119,7 @@ impl PartialEq for InputMode {
 
 impl App {
 
-    pub(crate) fn new(client: Client, chat_rx: tokio::sync::mpsc::Receiver<ChatMessage>) -> Self {
+    pub(crate) fn new(client: Client, chat_rx: tokio::sync::mpsc::Receiver<ChatMessage>, lock: InactivityLock) -> Self {
         let mut app = Self {
             running: true,
             state: AppState::default(),
@@ -75,9 +131,25 @@ impl App {
             active_window: 0,
             selected_chat: 0,
             active_chat: 0,
+            selected_message: None,
             show_popup: false,
+            incognito: false,
+            show_diagnostics: false,
+            show_message_info: false,
+            verifying: None,
+            server_info: None,
             chat_listener: None,
             incoming_messages: Arc::new(RwLock::new(Vec::new())),
+            lock,
+            unlocked_state: AppState::default(),
+            typing: HashMap::new(),
+            typing_sent: None,
+            reconnecting: false,
+            dialing: None,
+            presence: HashMap::new(),
+            presence_checked: None,
+            accent_contrast: DEFAULT_ACCENT_CONTRAST,
+            scroll: HashMap::new(),
         };
 
         let incoming_messages = app.incoming_messages.clone();
@@ -91,6 +163,161 @@ impl App {
         for message in messages {
             self.handle_incoming_chat_message(message).await;
         }
+        if let Ok(closed) = self.client.close_idle_chats().await {
+            if !closed.is_empty() {
+                self.clamp_chat_selection();
+            }
+        }
+        if !self.client.is_connected() {
+            // The connection is opened in the background so the screen keeps being drawn meanwhile
+            self.reconnecting = true;
+            match self.dialing.take() {
+                None => self.dialing = Some(tokio::spawn(self.client.dial())),
+                Some(dialing) if !dialing.is_finished() => self.dialing = Some(dialing),
+                Some(dialing) => {
+                    let resumed = match dialing.await {
+                        Ok(Ok(connection)) => self.client.resume(connection).await,
+                        Ok(Err(e)) => Err(e),
+                        Err(e) => Err(ClientError::GenericError(e.to_string())),
+                    };
+                    match resumed {
+                        Ok(()) => self.reconnecting = false,
+                        Err(e) => log::error!("Failed to reconnect: {}", e),
+                    }
+                }
+            }
+        } else {
+            if let Err(e) = self.client.rotate_session_keys_if_due().await {
+                log::error!("Failed to rotate session keys: {}", e);
+            }
+            if let Err(e) = self.client.rotate_signed_prekey_if_due().await {
+                log::error!("Failed to rotate the signed pre-key: {}", e);
+            }
+            self.refresh_presence(Instant::now()).await;
+        }
+        if self.lock.tick(Instant::now()) {
+            self.lock_screen();
+        }
+    }
+
+    /// Hides everything behind the lock screen. Messages keep being received while locked.
+    pub(crate) fn lock_screen(&mut self) {
+        self.lock.lock();
+        self.unlocked_state = self.state;
+        self.state = AppState::Locked;
+        self.show_popup = false;
+        self.show_diagnostics = false;
+        self.show_message_info = false;
+        self.verifying = None;
+        self.error = None;
+        self.input_mode = InputMode::Insert;
+        self.input.clear();
+        self.character_index = 0;
+    }
+
+    pub(crate) fn unlock_screen(&mut self) {
+        let now = Instant::now();
+        if let Some(wait) = self.lock.retry_in(now) {
+            self.error = Some(TuiError::RetryLater(wait.as_secs().max(1)));
+        } else if self.lock.unlock(&self.input, now) {
+            self.state = self.unlocked_state;
+            self.error = None;
+        } else if self.lock.secret() == LockSecret::Passphrase {
+            self.error = Some(TuiError::WrongPassphrase);
+        } else {
+            self.error = Some(TuiError::WrongPin);
+        }
+    }
+
+    /// Asks the server which friends are online, at most once every [`PRESENCE_INTERVAL`].
+    async fn refresh_presence(&mut self, now: Instant) {
+        if self.state != AppState::Chats
+            || self.presence_checked.is_some_and(|t| now.duration_since(t) < PRESENCE_INTERVAL) {
+            return;
+        }
+        let chats = self.client.get_open_chats();
+        if chats.is_empty() {
+            return;
+        }
+        self.presence_checked = Some(now);
+        match self.client.get_presence(chats).await {
+            Ok(presence) => self.presence = presence,
+            Err(e) => log::error!("Failed to get presence: {}", e),
+        }
+    }
+
+    /// Returns `true` if `friend` sent a typing notification in the last [`TYPING_TIMEOUT`].
+    pub(crate) fn is_typing(&self, friend: &str, now: Instant) -> bool {
+        self.typing.get(friend).is_some_and(|t| now.duration_since(*t) < TYPING_TIMEOUT)
+    }
+
+    /// Returns the labels of the chat list: the friends of the open chats, then the group chats.
+    pub(crate) fn chat_list(&self) -> Vec<String> {
+        let mut chats = self.client.get_open_chats();
+        for id in self.client.get_group_chats() {
+            if let Some(group) = self.client.get_group(&id) {
+                chats.push(format!("# {}", group.name));
+            }
+        }
+        chats
+    }
+
+    /// Returns the number of entries in the chat list.
+    pub(crate) fn chat_count(&self) -> usize {
+        self.client.get_friends_count() + self.client.get_group_chats().len()
+    }
+
+    /// Returns the id of the group chat at `index` in the chat list, `None` if it is the chat of a friend.
+    pub(crate) fn group_at(&self, index: usize) -> Option<String> {
+        let friends = self.client.get_friends_count();
+        index.checked_sub(friends).and_then(|i| self.client.get_group_chats().get(i).cloned())
+    }
+
+    /// Returns the friend or the group id of the active chat.
+    pub(crate) fn active_chat_key(&self) -> Option<String> {
+        self.group_at(self.active_chat).or_else(|| self.client.get_open_chats().get(self.active_chat).cloned())
+    }
+
+    /// Returns the rows the history of the active chat is scrolled up from its newest message.
+    pub(crate) fn scroll_offset(&self) -> usize {
+        self.active_chat_key().and_then(|key| self.scroll.get(&key).copied()).unwrap_or(0)
+    }
+
+    /// Scrolls the history of the active chat `rows` up (older) or down (newer). Scrolling down to the
+    /// newest message follows new messages again.
+    pub(crate) fn scroll_history(&mut self, up: bool, rows: usize) {
+        let Some(key) = self.active_chat_key() else { return };
+        let history = match self.group_at(self.active_chat) {
+            Some(group) => self.client.get_group_history(&group),
+            None => self.client.get_chat_history(&key),
+        };
+        // The widget stops at the oldest row anyway, this keeps the way back down short
+        let limit = history.map_or(0, |h| h.len());
+        let offset = self.scroll.get(&key).copied().unwrap_or(0);
+        let offset = match up {
+            true => offset.saturating_add(rows).min(limit),
+            false => offset.saturating_sub(rows),
+        };
+        match offset {
+            0 => self.scroll.remove(&key),
+            offset => self.scroll.insert(key, offset),
+        };
+    }
+
+    /// Keeps the rows shown in place when a message is added to the history of `key` while it is scrolled up.
+    pub(crate) fn keep_scroll_position(&mut self, key: &str) {
+        if let Some(offset) = self.scroll.get_mut(key) {
+            *offset += 1;
+        }
+    }
+
+    /// Keeps the selected and active chat indexes valid after chats have been removed.
+    pub(crate) fn clamp_chat_selection(&mut self) {
+        let last = self.chat_count().saturating_sub(1);
+        self.selected_chat = self.selected_chat.min(last);
+        self.active_chat = self.active_chat.min(last);
+        // The active chat may now be a different one
+        self.selected_message = None;
     }
 
     pub async fn quit(&mut self) {
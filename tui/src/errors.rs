@@ -1,10 +1,17 @@
 use std::fmt::{Display, Formatter};
 use client::errors::ClientError;
+use client::DeliveryStatus;
 
 pub(crate) enum TuiError {
     EmptyUsernameInput,
     ClientError(ClientError),
     InvalidUser(String),
+    WrongPin,
+    WrongPassphrase,
+    /// The lock refuses to be tried for this many more seconds, after a wrong secret.
+    RetryLater(u64),
+    /// A message the server did not hand to the friend named, with what it did instead.
+    Undelivered(String, DeliveryStatus),
 }
 
 impl Display for TuiError {
@@ -13,6 +20,11 @@ impl Display for TuiError {
             TuiError::EmptyUsernameInput => write!(f, "Username cannot be empty"),
             TuiError::ClientError(e) => write!(f, "{}", e),
             TuiError::InvalidUser(s) => write!(f, "{}", s),
+            TuiError::WrongPin => write!(f, "Wrong PIN"),
+            TuiError::WrongPassphrase => write!(f, "Wrong passphrase"),
+            TuiError::RetryLater(secs) => write!(f, "Too many attempts, try again in {}s", secs),
+            TuiError::Undelivered(friend, DeliveryStatus::Queued) => write!(f, "{} is offline, the message is sent when they connect", friend),
+            TuiError::Undelivered(friend, _) => write!(f, "{} does not take messages from you", friend),
         }
     }
 }
@@ -1,6 +1,6 @@
 use std::time::Duration;
 
-use crossterm::event::{Event as CrosstermEvent, KeyEvent};
+use crossterm::event::{Event as CrosstermEvent, KeyEvent, MouseEvent, MouseEventKind};
 use futures::{FutureExt, StreamExt};
 use tokio::sync::mpsc;
 
@@ -11,6 +11,8 @@ use crate::app::AppResult;
 pub enum Event {
     Tick,
     Key(KeyEvent),
+    /// A turn of the mouse wheel, the other mouse events are dropped.
+    Mouse(MouseEvent),
 }
 
 /// Terminal event handler.
@@ -51,7 +53,11 @@ impl EventHandler {
                                 _sender.send(Event::Key(key)).unwrap();
                                 }
                             },
-                            CrosstermEvent::Mouse(_mouse) => {},
+                            CrosstermEvent::Mouse(mouse) => {
+                                if matches!(mouse.kind, MouseEventKind::ScrollUp | MouseEventKind::ScrollDown) {
+                                    _sender.send(Event::Mouse(mouse)).unwrap();
+                                }
+                            },
                             CrosstermEvent::Resize(_x, _y) => {},
                             CrosstermEvent::FocusLost => {},
                             CrosstermEvent::FocusGained => {},
@@ -1,14 +1,32 @@
+use std::time::Instant;
 use chrono::{DateTime, Utc};
-use client::ChatMessage;
-use crate::app::{App, AppResult, AppState, InputMode};
-use crossterm::event::{KeyCode, KeyEvent, KeyEventKind};
+use client::{ChatMessage, DeliveryStatus};
+use client::errors::ClientError;
+use std::path::Path;
+use common::{validate_username, UsernameError, CONFIG, USERNAME_LENGTH};
+use crate::app::{App, AppResult, AppState, InputMode, DEFAULT_DOWNLOAD_DIR, GROUP_COMMAND, REKEY_COMMAND, SCROLL_LINES, SCROLL_PAGE, SEND_FILE_COMMAND, TYPING_DEBOUNCE};
+use crossterm::event::{KeyCode, KeyEvent, KeyEventKind, MouseEvent, MouseEventKind};
 use crate::errors::TuiError;
+use crate::lock::LockSecret;
 
 pub async fn handle_key_events(key: KeyEvent, app: &mut App) -> AppResult<()> {
+        app.lock.touch(Instant::now());
+
+        // The history scrolls in both modes, so that one can read back while writing
+        if app.state == AppState::Chats && !app.show_popup && key.kind == KeyEventKind::Press {
+            if let KeyCode::PageUp | KeyCode::PageDown = key.code {
+                app.scroll_history(key.code == KeyCode::PageUp, SCROLL_PAGE);
+                return Ok(());
+            }
+        }
 
         match app.input_mode {
 
             InputMode::Normal if key.kind == KeyEventKind::Press => match key.code {
+                // Only the keys of the safety number popup act while it is shown, not those of the chats beneath
+                code if app.state == AppState::Chats && app.verifying.is_some()
+                    && !matches!(code, KeyCode::Char('s') | KeyCode::Char('v') | KeyCode::Esc) => {},
+
                 KeyCode::Char('i') => {
                     app.input_mode = InputMode::Insert;
                     app.input.clear();
@@ -22,6 +40,7 @@ pub async fn handle_key_events(key: KeyEvent, app: &mut App) -> AppResult<()> {
 
                 KeyCode::Char('a') | KeyCode::Char('/') if app.state == AppState::Chats => {
                     app.show_popup = !app.show_popup;
+                    app.incognito = false;
                     app.input_mode = InputMode::Insert;
                     app.error = None;
                     app.input.clear();
@@ -31,6 +50,7 @@ pub async fn handle_key_events(key: KeyEvent, app: &mut App) -> AppResult<()> {
                 KeyCode::Left | KeyCode::Char('h') if app.state == AppState::Chats => {
                     if !app.show_popup {
                         app.active_window = 0;
+                        app.selected_message = None;
                     }
                 },
 
@@ -42,21 +62,115 @@ pub async fn handle_key_events(key: KeyEvent, app: &mut App) -> AppResult<()> {
 
                 KeyCode::Down | KeyCode::Char('j') if app.state == AppState::Chats && app.active_window == 0 => {
                     if !app.show_popup {
-                        app.selected_chat = (app.selected_chat + 1) % app.client.get_friends_count(); //app.client.friends.len();
+                        app.selected_chat = (app.selected_chat + 1) % app.chat_count().max(1);
                     }
 
                 },
 
                 KeyCode::Up | KeyCode::Char('k') if app.state == AppState::Chats && app.active_window == 0 => {
                     if !app.show_popup {
-                        app.selected_chat = (app.selected_chat  + app.client.get_friends_count() - 1) % app.client.get_friends_count(); //app.client.friends.len();
+                        let count = app.chat_count().max(1);
+                        app.selected_chat = (app.selected_chat + count - 1) % count;
+                    }
+                },
+
+                KeyCode::Down | KeyCode::Char('j') if app.state == AppState::Chats && app.active_window == 1 => {
+                    if !app.show_popup {
+                        app.select_message(false);
                     }
                 },
 
+                KeyCode::Up | KeyCode::Char('k') if app.state == AppState::Chats && app.active_window == 1 => {
+                    if !app.show_popup {
+                        app.select_message(true);
+                    }
+                },
+
+                KeyCode::Delete if app.state == AppState::Chats && !app.show_popup && app.selected_message.is_some() => {
+                    app.delete_selected_message();
+                },
+
+                KeyCode::Char('e') if app.state == AppState::Chats && !app.show_popup && app.selected_message.is_some() => {
+                    app.show_message_info = !app.show_message_info;
+                },
+
+                KeyCode::Esc if app.state == AppState::Chats && app.show_message_info => {
+                    app.show_message_info = false;
+                },
+
+                KeyCode::Esc if app.state == AppState::Chats && app.selected_message.is_some() => {
+                    app.selected_message = None;
+                },
+
+                KeyCode::Char('x') if app.state == AppState::Chats && app.active_window == 0 => {
+                    if let Some(chat) = app.client.get_open_chats().get(app.selected_chat).cloned().filter(|_| !app.show_popup) {
+                        let enabled = app.client.is_auto_close(&chat);
+                        app.client.set_auto_close(&chat, !enabled).ok();
+                    }
+                },
+
+                KeyCode::Char('m') if app.state == AppState::Chats && app.active_window == 0 => {
+                    if let Some(chat) = app.client.get_open_chats().get(app.selected_chat).cloned().filter(|_| !app.show_popup) {
+                        let muted = app.client.is_muted(&chat);
+                        app.client.set_muted(&chat, !muted).ok();
+                    }
+                },
+
+                KeyCode::Char('b') if app.state == AppState::Chats && app.active_window == 0 => {
+                    if let Some(chat) = app.client.get_open_chats().get(app.selected_chat).cloned().filter(|_| !app.show_popup) {
+                        if app.client.is_blocked(&chat) {
+                            app.client.unblock_user(&chat);
+                        } else {
+                            app.client.block_user(chat);
+                        }
+                    }
+                },
+
+                KeyCode::Char('r') if app.state == AppState::Chats && app.active_window == 0 => {
+                    if let Some(chat) = app.client.get_open_chats().get(app.selected_chat).cloned().filter(|_| !app.show_popup) {
+                        if let Err(e) = app.client.reset_session(&chat).await {
+                            app.error = Some(TuiError::from(e));
+                        }
+                    }
+                },
+
+                KeyCode::Char('d') if app.state == AppState::Chats && !app.show_popup => {
+                    app.show_diagnostics = !app.show_diagnostics;
+                    if app.show_diagnostics {
+                        app.server_info = app.client.server_info().await.ok();
+                    }
+                },
+
+                KeyCode::Esc if app.state == AppState::Chats && app.show_diagnostics => {
+                    app.show_diagnostics = false;
+                },
+
+                KeyCode::Char('s') if app.state == AppState::Chats && app.active_window == 0 && !app.show_popup => {
+                    app.verifying = match app.verifying {
+                        Some(_) => None,
+                        None => app.client.get_open_chats().get(app.selected_chat).cloned(),
+                    };
+                },
+
+                KeyCode::Char('v') if app.state == AppState::Chats && app.verifying.is_some() => {
+                    if let Some(friend) = &app.verifying {
+                        let verified = app.client.friend_info(friend).is_some_and(|info| info.verified);
+                        app.client.set_verified(friend, !verified).ok();
+                    }
+                },
+
+                KeyCode::Esc if app.state == AppState::Chats && app.verifying.is_some() => {
+                    app.verifying = None;
+                },
+
                 KeyCode::Esc if app.state == AppState::Chats && app.show_popup => {
                     app.show_popup = false;
                 },
 
+                KeyCode::Esc if app.state == AppState::Chats && app.error.is_some() => {
+                    app.error = None;
+                },
+
                 KeyCode::Enter if app.state == AppState::Chats && !app.show_popup => {
                     app.submit_message().await;
 
@@ -72,13 +186,20 @@ pub async fn handle_key_events(key: KeyEvent, app: &mut App) -> AppResult<()> {
                         app.active_window == 0 {
                         return Ok(());
                     }
-                    app.enter_char(to_insert)
+                    app.enter_char(to_insert);
+                    app.notify_typing(Instant::now()).await;
                 },
                 KeyCode::Enter => app.submit_message().await,
-                KeyCode::Backspace => app.delete_char(),
+                KeyCode::Tab if app.state == AppState::Chats && app.show_popup => {
+                    app.incognito = !app.incognito;
+                },
+                KeyCode::Backspace => {
+                    app.delete_char();
+                    app.notify_typing(Instant::now()).await;
+                },
                 KeyCode::Left => app.move_cursor_left(),
                 KeyCode::Right => app.move_cursor_right(),
-                KeyCode::Esc => app.input_mode = InputMode::Normal,
+                KeyCode::Esc if app.state != AppState::Locked => app.input_mode = InputMode::Normal,
                 _ => {}
             },
 
@@ -89,6 +210,19 @@ pub async fn handle_key_events(key: KeyEvent, app: &mut App) -> AppResult<()> {
     Ok(())
 }
 
+/// Scrolls the history of the active chat with the mouse wheel.
+pub fn handle_mouse_events(mouse: MouseEvent, app: &mut App) -> AppResult<()> {
+    if app.state != AppState::Chats || app.show_popup {
+        return Ok(());
+    }
+    match mouse.kind {
+        MouseEventKind::ScrollUp => app.scroll_history(true, SCROLL_LINES),
+        MouseEventKind::ScrollDown => app.scroll_history(false, SCROLL_LINES),
+        _ => {}
+    }
+    Ok(())
+}
+
 
 impl App {
     pub(crate) fn move_cursor_left(&mut self) {
@@ -111,15 +245,21 @@ impl App {
         if self.input_mode == InputMode::Insert {
             match self.state {
                 AppState::Register => {
-                    if new_char.is_whitespace() || !new_char.is_ascii_alphanumeric() {
-                        return; // Disallow whitespace and non-alphanumeric characters in Register state
+                    if !self.may_become_username(new_char) {
+                        return; // Disallow what the registration would refuse
                     }
                 },
                 AppState::Chats => {
-                    if self.show_popup && (new_char.is_whitespace() || !new_char.is_ascii_alphanumeric()) {
+                    if self.show_popup && !self.may_become_username(new_char) {
                         return; // Restrict input when popup is shown in Chats state
                     }
                 },
+                AppState::Locked => {
+                    // Passphrases may have spaces, PINs not
+                    if new_char.is_whitespace() && self.lock.secret() != LockSecret::Passphrase {
+                        return;
+                    }
+                },
             }
 
             let index = self.byte_index(); // Get byte index corresponding to the cursor position
@@ -128,6 +268,21 @@ impl App {
         }
     }
 
+    /// Whether typing `new_char` at the cursor leaves a valid username, or one that is only too short yet,
+    /// so the input follows the same rules as [`validate_username`].
+    fn may_become_username(&self, new_char: char) -> bool {
+        if new_char.is_whitespace() {
+            return false;
+        }
+        let mut candidate = self.input.clone();
+        candidate.insert(self.byte_index(), new_char);
+        match validate_username(&candidate) {
+            Ok(()) => true,
+            Err(UsernameError::InvalidLength(n)) => n < *USERNAME_LENGTH.start(),
+            Err(_) => false,
+        }
+    }
+
     /// Returns the byte index based on the character position.
     ///
     /// Since each character in a string can be contained multiple bytes, it's necessary to calculate
@@ -174,6 +329,7 @@ impl App {
     pub(crate) async fn submit_message(&mut self) {
         // self.messages.push(self.input.clone());
         match self.state {
+            AppState::Locked => self.unlock_screen(),
             AppState::Register => {
 
                 if self.input.is_empty() {
@@ -197,6 +353,14 @@ impl App {
                         if !self.show_popup {
                             if self.active_window == 0 {
                                 self.active_chat = self.selected_chat;
+                                self.selected_message = None;
+                                if let Some(chat) = self.client.get_open_chats().get(self.active_chat).cloned() {
+                                    if let Err(e) = self.client.send_read_receipts(&chat).await {
+                                        self.error = Some(TuiError::from(e));
+                                    }
+                                } else if let Some(group) = self.group_at(self.active_chat) {
+                                    self.client.mark_group_read(&group);
+                                }
                             }
                         }
                     },
@@ -208,7 +372,7 @@ impl App {
                                 return;
                             }
 
-                            match self.client.get_user_prekey_bundle(self.input.clone()).await {
+                            match self.client.open_chat(self.input.clone(), self.incognito).await {
                                 Ok(_) => {
                                     self.show_popup = false;
                                 },
@@ -216,6 +380,33 @@ impl App {
                                     self.error = Some(TuiError::from(e));
                                 }
                             }
+                        } else if self.active_window == 1 && self.input.trim() == REKEY_COMMAND {
+                            if let Some(friend) = self.client.get_open_chats().get(self.active_chat).cloned() {
+                                if let Err(e) = self.client.rekey_session(&friend).await {
+                                    self.error = Some(TuiError::from(e));
+                                }
+                            }
+                        } else if let Some(args) = self.input.trim().strip_prefix(GROUP_COMMAND).filter(|_| self.active_window == 1) {
+                            let mut args = args.split_whitespace().map(str::to_string);
+                            if let Some(name) = args.next() {
+                                if let Err(e) = self.client.create_group(name, args.collect()).await {
+                                    self.error = Some(TuiError::from(e));
+                                }
+                            }
+                        } else if let Some(path) = self.input.trim().strip_prefix(SEND_FILE_COMMAND).filter(|_| self.active_window == 1) {
+                            if let Some(friend) = self.client.get_open_chats().get(self.active_chat).cloned() {
+                                self.error = match self.client.send_file(&friend, Path::new(path.trim())).await {
+                                    Ok(DeliveryStatus::Delivered) => None,
+                                    Ok(status) => Some(TuiError::Undelivered(friend, status)),
+                                    Err(e) => Some(TuiError::from(e)),
+                                };
+                            }
+                        } else if let Some(group) = self.group_at(self.active_chat).filter(|_| self.active_window == 1 && !self.input.is_empty()) {
+                            if let Err(e) = self.client.send_group_message(&group, self.input.clone()).await {
+                                self.error = Some(TuiError::from(e));
+                            }
+                            // Our own message is shown at once
+                            self.scroll.remove(&group);
                         } else {
                             if self.active_window == 1 && !self.input.is_empty() {
 
@@ -227,8 +418,13 @@ impl App {
                                     DateTime::from(Utc::now()), // timestamp
                                 );
 
-                                self.client.send_chat_message(message.clone()).await.expect("Failed to send message");
-                                self.client.add_chat_message(message.clone(), &message.to);
+                                let to = message.to.clone();
+                                self.scroll.remove(&to);
+                                self.error = match self.client.send_and_store_chat_message(message).await {
+                                    Ok(DeliveryStatus::Delivered) => None,
+                                    Ok(status) => Some(TuiError::Undelivered(to, status)),
+                                    Err(e) => Some(TuiError::from(e)),
+                                };
                                 self.input.clear();
                                 self.reset_cursor();
                             }
@@ -241,17 +437,134 @@ impl App {
         self.reset_cursor();
     }
 
+    /// Tells the friend of the active chat that we are editing a message, at most once per [`TYPING_DEBOUNCE`].
+    pub(crate) async fn notify_typing(&mut self, now: Instant) {
+        if self.state != AppState::Chats || self.show_popup || self.active_window != 1 || self.input.is_empty() {
+            return;
+        }
+        let Some(chat) = self.client.get_open_chats().get(self.active_chat).cloned() else { return };
+        if let Some((last, at)) = &self.typing_sent {
+            if *last == chat && now.duration_since(*at) < TYPING_DEBOUNCE {
+                return;
+            }
+        }
+        self.typing_sent = Some((chat.clone(), now));
+        if let Err(e) = self.client.send_typing(&chat).await {
+            log::error!("Failed to send typing notification: {}", e);
+        }
+    }
+
+    /// Moves the message selection one message up (older) or down (newer) in the active chat.
+    /// Moving up from no selection selects the newest message, moving down past it clears the selection.
+    pub(crate) fn select_message(&mut self, up: bool) {
+        let Some(chat) = self.client.get_open_chats().get(self.active_chat).cloned() else { return };
+        let count = self.client.get_chat_history(&chat).map_or(0, |h| h.len());
+        self.selected_message = match (self.selected_message, up) {
+            _ if count == 0 => None,
+            (None, true) => Some(count - 1),
+            (None, false) => None,
+            (Some(i), true) => Some(i.saturating_sub(1)),
+            (Some(i), false) if i + 1 < count => Some(i + 1),
+            (Some(_), false) => None,
+        };
+    }
+
+    /// Deletes the selected message from the history of the active chat.
+    pub(crate) fn delete_selected_message(&mut self) {
+        let Some(chat) = self.client.get_open_chats().get(self.active_chat).cloned() else { return };
+        let history = self.client.get_chat_history(&chat).unwrap_or_default();
+        if let Some(message) = self.selected_message.and_then(|i| history.get(i)) {
+            self.client.delete_message(&chat, &message.timestamp);
+        }
+        let count = history.len().saturating_sub(1);
+        self.selected_message = self.selected_message.filter(|_| count > 0).map(|i| i.min(count - 1));
+    }
+
     pub(crate) async fn handle_incoming_chat_message(&mut self, message: ChatMessage) {
+        // Blocked users go unnoticed, whatever they send: no handler sees their messages
+        if self.client.is_blocked(&message.from) {
+            return;
+        }
         match message.msg_type.as_str() {
             "initial_message" => {
-                self.client.add_friend(message).expect("Cannot add friend");
+                if let Err(e) = self.client.accept_initial_message(message).await {
+                    self.error = Some(TuiError::from(e));
+                }
+            },
+            "session_rejected" => {
+                if let Some(reason) = self.client.session_rejected(message) {
+                    self.error = Some(TuiError::from(ClientError::SessionRejected(reason)));
+                    self.clamp_chat_selection();
+                }
+            },
+            "chat_settings" => {
+                if let Err(e) = self.client.apply_chat_settings(message) {
+                    self.error = Some(TuiError::from(e));
+                }
+            },
+            "chat" | "group_chat" => {
+                let from = message.from.clone();
+                // The message they were typing has arrived
+                self.typing.remove(&from);
+                // Messages lost to a long gap are noted in the history, the user may then reset the session
+                let received = self.client.decrypt_chat_message(message);
+                if let Ok(chat) = &received {
+                    self.keep_scroll_position(chat);
+                }
+                let received = received.is_ok();
+                if received
+                    && self.state == AppState::Chats
+                    && self.client.get_open_chats().get(self.active_chat) == Some(&from) {
+                    if let Err(e) = self.client.send_read_receipts(&from).await {
+                        self.error = Some(TuiError::from(e));
+                    }
+                }
+            },
+            "file" => {
+                let from = message.from.clone();
+                let dir = CONFIG.get_download_dir().unwrap_or_else(|| DEFAULT_DOWNLOAD_DIR.to_string());
+                match self.client.receive_file_chunk(message, Path::new(&dir)) {
+                    // The file is added to the history once whole
+                    Ok(Some(_)) => self.keep_scroll_position(&from),
+                    Ok(None) => {},
+                    Err(e) => self.error = Some(TuiError::from(e)),
+                }
             },
-            "chat" => {
-                self.client.decrypt_chat_message(message).ok();
+            "read_receipt" => {
+                if let Err(e) = self.client.apply_read_receipt(message) {
+                    self.error = Some(TuiError::from(e));
+                }
+            },
+            "group_invite" => {
+                if let Err(e) = self.client.apply_group_invite(message) {
+                    self.error = Some(TuiError::from(e));
+                }
+            },
+            "rekey" => {
+                if let Err(e) = self.client.apply_rekey(message) {
+                    self.error = Some(TuiError::from(e));
+                }
+            },
+            "typing" => {
+                match self.client.apply_typing(message) {
+                    Ok(Some(from)) => {
+                        self.typing.insert(from, Instant::now());
+                    },
+                    Ok(None) => {},
+                    Err(e) => log::error!("Failed to read typing notification: {}", e),
+                }
             },
 
             "close_chat" => {
+                self.typing.remove(&message.from);
                 self.client.remove_friend(message.from);
+                self.clamp_chat_selection();
+            },
+            "replenish_prekeys" => {
+                let count = message.text.parse().unwrap_or(0);
+                if let Err(e) = self.client.upload_one_time_prekeys(count).await {
+                    self.error = Some(TuiError::from(e));
+                }
             },
             _ => {}
         }
@@ -0,0 +1,268 @@
+use std::time::{Duration, Instant};
+use argon2::Argon2;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use subtle::ConstantTimeEq;
+
+/// Byte size of the random salt the PIN is hashed with.
+const SALT_LENGTH: usize = 16;
+
+/// Time the lock refuses to be tried after the first wrong secret, doubled after each one that follows.
+const FIRST_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Longest time the lock refuses to be tried after a wrong secret.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(300);
+
+/// Locks the application after a period without key events.
+///
+/// The lock is opened with the passphrase of the saved state if there is one, see
+/// [`InactivityLock::with_passphrase`], and otherwise with a PIN the user chooses when the application
+/// starts. Only the Argon2id hash of the secret is kept, under a random salt, so that a memory dump does
+/// not give away a short PIN by a lookup. Each wrong secret makes the lock wait longer before the next try.
+pub(crate) struct InactivityLock {
+    timeout: Option<Duration>,
+    last_activity: Instant,
+    locked: bool,
+    secret: Option<PinHash>,
+    /// Whether `secret` is the passphrase of the saved state rather than a PIN.
+    passphrase: bool,
+    /// Wrong secrets entered since the lock was last opened.
+    failures: u32,
+    /// When the lock may be tried again after a wrong secret.
+    retry_at: Option<Instant>,
+}
+
+/// What the lock screen asks for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum LockSecret {
+    /// A PIN to be chosen, which opens the lock from then on.
+    NewPin,
+    Pin,
+    Passphrase,
+}
+
+/// The hash of the PIN and the salt it was hashed with.
+struct PinHash {
+    salt: [u8; SALT_LENGTH],
+    hash: [u8; 32],
+}
+
+impl PinHash {
+    /// Hashes `pin` under a random salt.
+    fn random(pin: &str) -> Self {
+        let mut salt = [0u8; SALT_LENGTH];
+        OsRng.fill_bytes(&mut salt);
+        Self::new(pin, salt)
+    }
+
+    fn new(pin: &str, salt: [u8; SALT_LENGTH]) -> Self {
+        let mut hash = [0u8; 32];
+        Argon2::default()
+            .hash_password_into(pin.as_bytes(), &salt, &mut hash)
+            .expect("the default parameters accept a 16-byte salt and a 32-byte hash");
+        Self { salt, hash }
+    }
+
+    /// Whether `pin` is the hashed one, compared in constant time.
+    fn matches(&self, pin: &str) -> bool {
+        PinHash::new(pin, self.salt).hash.ct_eq(&self.hash).into()
+    }
+}
+
+impl InactivityLock {
+    pub(crate) fn new(timeout: Option<Duration>, now: Instant) -> Self {
+        Self {
+            timeout,
+            last_activity: now,
+            locked: false,
+            secret: None,
+            passphrase: false,
+            failures: 0,
+            retry_at: None,
+        }
+    }
+
+    /// Opens the lock with `passphrase`, the one of the saved state, instead of a PIN.
+    pub(crate) fn with_passphrase(mut self, passphrase: &str) -> Self {
+        self.secret = Some(PinHash::random(passphrase));
+        self.passphrase = true;
+        self
+    }
+
+    /// Records user activity, postponing the lock.
+    pub(crate) fn touch(&mut self, now: Instant) {
+        self.last_activity = now;
+    }
+
+    /// Locks if the timeout has elapsed since the last activity. Returns `true` if it just locked.
+    pub(crate) fn tick(&mut self, now: Instant) -> bool {
+        match self.timeout {
+            Some(timeout) if !self.locked && now.duration_since(self.last_activity) >= timeout => {
+                self.locked = true;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Locks at once, such as when the application starts and a PIN is to be chosen.
+    pub(crate) fn lock(&mut self) {
+        self.locked = true;
+    }
+
+    pub(crate) fn is_locked(&self) -> bool {
+        self.locked
+    }
+
+    /// Returns `true` if the lock is enabled and no secret opens it yet, so the next unlock sets the PIN.
+    pub(crate) fn needs_pin(&self) -> bool {
+        self.timeout.is_some() && self.secret.is_none()
+    }
+
+    /// What the lock screen asks for.
+    pub(crate) fn secret(&self) -> LockSecret {
+        match &self.secret {
+            None => LockSecret::NewPin,
+            Some(_) if self.passphrase => LockSecret::Passphrase,
+            Some(_) => LockSecret::Pin,
+        }
+    }
+
+    /// How long the lock refuses to be tried, after a wrong secret.
+    pub(crate) fn retry_in(&self, now: Instant) -> Option<Duration> {
+        self.retry_at.filter(|at| *at > now).map(|at| at - now)
+    }
+
+    /// Unlocks if `secret` matches, or sets the PIN if none has been chosen yet.
+    ///
+    /// A wrong secret, and any secret tried before [`InactivityLock::retry_in`] has elapsed, is refused.
+    pub(crate) fn unlock(&mut self, secret: &str, now: Instant) -> bool {
+        if secret.is_empty() || self.retry_in(now).is_some() {
+            return false;
+        }
+        match &self.secret {
+            Some(expected) if !expected.matches(secret) => {
+                let delay = FIRST_RETRY_DELAY.saturating_mul(2u32.saturating_pow(self.failures)).min(MAX_RETRY_DELAY);
+                self.failures = self.failures.saturating_add(1);
+                self.retry_at = Some(now + delay);
+                false
+            }
+            _ => {
+                if self.secret.is_none() {
+                    self.secret = Some(PinHash::random(secret));
+                }
+                self.failures = 0;
+                self.retry_at = None;
+                self.locked = false;
+                self.last_activity = now;
+                true
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_locks_after_timeout() {
+        let start = Instant::now();
+        let mut lock = InactivityLock::new(Some(Duration::from_secs(60)), start);
+
+        assert!(!lock.tick(start + Duration::from_secs(30)));
+        lock.touch(start + Duration::from_secs(30));
+        assert!(!lock.tick(start + Duration::from_secs(80)));
+        assert!(lock.tick(start + Duration::from_secs(90)));
+        assert!(lock.is_locked());
+
+        // Already locked, further ticks are no-ops
+        assert!(!lock.tick(start + Duration::from_secs(200)));
+    }
+
+    #[test]
+    fn test_never_locks_without_timeout() {
+        let start = Instant::now();
+        let mut lock = InactivityLock::new(None, start);
+        assert!(!lock.tick(start + Duration::from_secs(3600)));
+        assert!(!lock.is_locked());
+    }
+
+    #[test]
+    fn test_first_unlock_sets_pin() {
+        let start = Instant::now();
+        let mut lock = InactivityLock::new(Some(Duration::from_secs(60)), start);
+        assert!(lock.needs_pin());
+        assert_eq!(lock.secret(), LockSecret::NewPin);
+        lock.lock();
+
+        assert!(!lock.unlock("", start));
+        assert!(lock.unlock("1234", start + Duration::from_secs(1)));
+        assert!(!lock.is_locked());
+        assert!(!lock.needs_pin());
+        assert_eq!(lock.secret(), LockSecret::Pin);
+
+        lock.tick(start + Duration::from_secs(61));
+        assert!(!lock.unlock("0000", start + Duration::from_secs(62)));
+        assert!(lock.is_locked());
+        assert!(lock.unlock("1234", start + Duration::from_secs(63)));
+    }
+
+    #[test]
+    fn test_no_pin_without_timeout() {
+        let lock = InactivityLock::new(None, Instant::now());
+        assert!(!lock.needs_pin());
+    }
+
+    #[test]
+    fn test_passphrase_opens_the_lock() {
+        let start = Instant::now();
+        let mut lock = InactivityLock::new(Some(Duration::from_secs(60)), start).with_passphrase("correct horse");
+        assert!(!lock.needs_pin());
+        assert_eq!(lock.secret(), LockSecret::Passphrase);
+
+        lock.tick(start + Duration::from_secs(60));
+        assert!(!lock.unlock("1234", start + Duration::from_secs(61)));
+        assert!(lock.is_locked());
+        assert!(lock.unlock("correct horse", start + Duration::from_secs(62)));
+        assert!(!lock.is_locked());
+    }
+
+    #[test]
+    fn test_wrong_secrets_delay_the_next_try() {
+        let start = Instant::now();
+        let mut lock = InactivityLock::new(Some(Duration::from_secs(60)), start).with_passphrase("secret");
+        lock.lock();
+
+        assert!(!lock.unlock("wrong", start));
+        assert_eq!(lock.retry_in(start), Some(FIRST_RETRY_DELAY));
+        // Even the right secret is refused until then, and does not count as a failure
+        assert!(!lock.unlock("secret", start + FIRST_RETRY_DELAY / 2));
+        assert!(!lock.unlock("wrong", start + FIRST_RETRY_DELAY));
+        assert_eq!(lock.retry_in(start + FIRST_RETRY_DELAY), Some(FIRST_RETRY_DELAY * 2));
+
+        // The delay keeps growing, up to its maximum
+        let mut now = start + FIRST_RETRY_DELAY * 3;
+        for _ in 0..16 {
+            assert!(!lock.unlock("wrong", now));
+            now += lock.retry_in(now).unwrap();
+        }
+        assert!(!lock.unlock("wrong", now));
+        assert_eq!(lock.retry_in(now), Some(MAX_RETRY_DELAY));
+
+        let end = now + MAX_RETRY_DELAY;
+        assert!(lock.unlock("secret", end));
+        assert_eq!(lock.retry_in(end), None);
+        lock.lock();
+        assert!(!lock.unlock("wrong", end));
+        assert_eq!(lock.retry_in(end), Some(FIRST_RETRY_DELAY));
+    }
+
+    #[test]
+    fn test_pin_hash_is_salted() {
+        let (first, second) = (PinHash::new("1234", [1; SALT_LENGTH]), PinHash::new("1234", [2; SALT_LENGTH]));
+        assert_ne!(first.hash, second.hash);
+        assert!(first.matches("1234") && second.matches("1234"));
+        assert!(!first.matches("12345"));
+    }
+}
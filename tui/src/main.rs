@@ -1,8 +1,13 @@
 #![allow(warnings)]
 use std::io;
-use client::Client;
+use std::path::PathBuf;
+use chrono::Duration;
+use client::{ChatMessage, Client, ConnectionState, RetentionPolicy};
+use client::errors::ClientError;
+use common::CONFIG;
 use ratatui::backend::CrosstermBackend;
 use ratatui::Terminal;
+use tokio::sync::watch;
 
 mod handler;
 mod app;
@@ -11,32 +16,96 @@ mod errors;
 pub mod event;
 mod tui;
 mod ui;
+mod lock;
+mod sanitize;
+mod accent;
+mod startup;
 
 use crate::app::{App, AppResult};
 use crate::event::{EventHandler, Event};
-use crate::handler::handle_key_events;
+use crate::handler::{handle_key_events, handle_mouse_events};
+use crate::lock::InactivityLock;
+use crate::startup::{landing_state, Startup, StartupAction};
 use crate::tui::Tui;
 
 #[tokio::main]
 async fn main() -> AppResult<()> {
 
-
-    // Init client
-    let (chat_tx, chat_rx) = tokio::sync::mpsc::channel(100);
-    let client = Client::new(chat_tx).await.unwrap_or_else(|_| {
-        eprintln!("Failed to establish the connection with the server");
-        std::process::exit(1);
-    });
-
-    // Init ratatui
+    // Init ratatui, so that progress is shown while connecting
     let backend = CrosstermBackend::new(io::stdout());
     let terminal = Terminal::new(backend)?;
     let events = EventHandler::new(250);
     let mut tui = Tui::new(terminal, events);
     tui.init()?;
 
+    // Init client
+    let (chat_tx, chat_rx) = tokio::sync::mpsc::channel(100);
+    let state = CONFIG.get_state_file()
+        .map(PathBuf::from)
+        .zip(std::env::var("CLIENT_STATE_PASSPHRASE").ok());
+    let saved_state = state.clone().filter(|(path, _)| path.exists());
+    let mut startup = Startup::new(saved_state.is_some());
+    let mut attempt = Some(tokio::spawn(start_client(chat_tx.clone(), saved_state.clone(), startup.attempt())));
+    let mut client = loop {
+        tui.draw_startup(&startup)?;
+        match tui.events.next().await? {
+            Event::Tick => startup.refresh(),
+            Event::Key(key_event) => match startup.handle_key_event(key_event) {
+                StartupAction::Retry => {
+                    attempt = Some(tokio::spawn(start_client(chat_tx.clone(), saved_state.clone(), startup.attempt())));
+                }
+                StartupAction::Quit => {
+                    if let Some(attempt) = attempt.take() {
+                        attempt.abort();
+                    }
+                    tui.exit()?;
+                    return Ok(());
+                }
+                StartupAction::None => {}
+            },
+            Event::Mouse(_) => {}
+        }
+        if attempt.as_ref().is_some_and(|attempt| attempt.is_finished()) {
+            // A failed attempt has reported why on the progress channel
+            match attempt.take().unwrap().await {
+                Ok(Ok(client)) => break client,
+                Ok(Err(_)) => {}
+                Err(e) => {
+                    // A panicked attempt leaves the terminal to be restored before reporting it
+                    tui.exit()?;
+                    return Err(e.into());
+                }
+            }
+        }
+    };
+    if let Some(timeout) = CONFIG.get_chat_idle_timeout() {
+        client.set_retention_policy(Some(RetentionPolicy::new(Duration::seconds(timeout as i64))));
+    }
+    if let Some(interval) = CONFIG.get_session_rotation_interval() {
+        client.set_session_rotation_interval(Some(Duration::seconds(interval as i64)));
+    }
+    if let Some(interval) = CONFIG.get_signed_prekey_rotation_interval() {
+        client.set_signed_prekey_rotation_interval(Some(Duration::seconds(interval as i64)));
+    }
+
     // Run app
-    let mut app = App::new(client, chat_rx);
+    let mut lock = InactivityLock::new(
+        CONFIG.get_lock_timeout().map(std::time::Duration::from_secs),
+        std::time::Instant::now(),
+    );
+    // The passphrase of the saved state opens the lock, otherwise a PIN is chosen right away
+    if let Some((_, passphrase)) = &state {
+        lock = lock.with_passphrase(passphrase);
+    }
+    let landing = landing_state(&client.username);
+    let mut app = App::new(client, chat_rx, lock);
+    app.state = landing;
+    if let Some(contrast) = CONFIG.get_accent_contrast() {
+        app.accent_contrast = contrast;
+    }
+    if app.lock.needs_pin() {
+        app.lock_screen();
+    }
 
     while app.running {
 
@@ -45,12 +114,29 @@ async fn main() -> AppResult<()> {
         match tui.events.next().await? {
             Event::Tick => app.tick().await,
             Event::Key(key_event) => handle_key_events(key_event, &mut app).await?,
-            //Event::Mouse(_) => {}
+            Event::Mouse(mouse_event) => handle_mouse_events(mouse_event, &mut app)?,
             //Event::Resize(_, _) => {}
         }
         
     }
 
     tui.exit()?;
+    if let Some((path, passphrase)) = &state {
+        if let Err(e) = app.client.save_state(path, passphrase) {
+            eprintln!("{}", e);
+        }
+    }
     Ok(())
-}
\ No newline at end of file
+}
+
+/// Connects to the server, restoring the chats of `saved_state` if any, and reports each stage on `progress`.
+async fn start_client(
+    chat_tx: tokio::sync::mpsc::Sender<ChatMessage>,
+    saved_state: Option<(PathBuf, String)>,
+    progress: watch::Sender<ConnectionState>,
+) -> Result<Client, ClientError> {
+    match &saved_state {
+        Some((path, passphrase)) => Client::with_state_and_progress(chat_tx, path, passphrase, &progress).await,
+        None => Client::new_with_progress(chat_tx, &progress).await,
+    }
+}
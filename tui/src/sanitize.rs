@@ -0,0 +1,53 @@
+/// Returns a form of a peer-supplied `text` that is safe to render in the terminal.
+///
+/// Control characters (including the ESC that starts ANSI sequences) and Unicode
+/// characters that can reorder or hide text (bidi overrides, zero-width characters,
+/// line separators) are replaced by a visible `\u{..}` escape, so nothing reaches the
+/// terminal as raw bytes and the user can still see that something was sent.
+pub(crate) fn sanitize(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        if c == '\t' {
+            out.push(' ');
+        } else if c.is_control() || is_invisible_format(c) {
+            out.push_str(&format!("\\u{{{:x}}}", c as u32));
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+fn is_invisible_format(c: char) -> bool {
+    matches!(c,
+        '\u{200b}'..='\u{200f}' // zero-width and directional marks
+        | '\u{2028}'..='\u{202e}' // line/paragraph separators and bidi embeddings
+        | '\u{2060}'..='\u{2069}' // word joiner and bidi isolates
+        | '\u{feff}' // zero-width no-break space
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ansi_escapes_are_not_emitted() {
+        let text = "\u{1b}[2J\u{1b}[31mhello\u{1b}[0m\u{7}";
+        let safe = sanitize(text);
+        assert!(!safe.chars().any(|c| c.is_control()));
+        assert_eq!(safe, "\\u{1b}[2J\\u{1b}[31mhello\\u{1b}[0m\\u{7}");
+    }
+
+    #[test]
+    fn test_bidi_and_zero_width_are_escaped() {
+        assert_eq!(sanitize("abc\u{202e}fed\u{200b}"), "abc\\u{202e}fed\\u{200b}");
+        assert_eq!(sanitize("a\nb\tc"), "a\\u{a}b c");
+    }
+
+    #[test]
+    fn test_plain_text_is_unchanged() {
+        let text = "ciao, come stai? 😀 àèìòù";
+        assert_eq!(sanitize(text), text);
+    }
+}
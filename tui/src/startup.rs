@@ -0,0 +1,120 @@
+use client::ConnectionState;
+use crossterm::event::{KeyCode, KeyEvent};
+use tokio::sync::watch;
+use crate::app::AppState;
+
+/// What the user asked for on the loading screen.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum StartupAction {
+    None,
+    Retry,
+    Quit,
+}
+
+/// The loading screen shown while the client connects, before the [`crate::app::App`] exists.
+///
+/// Each attempt reports its progress on a watch channel, and a failed attempt can be retried.
+pub(crate) struct Startup {
+    progress: watch::Receiver<ConnectionState>,
+    pub(crate) state: ConnectionState,
+    /// Whether the chats saved on disk are loaded, which adds a stage.
+    pub(crate) loads_state: bool,
+}
+
+impl Startup {
+    pub(crate) fn new(loads_state: bool) -> Self {
+        Self {
+            progress: watch::channel(ConnectionState::Connecting).1,
+            state: ConnectionState::Connecting,
+            loads_state,
+        }
+    }
+
+    /// Starts over, following the progress of a new attempt reported on the returned sender.
+    pub(crate) fn attempt(&mut self) -> watch::Sender<ConnectionState> {
+        let (tx, rx) = watch::channel(ConnectionState::Connecting);
+        self.progress = rx;
+        self.state = ConnectionState::Connecting;
+        tx
+    }
+
+    /// Shows the latest stage reported by the current attempt.
+    pub(crate) fn refresh(&mut self) {
+        self.state = self.progress.borrow_and_update().clone();
+    }
+
+    pub(crate) fn handle_key_event(&self, key: KeyEvent) -> StartupAction {
+        match (&self.state, key.code) {
+            (_, KeyCode::Char('q')) => StartupAction::Quit,
+            (ConnectionState::Failed(_), KeyCode::Char('r')) => StartupAction::Retry,
+            (ConnectionState::Failed(_), KeyCode::Esc) => StartupAction::Quit,
+            _ => StartupAction::None,
+        }
+    }
+}
+
+/// The screen shown once the client is ready: the chats if the user is known already, the registration otherwise.
+pub(crate) fn landing_state(username: &str) -> AppState {
+    if username.is_empty() {
+        AppState::Register
+    } else {
+        AppState::Chats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crossterm::event::KeyModifiers;
+
+    fn key(code: KeyCode) -> KeyEvent {
+        KeyEvent::new(code, KeyModifiers::NONE)
+    }
+
+    #[test]
+    fn test_startup_follows_progress() {
+        let mut startup = Startup::new(true);
+        let progress = startup.attempt();
+        for state in [ConnectionState::LoadingState, ConnectionState::Handshaking, ConnectionState::Ready] {
+            progress.send_replace(state.clone());
+            startup.refresh();
+            assert_eq!(startup.state, state);
+        }
+        assert_eq!(landing_state(""), AppState::Register);
+        assert_eq!(landing_state("alice"), AppState::Chats);
+    }
+
+    #[test]
+    fn test_failed_startup_can_be_retried() {
+        let mut startup = Startup::new(false);
+        let progress = startup.attempt();
+        // Retrying is only offered once the attempt failed
+        assert_eq!(startup.handle_key_event(key(KeyCode::Char('r'))), StartupAction::None);
+        assert_eq!(startup.handle_key_event(key(KeyCode::Esc)), StartupAction::None);
+
+        progress.send_replace(ConnectionState::Failed("Connection refused".to_string()));
+        startup.refresh();
+        assert_eq!(startup.handle_key_event(key(KeyCode::Char('r'))), StartupAction::Retry);
+
+        let retry = startup.attempt();
+        assert_eq!(startup.state, ConnectionState::Connecting);
+        // Late updates of the abandoned attempt are not shown
+        progress.send_replace(ConnectionState::Failed("Connection reset".to_string()));
+        startup.refresh();
+        assert_eq!(startup.state, ConnectionState::Connecting);
+
+        retry.send_replace(ConnectionState::Ready);
+        startup.refresh();
+        assert_eq!(startup.state, ConnectionState::Ready);
+    }
+
+    #[test]
+    fn test_startup_can_be_quit() {
+        let mut startup = Startup::new(false);
+        let progress = startup.attempt();
+        assert_eq!(startup.handle_key_event(key(KeyCode::Char('q'))), StartupAction::Quit);
+        progress.send_replace(ConnectionState::Failed("Connection refused".to_string()));
+        startup.refresh();
+        assert_eq!(startup.handle_key_event(key(KeyCode::Esc)), StartupAction::Quit);
+    }
+}
@@ -1,4 +1,5 @@
 use crate::app::{App, AppResult};
+use crate::startup::Startup;
 use crate::event::EventHandler;
 use crate::ui;
 use crossterm::event::{DisableMouseCapture, EnableMouseCapture};
@@ -55,6 +56,12 @@ impl<B: Backend> Tui<B> {
         Ok(())
     }
 
+    /// Draws the loading screen of `startup`, see [`ui::render_startup`].
+    pub(crate) fn draw_startup(&mut self, startup: &Startup) -> AppResult<()> {
+        self.terminal.draw(|frame| ui::render_startup(startup, frame))?;
+        Ok(())
+    }
+
     /// Resets the terminal interface.
     ///
     /// This function is also used for the panic hook to revert
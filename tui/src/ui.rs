@@ -3,12 +3,20 @@ use ratatui::{
     Frame,
 };
 use ratatui::layout::{Constraint, Flex, Layout, Rect};
-use ratatui::widgets::Clear;
+use ratatui::widgets::{Clear, Paragraph};
 use crate::app::{App, AppState};
 use crate::widgets::chats::ChatsWidget;
 use crate::widgets::popup::PopupWidget;
 use crate::widgets::register::RegistrationWidget;
 use crate::widgets::empty_page::EmptyPage;
+use crate::widgets::lock::LockWidget;
+use crate::widgets::diagnostics::DiagnosticsWidget;
+use crate::widgets::message_info::MessageInfoWidget;
+use crate::widgets::verification::VerificationWidget;
+use crate::widgets::loading::LoadingWidget;
+use crate::startup::Startup;
+use crate::accent::accent;
+use std::time::Instant;
 
 /// Renders the user interface widgets.
 pub fn render(app: &mut App, frame: &mut Frame) {
@@ -33,7 +41,7 @@ pub fn render(app: &mut App, frame: &mut Frame) {
 
         },
         AppState::Chats => {
-            let chats = app.client.get_open_chats();
+            let chats = app.chat_list();
 
             if chats.is_empty() {
 
@@ -42,7 +50,19 @@ pub fn render(app: &mut App, frame: &mut Frame) {
                 frame.render_widget(EmptyPage::new(app.input_mode.clone()), frame.area());
 
             }else {
-                let active_chat_history = app.client.get_chat_history(&chats[app.active_chat]);
+                let active_chat_history = match app.group_at(app.active_chat) {
+                    Some(group) => app.client.get_group_history(&group),
+                    None => app.client.get_chat_history(&chats[app.active_chat]),
+                };
+                let auto_close = chats.iter().map(|c| app.client.is_auto_close(c)).collect();
+                let muted = chats.iter().map(|c| app.client.is_muted(c)).collect();
+                let blocked = chats.iter().map(|c| app.client.is_blocked(c)).collect();
+                let ephemeral = chats.iter().map(|c| app.client.is_ephemeral(c)).collect();
+                let online = chats.iter().map(|c| app.presence.get(c).copied()).collect();
+                let accents = chats.iter()
+                    .map(|c| app.client.friend_info(c).map(|info| accent(&info.fingerprint, app.accent_contrast)))
+                    .collect();
+                let typing = app.is_typing(&chats[app.active_chat], Instant::now());
                 frame.render_widget(
                     ChatsWidget::new(
                         app.client.username.clone(),
@@ -54,6 +74,16 @@ pub fn render(app: &mut App, frame: &mut Frame) {
                         app.selected_chat,
                         app.active_window,
                         active_chat_history,
+                        app.selected_message,
+                        app.scroll_offset(),
+                        auto_close,
+                        muted,
+                        blocked,
+                        ephemeral,
+                        online,
+                        accents,
+                        app.client.total_unread(),
+                        typing,
                     ),
                     frame.area()
                 );
@@ -71,16 +101,131 @@ pub fn render(app: &mut App, frame: &mut Frame) {
                     app.input.clone(),
                     app.character_index,
                     app.input_mode.clone(),
+                    app.incognito,
                     error_message,
                 ), area);
             }
+            if app.show_diagnostics {
+                let area = popup_area(area, 40, 8);
+                frame.render_widget(Clear, area);
+                frame.render_widget(DiagnosticsWidget::new(app.server_info.clone()), area);
+            }
+            if app.show_message_info {
+                let selected = app.client.get_open_chats().get(app.active_chat)
+                    .and_then(|chat| app.client.get_chat_history(chat))
+                    .zip(app.selected_message)
+                    .and_then(|(history, i)| history.get(i).cloned());
+                if let Some(message) = selected {
+                    let area = popup_area(area, 48, 8);
+                    frame.render_widget(Clear, area);
+                    frame.render_widget(MessageInfoWidget::new(message.meta), area);
+                }
+            }
+            if let Some(friend) = &app.verifying {
+                let verified = app.client.friend_info(friend).is_some_and(|info| info.verified);
+                let area = popup_area(area, 48, 10);
+                frame.render_widget(Clear, area);
+                frame.render_widget(VerificationWidget::new(friend.clone(), app.client.verification_code(friend), verified), area);
+            }
+        },
+        AppState::Locked => {
+            let error_message = match &app.error {
+                Some(e) => e.to_string(),
+                None => String::new(),
+            };
+            frame.render_widget(
+                LockWidget::new(
+                    app.input.chars().count(),
+                    app.lock.secret(),
+                    app.client.total_unread(),
+                    error_message,
+                ),
+                frame.area()
+            );
         },
     }
+    // The popup shows its own errors, those of the chats are shown on top of them
+    if let Some(error) = app.error.as_ref().filter(|_| app.state == AppState::Chats && !app.show_popup) {
+        let text = format!(" {} ", error);
+        let area = popup_area(Rect { height: 1, ..frame.area() }, text.chars().count() as u16, 1);
+        frame.render_widget(Clear, area);
+        frame.render_widget(Paragraph::new(text).style(Style::default().fg(Color::Black).bg(Color::LightRed)), area);
+    }
+    if app.reconnecting && app.state != AppState::Locked {
+        let area = popup_area(frame.area(), 20, 1);
+        frame.render_widget(Clear, area);
+        frame.render_widget(Paragraph::new(" Reconnecting…").style(Style::default().fg(Color::Black).bg(Color::Rgb(246, 193, 119))), area);
+    }
+}
+
+/// Renders the loading screen shown while the client starts.
+pub fn render_startup(startup: &Startup, frame: &mut Frame) {
+    frame.render_widget(ratatui::widgets::Block::default().style(Style::default().bg(Color::Rgb(31, 29, 46))), frame.area());
+    frame.render_widget(LoadingWidget::new(startup.state.clone(), startup.loads_state), frame.area());
 }
+
 fn popup_area(area: Rect, len_x: u16, len_y: u16) -> Rect {
     let vertical = Layout::vertical([Constraint::Length(len_y)]).flex(Flex::Center);
     let horizontal = Layout::horizontal([Constraint::Length(len_x)]).flex(Flex::Center);
     let [area] = vertical.areas(area);
     let [area] = horizontal.areas(area);
     area
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::errors::TuiError;
+    use crate::lock::InactivityLock;
+    use client::{Client, Connection, DeliveryStatus};
+    use ratatui::backend::TestBackend;
+    use ratatui::Terminal;
+    use tokio::net::TcpListener;
+
+    /// An [`App`] whose client is connected to a socket that never answers.
+    async fn offline_app() -> App {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        let accept = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            tokio_tungstenite::accept_async(stream).await.unwrap()
+        });
+        let connection = Connection::open(&url).await.unwrap();
+        // The server end is dropped, nothing is exchanged with it
+        accept.await.unwrap();
+        let (chat_tx, chat_rx) = tokio::sync::mpsc::channel(10);
+        App::new(Client::from_connection(connection, chat_tx), chat_rx, InactivityLock::new(None, std::time::Instant::now()))
+    }
+
+    fn screen(app: &mut App) -> String {
+        let mut terminal = Terminal::new(TestBackend::new(100, 30)).unwrap();
+        terminal.draw(|frame| render(app, frame)).unwrap();
+        terminal.backend().buffer().content().iter().map(|cell| cell.symbol()).collect()
+    }
+
+    #[tokio::test]
+    async fn test_locked_screen_shows_no_chat_content() {
+        let mut app = offline_app().await;
+        app.state = AppState::Chats;
+        assert!(screen(&mut app).contains("No chats available"));
+        app.show_popup = true;
+        app.input = "carol".to_string();
+        assert!(screen(&mut app).contains("carol"));
+
+        app.lock_screen();
+        app.input = "1234".to_string();
+        let locked = screen(&mut app);
+        assert!(locked.contains("Session locked") && locked.contains("****"));
+        for content in ["No chats available", "carol", "1234", "NORMAL", "INSERT"] {
+            assert!(!locked.contains(content), "{} shown on the lock screen", content);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_undelivered_message_is_shown_over_the_chats() {
+        let mut app = offline_app().await;
+        app.state = AppState::Chats;
+        app.error = Some(TuiError::Undelivered("bob".to_string(), DeliveryStatus::Queued));
+        assert!(screen(&mut app).contains("bob is offline, the message is sent when they connect"));
+    }
+}
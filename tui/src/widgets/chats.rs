@@ -1,3 +1,4 @@
+use chrono::{DateTime, Local, NaiveDate};
 use client::ChatMessage;
 use ratatui::{
     layout::{Constraint, Direction, Layout, Rect},
@@ -7,8 +8,34 @@ use ratatui::{
     buffer::Buffer,
 };
 use ratatui::layout::{Alignment, Margin};
-use ratatui::widgets::{List, ListItem};
+use ratatui::widgets::{List, ListItem, ListState, StatefulWidget};
+use crate::accent::Accent;
 use crate::app::InputMode;
+use crate::sanitize::sanitize;
+
+/// Marks chats whose history is never written to disk.
+const INCOGNITO_ICON: &str = "◌";
+/// Marks our messages the friend has read.
+const READ_ICON: &str = "✓";
+/// Marks files sent or received in a chat.
+const FILE_ICON: &str = "⎙";
+/// Shows whether the friend of a chat is online.
+const PRESENCE_ICON: &str = "●";
+/// How the time of a message is shown next to it.
+const TIME_FORMAT: &str = "%H:%M";
+/// How the day is shown in the separator between messages of different days.
+const DATE_FORMAT: &str = "%A %-d %B %Y";
+
+/// Returns the local time of an RFC 3339 `timestamp` and its day, or the timestamp as is if it does not parse.
+fn message_time(timestamp: &str) -> (String, Option<NaiveDate>) {
+    match DateTime::parse_from_rfc3339(timestamp) {
+        Ok(time) => {
+            let time = time.with_timezone(&Local);
+            (time.format(TIME_FORMAT).to_string(), Some(time.date_naive()))
+        }
+        Err(_) => (timestamp.to_string(), None),
+    }
+}
 
 pub(crate) struct ChatsWidget {
     whoami: String,
@@ -20,6 +47,20 @@ pub(crate) struct ChatsWidget {
     selected_chat: usize,
     active_window: usize,
     message_history: Option<Vec<ChatMessage>>,
+    selected_message: Option<usize>,
+    /// The rows of the history scrolled up from the newest message, 0 to follow new messages.
+    scroll_offset: usize,
+    auto_close: Vec<bool>,
+    muted: Vec<bool>,
+    blocked: Vec<bool>,
+    ephemeral: Vec<bool>,
+    /// Whether the friend of each chat is online, `None` if the server was not asked yet.
+    online: Vec<Option<bool>>,
+    /// The accent of each chat, `None` if the friend's identity key is not known.
+    accents: Vec<Option<Accent>>,
+    total_unread: usize,
+    /// The friend of the active chat is typing.
+    typing: bool,
 }
 
 impl ChatsWidget {
@@ -33,6 +74,16 @@ impl ChatsWidget {
         selected_chat: usize,
         active_window: usize,
         message_history: Option<Vec<ChatMessage>>,
+        selected_message: Option<usize>,
+        scroll_offset: usize,
+        auto_close: Vec<bool>,
+        muted: Vec<bool>,
+        blocked: Vec<bool>,
+        ephemeral: Vec<bool>,
+        online: Vec<Option<bool>>,
+        accents: Vec<Option<Accent>>,
+        total_unread: usize,
+        typing: bool,
     ) -> Self {
         Self {
             whoami,
@@ -43,7 +94,17 @@ impl ChatsWidget {
             chats,
             selected_chat,
             active_window,
-            message_history
+            message_history,
+            selected_message,
+            scroll_offset,
+            auto_close,
+            muted,
+            blocked,
+            ephemeral,
+            online,
+            accents,
+            total_unread,
+            typing,
         }
     }
 }
@@ -69,7 +130,11 @@ impl Widget for ChatsWidget {
 
         let left = Block::default()
             .borders(Borders::ALL)
-            .title(" Chats ")
+            .title(if self.total_unread > 0 {
+                format!(" Chats ({}) ", self.total_unread)
+            } else {
+                " Chats ".to_string()
+            })
             .title_alignment(Alignment::Center)
             .border_style(Style::default().fg(
                 if self.active_window == 0 {
@@ -97,45 +162,133 @@ impl Widget for ChatsWidget {
             ])
             .split(main_layout[1]);
 
-        let messages = self.message_history.unwrap_or(vec![])
-            .iter()
-            .map(|msg| {
-                let style = if msg.from == self.whoami {
-                    Style::default()
-                        .add_modifier(Modifier::BOLD)
-                        .fg(Color::Rgb(224, 222, 244))
-                } else {
-                    Style::default().fg(Color::Rgb(144, 140, 170))
-                };
+        let active_index = self.chats.iter().position(|c| *c == self.active_chat);
+        let active_accent = active_index.and_then(|i| self.accents.get(i).cloned().flatten());
 
-                ListItem::new(format!("> {}", msg.text))
-                    .style(style)
+        let active_ephemeral = active_index
+            .and_then(|i| self.ephemeral.get(i).copied())
+            .unwrap_or(false);
+        let active_name = match &active_accent {
+            Some(accent) => format!("{} {}", accent.glyph, self.active_chat),
+            None => self.active_chat.clone(),
+        };
+        let title = if active_ephemeral {
+            format!(" {} {} - history is lost on quit ", INCOGNITO_ICON, active_name)
+        } else {
+            format!(" {} ", active_name)
+        };
+        let right = Block::default()
+            .borders(Borders::ALL)
+            .title(match &active_accent {
+                Some(accent) => Line::styled(title, Style::default().fg(accent.color)),
+                None => Line::raw(title),
             })
-            .collect::<Vec<_>>();
-
-        let right = List::new(messages).block(
-            Block::default()
-                .borders(Borders::ALL)
-                .title(format!(" {} ", self.active_chat))
-                .title_alignment(Alignment::Center)
-                .border_style(Style::default().fg(
-                        if self.active_window == 1 {
-                        Color::Rgb(156,207, 216)
-                    } else {
-                        Color::Rgb(49, 116, 143)
-                    }
-                ).add_modifier(
+            .title_alignment(Alignment::Center)
+            .border_style(Style::default().fg(
                     if self.active_window == 1 {
-                        Modifier::BOLD
-                    } else {
-                        Modifier::empty()
-                    }
-                )
+                    Color::Rgb(156,207, 216)
+                } else {
+                    Color::Rgb(49, 116, 143)
+                }
+            ).add_modifier(
+                if self.active_window == 1 {
+                    Modifier::BOLD
+                } else {
+                    Modifier::empty()
+                }
             )
         );
-
+        let history_area = right.inner(chat_area[0]);
         right.render(chat_area[0], buf);
 
+        let history_area = if self.typing {
+            let rows = Layout::default()
+                .direction(Direction::Vertical)
+                .constraints([Constraint::Length(1), Constraint::Min(0)])
+                .split(history_area);
+            Paragraph::new(Span::styled(
+                format!("… {} is typing", self.active_chat),
+                Style::default().add_modifier(Modifier::ITALIC).fg(Color::Rgb(144, 140, 170)),
+            ))
+                .alignment(Alignment::Center)
+                .render(rows[0], buf);
+            rows[1]
+        } else {
+            history_area
+        };
+
+        // A separator row goes before each message on a different day than the one before it,
+        // so the rows of the list no longer line up with the messages
+        let mut rows_of_messages = Vec::new();
+        let mut previous_day = None;
+        let mut messages = Vec::new();
+        for msg in self.message_history.unwrap_or(vec![]).iter() {
+            let (time, day) = message_time(&msg.timestamp);
+            if let Some(day) = day {
+                if previous_day.is_some_and(|previous| previous != day) {
+                    messages.push(ListItem::new(
+                        Line::from(format!("── {} ──", day.format(DATE_FORMAT))).alignment(Alignment::Center)
+                    ).style(Style::default().fg(Color::Rgb(110, 106, 134))));
+                }
+                previous_day = Some(day);
+            }
+            rows_of_messages.push(messages.len());
+
+            if msg.msg_type == "system_event" {
+                messages.push(ListItem::new(format!("-- {} --", sanitize(&msg.text)))
+                    .style(Style::default().add_modifier(Modifier::ITALIC).fg(Color::Rgb(110, 106, 134))));
+                continue;
+            }
+            let style = if msg.from == self.whoami {
+                Style::default()
+                    .add_modifier(Modifier::BOLD)
+                    .fg(Color::Rgb(224, 222, 244))
+            } else {
+                Style::default().fg(Color::Rgb(144, 140, 170))
+            };
+
+            let read = if msg.from == self.whoami && msg.read {
+                format!(" {}", READ_ICON)
+            } else {
+                String::new()
+            };
+            let file = if msg.msg_type == "file" {
+                format!("{} ", FILE_ICON)
+            } else {
+                String::new()
+            };
+            // Group chats have several senders, each message is signed with its own
+            let sender = match &msg.group_id {
+                Some(_) if msg.from != self.whoami => format!("{}: ", sanitize(&msg.from)),
+                _ => String::new(),
+            };
+            // The friend's messages are prefixed with their avatar, in their accent
+            let prefix = match &active_accent {
+                Some(accent) if msg.from != self.whoami => {
+                    Span::styled(format!("{} ", accent.glyph), Style::default().fg(accent.color))
+                }
+                _ => Span::raw("> "),
+            };
+            let mut line = Line::from(vec![prefix, Span::raw(format!("{}{}{}{}", sender, file, sanitize(&msg.text), read))]);
+            // The time is pushed to the right edge, a space away from the text at least
+            let time = sanitize(&time);
+            let padding = (history_area.width as usize).saturating_sub(line.width() + time.chars().count()).max(1);
+            line.push_span(Span::raw(" ".repeat(padding)));
+            line.push_span(Span::styled(time, Style::default().fg(Color::Rgb(110, 106, 134))));
+            messages.push(ListItem::new(line).style(style));
+        }
+
+        // The window ends `scroll_offset` rows above the newest one, a history shorter than the pane is shown whole.
+        // A selected message is kept in view whatever the offset
+        let first_row = messages.len()
+            .saturating_sub(history_area.height as usize)
+            .saturating_sub(self.scroll_offset);
+        let history = List::new(messages).highlight_style(Style::default().bg(Color::Rgb(64, 61, 82)));
+        let mut selection = ListState::default()
+            .with_offset(first_row)
+            .with_selected(self.selected_message.and_then(|i| rows_of_messages.get(i).copied()));
+        StatefulWidget::render(history, history_area, buf, &mut selection);
+
         let byte_index = self.input
             .char_indices()
             .map(|(i, _)| i)
@@ -182,6 +335,7 @@ impl Widget for ChatsWidget {
             .split(inner_chats_area);
 
         for (i, chat) in self.chats.iter().enumerate() {
+            let accent = self.accents.get(i).cloned().flatten();
             let (text_style, border_style) = if i == self.selected_chat && self.active_window == 0 {
                 (
                     Style::default()
@@ -198,10 +352,37 @@ impl Widget for ChatsWidget {
                 )
             };
 
-            let chat_rows_layout = Paragraph::new(Span::styled(
-                chat.clone(),
-                text_style,
-            ))
+            // Chats opted into the retention policy are marked so the user knows they may be auto-closed
+            let mut label = if self.ephemeral.get(i).copied().unwrap_or(false) {
+                format!("{} {}", INCOGNITO_ICON, chat)
+            } else {
+                chat.clone()
+            };
+            if self.auto_close.get(i).copied().unwrap_or(false) {
+                label.push_str(" (auto-close)");
+            }
+            if self.muted.get(i).copied().unwrap_or(false) {
+                label.push_str(" (muted)");
+            }
+            if self.blocked.get(i).copied().unwrap_or(false) {
+                label.push_str(" (blocked)");
+            }
+            let presence = match self.online.get(i).copied().flatten() {
+                Some(true) => Color::Green,
+                Some(false) => Color::Rgb(110, 106, 134),
+                None => Color::Rgb(49, 116, 143),
+            };
+            // The border takes the accent of the chat, the selection still shows in its weight
+            let border_style = match &accent {
+                Some(accent) => border_style.fg(accent.color),
+                None => border_style,
+            };
+            let mut row = vec![Span::styled(format!("{} ", PRESENCE_ICON), Style::default().fg(presence))];
+            if let Some(accent) = &accent {
+                row.push(Span::styled(format!("{} ", accent.glyph), Style::default().fg(accent.color)));
+            }
+            row.push(Span::styled(label, text_style));
+            let chat_rows_layout = Paragraph::new(Line::from(row))
                 .block(
                     Block::default()
                         .borders(Borders::ALL)
@@ -212,11 +393,19 @@ impl Widget for ChatsWidget {
         }
 
         let bottom_text = match self.input_mode {
+            InputMode::Normal if self.active_window == 1 => Line::from(vec![
+                Span::styled(" NORMAL ", Style::default().fg(Color::Black).bg(Color::Rgb(196, 167, 231))),
+                Span::styled(" | Press 'j'/'k' to select a message, 'Del' to delete it, 'e' for its encryption info, 'PgUp'/'PgDn' to scroll, 'h' to go back to the chats, 'i' to enter INSERT mode", Style::default().fg(Color::White)),
+            ]),
             InputMode::Normal => Line::from(vec![
                 Span::styled(" NORMAL ", Style::default().fg(Color::Black).bg(Color::Rgb(196, 167, 231))),
-                Span::styled(" | Press 'a' to add a friend, 'i' to enter INSERT mode, 'q' to quit", Style::default().fg(Color::White)),
+                Span::styled(" | Press 'a' to add a friend, 'd' for diagnostics, 's' for the safety number, 'x' to toggle auto-close, 'm' to mute, 'b' to block, 'r' to reset the session, 'i' to enter INSERT mode, 'q' to quit", Style::default().fg(Color::White)),
             ]),
 
+            InputMode::Insert if self.active_window == 1 => Line::from(vec![
+                Span::styled(" INSERT ", Style::default().fg(Color::Black).bg(Color::Rgb(246, 193, 119))),
+                Span::styled(" | Press 'ESC' to enter NORMAL mode, send ':rekey' to rotate your key in this chat", Style::default().fg(Color::White)),
+            ]),
             InputMode::Insert => Line::from(vec![
                 Span::styled(" INSERT ", Style::default().fg(Color::Black).bg(Color::Rgb(246, 193, 119))),
                 Span::styled(" | Press 'ESC' to enter NORMAL mode", Style::default().fg(Color::White)),
@@ -229,4 +418,333 @@ impl Widget for ChatsWidget {
         bottom_paragraph.render(pippo[1], buf);
     }
 
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{TimeZone, Utc};
+
+    #[test]
+    fn test_ansi_escapes_are_not_rendered() {
+        let message = ChatMessage::new(
+            "chat".to_string(),
+            "alice".to_string(),
+            "bob".to_string(),
+            "\u{1b}[2J\u{1b}[31mgotcha".to_string(),
+            Utc::now(),
+        );
+        let widget = ChatsWidget::new(
+            "alice".to_string(),
+            String::new(),
+            0,
+            InputMode::Normal,
+            "bob".to_string(),
+            vec!["bob".to_string()],
+            0,
+            1,
+            Some(vec![message]),
+            None,
+            0,
+            vec![false],
+            vec![false],
+            vec![false],
+            vec![false],
+            vec![None],
+            vec![None],
+            0,
+            false,
+        );
+        let area = Rect::new(0, 0, 100, 20);
+        let mut buf = Buffer::empty(area);
+        widget.render(area, &mut buf);
+
+        let screen: String = buf.content().iter().map(|c| c.symbol()).collect();
+        assert!(!screen.contains('\u{1b}'));
+        assert!(screen.contains("gotcha"));
+    }
+
+    #[test]
+    fn test_selected_message_is_highlighted() {
+        let messages = ["first", "second"].map(|text| ChatMessage::new(
+            "chat".to_string(),
+            "alice".to_string(),
+            "bob".to_string(),
+            text.to_string(),
+            Utc::now(),
+        ));
+        let widget = ChatsWidget::new(
+            "alice".to_string(),
+            String::new(),
+            0,
+            InputMode::Normal,
+            "bob".to_string(),
+            vec!["bob".to_string()],
+            0,
+            1,
+            Some(messages.to_vec()),
+            Some(1),
+            0,
+            vec![false],
+            vec![false],
+            vec![false],
+            vec![false],
+            vec![None],
+            vec![None],
+            0,
+            false,
+        );
+        let area = Rect::new(0, 0, 100, 20);
+        let mut buf = Buffer::empty(area);
+        widget.render(area, &mut buf);
+
+        let row_of = |text: &str| (0..area.height)
+            .find(|&y| (0..area.width).map(|x| buf[(x, y)].symbol()).collect::<String>().contains(text))
+            .unwrap();
+        let highlighted = |y: u16| (0..area.width).any(|x| buf[(x, y)].bg == Color::Rgb(64, 61, 82));
+        assert!(highlighted(row_of("second")));
+        assert!(!highlighted(row_of("first")));
+    }
+
+    #[test]
+    fn test_messages_show_their_time_and_day_changes() {
+        let times = [(14, 23, 50, "late"), (15, 0, 10, "early")].map(|(day, hour, minute, text)| ChatMessage::new(
+            "chat".to_string(),
+            "alice".to_string(),
+            "bob".to_string(),
+            text.to_string(),
+            Local.with_ymd_and_hms(2026, 10, day, hour, minute, 0).unwrap().to_utc(),
+        ));
+        let mut undated = times[1].clone();
+        undated.text = "undated".to_string();
+        undated.timestamp = "yesterday".to_string();
+        let widget = ChatsWidget::new(
+            "alice".to_string(),
+            String::new(),
+            0,
+            InputMode::Normal,
+            "bob".to_string(),
+            vec!["bob".to_string()],
+            0,
+            1,
+            Some(vec![times[0].clone(), times[1].clone(), undated]),
+            Some(1),
+            0,
+            vec![false],
+            vec![false],
+            vec![false],
+            vec![false],
+            vec![None],
+            vec![None],
+            0,
+            false,
+        );
+        let area = Rect::new(0, 0, 100, 20);
+        let mut buf = Buffer::empty(area);
+        widget.render(area, &mut buf);
+
+        let line = |y: u16| (0..area.width).map(|x| buf[(x, y)].symbol()).collect::<String>();
+        let row_of = |text: &str| (0..area.height).find(|&y| line(y).contains(text)).unwrap();
+        assert!(line(row_of("late")).trim_end_matches(['│', ' ']).ends_with("23:50"));
+        assert!(line(row_of("early")).trim_end_matches(['│', ' ']).ends_with("00:10"));
+        assert!(line(row_of("undated")).contains("yesterday"));
+
+        let separator = row_of("Thursday 15 October 2026");
+        assert_eq!(separator, row_of("late") + 1);
+        // The selection follows the message, not the row the separator took
+        let highlighted = |y: u16| (0..area.width).any(|x| buf[(x, y)].bg == Color::Rgb(64, 61, 82));
+        assert!(highlighted(row_of("early")));
+        assert!(!highlighted(separator));
+    }
+
+    #[test]
+    fn test_history_scrolls_from_the_newest_message() {
+        let render = |count: usize, scroll_offset: usize| {
+            let messages = (0..count).map(|i| ChatMessage::new(
+                "chat".to_string(),
+                "alice".to_string(),
+                "bob".to_string(),
+                format!("message {}.", i),
+                Utc::now(),
+            )).collect();
+            let widget = ChatsWidget::new(
+                "alice".to_string(),
+                String::new(),
+                0,
+                InputMode::Normal,
+                "bob".to_string(),
+                vec!["bob".to_string()],
+                0,
+                1,
+                Some(messages),
+                None,
+                scroll_offset,
+                vec![false],
+                vec![false],
+                vec![false],
+                vec![false],
+                vec![None],
+                vec![None],
+                0,
+                false,
+            );
+            let area = Rect::new(0, 0, 100, 20);
+            let mut buf = Buffer::empty(area);
+            widget.render(area, &mut buf);
+            let screen: String = buf.content().iter().map(|c| c.symbol()).collect();
+            move |i: usize| screen.contains(&format!("message {}.", i))
+        };
+
+        // At the bottom the newest messages are shown, the oldest are out of view
+        let shown = render(40, 0);
+        assert!(shown(39) && !shown(0));
+        let shown = render(40, 5);
+        assert!(shown(34) && !shown(35));
+        // Scrolling stops at the oldest message
+        let shown = render(40, 1000);
+        assert!(shown(0) && !shown(39));
+        // A history shorter than the pane is shown whole, whatever the offset
+        for offset in [0, 3] {
+            let shown = render(3, offset);
+            assert!((0..3).all(|i| shown(i)));
+        }
+    }
+
+    #[test]
+    fn test_typing_is_shown_under_the_title() {
+        let render = |typing: bool| {
+            let widget = ChatsWidget::new(
+                "alice".to_string(),
+                String::new(),
+                0,
+                InputMode::Normal,
+                "bob".to_string(),
+                vec!["bob".to_string()],
+                0,
+                1,
+                None,
+                None,
+                0,
+                vec![false],
+                vec![false],
+                vec![false],
+                vec![false],
+                vec![None],
+                vec![None],
+                0,
+                typing,
+            );
+            let area = Rect::new(0, 0, 100, 20);
+            let mut buf = Buffer::empty(area);
+            widget.render(area, &mut buf);
+            (0..area.height)
+                .map(|y| (0..area.width).map(|x| buf[(x, y)].symbol()).collect::<String>())
+                .collect::<Vec<_>>()
+        };
+        let rows = render(true);
+        // The title is on the top border, the notice on the first row inside
+        assert!(rows[1].contains("bob is typing"));
+        assert!(!render(false).iter().any(|row| row.contains("is typing")));
+    }
+
+    #[test]
+    fn test_online_friends_get_a_green_dot() {
+        let widget = ChatsWidget::new(
+            "alice".to_string(),
+            String::new(),
+            0,
+            InputMode::Normal,
+            "bob".to_string(),
+            vec!["bob".to_string(), "carol".to_string()],
+            0,
+            0,
+            None,
+            None,
+            0,
+            vec![false, false],
+            vec![false, false],
+            vec![false, false],
+            vec![false, false],
+            vec![Some(true), Some(false)],
+            vec![None, None],
+            0,
+            false,
+        );
+        let area = Rect::new(0, 0, 100, 20);
+        let mut buf = Buffer::empty(area);
+        widget.render(area, &mut buf);
+
+        // Only the chat list on the left, the title of the active chat also names bob
+        let list_width = area.width / 4;
+        let dot_of = |name: &str| {
+            let y = (0..area.height)
+                .find(|&y| (0..list_width).map(|x| buf[(x, y)].symbol()).collect::<String>().contains(name))
+                .unwrap();
+            let x = (0..list_width).find(|&x| buf[(x, y)].symbol() == PRESENCE_ICON).unwrap();
+            buf[(x, y)].fg
+        };
+        assert_eq!(dot_of("bob"), Color::Green);
+        assert_ne!(dot_of("carol"), Color::Green);
+    }
+
+    #[test]
+    fn test_accent_marks_the_chat_and_the_friends_messages() {
+        let accent = Accent { color: Color::Indexed(168), glyph: "9C".to_string() };
+        let messages = [("bob", "alice", "from bob"), ("alice", "bob", "from alice")].map(|(from, to, text)| ChatMessage::new(
+            "chat".to_string(),
+            to.to_string(),
+            from.to_string(),
+            text.to_string(),
+            Utc::now(),
+        ));
+        let widget = ChatsWidget::new(
+            "alice".to_string(),
+            String::new(),
+            0,
+            InputMode::Normal,
+            "bob".to_string(),
+            vec!["bob".to_string(), "carol".to_string()],
+            1,
+            1,
+            Some(messages.to_vec()),
+            None,
+            0,
+            vec![false, false],
+            vec![false, false],
+            vec![false, false],
+            vec![false, false],
+            vec![None, None],
+            vec![Some(accent.clone()), None],
+            0,
+            false,
+        );
+        let area = Rect::new(0, 0, 100, 20);
+        let mut buf = Buffer::empty(area);
+        widget.render(area, &mut buf);
+
+        let rows = (0..area.height)
+            .map(|y| (0..area.width).map(|x| buf[(x, y)].symbol()).collect::<String>())
+            .collect::<Vec<_>>();
+        // The first cell within `columns` where `text` starts
+        let find = |text: &str, columns: std::ops::Range<u16>| {
+            let starts_at = |x: u16, y: u16| text.chars().enumerate()
+                .all(|(i, c)| x + (i as u16) < area.width && buf[(x + i as u16, y)].symbol() == c.to_string());
+            (0..area.height)
+                .find_map(|y| columns.clone().find(|&x| starts_at(x, y)).map(|x| (x, y)))
+                .unwrap()
+        };
+        let list_width = area.width / 4;
+
+        // The chat title and the friend's messages carry the glyph in the accent, ours do not
+        let (x, y) = find("9C bob", list_width..area.width);
+        assert_eq!(buf[(x, y)].fg, accent.color);
+        assert!(rows.iter().any(|row| row.contains("9C from bob")));
+        assert!(rows.iter().any(|row| row.contains("> from alice")));
+        // The entry of the chat in the list has a border in the accent, the one without an accent keeps the theme
+        let (x, y) = find("9C", 0..list_width);
+        assert_eq!(buf[(x, y)].fg, accent.color);
+        assert_eq!(buf[(1, y - 1)].fg, accent.color);
+        let (_, y) = find("carol", 0..list_width);
+        assert_ne!(buf[(1, y - 1)].fg, accent.color);
+    }
+}
@@ -0,0 +1,46 @@
+use common::ServerInfo;
+use ratatui::{
+    layout::Rect,
+    style::{Color, Style},
+    text::Line,
+    widgets::{Block, Borders, Paragraph, Widget},
+    buffer::Buffer,
+};
+
+pub(crate) struct DiagnosticsWidget {
+    info: Option<ServerInfo>,
+}
+
+impl DiagnosticsWidget {
+    pub(crate) fn new(info: Option<ServerInfo>) -> Self {
+        Self { info }
+    }
+}
+
+impl Widget for DiagnosticsWidget {
+    fn render(self, area: Rect, buf: &mut Buffer) {
+        let limit = |value: Option<String>| value.unwrap_or("none".to_string());
+        let lines = match self.info {
+            Some(info) => vec![
+                Line::from(format!("Version: {}", info.version)),
+                Line::from(format!("Uptime: {}s", info.uptime_secs)),
+                Line::from(format!("Users: ~{}", info.registered_users)),
+                Line::from(format!("Offline storage: {}", if info.offline_storage { "yes" } else { "no" })),
+                Line::from(format!("Max message size: {}", limit(info.max_message_size.map(|s| format!("{} bytes", s))))),
+                Line::from(format!("Rate limit: {}", limit(info.rate_limit_per_minute.map(|r| format!("{}/min", r))))),
+                Line::from(format!("Max users: {}", limit(info.max_registered_users.map(|n| n.to_string())))),
+            ],
+            None => vec![Line::from("Server info unavailable")],
+        };
+
+        Paragraph::new(lines)
+            .style(Style::default().fg(Color::White))
+            .block(
+                Block::default()
+                    .borders(Borders::ALL)
+                    .title(" Diagnostics ")
+                    .border_style(Style::default().fg(Color::Rgb(156, 207, 216)))
+            )
+            .render(area, buf);
+    }
+}
@@ -50,7 +50,7 @@ impl Widget for EmptyPage {
         let bottom_text = match self.input_mode {
             InputMode::Normal => Line::from(vec![
                 Span::styled(" NORMAL ", Style::default().fg(Color::Black).bg(Color::Rgb(196, 167, 231))),
-                Span::styled(" | Press 'a' to add a friend, 'i' to enter INSERT mode, 'q' to quit", Style::default().fg(Color::White)),
+                Span::styled(" | Press 'a' to add a friend, 'd' for diagnostics, 'i' to enter INSERT mode, 'q' to quit", Style::default().fg(Color::White)),
             ]),
 
             InputMode::Insert => Line::from(vec![
@@ -0,0 +1,100 @@
+use client::ConnectionState;
+use ratatui::{
+    layout::{Constraint, Direction, Layout, Rect},
+    style::{Color, Style},
+    text::Line,
+    widgets::{Paragraph, Widget},
+    buffer::Buffer,
+};
+use ratatui::layout::{Alignment, Flex};
+
+pub(crate) struct LoadingWidget {
+    state: ConnectionState,
+    loads_state: bool,
+}
+
+impl LoadingWidget {
+    pub(crate) fn new(state: ConnectionState, loads_state: bool) -> Self {
+        Self { state, loads_state }
+    }
+}
+
+impl Widget for LoadingWidget {
+    fn render(self, area: Rect, buf: &mut Buffer) {
+        let mut stages = vec![(ConnectionState::Connecting, "Connecting to the server")];
+        if self.loads_state {
+            stages.push((ConnectionState::LoadingState, "Loading the saved chats"));
+        }
+        stages.push((ConnectionState::Handshaking, "Establishing a secure session"));
+
+        let current = stages.iter().position(|(stage, _)| *stage == self.state);
+        let done = Style::default().fg(Color::Rgb(156, 207, 216));
+        let (lines, footer) = match &self.state {
+            ConnectionState::Failed(reason) => (
+                vec![Line::styled("Could not start the session", Style::default().fg(Color::White))],
+                vec![
+                    Line::styled(reason.clone(), Style::default().fg(Color::LightRed)),
+                    Line::from("r: retry   q: quit"),
+                ],
+            ),
+            state => {
+                let lines = stages
+                    .iter()
+                    .enumerate()
+                    .map(|(i, (_, label))| match current {
+                        _ if *state == ConnectionState::Ready => Line::styled(format!("✓ {}", label), done),
+                        Some(c) if i < c => Line::styled(format!("✓ {}", label), done),
+                        Some(c) if i == c => Line::styled(format!("… {}", label), Style::default().fg(Color::White)),
+                        _ => Line::styled(format!("  {}", label), Style::default().fg(Color::Gray)),
+                    })
+                    .collect::<Vec<_>>();
+                (lines, vec![Line::from("q: quit")])
+            }
+        };
+
+        let vertical_layout = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints(vec![
+                Constraint::Length(lines.len() as u16),
+                Constraint::Length(1),
+                Constraint::Length(footer.len() as u16),
+            ])
+            .flex(Flex::Center)
+            .split(area);
+        let horizontal = Layout::horizontal([Constraint::Length(34)]).flex(Flex::Center);
+
+        let [stages_area] = horizontal.areas(vertical_layout[0]);
+        Paragraph::new(lines).render(stages_area, buf);
+        Paragraph::new(footer)
+            .style(Style::default().fg(Color::Gray))
+            .alignment(Alignment::Center)
+            .render(vertical_layout[2], buf);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rendered(widget: LoadingWidget) -> String {
+        let area = Rect::new(0, 0, 60, 12);
+        let mut buf = Buffer::empty(area);
+        widget.render(area, &mut buf);
+        buf.content().iter().map(|c| c.symbol()).collect()
+    }
+
+    #[test]
+    fn test_stages_are_shown() {
+        let screen = rendered(LoadingWidget::new(ConnectionState::Handshaking, true));
+        assert!(screen.contains("✓ Connecting to the server"));
+        assert!(screen.contains("✓ Loading the saved chats"));
+        assert!(screen.contains("… Establishing a secure session"));
+
+        let screen = rendered(LoadingWidget::new(ConnectionState::Connecting, false));
+        assert!(!screen.contains("Loading the saved chats"));
+
+        let screen = rendered(LoadingWidget::new(ConnectionState::Failed("Connection refused".to_string()), false));
+        assert!(screen.contains("Connection refused"));
+        assert!(screen.contains("r: retry"));
+    }
+}
@@ -0,0 +1,106 @@
+use ratatui::{
+    layout::{Constraint, Direction, Layout, Rect},
+    style::{Color, Style},
+    text::{Line, Span},
+    widgets::{Block, Borders, Paragraph, Widget},
+    buffer::Buffer,
+};
+use ratatui::layout::{Alignment, Flex};
+use crate::lock::LockSecret;
+
+pub(crate) struct LockWidget {
+    pin_length: usize,
+    secret: LockSecret,
+    unread: usize,
+    error: String,
+}
+
+impl LockWidget {
+    pub fn new(pin_length: usize, secret: LockSecret, unread: usize, error: String) -> Self {
+        Self {
+            pin_length,
+            secret,
+            unread,
+            error,
+        }
+    }
+}
+
+impl Widget for LockWidget {
+    fn render(self, area: Rect, buf: &mut Buffer) {
+
+        let vertical_layout = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints(vec![
+                Constraint::Length(2),
+                Constraint::Length(3),
+                Constraint::Length(1),
+            ])
+            .flex(Flex::Center)
+            .split(area);
+
+        let horizontal = Layout::horizontal([Constraint::Length(30)]).flex(Flex::Center);
+
+        let unread = match self.unread {
+            0 => "No new messages".to_string(),
+            1 => "1 new message".to_string(),
+            n => format!("{} new messages", n),
+        };
+        let title_text = Paragraph::new(vec![
+            Line::from("Session locked"),
+            Line::from(unread),
+        ])
+            .style(Style::default().fg(Color::White))
+            .alignment(Alignment::Center);
+
+        title_text.render(vertical_layout[0], buf);
+
+        // Only the length of the PIN is shown
+        let input = Line::from(vec![
+            Span::raw("*".repeat(self.pin_length)),
+            Span::styled("|", Style::default().fg(Color::Gray)),
+        ]);
+
+        let input_paragraph = Paragraph::new(input)
+            .block(
+                Block::default()
+                    .borders(Borders::ALL)
+                    .title(match self.secret {
+                        LockSecret::NewPin => " Choose a PIN ",
+                        LockSecret::Pin => " Enter PIN ",
+                        LockSecret::Passphrase => " Enter passphrase ",
+                    })
+                    .border_style(Style::default().fg(Color::Rgb(156, 207, 216)))
+            );
+
+        let [input_area] = horizontal.areas(vertical_layout[1]);
+        input_paragraph.render(input_area, buf);
+
+        let error = Paragraph::new(self.error)
+            .style(Style::default().fg(Color::LightRed))
+            .alignment(Alignment::Center);
+
+        error.render(vertical_layout[2], buf);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rendered(widget: LockWidget) -> String {
+        let area = Rect::new(0, 0, 60, 12);
+        let mut buf = Buffer::empty(area);
+        widget.render(area, &mut buf);
+        buf.content().iter().map(|c| c.symbol()).collect()
+    }
+
+    #[test]
+    fn test_pin_is_masked() {
+        let screen = rendered(LockWidget::new(4, LockSecret::Pin, 3, String::new()));
+        assert!(screen.contains("****|"));
+        assert!(screen.contains("3 new messages"));
+        assert!(screen.contains("Enter PIN"));
+        assert!(rendered(LockWidget::new(4, LockSecret::Passphrase, 0, String::new())).contains("Enter passphrase"));
+    }
+}
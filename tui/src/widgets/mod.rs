@@ -2,3 +2,8 @@ pub(crate) mod register;
 pub(crate) mod chats;
 pub(crate) mod popup;
 pub (crate) mod empty_page;
+pub(crate) mod lock;
+pub(crate) mod diagnostics;
+pub(crate) mod message_info;
+pub(crate) mod verification;
+pub(crate) mod loading;
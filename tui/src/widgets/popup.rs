@@ -9,15 +9,17 @@ pub(crate) struct PopupWidget {
 
     character_index: usize,
     input_mode: InputMode,
+    incognito: bool,
     display_message: String,
 }
 
 impl PopupWidget {
-    pub(crate) fn new(input: String, character_index: usize, input_mode: InputMode, display_message: String) -> Self {
+    pub(crate) fn new(input: String, character_index: usize, input_mode: InputMode, incognito: bool, display_message: String) -> Self {
         Self {
             input,
             character_index,
             input_mode,
+            incognito,
             display_message,
         }
     }
@@ -44,14 +46,20 @@ impl Widget for PopupWidget {
             ]);
 
             let input_paragraph = Paragraph::new(input_with_cursor)
-                .block(Block::default().borders(Borders::ALL).title(" Add a friend "));
+                .block(Block::default().borders(Borders::ALL).title(
+                    // Tab toggles an ephemeral chat
+                    if self.incognito { " Add a friend (incognito) " } else { " Add a friend " }
+                ));
 
             // Render popup
             input_paragraph.render(popup_layout[0], buf);
 
             // Render the horizontal line just below the popup
-            let line = Paragraph::new(self.display_message)
-                .style(Style::default().fg(Color::LightRed));
+            let line = if self.display_message.is_empty() {
+                Paragraph::new("Tab: toggle incognito").style(Style::default().fg(Color::Gray))
+            } else {
+                Paragraph::new(self.display_message).style(Style::default().fg(Color::LightRed))
+            };
 
             line.render(popup_layout[1], buf);
     }
@@ -42,8 +42,7 @@ impl Widget for RegistrationWidget {
                     Constraint::Length(instruction_height),
                     Constraint::Min(0),
                     Constraint::Length(bottom_bar_height), // Bottom bar
-                ]
-                    .as_ref(),
+                ],
             )
             .split(area);
 
@@ -0,0 +1,83 @@
+use protocol::fingerprint::format_safety_number;
+use ratatui::{
+    layout::{Alignment, Rect},
+    style::{Color, Style},
+    text::Line,
+    widgets::{Block, Borders, Paragraph, Widget},
+    buffer::Buffer,
+};
+
+/// The number of groups of 5 digits on each line of the safety number, 3 lines of 4 like Signal.
+const GROUPS_PER_LINE: usize = 4;
+
+/// Shows the safety number of the active chat, to compare with the friend's out of band.
+pub(crate) struct VerificationWidget {
+    friend: String,
+    safety_number: Option<String>,
+    verified: bool,
+}
+
+impl VerificationWidget {
+    pub(crate) fn new(friend: String, safety_number: Option<String>, verified: bool) -> Self {
+        Self { friend, safety_number, verified }
+    }
+}
+
+impl Widget for VerificationWidget {
+    fn render(self, area: Rect, buf: &mut Buffer) {
+        let mut lines = vec![
+            Line::from(format!("Compare this number with {}:", self.friend)),
+            Line::from(""),
+        ];
+        match self.safety_number {
+            Some(number) => {
+                let groups: Vec<String> = format_safety_number(&number).split(' ').map(str::to_string).collect();
+                for line in groups.chunks(GROUPS_PER_LINE) {
+                    lines.push(Line::from(line.join(" ")).alignment(Alignment::Center));
+                }
+            }
+            None => lines.push(Line::from("No safety number for this chat")),
+        }
+        lines.push(Line::from(""));
+        lines.push(Line::from(format!("Verified: {}", if self.verified { "yes" } else { "no" })));
+        lines.push(Line::from("Press 'v' to toggle verified, 'ESC' to close"));
+
+        Paragraph::new(lines)
+            .style(Style::default().fg(Color::White))
+            .block(
+                Block::default()
+                    .borders(Borders::ALL)
+                    .title(" Safety number ")
+                    .border_style(Style::default().fg(Color::Rgb(156, 207, 216)))
+            )
+            .render(area, buf);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rendered(widget: VerificationWidget) -> Vec<String> {
+        let area = Rect::new(0, 0, 48, 10);
+        let mut buf = Buffer::empty(area);
+        widget.render(area, &mut buf);
+        (0..area.height)
+            .map(|y| (0..area.width).map(|x| buf[(x, y)].symbol()).collect())
+            .collect()
+    }
+
+    #[test]
+    fn test_safety_number_is_shown_in_three_lines() {
+        let number = "0123456789".repeat(6);
+        let rows = rendered(VerificationWidget::new("bob".to_string(), Some(number), false));
+        assert!(rows[1].contains("Compare this number with bob"));
+        assert!(rows[3].contains("01234 56789 01234 56789"));
+        assert!(rows[5].contains("01234 56789 01234 56789"));
+        assert!(rows[7].contains("Verified: no"));
+
+        let rows = rendered(VerificationWidget::new("bob".to_string(), None, true));
+        assert!(rows.iter().any(|row| row.contains("No safety number")));
+        assert!(rows.iter().any(|row| row.contains("Verified: yes")));
+    }
+}